@@ -10,10 +10,46 @@ SERVER_IP = os.getenv("SERVER_IP", "127.0.0.1")
 SERVER_PORT = int(os.getenv("SERVER_PORT", 50000))
 SESSION_CODE = os.getenv("SESSION_CODE", "default-code")
 
+# rdp_check_capture_permission() result meaning capture is allowed
+CAPTURE_PERMISSION_GRANTED = 0
+
 class RawImage(ctypes.Structure):
     _fields_ = [
         ("data", ctypes.POINTER(ctypes.c_uint8)),
         ("len", ctypes.c_size_t),
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+        ("format", ctypes.c_uint32),
+        ("stride", ctypes.c_uint32),
+        ("pixel_format", ctypes.c_uint32),
+        ("dirty_x", ctypes.c_uint32),
+        ("dirty_y", ctypes.c_uint32),
+        ("dirty_w", ctypes.c_uint32),
+        ("dirty_h", ctypes.c_uint32),
+        ("content_hash", ctypes.c_uint64),
+        ("cursor_x", ctypes.c_int32),
+        ("cursor_y", ctypes.c_int32),
+        ("cursor_visible", ctypes.c_uint8),
+        ("hotspot_x", ctypes.c_uint32),
+        ("hotspot_y", ctypes.c_uint32),
+        ("sequence", ctypes.c_uint64),
+        ("timestamp_us", ctypes.c_uint64),
+        ("quality", ctypes.c_uint8),
+        ("keyframe", ctypes.c_uint8),
+        ("uncompressed_len", ctypes.c_uint64),
+        ("encrypted", ctypes.c_uint8),
+    ]
+
+
+class DisplayInfo(ctypes.Structure):
+    _fields_ = [
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+        ("is_primary", ctypes.c_uint8),
+        ("id", ctypes.c_uint64),
+        ("scale", ctypes.c_float),
+        ("x", ctypes.c_int32),
+        ("y", ctypes.c_int32),
     ]
 
 
@@ -38,9 +74,29 @@ def get_rust_library():
     rdp_lib.capture_and_encode.restype = ctypes.POINTER(RawImage)
     rdp_lib.free_image.argtypes = [ctypes.POINTER(RawImage)]
 
+    rdp_lib.rdp_display_count.argtypes = []
+    rdp_lib.rdp_display_count.restype = ctypes.c_int32
+    rdp_lib.rdp_display_info.argtypes = [ctypes.c_int32, ctypes.POINTER(DisplayInfo)]
+    rdp_lib.rdp_display_info.restype = ctypes.c_int32
+
+    rdp_lib.rdp_check_capture_permission.argtypes = []
+    rdp_lib.rdp_check_capture_permission.restype = ctypes.c_int32
+    rdp_lib.rdp_request_capture_permission.argtypes = []
+    rdp_lib.rdp_request_capture_permission.restype = ctypes.c_int32
+
     return rdp_lib
 
 
+def list_displays(rdp_lib):
+    """Returns [(index, width, height, is_primary), ...] for every display."""
+    displays = []
+    for index in range(max(rdp_lib.rdp_display_count(), 0)):
+        info = DisplayInfo()
+        if rdp_lib.rdp_display_info(index, ctypes.byref(info)) == 0:
+            displays.append((index, info.width, info.height, bool(info.is_primary)))
+    return displays
+
+
 def handle_key(cmd: str, key_val: str, keyboard: KeyboardController):
     """
     Handle key_down / key_up for both printable and special keys.
@@ -120,6 +176,15 @@ def main():
         print(f"FATAL ERROR: Failed to load Rust library: {e}")
         sys.exit(1)
 
+    # Without it macOS captures nothing but the wallpaper
+    if rdp_lib.rdp_check_capture_permission() != CAPTURE_PERMISSION_GRANTED:
+        if rdp_lib.rdp_request_capture_permission() != CAPTURE_PERMISSION_GRANTED:
+            print(
+                "FATAL ERROR: Screen Recording permission is required. Grant it in "
+                "System Settings > Privacy & Security, then restart the host."
+            )
+            sys.exit(1)
+
     # 2. Initialize Input Controllers
     try:
         mouse = MouseController()
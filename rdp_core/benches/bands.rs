@@ -0,0 +1,53 @@
+//! Encoding a 4K frame whole against splitting it into bands encoded in
+//! parallel (`encode_bands`):
+//!
+//! ```text
+//! cargo bench --bench bands
+//! ```
+//!
+//! Prints, per band count, the mean time and size of a JPEG frame at the
+//! default settings and the speedup over a single band, on the default
+//! encoder pool (one thread per physical core but one).
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rdp_core::{FrameFormat, SessionConfig, encode_bgra};
+
+mod common;
+
+const WIDTH: u32 = 3840;
+const HEIGHT: u32 = 2160;
+const ROUNDS: u32 = 20;
+
+fn main() {
+    let frame = common::desktop(WIDTH, HEIGHT);
+    println!("JPEG bands, {WIDTH}x{HEIGHT}, {ROUNDS} rounds");
+
+    let mut whole = Duration::ZERO;
+    for bands in [1, 2, 4, 8, 16] {
+        let config = SessionConfig {
+            format: FrameFormat::Jpeg,
+            encode_bands: bands,
+            ..SessionConfig::default()
+        };
+        // Warm-up (starting the pool), and the size to report
+        let size = encode_bgra(&frame, WIDTH, HEIGHT, &config)
+            .expect("encoding failed")
+            .len();
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            black_box(encode_bgra(black_box(&frame), WIDTH, HEIGHT, &config).unwrap());
+        }
+        let per_frame = started.elapsed() / ROUNDS;
+        if bands == 1 {
+            whole = per_frame;
+        }
+        println!(
+            "{bands:>3} bands: {:7.2} ms/frame, {:6} KiB, {:4.1}x",
+            per_frame.as_secs_f64() * 1000.0,
+            size / 1024,
+            whole.as_secs_f64() / per_frame.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Allocator traffic per frame with the session's reused buffers, against
+//! a fresh session per frame, which starts every buffer from nothing as
+//! capture did before the buffers were kept:
+//!
+//! ```text
+//! cargo bench --bench buffers
+//! ```
+//!
+//! Captures a 3840x2160 test pattern scaled to 1920x1080 and prints, per
+//! output format, the mean time, allocations and bytes allocated per frame,
+//! next to the size of the returned frame, which is the one allocation a
+//! reused session should still make.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Instant;
+
+use rdp_core::{CaptureBackend, CaptureSession, FrameFormat, SessionConfig, set_test_pattern};
+
+const SOURCE: (u32, u32) = (3840, 2160);
+const TARGET: (u32, u32) = (1920, 1080);
+const ROUNDS: u32 = 20;
+
+/// The system allocator, counting what it hands out.
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Relaxed);
+        BYTES.fetch_add(layout.size() as u64, Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Relaxed);
+        BYTES.fetch_add(new_size.saturating_sub(layout.size()) as u64, Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn open(format: FrameFormat) -> CaptureSession {
+    let config = SessionConfig {
+        format,
+        ..SessionConfig::default()
+    };
+    CaptureSession::with_backend(CaptureBackend::Test, 0, config).unwrap()
+}
+
+/// Runs `capture` `ROUNDS` times, printing the means per frame.
+fn measure(label: &str, mut capture: impl FnMut() -> usize) {
+    let (allocations, bytes) = (ALLOCATIONS.load(Relaxed), BYTES.load(Relaxed));
+    let started = Instant::now();
+    let mut returned = 0;
+    for _ in 0..ROUNDS {
+        returned += black_box(capture());
+    }
+    let elapsed = started.elapsed() / ROUNDS;
+    let rounds = u64::from(ROUNDS);
+    println!(
+        "{label:<18}: {:7.2} ms, {:6} allocations, {:8.2} MB allocated, {:6.2} MB returned",
+        elapsed.as_secs_f64() * 1000.0,
+        (ALLOCATIONS.load(Relaxed) - allocations) / rounds,
+        (BYTES.load(Relaxed) - bytes) as f64 / rounds as f64 / 1e6,
+        returned as f64 / rounds as f64 / 1e6
+    );
+}
+
+fn main() {
+    // A new frame on every poll, so capture never waits on the clock
+    set_test_pattern(SOURCE.0, SOURCE.1, 0).unwrap();
+    println!(
+        "{}x{} test pattern to {}x{}, {ROUNDS} rounds",
+        SOURCE.0, SOURCE.1, TARGET.0, TARGET.1
+    );
+
+    for (name, format) in [("raw", FrameFormat::Raw), ("jpeg", FrameFormat::Jpeg)] {
+        let mut session = open(format);
+        // Warm-up, which sizes the session's buffers
+        session.capture_at(TARGET.0, TARGET.1).unwrap();
+        measure(&format!("{name}, reused"), || {
+            session.capture_at(TARGET.0, TARGET.1).unwrap().data.len()
+        });
+        measure(&format!("{name}, fresh"), || {
+            open(format)
+                .capture_at(TARGET.0, TARGET.1)
+                .unwrap()
+                .data
+                .len()
+        });
+    }
+}
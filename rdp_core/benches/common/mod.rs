@@ -0,0 +1,23 @@
+//! Input shared by the benchmarks.
+
+/// A BGRA frame with what screens tend to show: flat panels, a gradient
+/// and a block of fine, text-like detail.
+pub fn desktop(width: u32, height: u32) -> Vec<u8> {
+    let mut frame = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let px = if y < 40 {
+                [60, 50, 45, 255]
+            } else if x < width / 4 {
+                let shade = (y * 255 / height) as u8;
+                [shade, 120, 255 - shade, 255]
+            } else if (x / 7 + y / 11) % 3 == 0 && (x * 31 + y * 17) % 5 != 0 {
+                [20, 20, 20, 255]
+            } else {
+                [250, 250, 250, 255]
+            };
+            frame.extend_from_slice(&px);
+        }
+    }
+    frame
+}
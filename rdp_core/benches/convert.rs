@@ -0,0 +1,62 @@
+//! BGRA conversion on each path against the scalar loop:
+//!
+//! ```text
+//! cargo bench --bench convert
+//! ```
+//!
+//! One group per frame size, with a benchmark per output layout and path
+//! (`rgb/Scalar`, `rgb/Avx2`, ...), so each path's time and throughput
+//! sits next to the scalar one. That every path matches the scalar output
+//! is covered by the unit tests in `src/pixels.rs`.
+
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use rdp_core::{ConvertPath, PixelFormat, convert_bgra_with};
+
+mod common;
+
+const SIZES: [(u32, u32); 3] = [(1920, 1080), (2560, 1440), (3840, 2160)];
+
+fn convert(c: &mut Criterion) {
+    let paths: Vec<ConvertPath> = [
+        ConvertPath::Scalar,
+        ConvertPath::Ssse3,
+        ConvertPath::Avx2,
+        ConvertPath::Neon,
+    ]
+    .into_iter()
+    .filter(|path| path.is_available())
+    .collect();
+
+    for (width, height) in SIZES {
+        // One pixel short of a whole row, so every path leaves a tail
+        let frame = common::desktop(width, height);
+        let frame = &frame[..frame.len() - 4];
+        let mut group = c.benchmark_group(format!("convert/{width}x{height}"));
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        // 4K frames take a while on the scalar path
+        group.sample_size(20);
+        for format in [
+            PixelFormat::Rgb,
+            PixelFormat::Bgr,
+            PixelFormat::Rgba,
+            PixelFormat::Gray,
+        ] {
+            for &path in &paths {
+                let id = BenchmarkId::new(format.name(), format!("{path:?}"));
+                let mut out = Vec::new();
+                group.bench_with_input(id, frame, |b, frame| {
+                    b.iter(|| {
+                        convert_bgra_with(path, black_box(frame), format, &mut out);
+                        black_box(&out);
+                    });
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, convert);
+criterion_main!(benches);
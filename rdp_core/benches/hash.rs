@@ -0,0 +1,53 @@
+//! The change detection hash against the JPEG encode it saves on an
+//! unchanged screen:
+//!
+//! ```text
+//! cargo bench --bench hash
+//! ```
+//!
+//! Prints, per frame size, the mean time to hash a BGRA frame and to encode
+//! it at the default settings, and how many times cheaper the hash is.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rdp_core::{FrameFormat, SessionConfig, encode_bgra, frame_hash};
+
+mod common;
+
+const SIZES: [(u32, u32); 3] = [(1280, 720), (1920, 1080), (3840, 2160)];
+const ROUNDS: u32 = 20;
+
+fn mean(mut run: impl FnMut()) -> Duration {
+    // Warm-up
+    run();
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        run();
+    }
+    started.elapsed() / ROUNDS
+}
+
+fn main() {
+    let config = SessionConfig {
+        format: FrameFormat::Jpeg,
+        ..SessionConfig::default()
+    };
+    println!("Change detection hash against JPEG encoding, {ROUNDS} rounds");
+    for (width, height) in SIZES {
+        let frame = common::desktop(width, height);
+        let salt = [width, height, 0, 0, u32::MAX, u32::MAX];
+        let hash = mean(|| {
+            black_box(frame_hash(black_box(&frame), &salt));
+        });
+        let encode = mean(|| {
+            black_box(encode_bgra(black_box(&frame), width, height, &config).unwrap());
+        });
+        println!(
+            "{width:>4}x{height:<4}: hash {:6.3} ms, encode {:7.2} ms, {:5.0}x cheaper",
+            hash.as_secs_f64() * 1000.0,
+            encode.as_secs_f64() * 1000.0,
+            encode.as_secs_f64() / hash.as_secs_f64()
+        );
+    }
+}
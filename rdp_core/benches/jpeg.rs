@@ -0,0 +1,78 @@
+//! JPEG encoding speed and size on a synthetic 1080p desktop, for
+//! comparing libjpeg-turbo with the pure-Rust fallback:
+//!
+//! ```text
+//! cargo bench --bench jpeg
+//! cargo bench --bench jpeg --no-default-features
+//! ```
+//!
+//! Each run prints the encoder it was built with and, per subsampling, the
+//! mean time and size of a frame at the default quality, baseline and (with
+//! libjpeg-turbo) progressive. The fallback always encodes colour at 4:4:4,
+//! so its rows differ only in time.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use rdp_core::{FrameFormat, SessionConfig, Subsampling, encode_bgra};
+
+mod common;
+
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+const ROUNDS: u32 = 30;
+
+fn main() {
+    let frame = common::desktop(WIDTH, HEIGHT);
+    let encoder = if cfg!(feature = "turbojpeg") {
+        "libjpeg-turbo"
+    } else {
+        "image (pure Rust)"
+    };
+    println!("JPEG via {encoder}, {WIDTH}x{HEIGHT}, {ROUNDS} rounds");
+
+    // The fallback encoder has no progressive mode
+    let modes: &[bool] = if cfg!(feature = "turbojpeg") {
+        &[false, true]
+    } else {
+        &[false]
+    };
+    for (name, subsampling) in [
+        ("4:4:4", Subsampling::Yuv444),
+        ("4:2:0", Subsampling::Yuv420),
+        ("gray", Subsampling::Gray),
+    ] {
+        for &progressive in modes {
+            run(&frame, name, subsampling, progressive);
+        }
+    }
+}
+
+/// Prints the mean time and size of a frame encoded with `subsampling`.
+fn run(frame: &[u8], name: &str, subsampling: Subsampling, progressive: bool) {
+    let config = SessionConfig {
+        format: FrameFormat::Jpeg,
+        subsampling,
+        progressive,
+        ..SessionConfig::default()
+    };
+    // Warm-up, and the size to report
+    let size = encode_bgra(frame, WIDTH, HEIGHT, &config)
+        .expect("encoding failed")
+        .len();
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(encode_bgra(black_box(frame), WIDTH, HEIGHT, &config).unwrap());
+    }
+    let per_frame = started.elapsed() / ROUNDS;
+    let mode = if progressive {
+        "progressive"
+    } else {
+        "baseline"
+    };
+    println!(
+        "{name:>6} {mode:>11}: {:7.2} ms/frame, {:6} KiB",
+        per_frame.as_secs_f64() * 1000.0,
+        size / 1024
+    );
+}
@@ -0,0 +1,71 @@
+//! What each resize algorithm costs per frame when a 4K desktop is scaled
+//! down, so the smoother filters can be weighed against their time:
+//!
+//! ```text
+//! cargo bench --bench resize
+//! ```
+//!
+//! Captures from the 3840x2160 test pattern with raw output, so encoding
+//! does not hide the difference, and prints per algorithm and target size
+//! the mean time per frame and the session's resize stage time.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use fast_image_resize as fr;
+use rdp_core::{CaptureBackend, CaptureSession, FrameFormat, SessionConfig, set_test_pattern};
+
+const SOURCE: (u32, u32) = (3840, 2160);
+const TARGETS: [(u32, u32); 2] = [(1920, 1080), (1280, 720)];
+const ROUNDS: u32 = 20;
+
+const ALGORITHMS: [(&str, fr::ResizeAlg); 4] = [
+    ("nearest", fr::ResizeAlg::Nearest),
+    (
+        "bilinear",
+        fr::ResizeAlg::Convolution(fr::FilterType::Bilinear),
+    ),
+    (
+        "catmull-rom",
+        fr::ResizeAlg::Convolution(fr::FilterType::CatmullRom),
+    ),
+    (
+        "lanczos3",
+        fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3),
+    ),
+];
+
+fn main() {
+    // A new frame on every poll, so capture never waits on the clock
+    set_test_pattern(SOURCE.0, SOURCE.1, 0).unwrap();
+    println!(
+        "{}x{} test pattern, raw output, {ROUNDS} rounds",
+        SOURCE.0, SOURCE.1
+    );
+
+    for (width, height) in TARGETS {
+        for (name, resize_alg) in ALGORITHMS {
+            let config = SessionConfig {
+                format: FrameFormat::Raw,
+                resize_alg,
+                ..SessionConfig::default()
+            };
+            let mut session =
+                CaptureSession::with_backend(CaptureBackend::Test, 0, config).unwrap();
+            // Warm-up, which also sizes the session's buffers
+            session.capture_at(width, height).unwrap();
+
+            let started = Instant::now();
+            for _ in 0..ROUNDS {
+                black_box(session.capture_at(width, height).unwrap());
+            }
+            let frame: Duration = started.elapsed() / ROUNDS;
+            let resize_us = session.stats().snapshot().resize_us_avg;
+            println!(
+                "{width:>4}x{height:<4} {name:<11}: frame {:7.2} ms, resize {:7.2} ms",
+                frame.as_secs_f64() * 1000.0,
+                resize_us as f64 / 1000.0
+            );
+        }
+    }
+}
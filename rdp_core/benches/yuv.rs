@@ -0,0 +1,40 @@
+//! I420 and NV12 conversion speed at common frame sizes:
+//!
+//! ```text
+//! cargo bench --bench yuv
+//! ```
+//!
+//! Prints the mean time per frame for each output. The conversions'
+//! correctness is covered by the unit tests in `src/yuv.rs`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use rdp_core::{FrameFormat, SessionConfig, encode_bgra};
+
+mod common;
+
+const SIZES: [(u32, u32); 2] = [(1920, 1080), (3840, 2160)];
+const ROUNDS: u32 = 20;
+
+fn main() {
+    for format in [FrameFormat::I420, FrameFormat::Nv12] {
+        let config = SessionConfig {
+            format,
+            ..SessionConfig::default()
+        };
+        for (width, height) in SIZES {
+            let frame = common::desktop(width, height);
+            let started = Instant::now();
+            for _ in 0..ROUNDS {
+                black_box(encode_bgra(black_box(&frame), width, height, &config).unwrap());
+            }
+            let per_frame = started.elapsed() / ROUNDS;
+            println!(
+                "{:>4} {width}x{height}: {:6.2} ms/frame",
+                format.name(),
+                per_frame.as_secs_f64() * 1000.0
+            );
+        }
+    }
+}
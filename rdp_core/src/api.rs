@@ -0,0 +1,155 @@
+//! The crate's Rust API: a capture session with owned frames and `Result`s
+//! instead of handles, status codes and `free_image`. It wraps the same
+//! `RdpSession` the `extern "C"` functions drive, so both see identical
+//! behaviour; only the error type differs.
+//!
+//! ```ignore
+//! let config = SessionConfig { format: FrameFormat::Png, ..Default::default() };
+//! let mut session = CaptureSession::new(config)?;
+//! let frame = session.capture()?;
+//! std::fs::write("screen.png", &*frame)?;
+//! ```
+
+use std::sync::Arc;
+
+use crate::capture::Backend;
+use crate::encode;
+use crate::error::{CaptureError, RdpStatus, fail};
+use crate::frame::{EncodedFrame, PixelFormat};
+use crate::pixels;
+use crate::session::{RdpSession, SessionConfig};
+use crate::stats::Stats;
+use crate::tiles;
+
+/// A capture session on one display, all displays or one window.
+///
+/// Owns its capturer, so it can be moved to (and used from) another
+/// thread; several sessions capture independently (see `RdpSession`).
+pub struct CaptureSession {
+    session: RdpSession,
+}
+
+impl CaptureSession {
+    /// Opens a session on the primary display with `config`.
+    pub fn new(config: SessionConfig) -> Result<CaptureSession, CaptureError> {
+        CaptureSession::on_display(-1, config)
+    }
+
+    /// Opens a session on `display_index` from `rdp_display_info`'s list,
+    /// -1 for the primary display or `SPAN_ALL` for all of them.
+    pub fn on_display(
+        display_index: i32,
+        config: SessionConfig,
+    ) -> Result<CaptureSession, CaptureError> {
+        CaptureSession::configured(RdpSession::new(display_index), config)
+    }
+
+    /// `on_display` with the capture backend forced to `backend`.
+    pub fn with_backend(
+        backend: Backend,
+        display_index: i32,
+        config: SessionConfig,
+    ) -> Result<CaptureSession, CaptureError> {
+        CaptureSession::configured(RdpSession::with_backend(backend, display_index), config)
+    }
+
+    /// Opens a session on the display with `DisplayInfo::id` `id`.
+    pub fn on_display_id(id: u64, config: SessionConfig) -> Result<CaptureSession, CaptureError> {
+        CaptureSession::configured(RdpSession::with_display_id(id), config)
+    }
+
+    /// Opens a session following the window with `WindowInfo::id` `id`.
+    pub fn on_window(id: u64, config: SessionConfig) -> Result<CaptureSession, CaptureError> {
+        CaptureSession::configured(RdpSession::with_window(id), config)
+    }
+
+    fn configured(
+        opened: Result<RdpSession, RdpStatus>,
+        config: SessionConfig,
+    ) -> Result<CaptureSession, CaptureError> {
+        let mut session = opened?;
+        session.set_config(config)?;
+        Ok(CaptureSession { session })
+    }
+
+    /// Captures and encodes one frame at the configured output size,
+    /// waiting up to the configured timeout for one.
+    pub fn capture(&mut self) -> Result<EncodedFrame, CaptureError> {
+        Ok(self.session.capture(0, 0)?)
+    }
+
+    /// `capture`, resized to `width x height` for this frame only.
+    pub fn capture_at(&mut self, width: u32, height: u32) -> Result<EncodedFrame, CaptureError> {
+        Ok(self.session.capture(width, height)?)
+    }
+
+    /// Polls once for a frame, failing with `CaptureError::WouldBlock` when
+    /// there is none yet.
+    pub fn try_capture(&mut self) -> Result<EncodedFrame, CaptureError> {
+        Ok(self.session.try_capture(0, 0)?)
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        self.session.config()
+    }
+
+    /// Replaces every setting; nothing changes if one is rejected.
+    pub fn set_config(&mut self, config: SessionConfig) -> Result<(), CaptureError> {
+        Ok(self.session.set_config(config)?)
+    }
+
+    /// Makes the next tiled, video or delta frame a keyframe.
+    pub fn request_keyframe(&mut self) {
+        self.session.request_keyframe();
+    }
+
+    /// The session's timings and counters.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.session.stats()
+    }
+
+    /// The underlying session, for what the config does not cover (input
+    /// injection, encryption, recording). Its errors convert into
+    /// `CaptureError` with `From` on the same thread.
+    pub fn session_mut(&mut self) -> &mut RdpSession {
+        &mut self.session
+    }
+
+    pub fn into_session(self) -> RdpSession {
+        self.session
+    }
+}
+
+/// Encodes a tightly packed `width x height` BGRA image the way a session
+/// with `config` encodes a frame once it is resized: same format, quality
+/// and layout, but without tiling, overlays or delta coding. With
+/// `encode_bands` above 1, JPEG, PNG and WebP come back as a keyframe tile
+/// container of bands (see `tiles`). The video formats need a session's
+/// encoder and fail with `InvalidArgument`.
+pub fn encode_bgra(
+    bgra: &[u8],
+    width: u32,
+    height: u32,
+    config: &SessionConfig,
+) -> Result<Vec<u8>, CaptureError> {
+    if bgra.len() as u64 != u64::from(width) * u64::from(height) * 4 {
+        return Err(CaptureError::from(fail(
+            RdpStatus::InvalidArgument,
+            format!("{} bytes is not a {width}x{height} BGRA image", bgra.len()),
+        )));
+    }
+    encode::ensure_supported(config.format)?;
+    let pixel_format = encode::input_format(config);
+    let mut converted = Vec::new();
+    let pixels = if pixel_format == PixelFormat::Bgra {
+        bgra
+    } else {
+        pixels::convert_bgra(bgra, pixel_format, &mut converted);
+        &converted
+    };
+    if tiles::uses_bands(config) {
+        let bpp = pixel_format.bytes_per_pixel();
+        return Ok(tiles::encode_bands(pixels, (width, height), bpp, config)?);
+    }
+    Ok(encode::encode(pixels, width, height, config)?)
+}
@@ -0,0 +1,356 @@
+//! Capturing from async code (`async` feature): futures and a frame stream
+//! over a session whose blocking work runs on threads of its own, so an
+//! executor's workers never wait on a capturer or an encoder.
+//!
+//! Built on `std::future` and `Waker` alone, so it works under any
+//! executor. `FrameStream::poll_next` has the signature of
+//! `futures::Stream::poll_next`; wrap it with `futures::stream::poll_fn`
+//! where a `Stream` is wanted.
+//!
+//! - `capture` hands the request to the session's worker thread, which
+//!   polls the capturer until a frame is ready and encodes it.
+//! - `frames` runs a background stream (see the `stream` module) that
+//!   queues the newest frames for the consumer.
+//!
+//! Dropping a pending `Capture` or a `FrameStream` stops its work within a
+//! poll interval, or once the frame being encoded is done. Dropping the
+//! session ends and joins the worker thread.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::{Duration, Instant};
+
+use crate::api::CaptureSession;
+use crate::error::{CaptureError, RdpStatus, fail, fail_at, guard};
+use crate::frame::EncodedFrame;
+use crate::log::LogLevel;
+use crate::session::{RdpSession, SessionConfig, WAIT_FOREVER};
+use crate::stats::Stats;
+use crate::stream::{DEFAULT_RING_SIZE, POLL_INTERVAL, Sink, Stream};
+
+/// A capture session for async callers. Captures and streams share the
+/// session and take turns on it.
+pub struct AsyncCaptureSession {
+    session: Arc<Mutex<RdpSession>>,
+    /// `None` once dropping has begun.
+    jobs: Option<Sender<Arc<Slot>>>,
+    closed: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl AsyncCaptureSession {
+    /// Opens a session on the primary display, as `CaptureSession::new`.
+    /// Blocks while the display is opened (on Wayland, until the user
+    /// answers the portal's dialog).
+    pub fn new(config: SessionConfig) -> Result<AsyncCaptureSession, CaptureError> {
+        AsyncCaptureSession::from_session(CaptureSession::new(config)?)
+    }
+
+    /// Moves `session` behind a worker thread.
+    pub fn from_session(session: CaptureSession) -> Result<AsyncCaptureSession, CaptureError> {
+        let session = Arc::new(Mutex::new(session.into_session()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (jobs, queue) = mpsc::channel::<Arc<Slot>>();
+
+        let (worker_session, worker_closed) = (Arc::clone(&session), Arc::clone(&closed));
+        let worker = thread::Builder::new()
+            .name("rdp-async".into())
+            .spawn(move || {
+                for slot in queue {
+                    let stopped = || {
+                        slot.cancelled.load(Ordering::Acquire)
+                            || worker_closed.load(Ordering::Acquire)
+                    };
+                    let result = capture_until(&worker_session, stopped);
+                    slot.complete(result);
+                }
+            })
+            .map_err(|e| {
+                CaptureError::from(fail(
+                    RdpStatus::CapturerInitFailed,
+                    format!("Failed to start capture thread: {e}"),
+                ))
+            })?;
+
+        Ok(AsyncCaptureSession {
+            session,
+            jobs: Some(jobs),
+            closed,
+            worker: Some(worker),
+        })
+    }
+
+    /// Captures and encodes one frame at the configured output size. The
+    /// request is queued at once; the future only waits for it. Waits up to
+    /// the session's timeout for a frame, but not for `target_fps`, which
+    /// only paces `frames`.
+    pub fn capture(&self) -> Capture {
+        let slot = Arc::new(Slot::default());
+        let worker = self.worker.as_ref().map(|w| w.thread().clone());
+        let queued = self
+            .jobs
+            .as_ref()
+            .is_some_and(|jobs| jobs.send(Arc::clone(&slot)).is_ok());
+        if !queued {
+            slot.complete(Err(closed_error()));
+        }
+        Capture { slot, worker }
+    }
+
+    /// Streams frames at `fps` (0 = as fast as they come), which becomes
+    /// the session's target rate. The stream keeps the newest few frames
+    /// when the consumer falls behind, skips "no change" captures and ends
+    /// after an error.
+    pub fn frames(&self, fps: u32) -> Result<FrameStream, CaptureError> {
+        let stats = {
+            let mut session = lock(&self.session);
+            session.set_target_fps(fps);
+            session.stats()
+        };
+        let feed = Arc::new(Feed::new(DEFAULT_RING_SIZE, stats));
+        let stream = Stream::start(Arc::clone(&self.session), Sink::Feed(Arc::clone(&feed)))?;
+        Ok(FrameStream {
+            feed,
+            stream: Some(stream),
+        })
+    }
+
+    /// Replaces the session's settings, waiting for a capture in progress.
+    pub fn set_config(&self, config: SessionConfig) -> Result<(), CaptureError> {
+        Ok(lock(&self.session).set_config(config)?)
+    }
+
+    pub fn config(&self) -> SessionConfig {
+        lock(&self.session).config().clone()
+    }
+}
+
+impl Drop for AsyncCaptureSession {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Release);
+        // Ends the worker's loop once the queue is drained
+        self.jobs = None;
+        if let Some(worker) = self.worker.take() {
+            worker.thread().unpark();
+            let _ = worker.join();
+        }
+    }
+}
+
+fn lock(session: &Mutex<RdpSession>) -> MutexGuard<'_, RdpSession> {
+    session.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What a capture that was given up on resolves to, if anything still
+/// waits for it.
+fn closed_error() -> CaptureError {
+    CaptureError::from(fail_at(
+        LogLevel::Debug,
+        RdpStatus::CaptureFailed,
+        "Capture cancelled, or its session closed",
+    ))
+}
+
+/// Polls the capturer until there is a frame, the session's timeout passes
+/// or `stopped` says to give up. The lock is only held for each attempt,
+/// so setters and streams get through in between.
+fn capture_until(
+    session: &Mutex<RdpSession>,
+    stopped: impl Fn() -> bool,
+) -> Result<EncodedFrame, CaptureError> {
+    let timeout_ms = lock(session).config().timeout_ms;
+    let deadline = (timeout_ms != WAIT_FOREVER)
+        .then(|| Instant::now() + Duration::from_millis(u64::from(timeout_ms)));
+    loop {
+        if stopped() {
+            return Err(closed_error());
+        }
+        match guard(Err(RdpStatus::Panic), || lock(session).try_capture(0, 0)) {
+            Err(RdpStatus::WouldBlock) => {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Err(CaptureError::from(fail(
+                        RdpStatus::Timeout,
+                        format!("No frame within {timeout_ms} ms"),
+                    )));
+                }
+                thread::park_timeout(POLL_INTERVAL);
+            }
+            // Converted on this thread, where the message was recorded
+            other => return other.map_err(CaptureError::from),
+        }
+    }
+}
+
+/// Where the worker leaves one capture's result for its future.
+#[derive(Default)]
+struct Slot {
+    state: Mutex<SlotState>,
+    cancelled: AtomicBool,
+}
+
+#[derive(Default)]
+struct SlotState {
+    result: Option<Result<EncodedFrame, CaptureError>>,
+    waker: Option<Waker>,
+}
+
+impl Slot {
+    fn complete(&self, result: Result<EncodedFrame, CaptureError>) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The future of `AsyncCaptureSession::capture`. Dropping it before it
+/// completes cancels the capture.
+pub struct Capture {
+    slot: Arc<Slot>,
+    /// To cut the worker's nap short on cancellation.
+    worker: Option<Thread>,
+}
+
+impl Future for Capture {
+    type Output = Result<EncodedFrame, CaptureError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self
+            .slot
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.slot.cancelled.store(true, Ordering::Release);
+        if let Some(worker) = &self.worker {
+            worker.unpark();
+        }
+    }
+}
+
+/// Frames a `FrameStream` has yet to yield, filled by its stream thread.
+pub struct Feed {
+    state: Mutex<FeedState>,
+    capacity: usize,
+    /// The session's, where dropped frames are counted.
+    stats: Arc<Stats>,
+}
+
+struct FeedState {
+    items: VecDeque<Result<EncodedFrame, CaptureError>>,
+    ended: bool,
+    waker: Option<Waker>,
+}
+
+impl Feed {
+    fn new(capacity: usize, stats: Arc<Stats>) -> Feed {
+        Feed {
+            state: Mutex::new(FeedState {
+                items: VecDeque::with_capacity(capacity),
+                ended: false,
+                waker: None,
+            }),
+            capacity,
+            stats,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FeedState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queues `item`, dropping the oldest frame when the consumer is
+    /// `capacity` behind.
+    pub fn push(&self, item: Result<EncodedFrame, CaptureError>) {
+        let mut state = self.lock();
+        if state.items.len() == self.capacity {
+            state.items.pop_front();
+            self.stats.frames_dropped(1);
+        }
+        state.items.push_back(item);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Marks the stream finished once the queued items are taken.
+    pub fn end(&self) {
+        let mut state = self.lock();
+        state.ended = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The frames of `AsyncCaptureSession::frames`, in capture order. Dropping
+/// it stops the stream thread, waiting for the frame in progress.
+pub struct FrameStream {
+    feed: Arc<Feed>,
+    /// `None` only while dropping.
+    stream: Option<Stream>,
+}
+
+impl FrameStream {
+    /// The next frame; `None` once the stream has ended, which it only
+    /// does after yielding an error.
+    pub fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<EncodedFrame, CaptureError>>> {
+        let mut state = self.feed.lock();
+        match state.items.pop_front() {
+            Some(item) => Poll::Ready(Some(item)),
+            None if state.ended => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// `poll_next` as a future:
+    /// `while let Some(frame) = frames.next_frame().await`.
+    pub fn next_frame(&mut self) -> NextFrame<'_> {
+        NextFrame { stream: self }
+    }
+}
+
+impl Drop for FrameStream {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            stream.stop();
+        }
+    }
+}
+
+/// The future of `FrameStream::next_frame`.
+pub struct NextFrame<'a> {
+    stream: &'a mut FrameStream,
+}
+
+impl Future for NextFrame<'_> {
+    type Output = Option<Result<EncodedFrame, CaptureError>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
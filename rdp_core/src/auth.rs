@@ -0,0 +1,36 @@
+//! The access token the network servers demand, set with
+//! `rdp_server_set_auth_token`. It is read as each client connects, so
+//! changing it affects new connections only; how a client presents it
+//! depends on the protocol (see `http`, `ws` and `tcp`).
+
+use std::hint::black_box;
+use std::sync::{Arc, Mutex, PoisonError};
+
+static TOKEN: Mutex<Option<Arc<str>>> = Mutex::new(None);
+
+/// Requires `token` from every client connecting from now on; `None` lets
+/// anyone in.
+pub fn set_token(token: Option<&str>) {
+    *TOKEN.lock().unwrap_or_else(PoisonError::into_inner) = token.map(Arc::from);
+}
+
+/// The token new clients must present, if any.
+pub fn token() -> Option<Arc<str>> {
+    TOKEN.lock().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+/// Whether `presented` (`None` if the client sent nothing) is `token`.
+pub fn matches(token: &str, presented: Option<&[u8]>) -> bool {
+    presented.is_some_and(|presented| constant_time_eq(token.as_bytes(), presented))
+}
+
+/// Compares without stopping at the first difference, so the time taken
+/// does not tell an attacker how much of a guess was right. Only the
+/// length of `expected` can be learnt.
+fn constant_time_eq(expected: &[u8], presented: &[u8]) -> bool {
+    let mut diff = u8::from(expected.len() != presented.len());
+    for (i, &byte) in expected.iter().enumerate() {
+        diff |= byte ^ presented.get(i).copied().unwrap_or(0);
+    }
+    black_box(diff) == 0
+}
@@ -0,0 +1,38 @@
+//! Standard base64 (RFC 4648, with padding).
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[cfg(feature = "websocket")]
+pub fn encode(bytes: &[u8]) -> String {
+    let mut out = Vec::new();
+    encode_into(bytes, &mut out);
+    // Only ever ASCII
+    String::from_utf8(out).unwrap_or_default()
+}
+
+/// Appends the base64 of `bytes` to `out`, without line breaks.
+pub fn encode_into(bytes: &[u8], out: &mut Vec<u8>) {
+    out.reserve(bytes.len().div_ceil(3) * 4);
+    let mut chunks = bytes.chunks_exact(3);
+    for chunk in &mut chunks {
+        let n = u32::from(chunk[0]) << 16 | u32::from(chunk[1]) << 8 | u32::from(chunk[2]);
+        out.extend_from_slice(&[
+            ALPHABET[(n >> 18) as usize],
+            ALPHABET[(n >> 12 & 0x3f) as usize],
+            ALPHABET[(n >> 6 & 0x3f) as usize],
+            ALPHABET[(n & 0x3f) as usize],
+        ]);
+    }
+    // A tail of k bytes fills k + 1 characters; padding makes up the rest
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let n = u32::from(tail[0]) << 16 | u32::from(*tail.get(1).unwrap_or(&0)) << 8;
+        for i in 0..4 {
+            if i <= tail.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize]);
+            } else {
+                out.push(b'=');
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Fan-out of a stream's frames to network clients. The stream thread
+//! publishes each frame once, into a `FrameQueue` per client, so the
+//! session's queue policy decides whether a slow client skips frames
+//! (counted against it) or holds up the stream; see the `queue` module.
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::frame::EncodedFrame;
+use crate::queue::{ConsumerKind, FrameQueue, QueuePolicy};
+use crate::session::SessionConfig;
+use crate::stats::Stats;
+
+type Queue = FrameQueue<Arc<EncodedFrame>>;
+
+pub struct Broadcast {
+    clients: Mutex<Clients>,
+    kind: ConsumerKind,
+    policy: QueuePolicy,
+    capacity: usize,
+    report_drops: bool,
+    /// The session's, where the client queues are counted.
+    stats: Arc<Stats>,
+}
+
+#[derive(Default)]
+struct Clients {
+    queues: Vec<Arc<Queue>>,
+    /// The newest frame, which a client that subscribes starts with.
+    latest: Option<Arc<EncodedFrame>>,
+    /// Set when the stream ended; no frame follows.
+    closed: bool,
+}
+
+impl Broadcast {
+    /// A broadcast to `kind` clients, queued as `config` says.
+    pub fn new(kind: ConsumerKind, config: &SessionConfig, stats: Arc<Stats>) -> Broadcast {
+        Broadcast {
+            clients: Mutex::default(),
+            kind,
+            policy: config.queue_policy,
+            capacity: config.queue_capacity as usize,
+            report_drops: config.report_drops,
+            stats,
+        }
+    }
+
+    /// Queues `frame` for every client. Under `QueuePolicy::Block` this
+    /// waits for each in turn to have room, or for `give_up`.
+    pub fn publish(&self, frame: EncodedFrame, give_up: impl Fn() -> bool) {
+        let frame = Arc::new(frame);
+        // Pushed outside the lock, so a blocked push does not keep clients
+        // from leaving
+        let queues = {
+            let mut clients = self.lock();
+            clients.latest = Some(Arc::clone(&frame));
+            clients.queues.clone()
+        };
+        for queue in queues {
+            queue.push(Arc::clone(&frame), &give_up);
+        }
+    }
+
+    /// A new client's queue, holding the newest frame so far so the client
+    /// need not wait for the next one. Already closed if the broadcast is.
+    pub fn subscribe(&self) -> Subscription<'_> {
+        let queue = Arc::new(FrameQueue::new(
+            self.kind,
+            self.policy,
+            self.capacity,
+            &self.stats,
+        ));
+        let mut clients = self.lock();
+        if clients.closed {
+            queue.close();
+        } else {
+            if let Some(latest) = &clients.latest {
+                queue.push(Arc::clone(latest), || true);
+            }
+            clients.queues.push(Arc::clone(&queue));
+        }
+        Subscription {
+            broadcast: self,
+            queue,
+        }
+    }
+
+    /// Whether clients are told how many frames they missed.
+    pub fn reports_drops(&self) -> bool {
+        self.report_drops
+    }
+
+    /// Ends the broadcast, waking every waiting client and releasing a
+    /// blocked `publish`.
+    pub fn close(&self) {
+        let mut clients = self.lock();
+        clients.closed = true;
+        for queue in &clients.queues {
+            queue.close();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Clients> {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// One client's place in a `Broadcast`; leaving is dropping it.
+pub struct Subscription<'a> {
+    broadcast: &'a Broadcast,
+    queue: Arc<Queue>,
+}
+
+impl Subscription<'_> {
+    /// Blocks until the next frame for this client. `None` once the
+    /// broadcast is closed.
+    pub fn next(&self) -> Option<Arc<EncodedFrame>> {
+        self.queue.pop_unless(|| false)
+    }
+
+    /// `next`, also returning `None` once `give_up` is true; it is checked
+    /// whenever the caller is woken, including by `wake`.
+    #[cfg(feature = "websocket")]
+    pub fn next_unless(&self, give_up: impl Fn() -> bool) -> Option<Arc<EncodedFrame>> {
+        self.queue.pop_unless(give_up)
+    }
+
+    /// Wakes a waiting `next_unless` so it re-checks its `give_up`
+    /// condition.
+    #[cfg(feature = "websocket")]
+    pub fn wake(&self) {
+        self.queue.wake();
+    }
+
+    /// Frames this client has missed so far.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped_total()
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        self.broadcast
+            .lock()
+            .queues
+            .retain(|queue| !Arc::ptr_eq(queue, &self.queue));
+        // Releases a publish blocked on this client
+        self.queue.close();
+    }
+}
@@ -0,0 +1,327 @@
+//! The screen capture backends behind a session, each a `FrameSource`:
+//!
+//! - `Native`: scrap (X11, DXGI, Quartz), opened on a display index, or
+//!   on every display at once by `span::Span`.
+//! - `Wayland`: a screencast negotiated through xdg-desktop-portal and
+//!   streamed over PipeWire (experimental `wayland` feature, Linux only).
+//!   The user picks the monitor in the portal's dialog; X11 cannot see a
+//!   Wayland desktop.
+//! - `Test`: generated frames (`pattern::TestPatternSource`), so the
+//!   pipeline runs without any display; never picked by `Auto`.
+
+use std::io;
+use std::ops::Deref;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use scrap::Display;
+
+use crate::error::{RdpStatus, fail};
+use crate::pattern::TestPatternSource;
+#[cfg(all(feature = "wayland", target_os = "linux"))]
+use crate::wayland;
+
+/// Capture backends, as chosen with `rdp_set_capture_backend` or
+/// `rdp_session_new_with_backend` and reported in `RdpStats::backend`.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Wayland when the process runs in a Wayland session
+    /// (`WAYLAND_DISPLAY` is set) and the backend is compiled in, otherwise
+    /// native.
+    Auto = 0,
+    /// scrap: X11, DXGI desktop duplication or Quartz.
+    Native = 1,
+    /// xdg-desktop-portal and PipeWire (experimental `wayland` feature).
+    Wayland = 2,
+    /// Generated test frames, as set with `rdp_set_test_pattern`.
+    Test = 3,
+}
+
+impl Backend {
+    /// Maps an FFI backend value back onto the enum.
+    pub fn from_i32(value: i32) -> Option<Backend> {
+        match value {
+            0 => Some(Backend::Auto),
+            1 => Some(Backend::Native),
+            2 => Some(Backend::Wayland),
+            3 => Some(Backend::Test),
+            _ => None,
+        }
+    }
+
+    /// Whether this build includes the backend.
+    pub fn is_compiled(self) -> bool {
+        match self {
+            Backend::Auto | Backend::Native | Backend::Test => true,
+            Backend::Wayland => cfg!(all(feature = "wayland", target_os = "linux")),
+        }
+    }
+
+    /// Why the backend cannot work in this process's environment, if it
+    /// cannot: X11 and Wayland both need their display server.
+    fn missing(self) -> Option<&'static str> {
+        let set = |name| std::env::var_os(name).is_some_and(|v| !v.is_empty());
+        match self {
+            Backend::Native if cfg!(all(unix, not(target_os = "macos"))) && !set("DISPLAY") => {
+                Some("DISPLAY is not set")
+            }
+            Backend::Wayland if !set("WAYLAND_DISPLAY") => Some("WAYLAND_DISPLAY is not set"),
+            _ => None,
+        }
+    }
+}
+
+/// Bit `1 << backend` set for every backend compiled into this build.
+pub fn compiled() -> u32 {
+    [Backend::Native, Backend::Wayland, Backend::Test]
+        .into_iter()
+        .filter(|backend| backend.is_compiled())
+        .fold(0, |mask, backend| mask | 1 << backend as u32)
+}
+
+/// The backend `Auto` stands for, as set by `set_backend`.
+static BACKEND: Mutex<Backend> = Mutex::new(Backend::Auto);
+
+/// Chooses the backend for sessions opened from now on without one of
+/// their own. Fails with `RdpStatus::BackendUnavailable` for one that is
+/// not compiled in.
+pub fn set_backend(backend: Backend) -> Result<(), RdpStatus> {
+    if !backend.is_compiled() {
+        return Err(not_compiled(backend));
+    }
+    *BACKEND.lock().unwrap_or_else(PoisonError::into_inner) = backend;
+    Ok(())
+}
+
+/// The backend to open for `requested`: never `Auto`. Fails with
+/// `RdpStatus::BackendUnavailable` when a backend asked for explicitly is
+/// not compiled in or cannot work here.
+pub fn resolve(requested: Backend) -> Result<Backend, RdpStatus> {
+    let requested = match requested {
+        Backend::Auto => *BACKEND.lock().unwrap_or_else(PoisonError::into_inner),
+        backend => backend,
+    };
+    match requested {
+        Backend::Auto if Backend::Wayland.is_compiled() && Backend::Wayland.missing().is_none() => {
+            Ok(Backend::Wayland)
+        }
+        Backend::Auto => Ok(Backend::Native),
+        backend if !backend.is_compiled() => Err(not_compiled(backend)),
+        backend => match backend.missing() {
+            Some(reason) => Err(fail(
+                RdpStatus::BackendUnavailable,
+                format!("{backend:?} capture is not available: {reason}"),
+            )),
+            None => Ok(backend),
+        },
+    }
+}
+
+fn not_compiled(backend: Backend) -> RdpStatus {
+    fail(
+        RdpStatus::BackendUnavailable,
+        format!("{backend:?} capture is not compiled into this build"),
+    )
+}
+
+/// What the session pipeline captures from. Every backend hands out BGRA
+/// frames of `width() x height()` pixels, rows possibly padded at the end,
+/// so everything from the crop on is shared.
+pub trait FrameSource {
+    fn backend(&self) -> Backend;
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    /// The next frame, or `WouldBlock` while there is none. A source that
+    /// lost its display fails with `ConnectionReset` or another kind
+    /// `is_display_lost` recognizes, so the session reopens it.
+    fn frame(&mut self) -> io::Result<Frame<'_>>;
+
+    /// Whether `frame_within` waits for the OS to signal a new frame
+    /// rather than polling once.
+    fn signals(&self) -> bool {
+        false
+    }
+
+    /// `frame`, but waiting up to `timeout` for a new frame when the
+    /// backend `signals`.
+    fn frame_within(&mut self, timeout: Duration) -> io::Result<Frame<'_>> {
+        let _ = timeout;
+        self.frame()
+    }
+}
+
+#[cfg(not(windows))]
+impl FrameSource for scrap::Capturer {
+    fn backend(&self) -> Backend {
+        Backend::Native
+    }
+
+    fn width(&self) -> usize {
+        scrap::Capturer::width(self)
+    }
+
+    fn height(&self) -> usize {
+        scrap::Capturer::height(self)
+    }
+
+    fn frame(&mut self) -> io::Result<Frame<'_>> {
+        scrap::Capturer::frame(self).map(Frame::Native)
+    }
+}
+
+/// DXGI desktop duplication, driven directly rather than through
+/// `scrap::Capturer`, which always asks `AcquireNextFrame` for a frame
+/// without waiting.
+#[cfg(windows)]
+pub struct Dxgi {
+    inner: scrap::dxgi::Capturer,
+    width: usize,
+    height: usize,
+}
+
+#[cfg(windows)]
+impl FrameSource for Dxgi {
+    fn backend(&self) -> Backend {
+        Backend::Native
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn frame(&mut self) -> io::Result<Frame<'_>> {
+        self.frame_within(Duration::ZERO)
+    }
+
+    fn signals(&self) -> bool {
+        true
+    }
+
+    fn frame_within(&mut self, timeout: Duration) -> io::Result<Frame<'_>> {
+        let timeout_ms = timeout.as_millis().min(u128::from(u32::MAX - 1)) as u32;
+        match self.inner.frame(timeout_ms) {
+            Ok(frame) => Ok(Frame::Borrowed(frame)),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(io::ErrorKind::WouldBlock.into()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(all(feature = "wayland", target_os = "linux"))]
+impl FrameSource for wayland::Capturer {
+    fn backend(&self) -> Backend {
+        Backend::Wayland
+    }
+
+    fn width(&self) -> usize {
+        wayland::Capturer::width(self)
+    }
+
+    fn height(&self) -> usize {
+        wayland::Capturer::height(self)
+    }
+
+    fn frame(&mut self) -> io::Result<Frame<'_>> {
+        wayland::Capturer::frame(self).map(Frame::Borrowed)
+    }
+}
+
+/// Opens scrap on `display`, the one at `index` in `Display::all()` (-1
+/// for the primary).
+#[cfg(not(windows))]
+pub fn native(display: Display, index: i32) -> Result<Box<dyn FrameSource>, RdpStatus> {
+    let _ = index;
+    match scrap::Capturer::new(display) {
+        Ok(capturer) => Ok(Box::new(capturer)),
+        Err(e) => Err(init_failed(e)),
+    }
+}
+
+/// Opens desktop duplication on `display`, the one at `index` in
+/// `Display::all()` (-1 for the primary, which DXGI lists first). scrap's
+/// `Display` does not expose its DXGI output, so the output is looked up
+/// again by index, in the same enumeration `Display::all()` makes.
+#[cfg(windows)]
+pub fn native(display: Display, index: i32) -> Result<Box<dyn FrameSource>, RdpStatus> {
+    let output = scrap::dxgi::Displays::new()
+        .map_err(init_failed)?
+        .nth(index.max(0) as usize)
+        .ok_or_else(|| init_failed(io::ErrorKind::NotFound.into()))?;
+    drop(display);
+    let (width, height) = (output.width() as usize, output.height() as usize);
+    match scrap::dxgi::Capturer::new(&output) {
+        Ok(inner) => Ok(Box::new(Dxgi {
+            inner,
+            width,
+            height,
+        })),
+        Err(e) => Err(init_failed(e)),
+    }
+}
+
+fn init_failed(e: io::Error) -> RdpStatus {
+    fail(
+        RdpStatus::CapturerInitFailed,
+        format!("Failed to create capturer: {e}"),
+    )
+}
+
+/// Starts a portal screencast, which may wait on the user for a while.
+/// `restore_token` carries the user's choice of monitor from one call to
+/// the next (a session reopening its stream), so it is asked for once; it
+/// belongs to one session, as others may share other monitors.
+pub fn wayland(restore_token: &mut Option<String>) -> Result<Box<dyn FrameSource>, RdpStatus> {
+    #[cfg(all(feature = "wayland", target_os = "linux"))]
+    {
+        let capturer = wayland::Capturer::open(restore_token.as_deref())?;
+        if let Some(token) = capturer.restore_token() {
+            *restore_token = Some(token.to_owned());
+        }
+        Ok(Box::new(capturer))
+    }
+    #[cfg(not(all(feature = "wayland", target_os = "linux")))]
+    {
+        let _ = restore_token;
+        Err(not_compiled(Backend::Wayland))
+    }
+}
+
+/// Opens whichever backend `backend` names (never `Auto`), on `display`
+/// (at `index`, see `native`) for native capture.
+pub fn open(
+    backend: Backend,
+    index: i32,
+    display: impl FnOnce() -> Result<Display, RdpStatus>,
+    restore_token: &mut Option<String>,
+) -> Result<Box<dyn FrameSource>, RdpStatus> {
+    match backend {
+        Backend::Wayland => wayland(restore_token),
+        Backend::Test => Ok(Box::new(TestPatternSource::open())),
+        _ => native(display()?, index),
+    }
+}
+
+/// A captured frame: BGRA rows, possibly padded at the end.
+pub enum Frame<'a> {
+    /// Windows capture borrows DXGI's mapping directly instead.
+    #[cfg(not(windows))]
+    Native(scrap::Frame<'a>),
+    Borrowed(&'a [u8]),
+}
+
+impl Deref for Frame<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(not(windows))]
+            Frame::Native(frame) => frame,
+            Frame::Borrowed(pixels) => pixels,
+        }
+    }
+}
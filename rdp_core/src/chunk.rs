@@ -0,0 +1,282 @@
+//! Frames cut into chunks that each fit one datagram, for senders on
+//! MTU-limited transports such as UDP. Every chunk is a 16-byte
+//! little-endian header followed by a slice of the frame:
+//!
+//! ```text
+//! u64 sequence       RawImage::sequence of the frame
+//! u16 index          position of the chunk in the frame, from 0
+//! u16 count          chunks the frame was cut into
+//! u32 checksum       CRC-32 (IEEE) of this chunk's payload
+//! [u8]               the payload
+//! ```
+//!
+//! (Python: `struct.unpack("<QHHI", chunk[:16])`.) The payloads, joined in
+//! index order, are the frame's data; chunks may arrive in any order, and a
+//! receiver drops a frame with a missing or damaged chunk. The header only
+//! identifies the frame: format, size and the rest of the metadata travel
+//! however the application sends them. The layout is fixed; anything added
+//! later gets a new function.
+//!
+//! Chunks are as full as the MTU allows, except that a JPEG with restart
+//! markers (see `restart`) can be cut at markers instead, so each chunk
+//! starts one and a lost chunk damages only the bands inside it.
+
+use crate::error::{RdpStatus, fail};
+use crate::restart;
+
+/// Bytes of header in front of each chunk's payload.
+pub const HEADER_LEN: usize = 16;
+
+/// A frame's chunks, handed to FFI callers by `rdp_frame_chunks`.
+pub struct ChunkSet {
+    chunks: Vec<Vec<u8>>,
+}
+
+impl ChunkSet {
+    pub fn new(chunks: Vec<Vec<u8>>) -> ChunkSet {
+        ChunkSet { chunks }
+    }
+
+    pub fn chunks(&self) -> &[Vec<u8>] {
+        &self.chunks
+    }
+}
+
+/// Cuts frame `sequence`'s `data` into chunks of at most `mtu` bytes,
+/// headers included. With `align_to_restarts` and a JPEG `data` that has
+/// restart markers, each chunk ends just before the last marker that fits,
+/// where one does. Fails with `RdpStatus::InvalidArgument` if `mtu` leaves
+/// no room for a payload or the frame would take more than 65535 chunks.
+pub fn split(
+    data: &[u8],
+    sequence: u64,
+    mtu: usize,
+    align_to_restarts: bool,
+) -> Result<Vec<Vec<u8>>, RdpStatus> {
+    let Some(room) = mtu.checked_sub(HEADER_LEN).filter(|&room| room > 0) else {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            format!("An MTU of {mtu} bytes leaves no room after the {HEADER_LEN}-byte header"),
+        ));
+    };
+    let markers = if align_to_restarts {
+        restart::markers(data)
+    } else {
+        Vec::new()
+    };
+
+    let mut bounds = vec![0];
+    let mut start = 0;
+    while start < data.len() {
+        let limit = data.len().min(start + room);
+        // The furthest marker past `start` that keeps the chunk in the MTU
+        let at = markers.partition_point(|&m| m <= limit);
+        let end = match markers[..at].last() {
+            Some(&marker) if marker > start && limit < data.len() => marker,
+            _ => limit,
+        };
+        bounds.push(end);
+        start = end;
+    }
+    // An empty frame is still a chunk
+    if bounds.len() == 1 {
+        bounds.push(0);
+    }
+
+    let count = u16::try_from(bounds.len() - 1).map_err(|_| {
+        fail(
+            RdpStatus::InvalidArgument,
+            format!(
+                "The {}-byte frame needs more than 65535 chunks of {mtu} bytes",
+                data.len()
+            ),
+        )
+    })?;
+    Ok(bounds
+        .windows(2)
+        .enumerate()
+        .map(|(index, pair)| {
+            let payload = &data[pair[0]..pair[1]];
+            let mut chunk = Vec::with_capacity(HEADER_LEN + payload.len());
+            chunk.extend_from_slice(&sequence.to_le_bytes());
+            chunk.extend_from_slice(&(index as u16).to_le_bytes());
+            chunk.extend_from_slice(&count.to_le_bytes());
+            chunk.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+            chunk.extend_from_slice(payload);
+            chunk
+        })
+        .collect())
+}
+
+/// Joins the chunks of one frame, in any order, back into its sequence
+/// number and data. Fails with `RdpStatus::InvalidArgument` when a chunk is
+/// truncated or damaged, belongs to another frame, or is missing or
+/// repeated.
+pub fn reassemble<'a>(
+    chunks: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<(u64, Vec<u8>), RdpStatus> {
+    let invalid = |what: String| fail(RdpStatus::InvalidArgument, what);
+
+    let mut frame: Option<(u64, Vec<Option<&[u8]>>)> = None;
+    for chunk in chunks {
+        let Some((header, payload)) = chunk.split_first_chunk::<HEADER_LEN>() else {
+            return Err(invalid(format!(
+                "A {}-byte chunk is shorter than its header",
+                chunk.len()
+            )));
+        };
+        let sequence = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let index = usize::from(u16::from_le_bytes([header[8], header[9]]));
+        let count = usize::from(u16::from_le_bytes([header[10], header[11]]));
+        let checksum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        if crc32fast::hash(payload) != checksum {
+            return Err(invalid(format!(
+                "Chunk {index} of frame {sequence} is damaged"
+            )));
+        }
+
+        let (expected, slots) = frame.get_or_insert_with(|| (sequence, vec![None; count]));
+        if sequence != *expected || count != slots.len() || index >= count {
+            return Err(invalid(format!(
+                "Chunk {index}/{count} of frame {sequence} does not belong with frame \
+                 {expected}'s {} chunks",
+                slots.len()
+            )));
+        }
+        if slots[index].replace(payload).is_some() {
+            return Err(invalid(format!(
+                "Chunk {index} of frame {sequence} was given twice"
+            )));
+        }
+    }
+
+    let Some((sequence, slots)) = frame else {
+        return Err(invalid("No chunks to reassemble".into()));
+    };
+    if let Some(missing) = slots.iter().position(Option::is_none) {
+        return Err(invalid(format!(
+            "Chunk {missing} of frame {sequence}'s {} is missing",
+            slots.len()
+        )));
+    }
+    Ok((
+        sequence,
+        slots.into_iter().flatten().flatten().copied().collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, Subsampling};
+    use crate::frame::FrameFormat;
+    use crate::pixels;
+    use crate::session::SessionConfig;
+
+    fn frame(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn refused(chunks: &[Vec<u8>]) -> bool {
+        reassemble(chunks.iter().map(Vec::as_slice)) == Err(RdpStatus::InvalidArgument)
+    }
+
+    #[test]
+    fn chunks_round_trip_in_any_order() {
+        let data = frame(10_000);
+        let chunks = split(&data, 42, 1200, false).unwrap();
+        assert_eq!(chunks.len(), 9);
+        for (index, chunk) in chunks.iter().enumerate() {
+            assert!(chunk.len() <= 1200);
+            assert_eq!(chunk[..8], 42u64.to_le_bytes());
+            assert_eq!(chunk[8..10], (index as u16).to_le_bytes());
+            assert_eq!(chunk[10..12], 9u16.to_le_bytes());
+        }
+        let reversed = chunks.iter().rev().map(Vec::as_slice);
+        assert_eq!(reassemble(reversed), Ok((42, data)));
+
+        // An empty frame is still one chunk
+        let chunks = split(&[], 1, 1200, false).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            reassemble(chunks.iter().map(Vec::as_slice)),
+            Ok((1, Vec::new()))
+        );
+    }
+
+    #[test]
+    fn missing_repeated_and_damaged_chunks_are_refused() {
+        let chunks = split(&frame(5000), 3, 1000, false).unwrap();
+        assert!(refused(&chunks[1..]), "missing");
+        assert!(refused(&[&chunks[..], &chunks[2..3]].concat()), "repeated");
+
+        let mut damaged = chunks.clone();
+        *damaged[2].last_mut().unwrap() ^= 1;
+        assert!(refused(&damaged), "damaged");
+
+        let other = split(&frame(5000), 4, 1000, false).unwrap();
+        assert!(
+            refused(&[&chunks[..5], &other[5..]].concat()),
+            "mixed frames"
+        );
+        assert!(
+            refused(&[chunks[0][..HEADER_LEN - 1].to_vec()]),
+            "truncated"
+        );
+        assert!(refused(&[]), "empty");
+    }
+
+    #[test]
+    fn an_mtu_must_leave_room_for_a_payload() {
+        for mtu in [0, HEADER_LEN] {
+            assert_eq!(split(b"x", 0, mtu, false), Err(RdpStatus::InvalidArgument));
+        }
+        assert_eq!(
+            split(&frame(65536), 0, HEADER_LEN + 1, false),
+            Err(RdpStatus::InvalidArgument)
+        );
+    }
+
+    /// Aligned chunks start at restart markers and pack in as many bands
+    /// as fit; the frame still comes back whole.
+    #[test]
+    fn aligned_chunks_start_at_restart_markers() {
+        let (width, height) = (160, 160);
+        let config = SessionConfig {
+            format: FrameFormat::Jpeg,
+            restart_rows: 1,
+            // 8-pixel MCU rows, with either JPEG encoder
+            subsampling: Subsampling::Yuv444,
+            ..SessionConfig::default()
+        };
+        let bgra: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x + y) as u8, (x * 3) as u8, ((y * 5) ^ x) as u8, 0xff]
+            })
+            .collect();
+        let mut input = Vec::new();
+        pixels::convert_bgra(&bgra, encode::input_format(&config), &mut input);
+        let jpeg = encode::encode(&input, width, height, &config).unwrap();
+        let markers = restart::markers(&jpeg);
+        assert_eq!(
+            markers.len(),
+            19,
+            "a marker between each of the 20 MCU rows"
+        );
+
+        // Room for the largest stretch between markers, so every cut can
+        // fall on one
+        let stretches = [&[0][..], &markers, &[jpeg.len()]].concat();
+        let widest = stretches.windows(2).map(|w| w[1] - w[0]).max().unwrap();
+        let chunks = split(&jpeg, 5, HEADER_LEN + widest, true).unwrap();
+        assert!(chunks.len() < markers.len(), "chunks hold several bands");
+
+        let mut at = 0;
+        for chunk in &chunks[..chunks.len() - 1] {
+            at += chunk.len() - HEADER_LEN;
+            assert!(markers.contains(&at), "a chunk ends at {at}, not a marker");
+        }
+        assert_eq!(reassemble(chunks.iter().map(Vec::as_slice)), Ok((5, jpeg)));
+    }
+}
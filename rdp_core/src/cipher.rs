@@ -0,0 +1,211 @@
+//! AES-256-GCM encryption of frame payloads, built with the `encryption`
+//! feature (through `ring`). With a key set on a
+//! session, every frame it produces carries
+//!
+//! ```text
+//! [u8; 12]  nonce
+//! [u8]      ciphertext, as long as the plaintext payload
+//! [u8; 16]  GCM tag
+//! ```
+//!
+//! instead of its payload, and is flagged `RawImage::encrypted`. Only the
+//! payload is encrypted and authenticated; the metadata travels in the
+//! clear. Nonces are 4 random bytes, drawn whenever a key is set, followed
+//! by a big-endian count of the frames the session has encrypted, so no
+//! nonce repeats within a session even when the same key is set again.
+
+use crate::error::{RdpStatus, fail};
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+/// A session's key, if encryption is on, and nonce counter.
+#[derive(Default)]
+pub struct FrameCipher {
+    key: Option<[u8; KEY_LEN]>,
+    prefix: [u8; 4],
+    /// Nonces used so far; never reset, whatever the key.
+    counter: u64,
+}
+
+impl FrameCipher {
+    /// Encrypts frames sealed from now on under `key`, or stops encrypting
+    /// with `None`.
+    pub fn set_key(&mut self, key: Option<&[u8]>) -> Result<(), RdpStatus> {
+        let Some(key) = key else {
+            self.wipe();
+            return Ok(());
+        };
+        let key: [u8; KEY_LEN] = key.try_into().map_err(|_| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Encryption key is {} bytes, not {KEY_LEN}", key.len()),
+            )
+        })?;
+        random_bytes(&mut self.prefix)?;
+        self.wipe();
+        self.key = Some(key);
+        Ok(())
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// `plaintext` encrypted under the next nonce, in the layout above.
+    /// Only called with a key set.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, RdpStatus> {
+        let Some(key) = &self.key else {
+            return Err(fail(RdpStatus::InvalidArgument, "No encryption key set"));
+        };
+        let counter = self.counter;
+        self.counter = counter.checked_add(1).ok_or_else(|| {
+            fail(
+                RdpStatus::EncodeFailed,
+                "Encryption nonces exhausted; reopen the session",
+            )
+        })?;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..4].copy_from_slice(&self.prefix);
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        seal(key, &nonce, plaintext)
+    }
+
+    fn wipe(&mut self) {
+        if let Some(key) = &mut self.key {
+            // Volatile so the wipe is not optimized away as a dead store
+            for byte in key.iter_mut() {
+                unsafe { std::ptr::write_volatile(byte, 0) };
+            }
+        }
+        self.key = None;
+    }
+}
+
+impl Drop for FrameCipher {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+/// The payload of a frame sealed with `key`; fails with
+/// `RdpStatus::InvalidArgument` when it is truncated, was sealed with
+/// another key or has been tampered with.
+pub fn open(key: &[u8; KEY_LEN], sealed: &[u8]) -> Result<Vec<u8>, RdpStatus> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            format!(
+                "Encrypted frame of {} bytes is shorter than its nonce and tag",
+                sealed.len()
+            ),
+        ));
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    decrypt(key, nonce, ciphertext, tag)
+}
+
+#[cfg(feature = "encryption")]
+fn seal(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, RdpStatus> {
+    use ring::aead::{Aad, Nonce};
+
+    let failed = || fail(RdpStatus::EncodeFailed, "AES-GCM encryption failed");
+    let mut out = Vec::with_capacity(NONCE_LEN + plaintext.len() + TAG_LEN);
+    out.extend_from_slice(nonce);
+    out.extend_from_slice(plaintext);
+    let tag = aead_key(key)?
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(*nonce),
+            Aad::empty(),
+            &mut out[NONCE_LEN..],
+        )
+        .map_err(|_| failed())?;
+    out.extend_from_slice(tag.as_ref());
+    Ok(out)
+}
+
+#[cfg(feature = "encryption")]
+fn decrypt(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8],
+    ciphertext: &[u8],
+    tag: &[u8],
+) -> Result<Vec<u8>, RdpStatus> {
+    use ring::aead::{Aad, Nonce};
+
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| fail(RdpStatus::InvalidArgument, "Malformed nonce"))?;
+    // ring opens the ciphertext and its tag together, in place
+    let mut out = [ciphertext, tag].concat();
+    let len = aead_key(key)?
+        .open_in_place(nonce, Aad::empty(), &mut out)
+        .map_err(|_| {
+            fail(
+                RdpStatus::InvalidArgument,
+                "Encrypted frame does not authenticate (wrong key or corrupted data)",
+            )
+        })?
+        .len();
+    out.truncate(len);
+    Ok(out)
+}
+
+#[cfg(feature = "encryption")]
+fn aead_key(key: &[u8; KEY_LEN]) -> Result<ring::aead::LessSafeKey, RdpStatus> {
+    use ring::aead::{AES_256_GCM, LessSafeKey, UnboundKey};
+
+    match UnboundKey::new(&AES_256_GCM, key) {
+        Ok(key) => Ok(LessSafeKey::new(key)),
+        Err(_) => Err(fail(RdpStatus::EncodeFailed, "AES-256-GCM is unavailable")),
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn random_bytes(buf: &mut [u8]) -> Result<(), RdpStatus> {
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    SystemRandom::new().fill(buf).map_err(|_| {
+        fail(
+            RdpStatus::EncodeFailed,
+            "Failed to draw random bytes for the nonce",
+        )
+    })
+}
+
+#[cfg(not(feature = "encryption"))]
+fn seal(
+    _key: &[u8; KEY_LEN],
+    _nonce: &[u8; NONCE_LEN],
+    _plaintext: &[u8],
+) -> Result<Vec<u8>, RdpStatus> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn decrypt(
+    _key: &[u8; KEY_LEN],
+    _nonce: &[u8],
+    _ciphertext: &[u8],
+    _tag: &[u8],
+) -> Result<Vec<u8>, RdpStatus> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn random_bytes(_buf: &mut [u8]) -> Result<(), RdpStatus> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn unsupported() -> RdpStatus {
+    fail(
+        RdpStatus::Unsupported,
+        "Frame encryption needs the `encryption` feature",
+    )
+}
@@ -0,0 +1,154 @@
+//! Plain-text clipboard access for copy/paste between viewer and host,
+//! built with the `clipboard` feature through `arboard`.
+//!
+//! Text crosses the FFI as UTF-8. arboard talks to the Win32 clipboard,
+//! `NSPasteboard`, and the X11 `CLIPBOARD` selection; Wayland sessions
+//! without XWayland have no X server to talk to and report
+//! `RdpStatus::ClipboardUnavailable`, as do headless sessions.
+
+use crate::error::RdpStatus;
+
+/// Current clipboard text; empty when the clipboard holds no text.
+pub fn get_text() -> Result<String, RdpStatus> {
+    platform::get_text()
+}
+
+/// Replaces the clipboard contents with `text`.
+pub fn set_text(text: &str) -> Result<(), RdpStatus> {
+    platform::set_text(text)
+}
+
+/// A counter that changes whenever the clipboard does, including after our
+/// own `set_text`; 0 if the clipboard cannot be reached.
+pub fn generation() -> u64 {
+    platform::generation()
+}
+
+#[cfg(feature = "clipboard")]
+mod platform {
+    use std::sync::{Mutex, PoisonError};
+
+    use arboard::{Clipboard, Error};
+
+    use crate::error::{RdpStatus, fail};
+
+    /// Opened on first use and kept: on X11 it is also what serves text we
+    /// set to other clients, until one of them takes the clipboard over.
+    static CLIPBOARD: Mutex<Option<Clipboard>> = Mutex::new(None);
+
+    /// Runs `f` on the shared clipboard, opening it first if need be.
+    fn with_clipboard<T>(
+        f: impl FnOnce(&mut Clipboard) -> Result<T, Error>,
+    ) -> Result<T, RdpStatus> {
+        let mut clipboard = CLIPBOARD.lock().unwrap_or_else(PoisonError::into_inner);
+        let clipboard = match &mut *clipboard {
+            Some(clipboard) => clipboard,
+            empty @ None => empty.insert(Clipboard::new().map_err(unavailable)?),
+        };
+        f(clipboard).map_err(unavailable)
+    }
+
+    fn unavailable(e: Error) -> RdpStatus {
+        fail(
+            RdpStatus::ClipboardUnavailable,
+            format!("Clipboard unavailable: {e}"),
+        )
+    }
+
+    pub fn get_text() -> Result<String, RdpStatus> {
+        with_clipboard(|clipboard| match clipboard.get_text() {
+            // Empty, or holding something other than text
+            Err(Error::ContentNotAvailable) => Ok(String::new()),
+            text => text,
+        })
+    }
+
+    pub fn set_text(text: &str) -> Result<(), RdpStatus> {
+        with_clipboard(|clipboard| clipboard.set_text(text))
+    }
+
+    /// `GetClipboardSequenceNumber`, bumped by every writer.
+    #[cfg(windows)]
+    pub fn generation() -> u64 {
+        clipboard_win::seq_num().map_or(0, |n| u64::from(n.get()))
+    }
+
+    /// `NSPasteboard.changeCount`, bumped by every writer.
+    #[cfg(target_os = "macos")]
+    pub fn generation() -> u64 {
+        use objc2_app_kit::NSPasteboard;
+        NSPasteboard::generalPasteboard().changeCount() as u64
+    }
+
+    /// X11 has no change counter without XFixes, so this hashes the text;
+    /// it costs one selection round trip per call.
+    #[cfg(not(any(windows, target_os = "macos")))]
+    pub fn generation() -> u64 {
+        /// Last content hash seen and the count of changes.
+        static GENERATION: Mutex<(Option<u64>, u64)> = Mutex::new((None, 0));
+
+        let key = get_text()
+            .ok()
+            .map(|text| crate::pixels::frame_hash(text.as_bytes(), &[]));
+        let mut state = GENERATION.lock().unwrap_or_else(PoisonError::into_inner);
+        if key.is_some() && key != state.0 {
+            state.0 = key;
+            state.1 += 1;
+        }
+        state.1
+    }
+}
+
+#[cfg(not(feature = "clipboard"))]
+mod platform {
+    use crate::error::{RdpStatus, fail};
+
+    pub fn get_text() -> Result<String, RdpStatus> {
+        Err(unsupported())
+    }
+
+    pub fn set_text(_text: &str) -> Result<(), RdpStatus> {
+        Err(unsupported())
+    }
+
+    pub fn generation() -> u64 {
+        0
+    }
+
+    fn unsupported() -> RdpStatus {
+        fail(
+            RdpStatus::Unsupported,
+            "The clipboard needs the `clipboard` feature",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whichever way the build and the session go, every call answers with
+    /// a status instead of hanging or panicking, and a round trip through a
+    /// reachable clipboard keeps the text and moves the generation.
+    #[test]
+    fn text_round_trips_or_reports_why_not() {
+        let before = generation();
+        let text = "rdp clipboard \u{2713} test";
+        match set_text(text) {
+            Ok(()) => {
+                assert_eq!(get_text().as_deref(), Ok(text));
+                assert_ne!(generation(), before, "setting moved the generation");
+            }
+            Err(status) => {
+                let expected = if cfg!(feature = "clipboard") {
+                    RdpStatus::ClipboardUnavailable
+                } else {
+                    RdpStatus::Unsupported
+                };
+                assert_eq!(status, expected);
+                assert_eq!(get_text(), Err(expected));
+                assert_eq!(generation(), 0);
+            }
+        }
+    }
+}
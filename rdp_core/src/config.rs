@@ -0,0 +1,942 @@
+//! `RdpConfig`: every session setting in one versioned `#[repr(C)]`
+//! struct, for `rdp_session_new_with_config` and
+//! `capture_and_encode_with_config`, so new settings stop growing
+//! parameter lists.
+//!
+//! The struct only ever grows at the end, and `struct_size` says how much
+//! of it the caller knows:
+//!
+//! - A caller built against an older, shorter struct gets the defaults for
+//!   the fields it does not have.
+//! - A caller built against a newer, longer struct may run on this library
+//!   as long as the fields it does not know are zero, which is what
+//!   `rdp_config_default` and a zeroed struct leave them at. Anything else
+//!   asks for a setting this library cannot honour and fails with
+//!   `RdpStatus::InvalidConfigSize`.
+//!
+//! It also reads and writes the same settings, and those `RdpConfig` has
+//! no room for, as a JSON object for `rdp_session_new_json` and
+//! `rdp_session_get_config_json`.
+
+use std::mem;
+use std::ptr;
+
+use fast_image_resize as fr;
+use image::codecs::png::CompressionType;
+
+use crate::capture::Backend;
+use crate::display::SPAN_ALL;
+use crate::encode::Subsampling;
+use crate::error::{RdpStatus, fail};
+use crate::focus::{self, Focus};
+use crate::frame::{FrameFormat, PixelFormat};
+use crate::json::{self, Value};
+use crate::log::{self, LogLevel};
+use crate::orient::Orientation;
+use crate::overlay::{Anchor, TextOverlay};
+use crate::pace::WaitStrategy;
+use crate::pixels::Rect;
+use crate::priority::ThreadPriority;
+use crate::queue::{self, QueuePolicy};
+use crate::scale::{self, FitMode};
+use crate::session::{
+    DEFAULT_QUALITY, DEFAULT_RECOVERY_TIMEOUT_MS, MAX_DOWNSCALE_STEP, MAX_ENCODE_BANDS,
+    MIN_TILE_SIZE, RdpSession, SessionConfig, WAIT_FOREVER,
+};
+use crate::yuv::YuvMatrix;
+use crate::zstd;
+
+/// Session settings, as filled in by `rdp_config_default`.
+///
+/// Numeric values are those of the matching `rdp_session_set_*` function,
+/// booleans are 0 or 1.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RdpConfig {
+    /// `sizeof(RdpConfig)` as the caller was compiled; set it before
+    /// anything else.
+    pub struct_size: u32,
+    /// As for `rdp_session_new`: -1 for the primary display (default),
+    /// `SPAN_ALL` (-2) for all of them.
+    pub display_index: i32,
+    /// Size every frame is resized to; both 0 (default) keeps the captured
+    /// size, subject to `scale` and `max_dim`.
+    pub target_w: u32,
+    pub target_h: u32,
+    /// `RawImage::format` value; 0 = JPEG (default).
+    pub format: u32,
+    /// `RawImage::pixel_format` value; 0 = BGRA (default).
+    pub pixel_format: u32,
+    /// JPEG and WebP quality, 1–100 (default 70).
+    pub quality: u32,
+    /// 0 = 4:4:4, 1 = 4:2:2, 2 = 4:2:0 (default), 3 = grayscale.
+    pub subsampling: i32,
+    /// 0 = stretch (default), 1 = fit, 2 = fill.
+    pub fit_mode: u32,
+    /// `0xRRGGBB` padding of `fit_mode` 1 (default black).
+    pub fill_color: u32,
+    /// 0 = nearest (default), 1 = bilinear, 2 = Catmull-Rom, 3 = Lanczos3.
+    pub resize_alg: u32,
+    /// 0 = as captured (default) to 5, as for
+    /// `rdp_session_set_orientation`.
+    pub orientation: u32,
+    /// Output size as a multiple of the captured size (default 1.0);
+    /// values above 1 upscale.
+    pub scale: f32,
+    /// Cap on the long edge of the output; 0 (default) for none.
+    pub max_dim: u32,
+    /// How long a capture waits for a frame; `UINT32_MAX` (default) waits
+    /// forever, 0 polls once.
+    pub timeout_ms: u32,
+    /// How long a lost display is retried (default 10 minutes).
+    pub recovery_timeout_ms: u32,
+    /// Rate captures are paced to; 0 (default) leaves them unpaced.
+    pub fps: u32,
+    /// As for `rdp_set_capture_backend`; 0 (default) chooses automatically.
+    pub backend: i32,
+    pub include_cursor: u8,
+    pub detect_changes: u8,
+    pub track_dirty: u8,
+    pub capture_logical_size: u8,
+    /// Non-zero for progressive JPEG, as for
+    /// `rdp_session_set_progressive`.
+    pub progressive: u8,
+    /// MCU rows between JPEG restart markers, as for
+    /// `rdp_session_set_restart_interval`; 0 (default) for none.
+    pub restart_rows: u32,
+    /// As for `rdp_session_set_queue_policy`: 0 = latest wins (default),
+    /// 1 = block.
+    pub queue_policy: u32,
+    /// Frames each network client's queue holds, 1 (default) to 16; 0
+    /// means the default.
+    pub queue_capacity: u32,
+    /// Non-zero to tell network clients about the frames they missed, as
+    /// for `rdp_session_set_drop_reports`.
+    pub report_drops: u8,
+    /// Stream thread priority, as for `rdp_session_set_thread_priority`:
+    /// 0 = normal (default), 1 = above normal, 2 = time critical.
+    pub thread_priority: u32,
+    /// Cores stream threads are pinned to, as for
+    /// `rdp_session_set_thread_affinity`; 0 (default) for none.
+    pub thread_affinity: u64,
+}
+
+/// Size of the first version of `RdpConfig`, the least a caller may pass.
+pub const MIN_SIZE: u32 = 76;
+
+impl Default for RdpConfig {
+    fn default() -> RdpConfig {
+        RdpConfig {
+            struct_size: mem::size_of::<RdpConfig>() as u32,
+            display_index: -1,
+            target_w: 0,
+            target_h: 0,
+            format: FrameFormat::Jpeg as u32,
+            pixel_format: PixelFormat::Bgra as u32,
+            quality: u32::from(DEFAULT_QUALITY),
+            subsampling: 2,
+            fit_mode: FitMode::Stretch as u32,
+            fill_color: 0x000000,
+            resize_alg: 0,
+            orientation: Orientation::Normal as u32,
+            scale: 1.0,
+            max_dim: 0,
+            timeout_ms: WAIT_FOREVER,
+            recovery_timeout_ms: DEFAULT_RECOVERY_TIMEOUT_MS,
+            fps: 0,
+            backend: Backend::Auto as i32,
+            include_cursor: 0,
+            detect_changes: 0,
+            track_dirty: 0,
+            capture_logical_size: 0,
+            progressive: 0,
+            restart_rows: 0,
+            queue_policy: QueuePolicy::LatestWins as u32,
+            queue_capacity: queue::DEFAULT_CAPACITY as u32,
+            report_drops: 0,
+            thread_priority: ThreadPriority::Normal as u32,
+            thread_affinity: 0,
+        }
+    }
+}
+
+/// The caller's `struct_size`, checked against `MIN_SIZE`.
+///
+/// # Safety
+/// `config` must be valid for reading its first four bytes.
+unsafe fn size_of_callers(config: *const RdpConfig) -> Result<usize, RdpStatus> {
+    let size = unsafe { ptr::addr_of!((*config).struct_size).read_unaligned() };
+    if size < MIN_SIZE {
+        return Err(fail(
+            RdpStatus::InvalidConfigSize,
+            format!("RdpConfig::struct_size is {size}, at least {MIN_SIZE} expected"),
+        ));
+    }
+    Ok(size as usize)
+}
+
+/// Fills the caller's struct with the defaults, as far as this library
+/// knows its fields; `struct_size` and any later fields stay as they are.
+///
+/// # Safety
+/// `config` must be valid for writes of its `struct_size` bytes.
+pub unsafe fn write_default(config: *mut RdpConfig) -> Result<(), RdpStatus> {
+    let size = unsafe { size_of_callers(config) }?;
+    let defaults = RdpConfig {
+        struct_size: size as u32,
+        ..RdpConfig::default()
+    };
+    let known = size.min(mem::size_of::<RdpConfig>());
+    unsafe {
+        ptr::copy_nonoverlapping(
+            ptr::addr_of!(defaults).cast::<u8>(),
+            config.cast::<u8>(),
+            known,
+        )
+    };
+    Ok(())
+}
+
+/// Reads the caller's struct: defaults for fields it does not have, and
+/// `RdpStatus::InvalidConfigSize` if it sets fields this library does not
+/// have.
+///
+/// # Safety
+/// `config` must be valid for reads of its `struct_size` bytes.
+pub unsafe fn read(config: *const RdpConfig) -> Result<RdpConfig, RdpStatus> {
+    let size = unsafe { size_of_callers(config) }?;
+    let ours = mem::size_of::<RdpConfig>();
+    let mut read = RdpConfig::default();
+    unsafe {
+        ptr::copy_nonoverlapping(
+            config.cast::<u8>(),
+            ptr::addr_of_mut!(read).cast::<u8>(),
+            size.min(ours),
+        )
+    };
+    if size > ours {
+        let unknown =
+            unsafe { std::slice::from_raw_parts(config.cast::<u8>().add(ours), size - ours) };
+        if let Some(offset) = unknown.iter().position(|&b| b != 0) {
+            return Err(fail(
+                RdpStatus::InvalidConfigSize,
+                format!(
+                    "RdpConfig sets byte {} of {size}, past the {ours} this library knows",
+                    ours + offset
+                ),
+            ));
+        }
+    }
+    read.struct_size = ours as u32;
+    Ok(read)
+}
+
+impl RdpConfig {
+    /// Opens the session the config describes and applies its settings.
+    pub fn open(&self) -> Result<RdpSession, RdpStatus> {
+        let (backend, display_index, config) = self.validate()?;
+        let mut session = RdpSession::with_backend(backend, display_index)?;
+        session.set_config(config)?;
+        Ok(session)
+    }
+
+    /// Checks every field, failing with the status naming the first bad
+    /// one, and splits the result into what opens the session and what
+    /// configures it.
+    fn validate(&self) -> Result<(Backend, i32, SessionConfig), RdpStatus> {
+        let backend = Backend::from_i32(self.backend).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidBackend,
+                format!("Unknown capture backend {}", self.backend),
+            )
+        })?;
+        if self.display_index < SPAN_ALL {
+            return Err(fail(
+                RdpStatus::InvalidDisplayIndex,
+                format!("Invalid display index {}", self.display_index),
+            ));
+        }
+        if (self.target_w == 0) != (self.target_h == 0) {
+            return Err(fail(
+                RdpStatus::InvalidTargetSize,
+                format!(
+                    "Target size {}x{} must be set or cleared on both axes",
+                    self.target_w, self.target_h
+                ),
+            ));
+        }
+        if !(1..=100).contains(&self.quality) {
+            return Err(fail(
+                RdpStatus::InvalidQuality,
+                format!("Quality {} is outside 1–100", self.quality),
+            ));
+        }
+        let subsampling = Subsampling::from_i32(self.subsampling).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidSubsampling,
+                format!("Unknown subsampling {}", self.subsampling),
+            )
+        })?;
+        let format = FrameFormat::from_u32(self.format)
+            .filter(|f| {
+                !matches!(
+                    f,
+                    FrameFormat::TiledKeyframe | FrameFormat::TiledDelta | FrameFormat::Text
+                )
+            })
+            .ok_or_else(|| {
+                fail(
+                    RdpStatus::InvalidFormat,
+                    format!("Unknown output format {}", self.format),
+                )
+            })?;
+        let pixel_format = PixelFormat::from_u32(self.pixel_format).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidPixelFormat,
+                format!("Unknown pixel format {}", self.pixel_format),
+            )
+        })?;
+        let fit = FitMode::from_u32(self.fit_mode).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidFitMode,
+                format!("Unknown fit mode {}", self.fit_mode),
+            )
+        })?;
+        let resize_alg = scale::resize_alg_from_u32(self.resize_alg).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidResizeAlg,
+                format!("Unknown resize algorithm {}", self.resize_alg),
+            )
+        })?;
+        let orientation = Orientation::from_u32(self.orientation).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidOrientation,
+                format!("Unknown orientation {}", self.orientation),
+            )
+        })?;
+        let queue_policy = QueuePolicy::from_u32(self.queue_policy).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown queue policy {}", self.queue_policy),
+            )
+        })?;
+        if self.queue_capacity as usize > queue::MAX_CAPACITY {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!(
+                    "Queue capacity {} exceeds {}",
+                    self.queue_capacity,
+                    queue::MAX_CAPACITY
+                ),
+            ));
+        }
+        let thread_priority = ThreadPriority::from_u32(self.thread_priority).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown thread priority {}", self.thread_priority),
+            )
+        })?;
+        // Written so NaN fails as well
+        if !(self.scale > 0.0 && self.scale.is_finite()) {
+            return Err(fail(
+                RdpStatus::InvalidScale,
+                format!("Scale {} is not a positive number", self.scale),
+            ));
+        }
+
+        let config = SessionConfig {
+            format,
+            pixel_format,
+            grayscale: pixel_format == PixelFormat::Gray,
+            quality: self.quality as u8,
+            subsampling,
+            progressive: self.progressive != 0,
+            restart_rows: self.restart_rows,
+            output_size: (self.target_w, self.target_h),
+            scale: self.scale,
+            max_dim: self.max_dim,
+            resize_alg,
+            fit,
+            fill_color: self.fill_color,
+            orientation,
+            capture_logical_size: self.capture_logical_size != 0,
+            timeout_ms: self.timeout_ms,
+            recovery_timeout_ms: self.recovery_timeout_ms,
+            detect_changes: self.detect_changes != 0,
+            track_dirty: self.track_dirty != 0,
+            include_cursor: self.include_cursor != 0,
+            target_fps: self.fps,
+            queue_policy,
+            queue_capacity: self.queue_capacity.max(1),
+            report_drops: self.report_drops != 0,
+            thread_priority,
+            thread_affinity: self.thread_affinity,
+            ..SessionConfig::default()
+        };
+        Ok((backend, self.display_index, config))
+    }
+}
+
+// JSON configs. Keys are the `SessionConfig` fields, plus `display` and
+// `backend`, which pick what the session opens; enums are given by name.
+
+const BACKENDS: &[(&str, Backend)] = &[
+    ("auto", Backend::Auto),
+    ("native", Backend::Native),
+    ("wayland", Backend::Wayland),
+    ("test", Backend::Test),
+];
+
+const SUBSAMPLINGS: &[(&str, Subsampling)] = &[
+    ("4:4:4", Subsampling::Yuv444),
+    ("4:2:2", Subsampling::Yuv422),
+    ("4:2:0", Subsampling::Yuv420),
+    ("gray", Subsampling::Gray),
+];
+
+const PNG_COMPRESSIONS: &[(&str, CompressionType)] = &[
+    ("fast", CompressionType::Fast),
+    ("default", CompressionType::Default),
+    ("best", CompressionType::Best),
+];
+
+const FIT_MODES: &[(&str, FitMode)] = &[
+    ("stretch", FitMode::Stretch),
+    ("fit", FitMode::Fit),
+    ("fill", FitMode::Fill),
+];
+
+const ORIENTATIONS: &[(&str, Orientation)] = &[
+    ("normal", Orientation::Normal),
+    ("rotate90", Orientation::Rotate90),
+    ("rotate180", Orientation::Rotate180),
+    ("rotate270", Orientation::Rotate270),
+    ("flip_h", Orientation::FlipH),
+    ("flip_v", Orientation::FlipV),
+];
+
+const ANCHORS: &[(&str, Anchor)] = &[
+    ("top_left", Anchor::TopLeft),
+    ("top_right", Anchor::TopRight),
+    ("bottom_left", Anchor::BottomLeft),
+    ("bottom_right", Anchor::BottomRight),
+    ("center", Anchor::Center),
+];
+
+const YUV_MATRICES: &[(&str, YuvMatrix)] =
+    &[("bt601", YuvMatrix::Bt601), ("bt709", YuvMatrix::Bt709)];
+
+const QUEUE_POLICIES: &[(&str, QueuePolicy)] = &[
+    ("latest_wins", QueuePolicy::LatestWins),
+    ("block", QueuePolicy::Block),
+];
+
+const THREAD_PRIORITIES: &[(&str, ThreadPriority)] = &[
+    ("normal", ThreadPriority::Normal),
+    ("above_normal", ThreadPriority::AboveNormal),
+    ("time_critical", ThreadPriority::TimeCritical),
+];
+
+/// In the order of `scale::resize_alg_from_u32`.
+const RESIZE_ALGS: &[&str] = &["nearest", "bilinear", "catmull_rom", "lanczos3"];
+
+/// What a JSON config opens and how the session is set up.
+pub struct JsonConfig {
+    pub backend: Backend,
+    pub display_index: i32,
+    pub config: SessionConfig,
+}
+
+impl JsonConfig {
+    /// Opens the session the config describes and applies its settings.
+    pub fn open(self) -> Result<RdpSession, RdpStatus> {
+        let mut session = RdpSession::with_backend(self.backend, self.display_index)?;
+        session.set_config(self.config)?;
+        Ok(session)
+    }
+}
+
+/// Parses a JSON config object. Keys left out keep their defaults; unknown
+/// keys are logged as warnings and skipped. A value of the wrong type or
+/// out of range fails with `RdpStatus::InvalidArgument`, naming the key.
+pub fn from_json(text: &str) -> Result<JsonConfig, RdpStatus> {
+    let document = json::parse(text).map_err(|e| {
+        fail(
+            RdpStatus::InvalidArgument,
+            format!("Config is not valid JSON: {e}"),
+        )
+    })?;
+    let Value::Object(members) = document else {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            "Config must be a JSON object",
+        ));
+    };
+
+    let mut parsed = JsonConfig {
+        backend: Backend::Auto,
+        display_index: -1,
+        config: SessionConfig::default(),
+    };
+    let mut target = (0, 0);
+    let config = &mut parsed.config;
+    for (key, value) in &members {
+        let key = key.as_str();
+        match key {
+            "display" => {
+                parsed.display_index = integer(value)
+                    .and_then(|index| i32::try_from(index).ok().filter(|&i| i >= SPAN_ALL))
+                    .ok_or_else(|| invalid(key, "a display index, -1 or -2"))?;
+            }
+            "backend" => parsed.backend = named(key, value, BACKENDS)?,
+            "format" => {
+                config.format = value
+                    .as_str()
+                    .and_then(FrameFormat::from_name)
+                    .ok_or_else(|| invalid(key, "an output format name such as \"jpeg\""))?;
+            }
+            "pixel_format" => {
+                config.pixel_format = value
+                    .as_str()
+                    .and_then(PixelFormat::from_name)
+                    .ok_or_else(|| invalid(key, "a pixel format name such as \"bgra\""))?;
+            }
+            "grayscale" => config.grayscale = boolean(key, value)?,
+            "quality" => config.quality = quality(key, value)?,
+            "subsampling" => config.subsampling = named(key, value, SUBSAMPLINGS)?,
+            "progressive" => config.progressive = boolean(key, value)?,
+            "restart_rows" => config.restart_rows = unsigned(key, value)?,
+            "png_compression" => config.png_compression = named(key, value, PNG_COMPRESSIONS)?,
+            "target_w" => target.0 = unsigned(key, value)?,
+            "target_h" => target.1 = unsigned(key, value)?,
+            "scale" => {
+                config.scale = value
+                    .as_f64()
+                    .map(|n| n as f32)
+                    .filter(|&n| n > 0.0 && n.is_finite())
+                    .ok_or_else(|| invalid(key, "a positive number"))?;
+            }
+            "max_dim" => config.max_dim = unsigned(key, value)?,
+            "downscale_only" => config.downscale_only = boolean(key, value)?,
+            "resize_alg" => {
+                config.resize_alg = value
+                    .as_str()
+                    .and_then(|name| RESIZE_ALGS.iter().position(|&n| n == name))
+                    .and_then(|i| scale::resize_alg_from_u32(i as u32))
+                    .ok_or_else(|| invalid(key, &one_of(RESIZE_ALGS)))?;
+            }
+            "fit_mode" => config.fit = named(key, value, FIT_MODES)?,
+            "fill_color" => config.fill_color = color(key, value)?,
+            "region" => {
+                config.region = match value {
+                    Value::Null => None,
+                    _ => Some(rect(key, value)?),
+                };
+            }
+            "orientation" => config.orientation = named(key, value, ORIENTATIONS)?,
+            "capture_logical_size" => config.capture_logical_size = boolean(key, value)?,
+            "blackout" => {
+                let Value::Array(items) = value else {
+                    return Err(invalid(key, "an array of rectangles"));
+                };
+                config.blackout = items
+                    .iter()
+                    .map(|item| rect(key, item))
+                    .collect::<Result<_, _>>()?;
+            }
+            "blackout_color" => config.blackout_color = color(key, value)?,
+            "watermark" => config.watermark = watermark(key, value)?,
+            "overlay_timestamp" => config.overlay_timestamp = boolean(key, value)?,
+            "timeout_ms" => config.timeout_ms = unsigned(key, value)?,
+            "recovery_timeout_ms" => config.recovery_timeout_ms = unsigned(key, value)?,
+            "detect_changes" => config.detect_changes = boolean(key, value)?,
+            "track_dirty" => config.track_dirty = boolean(key, value)?,
+            "tile_size" => {
+                config.tile_size = unsigned(key, value)?;
+                if config.tile_size != 0 && config.tile_size < MIN_TILE_SIZE {
+                    return Err(invalid(key, &format!("0 or at least {MIN_TILE_SIZE}")));
+                }
+            }
+            "keyframe_interval" => config.keyframe_interval = unsigned(key, value)?,
+            "encode_bands" => {
+                config.encode_bands = unsigned(key, value)?;
+                if config.encode_bands > MAX_ENCODE_BANDS {
+                    return Err(invalid(key, &format!("at most {MAX_ENCODE_BANDS}")));
+                }
+            }
+            "include_cursor" => config.include_cursor = boolean(key, value)?,
+            "focus" => config.focus = focus(key, value)?,
+            "fps" => config.target_fps = unsigned(key, value)?,
+            "target_frame_bytes" => config.target_frame_bytes = unsigned(key, value)?,
+            "min_quality" => config.min_quality = quality(key, value)?,
+            "max_quality" => config.max_quality = quality(key, value)?,
+            "budget_downscale_step" => {
+                config.budget_downscale_step = value
+                    .as_f64()
+                    .map(|n| n as f32)
+                    .filter(|n| (0.0..=MAX_DOWNSCALE_STEP).contains(n))
+                    .ok_or_else(|| {
+                        invalid(key, &format!("a number from 0 to {MAX_DOWNSCALE_STEP}"))
+                    })?;
+            }
+            "bitrate_kbps" => config.bitrate_kbps = unsigned(key, value)?,
+            "zstd_level" => {
+                config.zstd_level = integer(value)
+                    .and_then(|level| i32::try_from(level).ok())
+                    .filter(|level| (zstd::MIN_LEVEL..=zstd::MAX_LEVEL).contains(level))
+                    .ok_or_else(|| {
+                        invalid(
+                            key,
+                            &format!("an integer from {} to {}", zstd::MIN_LEVEL, zstd::MAX_LEVEL),
+                        )
+                    })?;
+            }
+            "zstd_delta" => config.zstd_delta = boolean(key, value)?,
+            "yuv_matrix" => config.yuv_matrix = named(key, value, YUV_MATRICES)?,
+            "wait_strategy" => config.wait_strategy = wait_strategy(key, value)?,
+            "queue_policy" => config.queue_policy = named(key, value, QUEUE_POLICIES)?,
+            "queue_capacity" => {
+                config.queue_capacity = unsigned(key, value)?;
+                if !(1..=queue::MAX_CAPACITY as u32).contains(&config.queue_capacity) {
+                    return Err(invalid(
+                        key,
+                        &format!("an integer from 1 to {}", queue::MAX_CAPACITY),
+                    ));
+                }
+            }
+            "report_drops" => config.report_drops = boolean(key, value)?,
+            "thread_priority" => config.thread_priority = named(key, value, THREAD_PRIORITIES)?,
+            "thread_affinity" => config.thread_affinity = cores(key, value)?,
+            _ => log::log(
+                LogLevel::Warn,
+                &format!("Ignoring unknown config key \"{key}\""),
+            ),
+        }
+    }
+
+    if (target.0 == 0) != (target.1 == 0) {
+        let key = if target.0 == 0 {
+            "target_w"
+        } else {
+            "target_h"
+        };
+        return Err(invalid(key, "set together with the other target axis"));
+    }
+    if config.min_quality > config.max_quality {
+        return Err(invalid("min_quality", "at most max_quality"));
+    }
+    config.output_size = target;
+    Ok(parsed)
+}
+
+/// The effective settings of `session` as a JSON object, in the form
+/// `from_json` reads.
+pub fn to_json(session: &RdpSession) -> String {
+    let config = session.config();
+    let resize_alg = match config.resize_alg {
+        fr::ResizeAlg::Nearest => Some(0),
+        fr::ResizeAlg::Convolution(fr::FilterType::Bilinear) => Some(1),
+        fr::ResizeAlg::Convolution(fr::FilterType::CatmullRom) => Some(2),
+        fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3) => Some(3),
+        _ => None,
+    };
+    let number = |n: u32| Value::Number(f64::from(n));
+    let rect = |r: &Rect| {
+        Value::Object(vec![
+            ("x".into(), number(r.x)),
+            ("y".into(), number(r.y)),
+            ("w".into(), number(r.w)),
+            ("h".into(), number(r.h)),
+        ])
+    };
+    let members: Vec<(&str, Value)> = vec![
+        ("display", Value::Number(f64::from(session.display_index()))),
+        ("backend", name_of(BACKENDS, session.backend())),
+        ("format", Value::String(config.format.name().into())),
+        (
+            "pixel_format",
+            Value::String(config.pixel_format.name().into()),
+        ),
+        ("grayscale", Value::Bool(config.grayscale)),
+        ("quality", number(config.quality.into())),
+        ("subsampling", name_of(SUBSAMPLINGS, config.subsampling)),
+        ("progressive", Value::Bool(config.progressive)),
+        ("restart_rows", number(config.restart_rows)),
+        (
+            "png_compression",
+            name_of(PNG_COMPRESSIONS, config.png_compression),
+        ),
+        ("target_w", number(config.output_size.0)),
+        ("target_h", number(config.output_size.1)),
+        ("scale", float(config.scale)),
+        ("max_dim", number(config.max_dim)),
+        ("downscale_only", Value::Bool(config.downscale_only)),
+        (
+            "resize_alg",
+            resize_alg.map_or(Value::Null, |i| Value::String(RESIZE_ALGS[i].into())),
+        ),
+        ("fit_mode", name_of(FIT_MODES, config.fit)),
+        ("fill_color", number(config.fill_color)),
+        ("region", config.region.as_ref().map_or(Value::Null, rect)),
+        ("orientation", name_of(ORIENTATIONS, config.orientation)),
+        (
+            "capture_logical_size",
+            Value::Bool(config.capture_logical_size),
+        ),
+        (
+            "blackout",
+            Value::Array(config.blackout.iter().map(rect).collect()),
+        ),
+        ("blackout_color", number(config.blackout_color)),
+        (
+            "watermark",
+            config.watermark.as_ref().map_or(Value::Null, |w| {
+                Value::Object(vec![
+                    ("text".into(), Value::String(w.text.clone())),
+                    ("anchor".into(), name_of(ANCHORS, w.anchor)),
+                    ("opacity".into(), number(w.opacity.into())),
+                ])
+            }),
+        ),
+        ("overlay_timestamp", Value::Bool(config.overlay_timestamp)),
+        ("timeout_ms", number(config.timeout_ms)),
+        ("recovery_timeout_ms", number(config.recovery_timeout_ms)),
+        ("detect_changes", Value::Bool(config.detect_changes)),
+        ("track_dirty", Value::Bool(config.track_dirty)),
+        ("tile_size", number(config.tile_size)),
+        ("keyframe_interval", number(config.keyframe_interval)),
+        ("encode_bands", number(config.encode_bands)),
+        ("include_cursor", Value::Bool(config.include_cursor)),
+        (
+            "focus",
+            config.focus.map_or(Value::Null, |f| {
+                Value::Object(vec![
+                    ("width".into(), number(f.width)),
+                    ("height".into(), number(f.height)),
+                    ("quality".into(), number(f.quality.into())),
+                    ("subsampling".into(), name_of(SUBSAMPLINGS, f.subsampling)),
+                ])
+            }),
+        ),
+        ("fps", number(config.target_fps)),
+        ("target_frame_bytes", number(config.target_frame_bytes)),
+        ("min_quality", number(config.min_quality.into())),
+        ("max_quality", number(config.max_quality.into())),
+        ("budget_downscale_step", float(config.budget_downscale_step)),
+        ("bitrate_kbps", number(config.bitrate_kbps)),
+        ("zstd_level", Value::Number(config.zstd_level.into())),
+        ("zstd_delta", Value::Bool(config.zstd_delta)),
+        ("yuv_matrix", name_of(YUV_MATRICES, config.yuv_matrix)),
+        (
+            "wait_strategy",
+            match config.wait_strategy {
+                WaitStrategy::Backoff => Value::String("backoff".into()),
+                WaitStrategy::Yield => Value::String("yield".into()),
+                WaitStrategy::Sleep { ms } => Value::Object(vec![("sleep_ms".into(), number(ms))]),
+                WaitStrategy::SpinThenSleep { spin_us, sleep_ms } => Value::Object(vec![
+                    ("spin_us".into(), number(spin_us)),
+                    ("sleep_ms".into(), number(sleep_ms)),
+                ]),
+                WaitStrategy::Signal { ms } => {
+                    Value::Object(vec![("signal_ms".into(), number(ms))])
+                }
+            },
+        ),
+        ("queue_policy", name_of(QUEUE_POLICIES, config.queue_policy)),
+        ("queue_capacity", number(config.queue_capacity)),
+        ("report_drops", Value::Bool(config.report_drops)),
+        (
+            "thread_priority",
+            name_of(THREAD_PRIORITIES, config.thread_priority),
+        ),
+        (
+            "thread_affinity",
+            Value::Array(
+                (0..u64::BITS)
+                    .filter(|core| config.thread_affinity & 1 << core != 0)
+                    .map(number)
+                    .collect(),
+            ),
+        ),
+    ];
+    Value::Object(members.into_iter().map(|(k, v)| (k.into(), v)).collect()).to_string()
+}
+
+/// The `InvalidArgument` failure for `key`, whose value should have been
+/// `expected`.
+fn invalid(key: &str, expected: &str) -> RdpStatus {
+    fail(
+        RdpStatus::InvalidArgument,
+        format!("Config key \"{key}\" must be {expected}"),
+    )
+}
+
+fn one_of(names: &[&str]) -> String {
+    let quoted: Vec<String> = names.iter().map(|n| format!("\"{n}\"")).collect();
+    format!("one of {}", quoted.join(", "))
+}
+
+fn named<T: Copy>(key: &str, value: &Value, table: &[(&str, T)]) -> Result<T, RdpStatus> {
+    value
+        .as_str()
+        .and_then(|name| table.iter().find(|(n, _)| *n == name))
+        .map(|&(_, v)| v)
+        .ok_or_else(|| {
+            let names: Vec<&str> = table.iter().map(|&(n, _)| n).collect();
+            invalid(key, &one_of(&names))
+        })
+}
+
+fn name_of<T: PartialEq>(table: &[(&str, T)], value: T) -> Value {
+    table
+        .iter()
+        .find(|(_, v)| *v == value)
+        .map_or(Value::Null, |(n, _)| Value::String((*n).into()))
+}
+
+/// `n` with the digits it was written with (0.7 rather than the
+/// 0.699999988 its `f64` widening prints as).
+fn float(n: f32) -> Value {
+    Value::Number(n.to_string().parse().unwrap_or_default())
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool, RdpStatus> {
+    value.as_bool().ok_or_else(|| invalid(key, "true or false"))
+}
+
+fn unsigned(key: &str, value: &Value) -> Result<u32, RdpStatus> {
+    value
+        .as_u32()
+        .ok_or_else(|| invalid(key, "a non-negative integer"))
+}
+
+/// A whole number, positive or not.
+fn integer(value: &Value) -> Option<i64> {
+    value
+        .as_f64()
+        .filter(|n| n.fract() == 0.0 && n.abs() <= f64::from(u32::MAX))
+        .map(|n| n as i64)
+}
+
+fn quality(key: &str, value: &Value) -> Result<u8, RdpStatus> {
+    value
+        .as_u32()
+        .filter(|q| (1..=100).contains(q))
+        .map(|q| q as u8)
+        .ok_or_else(|| invalid(key, "an integer from 1 to 100"))
+}
+
+fn color(key: &str, value: &Value) -> Result<u32, RdpStatus> {
+    value
+        .as_u32()
+        .filter(|&c| c <= 0xff_ffff)
+        .ok_or_else(|| invalid(key, "a 0xRRGGBB colour from 0 to 16777215"))
+}
+
+/// An `{"x", "y", "w", "h"}` object with a non-zero area.
+fn rect(key: &str, value: &Value) -> Result<Rect, RdpStatus> {
+    let field = |name| value.get(name).and_then(Value::as_u32);
+    match (field("x"), field("y"), field("w"), field("h")) {
+        (Some(x), Some(y), Some(w), Some(h)) if w > 0 && h > 0 => Ok(Rect { x, y, w, h }),
+        _ => Err(invalid(
+            key,
+            "an object of non-negative integers x, y, w and h, with w and h above 0",
+        )),
+    }
+}
+
+/// `{"text", "anchor", "opacity"}`, with the anchor defaulting to top left
+/// and the opacity to 255; null or empty text for none.
+fn watermark(key: &str, value: &Value) -> Result<Option<TextOverlay>, RdpStatus> {
+    if *value == Value::Null {
+        return Ok(None);
+    }
+    let Some(text) = value.get("text").and_then(Value::as_str) else {
+        return Err(invalid(key, "null or an object with a \"text\" string"));
+    };
+    let anchor = match value.get("anchor") {
+        Some(anchor) => named(&format!("{key}.anchor"), anchor, ANCHORS)?,
+        None => Anchor::TopLeft,
+    };
+    let opacity = match value.get("opacity") {
+        Some(opacity) => opacity
+            .as_u32()
+            .filter(|&o| o <= 255)
+            .ok_or_else(|| invalid(&format!("{key}.opacity"), "an integer from 0 to 255"))?
+            as u8,
+        None => 255,
+    };
+    Ok((!text.is_empty()).then(|| TextOverlay {
+        text: text.to_owned(),
+        anchor,
+        opacity,
+    }))
+}
+
+/// `{"width", "height", "quality", "subsampling"}`, with the quality
+/// defaulting to 90 and the subsampling to 4:4:4; null for none.
+fn focus(key: &str, value: &Value) -> Result<Option<Focus>, RdpStatus> {
+    if *value == Value::Null {
+        return Ok(None);
+    }
+    let size = |name| value.get(name).and_then(Value::as_u32).filter(|&n| n > 0);
+    let (Some(width), Some(height)) = (size("width"), size("height")) else {
+        return Err(invalid(
+            key,
+            "null or an object with integer \"width\" and \"height\" above 0",
+        ));
+    };
+    let quality = match value.get("quality") {
+        Some(q) => quality(&format!("{key}.quality"), q)?,
+        None => focus::DEFAULT_QUALITY,
+    };
+    let subsampling = match value.get("subsampling") {
+        Some(s) => named(&format!("{key}.subsampling"), s, SUBSAMPLINGS)?,
+        None => Subsampling::Yuv444,
+    };
+    Ok(Some(Focus {
+        width,
+        height,
+        quality,
+        subsampling,
+    }))
+}
+
+/// An array of core numbers, 0 to 63, as a mask with their bits set.
+fn cores(key: &str, value: &Value) -> Result<u64, RdpStatus> {
+    let expected = || invalid(key, "an array of core numbers from 0 to 63");
+    let Value::Array(items) = value else {
+        return Err(expected());
+    };
+    items.iter().try_fold(0u64, |mask, item| {
+        item.as_u32()
+            .filter(|&core| core < u64::BITS)
+            .map(|core| mask | 1 << core)
+            .ok_or_else(expected)
+    })
+}
+
+/// `"backoff"`, `"yield"`, `{"sleep_ms"}` to sleep a fixed time,
+/// `{"spin_us", "sleep_ms"}` to spin before sleeping, or `{"signal_ms"}` to
+/// wait for the backend's new-frame signal.
+fn wait_strategy(key: &str, value: &Value) -> Result<WaitStrategy, RdpStatus> {
+    let field = |name| value.get(name).map(Value::as_u32);
+    match (
+        value.as_str(),
+        field("spin_us"),
+        field("sleep_ms"),
+        field("signal_ms"),
+    ) {
+        (Some("backoff"), ..) => Ok(WaitStrategy::Backoff),
+        (Some("yield"), ..) => Ok(WaitStrategy::Yield),
+        (None, None, Some(Some(ms)), None) => Ok(WaitStrategy::Sleep { ms }),
+        (None, Some(Some(spin_us)), Some(Some(sleep_ms)), None) => {
+            Ok(WaitStrategy::SpinThenSleep { spin_us, sleep_ms })
+        }
+        (None, None, None, Some(Some(ms))) => Ok(WaitStrategy::Signal { ms }),
+        _ => Err(invalid(
+            key,
+            "\"backoff\", \"yield\", {\"sleep_ms\"}, {\"spin_us\", \"sleep_ms\"} or {\"signal_ms\"} with non-negative integers",
+        )),
+    }
+}
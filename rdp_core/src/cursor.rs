@@ -0,0 +1,813 @@
+//! Mouse cursor sampling and overlay.
+//!
+//! scrap's frames leave the cursor out on most platforms, so the position is
+//! queried separately from the OS and the cursor is blended in by hand.
+//! Positions are reported relative to the top-left corner of the captured
+//! display, in that display's pixels.
+
+use std::sync::{Mutex, PoisonError};
+
+use crate::error::{RdpStatus, fail};
+
+/// A straight-alpha BGRA cursor bitmap and its hotspot.
+#[derive(Clone, Debug)]
+pub struct CursorImage {
+    pub width: u32,
+    pub height: u32,
+    pub hot_x: u32,
+    pub hot_y: u32,
+    /// `width * height` BGRA pixels, rows tightly packed.
+    pub pixels: Vec<u8>,
+}
+
+/// Classic arrow used where the OS cursor image is not available:
+/// `X` is the black body, `.` the white outline, space is transparent.
+const ARROW: [&str; 19] = [
+    ".           ",
+    "..          ",
+    ".X.         ",
+    ".XX.        ",
+    ".XXX.       ",
+    ".XXXX.      ",
+    ".XXXXX.     ",
+    ".XXXXXX.    ",
+    ".XXXXXXX.   ",
+    ".XXXXXXXX.  ",
+    ".XXXXXXXXX. ",
+    ".XXXXXX.....",
+    ".XXX.XX.    ",
+    ".XX. .XX.   ",
+    ".X.  .XX.   ",
+    "..    .XX.  ",
+    ".     .XX.  ",
+    "       .XX. ",
+    "       ...  ",
+];
+
+/// The built-in arrow, hotspot at its tip.
+pub fn fallback_arrow() -> CursorImage {
+    let pixels = ARROW
+        .iter()
+        .flat_map(|row| row.bytes())
+        .flat_map(|cell| match cell {
+            b'X' => [0x00, 0x00, 0x00, 0xff],
+            b'.' => [0xff, 0xff, 0xff, 0xff],
+            _ => [0x00, 0x00, 0x00, 0x00],
+        })
+        .collect();
+
+    CursorImage {
+        width: ARROW[0].len() as u32,
+        height: ARROW.len() as u32,
+        hot_x: 0,
+        hot_y: 0,
+        pixels,
+    }
+}
+
+/// Alpha-blends `image` onto a tightly packed `w x h` BGRA buffer with its
+/// hotspot at (`x`, `y`). Parts falling outside the buffer are clipped, so
+/// any position (including off-screen ones) is safe.
+pub fn overlay(buf: &mut [u8], w: u32, h: u32, x: i32, y: i32, image: &CursorImage) {
+    let left = i64::from(x) - i64::from(image.hot_x);
+    let top = i64::from(y) - i64::from(image.hot_y);
+
+    for row in 0..image.height {
+        let dst_y = top + i64::from(row);
+        if dst_y < 0 || dst_y >= i64::from(h) {
+            continue;
+        }
+        for col in 0..image.width {
+            let dst_x = left + i64::from(col);
+            if dst_x < 0 || dst_x >= i64::from(w) {
+                continue;
+            }
+
+            let src = ((row * image.width + col) * 4) as usize;
+            let src = &image.pixels[src..src + 4];
+            let alpha = u32::from(src[3]);
+            if alpha == 0 {
+                continue;
+            }
+
+            let dst = ((dst_y as usize * w as usize) + dst_x as usize) * 4;
+            let dst = &mut buf[dst..dst + 4];
+            for c in 0..3 {
+                let blended = u32::from(src[c]) * alpha + u32::from(dst[c]) * (255 - alpha) + 127;
+                dst[c] = (blended / 255) as u8;
+            }
+        }
+    }
+}
+
+/// Identity of the last cursor shape seen and how often it has changed.
+static GENERATION: Mutex<(Option<u64>, u64)> = Mutex::new((None, 0));
+
+/// A counter that changes whenever the system cursor's shape does, so
+/// clients only refetch the image when needed. Stays 0 where the shape
+/// cannot be read.
+pub fn generation() -> u64 {
+    let key = platform::shape_key();
+    let mut state = GENERATION.lock().unwrap_or_else(PoisonError::into_inner);
+    if key.is_some() && key != state.0 {
+        state.0 = key;
+        state.1 += 1;
+    }
+    state.1
+}
+
+/// The system cursor's current image.
+pub fn current_shape() -> Result<CursorImage, RdpStatus> {
+    if !platform::SHAPE_SUPPORTED {
+        return Err(fail(
+            RdpStatus::Unsupported,
+            "Cursor images are not available on this platform",
+        ));
+    }
+    platform::shape()
+        .ok_or_else(|| fail(RdpStatus::CaptureFailed, "Failed to read the cursor image"))
+}
+
+pub use platform::CursorProbe;
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use std::ffi::c_void;
+    use std::rc::Rc;
+
+    use scrap::x11::Server;
+
+    use super::CursorImage;
+    use crate::display::SPAN_ALL;
+
+    /// Reading the shape needs XFixes, which scrap does not link.
+    pub const SHAPE_SUPPORTED: bool = false;
+
+    pub fn shape() -> Option<CursorImage> {
+        None
+    }
+
+    pub fn shape_key() -> Option<u64> {
+        None
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct QueryPointerCookie {
+        sequence: u32,
+    }
+
+    #[repr(C)]
+    struct QueryPointerReply {
+        response_type: u8,
+        same_screen: u8,
+        sequence: u16,
+        length: u32,
+        root: u32,
+        child: u32,
+        root_x: i16,
+        root_y: i16,
+        win_x: i16,
+        win_y: i16,
+        mask: u16,
+        pad0: [u8; 2],
+    }
+
+    unsafe extern "C" {
+        fn xcb_query_pointer(c: *mut c_void, window: u32) -> QueryPointerCookie;
+        fn xcb_query_pointer_reply(
+            c: *mut c_void,
+            cookie: QueryPointerCookie,
+            e: *mut *mut c_void,
+        ) -> *mut QueryPointerReply;
+        fn free(ptr: *mut c_void);
+    }
+
+    /// Queries the pointer through the X server connection of the captured
+    /// display. All RandR monitors share one root window, so the root
+    /// coordinates are shifted by the monitor's origin (by the top-left
+    /// corner of the layout when spanning every display).
+    pub struct CursorProbe {
+        server: Rc<Server>,
+        root: u32,
+        origin: (i32, i32),
+    }
+
+    impl CursorProbe {
+        /// Resolves `display_index` the same way `RdpSession::new` does.
+        pub fn new(display_index: i32) -> Option<CursorProbe> {
+            let server = Rc::new(Server::default().ok()?);
+            let mut displays = Server::displays(server.clone());
+            if display_index == SPAN_ALL {
+                let all: Vec<_> = displays.collect();
+                let root = all.first()?.root();
+                let x = all.iter().map(|d| d.rect().x).min()?;
+                let y = all.iter().map(|d| d.rect().y).min()?;
+                return Some(CursorProbe {
+                    server,
+                    root,
+                    origin: (i32::from(x), i32::from(y)),
+                });
+            }
+            let display = if display_index == -1 {
+                displays.find(|d| d.is_default())
+            } else {
+                displays.nth(usize::try_from(display_index).ok()?)
+            }?;
+
+            let rect = display.rect();
+            Some(CursorProbe {
+                server,
+                root: display.root(),
+                origin: (i32::from(rect.x), i32::from(rect.y)),
+            })
+        }
+
+        /// Current hotspot position relative to the display.
+        pub fn position(&self) -> Option<(i32, i32)> {
+            unsafe {
+                let conn = self.server.raw();
+                let cookie = xcb_query_pointer(conn, self.root);
+                let reply = xcb_query_pointer_reply(conn, cookie, std::ptr::null_mut());
+                if reply.is_null() {
+                    return None;
+                }
+                let (x, y, same_screen) = ((*reply).root_x, (*reply).root_y, (*reply).same_screen);
+                free(reply.cast());
+
+                (same_screen != 0)
+                    .then(|| (i32::from(x) - self.origin.0, i32::from(y) - self.origin.1))
+            }
+        }
+
+        /// Maps a display pixel onto the root window, the inverse of
+        /// `position`.
+        pub fn to_screen(&self, x: i32, y: i32) -> (f64, f64) {
+            (f64::from(x + self.origin.0), f64::from(y + self.origin.1))
+        }
+
+        /// Maps a point on the root window onto the display's pixels.
+        pub fn to_display(&self, x: f64, y: f64) -> (i32, i32) {
+            (
+                x.round() as i32 - self.origin.0,
+                y.round() as i32 - self.origin.1,
+            )
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::ffi::{CStr, c_char, c_void};
+
+    use super::CursorImage;
+    use crate::display::{CGDisplayBounds, CGPoint, CGRect, CGSize, SPAN_ALL};
+    use crate::pixels;
+
+    pub const SHAPE_SUPPORTED: bool = true;
+
+    /// `kCGImageAlphaPremultipliedFirst | kCGBitmapByteOrder32Little`, i.e.
+    /// BGRA in memory.
+    const BITMAP_BGRA: u32 = 2 | (2 << 12);
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    unsafe extern "C" {
+        fn CGEventCreate(source: *const c_void) -> *mut c_void;
+        fn CGEventGetLocation(event: *mut c_void) -> CGPoint;
+        fn CGImageGetWidth(image: *mut c_void) -> usize;
+        fn CGImageGetHeight(image: *mut c_void) -> usize;
+        fn CGColorSpaceCreateDeviceRGB() -> *mut c_void;
+        fn CGColorSpaceRelease(space: *mut c_void);
+        fn CGBitmapContextCreate(
+            data: *mut c_void,
+            width: usize,
+            height: usize,
+            bits_per_component: usize,
+            bytes_per_row: usize,
+            space: *mut c_void,
+            bitmap_info: u32,
+        ) -> *mut c_void;
+        fn CGContextDrawImage(context: *mut c_void, rect: CGRect, image: *mut c_void);
+        fn CGContextRelease(context: *mut c_void);
+    }
+
+    #[link(name = "AppKit", kind = "framework")]
+    unsafe extern "C" {}
+
+    #[link(name = "objc")]
+    unsafe extern "C" {
+        fn objc_getClass(name: *const c_char) -> *mut c_void;
+        fn sel_registerName(name: *const c_char) -> *mut c_void;
+        fn objc_msgSend();
+        fn objc_autoreleasePoolPush() -> *mut c_void;
+        fn objc_autoreleasePoolPop(pool: *mut c_void);
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    unsafe extern "C" {
+        fn CFRelease(cf: *const c_void);
+    }
+
+    /// The current `NSCursor` image as straight-alpha BGRA pixels, with the
+    /// hotspot converted from points to pixels.
+    pub fn shape() -> Option<CursorImage> {
+        unsafe {
+            let pool = objc_autoreleasePoolPush();
+            let shape = read_shape();
+            objc_autoreleasePoolPop(pool);
+            shape
+        }
+    }
+
+    /// AppKit hands out a fresh `NSCursor` object for the same shape, so
+    /// shapes are told apart by their pixels (cursor images are tiny).
+    pub fn shape_key() -> Option<u64> {
+        shape().map(|s| pixels::frame_hash(&s.pixels, &[s.width, s.height, s.hot_x, s.hot_y]))
+    }
+
+    unsafe fn read_shape() -> Option<CursorImage> {
+        // objc_msgSend has to be called through the real signature of each
+        // method; two-double structs come back in registers on both ABIs
+        type SendId = unsafe extern "C" fn(*mut c_void, *mut c_void) -> *mut c_void;
+        type SendPair = unsafe extern "C" fn(*mut c_void, *mut c_void) -> CGSize;
+        type SendCgImage = unsafe extern "C" fn(
+            *mut c_void,
+            *mut c_void,
+            *const CGRect,
+            *mut c_void,
+            *mut c_void,
+        ) -> *mut c_void;
+        let msg_send = objc_msgSend as unsafe extern "C" fn();
+        let sel = |name: &CStr| unsafe { sel_registerName(name.as_ptr()) };
+
+        unsafe {
+            let send_id: SendId = std::mem::transmute(msg_send);
+            let send_pair: SendPair = std::mem::transmute(msg_send);
+            let send_cg_image: SendCgImage = std::mem::transmute(msg_send);
+
+            let class = objc_getClass(c"NSCursor".as_ptr());
+            let cursor = send_id(class, sel(c"currentSystemCursor"));
+            if cursor.is_null() {
+                return None;
+            }
+            let image = send_id(cursor, sel(c"image"));
+            if image.is_null() {
+                return None;
+            }
+            let hotspot = send_pair(cursor, sel(c"hotSpot"));
+            let points = send_pair(image, sel(c"size"));
+
+            // Owned by the NSImage, so not released here
+            let cg_image = send_cg_image(
+                image,
+                sel(c"CGImageForProposedRect:context:hints:"),
+                std::ptr::null(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            );
+            if cg_image.is_null() {
+                return None;
+            }
+            let (w, h) = (CGImageGetWidth(cg_image), CGImageGetHeight(cg_image));
+            if w == 0 || h == 0 {
+                return None;
+            }
+
+            let mut bgra = vec![0u8; w * h * 4];
+            let space = CGColorSpaceCreateDeviceRGB();
+            let context =
+                CGBitmapContextCreate(bgra.as_mut_ptr().cast(), w, h, 8, w * 4, space, BITMAP_BGRA);
+            CGColorSpaceRelease(space);
+            if context.is_null() {
+                return None;
+            }
+            let bounds = CGRect {
+                origin: CGPoint { x: 0.0, y: 0.0 },
+                size: CGSize {
+                    width: w as f64,
+                    height: h as f64,
+                },
+            };
+            CGContextDrawImage(context, bounds, cg_image);
+            CGContextRelease(context);
+
+            // CoreGraphics only renders premultiplied alpha
+            for px in bgra.chunks_exact_mut(4) {
+                let alpha = u32::from(px[3]);
+                if alpha != 0 && alpha != 255 {
+                    for c in &mut px[..3] {
+                        *c = ((u32::from(*c) * 255 + alpha / 2) / alpha).min(255) as u8;
+                    }
+                }
+            }
+
+            // hotSpot is in points; Retina cursors have more pixels than that
+            let scale = if points.width > 0.0 {
+                w as f64 / points.width
+            } else {
+                1.0
+            };
+            Some(CursorImage {
+                width: w as u32,
+                height: h as u32,
+                hot_x: ((hotspot.width * scale) as u32).min(w as u32 - 1),
+                hot_y: ((hotspot.height * scale) as u32).min(h as u32 - 1),
+                pixels: bgra,
+            })
+        }
+    }
+
+    /// Reads the pointer from a null CGEvent. Locations are in global points,
+    /// so they are converted to the display's pixels (Retina scale). When
+    /// spanning every display, points are counted from the top-left corner
+    /// of the layout at the highest scale of any display.
+    pub struct CursorProbe {
+        id: u32,
+        pixels_per_point: f64,
+        /// Fixed origin of a spanning probe; others follow their display.
+        span_origin: Option<CGPoint>,
+    }
+
+    impl CursorProbe {
+        /// Resolves `display_index` the same way `RdpSession::new` does.
+        pub fn new(display_index: i32) -> Option<CursorProbe> {
+            if display_index == SPAN_ALL {
+                let all = scrap::quartz::Display::online().ok()?;
+                let bounds: Vec<_> = all
+                    .iter()
+                    .map(|d| (d.width() as f64, unsafe { CGDisplayBounds(d.id()) }))
+                    .filter(|(_, b)| b.size.width > 0.0)
+                    .collect();
+                let x = bounds.iter().map(|(_, b)| b.origin.x).reduce(f64::min)?;
+                let y = bounds.iter().map(|(_, b)| b.origin.y).reduce(f64::min)?;
+                let pixels_per_point = bounds
+                    .iter()
+                    .map(|(w, b)| w / b.size.width)
+                    .reduce(f64::max)?;
+                return Some(CursorProbe {
+                    id: all.first()?.id(),
+                    pixels_per_point,
+                    span_origin: Some(CGPoint { x, y }),
+                });
+            }
+            let display = if display_index == -1 {
+                scrap::quartz::Display::primary()
+            } else {
+                *scrap::quartz::Display::online()
+                    .ok()?
+                    .get(usize::try_from(display_index).ok()?)?
+            };
+
+            let bounds = unsafe { CGDisplayBounds(display.id()) };
+            if bounds.size.width <= 0.0 {
+                return None;
+            }
+            Some(CursorProbe {
+                id: display.id(),
+                pixels_per_point: display.width() as f64 / bounds.size.width,
+                span_origin: None,
+            })
+        }
+
+        fn origin(&self) -> CGPoint {
+            self.span_origin
+                .unwrap_or_else(|| unsafe { CGDisplayBounds(self.id) }.origin)
+        }
+
+        /// Current hotspot position relative to the display.
+        pub fn position(&self) -> Option<(i32, i32)> {
+            unsafe {
+                let event = CGEventCreate(std::ptr::null());
+                if event.is_null() {
+                    return None;
+                }
+                let at = CGEventGetLocation(event);
+                CFRelease(event);
+
+                let origin = self.origin();
+                Some((
+                    ((at.x - origin.x) * self.pixels_per_point).round() as i32,
+                    ((at.y - origin.y) * self.pixels_per_point).round() as i32,
+                ))
+            }
+        }
+
+        /// Maps a display pixel onto global display points, the inverse of
+        /// `position`.
+        pub fn to_screen(&self, x: i32, y: i32) -> (f64, f64) {
+            let origin = self.origin();
+            (
+                origin.x + f64::from(x) / self.pixels_per_point,
+                origin.y + f64::from(y) / self.pixels_per_point,
+            )
+        }
+
+        /// Maps global display points onto the display's pixels.
+        pub fn to_display(&self, x: f64, y: f64) -> (i32, i32) {
+            let origin = self.origin();
+            (
+                ((x - origin.x) * self.pixels_per_point).round() as i32,
+                ((y - origin.y) * self.pixels_per_point).round() as i32,
+            )
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+
+    use super::CursorImage;
+    use crate::display::{EnumDisplayMonitors, GetMonitorInfoW, MonitorInfoExW, Rect, SPAN_ALL};
+
+    pub const SHAPE_SUPPORTED: bool = true;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[repr(C)]
+    struct CursorInfo {
+        cb_size: u32,
+        flags: u32,
+        cursor: *mut c_void,
+        screen_pos: Point,
+    }
+
+    #[repr(C)]
+    struct IconInfo {
+        is_icon: i32,
+        hot_x: u32,
+        hot_y: u32,
+        mask: *mut c_void,
+        color: *mut c_void,
+    }
+
+    #[repr(C)]
+    struct Bitmap {
+        bm_type: i32,
+        width: i32,
+        height: i32,
+        width_bytes: i32,
+        planes: u16,
+        bits_pixel: u16,
+        bits: *mut c_void,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct BitmapInfoHeader {
+        size: u32,
+        width: i32,
+        height: i32,
+        planes: u16,
+        bit_count: u16,
+        compression: u32,
+        size_image: u32,
+        x_pels_per_meter: i32,
+        y_pels_per_meter: i32,
+        clr_used: u32,
+        clr_important: u32,
+    }
+
+    #[repr(C)]
+    struct BitmapInfo {
+        header: BitmapInfoHeader,
+        colors: [u32; 2],
+    }
+
+    #[link(name = "user32")]
+    unsafe extern "system" {
+        fn GetCursorPos(point: *mut Point) -> i32;
+        fn GetCursorInfo(info: *mut CursorInfo) -> i32;
+        fn GetIconInfo(icon: *mut c_void, info: *mut IconInfo) -> i32;
+        fn GetDC(window: *mut c_void) -> *mut c_void;
+        fn ReleaseDC(window: *mut c_void, hdc: *mut c_void) -> i32;
+    }
+
+    #[link(name = "gdi32")]
+    unsafe extern "system" {
+        fn GetObjectW(object: *mut c_void, size: i32, out: *mut c_void) -> i32;
+        fn GetDIBits(
+            hdc: *mut c_void,
+            bitmap: *mut c_void,
+            start: u32,
+            lines: u32,
+            bits: *mut c_void,
+            info: *mut BitmapInfo,
+            usage: u32,
+        ) -> i32;
+        fn DeleteObject(object: *mut c_void) -> i32;
+    }
+
+    fn current_cursor() -> Option<*mut c_void> {
+        let mut info = CursorInfo {
+            cb_size: std::mem::size_of::<CursorInfo>() as u32,
+            flags: 0,
+            cursor: std::ptr::null_mut(),
+            screen_pos: Point::default(),
+        };
+        let ok = unsafe { GetCursorInfo(&mut info) } != 0;
+        (ok && !info.cursor.is_null()).then_some(info.cursor)
+    }
+
+    /// Shared system cursors keep their handle, so the handle identifies
+    /// the shape.
+    pub fn shape_key() -> Option<u64> {
+        current_cursor().map(|cursor| cursor as usize as u64)
+    }
+
+    /// The current cursor as straight-alpha BGRA pixels.
+    pub fn shape() -> Option<CursorImage> {
+        let cursor = current_cursor()?;
+        let mut icon = IconInfo {
+            is_icon: 0,
+            hot_x: 0,
+            hot_y: 0,
+            mask: std::ptr::null_mut(),
+            color: std::ptr::null_mut(),
+        };
+        if unsafe { GetIconInfo(cursor, &mut icon) } == 0 {
+            return None;
+        }
+
+        let shape = read_icon(&icon);
+        // GetIconInfo hands us copies of both bitmaps
+        for bitmap in [icon.mask, icon.color] {
+            if !bitmap.is_null() {
+                unsafe { DeleteObject(bitmap) };
+            }
+        }
+        shape
+    }
+
+    fn read_icon(icon: &IconInfo) -> Option<CursorImage> {
+        let (mask, mask_w, mask_h) = dib_pixels(icon.mask)?;
+        let (pixels, width, height) = if icon.color.is_null() {
+            // Monochrome cursors stack the AND mask on top of the XOR mask
+            let height = mask_h / 2;
+            let (and, xor) = mask.split_at((mask_w * height * 4) as usize);
+            let pixels = and
+                .chunks_exact(4)
+                .zip(xor.chunks_exact(4))
+                .flat_map(|(a, x)| match (a[0] != 0, x[0] != 0) {
+                    (false, false) => [0x00, 0x00, 0x00, 0xff],
+                    (false, true) => [0xff, 0xff, 0xff, 0xff],
+                    (true, false) => [0x00, 0x00, 0x00, 0x00],
+                    // Inverting the screen has no alpha equivalent; draw it dark
+                    (true, true) => [0x00, 0x00, 0x00, 0xff],
+                })
+                .collect();
+            (pixels, mask_w, height)
+        } else {
+            let (mut pixels, w, h) = dib_pixels(icon.color)?;
+            // Colour cursors without an alpha channel take it from the mask
+            if pixels.chunks_exact(4).all(|px| px[3] == 0) {
+                for (px, m) in pixels.chunks_exact_mut(4).zip(mask.chunks_exact(4)) {
+                    px[3] = if m[0] == 0 { 0xff } else { 0x00 };
+                }
+            }
+            (pixels, w, h)
+        };
+
+        Some(CursorImage {
+            width,
+            height,
+            hot_x: icon.hot_x.min(width.saturating_sub(1)),
+            hot_y: icon.hot_y.min(height.saturating_sub(1)),
+            pixels,
+        })
+    }
+
+    /// Top-down 32-bit BGRA copy of a GDI bitmap, and its size.
+    fn dib_pixels(bitmap: *mut c_void) -> Option<(Vec<u8>, u32, u32)> {
+        let mut bm = Bitmap {
+            bm_type: 0,
+            width: 0,
+            height: 0,
+            width_bytes: 0,
+            planes: 0,
+            bits_pixel: 0,
+            bits: std::ptr::null_mut(),
+        };
+        let size = std::mem::size_of::<Bitmap>() as i32;
+        if unsafe { GetObjectW(bitmap, size, (&mut bm as *mut Bitmap).cast()) } == 0 {
+            return None;
+        }
+        let (w, h) = (
+            u32::try_from(bm.width).ok()?,
+            u32::try_from(bm.height).ok()?,
+        );
+
+        let mut info = BitmapInfo {
+            header: BitmapInfoHeader {
+                size: std::mem::size_of::<BitmapInfoHeader>() as u32,
+                width: bm.width,
+                // Negative height asks for top-down rows
+                height: -bm.height,
+                planes: 1,
+                bit_count: 32,
+                ..BitmapInfoHeader::default()
+            },
+            colors: [0; 2],
+        };
+        let mut pixels = vec![0u8; w as usize * h as usize * 4];
+        let lines = unsafe {
+            let hdc = GetDC(std::ptr::null_mut());
+            let lines = GetDIBits(hdc, bitmap, 0, h, pixels.as_mut_ptr().cast(), &mut info, 0);
+            ReleaseDC(std::ptr::null_mut(), hdc);
+            lines
+        };
+        (lines == h as i32).then_some((pixels, w, h))
+    }
+
+    /// Uses `GetCursorPos` (virtual-desktop coordinates) and the origin of
+    /// the monitor whose GDI device name matches the DXGI output, or of the
+    /// whole virtual desktop when spanning every display.
+    pub struct CursorProbe {
+        origin: (i32, i32),
+    }
+
+    unsafe extern "system" fn collect(
+        monitor: *mut c_void,
+        _hdc: *mut c_void,
+        _rect: *mut Rect,
+        data: isize,
+    ) -> i32 {
+        let found = unsafe { &mut *(data as *mut Vec<([u16; 32], Rect)>) };
+        let mut info = MonitorInfoExW {
+            cb_size: std::mem::size_of::<MonitorInfoExW>() as u32,
+            rc_monitor: Rect::default(),
+            rc_work: Rect::default(),
+            flags: 0,
+            device: [0; 32],
+        };
+        if unsafe { GetMonitorInfoW(monitor, &mut info) } != 0 {
+            found.push((info.device, info.rc_monitor));
+        }
+        1
+    }
+
+    impl CursorProbe {
+        /// Resolves `display_index` the same way `RdpSession::new` does.
+        pub fn new(display_index: i32) -> Option<CursorProbe> {
+            let mut monitors: Vec<([u16; 32], Rect)> = Vec::new();
+            unsafe {
+                EnumDisplayMonitors(
+                    std::ptr::null_mut(),
+                    std::ptr::null(),
+                    collect,
+                    &mut monitors as *mut _ as isize,
+                );
+            }
+            if display_index == SPAN_ALL {
+                let left = monitors.iter().map(|(_, rect)| rect.left).min()?;
+                let top = monitors.iter().map(|(_, rect)| rect.top).min()?;
+                return Some(CursorProbe {
+                    origin: (left, top),
+                });
+            }
+
+            let index = usize::try_from(display_index.max(0)).ok()?;
+            let output = scrap::dxgi::Displays::new().ok()?.nth(index)?;
+            let name: Vec<u16> = output
+                .name()
+                .iter()
+                .copied()
+                .take_while(|&c| c != 0)
+                .collect();
+
+            let origin = monitors
+                .iter()
+                .find(|(device, _)| device.iter().take_while(|&&c| c != 0).eq(name.iter()))
+                .map_or((0, 0), |(_, rect)| (rect.left, rect.top));
+            Some(CursorProbe { origin })
+        }
+
+        /// Current hotspot position relative to the display.
+        pub fn position(&self) -> Option<(i32, i32)> {
+            let mut at = Point::default();
+            if unsafe { GetCursorPos(&mut at) } == 0 {
+                return None;
+            }
+            Some((at.x - self.origin.0, at.y - self.origin.1))
+        }
+
+        /// Maps a display pixel onto the virtual desktop, the inverse of
+        /// `position`.
+        pub fn to_screen(&self, x: i32, y: i32) -> (f64, f64) {
+            (f64::from(x + self.origin.0), f64::from(y + self.origin.1))
+        }
+
+        /// Maps a point on the virtual desktop onto the display's pixels.
+        pub fn to_display(&self, x: f64, y: f64) -> (i32, i32) {
+            (
+                x.round() as i32 - self.origin.0,
+                y.round() as i32 - self.origin.1,
+            )
+        }
+    }
+}
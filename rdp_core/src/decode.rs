@@ -0,0 +1,672 @@
+//! Decoding of the frames this library produces back into pixels, for
+//! viewers that link it too:
+//!
+//! - `decode_image` (`rdp_decode`) decodes a single JPEG, PNG or WebP
+//!   payload, the formats that describe themselves. JPEG goes through
+//!   turbojpeg with the `turbojpeg` feature, the rest through `image`.
+//! - A `Decoder` (`rdp_decoder_new`) follows a session's frames in any
+//!   image format. It is told what each frame's `RawImage` says about it
+//!   (`FrameInfo`), since raw, zstd, I420 and NV12 payloads do not carry
+//!   their own size, and keeps the image so far for tiled and zstd deltas
+//!   to be applied to. A delta it cannot apply, because frames before it
+//!   were missed or failed, fails with `RdpStatus::KeyframeNeeded` until
+//!   the next keyframe.
+//!
+//! Images come out tightly packed in the pixel format asked for, gray as
+//! BT.601 luma. Video frames (H.264, VP8, VP9) need a video decoder and are
+//! not handled here; encrypted frames must go through `rdp_decrypt_frame`
+//! first.
+
+use image::ImageFormat;
+
+use crate::error::{RdpStatus, fail};
+use crate::frame::{EncodedFrame, FrameFormat, PixelFormat};
+use crate::pixels::{self, Rect};
+use crate::tiles;
+use crate::yuv::{self, YuvMatrix};
+use crate::zstd;
+
+/// The longest image side decoded. Sizes come off the wire, and a whole
+/// image is allocated before anything is drawn on it.
+const MAX_SIDE: u32 = 16384;
+
+/// What a frame's `RawImage` says about its payload, beyond the bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub format: FrameFormat,
+    /// Size of raw, zstd, I420 and NV12 frames; the others carry theirs.
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per row of raw and zstd frames.
+    pub stride: u32,
+    /// Layout of raw and zstd frames and of raw or zstd tiles.
+    pub pixel_format: PixelFormat,
+    pub keyframe: bool,
+}
+
+impl From<&EncodedFrame> for FrameInfo {
+    fn from(frame: &EncodedFrame) -> FrameInfo {
+        FrameInfo {
+            format: frame.format,
+            width: frame.width,
+            height: frame.height,
+            stride: frame.stride,
+            pixel_format: frame.pixel_format,
+            keyframe: frame.keyframe,
+        }
+    }
+}
+
+/// A decoded image: tightly packed BGRA.
+struct Canvas {
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl Canvas {
+    /// The image as a raw frame in `pixel_format`.
+    fn to_frame(&self, pixel_format: PixelFormat) -> EncodedFrame {
+        let mut data = Vec::new();
+        pixels::convert_bgra(&self.pixels, pixel_format, &mut data);
+        let (width, height) = (self.width, self.height);
+        EncodedFrame {
+            content_hash: pixels::frame_hash(&data, &[width, height, pixel_format as u32]),
+            data,
+            width,
+            height,
+            format: FrameFormat::Raw,
+            pixel_format,
+            stride: width * pixel_format.bytes_per_pixel(),
+            dirty: Rect {
+                x: 0,
+                y: 0,
+                w: width,
+                h: height,
+            },
+            cursor: None,
+            hotspot: (0, 0),
+            sequence: 0,
+            timestamp_us: 0,
+            quality: 0,
+            keyframe: true,
+            uncompressed_len: 0,
+            encrypted: false,
+            progressive: false,
+        }
+    }
+
+    /// Copies `tile` over the image at `at`, which must leave it inside.
+    fn draw(&mut self, tile: &Canvas, at: Rect) {
+        let (row, stride) = (tile.width as usize * 4, self.width as usize * 4);
+        for (y, line) in tile.pixels.chunks_exact(row).enumerate() {
+            let start = (at.y as usize + y) * stride + at.x as usize * 4;
+            self.pixels[start..start + row].copy_from_slice(line);
+        }
+    }
+}
+
+/// Decodes a JPEG, PNG or WebP payload into `pixel_format`. Fails with
+/// `RdpStatus::DecodeFailed` for anything else, or a damaged image.
+pub fn decode_image(data: &[u8], pixel_format: PixelFormat) -> Result<EncodedFrame, RdpStatus> {
+    Ok(image(data, None)?.to_frame(pixel_format))
+}
+
+/// Decodes a JPEG, PNG or WebP payload, which must be in `expected` when
+/// that is given.
+fn image(data: &[u8], expected: Option<FrameFormat>) -> Result<Canvas, RdpStatus> {
+    let format = match image::guess_format(data) {
+        Ok(ImageFormat::Jpeg) => FrameFormat::Jpeg,
+        Ok(ImageFormat::Png) => FrameFormat::Png,
+        Ok(ImageFormat::WebP) => FrameFormat::WebP,
+        _ => {
+            return Err(fail(
+                RdpStatus::DecodeFailed,
+                "Frame is not a JPEG, PNG or WebP image",
+            ));
+        }
+    };
+    if expected.is_some_and(|expected| expected != format) {
+        return Err(fail(
+            RdpStatus::DecodeFailed,
+            format!("Frame labelled {expected:?} holds a {format:?} image"),
+        ));
+    }
+    match format {
+        FrameFormat::Jpeg => jpeg(data),
+        FrameFormat::Png => with_image(data, ImageFormat::Png),
+        _ => with_image(data, ImageFormat::WebP),
+    }
+}
+
+#[cfg(feature = "turbojpeg")]
+fn jpeg(data: &[u8]) -> Result<Canvas, RdpStatus> {
+    let image = turbojpeg::decompress(data, turbojpeg::PixelFormat::BGRA).map_err(|e| {
+        fail(
+            RdpStatus::DecodeFailed,
+            format!("Failed to decode JPEG: {e}"),
+        )
+    })?;
+    // `decompress` packs the rows tightly
+    Ok(Canvas {
+        pixels: image.pixels,
+        width: image.width as u32,
+        height: image.height as u32,
+    })
+}
+
+#[cfg(not(feature = "turbojpeg"))]
+fn jpeg(data: &[u8]) -> Result<Canvas, RdpStatus> {
+    with_image(data, ImageFormat::Jpeg)
+}
+
+fn with_image(data: &[u8], format: ImageFormat) -> Result<Canvas, RdpStatus> {
+    let image = image::load_from_memory_with_format(data, format)
+        .map_err(|e| {
+            fail(
+                RdpStatus::DecodeFailed,
+                format!("Failed to decode {format:?}: {e}"),
+            )
+        })?
+        .into_rgba8();
+    let (width, height) = image.dimensions();
+    let mut pixels = image.into_raw();
+    for px in pixels.chunks_exact_mut(4) {
+        px.swap(0, 2);
+    }
+    Ok(Canvas {
+        pixels,
+        width,
+        height,
+    })
+}
+
+/// Raw pixels laid out as `info` says.
+fn raw(data: &[u8], info: &FrameInfo) -> Result<Canvas, RdpStatus> {
+    let (w, h) = (info.width as usize, info.height as usize);
+    let row = w * info.pixel_format.bytes_per_pixel() as usize;
+    let stride = info.stride as usize;
+    if w == 0 || h == 0 || stride < row || data.len() < stride * (h - 1) + row {
+        return Err(fail(
+            RdpStatus::DecodeFailed,
+            format!(
+                "{} bytes are not a {w}x{h} {:?} frame with a stride of {stride}",
+                data.len(),
+                info.pixel_format
+            ),
+        ));
+    }
+    let mut pixels = Vec::with_capacity(w * h * 4);
+    for line in data.chunks(stride).take(h) {
+        to_bgra(&line[..row], info.pixel_format, &mut pixels);
+    }
+    Ok(Canvas {
+        pixels,
+        width: info.width,
+        height: info.height,
+    })
+}
+
+/// Appends `src`, laid out as `format`, to `out` as BGRA.
+fn to_bgra(src: &[u8], format: PixelFormat, out: &mut Vec<u8>) {
+    match format {
+        PixelFormat::Bgra => out.extend_from_slice(src),
+        PixelFormat::Rgba => out.extend(
+            src.chunks_exact(4)
+                .flat_map(|px| [px[2], px[1], px[0], px[3]]),
+        ),
+        PixelFormat::Rgb => out.extend(
+            src.chunks_exact(3)
+                .flat_map(|px| [px[2], px[1], px[0], 0xff]),
+        ),
+        PixelFormat::Bgr => out.extend(
+            src.chunks_exact(3)
+                .flat_map(|px| [px[0], px[1], px[2], 0xff]),
+        ),
+        PixelFormat::Gray => out.extend(src.iter().flat_map(|&l| [l, l, l, 0xff])),
+    }
+}
+
+/// Bytes a zstd frame described by `info` decompresses to.
+fn zstd_len(info: &FrameInfo) -> usize {
+    info.stride as usize * info.height as usize
+}
+
+/// Follows one session's frames, keeping the image they add up to.
+pub struct Decoder {
+    pixel_format: PixelFormat,
+    yuv_matrix: YuvMatrix,
+    /// The last image decoded.
+    canvas: Option<Canvas>,
+    /// Whether the next tiled delta may be drawn on `canvas`: false until a
+    /// keyframe, and again after a frame failed.
+    synced: bool,
+    /// The last zstd frame's decompressed pixels, which the next zstd delta
+    /// is XORed onto, and what they were; `None` when there is none.
+    reference: Vec<u8>,
+    reference_info: Option<FrameInfo>,
+}
+
+impl Decoder {
+    /// A decoder handing out images in `pixel_format`.
+    pub fn new(pixel_format: PixelFormat) -> Decoder {
+        Decoder {
+            pixel_format,
+            yuv_matrix: YuvMatrix::default(),
+            canvas: None,
+            synced: false,
+            reference: Vec::new(),
+            reference_info: None,
+        }
+    }
+
+    /// The matrix I420 and NV12 frames were encoded with; the session's
+    /// `rdp_session_set_yuv_matrix`, BT.601 by default.
+    pub fn set_yuv_matrix(&mut self, matrix: YuvMatrix) {
+        self.yuv_matrix = matrix;
+    }
+
+    /// Decodes the session's next frame. After a failure the last image is
+    /// kept, but deltas fail with `RdpStatus::KeyframeNeeded` until the
+    /// next keyframe, as they would be drawn on the wrong image.
+    pub fn feed(&mut self, data: &[u8], info: &FrameInfo) -> Result<(), RdpStatus> {
+        let result = self.decode(data, info);
+        if result.is_err() {
+            self.synced = false;
+            self.reference_info = None;
+        }
+        result
+    }
+
+    /// The image so far in the decoder's pixel format, or `None` before the
+    /// first frame was decoded.
+    pub fn frame(&self) -> Option<EncodedFrame> {
+        self.canvas
+            .as_ref()
+            .map(|canvas| canvas.to_frame(self.pixel_format))
+    }
+
+    fn decode(&mut self, data: &[u8], info: &FrameInfo) -> Result<(), RdpStatus> {
+        if matches!(
+            info.format,
+            FrameFormat::Raw | FrameFormat::RawZstd | FrameFormat::I420 | FrameFormat::Nv12
+        ) && (info.width > MAX_SIDE
+            || info.height > MAX_SIDE
+            || info.stride > MAX_SIDE * info.pixel_format.bytes_per_pixel())
+        {
+            return Err(fail(
+                RdpStatus::DecodeFailed,
+                format!(
+                    "A {}x{} frame with a stride of {} is larger than {MAX_SIDE} pixels a side",
+                    info.width, info.height, info.stride
+                ),
+            ));
+        }
+        let canvas = match info.format {
+            FrameFormat::Jpeg | FrameFormat::Png | FrameFormat::WebP => {
+                image(data, Some(info.format))?
+            }
+            FrameFormat::Raw => raw(data, info)?,
+            FrameFormat::I420 | FrameFormat::Nv12 => self.yuv(data, info)?,
+            FrameFormat::RawZstd => return self.zstd(data, info),
+            FrameFormat::TiledKeyframe | FrameFormat::TiledDelta => return self.tiled(data, info),
+            FrameFormat::H264 | FrameFormat::Vp8 | FrameFormat::Vp9 => {
+                return Err(fail(
+                    RdpStatus::Unsupported,
+                    format!("{:?} frames need a video decoder", info.format),
+                ));
+            }
+            FrameFormat::Text => {
+                return Err(fail(
+                    RdpStatus::InvalidArgument,
+                    "Text is not an image format",
+                ));
+            }
+        };
+        self.show(canvas);
+        Ok(())
+    }
+
+    /// Makes `canvas` the image so far, which any delta starts from.
+    fn show(&mut self, canvas: Canvas) {
+        self.canvas = Some(canvas);
+        self.synced = true;
+        self.reference_info = None;
+    }
+
+    fn yuv(&self, data: &[u8], info: &FrameInfo) -> Result<Canvas, RdpStatus> {
+        let (width, height) = yuv::even_size(info.width, info.height);
+        let luma_len = width as usize * height as usize;
+        if width == 0 || data.len() < luma_len + luma_len / 2 {
+            return Err(fail(
+                RdpStatus::DecodeFailed,
+                format!(
+                    "{} bytes are not a {width}x{height} {:?} frame",
+                    data.len(),
+                    info.format
+                ),
+            ));
+        }
+        let mut pixels = Vec::new();
+        yuv::to_bgra(
+            data,
+            info.format,
+            (width, height),
+            self.yuv_matrix,
+            &mut pixels,
+        );
+        Ok(Canvas {
+            pixels,
+            width,
+            height,
+        })
+    }
+
+    /// A zstd frame, XORed onto the one before it unless it is a keyframe.
+    fn zstd(&mut self, data: &[u8], info: &FrameInfo) -> Result<(), RdpStatus> {
+        let same_shape = |last: &FrameInfo| {
+            (last.width, last.height, last.stride, last.pixel_format)
+                == (info.width, info.height, info.stride, info.pixel_format)
+        };
+        if !info.keyframe && !self.reference_info.as_ref().is_some_and(same_shape) {
+            return Err(fail(
+                RdpStatus::KeyframeNeeded,
+                "zstd delta without the frame before it; waiting for a keyframe",
+            ));
+        }
+        let mut pixels = zstd::decompress(data, zstd_len(info))?;
+        if !info.keyframe {
+            for (px, before) in pixels.iter_mut().zip(&self.reference) {
+                *px ^= before;
+            }
+        }
+        let canvas = raw(&pixels, info)?;
+        self.show(canvas);
+        self.reference = pixels;
+        self.reference_info = Some(*info);
+        Ok(())
+    }
+
+    /// A tile container, drawn on a new image for a keyframe and on the
+    /// image so far for a delta. Every tile is decoded before any is drawn,
+    /// so a damaged one leaves the image as it was.
+    fn tiled(&mut self, data: &[u8], info: &FrameInfo) -> Result<(), RdpStatus> {
+        let (payload_format, tiles) = tiles::parse(data)?;
+        let corners = tiles
+            .iter()
+            .map(|tile| far_corner(tile.rect))
+            .collect::<Result<Vec<_>, _>>()?;
+        let decoded = tiles
+            .iter()
+            .map(|tile| tile_pixels(tile.payload, payload_format, tile.rect, info.pixel_format))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if info.format == FrameFormat::TiledKeyframe {
+            // A keyframe covers the whole image
+            let width = corners.iter().map(|&(right, _)| right).max().unwrap_or(0);
+            let height = corners.iter().map(|&(_, bottom)| bottom).max().unwrap_or(0);
+            let mut canvas = Canvas {
+                pixels: [0, 0, 0, 0xff].repeat(width as usize * height as usize),
+                width,
+                height,
+            };
+            for (tile, pixels) in tiles.iter().zip(&decoded) {
+                canvas.draw(pixels, tile.rect);
+            }
+            self.show(canvas);
+            return Ok(());
+        }
+
+        let canvas = match &mut self.canvas {
+            Some(canvas) if self.synced => canvas,
+            _ => {
+                return Err(fail(
+                    RdpStatus::KeyframeNeeded,
+                    "Tiled delta without the frames before it; waiting for a keyframe",
+                ));
+            }
+        };
+        let (width, height) = (canvas.width, canvas.height);
+        if let Some((tile, _)) = tiles
+            .iter()
+            .zip(&corners)
+            .find(|&(_, &(right, bottom))| right > width || bottom > height)
+        {
+            return Err(fail(
+                RdpStatus::KeyframeNeeded,
+                format!(
+                    "Tile {:?} lies outside the {width}x{height} image; waiting for a keyframe",
+                    tile.rect
+                ),
+            ));
+        }
+        for (tile, pixels) in tiles.iter().zip(&decoded) {
+            canvas.draw(pixels, tile.rect);
+        }
+        Ok(())
+    }
+}
+
+/// Where `rect` ends, right and bottom. Fails with `RdpStatus::DecodeFailed`
+/// when that is past `MAX_SIDE`.
+fn far_corner(rect: Rect) -> Result<(u32, u32), RdpStatus> {
+    match (rect.x.checked_add(rect.w), rect.y.checked_add(rect.h)) {
+        (Some(right), Some(bottom)) if right <= MAX_SIDE && bottom <= MAX_SIDE => {
+            Ok((right, bottom))
+        }
+        _ => Err(fail(
+            RdpStatus::DecodeFailed,
+            format!("Tile {rect:?} reaches past {MAX_SIDE} pixels a side"),
+        )),
+    }
+}
+
+/// Decodes one tile's payload, which must come out `rect`-sized.
+fn tile_pixels(
+    payload: &[u8],
+    format: FrameFormat,
+    rect: Rect,
+    pixel_format: PixelFormat,
+) -> Result<Canvas, RdpStatus> {
+    let info = FrameInfo {
+        format,
+        width: rect.w,
+        height: rect.h,
+        stride: rect.w * pixel_format.bytes_per_pixel(),
+        pixel_format,
+        keyframe: true,
+    };
+    let tile = match format {
+        FrameFormat::Jpeg | FrameFormat::Png | FrameFormat::WebP => image(payload, Some(format))?,
+        FrameFormat::Raw => raw(payload, &info)?,
+        FrameFormat::RawZstd => raw(&zstd::decompress(payload, zstd_len(&info))?, &info)?,
+        _ => {
+            return Err(fail(
+                RdpStatus::DecodeFailed,
+                format!("Tiles cannot hold {format:?} payloads"),
+            ));
+        }
+    };
+    if (tile.width, tile.height) != (rect.w, rect.h) {
+        return Err(fail(
+            RdpStatus::DecodeFailed,
+            format!(
+                "Tile {rect:?} decodes to {}x{} pixels",
+                tile.width, tile.height
+            ),
+        ));
+    }
+    Ok(tile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+    use crate::session::SessionConfig;
+
+    fn rect(x: u32, y: u32, w: u32, h: u32) -> Rect {
+        Rect { x, y, w, h }
+    }
+
+    /// A container of raw BGRA tiles, laid out as `tiles::parse` reads it.
+    fn container(tiles: &[(Rect, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(tiles.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(FrameFormat::Raw as u32).to_le_bytes());
+        for (rect, payload) in tiles {
+            for field in [rect.x, rect.y, rect.w, rect.h, payload.len() as u32] {
+                out.extend_from_slice(&field.to_le_bytes());
+            }
+            out.extend_from_slice(payload);
+        }
+        out
+    }
+
+    fn tiled(format: FrameFormat) -> FrameInfo {
+        FrameInfo {
+            format,
+            width: 0,
+            height: 0,
+            stride: 0,
+            pixel_format: PixelFormat::Bgra,
+            keyframe: format == FrameFormat::TiledKeyframe,
+        }
+    }
+
+    /// Opaque BGRA with a different value in every pixel.
+    fn image(width: u32, height: u32, seed: u32) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|i| {
+                let v = i.wrapping_mul(2654435761).wrapping_add(seed);
+                [v as u8, (v >> 8) as u8, (v >> 16) as u8, 0xff]
+            })
+            .collect()
+    }
+
+    /// A PNG tiled keyframe and then a delta come back out pixel for pixel.
+    #[test]
+    fn tiled_frames_round_trip() {
+        let (width, height) = (200, 130);
+        let config = SessionConfig {
+            format: FrameFormat::Png,
+            pixel_format: PixelFormat::Bgra,
+            tile_size: 64,
+            ..SessionConfig::default()
+        };
+        // As the session does, converted to what the encoder takes first
+        let input = encode::input_format(&config);
+        let mut converted = Vec::new();
+        let mut encode = |state: &mut tiles::TileState, screen: &[u8], scratch: &mut Vec<u8>| {
+            pixels::convert_bgra(screen, input, &mut converted);
+            let bpp = input.bytes_per_pixel();
+            tiles::encode(state, &converted, (width, height), bpp, &config, scratch).unwrap()
+        };
+        let mut state = tiles::TileState::default();
+        let mut scratch = Vec::new();
+        let mut decoder = Decoder::new(PixelFormat::Bgra);
+
+        let mut screen = image(width, height, 1);
+        let (data, format) = encode(&mut state, &screen, &mut scratch);
+        assert_eq!(format, FrameFormat::TiledKeyframe);
+        decoder.feed(&data, &tiled(format)).unwrap();
+        assert_eq!(decoder.frame().unwrap().data, screen);
+
+        // A change inside one tile
+        let row = width as usize * 4;
+        for y in 70..90 {
+            for pixel in screen[y * row + 80 * 4..y * row + 100 * 4].chunks_exact_mut(4) {
+                pixel.copy_from_slice(&[0x40, 0x40, 0x40, 0xff]);
+            }
+        }
+        let (data, format) = encode(&mut state, &screen, &mut scratch);
+        assert_eq!(format, FrameFormat::TiledDelta);
+        decoder.feed(&data, &tiled(format)).unwrap();
+        let frame = decoder.frame().unwrap();
+        assert_eq!((frame.width, frame.height), (width, height));
+        assert_eq!(frame.data, screen);
+    }
+
+    /// A tiny container placing a 1x1 tile far out is refused before a
+    /// canvas that size is allocated.
+    #[test]
+    fn keyframe_beyond_the_largest_image_is_refused() {
+        let mut decoder = Decoder::new(PixelFormat::Bgra);
+        let data = container(&[(rect(65535, 65535, 1, 1), &[0; 4])]);
+        assert_eq!(
+            decoder.feed(&data, &tiled(FrameFormat::TiledKeyframe)),
+            Err(RdpStatus::DecodeFailed)
+        );
+        assert!(decoder.frame().is_none());
+    }
+
+    /// Rects whose ends overflow `u32` fail cleanly, on keyframes and on
+    /// deltas, and leave the image as it was.
+    #[test]
+    fn overflowing_tile_rects_are_refused() {
+        let mut decoder = Decoder::new(PixelFormat::Bgra);
+        let pixel = [1, 2, 3, 0xff];
+        let keyframe = container(&[(rect(0, 0, 1, 1), &pixel)]);
+        decoder
+            .feed(&keyframe, &tiled(FrameFormat::TiledKeyframe))
+            .unwrap();
+
+        for rect in [rect(u32::MAX, 0, 1, 1), rect(0, u32::MAX, 1, 1)] {
+            let data = container(&[(rect, &[0; 4])]);
+            for format in [FrameFormat::TiledKeyframe, FrameFormat::TiledDelta] {
+                assert_eq!(
+                    decoder.feed(&data, &tiled(format)),
+                    Err(RdpStatus::DecodeFailed),
+                    "{format:?} with {rect:?}"
+                );
+            }
+        }
+        assert_eq!(decoder.frame().unwrap().data, pixel);
+
+        // The failures broke the chain of deltas
+        let delta = container(&[(rect(0, 0, 1, 1), &pixel)]);
+        assert_eq!(
+            decoder.feed(&delta, &tiled(FrameFormat::TiledDelta)),
+            Err(RdpStatus::KeyframeNeeded)
+        );
+    }
+
+    /// A delta tile inside the size limit but outside the image asks for a
+    /// keyframe instead of drawing past the image.
+    #[test]
+    fn delta_outside_the_image_needs_a_keyframe() {
+        let mut decoder = Decoder::new(PixelFormat::Bgra);
+        let keyframe = container(&[(rect(0, 0, 2, 2), &[0; 16])]);
+        decoder
+            .feed(&keyframe, &tiled(FrameFormat::TiledKeyframe))
+            .unwrap();
+        let delta = container(&[(rect(1, 1, 2, 2), &[0; 16])]);
+        assert_eq!(
+            decoder.feed(&delta, &tiled(FrameFormat::TiledDelta)),
+            Err(RdpStatus::KeyframeNeeded)
+        );
+    }
+
+    /// Sizes that only the caller's `FrameInfo` gives are limited too.
+    #[test]
+    fn raw_frames_beyond_the_largest_image_are_refused() {
+        let mut decoder = Decoder::new(PixelFormat::Bgra);
+        let info = FrameInfo {
+            format: FrameFormat::Raw,
+            width: 1,
+            height: MAX_SIDE + 1,
+            stride: 4,
+            pixel_format: PixelFormat::Bgra,
+            keyframe: true,
+        };
+        assert_eq!(decoder.feed(&[0; 64], &info), Err(RdpStatus::DecodeFailed));
+
+        let info = FrameInfo {
+            height: 2,
+            stride: 4,
+            ..info
+        };
+        decoder.feed(&[9; 8], &info).unwrap();
+        assert_eq!(decoder.frame().unwrap().data, [9; 8]);
+    }
+}
@@ -0,0 +1,405 @@
+use std::ffi::c_void;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::log::{self, LogLevel};
+use crate::pixels;
+
+/// Description of one display, as reported by `rdp_display_info`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DisplayInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Non-zero for the display `Display::primary()` (display index -1)
+    /// would capture.
+    pub is_primary: u8,
+    /// Opaque identifier that stays with the display while it is connected,
+    /// whatever happens to the indices around it: a hash of the device name
+    /// on Windows, of the display ID on macOS and of the root window and
+    /// position in the layout on X11, which names nothing more stable.
+    pub id: u64,
+    /// Physical pixels per logical point: 2 on a Retina panel, 1.5 at 150%
+    /// scaling on Windows. Always 1 on X11, which has no per-display scale.
+    pub scale: f32,
+    /// Top-left corner on the virtual desktop: root window pixels on X11,
+    /// virtual desktop pixels on Windows and global points on macOS.
+    pub x: i32,
+    pub y: i32,
+}
+
+/// Display index of sessions spanning every display (`RdpSession::new`).
+pub const SPAN_ALL: i32 = -2;
+
+/// Lists every display in `Display::all()` order, so positions in the
+/// returned Vec are valid session display indices.
+pub fn enumerate() -> io::Result<Vec<DisplayInfo>> {
+    platform::list()
+}
+
+/// Index of the display with `id`, if it is still connected.
+pub fn index_of(id: u64) -> io::Result<Option<usize>> {
+    Ok(enumerate()?.iter().position(|d| d.id == id))
+}
+
+/// Notified with the new display count whenever displays are added,
+/// removed or changed.
+pub type DisplayCallback = extern "C" fn(count: i32, user_data: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Listener {
+    callback: DisplayCallback,
+    user_data: *mut c_void,
+}
+
+// The host owns `user_data` and promises it can be used from any thread that
+// calls into the library.
+unsafe impl Send for Listener {}
+
+/// The list as of the last refresh, to tell when it changes.
+static KNOWN: Mutex<Option<Vec<DisplayInfo>>> = Mutex::new(None);
+static LISTENER: Mutex<Option<Listener>> = Mutex::new(None);
+static WATCHER: Mutex<Option<Watcher>> = Mutex::new(None);
+
+/// How often the watcher re-enumerates while a callback is installed.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Re-enumerates the displays, notifying the callback if the list differs
+/// from the previous refresh.
+pub fn refresh() -> io::Result<Vec<DisplayInfo>> {
+    let all = enumerate()?;
+    let previous = KNOWN
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .replace(all.clone());
+    if previous.is_some_and(|previous| previous != all) {
+        log::log(
+            LogLevel::Info,
+            &format!("Display list changed: {} connected", all.len()),
+        );
+        // Copied out so the callback runs without the lock held
+        let listener = *LISTENER.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(listener) = listener {
+            (listener.callback)(all.len() as i32, listener.user_data);
+        }
+    }
+    Ok(all)
+}
+
+/// Installs `callback` and starts watching for display changes, or stops
+/// when `None`.
+pub fn set_callback(callback: Option<DisplayCallback>, user_data: *mut c_void) {
+    *LISTENER.lock().unwrap_or_else(PoisonError::into_inner) = callback.map(|callback| Listener {
+        callback,
+        user_data,
+    });
+    if callback.is_some() {
+        // So the watcher's first pass compares against the list as installed
+        let _ = refresh();
+    }
+    // Stopped outside the lock, as the thread may be calling in itself
+    let stopped = {
+        let mut watcher = WATCHER.lock().unwrap_or_else(PoisonError::into_inner);
+        match (callback.is_some(), watcher.is_some()) {
+            (true, false) => {
+                *watcher = Watcher::start();
+                None
+            }
+            (false, true) => watcher.take(),
+            _ => None,
+        }
+    };
+    if let Some(watcher) = stopped {
+        watcher.stop();
+    }
+}
+
+/// Background thread refreshing the display list every `WATCH_INTERVAL`.
+struct Watcher {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Watcher {
+    fn start() -> Option<Watcher> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::Builder::new()
+            .name("rdp-displays".into())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Acquire) {
+                    thread::park_timeout(WATCH_INTERVAL);
+                    if !thread_stop.load(Ordering::Acquire) {
+                        let _ = refresh();
+                    }
+                }
+            });
+        match thread {
+            Ok(thread) => Some(Watcher { stop, thread }),
+            Err(e) => {
+                log::log(
+                    LogLevel::Error,
+                    &format!("Failed to start display watcher: {e}"),
+                );
+                None
+            }
+        }
+    }
+
+    /// Ends the thread; joins it unless called from it (i.e. from the
+    /// callback), in which case it exits once the callback returns.
+    fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        if self.thread.thread().id() == thread::current().id() {
+            return;
+        }
+        self.thread.thread().unpark();
+        let _ = self.thread.join();
+    }
+}
+
+/// Identifier of a display from the platform's name for it.
+fn id_of(name: &[u8], salt: &[u32]) -> u64 {
+    pixels::frame_hash(name, salt)
+}
+
+// The monitor geometry calls, declared once for `cursor` too: two
+// declarations of one foreign function must agree on their types
+#[cfg(target_os = "macos")]
+pub(crate) use platform::{CGDisplayBounds, CGPoint, CGRect, CGSize};
+#[cfg(windows)]
+pub(crate) use platform::{EnumDisplayMonitors, GetMonitorInfoW, MonitorInfoExW, Rect};
+
+/// scrap keeps the platform handles private, so the list is built from the
+/// platform modules directly, mirroring `Display::all()` and
+/// `Display::primary()`.
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use std::io;
+    use std::rc::Rc;
+
+    use scrap::x11::Server;
+
+    use super::{DisplayInfo, id_of};
+
+    pub fn list() -> io::Result<Vec<DisplayInfo>> {
+        let server = Rc::new(
+            Server::default().map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?,
+        );
+        let displays: Vec<_> = Server::displays(server).collect();
+        let primary = displays.iter().position(|d| d.is_default()).unwrap_or(0);
+
+        Ok(displays
+            .iter()
+            .enumerate()
+            .map(|(i, d)| {
+                let rect = d.rect();
+                DisplayInfo {
+                    width: u32::from(rect.w),
+                    height: u32::from(rect.h),
+                    is_primary: u8::from(i == primary),
+                    id: id_of(&[], &[d.root(), rect.x as u32, rect.y as u32]),
+                    scale: 1.0,
+                    x: i32::from(rect.x),
+                    y: i32::from(rect.y),
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::io;
+
+    use scrap::quartz::Display;
+
+    use super::{DisplayInfo, id_of};
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub(crate) struct CGPoint {
+        pub(crate) x: f64,
+        pub(crate) y: f64,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub(crate) struct CGSize {
+        pub(crate) width: f64,
+        pub(crate) height: f64,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub(crate) struct CGRect {
+        pub(crate) origin: CGPoint,
+        pub(crate) size: CGSize,
+    }
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    unsafe extern "C" {
+        pub(crate) fn CGDisplayBounds(display: u32) -> CGRect;
+    }
+
+    /// Frames come at the backing resolution, bounds are in points.
+    fn scale_of(display: Display, bounds: CGRect) -> f32 {
+        if bounds.size.width > 0.0 {
+            (display.width() as f64 / bounds.size.width) as f32
+        } else {
+            1.0
+        }
+    }
+
+    pub fn list() -> io::Result<Vec<DisplayInfo>> {
+        let displays = Display::online().map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+        let primary = displays.iter().position(|d| d.is_primary()).unwrap_or(0);
+
+        Ok(displays
+            .iter()
+            .enumerate()
+            .map(|(i, d)| {
+                let bounds = unsafe { CGDisplayBounds(d.id()) };
+                DisplayInfo {
+                    width: d.width() as u32,
+                    height: d.height() as u32,
+                    is_primary: u8::from(i == primary),
+                    id: id_of(&[], &[d.id()]),
+                    scale: scale_of(*d, bounds),
+                    x: bounds.origin.x.round() as i32,
+                    y: bounds.origin.y.round() as i32,
+                }
+            })
+            .collect())
+    }
+}
+
+/// DXGI's primary is simply the first output enumerated.
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+    use std::io;
+
+    use scrap::dxgi::Displays;
+
+    use super::{DisplayInfo, id_of};
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub(crate) struct Rect {
+        pub(crate) left: i32,
+        pub(crate) top: i32,
+        pub(crate) right: i32,
+        pub(crate) bottom: i32,
+    }
+
+    #[repr(C)]
+    pub(crate) struct MonitorInfoExW {
+        pub(crate) cb_size: u32,
+        pub(crate) rc_monitor: Rect,
+        pub(crate) rc_work: Rect,
+        pub(crate) flags: u32,
+        pub(crate) device: [u16; 32],
+    }
+
+    type MonitorEnumProc =
+        unsafe extern "system" fn(*mut c_void, *mut c_void, *mut Rect, isize) -> i32;
+
+    #[link(name = "user32")]
+    unsafe extern "system" {
+        pub(crate) fn EnumDisplayMonitors(
+            hdc: *mut c_void,
+            clip: *const Rect,
+            callback: MonitorEnumProc,
+            data: isize,
+        ) -> i32;
+        pub(crate) fn GetMonitorInfoW(monitor: *mut c_void, info: *mut MonitorInfoExW) -> i32;
+    }
+
+    /// `MDT_EFFECTIVE_DPI`: the DPI the user's scaling setting asks for.
+    const MDT_EFFECTIVE_DPI: i32 = 0;
+
+    #[link(name = "shcore")]
+    unsafe extern "system" {
+        fn GetDpiForMonitor(
+            monitor: *mut c_void,
+            dpi_type: i32,
+            dpi_x: *mut u32,
+            dpi_y: *mut u32,
+        ) -> i32;
+    }
+
+    unsafe extern "system" fn collect(
+        monitor: *mut c_void,
+        _hdc: *mut c_void,
+        _rect: *mut Rect,
+        data: isize,
+    ) -> i32 {
+        let found = unsafe { &mut *(data as *mut Vec<Monitor>) };
+        let mut info = MonitorInfoExW {
+            cb_size: std::mem::size_of::<MonitorInfoExW>() as u32,
+            rc_monitor: Rect::default(),
+            rc_work: Rect::default(),
+            flags: 0,
+            device: [0; 32],
+        };
+        let (mut dpi_x, mut dpi_y) = (0, 0);
+        if unsafe { GetMonitorInfoW(monitor, &mut info) } != 0
+            && unsafe { GetDpiForMonitor(monitor, MDT_EFFECTIVE_DPI, &mut dpi_x, &mut dpi_y) } == 0
+        {
+            found.push(Monitor {
+                device: info.device,
+                scale: dpi_x as f32 / 96.0,
+                origin: (info.rc_monitor.left, info.rc_monitor.top),
+            });
+        }
+        1
+    }
+
+    /// A GDI monitor, named as the DXGI output showing it.
+    struct Monitor {
+        device: [u16; 32],
+        scale: f32,
+        origin: (i32, i32),
+    }
+
+    /// Scale and position of every monitor.
+    fn monitors() -> Vec<Monitor> {
+        let mut monitors = Vec::new();
+        unsafe {
+            EnumDisplayMonitors(
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                collect,
+                &mut monitors as *mut _ as isize,
+            );
+        }
+        monitors
+    }
+
+    pub fn list() -> io::Result<Vec<DisplayInfo>> {
+        let monitors = monitors();
+        Ok(Displays::new()?
+            .enumerate()
+            .map(|(i, d)| {
+                let name: Vec<u8> = d.name().iter().flat_map(|c| c.to_le_bytes()).collect();
+                let monitor = monitors
+                    .iter()
+                    .find(|m| m.device.iter().take_while(|&&c| c != 0).eq(d.name()));
+                let (x, y) = monitor.map_or((0, 0), |m| m.origin);
+                DisplayInfo {
+                    width: d.width() as u32,
+                    height: d.height() as u32,
+                    is_primary: u8::from(i == 0),
+                    id: id_of(&name, &[]),
+                    scale: monitor.map_or(1.0, |m| m.scale),
+                    x,
+                    y,
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,552 @@
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{ExtendedColorType, ImageEncoder};
+
+use crate::error::{RdpStatus, fail};
+use crate::frame::{FrameFormat, PixelFormat};
+use crate::parallel;
+use crate::restart;
+use crate::session::SessionConfig;
+use crate::yuv;
+use crate::zstd;
+
+/// JPEG chroma subsampling. The `image` encoder used without the
+/// `turbojpeg` feature only honours `Gray`, and encodes colour at 4:4:4.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsampling {
+    Yuv444 = 0,
+    Yuv422 = 1,
+    Yuv420 = 2,
+    /// Luma only.
+    Gray = 3,
+}
+
+impl Subsampling {
+    /// Maps an FFI subsampling value (0 = 4:4:4, 1 = 4:2:2, 2 = 4:2:0,
+    /// 3 = grayscale) back onto the enum.
+    pub fn from_i32(value: i32) -> Option<Subsampling> {
+        match value {
+            0 => Some(Subsampling::Yuv444),
+            1 => Some(Subsampling::Yuv422),
+            2 => Some(Subsampling::Yuv420),
+            3 => Some(Subsampling::Gray),
+            _ => None,
+        }
+    }
+}
+
+/// Maps the FFI PNG compression level (0 = fast, 1 = default, 2 = best)
+/// onto the `image` crate's setting.
+pub fn png_compression_from_i32(value: i32) -> Option<CompressionType> {
+    match value {
+        0 => Some(CompressionType::Fast),
+        1 => Some(CompressionType::Default),
+        2 => Some(CompressionType::Best),
+        _ => None,
+    }
+}
+
+/// Fails with `RdpStatus::UnsupportedFormat` if `format` is not compiled
+/// into this build, so callers find out when configuring rather than on the
+/// first capture.
+pub fn ensure_supported(format: FrameFormat) -> Result<(), RdpStatus> {
+    if format == FrameFormat::WebP && !cfg!(feature = "webp") {
+        return Err(fail(
+            RdpStatus::UnsupportedFormat,
+            "WebP output requires building rdp_core with the `webp` feature",
+        ));
+    }
+    if format == FrameFormat::H264 && !cfg!(feature = "h264") {
+        return Err(fail(
+            RdpStatus::UnsupportedFormat,
+            "H.264 output requires building rdp_core with the `h264` feature",
+        ));
+    }
+    if format == FrameFormat::RawZstd && !cfg!(feature = "zstd") {
+        return Err(fail(
+            RdpStatus::UnsupportedFormat,
+            "zstd output requires building rdp_core with the `zstd` feature",
+        ));
+    }
+    if matches!(format, FrameFormat::Vp8 | FrameFormat::Vp9) && !cfg!(feature = "vpx") {
+        return Err(fail(
+            RdpStatus::UnsupportedFormat,
+            "VP8/VP9 output requires building rdp_core with the `vpx` feature",
+        ));
+    }
+    Ok(())
+}
+
+/// The layout the pipeline should convert captured BGRA into before
+/// calling `encode`. Grayscale sessions always use luma. Otherwise turbojpeg
+/// reads any of our layouts directly, so JPEG honours the configured one
+/// (the BGRA default skips conversion entirely); the `image` encoders,
+/// JPEG included without the `turbojpeg` feature, only take RGB or luma,
+/// and the video codecs and YUV outputs convert captured BGRA themselves.
+pub fn input_format(config: &SessionConfig) -> PixelFormat {
+    if config.grayscale {
+        return PixelFormat::Gray;
+    }
+    match config.format {
+        FrameFormat::Jpeg if !cfg!(feature = "turbojpeg") => {
+            if config.subsampling == Subsampling::Gray {
+                PixelFormat::Gray
+            } else {
+                PixelFormat::Rgb
+            }
+        }
+        FrameFormat::Jpeg | FrameFormat::Raw | FrameFormat::RawZstd => config.pixel_format,
+        FrameFormat::Png | FrameFormat::WebP => PixelFormat::Rgb,
+        FrameFormat::H264
+        | FrameFormat::Vp8
+        | FrameFormat::Vp9
+        | FrameFormat::I420
+        | FrameFormat::Nv12 => PixelFormat::Bgra,
+        // Never configured directly; tiling wraps one of the others
+        FrameFormat::TiledKeyframe | FrameFormat::TiledDelta | FrameFormat::Text => {
+            config.pixel_format
+        }
+    }
+}
+
+/// Produces the `config.format` payload for a tightly packed `width x height`
+/// frame laid out as `input_format(config)`. `Raw` returns a copy of the
+/// pixels, since `pixels` may be a buffer the session reuses. `I420` and
+/// `Nv12` drop an odd last column or row (see `yuv::even_size`).
+pub fn encode(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    config: &SessionConfig,
+) -> Result<Vec<u8>, RdpStatus> {
+    let color = match input_format(config) {
+        PixelFormat::Gray => ExtendedColorType::L8,
+        _ => ExtendedColorType::Rgb8,
+    };
+
+    match config.format {
+        FrameFormat::Raw => Ok(pixels.to_vec()),
+        // Without delta coding, which needs the session's previous frame
+        FrameFormat::RawZstd => zstd::compress(pixels, config.zstd_level),
+        // Progressive scans each cover the whole frame, so restart
+        // markers could not confine a loss to a band anyway
+        FrameFormat::Jpeg if config.restart_rows > 0 && !config.progressive => {
+            encode_jpeg_with_restarts(pixels, width, height, config)
+        }
+        FrameFormat::Jpeg => encode_jpeg(pixels, width, height, config),
+        FrameFormat::I420 | FrameFormat::Nv12 => {
+            let mut data = Vec::new();
+            let convert = match config.format {
+                FrameFormat::I420 => yuv::to_i420,
+                _ => yuv::to_nv12,
+            };
+            let format = input_format(config);
+            convert(pixels, width, height, format, config.yuv_matrix, &mut data);
+            Ok(data)
+        }
+        FrameFormat::WebP => encode_webp(pixels, width, height, color, config.quality),
+        FrameFormat::TiledKeyframe | FrameFormat::TiledDelta => Err(fail(
+            RdpStatus::InvalidArgument,
+            "Tiled formats are containers, enable them with tiling instead",
+        )),
+        FrameFormat::Text => Err(fail(
+            RdpStatus::InvalidArgument,
+            "Text is not an image format",
+        )),
+        // Coded against the previous frames, so the session's encoder
+        // handles them (see `video`)
+        FrameFormat::H264 | FrameFormat::Vp8 | FrameFormat::Vp9 => Err(fail(
+            RdpStatus::InvalidArgument,
+            "Video frames can only be encoded by a session",
+        )),
+        FrameFormat::Png => {
+            let mut data = Vec::new();
+            let encoder = PngEncoder::new_with_quality(
+                &mut data,
+                config.png_compression,
+                FilterType::Adaptive,
+            );
+            match encoder.write_image(pixels, width, height, color) {
+                Ok(()) => Ok(data),
+                Err(e) => Err(fail(
+                    RdpStatus::EncodeFailed,
+                    format!("Failed to compress PNG: {e}"),
+                )),
+            }
+        }
+    }
+}
+
+/// Width and height of the JPEG's MCUs, the blocks restart intervals are
+/// counted in: 16 pixels along each subsampled axis, 8 otherwise.
+fn mcu_size(config: &SessionConfig) -> (u32, u32) {
+    if !cfg!(feature = "turbojpeg") || input_format(config) == PixelFormat::Gray {
+        return (8, 8);
+    }
+    match config.subsampling {
+        Subsampling::Yuv444 | Subsampling::Gray => (8, 8),
+        Subsampling::Yuv422 => (16, 8),
+        Subsampling::Yuv420 => (16, 16),
+    }
+}
+
+/// A JPEG with a restart marker every `config.restart_rows` MCU rows,
+/// joined from strips of that height encoded on the pool (see `restart`).
+/// Rows are capped where the interval, counted in MCUs, would overflow its
+/// 16 bits.
+fn encode_jpeg_with_restarts(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    config: &SessionConfig,
+) -> Result<Vec<u8>, RdpStatus> {
+    let (mcu_w, mcu_h) = mcu_size(config);
+    let per_row = width.div_ceil(mcu_w).max(1);
+    let rows = config
+        .restart_rows
+        .min(u32::from(u16::MAX) / per_row)
+        .max(1);
+    let strip_h = rows * mcu_h;
+    if strip_h >= height {
+        return encode_jpeg(pixels, width, height, config);
+    }
+
+    let row_len = (width * input_format(config).bytes_per_pixel()) as usize;
+    let strips: Vec<(u32, u32)> = (0..height)
+        .step_by(strip_h as usize)
+        .map(|y| (y, strip_h.min(height - y)))
+        .collect();
+    let encoded = parallel::map(&strips, |&(y, h)| {
+        let start = y as usize * row_len;
+        let strip = &pixels[start..start + h as usize * row_len];
+        encode_jpeg(strip, width, h, config)
+    })?;
+    restart::join(&encoded, height, (rows * per_row) as u16)
+}
+
+#[cfg(feature = "turbojpeg")]
+fn encode_jpeg(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    config: &SessionConfig,
+) -> Result<Vec<u8>, RdpStatus> {
+    // turbojpeg reads the post-resize buffer in place, whatever its layout.
+    // Captured pixels are B, G, R, X on every platform Scrap supports (the
+    // fourth byte is not a meaningful alpha on X11), hence the X variants.
+    let pixel_format = input_format(config);
+    let image = turbojpeg::Image {
+        pixels,
+        width: width as usize,
+        pitch: (width * pixel_format.bytes_per_pixel()) as usize,
+        height: height as usize,
+        format: match pixel_format {
+            PixelFormat::Bgra => turbojpeg::PixelFormat::BGRX,
+            PixelFormat::Rgb => turbojpeg::PixelFormat::RGB,
+            PixelFormat::Bgr => turbojpeg::PixelFormat::BGR,
+            PixelFormat::Rgba => turbojpeg::PixelFormat::RGBX,
+            PixelFormat::Gray => turbojpeg::PixelFormat::GRAY,
+        },
+    };
+
+    // Luma input can only be encoded without chroma
+    let subsampling = if pixel_format == PixelFormat::Gray {
+        turbojpeg::Subsamp::Gray
+    } else {
+        match config.subsampling {
+            Subsampling::Yuv444 => turbojpeg::Subsamp::None,
+            Subsampling::Yuv422 => turbojpeg::Subsamp::Sub2x1,
+            Subsampling::Yuv420 => turbojpeg::Subsamp::Sub2x2,
+            Subsampling::Gray => turbojpeg::Subsamp::Gray,
+        }
+    };
+
+    let quality = i32::from(config.quality);
+    let compressed = if config.progressive {
+        compress_progressive(image, subsampling, quality)
+    } else {
+        turbojpeg::Compressor::new()
+            .and_then(|mut compressor| {
+                compressor.set_quality(quality);
+                compressor.set_subsamp(subsampling);
+                // Straight into a Vec, without an intermediate turbojpeg
+                // buffer
+                compressor.compress_to_vec(image)
+            })
+            .map_err(|e| e.to_string())
+    };
+    match compressed {
+        Ok(data) => Ok(data),
+        Err(e) => Err(fail(
+            RdpStatus::EncodeFailed,
+            format!("Failed to compress JPEG: {e}"),
+        )),
+    }
+}
+
+/// A progressive JPEG, through the C API since `turbojpeg::Compressor`
+/// has no way to pass `TJFLAG_PROGRESSIVE`.
+#[cfg(feature = "turbojpeg")]
+fn compress_progressive(
+    image: turbojpeg::Image<&[u8]>,
+    subsampling: turbojpeg::Subsamp,
+    quality: i32,
+) -> Result<Vec<u8>, String> {
+    use std::ffi::{CStr, c_int, c_ulong};
+    use turbojpeg::raw;
+
+    let error = |handle| {
+        // SAFETY: turbojpeg returns a NUL-terminated message for any
+        // handle, null included
+        unsafe { CStr::from_ptr(raw::tjGetErrorStr2(handle)) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    // SAFETY: `image` describes `pitch * height` readable bytes, as the
+    // caller built it from the frame; turbojpeg allocates `out`, which is
+    // copied before it and the handle are released
+    unsafe {
+        let handle = raw::tjInitCompress();
+        if handle.is_null() {
+            return Err(error(handle));
+        }
+        let mut out = std::ptr::null_mut();
+        let mut len: c_ulong = 0;
+        let status = raw::tjCompress2(
+            handle,
+            image.pixels.as_ptr(),
+            image.width as c_int,
+            image.pitch as c_int,
+            image.height as c_int,
+            image.format as c_int,
+            &mut out,
+            &mut len,
+            subsampling as c_int,
+            quality,
+            raw::TJFLAG_PROGRESSIVE as c_int,
+        );
+        let result = if status == 0 && !out.is_null() {
+            Ok(std::slice::from_raw_parts(out, len as usize).to_vec())
+        } else {
+            Err(error(handle))
+        };
+        raw::tjFree(out);
+        raw::tjDestroy(handle);
+        result
+    }
+}
+
+/// The pure-Rust fallback: `pixels` is RGB, or luma for grayscale output
+/// (see `input_format`). Chroma is never subsampled, and progressive
+/// output is unsupported.
+#[cfg(not(feature = "turbojpeg"))]
+fn encode_jpeg(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    config: &SessionConfig,
+) -> Result<Vec<u8>, RdpStatus> {
+    use image::codecs::jpeg::JpegEncoder;
+
+    if config.progressive {
+        return Err(fail(
+            RdpStatus::Unsupported,
+            "Progressive JPEG requires building rdp_core with the `turbojpeg` feature",
+        ));
+    }
+
+    let color = match input_format(config) {
+        PixelFormat::Gray => ExtendedColorType::L8,
+        _ => ExtendedColorType::Rgb8,
+    };
+    let mut data = Vec::new();
+    match JpegEncoder::new_with_quality(&mut data, config.quality)
+        .encode(pixels, width, height, color)
+    {
+        Ok(()) => Ok(data),
+        Err(e) => Err(fail(
+            RdpStatus::EncodeFailed,
+            format!("Failed to compress JPEG: {e}"),
+        )),
+    }
+}
+
+/// Lossy WebP at `quality` (1–100). libwebp only reads RGB(A), so luma is
+/// spread over the three channels first; the encoder's YUV conversion then
+/// leaves the chroma neutral.
+#[cfg(feature = "webp")]
+fn encode_webp(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ExtendedColorType,
+    quality: u8,
+) -> Result<Vec<u8>, RdpStatus> {
+    let rgb: Vec<u8>;
+    let pixels = if color == ExtendedColorType::L8 {
+        rgb = pixels.iter().flat_map(|&y| [y, y, y]).collect();
+        &rgb
+    } else {
+        pixels
+    };
+    match webp::Encoder::from_rgb(pixels, width, height).encode_simple(false, f32::from(quality)) {
+        Ok(data) => Ok(data.to_vec()),
+        Err(e) => Err(fail(
+            RdpStatus::EncodeFailed,
+            format!("Failed to compress WebP: {e:?}"),
+        )),
+    }
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp(
+    _pixels: &[u8],
+    _width: u32,
+    _height: u32,
+    _color: ExtendedColorType,
+    _quality: u8,
+) -> Result<Vec<u8>, RdpStatus> {
+    ensure_supported(FrameFormat::WebP)?;
+    unreachable!("WebP is only supported with the `webp` feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixels;
+
+    /// A BGRA gradient with fine detail, which JPEG quality shows up in.
+    fn bgra(width: u32, height: u32) -> Vec<u8> {
+        let mut frame = Vec::with_capacity((width * height * 4) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let detail = if (x * 7 + y * 13) % 5 == 0 { 60 } else { 0 };
+                frame.extend_from_slice(&[
+                    (x * 255 / width) as u8,
+                    (y * 255 / height) as u8,
+                    ((x + y) % 256) as u8 ^ detail,
+                    255,
+                ]);
+            }
+        }
+        frame
+    }
+
+    /// `bgra` in the layout `config` encodes from.
+    fn input(bgra: &[u8], config: &SessionConfig) -> Vec<u8> {
+        let mut pixels = Vec::new();
+        pixels::convert_bgra(bgra, input_format(config), &mut pixels);
+        pixels
+    }
+
+    #[test]
+    fn higher_quality_makes_larger_jpegs() {
+        let (width, height) = (96, 64);
+        let frame = bgra(width, height);
+        let mut last = 0;
+        for quality in [1, 10, 40, 70, 90, 100] {
+            let config = SessionConfig {
+                format: FrameFormat::Jpeg,
+                quality,
+                ..SessionConfig::default()
+            };
+            let jpeg = encode(&input(&frame, &config), width, height, &config).unwrap();
+            assert!(
+                jpeg.len() > last,
+                "quality {quality} gave {} bytes, no more than the {last} before",
+                jpeg.len()
+            );
+            last = jpeg.len();
+        }
+    }
+
+    #[test]
+    fn every_input_order_decodes_to_the_same_colours() {
+        let (width, height) = (64, 48);
+        // Smooth, so JPEG keeps it close
+        let mut frame = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                frame.extend_from_slice(&[(x * 4) as u8, (y * 5) as u8, 200 - (x + y) as u8, 255]);
+            }
+        }
+        let mut decoded = Vec::new();
+        for pixel_format in [
+            PixelFormat::Bgra,
+            PixelFormat::Rgb,
+            PixelFormat::Bgr,
+            PixelFormat::Rgba,
+        ] {
+            let config = SessionConfig {
+                format: FrameFormat::Jpeg,
+                quality: 95,
+                subsampling: Subsampling::Yuv444,
+                pixel_format,
+                ..SessionConfig::default()
+            };
+            let jpeg = encode(&input(&frame, &config), width, height, &config).unwrap();
+            let rgb = image::load_from_memory(&jpeg).unwrap().to_rgb8().into_raw();
+            let error = frame
+                .chunks_exact(4)
+                .zip(rgb.chunks_exact(3))
+                .flat_map(|(bgra, rgb)| [(bgra[2], rgb[0]), (bgra[1], rgb[1]), (bgra[0], rgb[2])])
+                .map(|(want, got)| want.abs_diff(got))
+                .max()
+                .unwrap();
+            assert!(error <= 8, "{pixel_format:?} input is off by up to {error}");
+            decoded.push((pixel_format, rgb));
+        }
+        // The direct BGRA path against the RGB conversion it replaced
+        let (_, bgra) = &decoded[0];
+        for (pixel_format, rgb) in &decoded[1..] {
+            let error = bgra
+                .iter()
+                .zip(rgb)
+                .map(|(a, b)| a.abs_diff(*b))
+                .max()
+                .unwrap();
+            assert!(
+                error <= 2,
+                "BGRA and {pixel_format:?} input differ by up to {error}"
+            );
+        }
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn webp_is_lossy_at_the_configured_quality() {
+        let (width, height) = (96, 64);
+        let frame = bgra(width, height);
+        let mut last = 0;
+        for quality in [1, 40, 70, 100] {
+            let config = SessionConfig {
+                format: FrameFormat::WebP,
+                quality,
+                ..SessionConfig::default()
+            };
+            let webp = encode(&input(&frame, &config), width, height, &config).unwrap();
+            assert!(webp.len() > last, "quality {quality}: {} bytes", webp.len());
+            last = webp.len();
+
+            let decoded = image::load_from_memory(&webp).unwrap().to_rgb8();
+            assert_eq!(decoded.dimensions(), (width, height));
+        }
+    }
+
+    #[cfg(not(feature = "webp"))]
+    #[test]
+    fn webp_without_the_feature_is_unsupported() {
+        assert_eq!(
+            ensure_supported(FrameFormat::WebP),
+            Err(RdpStatus::UnsupportedFormat)
+        );
+        let config = SessionConfig {
+            format: FrameFormat::WebP,
+            ..SessionConfig::default()
+        };
+        assert_eq!(
+            encode(&[0; 3], 1, 1, &config),
+            Err(RdpStatus::UnsupportedFormat)
+        );
+    }
+}
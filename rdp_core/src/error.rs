@@ -0,0 +1,361 @@
+use std::cell::RefCell;
+use std::ffi::{CString, c_char};
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::log::{self, LogLevel};
+
+/// Status codes returned across the FFI boundary. Zero is success, every
+/// failure is negative.
+///
+/// Bindings should mirror this enum rather than hard-coding the numbers;
+/// values are never reused or renumbered.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RdpStatus {
+    Ok = 0,
+    /// An argument was outside its documented range (e.g. an unknown
+    /// subsampling value, a zero-area region or a null pointer).
+    InvalidArgument = -1,
+    /// No display could be found (or none at the requested index).
+    NoDisplay = -2,
+    /// The display exists but a `Capturer` could not be created for it.
+    CapturerInitFailed = -3,
+    /// The capturer returned an error other than "would block".
+    CaptureFailed = -4,
+    /// The capturer reported a zero-sized display or frame.
+    EmptyFrame = -5,
+    /// The frame was shorter than `width * height * 4` bytes.
+    FrameTooSmall = -6,
+    /// The configured capture region lies entirely outside the display.
+    RegionOutOfBounds = -7,
+    /// An intermediate pixel buffer could not be built.
+    BufferFailed = -8,
+    /// Resizing to the target size failed.
+    ResizeFailed = -9,
+    /// The encoder rejected the frame.
+    EncodeFailed = -10,
+    /// No frame arrived within the allowed time.
+    Timeout = -11,
+    /// The library panicked internally; the message is available from
+    /// `rdp_last_error_message`.
+    Panic = -12,
+    /// A zero timeout was requested and no frame was ready yet.
+    WouldBlock = -13,
+    /// The requested output format is not compiled into this build.
+    UnsupportedFormat = -14,
+    /// A caller-provided buffer cannot hold the frame; the required size is
+    /// reported alongside.
+    BufferTooSmall = -15,
+    /// Change detection is on and the screen looks exactly as it did at the
+    /// previous capture; no frame was produced.
+    NoChange = -16,
+    /// The operation is not available on this platform or in this build.
+    Unsupported = -17,
+    /// The OS does not allow this process to inject input or capture the
+    /// screen (e.g. the macOS Accessibility or Screen Recording permission
+    /// has not been granted, or the user declined the Wayland portal's
+    /// screen sharing dialog).
+    PermissionDenied = -18,
+    /// Injected input was rejected or could not be delivered.
+    InjectionFailed = -19,
+    /// The system clipboard cannot be reached (no GUI session, no X server
+    /// under Wayland, or another process holds it).
+    ClipboardUnavailable = -20,
+    /// The session is already running a stream.
+    Busy = -21,
+    /// A socket could not be bound or set up.
+    NetworkError = -22,
+    /// The TLS certificate file could not be read or holds no valid PEM
+    /// certificate.
+    TlsCertificate = -23,
+    /// The TLS private key file could not be read or holds no valid PEM
+    /// private key.
+    TlsKey = -24,
+    /// The TLS private key does not belong to the certificate.
+    TlsKeyMismatch = -25,
+    /// A file could not be created or written, for a reason not covered
+    /// below (e.g. the disk is full).
+    FileError = -26,
+    /// A file could not be created or written for lack of permission.
+    FileAccessDenied = -27,
+    /// A file could not be created because its directory does not exist.
+    FileNotFound = -28,
+    /// The display's resolution changed. The session has already switched
+    /// to the new size and the next capture delivers a keyframe at it; no
+    /// frame was produced this time.
+    ResolutionChanged = -29,
+    /// The display is out of reach for now (locked workstation, secure
+    /// desktop, sleep); the session keeps trying to reopen it and resumes
+    /// with a keyframe once it is back. Window sessions also report their
+    /// window being minimized or off screen this way.
+    DisplayUnavailable = -30,
+    /// The session's display was disconnected. The session keeps looking
+    /// for it (within its recovery timeout) and resumes if it returns.
+    DisplayRemoved = -31,
+    /// The requested capture backend is not compiled into this build, or
+    /// cannot work in this environment (e.g. Wayland outside a Wayland
+    /// session).
+    BackendUnavailable = -32,
+    /// The window a window session shares was closed; every later capture
+    /// fails the same way.
+    WindowClosed = -33,
+    /// `RdpConfig::struct_size` is smaller than the first version of the
+    /// struct, or the caller's newer struct sets fields this library does
+    /// not know (they must be zero).
+    InvalidConfigSize = -34,
+    /// `RdpConfig::display_index` is below `SPAN_ALL`.
+    InvalidDisplayIndex = -35,
+    /// Only one of `RdpConfig::target_w` and `target_h` is 0.
+    InvalidTargetSize = -36,
+    /// `RdpConfig::quality` is outside 1–100.
+    InvalidQuality = -37,
+    /// `RdpConfig::subsampling` is not a known subsampling.
+    InvalidSubsampling = -38,
+    /// `RdpConfig::format` is not a capture format.
+    InvalidFormat = -39,
+    /// `RdpConfig::pixel_format` is not a known pixel format.
+    InvalidPixelFormat = -40,
+    /// `RdpConfig::fit_mode` is not a known fit mode.
+    InvalidFitMode = -41,
+    /// `RdpConfig::resize_alg` is not a known resize algorithm.
+    InvalidResizeAlg = -42,
+    /// `RdpConfig::orientation` is not a known orientation.
+    InvalidOrientation = -43,
+    /// `RdpConfig::scale` is not a positive, finite number.
+    InvalidScale = -44,
+    /// `RdpConfig::backend` is not a known capture backend.
+    InvalidBackend = -45,
+    /// The frame's working buffers do not fit the session's memory limit
+    /// (see `rdp_session_set_memory_limit`), even with the replay buffer's
+    /// stills given up; no frame was produced.
+    MemoryLimit = -46,
+    /// A delta frame (tiled, or zstd with delta coding) reached a decoder
+    /// without the frames it builds on, or on top of a frame of another
+    /// size; it can only go on from the next keyframe.
+    KeyframeNeeded = -47,
+    /// The frame data is damaged or not in the format it is labelled with.
+    DecodeFailed = -48,
+}
+
+/// A failure of the safe Rust API (`CaptureSession`), carrying the message
+/// `rdp_last_error_message` would report for it over FFI.
+///
+/// The variants a caller is likely to act on have their own; everything
+/// else is `Failed` with the underlying status.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CaptureError {
+    /// A non-blocking capture found no new frame yet.
+    WouldBlock,
+    /// No frame arrived within the session's timeout.
+    Timeout,
+    /// The screen has not changed since the previous frame.
+    NoChange,
+    /// The display changed resolution; the next capture is a keyframe at
+    /// the new size.
+    ResolutionChanged,
+    /// The display is out of reach for now (locked, asleep, window
+    /// minimized); the session keeps trying to reopen it.
+    DisplayUnavailable(String),
+    /// The display was disconnected; the session resumes if it returns
+    /// within the recovery timeout.
+    DisplayRemoved(String),
+    /// The shared window was closed.
+    WindowClosed(String),
+    /// No display matched, or none could be found at all.
+    NoDisplay(String),
+    /// The OS does not let this process capture the screen.
+    PermissionDenied(String),
+    /// A setting or argument was out of range.
+    InvalidArgument(String),
+    /// The format or backend is not compiled into this build, or the
+    /// operation is not available on this platform.
+    Unsupported {
+        status: RdpStatus,
+        message: String,
+    },
+    Failed {
+        status: RdpStatus,
+        message: String,
+    },
+}
+
+impl CaptureError {
+    /// The FFI status code for this error.
+    pub fn status(&self) -> RdpStatus {
+        match self {
+            CaptureError::WouldBlock => RdpStatus::WouldBlock,
+            CaptureError::Timeout => RdpStatus::Timeout,
+            CaptureError::NoChange => RdpStatus::NoChange,
+            CaptureError::ResolutionChanged => RdpStatus::ResolutionChanged,
+            CaptureError::DisplayUnavailable(_) => RdpStatus::DisplayUnavailable,
+            CaptureError::DisplayRemoved(_) => RdpStatus::DisplayRemoved,
+            CaptureError::WindowClosed(_) => RdpStatus::WindowClosed,
+            CaptureError::NoDisplay(_) => RdpStatus::NoDisplay,
+            CaptureError::PermissionDenied(_) => RdpStatus::PermissionDenied,
+            CaptureError::InvalidArgument(_) => RdpStatus::InvalidArgument,
+            CaptureError::Unsupported { status, .. } | CaptureError::Failed { status, .. } => {
+                *status
+            }
+        }
+    }
+
+    /// Whether a later capture on the same session may succeed, so a
+    /// capture loop should carry on rather than give up.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            CaptureError::WouldBlock
+                | CaptureError::Timeout
+                | CaptureError::NoChange
+                | CaptureError::ResolutionChanged
+                | CaptureError::DisplayUnavailable(_)
+                | CaptureError::DisplayRemoved(_)
+        )
+    }
+}
+
+impl From<RdpStatus> for CaptureError {
+    /// Pairs `status` with this thread's last error message, which is the
+    /// one `fail` recorded along with it.
+    fn from(status: RdpStatus) -> CaptureError {
+        let message = LAST_ERROR.with(|last| last.borrow().clone());
+        match status {
+            RdpStatus::WouldBlock => CaptureError::WouldBlock,
+            RdpStatus::Timeout => CaptureError::Timeout,
+            RdpStatus::NoChange => CaptureError::NoChange,
+            RdpStatus::ResolutionChanged => CaptureError::ResolutionChanged,
+            RdpStatus::DisplayUnavailable => CaptureError::DisplayUnavailable(message),
+            RdpStatus::DisplayRemoved => CaptureError::DisplayRemoved(message),
+            RdpStatus::WindowClosed => CaptureError::WindowClosed(message),
+            RdpStatus::NoDisplay => CaptureError::NoDisplay(message),
+            RdpStatus::PermissionDenied => CaptureError::PermissionDenied(message),
+            RdpStatus::InvalidArgument => CaptureError::InvalidArgument(message),
+            RdpStatus::Unsupported
+            | RdpStatus::UnsupportedFormat
+            | RdpStatus::BackendUnavailable => CaptureError::Unsupported { status, message },
+            status => CaptureError::Failed { status, message },
+        }
+    }
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::WouldBlock => f.write_str("no frame is ready yet"),
+            CaptureError::Timeout => f.write_str("timed out waiting for a frame"),
+            CaptureError::NoChange => f.write_str("the screen has not changed"),
+            CaptureError::ResolutionChanged => f.write_str("the display changed resolution"),
+            CaptureError::DisplayUnavailable(message)
+            | CaptureError::DisplayRemoved(message)
+            | CaptureError::WindowClosed(message)
+            | CaptureError::NoDisplay(message)
+            | CaptureError::PermissionDenied(message)
+            | CaptureError::InvalidArgument(message)
+            | CaptureError::Unsupported { message, .. } => f.write_str(message),
+            CaptureError::Failed { status, message } if message.is_empty() => {
+                write!(f, "{status:?}")
+            }
+            CaptureError::Failed { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+thread_local! {
+    /// Detail for the most recent failure on this thread.
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+    /// Copy handed out by `last_error_message`, kept alive until the next call.
+    static LAST_ERROR_C: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Records `message` as this thread's last error and logs it at error level.
+/// Returns `status` so failure paths can `return Err(fail(..))`.
+pub fn fail(status: RdpStatus, message: impl Into<String>) -> RdpStatus {
+    fail_at(LogLevel::Error, status, message)
+}
+
+/// `fail` for expected, non-fatal outcomes (e.g. "no frame yet") that should
+/// not be logged as errors.
+pub fn fail_at(level: LogLevel, status: RdpStatus, message: impl Into<String>) -> RdpStatus {
+    let message = message.into();
+    log::log(level, &message);
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    status
+}
+
+/// The message `fail` last recorded on this thread, for carrying a failure
+/// to the thread that asked for the work (see `parallel`).
+pub fn last_error() -> String {
+    LAST_ERROR.with(|last| last.borrow().clone())
+}
+
+/// Records `message` as this thread's last error without logging it, for a
+/// failure already logged on the thread it happened on.
+pub fn restore_last_error(status: RdpStatus, message: String) -> RdpStatus {
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    status
+}
+
+/// `fail` for a failed file operation, with the status matching `error`.
+pub fn fail_file(error: &io::Error, message: impl Into<String>) -> RdpStatus {
+    let status = match error.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+            RdpStatus::FileAccessDenied
+        }
+        io::ErrorKind::NotFound => RdpStatus::FileNotFound,
+        _ => RdpStatus::FileError,
+    };
+    fail(status, message)
+}
+
+/// Snapshot of this thread's last error as a C string. The pointer stays
+/// valid until the next call on the same thread.
+pub fn last_error_message() -> *const c_char {
+    let message = LAST_ERROR.with(|last| last.borrow().replace('\0', " "));
+    let message = CString::new(message).unwrap_or_default();
+
+    LAST_ERROR_C.with(|c| {
+        let mut c = c.borrow_mut();
+        *c = message;
+        c.as_ptr()
+    })
+}
+
+/// Runs `body`, turning a panic into `on_panic` (and a last-error message)
+/// so that no unwind ever crosses an `extern "C"` boundary. Every exported
+/// function is wrapped in this.
+pub fn guard<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(value) => value,
+        Err(payload) => {
+            let detail = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic payload".to_string());
+            fail(RdpStatus::Panic, format!("Internal panic: {detail}"));
+            on_panic
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guard_turns_panics_into_the_fallback_and_a_message() {
+        assert_eq!(guard(-1, || panic!("forced {}", 1)), -1);
+        assert_eq!(last_error(), "Internal panic: forced 1");
+        assert_eq!(guard(-1, || panic!("forced")), -1);
+        assert_eq!(last_error(), "Internal panic: forced");
+        assert_eq!(guard(-1, || panic::panic_any(7u8)), -1);
+        assert_eq!(last_error(), "Internal panic: unknown panic payload");
+        // Nothing is left broken for the next call
+        assert_eq!(guard(-1, || 5), 5);
+    }
+}
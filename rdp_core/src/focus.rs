@@ -0,0 +1,85 @@
+//! Focus mode: the rectangle around the cursor, where a presenter is
+//! working, encoded again at a higher JPEG quality on top of the frame.
+//!
+//! Frames are then tile containers (see `tiles`) whose last tile is the
+//! focus rectangle; drawing the tiles in order layers it over the rest.
+//! Untiled sessions send the whole frame at the session's quality and
+//! subsampling as a `TiledKeyframe`, then the focus. Tiled sessions append
+//! the focus to their keyframes, and to deltas whenever it moved or a
+//! changed tile drew over it; otherwise the client still shows it.
+//!
+//! The rectangle only follows the cursor once it leaves the middle half,
+//! and sits on a 16-pixel grid, so small movements leave it (and the
+//! client's picture) alone.
+
+use crate::encode::Subsampling;
+use crate::pixels::Rect;
+
+/// Quality of the focus rectangle when none is given.
+pub const DEFAULT_QUALITY: u8 = 90;
+
+/// The largest JPEG block; the rectangle's corner stays on this grid.
+const GRID: u32 = 16;
+
+/// What the focus rectangle looks like.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Focus {
+    /// Size in output pixels, shrunk to the frame where it is larger.
+    pub width: u32,
+    pub height: u32,
+    /// JPEG quality (1–100) and subsampling of the rectangle.
+    pub quality: u8,
+    pub subsampling: Subsampling,
+}
+
+/// Per-session record of where the focus rectangle was last placed.
+#[derive(Default)]
+pub struct FocusState {
+    rect: Option<Rect>,
+}
+
+impl FocusState {
+    /// Places the rectangle in a `width x height` frame for `cursor` (in
+    /// output pixels, `None` when it is not over the frame) and returns it
+    /// along with whether it moved since the last frame. Without a cursor
+    /// it stays where it was, or starts in the middle of the frame.
+    pub fn place(
+        &mut self,
+        focus: &Focus,
+        cursor: Option<(i32, i32)>,
+        (width, height): (u32, u32),
+    ) -> (Rect, bool) {
+        let (w, h) = (focus.width.min(width), focus.height.min(height));
+        let kept = self
+            .rect
+            .filter(|r| (r.w, r.h) == (w, h) && r.x + w <= width && r.y + h <= height)
+            .filter(|&r| cursor.is_none_or(|at| in_middle(r, at)));
+
+        let rect = kept.unwrap_or_else(|| {
+            let (x, y) = cursor.unwrap_or((width as i32 / 2, height as i32 / 2));
+            let corner = |at: i32, len: u32, limit: u32| {
+                let start = (at - len as i32 / 2).clamp(0, (limit - len) as i32) as u32;
+                start / GRID * GRID
+            };
+            Rect {
+                x: corner(x, w, width),
+                y: corner(y, h, height),
+                w,
+                h,
+            }
+        });
+        let moved = self.rect != Some(rect);
+        self.rect = Some(rect);
+        (rect, moved)
+    }
+}
+
+/// Whether `at` lies in the middle half of `rect` on both axes.
+fn in_middle(rect: Rect, (x, y): (i32, i32)) -> bool {
+    let middle = |at: i32, start: u32, len: u32| {
+        let at = i64::from(at);
+        let (start, len) = (i64::from(start), i64::from(len));
+        (start + len / 4..start + len - len / 4).contains(&at)
+    };
+    middle(x, rect.x, rect.w) && middle(y, rect.y, rect.h)
+}
@@ -0,0 +1,249 @@
+use std::ops::Deref;
+
+use crate::pixels::Rect;
+use crate::yuv;
+
+/// Payload encodings a frame can carry, as stored in `RawImage::format`.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameFormat {
+    Jpeg = 0,
+    Png = 1,
+    /// Only produced when the crate is built with the `webp` feature.
+    WebP = 2,
+    /// Uncompressed pixels laid out as described by the frame's
+    /// `pixel_format` and `stride`.
+    Raw = 3,
+    /// Tile container (see the `tiles` module) holding the whole image.
+    TiledKeyframe = 4,
+    /// Tile container holding only the tiles changed since the last frame.
+    TiledDelta = 5,
+    /// UTF-8 text, as returned by `rdp_clipboard_get_text`; not a capture
+    /// format.
+    Text = 6,
+    /// Annex-B H.264 (see the `h264` module); only produced when the crate
+    /// is built with the `h264` feature.
+    H264 = 7,
+    /// Raw VP8 and VP9 frames (see the `vpx` module); only produced when
+    /// the crate is built with the `vpx` feature.
+    Vp8 = 8,
+    Vp9 = 9,
+    /// `Raw` pixels compressed with zstd (see the `zstd` module); only
+    /// produced when the crate is built with the `zstd` feature.
+    RawZstd = 10,
+    /// Planar YUV 4:2:0 at even dimensions: a Y plane, then U and V planes
+    /// of half the width and height (see `EncodedFrame::planes`).
+    I420 = 11,
+    /// `I420` with U and V interleaved in a single half-height plane after
+    /// the Y plane.
+    Nv12 = 12,
+}
+
+impl FrameFormat {
+    /// The media type of a payload in this format.
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            FrameFormat::Jpeg => "image/jpeg",
+            FrameFormat::Png => "image/png",
+            FrameFormat::WebP => "image/webp",
+            FrameFormat::Text => "text/plain;charset=utf-8",
+            _ => "application/octet-stream",
+        }
+    }
+
+    /// Maps an FFI format value back onto the enum.
+    pub fn from_u32(value: u32) -> Option<FrameFormat> {
+        match value {
+            0 => Some(FrameFormat::Jpeg),
+            1 => Some(FrameFormat::Png),
+            2 => Some(FrameFormat::WebP),
+            3 => Some(FrameFormat::Raw),
+            4 => Some(FrameFormat::TiledKeyframe),
+            5 => Some(FrameFormat::TiledDelta),
+            6 => Some(FrameFormat::Text),
+            7 => Some(FrameFormat::H264),
+            8 => Some(FrameFormat::Vp8),
+            9 => Some(FrameFormat::Vp9),
+            10 => Some(FrameFormat::RawZstd),
+            11 => Some(FrameFormat::I420),
+            12 => Some(FrameFormat::Nv12),
+            _ => None,
+        }
+    }
+
+    /// The lowercase name configs and the Python module use.
+    pub fn name(self) -> &'static str {
+        match self {
+            FrameFormat::Jpeg => "jpeg",
+            FrameFormat::Png => "png",
+            FrameFormat::WebP => "webp",
+            FrameFormat::Raw => "raw",
+            FrameFormat::TiledKeyframe => "tiled_keyframe",
+            FrameFormat::TiledDelta => "tiled_delta",
+            FrameFormat::Text => "text",
+            FrameFormat::H264 => "h264",
+            FrameFormat::Vp8 => "vp8",
+            FrameFormat::Vp9 => "vp9",
+            FrameFormat::RawZstd => "raw_zstd",
+            FrameFormat::I420 => "i420",
+            FrameFormat::Nv12 => "nv12",
+        }
+    }
+
+    /// The output format called `name`; the tiled formats and text are
+    /// not output formats and have none.
+    pub fn from_name(name: &str) -> Option<FrameFormat> {
+        Some(match name {
+            "jpeg" => FrameFormat::Jpeg,
+            "png" => FrameFormat::Png,
+            "webp" => FrameFormat::WebP,
+            "raw" => FrameFormat::Raw,
+            "h264" => FrameFormat::H264,
+            "vp8" => FrameFormat::Vp8,
+            "vp9" => FrameFormat::Vp9,
+            "raw_zstd" => FrameFormat::RawZstd,
+            "i420" => FrameFormat::I420,
+            "nv12" => FrameFormat::Nv12,
+            _ => return None,
+        })
+    }
+}
+
+/// Channel layouts for raw frames and JPEG input, as stored in
+/// `RawImage::pixel_format`. Variants name the byte order in memory.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// As captured; needs no conversion.
+    Bgra = 0,
+    Rgb = 1,
+    Bgr = 2,
+    Rgba = 3,
+    /// Single-channel BT.601 luma, produced by grayscale sessions.
+    Gray = 4,
+}
+
+impl PixelFormat {
+    /// Maps an FFI pixel-format value back onto the enum.
+    pub fn from_u32(value: u32) -> Option<PixelFormat> {
+        match value {
+            0 => Some(PixelFormat::Bgra),
+            1 => Some(PixelFormat::Rgb),
+            2 => Some(PixelFormat::Bgr),
+            3 => Some(PixelFormat::Rgba),
+            4 => Some(PixelFormat::Gray),
+            _ => None,
+        }
+    }
+
+    /// The lowercase name configs and the Python module use.
+    pub fn name(self) -> &'static str {
+        match self {
+            PixelFormat::Bgra => "bgra",
+            PixelFormat::Rgb => "rgb",
+            PixelFormat::Bgr => "bgr",
+            PixelFormat::Rgba => "rgba",
+            PixelFormat::Gray => "gray",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PixelFormat> {
+        Some(match name {
+            "bgra" => PixelFormat::Bgra,
+            "rgb" => PixelFormat::Rgb,
+            "bgr" => PixelFormat::Bgr,
+            "rgba" => PixelFormat::Rgba,
+            "gray" => PixelFormat::Gray,
+            _ => return None,
+        })
+    }
+
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Bgra | PixelFormat::Rgba => 4,
+            PixelFormat::Rgb | PixelFormat::Bgr => 3,
+            PixelFormat::Gray => 1,
+        }
+    }
+}
+
+/// An encoded frame plus the metadata describing it.
+#[derive(Clone, Debug)]
+pub struct EncodedFrame {
+    pub data: Vec<u8>,
+    /// Dimensions of the encoded image, after any crop/resize.
+    pub width: u32,
+    pub height: u32,
+    pub format: FrameFormat,
+    /// Layout of the pixels for `FrameFormat::Raw` and `RawZstd`; the
+    /// encoder's input layout otherwise.
+    pub pixel_format: PixelFormat,
+    /// Bytes per row for `FrameFormat::Raw` and `RawZstd`, and of the Y
+    /// plane for `I420` and `Nv12`; 0 for the other compressed formats.
+    pub stride: u32,
+    /// Part of the image that changed since the previous frame, in output
+    /// coordinates; zero-sized when nothing changed. Covers the whole image
+    /// unless dirty tracking is on.
+    pub dirty: Rect,
+    /// `pixels::frame_hash` of the pixels that were encoded, salted with
+    /// their width, height and `PixelFormat`, so it does not depend on the
+    /// encoder settings.
+    pub content_hash: u64,
+    /// Cursor hotspot in output coordinates, sampled with the frame; `None`
+    /// when it is on another display, outside the capture region or cannot
+    /// be queried.
+    pub cursor: Option<(i32, i32)>,
+    /// Hotspot of a cursor image from `rdp_get_cursor_image`; (0, 0) for
+    /// captured frames.
+    pub hotspot: (u32, u32),
+    /// Position of the capture among all the session's captures, counting
+    /// those skipped as unchanged, and when it was taken (see
+    /// `RawImage::timestamp_us`). 0 for anything not captured by a session.
+    pub sequence: u64,
+    pub timestamp_us: u64,
+    /// JPEG or WebP quality the frame was encoded at (for JPEG chosen by the
+    /// rate controller when a byte budget is set); 0 for other formats.
+    pub quality: u8,
+    /// Whether the frame decodes on its own: true for whole images and
+    /// tiled or video keyframes, false for tiled deltas and video
+    /// inter frames.
+    pub keyframe: bool,
+    /// Size of `data` once decompressed, for `FrameFormat::RawZstd`; 0 for
+    /// other formats.
+    pub uncompressed_len: u64,
+    /// `data` is sealed with the session's encryption key (see the `cipher`
+    /// module) instead of being the payload itself.
+    pub encrypted: bool,
+    /// The JPEG payload, or every JPEG tile, is progressive rather than
+    /// baseline.
+    pub progressive: bool,
+}
+
+impl EncodedFrame {
+    /// Offsets and bytes per row of the planes of an `I420` or `Nv12`
+    /// frame (see `yuv::planes`); `None` for other formats.
+    pub fn planes(&self) -> Option<([u64; 3], [u32; 3])> {
+        yuv::planes(self.format, self.width, self.height)
+    }
+
+    /// CRC-32 (IEEE, as zlib's `crc32`) of the payload, the `checksum` of
+    /// the frame's `RawImage`.
+    pub fn checksum(&self) -> u32 {
+        crc32fast::hash(&self.data)
+    }
+}
+
+impl Deref for EncodedFrame {
+    type Target = [u8];
+
+    /// The payload, as `data`.
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl AsRef<[u8]> for EncodedFrame {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
@@ -0,0 +1,242 @@
+//! H.264 output through Cisco's openh264, built with the `h264` feature
+//! (which links `libopenh264`).
+//!
+//! Frames are encoded from I420 (see `yuv`) with openh264's screen-content
+//! tuning and bitrate rate control. Each frame's payload is an Annex-B byte
+//! stream: its NAL units, each preceded by a 00 00 00 01 start code. Every
+//! IDR frame (`RawImage::keyframe`) starts with the SPS and PPS, so a
+//! decoder can start from any of them.
+
+use std::ffi::{c_int, c_longlong, c_void};
+use std::ptr;
+
+use crate::error::{RdpStatus, fail};
+use crate::video::{Encoder, Packet, Params};
+
+// codec_api.h / codec_app_def.h
+const SCREEN_CONTENT_REAL_TIME: c_int = 1;
+const RC_BITRATE_MODE: c_int = 1;
+const VIDEO_FORMAT_I420: c_int = 23;
+const ENCODER_OPTION_IDR_INTERVAL: c_int = 1;
+const ENCODER_OPTION_BITRATE: c_int = 5;
+const SPATIAL_LAYER_ALL: c_int = 4;
+/// `ForceIntraFrame`'s layer id for every layer.
+const ALL_LAYERS: c_int = -1;
+const VIDEO_FRAME_TYPE_IDR: c_int = 1;
+const VIDEO_FRAME_TYPE_SKIP: c_int = 4;
+const MAX_LAYER_NUM_OF_FRAME: usize = 128;
+
+#[repr(C)]
+struct SEncParamBase {
+    usage_type: c_int,
+    pic_width: c_int,
+    pic_height: c_int,
+    target_bitrate: c_int,
+    rc_mode: c_int,
+    max_frame_rate: f32,
+}
+
+#[repr(C)]
+struct SSourcePicture {
+    color_format: c_int,
+    stride: [c_int; 4],
+    data: [*mut u8; 4],
+    pic_width: c_int,
+    pic_height: c_int,
+    time_stamp: c_longlong,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SLayerBSInfo {
+    temporal_id: u8,
+    spatial_id: u8,
+    quality_id: u8,
+    frame_type: c_int,
+    layer_type: u8,
+    sub_seq_id: c_int,
+    nal_count: c_int,
+    nal_length_in_byte: *mut c_int,
+    bs_buf: *mut u8,
+}
+
+#[repr(C)]
+struct SFrameBSInfo {
+    layer_num: c_int,
+    layer_info: [SLayerBSInfo; MAX_LAYER_NUM_OF_FRAME],
+    frame_type: c_int,
+    frame_size_in_bytes: c_int,
+    time_stamp: c_longlong,
+}
+
+#[repr(C)]
+struct SBitrateInfo {
+    layer: c_int,
+    bitrate: c_int,
+}
+
+/// The C view of the `ISVCEncoder` C++ interface: a pointer to its
+/// vtable, whose entries take the object as their first argument.
+type ISVCEncoder = *const ISVCEncoderVtbl;
+
+#[repr(C)]
+struct ISVCEncoderVtbl {
+    initialize: unsafe extern "C" fn(*mut ISVCEncoder, *const SEncParamBase) -> c_int,
+    initialize_ext: unsafe extern "C" fn(*mut ISVCEncoder, *const c_void) -> c_int,
+    get_default_params: unsafe extern "C" fn(*mut ISVCEncoder, *mut c_void) -> c_int,
+    uninitialize: unsafe extern "C" fn(*mut ISVCEncoder) -> c_int,
+    encode_frame:
+        unsafe extern "C" fn(*mut ISVCEncoder, *const SSourcePicture, *mut SFrameBSInfo) -> c_int,
+    encode_parameter_sets: unsafe extern "C" fn(*mut ISVCEncoder, *mut SFrameBSInfo) -> c_int,
+    force_intra_frame: unsafe extern "C" fn(*mut ISVCEncoder, bool, c_int) -> c_int,
+    set_option: unsafe extern "C" fn(*mut ISVCEncoder, c_int, *mut c_void) -> c_int,
+    get_option: unsafe extern "C" fn(*mut ISVCEncoder, c_int, *mut c_void) -> c_int,
+}
+
+#[link(name = "openh264")]
+unsafe extern "C" {
+    fn WelsCreateSVCEncoder(encoder: *mut *mut ISVCEncoder) -> c_int;
+    fn WelsDestroySVCEncoder(encoder: *mut ISVCEncoder);
+}
+
+pub struct H264Encoder {
+    encoder: *mut ISVCEncoder,
+    params: Params,
+}
+
+impl H264Encoder {
+    pub fn new(params: Params) -> Result<H264Encoder, RdpStatus> {
+        let mut encoder = ptr::null_mut();
+        if unsafe { WelsCreateSVCEncoder(&mut encoder) } != 0 || encoder.is_null() {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                "Failed to create the H.264 encoder",
+            ));
+        }
+        // Owned from here on, so failures below are cleaned up by Drop
+        let mut this = H264Encoder { encoder, params };
+
+        let base = SEncParamBase {
+            usage_type: SCREEN_CONTENT_REAL_TIME,
+            pic_width: params.width as c_int,
+            pic_height: params.height as c_int,
+            target_bitrate: (params.bitrate_kbps.saturating_mul(1000)).min(i32::MAX as u32)
+                as c_int,
+            rc_mode: RC_BITRATE_MODE,
+            max_frame_rate: params.fps,
+        };
+        if unsafe { ((**encoder).initialize)(encoder, &base) } != 0 {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!(
+                    "Failed to initialize the H.264 encoder for {}x{} at {} fps",
+                    params.width, params.height, params.fps
+                ),
+            ));
+        }
+        let mut interval = params.keyframe_interval as c_int;
+        this.set_option(ENCODER_OPTION_IDR_INTERVAL, &mut interval)?;
+        Ok(this)
+    }
+
+    fn set_option<T>(&mut self, option: c_int, value: &mut T) -> Result<(), RdpStatus> {
+        let status = unsafe {
+            ((**self.encoder).set_option)(self.encoder, option, value as *mut T as *mut c_void)
+        };
+        if status != 0 {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!("Failed to set H.264 encoder option {option} ({status})"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Encoder for H264Encoder {
+    fn params(&self) -> Params {
+        self.params
+    }
+
+    fn set_bitrate(&mut self, kbps: u32) -> Result<(), RdpStatus> {
+        let mut info = SBitrateInfo {
+            layer: SPATIAL_LAYER_ALL,
+            bitrate: kbps.saturating_mul(1000).min(i32::MAX as u32) as c_int,
+        };
+        self.set_option(ENCODER_OPTION_BITRATE, &mut info)?;
+        self.params.bitrate_kbps = kbps;
+        Ok(())
+    }
+
+    fn force_keyframe(&mut self) -> Result<(), RdpStatus> {
+        let status =
+            unsafe { ((**self.encoder).force_intra_frame)(self.encoder, true, ALL_LAYERS) };
+        if status != 0 {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!("Failed to force an H.264 IDR frame ({status})"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, i420: &[u8], timestamp_ms: u64) -> Result<Option<Packet>, RdpStatus> {
+        let (w, h) = (self.params.width as usize, self.params.height as usize);
+        let base = i420.as_ptr() as *mut u8;
+        let picture = SSourcePicture {
+            color_format: VIDEO_FORMAT_I420,
+            stride: [w as c_int, (w / 2) as c_int, (w / 2) as c_int, 0],
+            // openh264 only reads the planes, despite the mutable type
+            data: unsafe {
+                [
+                    base,
+                    base.add(w * h),
+                    base.add(w * h + w * h / 4),
+                    ptr::null_mut(),
+                ]
+            },
+            pic_width: w as c_int,
+            pic_height: h as c_int,
+            time_stamp: timestamp_ms as c_longlong,
+        };
+        let mut info: Box<SFrameBSInfo> = Box::new(unsafe { std::mem::zeroed() });
+
+        let status = unsafe { ((**self.encoder).encode_frame)(self.encoder, &picture, &mut *info) };
+        if status != 0 {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!("H.264 encoding failed ({status})"),
+            ));
+        }
+        if info.frame_type == VIDEO_FRAME_TYPE_SKIP {
+            return Ok(None);
+        }
+
+        let mut data = Vec::with_capacity(info.frame_size_in_bytes.max(0) as usize);
+        for layer in
+            &info.layer_info[..info.layer_num.clamp(0, MAX_LAYER_NUM_OF_FRAME as c_int) as usize]
+        {
+            let lengths = unsafe {
+                std::slice::from_raw_parts(
+                    layer.nal_length_in_byte,
+                    layer.nal_count.max(0) as usize,
+                )
+            };
+            let len: usize = lengths.iter().map(|&n| n.max(0) as usize).sum();
+            data.extend_from_slice(unsafe { std::slice::from_raw_parts(layer.bs_buf, len) });
+        }
+        Ok(Some(Packet {
+            data,
+            keyframe: info.frame_type == VIDEO_FRAME_TYPE_IDR,
+        }))
+    }
+}
+
+impl Drop for H264Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            ((**self.encoder).uninitialize)(self.encoder);
+            WelsDestroySVCEncoder(self.encoder);
+        }
+    }
+}
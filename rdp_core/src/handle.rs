@@ -0,0 +1,374 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::broadcast::Broadcast;
+use crate::error::{RdpStatus, fail, fail_at};
+use crate::frame::{EncodedFrame, FrameFormat};
+use crate::http;
+use crate::log::LogLevel;
+use crate::queue::ConsumerKind;
+use crate::server::Server;
+use crate::session::RdpSession;
+use crate::stats::Stats;
+use crate::stream::{Sink, Stream};
+use crate::tcp;
+
+/// What an FFI session pointer refers to: the session behind a lock, so a
+/// stream thread can capture from it while the host keeps calling setters,
+/// plus that stream, if one is running, and the server it may feed.
+pub struct SessionHandle {
+    session: Arc<Mutex<RdpSession>>,
+    stream: Mutex<Option<Stream>>,
+    http: Mutex<Option<Server>>,
+    tcp: Mutex<Option<Server>>,
+    ws: Mutex<Option<Server>>,
+    local: Mutex<Option<Server>>,
+    /// The session's stats, reachable without its lock.
+    stats: Arc<Stats>,
+}
+
+impl SessionHandle {
+    pub fn new(session: RdpSession) -> SessionHandle {
+        SessionHandle {
+            stats: session.stats(),
+            session: Arc::new(Mutex::new(session)),
+            stream: Mutex::new(None),
+            http: Mutex::new(None),
+            tcp: Mutex::new(None),
+            ws: Mutex::new(None),
+            local: Mutex::new(None),
+        }
+    }
+
+    /// Never waits for a capture in progress.
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Exclusive access to the session, waiting for any capture the stream
+    /// thread has in progress.
+    pub fn lock(&self) -> MutexGuard<'_, RdpSession> {
+        self.session.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts a stream feeding `sink`, setting the session's target rate to
+    /// `fps`; fails with `RdpStatus::Busy` if a stream is already running.
+    pub fn start_stream(&self, fps: u32, sink: Sink) -> Result<(), RdpStatus> {
+        if fps == 0 {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Stream frame rate must be > 0",
+            ));
+        }
+
+        let mut stream = self.stream.lock().unwrap_or_else(PoisonError::into_inner);
+        if stream.is_some() {
+            return Err(fail(RdpStatus::Busy, "Session is already streaming"));
+        }
+        self.lock().set_target_fps(fps);
+        *stream = Some(Stream::start(Arc::clone(&self.session), sink)?);
+        Ok(())
+    }
+
+    /// The newest frame a buffered stream has captured since the last poll.
+    pub fn poll_latest(&self) -> Result<EncodedFrame, RdpStatus> {
+        let stream = self.stream.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(ring) = stream.as_ref().and_then(Stream::ring) else {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Session has no buffered stream running",
+            ));
+        };
+        ring.take_latest().ok_or_else(|| {
+            fail_at(
+                LogLevel::Debug,
+                RdpStatus::WouldBlock,
+                "No new frame since the last poll",
+            )
+        })
+    }
+
+    /// Starts a stream at `fps` and an HTTP server on `bind_addr:port`
+    /// serving it as MJPEG (see the `http` module). The session must be
+    /// producing plain JPEG frames.
+    pub fn start_http(&self, bind_addr: &str, port: u16, fps: u32) -> Result<(), RdpStatus> {
+        {
+            let session = self.lock();
+            let config = session.config();
+            if config.format != FrameFormat::Jpeg || config.tile_size > 0 || session.is_encrypting()
+            {
+                return Err(fail(
+                    RdpStatus::InvalidArgument,
+                    "HTTP streaming needs untiled, unencrypted JPEG output",
+                ));
+            }
+        }
+
+        self.serve(
+            &self.http,
+            "HTTP",
+            ConsumerKind::Http,
+            fps,
+            |broadcast, stats| {
+                Server::start(
+                    "HTTP",
+                    bind_addr,
+                    port,
+                    broadcast,
+                    stats,
+                    Arc::new(http::serve),
+                )
+            },
+        )
+    }
+
+    /// Stops the HTTP server and the stream feeding it, if running.
+    pub fn stop_http(&self) {
+        self.stop_serving(&self.http);
+    }
+
+    /// Starts a stream at `fps` and a TCP frame server on `bind_addr:port`
+    /// sending it to every client (see the `tcp` module).
+    pub fn start_tcp(&self, bind_addr: &str, port: u16, fps: u32) -> Result<(), RdpStatus> {
+        self.serve(
+            &self.tcp,
+            "TCP",
+            ConsumerKind::Tcp,
+            fps,
+            |broadcast, stats| {
+                Server::start(
+                    "TCP",
+                    bind_addr,
+                    port,
+                    broadcast,
+                    stats,
+                    Arc::new(tcp::serve),
+                )
+            },
+        )
+    }
+
+    /// Stops the TCP server and the stream feeding it, if running, once
+    /// every client connection is closed.
+    pub fn stop_tcp(&self) {
+        self.stop_serving(&self.tcp);
+    }
+
+    /// Starts a stream at `fps` and a WebSocket server on `bind_addr:port`
+    /// sending it to every client, which may in turn adjust the session
+    /// (see the `ws` module).
+    #[cfg(feature = "websocket")]
+    pub fn start_ws(&self, bind_addr: &str, port: u16, fps: u32) -> Result<(), RdpStatus> {
+        let session = Arc::clone(&self.session);
+        let handler =
+            move |conn, broadcast: &Broadcast| crate::ws::serve(conn, broadcast, &session);
+        self.serve(
+            &self.ws,
+            "WebSocket",
+            ConsumerKind::WebSocket,
+            fps,
+            |broadcast, stats| {
+                Server::start(
+                    "WebSocket",
+                    bind_addr,
+                    port,
+                    broadcast,
+                    stats,
+                    Arc::new(handler),
+                )
+            },
+        )
+    }
+
+    #[cfg(not(feature = "websocket"))]
+    pub fn start_ws(&self, _bind_addr: &str, _port: u16, _fps: u32) -> Result<(), RdpStatus> {
+        Err(fail(
+            RdpStatus::Unsupported,
+            "WebSocket serving needs the `websocket` feature",
+        ))
+    }
+
+    /// Stops the WebSocket server and the stream feeding it, if running,
+    /// once every client has been sent a close frame.
+    pub fn stop_ws(&self) {
+        self.stop_serving(&self.ws);
+    }
+
+    /// Starts a stream at `fps` and the TCP frame server on the Unix domain
+    /// socket or named pipe at `path` (see the `local` module).
+    pub fn start_local(&self, path: &str, fps: u32) -> Result<(), RdpStatus> {
+        self.serve(
+            &self.local,
+            "local",
+            ConsumerKind::Local,
+            fps,
+            |broadcast, stats| {
+                Server::start_local("local", path, broadcast, stats, Arc::new(tcp::serve))
+            },
+        )
+    }
+
+    /// Stops the local server and the stream feeding it, if running, and
+    /// removes its socket file.
+    pub fn stop_local(&self) {
+        self.stop_serving(&self.local);
+    }
+
+    /// Starts a `name` server for `kind` clients in `slot` with `start`,
+    /// fed by a new stream at `fps`.
+    fn serve(
+        &self,
+        slot: &Mutex<Option<Server>>,
+        name: &'static str,
+        kind: ConsumerKind,
+        fps: u32,
+        start: impl FnOnce(Arc<Broadcast>, Arc<Stats>) -> Result<Server, RdpStatus>,
+    ) -> Result<(), RdpStatus> {
+        let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+        if slot.is_some() {
+            return Err(fail(
+                RdpStatus::Busy,
+                format!("Session is already serving {name}"),
+            ));
+        }
+        let broadcast = Broadcast::new(kind, self.lock().config(), Arc::clone(&self.stats));
+        let broadcast = Arc::new(broadcast);
+        let server = start(Arc::clone(&broadcast), Arc::clone(&self.stats))?;
+        if let Err(status) = self.start_stream(fps, Sink::Broadcast(broadcast)) {
+            server.stop();
+            return Err(status);
+        }
+        *slot = Some(server);
+        Ok(())
+    }
+
+    fn stop_serving(&self, slot: &Mutex<Option<Server>>) {
+        let server = slot.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(server) = server {
+            // Ending the stream closes the broadcast, releasing the clients
+            self.stop_stream();
+            server.stop();
+        }
+    }
+
+    /// Stops the running stream, if any.
+    pub fn stop_stream(&self) {
+        // Taken out before stopping so the lock is not held while joining:
+        // the callback may call back into this handle.
+        let stream = self
+            .stream
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(stream) = stream {
+            stream.stop();
+        }
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.stop_http();
+        self.stop_tcp();
+        self.stop_ws();
+        self.stop_local();
+        self.stop_stream();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::capture::Backend;
+    use crate::queue::QueuePolicy;
+    use crate::stream::FrameRing;
+
+    const RING_SIZE: usize = 3;
+
+    fn pattern_handle() -> SessionHandle {
+        let mut session = RdpSession::with_backend(Backend::Test, 0).expect("test pattern session");
+        session.set_output_size(160, 90).unwrap();
+        SessionHandle::new(session)
+    }
+
+    #[test]
+    fn slow_polling_keeps_memory_flat() {
+        let handle = pattern_handle();
+        let ring = FrameRing::new(RING_SIZE, QueuePolicy::LatestWins, handle.stats());
+        handle.start_stream(30, Sink::Ring(Arc::new(ring))).unwrap();
+
+        // Poll once a second while the stream captures at 30 Hz. The first
+        // second, with the ring filling up, sets the peak the rest must keep
+        let memory = Arc::clone(handle.stats().memory());
+        let mut last = None;
+        let mut largest = 0;
+        let mut peaks = Vec::new();
+        for _ in 0..4 {
+            thread::sleep(Duration::from_secs(1));
+            let frame = handle.poll_latest().expect("a frame every second");
+            assert!(last < Some(frame.sequence), "the newest frame each poll");
+            last = Some(frame.sequence);
+            largest = largest.max(frame.data.capacity() as u64);
+            peaks.push(memory.peak());
+            memory.reset();
+        }
+        let dropped = handle.stats().snapshot().frames_dropped;
+        handle.stop_stream();
+
+        // Each second ~27 frames go unpolled; kept, they would pile up
+        assert!(dropped > 60, "{dropped} dropped");
+        assert!(
+            peaks[1..].iter().all(|&peak| peak <= peaks[0] + largest),
+            "peaks {peaks:?} grow by more than a {largest} byte frame"
+        );
+    }
+
+    #[test]
+    fn tcp_clients_read_length_prefixed_records() {
+        let handle = pattern_handle();
+        let port = {
+            let probe = TcpListener::bind("127.0.0.1:0").unwrap();
+            probe.local_addr().unwrap().port()
+        };
+        handle.start_tcp("127.0.0.1", port, 30).unwrap();
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut last = None;
+        for _ in 0..3 {
+            let mut header = [0; 16];
+            client.read_exact(&mut header).unwrap();
+            let length = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let sequence = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            let timestamp_us = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            let mut payload = vec![0; length as usize];
+            client.read_exact(&mut payload).unwrap();
+
+            assert!(payload.starts_with(&[0xff, 0xd8]), "a JPEG");
+            assert!(payload.ends_with(&[0xff, 0xd9]), "all of it");
+            if let Some((sequence_before, timestamp_before)) = last {
+                assert!(sequence > sequence_before, "in order");
+                assert!(timestamp_us >= timestamp_before, "in order");
+            }
+            last = Some((sequence, timestamp_us));
+        }
+
+        // Stopping closes the connection rather than leaving it hanging
+        handle.stop_tcp();
+        let mut rest = Vec::new();
+        assert!(
+            client.read_to_end(&mut rest).is_ok(),
+            "closed, not timed out"
+        );
+        assert!(
+            TcpStream::connect(("127.0.0.1", port)).is_err(),
+            "listener closed"
+        );
+    }
+}
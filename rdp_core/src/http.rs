@@ -0,0 +1,242 @@
+//! A minimal HTTP/1.1 server for viewers that only have a browser (or
+//! anything else that plays MJPEG). It serves a session's stream from a
+//! `Broadcast`, so every client shares the same captures:
+//!
+//! - `GET /stream`: `multipart/x-mixed-replace` with one JPEG part per
+//!   frame, until the client disconnects or the server stops.
+//! - `GET /snapshot`: the newest frame as a single `image/jpeg`.
+//!
+//! Anything else gets a 404. Every connection serves one request and is
+//! then closed. With an access token set (`rdp_server_set_auth_token`),
+//! requests must carry it as a `?token=` query parameter or an
+//! `Authorization: Bearer` header, or they get a 401.
+//!
+//! A `/stream` client queues frames like the `tcp` module's clients, but
+//! MJPEG has nowhere to put a drop report, so its drops only show in
+//! `rdp_session_get_consumer_stats`.
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth;
+use crate::broadcast::{Broadcast, Subscription};
+use crate::frame::{EncodedFrame, FrameFormat};
+use crate::server::Conn;
+
+/// Separator between the parts of `/stream`.
+const BOUNDARY: &str = "rdpframe";
+
+/// Longest a client may take to send its request or accept a write before
+/// it is dropped.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest request head read; browsers send well under this.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// `server::Handler` for HTTP clients.
+pub fn serve(mut conn: Conn, broadcast: &Broadcast) -> io::Result<()> {
+    conn.set_read_timeout(Some(IO_TIMEOUT))?;
+    conn.set_write_timeout(Some(IO_TIMEOUT))?;
+    conn.set_nodelay(true)?;
+
+    let request = Request::read(&mut conn)?;
+    if !request.authorized() {
+        return unauthorized(&mut conn);
+    }
+    if request.method != "GET" {
+        return respond(
+            &mut conn,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"GET only\n",
+        );
+    }
+    match request.path() {
+        "/stream" => stream(&mut conn, broadcast),
+        "/snapshot" => match next_jpeg(&broadcast.subscribe()) {
+            Some(frame) => respond(&mut conn, "200 OK", "image/jpeg", &frame.data),
+            None => respond(
+                &mut conn,
+                "503 Service Unavailable",
+                "text/plain",
+                b"Stream stopped\n",
+            ),
+        },
+        _ => respond(&mut conn, "404 Not Found", "text/plain", b"Not found\n"),
+    }
+}
+
+/// The head of an HTTP request.
+pub struct Request {
+    pub method: String,
+    /// Path and query, as sent.
+    pub target: String,
+    /// Names lowercased.
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Reads up to the blank line ending the head, and not past it, so the
+    /// connection is left at the body (or the first WebSocket frame).
+    pub fn read(conn: &mut impl Read) -> io::Result<Request> {
+        let mut head = Vec::with_capacity(1024);
+        let mut byte = [0u8];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_REQUEST {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request too large",
+                ));
+            }
+            conn.read_exact(&mut byte)?;
+            head.push(byte[0]);
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let mut words = lines.next().unwrap_or_default().split_whitespace();
+        let (method, target) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+        Ok(Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            headers: lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+                .collect(),
+        })
+    }
+
+    /// The target without its query string.
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// The value of header `name` (lowercase), if sent.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The percent-decoded value of query parameter `name`, if present.
+    pub fn query_param(&self, name: &str) -> Option<Vec<u8>> {
+        let (_, query) = self.target.split_once('?')?;
+        query
+            .split('&')
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| percent_decode(value))
+    }
+
+    /// Whether the request carries the access token, or none is needed.
+    pub fn authorized(&self) -> bool {
+        let Some(token) = auth::token() else {
+            return true;
+        };
+        let bearer = self
+            .header("authorization")
+            .and_then(|v| v.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, credentials)| credentials.trim().as_bytes());
+        let query = self.query_param("token");
+        // Both are checked so the time taken does not tell which was tried
+        let by_header = auth::matches(&token, bearer);
+        let by_query = auth::matches(&token, query.as_deref());
+        by_header | by_query
+    }
+}
+
+/// Decodes `%XX` escapes and `+` (a space in query strings); malformed
+/// escapes are kept as they are.
+fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => bytes
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()),
+            _ => None,
+        };
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+            (None, b'+') => out.push(b' '),
+            (None, byte) => out.push(byte),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// Turns away a client without the access token. Always returns a
+/// `PermissionDenied` error, which the server counts in the stats.
+pub fn unauthorized(conn: &mut impl Write) -> io::Result<()> {
+    let body = b"Missing or wrong access token\n";
+    let _ = write!(
+        conn,
+        "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Type: text/plain\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .and_then(|()| conn.write_all(body))
+    .and_then(|()| conn.flush());
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "missing or wrong access token",
+    ))
+}
+
+pub fn respond(
+    conn: &mut impl Write,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        conn,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    conn.write_all(body)?;
+    conn.flush()
+}
+
+fn stream(conn: &mut Conn, broadcast: &Broadcast) -> io::Result<()> {
+    write!(
+        conn,
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+    let frames = broadcast.subscribe();
+    while let Some(frame) = next_jpeg(&frames) {
+        write!(
+            conn,
+            "--{BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            frame.data.len()
+        )?;
+        conn.write_all(&frame.data)?;
+        conn.write_all(b"\r\n")?;
+        conn.flush()?;
+    }
+    Ok(())
+}
+
+/// The client's next JPEG frame, skipping frames in any other format and
+/// encrypted ones (the session's settings may change while serving).
+fn next_jpeg(frames: &Subscription) -> Option<Arc<EncodedFrame>> {
+    loop {
+        let frame = frames.next()?;
+        if frame.format == FrameFormat::Jpeg && !frame.encrypted {
+            return Some(frame);
+        }
+    }
+}
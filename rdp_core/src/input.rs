@@ -0,0 +1,848 @@
+//! Synthetic input for remote control.
+//!
+//! Every injection goes through one process-wide backend behind a mutex, so
+//! the FFI entry points can be called from any thread. The backend is
+//! opened on first use; if that fails (no X server, missing permission) the
+//! next call tries again.
+//!
+//! Positions are global screen coordinates as the platform's injection API
+//! takes them: root-window pixels on X11, virtual-desktop pixels on
+//! Windows, global display points on macOS. `CursorProbe::to_screen` maps
+//! a captured display's pixels onto them.
+//!
+//! Keys are identified by USB HID usage IDs (see `keymap`). Modifiers are
+//! ordinary keys: press Control, then C, then release both to relay Ctrl+C.
+//! The OS keeps track of what is held on X11 and Windows; on macOS the
+//! backend does, and stamps the held modifiers onto every event it posts.
+
+use std::sync::{Mutex, PoisonError};
+
+use crate::error::{RdpStatus, fail};
+use crate::keymap;
+
+/// Mouse buttons accepted by `rdp_inject_mouse_button`.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    Left = 0,
+    Right = 1,
+    Middle = 2,
+    /// The "back" side button (X1).
+    Back = 3,
+    /// The "forward" side button (X2).
+    Forward = 4,
+}
+
+impl MouseButton {
+    /// Maps an FFI button value back onto the enum.
+    pub fn from_i32(value: i32) -> Option<MouseButton> {
+        match value {
+            0 => Some(MouseButton::Left),
+            1 => Some(MouseButton::Right),
+            2 => Some(MouseButton::Middle),
+            3 => Some(MouseButton::Back),
+            4 => Some(MouseButton::Forward),
+            _ => None,
+        }
+    }
+}
+
+/// Largest scroll step accepted per axis and call. X11 has no multi-notch
+/// events, so this also bounds the clicks one call can generate.
+const MAX_SCROLL: u32 = 100;
+
+static BACKEND: Mutex<Option<platform::Backend>> = Mutex::new(None);
+
+/// Runs `body` on the shared backend, opening it first if needed.
+fn with_backend<T>(
+    body: impl FnOnce(&mut platform::Backend) -> Result<T, RdpStatus>,
+) -> Result<T, RdpStatus> {
+    let mut slot = BACKEND.lock().unwrap_or_else(PoisonError::into_inner);
+    let backend = match &mut *slot {
+        Some(backend) => backend,
+        empty @ None => empty.insert(platform::Backend::new()?),
+    };
+    body(backend)
+}
+
+/// Moves the pointer to (`x`, `y`) in global screen coordinates.
+pub fn move_to(x: f64, y: f64) -> Result<(), RdpStatus> {
+    if !(x.is_finite() && y.is_finite()) {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            format!("Invalid pointer position ({x}, {y})"),
+        ));
+    }
+    with_backend(|backend| backend.move_to(x, y))
+}
+
+/// Presses or releases `button` at the current pointer position.
+pub fn button(button: MouseButton, pressed: bool) -> Result<(), RdpStatus> {
+    with_backend(|backend| backend.button(button, pressed))
+}
+
+/// Presses or releases the key with HID usage ID `hid`.
+pub fn key(hid: u32, pressed: bool) -> Result<(), RdpStatus> {
+    let code = keymap::lookup(hid)?;
+    with_backend(|backend| backend.key(code, pressed))
+}
+
+/// Scrolls by `dx`/`dy` wheel notches (positive = right/up).
+///
+/// Each call is delivered as it arrives, as a single event per axis where
+/// the platform can express a multi-notch step, so a trackpad viewer
+/// sending frequent small deltas produces the same number of OS events and
+/// nothing builds up in a queue. Viewers with finer-grained input should
+/// accumulate it into notches themselves.
+pub fn scroll(dx: i32, dy: i32) -> Result<(), RdpStatus> {
+    if dx.unsigned_abs() > MAX_SCROLL || dy.unsigned_abs() > MAX_SCROLL {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            format!("Scroll ({dx}, {dy}) exceeds {MAX_SCROLL} notches"),
+        ));
+    }
+    if dx == 0 && dy == 0 {
+        return Ok(());
+    }
+    with_backend(|backend| backend.scroll(dx, dy))
+}
+
+/// Types `text` literally, independent of the keyboard layout and of any
+/// modifiers being held.
+pub fn type_text(text: &str) -> Result<(), RdpStatus> {
+    if text.is_empty() {
+        return Ok(());
+    }
+    with_backend(|backend| backend.type_text(text))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use std::ffi::{c_char, c_int, c_uint, c_ulong, c_void};
+
+    use super::MouseButton;
+    use crate::error::{RdpStatus, fail};
+
+    #[link(name = "X11")]
+    unsafe extern "C" {
+        fn XOpenDisplay(name: *const c_char) -> *mut c_void;
+        fn XCloseDisplay(display: *mut c_void) -> c_int;
+        fn XFlush(display: *mut c_void) -> c_int;
+        fn XSync(display: *mut c_void, discard: c_int) -> c_int;
+        fn XFree(data: *mut c_void) -> c_int;
+        fn XDisplayKeycodes(display: *mut c_void, min: *mut c_int, max: *mut c_int) -> c_int;
+        fn XGetKeyboardMapping(
+            display: *mut c_void,
+            first: u8,
+            count: c_int,
+            keysyms_per_keycode: *mut c_int,
+        ) -> *mut c_ulong;
+        fn XChangeKeyboardMapping(
+            display: *mut c_void,
+            first: c_int,
+            keysyms_per_keycode: c_int,
+            keysyms: *const c_ulong,
+            count: c_int,
+        ) -> c_int;
+    }
+
+    #[link(name = "Xtst")]
+    unsafe extern "C" {
+        fn XTestQueryExtension(
+            display: *mut c_void,
+            event_base: *mut c_int,
+            error_base: *mut c_int,
+            major: *mut c_int,
+            minor: *mut c_int,
+        ) -> c_int;
+        fn XTestFakeMotionEvent(
+            display: *mut c_void,
+            screen: c_int,
+            x: c_int,
+            y: c_int,
+            delay: c_ulong,
+        ) -> c_int;
+        fn XTestFakeButtonEvent(
+            display: *mut c_void,
+            button: c_uint,
+            is_press: c_int,
+            delay: c_ulong,
+        ) -> c_int;
+        fn XTestFakeKeyEvent(
+            display: *mut c_void,
+            keycode: c_uint,
+            is_press: c_int,
+            delay: c_ulong,
+        ) -> c_int;
+    }
+
+    /// X keycodes are evdev codes shifted past the reserved range.
+    const EVDEV_OFFSET: c_uint = 8;
+
+    /// An Xlib connection used only for XTEST requests.
+    pub struct Backend {
+        display: *mut c_void,
+        /// Keycode with no symbols, borrowed for typing text.
+        spare_keycode: Option<c_int>,
+    }
+
+    // The connection is only ever touched with the backend mutex held
+    unsafe impl Send for Backend {}
+
+    impl Backend {
+        pub fn new() -> Result<Backend, RdpStatus> {
+            let display = unsafe { XOpenDisplay(std::ptr::null()) };
+            if display.is_null() {
+                return Err(fail(
+                    RdpStatus::InjectionFailed,
+                    "Cannot open the X display for input injection",
+                ));
+            }
+
+            let mut unused = [0; 4];
+            let [a, b, c, d] = &mut unused;
+            if unsafe { XTestQueryExtension(display, a, b, c, d) } == 0 {
+                unsafe { XCloseDisplay(display) };
+                return Err(fail(
+                    RdpStatus::Unsupported,
+                    "The X server does not support the XTEST extension",
+                ));
+            }
+            Ok(Backend {
+                display,
+                spare_keycode: None,
+            })
+        }
+
+        /// Sends the queued requests; Xlib reports nothing synchronously.
+        fn flush(&self) -> Result<(), RdpStatus> {
+            unsafe { XFlush(self.display) };
+            Ok(())
+        }
+
+        pub fn move_to(&mut self, x: f64, y: f64) -> Result<(), RdpStatus> {
+            // Screen -1 is whichever screen the pointer is on
+            unsafe {
+                XTestFakeMotionEvent(self.display, -1, x.round() as c_int, y.round() as c_int, 0)
+            };
+            self.flush()
+        }
+
+        pub fn button(&mut self, button: MouseButton, pressed: bool) -> Result<(), RdpStatus> {
+            let number = match button {
+                MouseButton::Left => 1,
+                MouseButton::Middle => 2,
+                MouseButton::Right => 3,
+                MouseButton::Back => 8,
+                MouseButton::Forward => 9,
+            };
+            unsafe { XTestFakeButtonEvent(self.display, number, c_int::from(pressed), 0) };
+            self.flush()
+        }
+
+        /// The core protocol scrolls with buttons 4/5 (vertical) and 6/7
+        /// (horizontal), one click per notch.
+        pub fn scroll(&mut self, dx: i32, dy: i32) -> Result<(), RdpStatus> {
+            let vertical = if dy > 0 { 4 } else { 5 };
+            let horizontal = if dx > 0 { 7 } else { 6 };
+            for (button, clicks) in [
+                (vertical, dy.unsigned_abs()),
+                (horizontal, dx.unsigned_abs()),
+            ] {
+                for _ in 0..clicks {
+                    unsafe {
+                        XTestFakeButtonEvent(self.display, button, 1, 0);
+                        XTestFakeButtonEvent(self.display, button, 0, 0);
+                    }
+                }
+            }
+            self.flush()
+        }
+
+        pub fn key(&mut self, evdev: u16, pressed: bool) -> Result<(), RdpStatus> {
+            let keycode = c_uint::from(evdev) + EVDEV_OFFSET;
+            unsafe { XTestFakeKeyEvent(self.display, keycode, c_int::from(pressed), 0) };
+            self.flush()
+        }
+
+        /// Types each character by temporarily binding its keysym to a
+        /// spare keycode, the way xdotool does, so characters missing from
+        /// the active layout still come through.
+        pub fn type_text(&mut self, text: &str) -> Result<(), RdpStatus> {
+            let spare = self.spare_keycode()?;
+            for ch in text.chars() {
+                let keysym = keysym_for(ch);
+                unsafe {
+                    XChangeKeyboardMapping(self.display, spare, 1, &keysym, 1);
+                    // Clients must see the new mapping before the key event
+                    XSync(self.display, 0);
+                    XTestFakeKeyEvent(self.display, spare as c_uint, 1, 0);
+                    XTestFakeKeyEvent(self.display, spare as c_uint, 0, 0);
+                    XSync(self.display, 0);
+                }
+            }
+
+            let no_symbol: c_ulong = 0;
+            unsafe { XChangeKeyboardMapping(self.display, spare, 1, &no_symbol, 1) };
+            self.flush()
+        }
+
+        fn spare_keycode(&mut self) -> Result<c_int, RdpStatus> {
+            if let Some(keycode) = self.spare_keycode {
+                return Ok(keycode);
+            }
+
+            let (mut min, mut max, mut per_keycode) = (0, 0, 0);
+            let spare = unsafe {
+                XDisplayKeycodes(self.display, &mut min, &mut max);
+                let count = max - min + 1;
+                let map = XGetKeyboardMapping(self.display, min as u8, count, &mut per_keycode);
+                if map.is_null() || count <= 0 || per_keycode <= 0 {
+                    None
+                } else {
+                    let per = per_keycode as usize;
+                    let keysyms = std::slice::from_raw_parts(map, count as usize * per);
+                    let spare = (min..=max).rev().find(|&keycode| {
+                        let start = (keycode - min) as usize * per;
+                        keysyms[start..start + per].iter().all(|&sym| sym == 0)
+                    });
+                    XFree(map.cast());
+                    spare
+                }
+            };
+
+            let keycode = spare.ok_or_else(|| {
+                fail(
+                    RdpStatus::InjectionFailed,
+                    "No free keycode to type text with",
+                )
+            })?;
+            self.spare_keycode = Some(keycode);
+            Ok(keycode)
+        }
+    }
+
+    /// Keysyms are Latin-1 code points for Latin-1, Unicode plus 0x01000000
+    /// for everything else; control characters use the function keysyms.
+    fn keysym_for(ch: char) -> c_ulong {
+        match ch {
+            '\n' | '\r' => 0xff0d,
+            '\t' => 0xff09,
+            '\u{8}' => 0xff08,
+            ' '..='~' | '\u{a0}'..='\u{ff}' => c_ulong::from(u32::from(ch)),
+            _ => 0x0100_0000 | c_ulong::from(u32::from(ch)),
+        }
+    }
+
+    impl Drop for Backend {
+        fn drop(&mut self) {
+            unsafe { XCloseDisplay(self.display) };
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::ffi::c_void;
+    use std::time::{Duration, Instant};
+
+    use super::MouseButton;
+    use crate::error::{RdpStatus, fail};
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct CGPoint {
+        x: f64,
+        y: f64,
+    }
+
+    const HID_EVENT_TAP: u32 = 0;
+    const LEFT_MOUSE_DOWN: u32 = 1;
+    const LEFT_MOUSE_UP: u32 = 2;
+    const RIGHT_MOUSE_DOWN: u32 = 3;
+    const RIGHT_MOUSE_UP: u32 = 4;
+    const MOUSE_MOVED: u32 = 5;
+    const LEFT_MOUSE_DRAGGED: u32 = 6;
+    const RIGHT_MOUSE_DRAGGED: u32 = 7;
+    const OTHER_MOUSE_DOWN: u32 = 25;
+    const OTHER_MOUSE_UP: u32 = 26;
+    const OTHER_MOUSE_DRAGGED: u32 = 27;
+    /// `kCGMouseEventClickState`
+    const CLICK_STATE_FIELD: u32 = 1;
+    /// `kCGEventFlagsChanged`, what modifier keys generate instead of
+    /// key down/up.
+    const FLAGS_CHANGED: u32 = 12;
+    /// `kCGScrollEventUnitLine`
+    const SCROLL_UNIT_LINE: u32 = 1;
+    /// Longest string `CGEventKeyboardSetUnicodeString` reliably delivers.
+    const UNICODE_CHUNK: usize = 20;
+
+    /// Modifier virtual keycodes and the event flag each one sets.
+    const MODIFIERS: [(u16, u64); 8] = [
+        (0x38, 0x0002_0000), // Shift
+        (0x3c, 0x0002_0000), // Right Shift
+        (0x3b, 0x0004_0000), // Control
+        (0x3e, 0x0004_0000), // Right Control
+        (0x3a, 0x0008_0000), // Option
+        (0x3d, 0x0008_0000), // Right Option
+        (0x37, 0x0010_0000), // Command
+        (0x36, 0x0010_0000), // Right Command
+    ];
+
+    /// Presses closer together than this count as a double (triple...) click.
+    const MULTI_CLICK_INTERVAL: Duration = Duration::from_millis(500);
+
+    #[link(name = "ApplicationServices", kind = "framework")]
+    unsafe extern "C" {
+        fn AXIsProcessTrusted() -> bool;
+        fn CGEventCreate(source: *const c_void) -> *mut c_void;
+        fn CGEventGetLocation(event: *mut c_void) -> CGPoint;
+        fn CGEventCreateMouseEvent(
+            source: *const c_void,
+            event_type: u32,
+            position: CGPoint,
+            button: u32,
+        ) -> *mut c_void;
+        fn CGEventSetIntegerValueField(event: *mut c_void, field: u32, value: i64);
+        fn CGEventCreateKeyboardEvent(
+            source: *const c_void,
+            keycode: u16,
+            key_down: bool,
+        ) -> *mut c_void;
+        fn CGEventKeyboardSetUnicodeString(event: *mut c_void, length: usize, text: *const u16);
+        fn CGEventSetFlags(event: *mut c_void, flags: u64);
+        fn CGEventSetType(event: *mut c_void, event_type: u32);
+        fn CGEventCreateScrollWheelEvent(
+            source: *const c_void,
+            units: u32,
+            wheel_count: u32,
+            wheel1: i32,
+            ...
+        ) -> *mut c_void;
+        fn CGEventPost(tap: u32, event: *mut c_void);
+        fn CFRelease(cf: *const c_void);
+    }
+
+    /// Posts CGEvents at the HID level. macOS does not track button state for
+    /// synthetic events, so moves with a button held are sent as drags and
+    /// repeated presses carry a click count. Modifier state is not tracked
+    /// either, so held modifiers are applied to each event explicitly.
+    pub struct Backend {
+        /// Bit per `MODIFIERS` entry, set while held.
+        modifiers: u8,
+        /// Bit per `button_number`, set while pressed.
+        held: u32,
+        /// Last press: button number, time and click count.
+        last_press: Option<(u32, Instant, i64)>,
+    }
+
+    impl Backend {
+        pub fn new() -> Result<Backend, RdpStatus> {
+            ensure_trusted()?;
+            Ok(Backend {
+                modifiers: 0,
+                held: 0,
+                last_press: None,
+            })
+        }
+
+        pub fn move_to(&mut self, x: f64, y: f64) -> Result<(), RdpStatus> {
+            let (event_type, button) = if self.held & 1 != 0 {
+                (LEFT_MOUSE_DRAGGED, 0)
+            } else if self.held & 2 != 0 {
+                (RIGHT_MOUSE_DRAGGED, 1)
+            } else if let Some(other) = (2..32).find(|b| self.held & (1 << b) != 0) {
+                (OTHER_MOUSE_DRAGGED, other)
+            } else {
+                (MOUSE_MOVED, 0)
+            };
+            post_mouse(event_type, CGPoint { x, y }, button, None, self.flags())
+        }
+
+        pub fn button(&mut self, button: MouseButton, pressed: bool) -> Result<(), RdpStatus> {
+            let number = button_number(button);
+            let event_type = match (number, pressed) {
+                (0, true) => LEFT_MOUSE_DOWN,
+                (0, false) => LEFT_MOUSE_UP,
+                (1, true) => RIGHT_MOUSE_DOWN,
+                (1, false) => RIGHT_MOUSE_UP,
+                (_, true) => OTHER_MOUSE_DOWN,
+                (_, false) => OTHER_MOUSE_UP,
+            };
+
+            let clicks = if pressed {
+                let clicks = match self.last_press {
+                    Some((last, at, count))
+                        if last == number && at.elapsed() < MULTI_CLICK_INTERVAL =>
+                    {
+                        count + 1
+                    }
+                    _ => 1,
+                };
+                self.last_press = Some((number, Instant::now(), clicks));
+                clicks
+            } else {
+                self.last_press.map_or(1, |(_, _, count)| count)
+            };
+
+            let at = current_location()?;
+            post_mouse(event_type, at, number, Some(clicks), self.flags())?;
+            if pressed {
+                self.held |= 1 << number;
+            } else {
+                self.held &= !(1 << number);
+            }
+            Ok(())
+        }
+
+        /// One line-based scroll event carrying both axes. The second wheel
+        /// is positive towards the left, hence the flipped `dx`.
+        pub fn scroll(&mut self, dx: i32, dy: i32) -> Result<(), RdpStatus> {
+            unsafe {
+                let event =
+                    CGEventCreateScrollWheelEvent(std::ptr::null(), SCROLL_UNIT_LINE, 2, dy, -dx);
+                if event.is_null() {
+                    return Err(fail(
+                        RdpStatus::InjectionFailed,
+                        "Failed to create a scroll event",
+                    ));
+                }
+                CGEventSetFlags(event, self.flags());
+                CGEventPost(HID_EVENT_TAP, event);
+                CFRelease(event);
+            }
+            Ok(())
+        }
+
+        pub fn key(&mut self, keycode: u16, pressed: bool) -> Result<(), RdpStatus> {
+            let modifier = MODIFIERS.iter().position(|&(code, _)| code == keycode);
+            if let Some(index) = modifier {
+                if pressed {
+                    self.modifiers |= 1 << index;
+                } else {
+                    self.modifiers &= !(1 << index);
+                }
+            }
+
+            unsafe {
+                let event = CGEventCreateKeyboardEvent(std::ptr::null(), keycode, pressed);
+                if event.is_null() {
+                    return Err(fail(
+                        RdpStatus::InjectionFailed,
+                        format!("Failed to create key event for keycode {keycode:#x}"),
+                    ));
+                }
+                if modifier.is_some() {
+                    CGEventSetType(event, FLAGS_CHANGED);
+                }
+                CGEventSetFlags(event, self.flags());
+                CGEventPost(HID_EVENT_TAP, event);
+                CFRelease(event);
+            }
+            Ok(())
+        }
+
+        /// Types `text` through key events carrying a Unicode string, with
+        /// no modifiers so held keys cannot turn it into shortcuts.
+        pub fn type_text(&mut self, text: &str) -> Result<(), RdpStatus> {
+            let units: Vec<u16> = text.encode_utf16().collect();
+            for chunk in units.chunks(UNICODE_CHUNK) {
+                for key_down in [true, false] {
+                    unsafe {
+                        let event = CGEventCreateKeyboardEvent(std::ptr::null(), 0, key_down);
+                        if event.is_null() {
+                            return Err(fail(
+                                RdpStatus::InjectionFailed,
+                                "Failed to create a text input event",
+                            ));
+                        }
+                        CGEventKeyboardSetUnicodeString(event, chunk.len(), chunk.as_ptr());
+                        CGEventSetFlags(event, 0);
+                        CGEventPost(HID_EVENT_TAP, event);
+                        CFRelease(event);
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn flags(&self) -> u64 {
+            MODIFIERS
+                .iter()
+                .enumerate()
+                .filter(|&(index, _)| self.modifiers & (1 << index) != 0)
+                .fold(0, |flags, (_, &(_, flag))| flags | flag)
+        }
+    }
+
+    fn button_number(button: MouseButton) -> u32 {
+        match button {
+            MouseButton::Left => 0,
+            MouseButton::Right => 1,
+            MouseButton::Middle => 2,
+            MouseButton::Back => 3,
+            MouseButton::Forward => 4,
+        }
+    }
+
+    /// Fails with `RdpStatus::PermissionDenied` unless the process may post
+    /// events (System Settings → Privacy & Security → Accessibility).
+    pub fn ensure_trusted() -> Result<(), RdpStatus> {
+        if unsafe { AXIsProcessTrusted() } {
+            Ok(())
+        } else {
+            Err(fail(
+                RdpStatus::PermissionDenied,
+                "Input injection needs the Accessibility permission",
+            ))
+        }
+    }
+
+    fn current_location() -> Result<CGPoint, RdpStatus> {
+        unsafe {
+            let event = CGEventCreate(std::ptr::null());
+            if event.is_null() {
+                return Err(fail(
+                    RdpStatus::InjectionFailed,
+                    "Cannot read the pointer location",
+                ));
+            }
+            let at = CGEventGetLocation(event);
+            CFRelease(event);
+            Ok(at)
+        }
+    }
+
+    fn post_mouse(
+        event_type: u32,
+        at: CGPoint,
+        button: u32,
+        clicks: Option<i64>,
+        flags: u64,
+    ) -> Result<(), RdpStatus> {
+        unsafe {
+            let event = CGEventCreateMouseEvent(std::ptr::null(), event_type, at, button);
+            if event.is_null() {
+                return Err(fail(
+                    RdpStatus::InjectionFailed,
+                    format!("Failed to create mouse event {event_type}"),
+                ));
+            }
+            if let Some(clicks) = clicks {
+                CGEventSetIntegerValueField(event, CLICK_STATE_FIELD, clicks);
+            }
+            CGEventSetFlags(event, flags);
+            CGEventPost(HID_EVENT_TAP, event);
+            CFRelease(event);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::MouseButton;
+    use crate::error::{RdpStatus, fail};
+
+    const INPUT_MOUSE: u32 = 0;
+    const INPUT_KEYBOARD: u32 = 1;
+    const KEYEVENTF_EXTENDEDKEY: u32 = 0x0001;
+    const KEYEVENTF_KEYUP: u32 = 0x0002;
+    const KEYEVENTF_UNICODE: u32 = 0x0004;
+    const KEYEVENTF_SCANCODE: u32 = 0x0008;
+    const MOUSEEVENTF_LEFTDOWN: u32 = 0x0002;
+    const MOUSEEVENTF_LEFTUP: u32 = 0x0004;
+    const MOUSEEVENTF_RIGHTDOWN: u32 = 0x0008;
+    const MOUSEEVENTF_RIGHTUP: u32 = 0x0010;
+    const MOUSEEVENTF_MIDDLEDOWN: u32 = 0x0020;
+    const MOUSEEVENTF_MIDDLEUP: u32 = 0x0040;
+    const MOUSEEVENTF_XDOWN: u32 = 0x0080;
+    const MOUSEEVENTF_XUP: u32 = 0x0100;
+    const MOUSEEVENTF_WHEEL: u32 = 0x0800;
+    const MOUSEEVENTF_HWHEEL: u32 = 0x1000;
+    /// One wheel notch.
+    const WHEEL_DELTA: i32 = 120;
+    const XBUTTON1: u32 = 0x0001;
+    const XBUTTON2: u32 = 0x0002;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct MouseInput {
+        dx: i32,
+        dy: i32,
+        mouse_data: u32,
+        flags: u32,
+        time: u32,
+        extra_info: usize,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct KeybdInput {
+        vk: u16,
+        scan: u16,
+        flags: u32,
+        time: u32,
+        extra_info: usize,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    union InputUnion {
+        mouse: MouseInput,
+        keyboard: KeybdInput,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Input {
+        kind: u32,
+        data: InputUnion,
+    }
+
+    #[link(name = "user32")]
+    unsafe extern "system" {
+        fn SetCursorPos(x: i32, y: i32) -> i32;
+        fn SendInput(count: u32, inputs: *const Input, size: i32) -> u32;
+    }
+
+    /// `SendInput` needs no connection, so the backend holds no state.
+    pub struct Backend;
+
+    impl Backend {
+        pub fn new() -> Result<Backend, RdpStatus> {
+            Ok(Backend)
+        }
+
+        pub fn move_to(&mut self, x: f64, y: f64) -> Result<(), RdpStatus> {
+            if unsafe { SetCursorPos(x.round() as i32, y.round() as i32) } == 0 {
+                return Err(fail(
+                    RdpStatus::InjectionFailed,
+                    format!("SetCursorPos({x}, {y}) failed"),
+                ));
+            }
+            Ok(())
+        }
+
+        pub fn button(&mut self, button: MouseButton, pressed: bool) -> Result<(), RdpStatus> {
+            let (flags, mouse_data) = match (button, pressed) {
+                (MouseButton::Left, true) => (MOUSEEVENTF_LEFTDOWN, 0),
+                (MouseButton::Left, false) => (MOUSEEVENTF_LEFTUP, 0),
+                (MouseButton::Right, true) => (MOUSEEVENTF_RIGHTDOWN, 0),
+                (MouseButton::Right, false) => (MOUSEEVENTF_RIGHTUP, 0),
+                (MouseButton::Middle, true) => (MOUSEEVENTF_MIDDLEDOWN, 0),
+                (MouseButton::Middle, false) => (MOUSEEVENTF_MIDDLEUP, 0),
+                (MouseButton::Back, true) => (MOUSEEVENTF_XDOWN, XBUTTON1),
+                (MouseButton::Back, false) => (MOUSEEVENTF_XUP, XBUTTON1),
+                (MouseButton::Forward, true) => (MOUSEEVENTF_XDOWN, XBUTTON2),
+                (MouseButton::Forward, false) => (MOUSEEVENTF_XUP, XBUTTON2),
+            };
+            send(&[mouse_input(flags, mouse_data, 0, 0)])
+        }
+
+        /// Both axes go out in one `SendInput` batch, one input each.
+        pub fn scroll(&mut self, dx: i32, dy: i32) -> Result<(), RdpStatus> {
+            let mut inputs = Vec::with_capacity(2);
+            if dy != 0 {
+                inputs.push(mouse_input(
+                    MOUSEEVENTF_WHEEL,
+                    (dy * WHEEL_DELTA) as u32,
+                    0,
+                    0,
+                ));
+            }
+            if dx != 0 {
+                inputs.push(mouse_input(
+                    MOUSEEVENTF_HWHEEL,
+                    (dx * WHEEL_DELTA) as u32,
+                    0,
+                    0,
+                ));
+            }
+            send(&inputs)
+        }
+
+        /// Sends the key by scancode, so it means the same physical key
+        /// whatever the layout.
+        pub fn key(&mut self, scancode: u16, pressed: bool) -> Result<(), RdpStatus> {
+            let mut flags = KEYEVENTF_SCANCODE;
+            if scancode >> 8 == 0xe0 {
+                flags |= KEYEVENTF_EXTENDEDKEY;
+            }
+            if !pressed {
+                flags |= KEYEVENTF_KEYUP;
+            }
+            send(&[key_input(scancode & 0xff, flags)])
+        }
+
+        /// Sends each UTF-16 unit as a `VK_PACKET` press and release.
+        pub fn type_text(&mut self, text: &str) -> Result<(), RdpStatus> {
+            let inputs: Vec<Input> = text
+                .encode_utf16()
+                // Edit controls expect a carriage return for Enter
+                .map(|unit| {
+                    if unit == u16::from(b'\n') {
+                        u16::from(b'\r')
+                    } else {
+                        unit
+                    }
+                })
+                .flat_map(|unit| {
+                    [
+                        key_input(unit, KEYEVENTF_UNICODE),
+                        key_input(unit, KEYEVENTF_UNICODE | KEYEVENTF_KEYUP),
+                    ]
+                })
+                .collect();
+            send(&inputs)
+        }
+    }
+
+    fn key_input(scan: u16, flags: u32) -> Input {
+        Input {
+            kind: INPUT_KEYBOARD,
+            data: InputUnion {
+                keyboard: KeybdInput {
+                    vk: 0,
+                    scan,
+                    flags,
+                    time: 0,
+                    extra_info: 0,
+                },
+            },
+        }
+    }
+
+    fn mouse_input(flags: u32, mouse_data: u32, dx: i32, dy: i32) -> Input {
+        Input {
+            kind: INPUT_MOUSE,
+            data: InputUnion {
+                mouse: MouseInput {
+                    dx,
+                    dy,
+                    mouse_data,
+                    flags,
+                    time: 0,
+                    extra_info: 0,
+                },
+            },
+        }
+    }
+
+    /// Queues `inputs` atomically. Windows silently blocks input into
+    /// higher-integrity windows (UIPI), which shows up as nothing inserted.
+    fn send(inputs: &[Input]) -> Result<(), RdpStatus> {
+        let size = std::mem::size_of::<Input>() as i32;
+        let inserted = unsafe { SendInput(inputs.len() as u32, inputs.as_ptr(), size) };
+        if inserted as usize != inputs.len() {
+            return Err(fail(
+                RdpStatus::InjectionFailed,
+                format!(
+                    "SendInput inserted {inserted} of {} events (blocked by UIPI?)",
+                    inputs.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
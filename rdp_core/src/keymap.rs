@@ -0,0 +1,173 @@
+//! Keycodes accepted by `rdp_inject_key`: USB HID usage IDs from the
+//! Keyboard/Keypad page (0x07), i.e. physical key positions. They are what
+//! browsers expose as `KeyboardEvent.code` and what the USB HID Usage
+//! Tables document, so viewers on any OS can produce them.
+//!
+//! Each key maps onto the platform's own physical code: evdev codes on X11
+//! (the X keycode is evdev + 8), set-1 scancodes on Windows (0xE0xx for the
+//! extended ones) and `kVK_*` virtual keycodes on macOS.
+
+use crate::error::{RdpStatus, fail};
+
+/// Marks a key the platform has no equivalent for.
+const NONE: u16 = u16::MAX;
+
+/// `(hid, evdev, scancode, mac)` for every supported key.
+#[rustfmt::skip]
+const KEYS: &[(u32, u16, u16, u16)] = &[
+    // Letters
+    (0x04, 30, 0x1e, 0x00), // A
+    (0x05, 48, 0x30, 0x0b), // B
+    (0x06, 46, 0x2e, 0x08), // C
+    (0x07, 32, 0x20, 0x02), // D
+    (0x08, 18, 0x12, 0x0e), // E
+    (0x09, 33, 0x21, 0x03), // F
+    (0x0a, 34, 0x22, 0x05), // G
+    (0x0b, 35, 0x23, 0x04), // H
+    (0x0c, 23, 0x17, 0x22), // I
+    (0x0d, 36, 0x24, 0x26), // J
+    (0x0e, 37, 0x25, 0x28), // K
+    (0x0f, 38, 0x26, 0x25), // L
+    (0x10, 50, 0x32, 0x2e), // M
+    (0x11, 49, 0x31, 0x2d), // N
+    (0x12, 24, 0x18, 0x1f), // O
+    (0x13, 25, 0x19, 0x23), // P
+    (0x14, 16, 0x10, 0x0c), // Q
+    (0x15, 19, 0x13, 0x0f), // R
+    (0x16, 31, 0x1f, 0x01), // S
+    (0x17, 20, 0x14, 0x11), // T
+    (0x18, 22, 0x16, 0x20), // U
+    (0x19, 47, 0x2f, 0x09), // V
+    (0x1a, 17, 0x11, 0x0d), // W
+    (0x1b, 45, 0x2d, 0x07), // X
+    (0x1c, 21, 0x15, 0x10), // Y
+    (0x1d, 44, 0x2c, 0x06), // Z
+    // Digit row
+    (0x1e, 2, 0x02, 0x12), // 1
+    (0x1f, 3, 0x03, 0x13), // 2
+    (0x20, 4, 0x04, 0x14), // 3
+    (0x21, 5, 0x05, 0x15), // 4
+    (0x22, 6, 0x06, 0x17), // 5
+    (0x23, 7, 0x07, 0x16), // 6
+    (0x24, 8, 0x08, 0x1a), // 7
+    (0x25, 9, 0x09, 0x1c), // 8
+    (0x26, 10, 0x0a, 0x19), // 9
+    (0x27, 11, 0x0b, 0x1d), // 0
+    // Editing and punctuation
+    (0x28, 28, 0x1c, 0x24), // Enter
+    (0x29, 1, 0x01, 0x35), // Escape
+    (0x2a, 14, 0x0e, 0x33), // Backspace
+    (0x2b, 15, 0x0f, 0x30), // Tab
+    (0x2c, 57, 0x39, 0x31), // Space
+    (0x2d, 12, 0x0c, 0x1b), // - _
+    (0x2e, 13, 0x0d, 0x18), // = +
+    (0x2f, 26, 0x1a, 0x21), // [ {
+    (0x30, 27, 0x1b, 0x1e), // ] }
+    (0x31, 43, 0x2b, 0x2a), // \ |
+    (0x32, 43, 0x2b, 0x2a), // Non-US # ~
+    (0x33, 39, 0x27, 0x29), // ; :
+    (0x34, 40, 0x28, 0x27), // ' "
+    (0x35, 41, 0x29, 0x32), // ` ~
+    (0x36, 51, 0x33, 0x2b), // , <
+    (0x37, 52, 0x34, 0x2f), // . >
+    (0x38, 53, 0x35, 0x2c), // / ?
+    (0x39, 58, 0x3a, 0x39), // Caps Lock
+    // Function keys
+    (0x3a, 59, 0x3b, 0x7a), // F1
+    (0x3b, 60, 0x3c, 0x78), // F2
+    (0x3c, 61, 0x3d, 0x63), // F3
+    (0x3d, 62, 0x3e, 0x76), // F4
+    (0x3e, 63, 0x3f, 0x60), // F5
+    (0x3f, 64, 0x40, 0x61), // F6
+    (0x40, 65, 0x41, 0x62), // F7
+    (0x41, 66, 0x42, 0x64), // F8
+    (0x42, 67, 0x43, 0x65), // F9
+    (0x43, 68, 0x44, 0x6d), // F10
+    (0x44, 87, 0x57, 0x67), // F11
+    (0x45, 88, 0x58, 0x6f), // F12
+    // Navigation
+    (0x46, 99, 0xe037, NONE), // Print Screen
+    (0x47, 70, 0x46, NONE), // Scroll Lock
+    (0x48, 119, NONE, NONE), // Pause (no plain scancode on Windows)
+    (0x49, 110, 0xe052, 0x72), // Insert (Help on Mac keyboards)
+    (0x4a, 102, 0xe047, 0x73), // Home
+    (0x4b, 104, 0xe049, 0x74), // Page Up
+    (0x4c, 111, 0xe053, 0x75), // Delete
+    (0x4d, 107, 0xe04f, 0x77), // End
+    (0x4e, 109, 0xe051, 0x79), // Page Down
+    (0x4f, 106, 0xe04d, 0x7c), // Right
+    (0x50, 105, 0xe04b, 0x7b), // Left
+    (0x51, 108, 0xe050, 0x7d), // Down
+    (0x52, 103, 0xe048, 0x7e), // Up
+    // Keypad
+    (0x53, 69, 0x45, 0x47), // Num Lock (Clear on Mac keyboards)
+    (0x54, 98, 0xe035, 0x4b), // Keypad /
+    (0x55, 55, 0x37, 0x43), // Keypad *
+    (0x56, 74, 0x4a, 0x4e), // Keypad -
+    (0x57, 78, 0x4e, 0x45), // Keypad +
+    (0x58, 96, 0xe01c, 0x4c), // Keypad Enter
+    (0x59, 79, 0x4f, 0x53), // Keypad 1
+    (0x5a, 80, 0x50, 0x54), // Keypad 2
+    (0x5b, 81, 0x51, 0x55), // Keypad 3
+    (0x5c, 75, 0x4b, 0x56), // Keypad 4
+    (0x5d, 76, 0x4c, 0x57), // Keypad 5
+    (0x5e, 77, 0x4d, 0x58), // Keypad 6
+    (0x5f, 71, 0x47, 0x59), // Keypad 7
+    (0x60, 72, 0x48, 0x5b), // Keypad 8
+    (0x61, 73, 0x49, 0x5c), // Keypad 9
+    (0x62, 82, 0x52, 0x52), // Keypad 0
+    (0x63, 83, 0x53, 0x41), // Keypad .
+    (0x64, 86, 0x56, 0x0a), // Non-US \ | (ISO key next to left Shift)
+    (0x65, 127, 0xe05d, NONE), // Application / Menu
+    (0x67, 117, 0x59, 0x51), // Keypad =
+    (0x68, 183, 0x64, 0x69), // F13
+    (0x69, 184, 0x65, 0x6b), // F14
+    (0x6a, 185, 0x66, 0x71), // F15
+    (0x6b, 186, 0x67, 0x6a), // F16
+    (0x6c, 187, 0x68, 0x40), // F17
+    (0x6d, 188, 0x69, 0x4f), // F18
+    (0x6e, 189, 0x6a, 0x50), // F19
+    (0x6f, 190, 0x6b, 0x5a), // F20
+    (0x70, 191, 0x6c, NONE), // F21
+    (0x71, 192, 0x6d, NONE), // F22
+    (0x72, 193, 0x6e, NONE), // F23
+    (0x73, 194, 0x76, NONE), // F24
+    // Media
+    (0x7f, 113, 0xe020, 0x4a), // Mute
+    (0x80, 115, 0xe030, 0x48), // Volume Up
+    (0x81, 114, 0xe02e, 0x49), // Volume Down
+    // Modifiers
+    (0xe0, 29, 0x1d, 0x3b), // Left Control
+    (0xe1, 42, 0x2a, 0x38), // Left Shift
+    (0xe2, 56, 0x38, 0x3a), // Left Alt / Option
+    (0xe3, 125, 0xe05b, 0x37), // Left Meta / Windows / Command
+    (0xe4, 97, 0xe01d, 0x3e), // Right Control
+    (0xe5, 54, 0x36, 0x3c), // Right Shift
+    (0xe6, 100, 0xe038, 0x3d), // Right Alt / Option / AltGr
+    (0xe7, 126, 0xe05c, 0x36), // Right Meta / Windows / Command
+];
+
+/// This platform's code for the key with HID usage `hid`.
+pub fn lookup(hid: u32) -> Result<u16, RdpStatus> {
+    let Some(&(_, evdev, scancode, mac)) = KEYS.iter().find(|&&(usage, ..)| usage == hid) else {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            format!("Unknown HID keycode {hid:#04x}"),
+        ));
+    };
+
+    let code = if cfg!(target_os = "macos") {
+        mac
+    } else if cfg!(windows) {
+        scancode
+    } else {
+        evdev
+    };
+    if code == NONE {
+        return Err(fail(
+            RdpStatus::Unsupported,
+            format!("HID keycode {hid:#04x} has no equivalent on this platform"),
+        ));
+    }
+    Ok(code)
+}
@@ -1,174 +1,4102 @@
-use scrap::{Capturer, Display};
-use std::io::ErrorKind::WouldBlock;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, c_char, c_void};
 use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
-use fast_image_resize as fr;
-use std::num::NonZeroU32;
+mod api;
+#[cfg(feature = "async")]
+mod async_capture;
+mod auth;
+mod base64;
+mod broadcast;
+mod capture;
+mod chunk;
+mod cipher;
+mod clipboard;
+mod config;
+mod cursor;
+mod decode;
+mod display;
+mod encode;
+mod error;
+mod focus;
+mod frame;
+#[cfg(feature = "h264")]
+mod h264;
+mod handle;
+mod http;
+mod input;
+mod json;
+mod keymap;
+mod local;
+mod log;
+mod memory;
+mod orient;
+mod overlay;
+mod pace;
+mod parallel;
+mod pattern;
+mod permission;
+mod pixels;
+mod priority;
+#[cfg(feature = "python")]
+mod python;
+mod queue;
+mod rate;
+mod record;
+mod replay;
+mod restart;
+mod rtp;
+mod scale;
+mod server;
+mod session;
+mod shm;
+mod simd;
+mod span;
+mod stats;
+mod stream;
+mod tcp;
+mod tiles;
+#[cfg(feature = "tls")]
+mod tls;
+mod version;
+mod video;
+#[cfg(feature = "vpx")]
+mod vpx;
+#[cfg(all(feature = "wayland", target_os = "linux"))]
+mod wayland;
+mod window;
+#[cfg(feature = "websocket")]
+mod ws;
+mod yuv;
+mod zstd;
 
-use image::{ImageBuffer, Rgb};
+pub use api::{CaptureSession, encode_bgra};
+#[cfg(feature = "async")]
+pub use async_capture::{AsyncCaptureSession, Capture, FrameStream, NextFrame};
+pub use capture::Backend as CaptureBackend;
+pub use chunk::{ChunkSet, reassemble, split as chunk_frame};
+pub use config::RdpConfig;
+pub use decode::{Decoder, FrameInfo, decode_image};
+pub use display::{DisplayCallback, DisplayInfo};
+pub use encode::Subsampling;
+pub use error::{CaptureError, RdpStatus};
+pub use focus::Focus;
+pub use frame::{EncodedFrame, FrameFormat, PixelFormat};
+pub use handle::SessionHandle;
+pub use input::MouseButton;
+pub use log::{LogCallback, LogLevel};
+pub use orient::Orientation;
+pub use overlay::{Anchor as WatermarkPosition, TextOverlay};
+pub use pace::WaitStrategy;
+pub use pattern::configure as set_test_pattern;
+pub use permission::CapturePermission;
+pub use pixels::{Rect as RdpRect, convert_bgra, convert_bgra_with, frame_hash};
+pub use priority::ThreadPriority;
+pub use queue::QueuePolicy;
+pub use rtp::{RtpPacket, RtpPackets, packetize_jpeg as packetize_rtp_jpeg};
+pub use scale::FitMode;
+pub use session::{RdpSession, SessionConfig};
+pub use shm::ShmReader;
+pub use simd::ConvertPath;
+pub use stats::{RdpConsumerStats, RdpStats};
+pub use stream::FrameCallback;
+pub use window::WindowInfo;
+pub use yuv::YuvMatrix;
 
+use error::{fail, fail_at, guard};
+use pixels::Rect;
+use stream::{FrameRing, Sink};
+
+/// A frame handed to the caller; release with `free_image`.
+///
+/// The struct and the `len` bytes behind `data` are owned by the library,
+/// whatever the format (raw frames included), and must be released with
+/// exactly one `free_image` call; copy the pixels out first if they need to
+/// outlive it.
+///
+/// New fields are only ever appended, so bindings that declare just the
+/// leading `data`/`len` pair keep working.
 #[repr(C)]
 pub struct RawImage {
     pub data: *mut u8,
     pub len: usize,
+    /// Dimensions of the encoded image, after any crop/resize.
+    pub width: u32,
+    pub height: u32,
+    /// A `FrameFormat` discriminant (0 = JPEG, 1 = PNG, 2 = WebP, 3 = raw,
+    /// 4 = tiled keyframe, 5 = tiled delta, 6 = UTF-8 text, 7 = H.264,
+    /// 8 = VP8, 9 = VP9, 10 = zstd-compressed raw, 11 = I420, 12 = NV12).
+    pub format: u32,
+    /// Bytes per row of a raw frame (once decompressed, for zstd) or of an
+    /// I420 or NV12 frame's Y plane; 0 for the other compressed formats.
+    pub stride: u32,
+    /// A `PixelFormat` discriminant (0 = BGRA, 1 = RGB, 2 = BGR, 3 = RGBA,
+    /// 4 = 8-bit gray) describing a raw frame's channels.
+    pub pixel_format: u32,
+    /// Bounding box of what changed since the previous frame, in this
+    /// image's coordinates (zero-sized if nothing did). Covers the whole
+    /// image unless dirty tracking is enabled on the session.
+    pub dirty_x: u32,
+    pub dirty_y: u32,
+    pub dirty_w: u32,
+    pub dirty_h: u32,
+    /// Hash of the pixel data before encoding, so it is unaffected by
+    /// quality or format settings but changes with size and pixel layout.
+    /// The function is fixed and platform independent (see
+    /// `pixels::frame_hash`): identical pixels always give the same value.
+    pub content_hash: u64,
+    /// Cursor hotspot in this image's coordinates, sampled at capture time.
+    /// Only meaningful when `cursor_visible` is non-zero; it is 0 when the
+    /// cursor is on another display, outside the capture region or cannot
+    /// be queried on this platform.
+    pub cursor_x: i32,
+    pub cursor_y: i32,
+    pub cursor_visible: u8,
+    /// Hotspot of an image from `rdp_get_cursor_image`; 0 for frames.
+    pub hotspot_x: u32,
+    pub hotspot_y: u32,
+    /// Number of the capture within its session, starting at 0. Frames
+    /// skipped by change detection still take a number, so a gap between
+    /// two frames counts the captures in between that looked the same or
+    /// failed to encode.
+    pub sequence: u64,
+    /// When the frame was grabbed from the OS (before resizing or encoding),
+    /// in microseconds since the session was opened, on the monotonic clock
+    /// (`std::time::Instant`: `CLOCK_MONOTONIC`, `QueryPerformanceCounter`,
+    /// `mach_absolute_time`). Differences between frames of one session are
+    /// exact; the values are unrelated to wall-clock time or to other
+    /// sessions.
+    pub timestamp_us: u64,
+    /// JPEG or WebP quality the frame was encoded at, which for JPEG varies
+    /// from frame to frame under `rdp_session_set_target_frame_bytes`; 0
+    /// for formats without one.
+    pub quality: u8,
+    /// Non-zero when the frame decodes without the ones before it: always
+    /// for still images, and for tiled and video keyframes (H.264 IDR). A
+    /// viewer joining a delta stream waits for one (or requests it with
+    /// `rdp_session_request_keyframe`).
+    pub keyframe: u8,
+    /// Byte count a zstd frame decompresses to (`stride * height`); 0 for
+    /// other formats.
+    pub uncompressed_len: u64,
+    /// Non-zero when `data` is the frame encrypted with the key from
+    /// `rdp_session_set_encryption_key`: a 12-byte nonce, the ciphertext
+    /// and a 16-byte AES-GCM tag. The other fields describe the frame as it
+    /// was before encryption.
+    pub encrypted: u8,
+    /// CRC-32 (IEEE, as zlib's `crc32`) of exactly the `len` bytes at
+    /// `data`, whatever the format, for a receiver to catch truncated or
+    /// corrupted payloads with `rdp_verify_frame`.
+    pub checksum: u32,
+    /// Byte offsets into `data` of the Y, U and V planes of an I420 frame,
+    /// or of the Y and interleaved UV planes of an NV12 frame (the third
+    /// entry 0), and the bytes per row of each; all 0 for other formats.
+    pub plane_offsets: [u64; 3],
+    pub plane_strides: [u32; 3],
+    /// Non-zero when the JPEG frame, or every JPEG tile of a tiled one, is
+    /// progressive (see `rdp_session_set_progressive`); 0 for baseline JPEG
+    /// and other formats.
+    pub progressive: u8,
+}
+
+/// Every `RawImage` handed out and not yet freed, by address, with the
+/// `data`/`len` it was handed out with. `free_image` only releases what is
+/// in here, so a second free, a foreign pointer or a caller that changed
+/// the fields cannot corrupt the heap.
+static LIVE_IMAGES: Mutex<BTreeMap<usize, (usize, usize)>> = Mutex::new(BTreeMap::new());
+
+fn live_images() -> MutexGuard<'static, BTreeMap<usize, (usize, usize)>> {
+    LIVE_IMAGES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl RawImage {
+    /// Hands `frame` over to the caller; reclaimed by `free_image`.
+    fn into_raw(frame: EncodedFrame) -> *mut RawImage {
+        let checksum = frame.checksum();
+        let (plane_offsets, plane_strides) = frame.planes().unwrap_or_default();
+        // A boxed slice, so `free_image` can rebuild it from pointer and length
+        let bytes = Box::into_raw(frame.data.into_boxed_slice());
+
+        let image_box = Box::new(RawImage {
+            data: bytes as *mut u8,
+            len: bytes.len(),
+            width: frame.width,
+            height: frame.height,
+            format: frame.format as u32,
+            stride: frame.stride,
+            pixel_format: frame.pixel_format as u32,
+            dirty_x: frame.dirty.x,
+            dirty_y: frame.dirty.y,
+            dirty_w: frame.dirty.w,
+            dirty_h: frame.dirty.h,
+            content_hash: frame.content_hash,
+            cursor_x: frame.cursor.map_or(0, |(x, _)| x),
+            cursor_y: frame.cursor.map_or(0, |(_, y)| y),
+            cursor_visible: u8::from(frame.cursor.is_some()),
+            hotspot_x: frame.hotspot.0,
+            hotspot_y: frame.hotspot.1,
+            sequence: frame.sequence,
+            timestamp_us: frame.timestamp_us,
+            quality: frame.quality,
+            keyframe: u8::from(frame.keyframe),
+            uncompressed_len: frame.uncompressed_len,
+            encrypted: u8::from(frame.encrypted),
+            checksum,
+            plane_offsets,
+            plane_strides,
+            progressive: u8::from(frame.progressive),
+        });
+
+        let image = Box::into_raw(image_box);
+        live_images().insert(image as usize, (bytes as *mut u8 as usize, bytes.len()));
+        image
+    }
+}
+
+/// Runs a single capture on a throwaway session, after letting `configure`
+/// adjust its settings.
+fn capture_once(
+    display_index: i32,
+    target_w: u32,
+    target_h: u32,
+    configure: impl FnOnce(&mut RdpSession) -> Result<(), RdpStatus>,
+) -> Result<EncodedFrame, RdpStatus> {
+    let mut session = RdpSession::new(display_index)?;
+    configure(&mut session)?;
+    session.capture(target_w, target_h)
+}
+
+/// Converts a capture result into the legacy "pointer or null" convention.
+fn into_raw_or_null(result: Result<EncodedFrame, RdpStatus>) -> *mut RawImage {
+    result.map_or(ptr::null_mut(), RawImage::into_raw)
+}
+
+/// Runs fallible FFI work, reporting a panic as `RdpStatus::Panic`.
+fn catch<T>(body: impl FnOnce() -> Result<T, RdpStatus>) -> Result<T, RdpStatus> {
+    guard(Err(RdpStatus::Panic), body)
+}
+
+/// Stores a capture result through `out_image` and returns its status code.
+/// On failure `*out_image` is set to null.
+///
+/// # Safety
+/// `out_image` must be null or valid for one pointer-sized write.
+unsafe fn write_capture(
+    result: Result<EncodedFrame, RdpStatus>,
+    out_image: *mut *mut RawImage,
+) -> i32 {
+    if out_image.is_null() {
+        return fail(RdpStatus::InvalidArgument, "out_image must not be null") as i32;
+    }
+
+    let (image, status) = match result {
+        Ok(frame) => (RawImage::into_raw(frame), RdpStatus::Ok),
+        Err(status) => (ptr::null_mut(), status),
+    };
+    unsafe { out_image.write(image) };
+    status as i32
+}
+
+/// Borrows an FFI session handle.
+///
+/// # Safety
+/// `session` must be null or a live pointer from `rdp_session_new`.
+unsafe fn handle_ref<'a>(session: *mut SessionHandle) -> Result<&'a SessionHandle, RdpStatus> {
+    unsafe { session.as_ref() }.ok_or_else(|| {
+        fail(
+            RdpStatus::InvalidArgument,
+            "Session handle must not be null",
+        )
+    })
+}
+
+/// Locks the session behind an FFI handle, waiting for any capture a stream
+/// thread has in progress.
+///
+/// # Safety
+/// Same as `handle_ref`.
+unsafe fn lock_session<'a>(
+    session: *mut SessionHandle,
+) -> Result<MutexGuard<'a, RdpSession>, RdpStatus> {
+    unsafe { handle_ref(session) }.map(SessionHandle::lock)
+}
+
+/// Parses an FFI format value, rejecting formats this build cannot produce,
+/// the tile containers (which wrap another format) and text.
+fn frame_format(format: u32) -> Result<FrameFormat, RdpStatus> {
+    let parsed = FrameFormat::from_u32(format)
+        .filter(|f| {
+            !matches!(
+                f,
+                FrameFormat::TiledKeyframe | FrameFormat::TiledDelta | FrameFormat::Text
+            )
+        })
+        .ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown output format {format}"),
+            )
+        })?;
+    encode::ensure_supported(parsed)?;
+    Ok(parsed)
+}
+
+/// Reads a server bind address; null means loopback only.
+///
+/// # Safety
+/// `bind_addr` must be null or point to a NUL-terminated string that
+/// outlives the result.
+unsafe fn bind_addr_from<'a>(bind_addr: *const c_char) -> Result<&'a str, RdpStatus> {
+    if bind_addr.is_null() {
+        return Ok("127.0.0.1");
+    }
+    unsafe { CStr::from_ptr(bind_addr) }.to_str().map_err(|e| {
+        fail(
+            RdpStatus::InvalidArgument,
+            format!("Bind address is not UTF-8: {e}"),
+        )
+    })
+}
+
+/// Borrows a non-null NUL-terminated path argument.
+unsafe fn path_from<'a>(path: *const c_char) -> Result<&'a str, RdpStatus> {
+    unsafe { CStr::from_ptr(path) }.to_str().map_err(|e| {
+        fail(
+            RdpStatus::InvalidArgument,
+            format!("Path is not UTF-8: {e}"),
+        )
+    })
 }
 
+/// Parses an FFI pixel-format value.
+fn pixel_format_from(pixel_format: u32) -> Result<PixelFormat, RdpStatus> {
+    PixelFormat::from_u32(pixel_format).ok_or_else(|| {
+        fail(
+            RdpStatus::InvalidArgument,
+            format!("Unknown pixel format {pixel_format}"),
+        )
+    })
+}
+
+/// Collapses a setter result into its status code.
+fn status_of(result: Result<(), RdpStatus>) -> i32 {
+    match result {
+        Ok(()) => RdpStatus::Ok as i32,
+        Err(status) => status as i32,
+    }
+}
+
+/// One-shot capture with every setting from `config`, on a throwaway
+/// session: the frame is written through `out_image` and the status
+/// returned, with the same per-field statuses as
+/// `rdp_session_new_with_config`. Release the frame with `free_image`.
+///
+/// # Safety
+/// `config` must be null or valid for reads of `struct_size` bytes;
+/// `out_image` must be null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capture_and_encode_with_config(
+    config: *const RdpConfig,
+    out_image: *mut *mut RawImage,
+) -> i32 {
+    if config.is_null() {
+        return fail(RdpStatus::InvalidArgument, "config must not be null") as i32;
+    }
+    let result = catch(|| unsafe { config::read(config) }?.open()?.capture(0, 0));
+    unsafe { write_capture(result, out_image) }
+}
+
+/// One-shot capture of the primary display.
+///
+/// Creates and destroys a session internally; callers capturing repeatedly
+/// should use the `rdp_session_*` functions instead.
 #[unsafe(no_mangle)]
 pub extern "C" fn capture_and_encode(target_w: u32, target_h: u32) -> *mut RawImage {
-    // 1. Create capturer
-    let display = match Display::primary() {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("Failed to get primary display: {e}");
-            return ptr::null_mut();
-        }
-    };
+    into_raw_or_null(catch(|| capture_once(-1, target_w, target_h, |_| Ok(()))))
+}
 
-    let mut capturer = match Capturer::new(display) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Failed to create capturer: {e}");
-            return ptr::null_mut();
-        }
-    };
+/// Status-code variant of `capture_display_and_encode`: the frame is written
+/// through `out_image` and the `RdpStatus` of the capture is returned.
+///
+/// # Safety
+/// `out_image` must be null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capture_and_encode_ex(
+    display_index: i32,
+    target_w: u32,
+    target_h: u32,
+    out_image: *mut *mut RawImage,
+) -> i32 {
+    let result = catch(|| capture_once(display_index, target_w, target_h, |_| Ok(())));
+    unsafe { write_capture(result, out_image) }
+}
+
+/// One-shot capture of the primary display at the given JPEG `quality`
+/// (clamped to 1–100).
+#[unsafe(no_mangle)]
+pub extern "C" fn capture_and_encode_q(target_w: u32, target_h: u32, quality: u8) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        capture_once(-1, target_w, target_h, |s| {
+            s.set_quality(quality);
+            Ok(())
+        })
+    }))
+}
+
+/// One-shot capture of the primary display in `format` (0 = JPEG,
+/// 1 = PNG, 2 = WebP). Returns null for an unknown format, or for WebP when
+/// the library was built without the `webp` feature.
+#[unsafe(no_mangle)]
+pub extern "C" fn capture_and_encode_fmt(
+    target_w: u32,
+    target_h: u32,
+    format: u32,
+) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        let format = frame_format(format)?;
+        capture_once(-1, target_w, target_h, |s| {
+            s.set_format(format);
+            Ok(())
+        })
+    }))
+}
+
+/// One-shot capture of the primary display returned as uncompressed pixels
+/// in `pixel_format` (0 = BGRA as captured, 1 = RGB, 2 = BGR, 3 = RGBA,
+/// 4 = gray), after any resize.
+/// Rows are `RawImage::stride` bytes apart. Returns null for an unknown
+/// pixel format.
+#[unsafe(no_mangle)]
+pub extern "C" fn capture_raw(target_w: u32, target_h: u32, pixel_format: u32) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        let pixel_format = pixel_format_from(pixel_format)?;
+        capture_once(-1, target_w, target_h, |s| {
+            s.set_format(FrameFormat::Raw);
+            s.set_pixel_format(pixel_format);
+            Ok(())
+        })
+    }))
+}
+
+/// One-shot capture of the primary display scaled by `scale` relative to its
+/// native size, each side rounded to an even number. `scale` must be > 0
+/// and, unless `allow_upscale` is set, <= 1; exactly 1.0 skips resizing.
+/// The frame is written through `out_image`; a bad scale returns
+/// `RdpStatus::InvalidArgument`.
+///
+/// # Safety
+/// `out_image` must be null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capture_and_encode_scaled(
+    scale: f32,
+    allow_upscale: bool,
+    out_image: *mut *mut RawImage,
+) -> i32 {
+    let result = catch(|| capture_once(-1, 0, 0, |s| s.set_scale(scale, allow_upscale)));
+    unsafe { write_capture(result, out_image) }
+}
+
+/// One-shot capture of the primary display that gives up after `timeout_ms`
+/// of waiting for a frame (`RdpStatus::Timeout`), or after a single attempt
+/// when `timeout_ms` is 0 (`RdpStatus::WouldBlock`). The frame is written
+/// through `out_image`.
+///
+/// # Safety
+/// `out_image` must be null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capture_and_encode_timeout(
+    target_w: u32,
+    target_h: u32,
+    timeout_ms: u32,
+    out_image: *mut *mut RawImage,
+) -> i32 {
+    let result = catch(|| {
+        capture_once(-1, target_w, target_h, |s| {
+            s.set_timeout(timeout_ms);
+            Ok(())
+        })
+    });
+    unsafe { write_capture(result, out_image) }
+}
 
-    let (w, h) = (capturer.width() as usize, capturer.height() as usize);
+/// One-shot capture of display `display_index` (-1 = primary, 0..n selects
+/// from `rdp_display_count`). Returns null if the index is out of range.
+#[unsafe(no_mangle)]
+pub extern "C" fn capture_display_and_encode(
+    display_index: i32,
+    target_w: u32,
+    target_h: u32,
+) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        capture_once(display_index, target_w, target_h, |_| Ok(()))
+    }))
+}
+
+/// One-shot capture of the `w x h` region at (`x`, `y`) on the primary
+/// display. The region is clamped to the display bounds; zero-area regions,
+/// or regions entirely off screen, return null.
+#[unsafe(no_mangle)]
+pub extern "C" fn capture_region_and_encode(
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    target_w: u32,
+    target_h: u32,
+) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        capture_once(-1, target_w, target_h, |s| {
+            s.set_region(Some(Rect { x, y, w, h }))
+        })
+    }))
+}
 
-    // 2. Get a frame (blocking until ready)
-    let frame = loop {
-        match capturer.frame() {
-            Ok(frame) => break frame,
-            Err(ref e) if e.kind() == WouldBlock => {
-                std::thread::sleep(std::time::Duration::from_millis(5));
-                continue;
+/// Takes a screenshot of display `display_index` (-1 = primary) straight
+/// to the file at `path`, a NUL-terminated UTF-8 path (non-ASCII paths work
+/// on every platform), overwriting it. `format` is 0 for JPEG or 1 for
+/// PNG; `quality` is the JPEG quality (1–100; 0 or less keeps the default)
+/// and `max_dim` caps the longer side (0 = native size). The image never
+/// crosses the FFI boundary.
+///
+/// Returns `RdpStatus::Ok`; the capture and encode failures of
+/// `capture_and_encode_ex` (e.g. `RdpStatus::NoDisplay`); or, once the
+/// image is ready, `RdpStatus::FileAccessDenied` when the file cannot be
+/// written for lack of permission, `RdpStatus::FileNotFound` when its
+/// directory does not exist and `RdpStatus::FileError` for any other I/O
+/// failure. A null or non-UTF-8 path or another `format` is
+/// `RdpStatus::InvalidArgument`.
+///
+/// # Safety
+/// `path` must be null or point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_capture_to_file(
+    display_index: i32,
+    path: *const c_char,
+    format: i32,
+    quality: i32,
+    max_dim: u32,
+) -> i32 {
+    status_of(catch(|| {
+        if path.is_null() {
+            return Err(fail(RdpStatus::InvalidArgument, "Screenshot path is null"));
+        }
+        let path = unsafe { path_from(path) }?;
+        let format = match format {
+            0 => FrameFormat::Jpeg,
+            1 => FrameFormat::Png,
+            _ => {
+                return Err(fail(
+                    RdpStatus::InvalidArgument,
+                    format!("Screenshots are JPEG (0) or PNG (1), not format {format}"),
+                ));
             }
-            Err(e) => {
-                eprintln!("Capture error: {e}");
-                return ptr::null_mut();
+        };
+        let frame = capture_once(display_index, 0, 0, |s| {
+            s.set_format(format);
+            if quality > 0 {
+                s.set_quality(quality.min(100) as u8);
             }
-        }
-    };
+            s.set_max_dim(max_dim);
+            Ok(())
+        })?;
+        // std converts the UTF-8 path to UTF-16 on Windows
+        std::fs::write(path, &frame.data)
+            .map_err(|e| error::fail_file(&e, format!("Failed to write {path}: {e}")))
+    }))
+}
+
+/// The library's version as a NUL-terminated semver string such as
+/// `"0.1.0"`, owned by the library and valid for as long as it is loaded.
+/// Needs no prior initialization, like the other version queries.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_version() -> *const c_char {
+    version::VERSION.as_ptr().cast()
+}
+
+/// The revision of the C ABI (structs and function signatures) this
+/// library implements; see `rdp_abi_compatible`.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_abi_version() -> u32 {
+    version::ABI_VERSION
+}
+
+/// What this build includes, as a bitmask: 1 = JPEG, 2 = PNG, 4 = raw,
+/// 8 = WebP, 16 = H.264, 32 = VP8/VP9, 64 = zstd, 128 = HTTP streaming,
+/// 256 = TCP serving, 512 = WebSocket serving, 1024 = TLS, 2048 = frame
+/// encryption, 4096 = input injection, 8192 = clipboard, 16384 = Wayland
+/// capture, 32768 = JPEG through libjpeg-turbo (otherwise the slower
+/// pure-Rust encoder). Set from the cargo features the library was built
+/// with.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_capabilities() -> u64 {
+    version::CAPABILITIES
+}
+
+/// Whether bindings written against ABI revision `expected` can use this
+/// library. On a mismatch `rdp_last_error_message` names both revisions
+/// and the library version, for bindings to fail fast with.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_abi_compatible(expected: u32) -> bool {
+    guard(false, || version::abi_compatible(expected))
+}
+
+/// Human-readable detail for the most recent failure on the calling thread
+/// (OS error text, encoder messages, ...), or an empty string if nothing has
+/// failed yet.
+///
+/// The returned NUL-terminated UTF-8 string is owned by the library and stays
+/// valid until the next `rdp_last_error_message` call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_last_error_message() -> *const c_char {
+    guard(ptr::null(), error::last_error_message)
+}
+
+/// Routes all diagnostics through `callback` instead of stderr. `level` is a
+/// `LogLevel` (0 = error, 1 = warn, 2 = info, 3 = debug) and `user_data` is
+/// passed back untouched. Passing a null `callback` uninstalls it; without a
+/// callback, warnings and errors go to stderr and the rest is dropped.
+///
+/// The callback is invoked without any library lock held, on whichever thread
+/// produced the message, so `user_data` must be safe to use from any thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_set_log_callback(callback: Option<LogCallback>, user_data: *mut c_void) {
+    guard((), || log::set_callback(callback, user_data));
+}
+
+/// The system cursor's current shape as a raw RGBA image (`format` 3,
+/// `pixel_format` 3, straight alpha) with its hotspot in `hotspot_x`/
+/// `hotspot_y`. Release it with `free_image`.
+///
+/// Returns null on failure; `rdp_last_error_message` has the reason.
+/// Platforms that cannot read the shape (currently X11) fail with
+/// `RdpStatus::Unsupported`.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_get_cursor_image() -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        let shape = cursor::current_shape()?;
+        let mut rgba = Vec::new();
+        pixels::convert_bgra(&shape.pixels, PixelFormat::Rgba, &mut rgba);
+        Ok(EncodedFrame {
+            content_hash: pixels::frame_hash(
+                &rgba,
+                &[shape.width, shape.height, PixelFormat::Rgba as u32],
+            ),
+            data: rgba,
+            width: shape.width,
+            height: shape.height,
+            format: FrameFormat::Raw,
+            pixel_format: PixelFormat::Rgba,
+            stride: shape.width * PixelFormat::Rgba.bytes_per_pixel(),
+            dirty: Rect {
+                x: 0,
+                y: 0,
+                w: shape.width,
+                h: shape.height,
+            },
+            cursor: None,
+            hotspot: (shape.hot_x, shape.hot_y),
+            sequence: 0,
+            timestamp_us: 0,
+            quality: 0,
+            keyframe: true,
+            uncompressed_len: 0,
+            encrypted: false,
+            progressive: false,
+        })
+    }))
+}
+
+/// Changes whenever the system cursor's shape does, so clients can poll it
+/// and only call `rdp_get_cursor_image` when it moves. Stays 0 on platforms
+/// without cursor images.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_get_cursor_generation() -> u64 {
+    guard(0, cursor::generation)
+}
+
+/// Moves the pointer to (`x`, `y`) in global screen coordinates: X11 root
+/// window pixels, Windows virtual-desktop pixels, macOS global points. Use
+/// `rdp_session_inject_mouse_move` to address a captured display instead.
+///
+/// Safe to call from any thread. Fails with `RdpStatus::PermissionDenied`
+/// when the OS does not let this process inject input.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_inject_mouse_move(x: i32, y: i32) -> i32 {
+    status_of(catch(|| input::move_to(f64::from(x), f64::from(y))))
+}
+
+/// Presses or releases a mouse button (0 = left, 1 = right, 2 = middle,
+/// 3 = back, 4 = forward) at the current pointer position.
+///
+/// Safe to call from any thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_inject_mouse_button(button: i32, pressed: bool) -> i32 {
+    status_of(catch(|| {
+        let button = MouseButton::from_i32(button).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown mouse button {button}"),
+            )
+        })?;
+        input::button(button, pressed)
+    }))
+}
+
+/// Scrolls by `dx` horizontal and `dy` vertical wheel notches (positive =
+/// right/up, at most 100 per axis). Each call becomes one event per axis
+/// (one click per notch on X11) sent straight away, without coalescing, so
+/// frequent small deltas never queue up behind each other.
+///
+/// Safe to call from any thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_inject_scroll(dx: i32, dy: i32) -> i32 {
+    status_of(catch(|| input::scroll(dx, dy)))
+}
+
+/// Presses or releases a key. `keycode` is a USB HID usage ID from the
+/// Keyboard/Keypad page (e.g. 0x04 = A, 0x28 = Enter, 0xE0 = left Control),
+/// the same codes browsers report as `KeyboardEvent.code`. Modifiers stay
+/// held until released, so Ctrl+C is Control down, C down, C up, Control up.
+///
+/// Fails with `RdpStatus::InvalidArgument` for codes outside the table and
+/// `RdpStatus::Unsupported` for keys this platform lacks. Safe to call from
+/// any thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_inject_key(keycode: u32, pressed: bool) -> i32 {
+    status_of(catch(|| input::key(keycode, pressed)))
+}
 
-    let total_len = frame.len();
-    if h == 0 || w == 0 || total_len == 0 {
-        eprintln!("Capture got empty frame (w={w}, h={h}, len={total_len})");
-        return ptr::null_mut();
-    }
-
-    // We EXPECT at least w * h * 4 bytes (BGRA)
-    let bytes_per_pixel = 4usize;
-    let needed = w
-        .checked_mul(h)
-        .and_then(|px| px.checked_mul(bytes_per_pixel))
-        .unwrap_or(0);
-
-    if needed == 0 || total_len < needed {
-        eprintln!(
-            "Frame too small: w={w}, h={h}, needed={needed}, got={total_len}"
-        );
-        return ptr::null_mut();
-    }
-
-    // --- Core fix: take EXACTLY w*h*4 bytes, ignore any trailing padding ---
-    let clean_buffer: Vec<u8> = frame[..needed].to_vec();
-    // ---------------------------------------------------------------------- 
-
-    // 3. Wrap in fast_image_resize Image
-    let src_image = match fr::Image::from_vec_u8(
-        NonZeroU32::new(w as u32).unwrap(),
-        NonZeroU32::new(h as u32).unwrap(),
-        clean_buffer,
-        fr::PixelType::U8x4,
-    ) {
-        Ok(img) => img,
-        Err(e) => {
-            eprintln!("Failed to create src_image for resize: {e}");
-            return ptr::null_mut();
+/// Types a NUL-terminated UTF-8 string as literal text, for input that does
+/// not map onto keycodes (IME output, characters from other layouts).
+/// Held modifiers do not apply to it.
+///
+/// # Safety
+/// `utf8` must be null or point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_inject_text(utf8: *const c_char) -> i32 {
+    status_of(catch(|| {
+        if utf8.is_null() {
+            return Err(fail(RdpStatus::InvalidArgument, "Text must not be null"));
         }
+        let text = unsafe { CStr::from_ptr(utf8) }.to_str().map_err(|e| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Text is not UTF-8: {e}"),
+            )
+        })?;
+        input::type_text(text)
+    }))
+}
+
+/// The clipboard's text as UTF-8 in a `RawImage` (`format` 6, `len` bytes,
+/// not NUL-terminated; width and height are 0). `len` is 0 when the
+/// clipboard holds no text. Release it with `free_image`.
+///
+/// Returns null on failure, e.g. `RdpStatus::ClipboardUnavailable` in a
+/// headless session, or `RdpStatus::Unsupported` if the library was built
+/// without the `clipboard` feature; `rdp_last_error_message` has the
+/// reason.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_clipboard_get_text() -> *mut RawImage {
+    into_raw_or_null(catch(|| Ok(text_frame(clipboard::get_text()?))))
+}
+
+/// `text` as a `FrameFormat::Text` frame, for handing strings out through
+/// a `RawImage`.
+fn text_frame(text: String) -> EncodedFrame {
+    let text = text.into_bytes();
+    EncodedFrame {
+        content_hash: pixels::frame_hash(&text, &[]),
+        data: text,
+        width: 0,
+        height: 0,
+        format: FrameFormat::Text,
+        pixel_format: PixelFormat::Gray,
+        stride: 0,
+        dirty: Rect {
+            x: 0,
+            y: 0,
+            w: 0,
+            h: 0,
+        },
+        cursor: None,
+        hotspot: (0, 0),
+        sequence: 0,
+        timestamp_us: 0,
+        quality: 0,
+        keyframe: true,
+        uncompressed_len: 0,
+        encrypted: false,
+        progressive: false,
+    }
+}
+
+/// Replaces the clipboard contents with `len` bytes of UTF-8 text. Fails
+/// like `rdp_clipboard_get_text`.
+///
+/// # Safety
+/// `utf8` must be valid for reads of `len` bytes (it may be null when `len`
+/// is 0).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_clipboard_set_text(utf8: *const c_char, len: usize) -> i32 {
+    status_of(catch(|| {
+        let bytes = if len == 0 {
+            &[][..]
+        } else if utf8.is_null() {
+            return Err(fail(RdpStatus::InvalidArgument, "Text must not be null"));
+        } else {
+            unsafe { std::slice::from_raw_parts(utf8.cast::<u8>(), len) }
+        };
+        let text = std::str::from_utf8(bytes).map_err(|e| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Text is not UTF-8: {e}"),
+            )
+        })?;
+        clipboard::set_text(text)
+    }))
+}
+
+/// Changes whenever the clipboard contents do (our own
+/// `rdp_clipboard_set_text` included), so hosts can poll it and only fetch
+/// the text when it moves. Cheap on Windows and macOS; X11 has no change
+/// counter, so there it fetches and hashes the text. 0 if the clipboard is
+/// unavailable or the library was built without the `clipboard` feature.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_clipboard_generation() -> u64 {
+    guard(0, clipboard::generation)
+}
+
+/// Chooses how sessions opened from now on capture the screen: 0 = pick
+/// automatically (the default), 1 = natively (X11, DXGI or Quartz), 2 =
+/// through the Wayland screencast portal, 3 = a generated test pattern (see
+/// `rdp_set_test_pattern`). Automatic picks Wayland when `WAYLAND_DISPLAY`
+/// is set and the `wayland` feature is built in, since X11 sees at most the
+/// XWayland windows of a Wayland desktop, and never the test pattern.
+///
+/// On Wayland the user chooses the monitor in the portal's dialog, so the
+/// display index is ignored and opening a session blocks until they answer
+/// (up to two minutes). Declining fails with `RdpStatus::PermissionDenied`.
+/// The portal draws the cursor into the frames, and input injection is not
+/// available.
+///
+/// Returns `RdpStatus::Ok`, `RdpStatus::InvalidArgument` for an unknown
+/// backend, or `RdpStatus::BackendUnavailable` for one not compiled in.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_set_capture_backend(backend: i32) -> i32 {
+    status_of(catch(|| {
+        let backend = CaptureBackend::from_i32(backend).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown capture backend {backend}"),
+            )
+        })?;
+        capture::set_backend(backend)
+    }))
+}
+
+/// The capture backends compiled into this build, as a bit mask with bit
+/// `1 << backend` set for each (backend numbers as for
+/// `rdp_set_capture_backend`): native capture and the test pattern are
+/// always there, Wayland with the `wayland` feature on Linux.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_list_backends() -> u32 {
+    guard(0, capture::compiled)
+}
+
+/// Sets what test pattern sessions (backend 3) opened from now on
+/// generate: `width` x `height` frames (32 to 8192 pixels each way), a new
+/// one `fps` times a second (at most 1000), or on every capture when `fps`
+/// is 0. The default is 1280x720 at 60 fps. Open test sessions pick up a
+/// new size at their next display check, failing a capture with
+/// `RdpStatus::ResolutionChanged` as a real display would.
+///
+/// Each frame has SMPTE color bars on top, a gradient below that moves 4
+/// pixels left per frame, and the frame number since the session opened
+/// burned into the top left corner: 32 square cells, most significant bit
+/// first, white for 1 and black for 0, each `clamp(width / 64, 1, 16)`
+/// pixels. Needs no display, so the whole pipeline runs headless.
+///
+/// Returns `RdpStatus::Ok` or `RdpStatus::InvalidArgument`.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_set_test_pattern(width: u32, height: u32, fps: u32) -> i32 {
+    status_of(catch(|| pattern::configure(width, height, fps)))
+}
+
+/// Whether this process may capture the screen: 0 = granted, 1 = denied,
+/// 2 = not granted and not yet asked for. Only macOS restricts capture
+/// (Screen Recording permission); elsewhere this is always 0. Without the
+/// permission macOS still hands out frames, but they show nothing but the
+/// wallpaper, so session creation fails with `RdpStatus::PermissionDenied`
+/// instead.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_check_capture_permission() -> i32 {
+    guard(CapturePermission::Undetermined as i32, || {
+        permission::check() as i32
+    })
+}
+
+/// Asks the user for the screen capture permission, showing the system
+/// prompt the first time, and returns the permission as
+/// `rdp_check_capture_permission` does. The prompt does not wait for an
+/// answer, and macOS only applies a grant once the app restarts. Always 0
+/// on platforms that do not restrict capture.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_request_capture_permission() -> i32 {
+    guard(CapturePermission::Undetermined as i32, || {
+        permission::request() as i32
+    })
+}
+
+/// Number of displays available for capture, or `RdpStatus::NoDisplay` if
+/// they cannot be enumerated.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_display_count() -> i32 {
+    let result = catch(|| {
+        display::enumerate().map_err(|e| {
+            fail(
+                RdpStatus::NoDisplay,
+                format!("Failed to enumerate displays: {e}"),
+            )
+        })
+    });
+
+    match result {
+        Ok(all) => all.len() as i32,
+        Err(status) => status as i32,
+    }
+}
+
+/// Re-enumerates the displays, e.g. after a monitor was plugged in, and
+/// calls the `rdp_set_display_callback` callback if they changed since the
+/// last refresh. Returns the number of displays, or `RdpStatus::NoDisplay`
+/// if they cannot be enumerated.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_refresh_displays() -> i32 {
+    let result = catch(|| {
+        display::refresh().map_err(|e| {
+            fail(
+                RdpStatus::NoDisplay,
+                format!("Failed to enumerate displays: {e}"),
+            )
+        })
+    });
+
+    match result {
+        Ok(all) => all.len() as i32,
+        Err(status) => status as i32,
+    }
+}
+
+/// Calls `callback` with the new display count whenever displays are
+/// added, removed or changed, so a host can update its monitor picker.
+/// While a callback is installed, a background thread re-enumerates the
+/// displays every second; `rdp_refresh_displays` checks immediately.
+/// Passing a null `callback` uninstalls it and stops the thread.
+///
+/// The callback is invoked without any library lock held, on the watcher
+/// thread or whichever thread called `rdp_refresh_displays`, so
+/// `user_data` must be safe to use from any thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_set_display_callback(
+    callback: Option<DisplayCallback>,
+    user_data: *mut c_void,
+) {
+    guard((), || display::set_callback(callback, user_data));
+}
+
+/// Fills `out` with the description of display `index` (the same index
+/// `rdp_session_new` accepts).
+///
+/// Returns `RdpStatus::Ok`, `RdpStatus::NoDisplay` for an out-of-range
+/// index, or `RdpStatus::InvalidArgument` if `out` is null.
+///
+/// # Safety
+/// `out` must be null or point to writable memory for one `DisplayInfo`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_display_info(index: i32, out: *mut DisplayInfo) -> i32 {
+    if out.is_null() {
+        return fail(RdpStatus::InvalidArgument, "out must not be null") as i32;
+    }
+
+    status_of(catch(|| {
+        let all = display::enumerate().map_err(|e| {
+            fail(
+                RdpStatus::NoDisplay,
+                format!("Failed to enumerate displays: {e}"),
+            )
+        })?;
+
+        let info = usize::try_from(index)
+            .ok()
+            .and_then(|i| all.get(i))
+            .ok_or_else(|| {
+                fail(
+                    RdpStatus::NoDisplay,
+                    format!("No display at index {index} ({} available)", all.len()),
+                )
+            })?;
+        unsafe { out.write(*info) };
+        Ok(())
+    }))
+}
+
+/// Lists the top-level windows that can be shared, frontmost first where
+/// the platform reports the stacking order, for `rdp_window_info` to read.
+/// Returns how many there are, or `RdpStatus::NoDisplay` if they cannot be
+/// enumerated (no X server, or a window manager that does not list its
+/// windows).
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_window_count() -> i32 {
+    let result = catch(|| {
+        window::refresh().map_err(|e| {
+            fail(
+                RdpStatus::NoDisplay,
+                format!("Failed to enumerate windows: {e}"),
+            )
+        })
+    });
+
+    match result {
+        Ok(count) => count as i32,
+        Err(status) => status as i32,
+    }
+}
+
+/// Fills `out` with the description of window `index` as of the last
+/// `rdp_window_count` call, so indices stay put while a host walks the
+/// list. Pass its `id` to `rdp_session_new_for_window`.
+///
+/// Returns `RdpStatus::Ok`, or `RdpStatus::InvalidArgument` for an
+/// out-of-range index or a null `out`.
+///
+/// # Safety
+/// `out` must be null or point to writable memory for one `WindowInfo`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_window_info(index: i32, out: *mut WindowInfo) -> i32 {
+    if out.is_null() {
+        return fail(RdpStatus::InvalidArgument, "out must not be null") as i32;
+    }
+
+    status_of(catch(|| {
+        let info = usize::try_from(index)
+            .ok()
+            .and_then(window::listed)
+            .ok_or_else(|| {
+                fail(
+                    RdpStatus::InvalidArgument,
+                    format!("No window at index {index} (call rdp_window_count first)"),
+                )
+            })?;
+        unsafe { out.write(info) };
+        Ok(())
+    }))
+}
+
+/// Opens a capture session on `display_index` (-1 = primary display,
+/// -2 = every display side by side in one frame). Returns null on
+/// failure, including when the OS withholds the screen capture permission
+/// (see `rdp_check_capture_permission`); release with `rdp_session_free`.
+///
+/// Spanning sessions copy each display onto a canvas covering the bounding
+/// box of the layout, at its place on the virtual desktop (see
+/// `DisplayInfo::x` and `y`); space no display covers stays black. A
+/// display with nothing new keeps its previous pixels rather than holding
+/// the others back. Cursor drawing, input injection, regions and blackout
+/// rectangles all use canvas coordinates. Not available on Wayland, where
+/// the portal shares a single monitor.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_session_new(display_index: i32) -> *mut SessionHandle {
+    match catch(|| RdpSession::new(display_index)) {
+        Ok(session) => Box::into_raw(Box::new(SessionHandle::new(session))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Opens a capture session on the display whose `DisplayInfo::id` is
+/// `display_id`. Unlike an index, the ID keeps pointing at the same
+/// monitor as others are plugged in or out. Returns null on failure
+/// (`RdpStatus::NoDisplay` when no display has that ID); release with
+/// `rdp_session_free`.
+///
+/// Sessions opened by index also stick to the display they opened on. Once
+/// it is disconnected, captures fail with `RdpStatus::DisplayRemoved`
+/// instead of showing whichever display took its index, and resume if it
+/// comes back within the recovery timeout.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_session_new_by_id(display_id: u64) -> *mut SessionHandle {
+    match catch(|| RdpSession::with_display_id(display_id)) {
+        Ok(session) => Box::into_raw(Box::new(SessionHandle::new(session))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Status-code variant of `rdp_session_new`: the session is written through
+/// `out_session` (null on failure).
+///
+/// # Safety
+/// `out_session` must be null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_new_ex(
+    display_index: i32,
+    out_session: *mut *mut SessionHandle,
+) -> i32 {
+    if out_session.is_null() {
+        return fail(RdpStatus::InvalidArgument, "out_session must not be null") as i32;
+    }
+
+    let (session, status) = match catch(|| RdpSession::new(display_index)) {
+        Ok(session) => (
+            Box::into_raw(Box::new(SessionHandle::new(session))),
+            RdpStatus::Ok,
+        ),
+        Err(status) => (ptr::null_mut(), status),
     };
+    unsafe { out_session.write(session) };
+    status as i32
+}
+
+/// `rdp_session_new_ex` with the capture backend forced to `backend` (as
+/// for `rdp_set_capture_backend`; 0 keeps the automatic choice) instead of
+/// the process-wide one. Fails with `RdpStatus::BackendUnavailable` when
+/// the backend is not compiled in or cannot work here, e.g. Wayland
+/// without `WAYLAND_DISPLAY` or native capture on Linux without `DISPLAY`.
+/// `RdpStats::backend` reports which backend a session uses.
+///
+/// # Safety
+/// `out_session` must be null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_new_with_backend(
+    backend: i32,
+    display_index: i32,
+    out_session: *mut *mut SessionHandle,
+) -> i32 {
+    if out_session.is_null() {
+        return fail(RdpStatus::InvalidArgument, "out_session must not be null") as i32;
+    }
 
-    // 4. Optional resize
-    let (final_pixel_data, final_w, final_h) = if target_w > 0 && target_h > 0 {
-        let mut dst_image = fr::Image::new(
-            NonZeroU32::new(target_w).unwrap(),
-            NonZeroU32::new(target_h).unwrap(),
-            fr::PixelType::U8x4,
-        );
-
-        let mut resizer = fr::Resizer::new(fr::ResizeAlg::Nearest);
-        if let Err(e) = resizer.resize(&src_image.view(), &mut dst_image.view_mut()) {
-            eprintln!("Resize error: {e}");
-            return ptr::null_mut();
-        }
-
-        (dst_image.into_vec(), target_w, target_h)
-    } else {
-        let w_u32 = w as u32;
-        let h_u32 = h as u32;
-        (src_image.into_vec(), w_u32, h_u32)
+    let result = catch(|| {
+        let backend = CaptureBackend::from_i32(backend).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown capture backend {backend}"),
+            )
+        })?;
+        RdpSession::with_backend(backend, display_index)
+    });
+    let (session, status) = match result {
+        Ok(session) => (
+            Box::into_raw(Box::new(SessionHandle::new(session))),
+            RdpStatus::Ok,
+        ),
+        Err(status) => (ptr::null_mut(), status),
     };
+    unsafe { out_session.write(session) };
+    status as i32
+}
 
-    // 5. Convert BGRA → RGB for JPEG encoder (Scrap on mac gives BGRA)
-    let rgb_pixels: Vec<u8> = final_pixel_data
-        .chunks_exact(4)
-        .flat_map(|bgra| {
-            let b = bgra[0];
-            let g = bgra[1];
-            let r = bgra[2];
-            [r, g, b] // → R, G, B
-        })
-        .collect();
-
-    let image_buf: ImageBuffer<Rgb<u8>, Vec<u8>> =
-        match ImageBuffer::from_vec(final_w, final_h, rgb_pixels) {
-            Some(buf) => buf,
-            None => {
-                eprintln!("Failed to create ImageBuffer (final_w={final_w}, final_h={final_h})");
-                return ptr::null_mut();
-            }
-        };
+/// Fills `config` with the default settings. Set `config->struct_size` to
+/// `sizeof(RdpConfig)` first; fields past what this library knows are left
+/// alone (zero them, as a newer library would read them).
+///
+/// Returns `RdpStatus::Ok`, `RdpStatus::InvalidConfigSize` for a
+/// `struct_size` below the first version's, or `RdpStatus::InvalidArgument`
+/// if `config` is null.
+///
+/// # Safety
+/// `config` must be null or valid for writes of `struct_size` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_config_default(config: *mut RdpConfig) -> i32 {
+    if config.is_null() {
+        return fail(RdpStatus::InvalidArgument, "config must not be null") as i32;
+    }
+    status_of(catch(|| unsafe { config::write_default(config) }))
+}
 
-    // 6. Compress to JPEG (quality 70 for speed)
-    let jpeg_data = match turbojpeg::compress_image(
-        &image_buf,
-        70,
-        turbojpeg::Subsamp::Sub2x2,
-    ) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Failed to compress JPEG: {e}");
-            return ptr::null_mut();
-        }
+/// Opens a session with every setting from `config` (see `RdpConfig`),
+/// written through `out_session` (null on failure).
+///
+/// Every field is checked before anything is opened. A bad field fails
+/// with its own status (`RdpStatus::InvalidQuality`,
+/// `RdpStatus::InvalidFormat` and the others from -34 on), a
+/// `struct_size` that does not fit this library with
+/// `RdpStatus::InvalidConfigSize`. Opening the display fails as for
+/// `rdp_session_new_with_backend`.
+///
+/// # Safety
+/// `config` must be null or valid for reads of `struct_size` bytes;
+/// `out_session` must be null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_new_with_config(
+    config: *const RdpConfig,
+    out_session: *mut *mut SessionHandle,
+) -> i32 {
+    if config.is_null() || out_session.is_null() {
+        return fail(
+            RdpStatus::InvalidArgument,
+            "config and out_session must not be null",
+        ) as i32;
+    }
+
+    let result = catch(|| unsafe { config::read(config) }?.open());
+    let (session, status) = match result {
+        Ok(session) => (
+            Box::into_raw(Box::new(SessionHandle::new(session))),
+            RdpStatus::Ok,
+        ),
+        Err(status) => (ptr::null_mut(), status),
     };
+    unsafe { out_session.write(session) };
+    status as i32
+}
 
-    let mut jpeg_vec = jpeg_data.to_vec();
+/// Opens a session configured by the JSON object `json`, written through
+/// `out_session` (null on failure), e.g.
+/// `{"display": 1, "quality": 80, "format": "png", "region": {"x": 0,
+/// "y": 0, "w": 1280, "h": 720}}`.
+///
+/// Keys are the settings `rdp_session_get_config_json` lists, with enums
+/// given by name; left-out keys keep their defaults. Unknown keys are
+/// logged as warnings and skipped. Malformed JSON, or a key with a value of
+/// the wrong type or out of range, fails with `RdpStatus::InvalidArgument`
+/// before anything is opened, and `rdp_last_error_message` names the key.
+///
+/// # Safety
+/// `json` must be null or a NUL-terminated string; `out_session` must be
+/// null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_new_json(
+    json: *const c_char,
+    out_session: *mut *mut SessionHandle,
+) -> i32 {
+    if json.is_null() || out_session.is_null() {
+        return fail(
+            RdpStatus::InvalidArgument,
+            "json and out_session must not be null",
+        ) as i32;
+    }
 
-    // 7. Build RawImage for FFI
-    let image_box = Box::new(RawImage {
-        data: jpeg_vec.as_mut_ptr(),
-        len: jpeg_vec.len(),
+    let result = catch(|| {
+        let text = unsafe { CStr::from_ptr(json) }.to_str().map_err(|e| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Config is not UTF-8: {e}"),
+            )
+        })?;
+        config::from_json(text)?.open()
     });
+    let (session, status) = match result {
+        Ok(session) => (
+            Box::into_raw(Box::new(SessionHandle::new(session))),
+            RdpStatus::Ok,
+        ),
+        Err(status) => (ptr::null_mut(), status),
+    };
+    unsafe { out_session.write(session) };
+    status as i32
+}
+
+/// The settings `session` is running with, as the JSON object
+/// `rdp_session_new_json` reads, in a `RawImage` like
+/// `rdp_clipboard_get_text`'s. `display` and `backend` are resolved: the
+/// display's current index and the backend actually opened. Meant for
+/// debugging; release it with `free_image`. Returns null on failure.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_get_config_json(session: *mut SessionHandle) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        let session = unsafe { lock_session(session) }?;
+        Ok(text_frame(config::to_json(&session)))
+    }))
+}
 
-    // Prevent Rust from freeing jpeg_vec; Python will call free_image
-    std::mem::forget(jpeg_vec);
+/// Opens a session that shares only the window with `WindowInfo::id`
+/// `window_id`, written through `out_session` (null on failure). The
+/// display under the window is captured and cropped to the window's
+/// bounds, looked up again for every frame, so the session follows the
+/// window as it moves between displays and resizes; a new size is
+/// delivered as a keyframe. Capture regions set on the session are
+/// ignored.
+///
+/// Whatever overlaps the window on screen (other windows, menus,
+/// notifications) is captured along with it, on every platform. Captures
+/// fail with `RdpStatus::DisplayUnavailable` while the window is minimized
+/// or off screen, and with `RdpStatus::WindowClosed` from the moment it is
+/// closed, which also ends a running stream. Fails with
+/// `RdpStatus::WindowClosed` if no window has that ID.
+///
+/// # Safety
+/// `out_session` must be null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_new_for_window(
+    window_id: u64,
+    out_session: *mut *mut SessionHandle,
+) -> i32 {
+    if out_session.is_null() {
+        return fail(RdpStatus::InvalidArgument, "out_session must not be null") as i32;
+    }
 
-    Box::into_raw(image_box)
+    let (session, status) = match catch(|| RdpSession::with_window(window_id)) {
+        Ok(session) => (
+            Box::into_raw(Box::new(SessionHandle::new(session))),
+            RdpStatus::Ok,
+        ),
+        Err(status) => (ptr::null_mut(), status),
+    };
+    unsafe { out_session.write(session) };
+    status as i32
 }
 
+/// Captures and encodes one frame from `session`.
+///
+/// Returns null on failure, including when change detection is on and the
+/// screen has not changed (see `rdp_session_set_detect_changes`).
+///
+/// The session follows its display through resolution changes (checked
+/// about once a second, and whenever capturing fails) by reopening the
+/// capturer. The capture that notices a new resolution fails with
+/// `RdpStatus::ResolutionChanged`, so a client can renegotiate its
+/// viewport; the next one delivers a keyframe at the new size.
+///
+/// When the display goes out of reach (locked workstation, secure desktop,
+/// sleep), captures fail straight away with `RdpStatus::DisplayUnavailable`
+/// while the session retries opening it with growing pauses (up to 5 s).
+/// Once it is back, capturing resumes with a keyframe. After
+/// `rdp_session_set_recovery_timeout` without success the session gives up
+/// and every capture fails with `RdpStatus::CaptureFailed`.
+///
+/// # Safety
+/// `session` must be null or a pointer returned by `rdp_session_new` that has
+/// not been freed. Calls on one session from several threads are
+/// serialized, so they wait for each other (and for a running stream's
+/// capture).
 #[unsafe(no_mangle)]
-pub extern "C" fn free_image(image_ptr: *mut RawImage) {
-    if image_ptr.is_null() {
-        return;
+pub unsafe extern "C" fn rdp_session_capture(
+    session: *mut SessionHandle,
+    target_w: u32,
+    target_h: u32,
+) -> *mut RawImage {
+    let result = catch(|| unsafe { lock_session(session) }?.capture(target_w, target_h));
+    into_raw_or_null(result)
+}
+
+/// Status-code variant of `rdp_session_capture`.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `out_image` must be null or valid
+/// for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_capture_ex(
+    session: *mut SessionHandle,
+    target_w: u32,
+    target_h: u32,
+    out_image: *mut *mut RawImage,
+) -> i32 {
+    let result = catch(|| unsafe { lock_session(session) }?.capture(target_w, target_h));
+    unsafe { write_capture(result, out_image) }
+}
+
+/// Captures one frame from `session` like `rdp_session_capture`, with the
+/// payload replaced by its base64 (RFC 4648, padded, no line breaks), e.g.
+/// for an `<img src="data:...">`. With `data_uri` set, it is prefixed with
+/// `data:<type>;base64,` for the frame's format (`image/jpeg`, `image/png`
+/// or `image/webp`; `application/octet-stream` for the others and for
+/// encrypted frames), ready to use as is. The payload is not
+/// NUL-terminated; `RawImage::len` is its length. Every other field
+/// describes the frame as encoded, and it is released with `free_image`.
+///
+/// Returns null on failure, as `rdp_session_capture` does.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_capture_base64(
+    session: *mut SessionHandle,
+    target_w: u32,
+    target_h: u32,
+    data_uri: bool,
+) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        let mut frame = unsafe { lock_session(session) }?.capture(target_w, target_h)?;
+        let mut text = Vec::new();
+        if data_uri {
+            let mime = if frame.encrypted {
+                "application/octet-stream"
+            } else {
+                frame.format.mime_type()
+            };
+            text.extend_from_slice(format!("data:{mime};base64,").as_bytes());
+        }
+        base64::encode_into(&frame.data, &mut text);
+        frame.data = text;
+        Ok(frame)
+    }))
+}
+
+/// Captures one frame from `session` and copies its data (encoded or raw,
+/// per the session format) into the caller's `buf` instead of allocating a
+/// `RawImage`. `*out_written` receives the byte count.
+///
+/// When `buf_len` is too small, returns `RdpStatus::BufferTooSmall` with the
+/// required size in `*out_written` and nothing written to `buf`. The frame
+/// is kept: the next capture from `session` with the same target size
+/// returns it (here, as soon as the buffer is large enough), so no frame a
+/// later delta depends on is lost. Passing a null `buf` with `buf_len` 0 is
+/// a valid way to ask for the size.
+/// On any other failure `*out_written` is 0. The library keeps no reference
+/// to `buf`, so the same buffer can be passed on every call.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `buf` must be null (with
+/// `buf_len` 0) or valid for `buf_len` byte writes, and `out_written` must
+/// be null or valid for one `usize` write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_capture_into(
+    session: *mut SessionHandle,
+    target_w: u32,
+    target_h: u32,
+    buf: *mut u8,
+    buf_len: usize,
+    out_written: *mut usize,
+) -> i32 {
+    if out_written.is_null() || (buf.is_null() && buf_len > 0) {
+        return fail(
+            RdpStatus::InvalidArgument,
+            "out_written must not be null, nor buf when buf_len > 0",
+        ) as i32;
+    }
+
+    let result = catch(|| {
+        let mut session = unsafe { lock_session(session) }?;
+        let frame = session.capture(target_w, target_h)?;
+        let needed = frame.data.len();
+        if needed > buf_len {
+            unsafe { out_written.write(needed) };
+            session.hold(frame, (target_w, target_h));
+            return Err(fail_at(
+                LogLevel::Warn,
+                RdpStatus::BufferTooSmall,
+                format!("Frame needs {needed} bytes, buffer holds {buf_len}"),
+            ));
+        }
+
+        // `frame.data` is dropped on return
+        unsafe { ptr::copy_nonoverlapping(frame.data.as_ptr(), buf, needed) };
+        Ok(needed)
+    });
+
+    match result {
+        Ok(written) => {
+            unsafe { out_written.write(written) };
+            RdpStatus::Ok as i32
+        }
+        Err(RdpStatus::BufferTooSmall) => RdpStatus::BufferTooSmall as i32,
+        Err(status) => {
+            unsafe { out_written.write(0) };
+            status as i32
+        }
     }
+}
+
+/// Non-blocking capture: polls `session` exactly once. Returns
+/// `RdpStatus::WouldBlock` with `*out_image` set to null when no frame is
+/// ready, leaving retry pacing to the caller.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture_ex`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_try_capture(
+    session: *mut SessionHandle,
+    target_w: u32,
+    target_h: u32,
+    out_image: *mut *mut RawImage,
+) -> i32 {
+    let result = catch(|| unsafe { lock_session(session) }?.try_capture(target_w, target_h));
+    unsafe { write_capture(result, out_image) }
+}
+
+/// Starts pushing frames from `session` to `callback`, `fps` times a second,
+/// on a thread the library owns; `fps` becomes the session's target rate
+/// (see `rdp_session_set_target_fps`). Each frame is captured and encoded
+/// with the session settings (as with a zero target size) and passed as
+/// `image` together with `user_data`; the library frees it once the
+/// callback returns, so do not call `free_image` on it and copy out
+/// anything needed later. Unchanged screens (see `rdp_session_set_detect_changes`) produce
+/// no call. Instead of waiting out the session timeout, the thread gives up
+/// on a frame that is not ready by the next tick.
+///
+/// Setters may be called from any thread, the callback included, while the
+/// stream runs and apply from the next frame. If capturing fails, the
+/// callback is called once more with a null `image` and the stream ends;
+/// the reason goes to the log callback, since `rdp_last_error_message` is
+/// per thread.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or an `fps` of 0,
+/// and `RdpStatus::Busy` if the session is already streaming.
+///
+/// # Safety
+/// `session` must be null or a live pointer from `rdp_session_new`.
+/// `user_data` must be usable from the stream thread.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_stream_start(
+    session: *mut SessionHandle,
+    fps: u32,
+    callback: FrameCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    status_of(catch(|| {
+        let sink = Sink::Callback {
+            callback,
+            user_data,
+        };
+        unsafe { handle_ref(session) }?.start_stream(fps, sink)
+    }))
+}
+
+/// Starts capturing from `session` `fps` times a second on a library-owned
+/// thread, like `rdp_stream_start`, but into a ring of the `ring_size`
+/// newest frames (0 = 3, at most 16) instead of a callback. Fetch them with
+/// `rdp_session_poll_latest`. Under the session's queue policy
+/// (`rdp_session_set_queue_policy`), frames that are never polled are
+/// dropped as newer ones arrive, so a stalled consumer costs at most
+/// `ring_size` frames of memory, or the stream waits for the next poll.
+/// Stop it with `rdp_stream_stop`.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, an `fps` of 0
+/// or a `ring_size` above 16, and `RdpStatus::Busy` if the session is
+/// already streaming.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_stream_start_buffered(
+    session: *mut SessionHandle,
+    fps: u32,
+    ring_size: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let capacity = match ring_size as usize {
+            0 => stream::DEFAULT_RING_SIZE,
+            n if n <= stream::MAX_RING_SIZE => n,
+            n => {
+                return Err(fail(
+                    RdpStatus::InvalidArgument,
+                    format!("Ring size {n} exceeds {}", stream::MAX_RING_SIZE),
+                ));
+            }
+        };
+        let handle = unsafe { handle_ref(session) }?;
+        let policy = handle.lock().config().queue_policy;
+        let ring = FrameRing::new(capacity, policy, handle.stats());
+        handle.start_stream(fps, Sink::Ring(Arc::new(ring)))
+    }))
+}
 
-    // Reclaim ownership of RawImage
-    let image_box: Box<RawImage> = unsafe { Box::from_raw(image_ptr) };
+/// The newest frame a buffered stream on `session` has captured since the
+/// previous poll, returned immediately; any older ones still in the ring
+/// are discarded. Release it with `free_image`.
+///
+/// Returns null when nothing was captured since the previous poll, or when
+/// no buffered stream is running; `rdp_session_poll_latest_ex` tells the
+/// two apart by status code.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_poll_latest(session: *mut SessionHandle) -> *mut RawImage {
+    into_raw_or_null(catch(|| unsafe { handle_ref(session) }?.poll_latest()))
+}
+
+/// `rdp_session_poll_latest`, writing the frame through `out_image` and
+/// returning the status: `RdpStatus::WouldBlock` when nothing was captured
+/// since the previous poll, `RdpStatus::InvalidArgument` when no buffered
+/// stream is running. `*out_image` is null on any failure.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture_ex`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_poll_latest_ex(
+    session: *mut SessionHandle,
+    out_image: *mut *mut RawImage,
+) -> i32 {
+    let result = catch(|| unsafe { handle_ref(session) }?.poll_latest());
+    unsafe { write_capture(result, out_image) }
+}
+
+/// Stops the stream running on `session`, if any. Once this returns the
+/// callback is no longer called: the stream thread has been joined. Called
+/// from the callback itself, it returns straight away instead and the
+/// thread finishes as soon as the callback does. `rdp_session_free` stops
+/// the stream too.
+///
+/// # Safety
+/// Same contract as `rdp_stream_start`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_stream_stop(session: *mut SessionHandle) {
+    let _ = catch(|| {
+        unsafe { handle_ref(session) }?.stop_stream();
+        Ok(())
+    });
+}
+
+/// Serves `session` over HTTP on `bind_addr` (a NUL-terminated IP address
+/// or host name; null means `127.0.0.1`, so only local viewers can connect)
+/// and `port`: `GET /stream` is an MJPEG stream
+/// (`multipart/x-mixed-replace`) that a browser can show in an `<img>`, and
+/// `GET /snapshot` a single JPEG of the newest frame. The frames come from
+/// a stream at `fps` (see `rdp_stream_start`), captured and encoded once
+/// however many clients are connected; by default a client that falls
+/// behind skips to the newest frame (see `rdp_session_set_queue_policy`).
+///
+/// The session must produce untiled, unencrypted JPEG and keep doing so;
+/// frames in other formats, or encrypted, are not served. `rdp_stream_stop` ends the stream and with it
+/// every connection, but keeps the port open until
+/// `rdp_http_stream_stop`.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, a non-UTF-8
+/// address, an `fps` of 0 or a session not producing unencrypted JPEG,
+/// `RdpStatus::Busy` if the session is already streaming or serving, and
+/// `RdpStatus::NetworkError` if the address cannot be bound (e.g. the port
+/// is in use).
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `bind_addr` must be null or
+/// point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_http_stream_start(
+    session: *mut SessionHandle,
+    bind_addr: *const c_char,
+    port: u16,
+    fps: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let handle = unsafe { handle_ref(session) }?;
+        handle.start_http(unsafe { bind_addr_from(bind_addr) }?, port, fps)
+    }))
+}
+
+/// Stops the HTTP server of `session` and the stream feeding it, closing
+/// every connection. Does nothing if no server is running.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_http_stream_stop(session: *mut SessionHandle) {
+    let _ = catch(|| {
+        unsafe { handle_ref(session) }?.stop_http();
+        Ok(())
+    });
+}
+
+/// Serves `session` to native viewers over plain TCP on `bind_addr` (a
+/// NUL-terminated IP address or host name; null means `127.0.0.1`) and
+/// `port`. Every client that connects receives a record per frame from a
+/// stream at `fps` (see `rdp_stream_start`), in the session's output
+/// format: a 16-byte little-endian header of `u32` payload length, `u32`
+/// sequence number and `u64` capture timestamp (`RawImage::sequence` and
+/// `timestamp_us`), then the payload; the `tcp` module documents the
+/// format in full. Frames are captured and encoded once however many
+/// clients connect. By default a client that cannot keep up has at most
+/// the newest frame pending, older ones are dropped, so it never stalls
+/// capture; `rdp_session_set_queue_policy` can make it wait for the client
+/// instead, and `rdp_session_set_drop_reports` tell it what it missed.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, a non-UTF-8
+/// address or an `fps` of 0, `RdpStatus::Busy` if the session is already
+/// streaming or serving, and `RdpStatus::NetworkError` if the address
+/// cannot be bound.
+///
+/// # Safety
+/// Same contract as `rdp_http_stream_start`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_tcp_serve(
+    session: *mut SessionHandle,
+    bind_addr: *const c_char,
+    port: u16,
+    fps: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let handle = unsafe { handle_ref(session) }?;
+        handle.start_tcp(unsafe { bind_addr_from(bind_addr) }?, port, fps)
+    }))
+}
+
+/// Stops the TCP server of `session` and the stream feeding it. Blocks
+/// until the listener and every client socket are closed and the server's
+/// threads have finished. Does nothing if no server is running.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_tcp_stop(session: *mut SessionHandle) {
+    let _ = catch(|| {
+        unsafe { handle_ref(session) }?.stop_tcp();
+        Ok(())
+    });
+}
+
+/// Serves `session` to browsers over WebSocket on `bind_addr` (a
+/// NUL-terminated IP address or host name; null means `127.0.0.1`) and
+/// `port`, from a stream at `fps` (see `rdp_stream_start`). Every client
+/// receives one binary message per frame: a 32-byte little-endian header of
+/// `u32` format, `u32` flags (bit 0 = keyframe), `u64` sequence number,
+/// `u64` capture timestamp, `u32` width and `u32` height, then the payload
+/// in the session's output format. Clients may send JSON text messages such
+/// as `{"width": 1280, "height": 720, "quality": 60, "keyframe": true}`,
+/// which are applied to the session (and so to every client) as they
+/// arrive. The `ws` module documents the protocol in full. Frames are
+/// captured and encoded once however many clients connect; by default a
+/// slow client skips to the newest frame (see
+/// `rdp_session_set_queue_policy` and `rdp_session_set_drop_reports`).
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, a non-UTF-8
+/// address or an `fps` of 0, `RdpStatus::Busy` if the session is already
+/// streaming or serving, `RdpStatus::NetworkError` if the address cannot
+/// be bound, and `RdpStatus::Unsupported` if the library was built without
+/// the `websocket` feature.
+///
+/// # Safety
+/// Same contract as `rdp_http_stream_start`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_ws_serve(
+    session: *mut SessionHandle,
+    bind_addr: *const c_char,
+    port: u16,
+    fps: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let handle = unsafe { handle_ref(session) }?;
+        handle.start_ws(unsafe { bind_addr_from(bind_addr) }?, port, fps)
+    }))
+}
+
+/// Stops the WebSocket server of `session` and the stream feeding it.
+/// Every client is sent a close frame and given a moment to answer; blocks
+/// until the server's threads have finished. Does nothing if no server is
+/// running.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_ws_stop(session: *mut SessionHandle) {
+    let _ = catch(|| {
+        unsafe { handle_ref(session) }?.stop_ws();
+        Ok(())
+    });
+}
+
+/// The session `rdp_local_serve` opened, serving while it is here.
+static LOCAL_SERVER: Mutex<Option<SessionHandle>> = Mutex::new(None);
+
+/// Frame rate of `rdp_local_serve` when `RdpConfig::fps` is 0.
+const LOCAL_DEFAULT_FPS: u32 = 30;
+
+/// Opens a session with every setting from `config` (see `RdpConfig`; null
+/// for the defaults) and serves it to viewers on this machine through the
+/// Unix domain socket at `path`, or on Windows the named pipe `path`
+/// (`\\.\pipe\<name>`). Clients receive exactly what `rdp_tcp_serve`
+/// sends, from a stream at `config->fps` (30 when 0), and several may
+/// connect, each with its own frame queue as `config->queue_policy` and
+/// `config->queue_capacity` say: by default a slow one skips frames and
+/// never stalls capture or the others. An access token
+/// (`rdp_server_set_auth_token`) is demanded as over TCP.
+///
+/// The socket is created with the permission bits
+/// `rdp_local_set_socket_mode` set (0600 by default, so only the same user
+/// can connect), under a temporary name that is then renamed to `path`.
+/// A stale socket file at `path`, left by a server that died, is replaced;
+/// `rdp_local_stop` removes the file. One local server runs at a time.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null or non-UTF-8 `path`, a
+/// `path` that exists and is not a socket or a pipe name without the
+/// `\\.\pipe\` prefix, `RdpStatus::Busy` if a local server is already
+/// running or another server answers on `path`, the statuses of
+/// `rdp_session_new_with_config` for a bad `config`, and
+/// `RdpStatus::FileAccessDenied`, `RdpStatus::FileNotFound` (no such
+/// directory), `RdpStatus::FileError` or, for a pipe,
+/// `RdpStatus::NetworkError` if it cannot be created.
+///
+/// # Safety
+/// `path` must be null or point to a NUL-terminated string; `config` must
+/// be null or valid for reads of `struct_size` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_local_serve(path: *const c_char, config: *const RdpConfig) -> i32 {
+    status_of(catch(|| {
+        if path.is_null() {
+            return Err(fail(RdpStatus::InvalidArgument, "Socket path is null"));
+        }
+        let path = unsafe { path_from(path) }?;
+        let config = if config.is_null() {
+            RdpConfig::default()
+        } else {
+            unsafe { config::read(config) }?
+        };
+
+        let mut server = LOCAL_SERVER.lock().unwrap_or_else(PoisonError::into_inner);
+        if server.is_some() {
+            return Err(fail(RdpStatus::Busy, "A local server is already running"));
+        }
+        let handle = SessionHandle::new(config.open()?);
+        let fps = if config.fps > 0 {
+            config.fps
+        } else {
+            LOCAL_DEFAULT_FPS
+        };
+        handle.start_local(path, fps)?;
+        *server = Some(handle);
+        Ok(())
+    }))
+}
+
+/// Stops the server `rdp_local_serve` started: closes every client
+/// connection, removes the socket file and frees the session, blocking
+/// until the server's threads have finished. Does nothing if none is
+/// running.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_local_stop() {
+    let _ = catch(|| {
+        let handle = LOCAL_SERVER
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        // Dropped outside the lock, which stops the server
+        drop(handle);
+        Ok(())
+    });
+}
+
+/// Sets the permission bits (e.g. `0600`, the default, or `0660` to let the
+/// user's group in) of the sockets `rdp_local_serve` creates from now on.
+/// On Windows, where pipes always refuse remote clients, a mode without
+/// group or other bits admits only the pipe's owner, and any other the
+/// default set of local users.
+///
+/// Returns `RdpStatus::InvalidArgument` for bits beyond `0777`.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_local_set_socket_mode(mode: u32) -> i32 {
+    status_of(catch(|| {
+        if mode > 0o777 {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Socket mode {mode:o} has bits beyond 0777"),
+            ));
+        }
+        local::set_mode(mode);
+        Ok(())
+    }))
+}
+
+/// Records `session` to an AVI file at `path` (a NUL-terminated path; an
+/// existing file is overwritten) with an MJPG stream that plays at `fps`,
+/// which VLC and ffmpeg open directly. Nothing is captured or encoded for
+/// the recording: every JPEG frame the session produces from now on,
+/// whether the host captures it or a stream or server does, is copied to a
+/// writer thread, so the session has to be captured from for anything to
+/// be recorded. Frames are placed by their capture timestamps, gaps are
+/// filled by repeating the last frame and frames beyond `fps` are left
+/// out, so the recording keeps wall-clock time. Frames are encrypted (see
+/// `rdp_session_set_encryption_key`) only after they are recorded.
+///
+/// A change of resolution does not end the recording: the file is
+/// finalized and recording rolls on to `<name>-2.avi`, `<name>-3.avi` and
+/// so on next to it, as it does when a file reaches 1 GiB.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or path, a
+/// non-UTF-8 path, an `fps` of 0 or a session not producing untiled JPEG,
+/// `RdpStatus::Busy` if the session is already recording and
+/// `RdpStatus::FileAccessDenied`, `RdpStatus::FileNotFound` (no such
+/// directory) or `RdpStatus::FileError` if the file cannot be created.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `path` must be null or point to
+/// a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_record_start(
+    session: *mut SessionHandle,
+    path: *const c_char,
+    fps: u32,
+) -> i32 {
+    status_of(catch(|| {
+        if path.is_null() {
+            return Err(fail(RdpStatus::InvalidArgument, "Recording path is null"));
+        }
+        let path = unsafe { path_from(path) }?;
+        unsafe { lock_session(session) }?.start_recording(path, fps)
+    }))
+}
+
+/// Ends the recording of `session`: writes out the frames still queued,
+/// finalizes the index and flushes the file to disk before returning. Does
+/// nothing if the session is not recording. Freeing the session does the
+/// same.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_record_stop(session: *mut SessionHandle) {
+    let _ = catch(|| {
+        let recorder = unsafe { lock_session(session) }?.take_recorder();
+        // Outside the lock, so capture goes on while the queue drains
+        if let Some(recorder) = recorder {
+            recorder.stop();
+        }
+        Ok(())
+    });
+}
+
+/// Makes `session` keep its last `seconds` of frames for
+/// `rdp_export_gif`: up to 10 a second, scaled down to at most 480 pixels
+/// wide, whatever the output format. `max_bytes` caps the memory they take
+/// (0 = 64 MiB); when either limit is reached the oldest frames go first.
+/// Only frames the session produces are kept, so something has to capture
+/// from it. `seconds` 0 turns the buffer off and frees it; changing the
+/// limits keeps the frames that still fit.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_replay_buffer(
+    session: *mut SessionHandle,
+    seconds: u32,
+    max_bytes: u64,
+) {
+    let _ = catch(|| {
+        let max_bytes = if max_bytes == 0 {
+            replay::DEFAULT_MAX_BYTES
+        } else {
+            max_bytes
+        };
+        unsafe { lock_session(session) }?.set_replay_buffer(seconds, max_bytes);
+        Ok(())
+    });
+}
+
+/// Writes the frames in the replay buffer of `session` (see
+/// `rdp_session_set_replay_buffer`) to `path`, a NUL-terminated path, as a
+/// looping animated GIF, each frame shown for as long as it was on screen.
+/// Frames are scaled down to at most `max_width` pixels wide (0 = as
+/// kept) and quantized to a palette of their own. A GIF has a single size,
+/// so after a change of resolution only the frames since are exported.
+/// Capturing goes on meanwhile; encoding takes a while, since the buffer
+/// is copied out first and encoded without holding up the session.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or path, a
+/// non-UTF-8 path, a session without a replay buffer or an empty one,
+/// `RdpStatus::EncodeFailed` if encoding fails and
+/// `RdpStatus::FileAccessDenied`, `RdpStatus::FileNotFound` or
+/// `RdpStatus::FileError` if the file cannot be written.
+///
+/// # Safety
+/// Same contract as `rdp_record_start`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_export_gif(
+    session: *mut SessionHandle,
+    path: *const c_char,
+    max_width: u32,
+) -> i32 {
+    status_of(catch(|| {
+        if path.is_null() {
+            return Err(fail(RdpStatus::InvalidArgument, "GIF path is null"));
+        }
+        let path = unsafe { path_from(path) }?;
+        let stills = unsafe { lock_session(session) }?.replay_stills()?;
+        replay::export_gif(&stills, path, max_width)
+    }))
+}
+
+/// Makes `session` publish every frame it produces from now on to a named
+/// shared memory region as well, for a viewer on the same machine to read
+/// without a copy through a socket. The region is a ring of `slot_count`
+/// frame slots (1 to 64), each sized for the display's pixels at 4 bytes
+/// apiece; `rdp_core/src/shm.rs` documents its layout and the protocol for
+/// reading it, and `ShmReader` implements that for Rust. Frames too large
+/// for a slot are left out and counted in the region's header.
+///
+/// `name` is a POSIX shared memory name (`/rdp-frames`; the slash is added
+/// if missing, and macOS allows 30 characters after it) or a Windows
+/// section name (`Local\rdp-frames`). On Unix a region of that name left
+/// by a session that died is replaced. A null `name` stops publishing.
+/// Either way the session's previous region is closed and, where the
+/// system keeps names around, unlinked, as it is when the session is
+/// freed.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, a name that is
+/// not UTF-8 or not valid on the platform or a `slot_count` out of range,
+/// `RdpStatus::Busy` on Windows when a section of that name is still open
+/// elsewhere, `RdpStatus::MemoryLimit` if the region does not fit the
+/// session's memory limit (see `rdp_session_set_memory_limit`), and
+/// `RdpStatus::FileAccessDenied` or `RdpStatus::FileError` if the region
+/// cannot be created.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `name` must be null or point to
+/// a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_enable_shm(
+    session: *mut SessionHandle,
+    name: *const c_char,
+    slot_count: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let name = if name.is_null() {
+            None
+        } else {
+            Some(unsafe { CStr::from_ptr(name) }.to_str().map_err(|e| {
+                fail(
+                    RdpStatus::InvalidArgument,
+                    format!("Shared memory name is not UTF-8: {e}"),
+                )
+            })?)
+        };
+        unsafe { lock_session(session) }?.set_shm(name, slot_count)
+    }))
+}
+
+/// Makes every HTTP, TCP or WebSocket server started from now on serve
+/// TLS only, with the PEM certificate (chain) at `cert_pem_path` and the
+/// PEM private key at `key_pem_path` (NUL-terminated paths). Self-signed
+/// certificates work; clients have to trust them on their side. Servers
+/// already running are not affected. Passing two nulls goes back to
+/// plaintext. Plaintext clients connecting to a TLS server are rejected
+/// right away (an HTTP client with a 400) instead of being left waiting.
+///
+/// Returns `RdpStatus::InvalidArgument` when only one path is null or a
+/// path is not UTF-8, `RdpStatus::TlsCertificate` or `RdpStatus::TlsKey`
+/// when the file cannot be read or does not hold a PEM certificate or key,
+/// `RdpStatus::TlsKeyMismatch` when the key does not belong to the
+/// certificate, and `RdpStatus::Unsupported` if the library was built
+/// without the `tls` feature. On failure the previous setting stays.
+///
+/// # Safety
+/// Each path must be null or point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_server_set_tls(
+    cert_pem_path: *const c_char,
+    key_pem_path: *const c_char,
+) -> i32 {
+    status_of(catch(|| {
+        let paths = match (cert_pem_path.is_null(), key_pem_path.is_null()) {
+            (true, true) => None,
+            (false, false) => Some((unsafe { path_from(cert_pem_path) }?, unsafe {
+                path_from(key_pem_path)
+            }?)),
+            _ => {
+                return Err(fail(
+                    RdpStatus::InvalidArgument,
+                    "TLS needs both a certificate and a key path, or neither",
+                ));
+            }
+        };
+        server::configure_tls(paths)
+    }))
+}
+
+/// Makes the HTTP, TCP and WebSocket servers (running or not) demand
+/// `token`, a NUL-terminated UTF-8 string, from every client connecting
+/// from now on; clients already connected stay. HTTP and WebSocket clients
+/// present it as a `?token=` query parameter or an `Authorization: Bearer`
+/// header, TCP clients as their first message: a `u32` little-endian byte
+/// count, then the token. Clients with a missing or wrong token get a 401
+/// (HTTP, WebSocket) or are disconnected (TCP), and are counted in
+/// `RdpStats::auth_failures` of the session being served. Tokens are
+/// compared in constant time. Null turns the check off again.
+///
+/// Returns `RdpStatus::InvalidArgument` for an empty or non-UTF-8 token.
+///
+/// # Safety
+/// `token` must be null or point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_server_set_auth_token(token: *const c_char) -> i32 {
+    status_of(catch(|| {
+        if token.is_null() {
+            auth::set_token(None);
+            return Ok(());
+        }
+        let token = unsafe { CStr::from_ptr(token) }.to_str().map_err(|e| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Access token is not UTF-8: {e}"),
+            )
+        })?;
+        if token.is_empty() {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Access token is empty (pass null to turn the check off)",
+            ));
+        }
+        auth::set_token(Some(token));
+        Ok(())
+    }))
+}
+
+/// Encrypts the payload of every frame `session` returns from now on with
+/// AES-256-GCM under the `key_len`-byte `key`, which must be 32 bytes:
+/// `RawImage::data` then holds a 12-byte nonce, the ciphertext and a
+/// 16-byte tag, and `RawImage::encrypted` is set. The metadata stays in the
+/// clear. Nonces never repeat within a session, even across key changes.
+/// The next frame is a keyframe. A null `key` (or a `key_len` of 0) turns
+/// encryption off again. `rdp_decrypt_frame` recovers the payload.
+///
+/// The HTTP server cannot serve an encrypted session; the TCP and WebSocket
+/// servers pass the sealed frames on as they are.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or a key that is
+/// not 32 bytes, and `RdpStatus::Unsupported` if the library was built
+/// without the `encryption` feature.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `key` must be null or point to
+/// `key_len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_encryption_key(
+    session: *mut SessionHandle,
+    key: *const u8,
+    key_len: usize,
+) -> i32 {
+    status_of(catch(|| {
+        let key = if key.is_null() || key_len == 0 {
+            None
+        } else {
+            Some(unsafe { std::slice::from_raw_parts(key, key_len) })
+        };
+        unsafe { lock_session(session) }?.set_encryption_key(key)
+    }))
+}
+
+/// Decrypts the payload of a frame from a session encrypting under `key`
+/// (32 bytes): `data` and `len` are the frame's `RawImage::data` and
+/// `RawImage::len`. Only `data` and `len` of the result are meaningful;
+/// the encrypted frame's other fields describe it. Release it with
+/// `free_image`.
+///
+/// Returns null on failure, with `RdpStatus::InvalidArgument` when `key` or
+/// `data` is null, or the data is truncated, was sealed under another key
+/// or has been tampered with, and `RdpStatus::Unsupported` if the library
+/// was built without the `encryption` feature; `rdp_last_error_message`
+/// has the reason.
+///
+/// # Safety
+/// `key` must be null or point to 32 readable bytes, `data` null or to
+/// `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_decrypt_frame(
+    key: *const u8,
+    data: *const u8,
+    len: usize,
+) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        if key.is_null() || data.is_null() {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Decrypting a frame needs a key and data",
+            ));
+        }
+        let key = unsafe { &*key.cast::<[u8; cipher::KEY_LEN]>() };
+        let payload = cipher::open(key, unsafe { std::slice::from_raw_parts(data, len) })?;
+        Ok(EncodedFrame {
+            content_hash: pixels::frame_hash(&payload, &[]),
+            data: payload,
+            width: 0,
+            height: 0,
+            format: FrameFormat::Raw,
+            pixel_format: PixelFormat::Gray,
+            stride: 0,
+            dirty: Rect {
+                x: 0,
+                y: 0,
+                w: 0,
+                h: 0,
+            },
+            cursor: None,
+            hotspot: (0, 0),
+            sequence: 0,
+            timestamp_us: 0,
+            quality: 0,
+            keyframe: true,
+            uncompressed_len: 0,
+            encrypted: false,
+            progressive: false,
+        })
+    }))
+}
+
+/// Switches `session` to tiled delta output: frames are cut into
+/// `tile_size`-pixel squares and only tiles that changed are sent, each
+/// encoded in the session format, inside the container documented in the
+/// `tiles` module. Every `keyframe_interval` frames (0 = only on demand) the
+/// whole image is sent instead. `RawImage::format` is 4 for keyframes and 5
+/// for deltas. `tile_size` 0 turns tiling off.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or a non-zero
+/// `tile_size` below 16.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_tiling(
+    session: *mut SessionHandle,
+    tile_size: u32,
+    keyframe_interval: u32,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_tiling(tile_size, keyframe_interval)
+    }))
+}
+
+/// Has `session` encode the `width x height` rectangle (in output pixels)
+/// around the mouse cursor again at `quality` (0 = 90) and `subsampling`
+/// (as in `rdp_session_set_subsampling`) on top of every JPEG frame, for
+/// viewers that care most about where the presenter is working. The rest
+/// of the frame keeps the session's quality and subsampling. The rectangle
+/// only follows the cursor once it leaves its middle half, so it does not
+/// jitter. `width` and `height` 0 turn focus off (the default).
+///
+/// Frames then arrive as tile containers (`RawImage::format` 4, or 5 for
+/// tiled deltas; see `rdp_session_set_tiling`) whose last tile is the
+/// rectangle; drawing tiles in order layers it over the rest. Untiled
+/// sessions send the whole frame and the rectangle every time; tiled
+/// sessions only resend the rectangle on keyframes, when it moves and when
+/// a changed tile covers it.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, a zero width or
+/// height (but not both), a quality above 100 or an unknown subsampling.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_focus(
+    session: *mut SessionHandle,
+    width: u32,
+    height: u32,
+    quality: u32,
+    subsampling: i32,
+) -> i32 {
+    status_of(catch(|| {
+        let mut s = unsafe { lock_session(session) }?;
+        if (width, height) == (0, 0) {
+            return s.set_focus(None);
+        }
+        let subsampling = Subsampling::from_i32(subsampling).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown subsampling value {subsampling}"),
+            )
+        })?;
+        let quality = match quality {
+            0 => focus::DEFAULT_QUALITY,
+            quality => u8::try_from(quality).map_err(|_| {
+                fail(
+                    RdpStatus::InvalidArgument,
+                    format!("Focus quality {quality} is outside 1..=100"),
+                )
+            })?,
+        };
+        s.set_focus(Some(Focus {
+            width,
+            height,
+            quality,
+            subsampling,
+        }))
+    }))
+}
+
+/// Splits JPEG, PNG and WebP frames from `session` into `bands` full-width
+/// horizontal bands that are encoded in parallel, for large frames that
+/// take one core too long. Each frame then arrives as a tile container
+/// (`RawImage::format` 4, see `rdp_session_set_tiling`) whose tiles are
+/// the bands, top to bottom; band heights are multiples of 16 but the last.
+/// Tiled sessions split their keyframes this way and encode changed tiles
+/// in parallel. 0 or 1 (the default) encodes frames whole. See
+/// `rdp_set_encode_threads` for the threads.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or more than 64
+/// bands.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_encode_bands(
+    session: *mut SessionHandle,
+    bands: u32,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_encode_bands(bands)
+    }))
+}
+
+/// Sets how many threads encode bands and tiles in parallel (see
+/// `rdp_session_set_encode_bands`), shared by all sessions. 0 restores the
+/// default: one per physical core but one, so capture keeps a core.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_set_encode_threads(threads: u32) -> i32 {
+    status_of(catch(|| parallel::set_threads(threads as usize)))
+}
+
+/// The number of threads encoding bands and tiles in parallel.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_encode_threads() -> u32 {
+    guard(0, || parallel::threads() as u32)
+}
+
+/// Makes the next tiled or video frame from `session` a keyframe, e.g.
+/// when a new viewer joins.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_request_keyframe(session: *mut SessionHandle) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.request_keyframe();
+        Ok(())
+    });
+}
+
+/// Turns dirty-rectangle tracking for `session` on or off (the default).
+/// While on, each frame is still encoded in full, but `RawImage::dirty_*`
+/// bound the pixels that changed since the previous frame (mapped through
+/// any resize, rounded outwards), so a client can repaint just that area.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_track_dirty(session: *mut SessionHandle, enabled: bool) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_track_dirty(enabled);
+        Ok(())
+    });
+}
+
+/// Turns change detection for `session` on or off (the default). While on,
+/// a capture whose pixels and cursor position match the previous one returns
+/// `RdpStatus::NoChange` without allocating a `RawImage`. The first capture
+/// after opening the session, or after changing any setting, always
+/// produces a frame.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_detect_changes(
+    session: *mut SessionHandle,
+    enabled: bool,
+) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_detect_changes(enabled);
+        Ok(())
+    });
+}
+
+/// Turns drawing the mouse cursor into `session`'s frames on or off (the
+/// default). The cursor is blended in before resizing, so it follows the
+/// capture region and output size; where the position cannot be queried the
+/// frames simply come without it.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_include_cursor(
+    session: *mut SessionHandle,
+    enabled: bool,
+) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_include_cursor(enabled);
+        Ok(())
+    });
+}
+
+/// Turns burning each frame's capture timestamp (milliseconds since the
+/// session opened, as in `timestamp_us`) and sequence number into the top
+/// left corner of `session`'s frames on or off (the default), for comparing
+/// capture and display time on the viewer when chasing latency. The digits
+/// are drawn at output resolution just before encoding and can be toggled
+/// at any time.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_overlay_timestamp(
+    session: *mut SessionHandle,
+    enabled: bool,
+) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_overlay_timestamp(enabled);
+        Ok(())
+    });
+}
+
+/// Moves the pointer to (`x`, `y`) in the pixels `session` captures, before
+/// any resize: relative to its capture region if one is set, otherwise to
+/// its display. Clients showing a resized frame scale their coordinates back
+/// up first. With `rdp_session_set_capture_logical_size` on, the display's
+/// scale is already accounted for: pass logical points.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_inject_mouse_move(
+    session: *mut SessionHandle,
+    x: i32,
+    y: i32,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.inject_mouse_move(x, y)
+    }))
+}
+
+/// Sets the JPEG and WebP quality used by `session` (clamped to 1–100).
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_quality(session: *mut SessionHandle, quality: u8) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_quality(quality);
+        Ok(())
+    });
+}
+
+/// Makes `session` adapt the JPEG quality from frame to frame so encoded
+/// frames stay near `bytes` each (0 = off, the default, back to the fixed
+/// quality). A proportional controller raises the quality while frames come
+/// in under budget and lowers it when they overshoot, within the range set
+/// by `rdp_session_set_quality_range`; each frame's choice is reported in
+/// `RawImage::quality`. Only JPEG output (tiled or not) is adapted.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_target_frame_bytes(
+    session: *mut SessionHandle,
+    bytes: u32,
+) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_target_frame_bytes(bytes);
+        Ok(())
+    });
+}
+
+/// Makes `session` aim for an average of `kbps` kilobits a second (0 = off,
+/// the default), e.g. following the throughput a server measures. A leaky
+/// bucket hands each frame its byte budget: credit from quiet periods
+/// (small or unchanged frames) is saved up, about a second's worth, so the
+/// occasional complex frame can borrow up to four times the average
+/// frame's share; an overrun is paid back by the frames after it. The JPEG
+/// quality then follows each budget as with
+/// `rdp_session_set_target_frame_bytes`, which the bitrate overrides.
+///
+/// The average frame's share comes from the target frame rate (see
+/// `rdp_session_set_target_fps`), or from the measured rate without one. A
+/// new bitrate restarts the bucket, and a keyframe requested with
+/// `rdp_session_request_keyframe` may overspend without leaving debt.
+/// Can be changed at any time, also while streaming.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_bitrate(session: *mut SessionHandle, kbps: u32) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_bitrate(kbps);
+        Ok(())
+    });
+}
+
+/// Bounds the quality the byte budget may pick (default 10–90).
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or unless
+/// `1 <= min <= max <= 100`.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_quality_range(
+    session: *mut SessionHandle,
+    min: u8,
+    max: u8,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_quality_range(min, max)
+    }))
+}
+
+/// Lets frames that exceed the byte budget even at the minimum quality
+/// shrink by `step` of each side (e.g. 0.1 for 10%), repeatedly down to a
+/// quarter of the size, instead of blowing the budget. The resolution
+/// grows back step by step once frames at the maximum quality use less than
+/// half the budget. 0 (the default) never changes the resolution.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or a `step`
+/// outside 0–0.5.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_budget_downscale(
+    session: *mut SessionHandle,
+    step: f32,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_budget_downscale(step)
+    }))
+}
+
+/// Paces `session` to `fps` frames a second (0 = unpaced, the default).
+/// Blocking captures first sleep until one frame interval after the previous
+/// one started, so a caller's loop runs at a steady rate however long
+/// encoding takes; a frame that overruns its interval starts the next one
+/// immediately, without trying to catch up later. `rdp_session_try_capture`
+/// is never paced. While a stream runs, this changes its rate.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_target_fps(session: *mut SessionHandle, fps: u32) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_target_fps(fps);
+        Ok(())
+    });
+}
+
+/// Frames per second `session` has actually produced recently (a running
+/// average over captures and stream frames alike, unchanged frames not
+/// counted), or 0 before its second frame or for a null session. Restarts
+/// whenever the target rate is changed. Also reported in
+/// `RdpStats::actual_fps`.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_get_actual_fps(session: *mut SessionHandle) -> f64 {
+    guard(0.0, || {
+        unsafe { handle_ref(session) }.map_or(0.0, |handle| handle.stats().fps())
+    })
+}
+
+/// Fills `out` with `session`'s pipeline timings and frame counters (see
+/// `RdpStats`). Cheap enough to call every frame, and never waits for a
+/// capture in progress, so it can be polled from another thread while a
+/// stream runs.
+///
+/// Returns `RdpStatus::InvalidArgument` if `session` or `out` is null.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `out` must be null or point to
+/// writable memory for one `RdpStats`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_get_stats(
+    session: *mut SessionHandle,
+    out: *mut RdpStats,
+) -> i32 {
+    status_of(catch(|| {
+        if out.is_null() {
+            return Err(fail(RdpStatus::InvalidArgument, "out must not be null"));
+        }
+        let stats = unsafe { handle_ref(session) }?.stats().snapshot();
+        unsafe { out.write(stats) };
+        Ok(())
+    }))
+}
+
+/// Fills `out` with the counters of up to `max` of `session`'s frame
+/// consumers (see `RdpConsumerStats`): one per buffered stream and per
+/// connected network client, oldest first. Like `rdp_session_get_stats` it
+/// never waits for a capture in progress. A consumer's entry goes when it
+/// does, so a client that disconnected is no longer listed.
+///
+/// Returns the number of consumers, which may exceed `max` (`out` may then
+/// be null with `max` 0 to just count them), or `RdpStatus::InvalidArgument`
+/// for a null session, or a null `out` with a non-zero `max`.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `out` must be null or point to
+/// writable memory for `max` `RdpConsumerStats`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_get_consumer_stats(
+    session: *mut SessionHandle,
+    out: *mut RdpConsumerStats,
+    max: u32,
+) -> i32 {
+    let result = catch(|| {
+        if out.is_null() && max > 0 {
+            return Err(fail(RdpStatus::InvalidArgument, "out must not be null"));
+        }
+        let consumers = unsafe { handle_ref(session) }?.stats().consumers();
+        for (i, consumer) in consumers.iter().take(max as usize).enumerate() {
+            unsafe { out.add(i).write(*consumer) };
+        }
+        Ok(consumers.len())
+    });
+
+    match result {
+        Ok(count) => count as i32,
+        Err(status) => status as i32,
+    }
+}
+
+/// Sets what the frame queue of each consumer of `session` started from
+/// now on does when the consumer falls behind; running consumers keep
+/// theirs. Consumers are network clients of the HTTP, TCP, WebSocket and
+/// local servers, each with its own queue, and a buffered stream
+/// (`rdp_stream_start_buffered`, whose ring size is its own). A callback
+/// stream has no queue: the stream always waits for the callback.
+///
+/// - 0, latest wins (default): a frame arriving at a full queue pushes out
+///   the oldest one, counted as dropped for that consumer and in
+///   `RdpStats::frames_dropped`. Capture never waits for a consumer.
+/// - 1, block: a frame arriving at a full queue waits for room, so capture
+///   (and every other consumer of the stream) runs at the slowest
+///   consumer's pace and nothing is dropped.
+///
+/// `capacity` is how many frames a network client's queue holds: 0 for
+/// the default of 1 (lowest latency), at most 16.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, an unknown
+/// policy or a capacity above 16.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_queue_policy(
+    session: *mut SessionHandle,
+    policy: u32,
+    capacity: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let policy = QueuePolicy::from_u32(policy).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown queue policy {policy}"),
+            )
+        })?;
+        if capacity as usize > queue::MAX_CAPACITY {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Queue capacity {capacity} exceeds {}", queue::MAX_CAPACITY),
+            ));
+        }
+        unsafe { lock_session(session) }?.set_queue(policy, capacity);
+        Ok(())
+    }))
+}
+
+/// Makes network clients of `session` that connect from now on get told
+/// how many frames they missed (non-zero `enabled`), off by default: TCP
+/// and local socket clients as a control record, WebSocket clients as a
+/// `{"dropped": N}` text message, before the frame that follows the gap.
+/// MJPEG over HTTP has no room for one. Clients that do not expect them
+/// should be left with reports off.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_drop_reports(session: *mut SessionHandle, enabled: i32) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_report_drops(enabled != 0);
+        Ok(())
+    });
+}
+
+/// Raises the priority of stream threads started on `session` from now on
+/// (by `rdp_stream_start` and its variants, and for the servers), so a
+/// loaded machine preempts capture and encoding less: 0 = normal (the
+/// default, which leaves scheduling alone), 1 = above normal, 2 = time
+/// critical. On Windows these are `THREAD_PRIORITY_ABOVE_NORMAL` and
+/// `THREAD_PRIORITY_TIME_CRITICAL`; on Linux nice -5 and `SCHED_RR` (nice
+/// -10 where that is not permitted); on macOS the user-initiated and
+/// user-interactive QoS classes.
+///
+/// Raising a priority often needs privileges (on Linux `CAP_SYS_NICE` or
+/// the matching `RLIMIT_NICE` / `RLIMIT_RTPRIO`). A thread that cannot get
+/// it logs a warning through the log callback and runs at the highest
+/// level it could get; `RdpStats::thread_priority` shows which.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or an unknown
+/// priority.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_thread_priority(
+    session: *mut SessionHandle,
+    priority: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let priority = ThreadPriority::from_u32(priority).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown thread priority {priority}"),
+            )
+        })?;
+        let mut session = unsafe { lock_session(session) }?;
+        let affinity = session.config().thread_affinity;
+        session.set_thread_scheduling(priority, affinity);
+        Ok(())
+    }))
+}
+
+/// Pins stream threads started on `session` from now on to the cores set
+/// in `mask` (bit `n` for core `n`, so cores 0 to 63); 0, the default,
+/// leaves them unpinned. macOS cannot pin threads. A thread that cannot be
+/// pinned, there or because a core does not exist or is not available to
+/// the process, logs a warning through the log callback and runs unpinned;
+/// `RdpStats::thread_affinity` shows the mask it got.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_thread_affinity(
+    session: *mut SessionHandle,
+    mask: u64,
+) -> i32 {
+    status_of(catch(|| {
+        let mut session = unsafe { lock_session(session) }?;
+        let priority = session.config().thread_priority;
+        session.set_thread_scheduling(priority, mask);
+        Ok(())
+    }))
+}
+
+/// Caps the memory `session`'s buffers hold at `bytes`; 0, the default,
+/// lifts the cap. Counted against it are the capture pipeline's working
+/// buffers, the frames waiting in its streams' and network clients' queues,
+/// the replay buffer and a shared memory region; memory the encoders keep
+/// internally is not. When the cap is reached, queues drop their oldest
+/// frames (counted in `RdpStats::frames_dropped`) and the replay buffer
+/// its oldest stills to make room for new ones, and a capture whose
+/// buffers still do not fit fails with `RdpStatus::MemoryLimit` (a stream
+/// skips the frame and tries again). `rdp_session_enable_shm` fails the
+/// same way for a region that does not fit. `RdpStats::memory_used` and
+/// `memory_peak` show what is held, with or without a cap.
+///
+/// Lowering the cap below what is held drops replay stills at once; the
+/// rest gives way as it next needs room.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_memory_limit(
+    session: *mut SessionHandle,
+    bytes: u64,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_memory_limit(bytes);
+        Ok(())
+    }))
+}
+
+/// Zeroes `session`'s counters, averages and maxima, e.g. after warm-up or
+/// between benchmark runs. The measured frame rate is left alone.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_reset_stats(session: *mut SessionHandle) {
+    let _ = catch(|| {
+        unsafe { handle_ref(session) }?.stats().reset();
+        Ok(())
+    });
+}
+
+/// Sets how long captures on `session` wait for a frame: 0 tries once and
+/// returns `RdpStatus::WouldBlock`, `u32::MAX` (the default) waits forever,
+/// anything else fails with `RdpStatus::Timeout` after that many
+/// milliseconds.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_timeout(session: *mut SessionHandle, timeout_ms: u32) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_timeout(timeout_ms);
+        Ok(())
+    });
+}
+
+/// Sets how long `session` keeps trying to reopen a display it lost
+/// before failing for good: `u32::MAX` never gives up, the default is 10
+/// minutes. See `rdp_session_capture`.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_recovery_timeout(
+    session: *mut SessionHandle,
+    timeout_ms: u32,
+) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_recovery_timeout(timeout_ms);
+        Ok(())
+    });
+}
+
+/// Sets how `session` waits in `rdp_session_capture` between polls that
+/// find no new frame: 0 = sleep 1 ms, doubling up to 16 ms (default), 1 =
+/// sleep `sleep_ms`, 2 = yield the thread, 3 = poll back to back for
+/// `spin_us` microseconds, then sleep `sleep_ms`, 4 = block in the capture
+/// backend until the OS signals a new frame, for up to `sleep_ms` a poll
+/// (0 = the whole capture timeout). Only DXGI on Windows has such a
+/// signal; elsewhere 4 sleeps `sleep_ms` between polls (backs off as 0
+/// does when `sleep_ms` is 0). Faster polling picks up frames sooner for
+/// more CPU time; `RdpStats::capture_wait_us_avg` shows the effect.
+/// `spin_us` and `sleep_ms` are ignored where unused. Streams poll on
+/// their own schedule.
+///
+/// Returns `RdpStatus::InvalidArgument` for an unknown strategy.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_wait_strategy(
+    session: *mut SessionHandle,
+    strategy: u32,
+    spin_us: u32,
+    sleep_ms: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let strategy = WaitStrategy::from_ffi(strategy, spin_us, sleep_ms).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown wait strategy {strategy}"),
+            )
+        })?;
+        unsafe { lock_session(session) }?.set_wait_strategy(strategy);
+        Ok(())
+    }))
+}
+
+/// Selects the encoding produced by `session`: 0 = JPEG (default), 1 = PNG,
+/// 2 = WebP, 3 = raw pixels (see `rdp_session_set_pixel_format`), 7 = H.264,
+/// 8 = VP8, 9 = VP9, 10 = raw pixels compressed with zstd (see
+/// `rdp_session_set_zstd`), 11 = planar YUV 4:2:0 (I420), 12 = semi-planar
+/// YUV 4:2:0 (NV12); see `rdp_session_set_yuv_matrix` for the last two.
+///
+/// I420 frames hold the Y plane followed by the U and V planes at half
+/// the width and height; NV12 frames the same Y plane followed by one
+/// half-height plane of interleaved U and V samples.
+/// `RawImage::plane_offsets` and `plane_strides` describe the planes. Like
+/// the video formats both are converted at even dimensions, and tiling
+/// does not apply to them.
+///
+/// The video formats are encoded at even dimensions (an odd last row or
+/// column is dropped), and `RawImage::keyframe` marks the frames a decoder
+/// can start from. H.264 frames are Annex-B NAL units whose IDR frames
+/// carry the SPS and PPS; VP8/VP9 frames are bare compressed frames, as
+/// documented in the `vpx` module. The encoder runs at the bitrate from
+/// `rdp_session_set_bitrate` (2000 kbps when unset) and the target frame
+/// rate (30 fps when unset), sends a keyframe every `keyframe_interval`
+/// frames of `rdp_session_set_tiling` (tiling itself does not apply) and
+/// may drop frames to hold the bitrate, which then fail with
+/// `RdpStatus::NoChange`.
+///
+/// Returns `RdpStatus::Ok`, `RdpStatus::UnsupportedFormat` for a format
+/// whose feature (`webp`, `h264`, `vpx` or `zstd`) is not in this build, or
+/// `RdpStatus::InvalidArgument` for a null session or an unknown format.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_format(session: *mut SessionHandle, format: u32) -> i32 {
+    status_of(catch(|| {
+        let mut s = unsafe { lock_session(session) }?;
+        s.set_format(frame_format(format)?);
+        Ok(())
+    }))
+}
+
+/// Selects the RGB to YUV matrix of I420 and NV12 frames from `session`:
+/// 0 = BT.601 (default), 1 = BT.709, both limited range. The video formats
+/// always use BT.601.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or an unknown
+/// matrix.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_yuv_matrix(
+    session: *mut SessionHandle,
+    matrix: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let matrix = YuvMatrix::from_u32(matrix).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown YUV matrix {matrix}"),
+            )
+        })?;
+        unsafe { lock_session(session) }?.set_yuv_matrix(matrix);
+        Ok(())
+    }))
+}
+
+/// Sets the channel layout of raw frames from `session`, which is also the
+/// layout handed to the JPEG encoder: 0 = BGRA (default, no conversion),
+/// 1 = RGB, 2 = BGR, 3 = RGBA, 4 = gray (same as enabling grayscale). PNG
+/// and WebP always encode from RGB.
+///
+/// Returns `RdpStatus::Ok`, or `RdpStatus::InvalidArgument` for a null
+/// session or an unknown pixel format.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_pixel_format(
+    session: *mut SessionHandle,
+    pixel_format: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let mut s = unsafe { lock_session(session) }?;
+        s.set_pixel_format(pixel_format_from(pixel_format)?);
+        Ok(())
+    }))
+}
+
+/// Switches `session` to single-channel BT.601 luma output (`enabled`) or
+/// back to colour. Grayscale JPEGs are encoded without chroma, PNGs as
+/// 8-bit gray, WebPs (which have no gray mode) with neutral chroma, and
+/// raw frames come back with `PixelFormat::Gray` and a stride equal to
+/// their width.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_grayscale(session: *mut SessionHandle, enabled: bool) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_grayscale(enabled);
+        Ok(())
+    });
+}
+
+/// Makes captures on `session` that pass a zero target size come out at
+/// `scale` times the display (or region) size, rounded to even dimensions.
+/// An explicit target size always wins. Pass 1.0 to go back to the native
+/// size.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, a scale <= 0,
+/// or a scale above 1 without `allow_upscale`.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_scale(
+    session: *mut SessionHandle,
+    scale: f32,
+    allow_upscale: bool,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_scale(scale, allow_upscale)
+    }))
+}
+
+/// Limits captures on `session` that pass a zero target size to at most
+/// `max_dim` pixels on the long edge, scaling proportionally (0 = no limit,
+/// the default). Frames already small enough are not resized. An explicit
+/// target size passed to the capture call takes precedence; a scale set with
+/// `rdp_session_set_scale` is applied first and then capped. The produced
+/// size is reported in `RawImage::width`/`height`.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_max_dim(session: *mut SessionHandle, max_dim: u32) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_max_dim(max_dim);
+        Ok(())
+    });
+}
+
+/// Makes captures on `session` that pass a zero target size come out at
+/// `width x height`, as though that size were passed to every capture call
+/// (streams included); `RawImage::width`/`height` report the size produced.
+/// Takes precedence over `rdp_session_set_scale` and
+/// `rdp_session_set_max_dim`; 0 x 0 goes back to them.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session or when exactly
+/// one of `width` and `height` is 0.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_output_size(
+    session: *mut SessionHandle,
+    width: u32,
+    height: u32,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_output_size(width, height)
+    }))
+}
+
+/// With `enabled`, `session` never upscales: a requested size larger than
+/// the captured frame is shrunk (keeping its aspect ratio) to fit it, down
+/// to the native resolution. `RawImage::width`/`height` report the size
+/// actually produced.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_downscale_only(
+    session: *mut SessionHandle,
+    enabled: bool,
+) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_downscale_only(enabled);
+        Ok(())
+    });
+}
+
+/// Sets the filter `session` resizes with: 0 = nearest (default, fastest),
+/// 1 = bilinear, 2 = Catmull-Rom bicubic, 3 = Lanczos3 (sharpest, slowest).
+/// Anything but nearest avoids the shimmer of thin text when downscaling.
+///
+/// Returns `RdpStatus::Ok`, or `RdpStatus::InvalidArgument` for a null
+/// session or an unknown algorithm.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_resize_alg(session: *mut SessionHandle, alg: u32) -> i32 {
+    status_of(catch(|| {
+        let mut s = unsafe { lock_session(session) }?;
+        let resize_alg = scale::resize_alg_from_u32(alg).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown resize algorithm {alg}"),
+            )
+        })?;
+        s.set_resize_alg(resize_alg);
+        Ok(())
+    }))
+}
+
+/// Sets how `session` maps frames onto an explicit target size:
+/// 0 = stretch (default), 1 = fit inside and pad with the fill colour,
+/// 2 = fill the target and crop the overflow.
+///
+/// Returns `RdpStatus::Ok`, or `RdpStatus::InvalidArgument` for a null
+/// session or an unknown mode.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_fit_mode(session: *mut SessionHandle, mode: u32) -> i32 {
+    status_of(catch(|| {
+        let mut s = unsafe { lock_session(session) }?;
+        let mode = FitMode::from_u32(mode).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown fit mode {mode}"),
+            )
+        })?;
+        s.set_fit_mode(mode);
+        Ok(())
+    }))
+}
+
+/// Turns shrinking `session`'s frames from the display's physical pixels to
+/// logical points (`DisplayInfo::scale`, e.g. 2560x1600 down to 1280x800 on
+/// a Retina panel) on or off (the default). The downsample is part of the
+/// normal resize, after which `scale` and `max_dim` still apply; explicit
+/// target sizes and the output size take precedence. While on,
+/// `rdp_session_inject_mouse_move` takes logical points too, so viewer
+/// coordinates map straight back. Each display uses its own factor.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_capture_logical_size(
+    session: *mut SessionHandle,
+    enabled: bool,
+) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_capture_logical_size(enabled);
+        Ok(())
+    });
+}
+
+/// Turns `session`'s frames, e.g. for a monitor mounted in portrait:
+/// 0 = as captured (default), 1 = rotate 90° clockwise, 2 = rotate 180°,
+/// 3 = rotate 270° clockwise, 4 = mirror left to right, 5 = mirror top to
+/// bottom. The frame is turned right after the crop (and the cursor, if
+/// drawn), so the reported size, dirty rectangle and cursor position all
+/// describe the turned frame, and explicit targets, the output size and
+/// `max_dim` apply to it: a 1080x1920 target fits a 1920x1080 display
+/// rotated by 90°. Input injection still takes display coordinates.
+///
+/// Returns `RdpStatus::Ok`, or `RdpStatus::InvalidArgument` for a null
+/// session or an unknown orientation.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_orientation(
+    session: *mut SessionHandle,
+    orientation: u32,
+) -> i32 {
+    status_of(catch(|| {
+        let mut s = unsafe { lock_session(session) }?;
+        let orientation = Orientation::from_u32(orientation).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown orientation {orientation}"),
+            )
+        })?;
+        s.set_orientation(orientation);
+        Ok(())
+    }))
+}
+
+/// Sets the colour `session` pads letterboxed frames with, as `0xRRGGBB`
+/// (default black).
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_fill_color(session: *mut SessionHandle, rgb: u32) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_fill_color(rgb);
+        Ok(())
+    });
+}
+
+/// Sets the PNG compression effort used by `session`: 0 = fast (default),
+/// 1 = zlib default, 2 = best.
+///
+/// Returns `RdpStatus::Ok`, or `RdpStatus::InvalidArgument` for a null
+/// session or any other value.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_png_compression(
+    session: *mut SessionHandle,
+    level: i32,
+) -> i32 {
+    status_of(catch(|| {
+        let mut s = unsafe { lock_session(session) }?;
+        let compression = encode::png_compression_from_i32(level).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown PNG compression level {level}"),
+            )
+        })?;
+        s.set_png_compression(compression);
+        Ok(())
+    }))
+}
+
+/// Sets the zstd compression level of `session`'s zstd frames (1–22,
+/// default 1) and turns delta coding on or off (the default). With
+/// `delta` non-zero, frames between keyframes are XORed with the frame
+/// before them before compressing, which shrinks mostly static screens
+/// dramatically; see the `zstd` module for how a client undoes it.
+/// Keyframes follow the `keyframe_interval` of `rdp_session_set_tiling`
+/// and `rdp_session_request_keyframe`, and any settings change.
+///
+/// Returns `RdpStatus::Ok`, or `RdpStatus::InvalidArgument` for a null
+/// session or a level outside 1–22.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_zstd(
+    session: *mut SessionHandle,
+    level: i32,
+    delta: u8,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_zstd(level, delta != 0)
+    }))
+}
+
+/// Sets the JPEG chroma subsampling used by `session`: 0 = 4:4:4,
+/// 1 = 4:2:2, 2 = 4:2:0 (default), 3 = grayscale.
+/// Builds without the `turbojpeg` feature (see `rdp_capabilities`) only
+/// honour 3, and encode colour at 4:4:4 whatever the setting.
+///
+/// Returns `RdpStatus::Ok`, or `RdpStatus::InvalidArgument` for a null
+/// session or any other value (the current setting is left unchanged).
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_subsampling(
+    session: *mut SessionHandle,
+    subsampling: i32,
+) -> i32 {
+    status_of(catch(|| {
+        let mut s = unsafe { lock_session(session) }?;
+        let subsamp = Subsampling::from_i32(subsampling).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown subsampling value {subsampling}"),
+            )
+        })?;
+        s.set_subsampling(subsamp);
+        Ok(())
+    }))
+}
+
+/// Makes `session` encode JPEG progressively (non-zero `progressive`) or
+/// as baseline (0, the default), from the next frame on. A viewer on a slow
+/// link can then show a coarse image as soon as the first scans arrive and
+/// refine it as the rest does. `RawImage::progressive` tells which a frame
+/// is. Progressive frames come out around 10% smaller, but take several
+/// times longer to encode: a synthetic 1080p desktop at quality 70 took
+/// 9.5 ms baseline and 70 ms progressive at 4:2:0 (14 and 86 ms at 4:4:4)
+/// with libjpeg-turbo 2.1 on one core. Tiled frames encode every JPEG tile
+/// progressively.
+///
+/// Returns `RdpStatus::Unsupported` when turning it on in a build without
+/// libjpeg-turbo (see `rdp_capabilities`), whose encoder is baseline only,
+/// or `RdpStatus::InvalidArgument` for a null session.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_progressive(
+    session: *mut SessionHandle,
+    progressive: i32,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_progressive(progressive != 0)
+    }))
+}
+
+/// Makes `session` put a JPEG restart marker (RST0 to RST7 in turn, with
+/// the interval in a DRI segment) after every `mcu_rows` rows of MCUs, or
+/// none for 0, the default. An MCU row is 16 pixels high at 4:2:0 and 8
+/// otherwise; the interval is capped where, counted in MCUs, it would no
+/// longer fit its 16 bits (some 60 rows on a 1080p frame). When a frame is
+/// sent in chunks and one is lost, a decoder skips to the next marker, so
+/// only that band is damaged instead of the rest of the image. The frames
+/// remain standard baseline JPEG that any decoder reads. Tiled frames put
+/// markers in every JPEG tile; progressive JPEG ignores the setting.
+///
+/// Markers cost a little size, as each one pads to a byte and restarts the
+/// DC prediction, and the frame is encoded as one strip per interval,
+/// which the encoder threads (see `rdp_set_encode_threads`) share.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_restart_interval(
+    session: *mut SessionHandle,
+    mcu_rows: u32,
+) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_restart_rows(mcu_rows);
+        Ok(())
+    });
+}
+
+/// Restricts `session` to the `w x h` region at (`x`, `y`), clamped to the
+/// display bounds at capture time.
+///
+/// Returns `RdpStatus::InvalidArgument` for a zero-area region or a null
+/// session.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_region(
+    session: *mut SessionHandle,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+) -> i32 {
+    status_of(catch(|| {
+        unsafe { lock_session(session) }?.set_region(Some(Rect { x, y, w, h }))
+    }))
+}
+
+/// Paints the `count` rectangles at `rects` (`RdpRect`: `u32` x, y, w, h
+/// in native display coordinates, whatever the capture region) over every
+/// frame of `session` with the blackout color (black unless
+/// `rdp_session_set_blackout_color` says otherwise), e.g. to hide a
+/// notification area or a password manager. They are painted into the
+/// captured pixels before they are resized, encoded, hashed for change
+/// detection or handed to anything else, so no output format, stream,
+/// server, recording or replay buffer ever sees what is under them; only
+/// the cursor, when drawn, goes on top. Rectangles may overlap and reach
+/// past the display; only their on-screen parts are painted. A `count` of
+/// 0 clears them.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, a null `rects`
+/// with a non-zero `count` or a zero-area rectangle; the previous
+/// rectangles then stay.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `rects` must be null or point
+/// to `count` readable `RdpRect`s.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_blackout_rects(
+    session: *mut SessionHandle,
+    rects: *const RdpRect,
+    count: usize,
+) -> i32 {
+    status_of(catch(|| {
+        let rects = match (rects.is_null(), count) {
+            (_, 0) => Vec::new(),
+            (true, _) => {
+                return Err(fail(
+                    RdpStatus::InvalidArgument,
+                    "Blackout rectangles are null",
+                ));
+            }
+            (false, _) => unsafe { std::slice::from_raw_parts(rects, count) }.to_vec(),
+        };
+        unsafe { lock_session(session) }?.set_blackout(rects)
+    }))
+}
+
+/// Sets the color blackout rectangles are painted in, as `0xRRGGBB`
+/// (default black).
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_blackout_color(session: *mut SessionHandle, rgb: u32) {
+    let _ = catch(|| {
+        unsafe { lock_session(session) }?.set_blackout_color(rgb);
+        Ok(())
+    });
+}
+
+/// Draws `utf8_text` on every frame of `session` as a watermark, e.g.
+/// "Shared by alice@corp — 2024-05-01", at `position` (0 = top left,
+/// 1 = top right, 2 = bottom left, 3 = bottom right, 4 = center) with
+/// `opacity` from 0 (invisible) to 255 (opaque). Newlines start new lines.
+/// The text is drawn after resizing, sized to the output height, so it
+/// stays legible at any output size; every format, stream, server,
+/// recording and replay buffer gets it. An empty string removes it.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null session, null or
+/// non-UTF-8 text or an unknown position; the previous watermark then
+/// stays.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`; `utf8_text` must be null or
+/// point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_set_watermark(
+    session: *mut SessionHandle,
+    utf8_text: *const c_char,
+    position: i32,
+    opacity: u8,
+) -> i32 {
+    status_of(catch(|| {
+        if utf8_text.is_null() {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Watermark text must not be null",
+            ));
+        }
+        let text = unsafe { CStr::from_ptr(utf8_text) }.to_str().map_err(|e| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Watermark text is not UTF-8: {e}"),
+            )
+        })?;
+        let anchor = WatermarkPosition::from_i32(position).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown watermark position {position}"),
+            )
+        })?;
+        unsafe { lock_session(session) }?.set_watermark(text, anchor, opacity);
+        Ok(())
+    }))
+}
+
+/// Goes back to capturing the whole display.
+///
+/// # Safety
+/// Same contract as `rdp_session_capture`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_clear_region(session: *mut SessionHandle) {
+    let _ = catch(|| unsafe { lock_session(session) }?.set_region(None));
+}
+
+/// Releases `session`, stopping its stream (and HTTP, TCP or WebSocket
+/// server) first if one is running.
+///
+/// # Safety
+/// `session` must be null or a pointer returned by `rdp_session_new` that has
+/// not already been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_session_free(session: *mut SessionHandle) {
+    if session.is_null() {
+        return;
+    }
+
+    guard((), || drop(unsafe { Box::from_raw(session) }));
+}
+
+/// Whether the `len` bytes at `data` have the CRC-32 `checksum`, as a
+/// frame's `RawImage::checksum`; for receivers to check payloads that
+/// crossed a network. False for a null `data` with a non-zero `len`.
+///
+/// # Safety
+/// `data` must be null or valid for reads of `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_verify_frame(data: *const u8, len: usize, checksum: u32) -> bool {
+    if data.is_null() {
+        return len == 0 && checksum == crc32fast::hash(&[]);
+    }
+    guard(false, || {
+        crc32fast::hash(unsafe { std::slice::from_raw_parts(data, len) }) == checksum
+    })
+}
+
+/// Splits the JPEG `frame` into RTP packets for an RTP/JPEG receiver, with
+/// RFC 2435 payloads: each at most `mtu` bytes including the 12-byte RTP
+/// header, numbered from `seq_start` and stamped with `timestamp` (a 90 kHz
+/// clock) and `ssrc`, the marker bit set on the last. The quantization
+/// tables go in the first packet (Q = 255), so any quality works, and a
+/// session's restart markers (`rdp_session_set_restart_interval`) are
+/// signalled so a receiver can recover from a lost packet. The packets are
+/// written through `out_packets`; send them in order, start the next frame
+/// at their `next_seq` and release them with one `rdp_rtp_packets_free`.
+///
+/// RFC 2435 only describes 4:2:2 and 4:2:0 colour frames up to 2040 pixels
+/// a side in multiples of 8, without progressive coding: other frames fail
+/// with `RdpStatus::Unsupported`, so configure the session to match.
+/// Returns `RdpStatus::InvalidArgument` for a null argument, a frame that is
+/// not JPEG or is encrypted, or an `mtu` too small for the headers (about
+/// 160 bytes), with `*out_packets` set to null on any failure.
+///
+/// # Safety
+/// `frame` must be null or a live frame from this library, `out_packets`
+/// null or valid for one pointer-sized write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_packetize_rtp_jpeg(
+    frame: *const RawImage,
+    mtu: u16,
+    ssrc: u32,
+    seq_start: u16,
+    timestamp: u32,
+    out_packets: *mut *mut RtpPackets,
+) -> i32 {
+    if out_packets.is_null() {
+        return fail(RdpStatus::InvalidArgument, "out_packets must not be null") as i32;
+    }
+
+    let result = catch(|| {
+        let frame = unsafe { frame.as_ref() }
+            .ok_or_else(|| fail(RdpStatus::InvalidArgument, "Frame must not be null"))?;
+        if frame.format != FrameFormat::Jpeg as u32 || frame.encrypted != 0 {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Only unencrypted JPEG frames can be sent as RTP/JPEG",
+            ));
+        }
+        let data = unsafe { std::slice::from_raw_parts(frame.data, frame.len) };
+        let packets = rtp::packetize_jpeg(data, mtu, ssrc, seq_start, timestamp)?;
+        Ok(Box::into_raw(Box::new(RtpPackets::new(packets, seq_start))))
+    });
+    let (packets, status) = match result {
+        Ok(packets) => (packets, RdpStatus::Ok),
+        Err(status) => (ptr::null_mut(), status),
+    };
+    unsafe { out_packets.write(packets) };
+    status as i32
+}
+
+/// Cuts `frame` into chunks of at most `mtu` bytes (1200 is safe on the
+/// internet), each with the 16-byte header documented in the `chunk`
+/// module: frame sequence, chunk index and count, and a CRC-32 of the
+/// payload. A UDP sender then just loops over `rdp_chunkset_get`; a
+/// receiver joins the payloads in index order, or hands the chunks to
+/// `rdp_reassemble`. Release the set with `rdp_chunkset_free`.
+///
+/// Returns null, with the reason in `rdp_last_error_message`, for a null
+/// frame, an `mtu` of 16 bytes or less, or a frame that would take more
+/// than 65535 chunks.
+///
+/// # Safety
+/// `frame` must be null or a live frame from this library.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_frame_chunks(frame: *const RawImage, mtu: u32) -> *mut ChunkSet {
+    unsafe { rdp_frame_chunks_ex(frame, mtu, false) }
+}
+
+/// `rdp_frame_chunks`, also cutting a JPEG frame with restart markers
+/// (`rdp_session_set_restart_interval`) at the markers when
+/// `align_to_restarts` is set: each chunk then ends before the last marker
+/// that fits, so the next starts at one and losing a chunk damages only the
+/// bands it holds. Chunks come out a little less full. Other frames,
+/// encrypted ones included, are cut as by `rdp_frame_chunks`.
+///
+/// # Safety
+/// Same contract as `rdp_frame_chunks`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_frame_chunks_ex(
+    frame: *const RawImage,
+    mtu: u32,
+    align_to_restarts: bool,
+) -> *mut ChunkSet {
+    catch(|| {
+        let frame = unsafe { frame.as_ref() }
+            .ok_or_else(|| fail(RdpStatus::InvalidArgument, "Frame must not be null"))?;
+        let data = unsafe { std::slice::from_raw_parts(frame.data, frame.len) };
+        let align =
+            align_to_restarts && frame.format == FrameFormat::Jpeg as u32 && frame.encrypted == 0;
+        let chunks = chunk::split(data, frame.sequence, mtu as usize, align)?;
+        Ok(Box::into_raw(Box::new(ChunkSet::new(chunks))))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Number of chunks in `set`; 0 for null.
+///
+/// # Safety
+/// `set` must be null or a live pointer from `rdp_frame_chunks`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_chunkset_count(set: *const ChunkSet) -> usize {
+    unsafe { set.as_ref() }.map_or(0, |set| set.chunks().len())
+}
+
+/// Points `*out_ptr` and `*out_len` at chunk `index` of `set`, header
+/// included, ready to send as is. The bytes belong to the set and stay
+/// valid until `rdp_chunkset_free`.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null argument or an index
+/// past the last chunk.
+///
+/// # Safety
+/// `set` must be null or a live pointer from `rdp_frame_chunks`; `out_ptr`
+/// and `out_len` null or valid for one write each.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_chunkset_get(
+    set: *const ChunkSet,
+    index: usize,
+    out_ptr: *mut *const u8,
+    out_len: *mut usize,
+) -> i32 {
+    if out_ptr.is_null() || out_len.is_null() {
+        return fail(
+            RdpStatus::InvalidArgument,
+            "out_ptr and out_len must not be null",
+        ) as i32;
+    }
+
+    status_of(catch(|| {
+        let set = unsafe { set.as_ref() }
+            .ok_or_else(|| fail(RdpStatus::InvalidArgument, "Chunk set must not be null"))?;
+        let chunk = set.chunks().get(index).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("No chunk {index} in a set of {}", set.chunks().len()),
+            )
+        })?;
+        unsafe {
+            out_ptr.write(chunk.as_ptr());
+            out_len.write(chunk.len());
+        }
+        Ok(())
+    }))
+}
+
+/// Releases `set` and its chunks. Null is ignored.
+///
+/// # Safety
+/// `set` must be null or a pointer from `rdp_frame_chunks` that has not
+/// already been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_chunkset_free(set: *mut ChunkSet) {
+    if set.is_null() {
+        return;
+    }
+
+    guard((), || drop(unsafe { Box::from_raw(set) }));
+}
+
+/// Joins the `count` chunks of one frame, `chunks[i]` being `lens[i]` bytes
+/// with their headers (see `rdp_frame_chunks`), in any order, back into
+/// the frame's data. Only `data`, `len` and `sequence` of the result are
+/// meaningful; release it with `free_image`.
+///
+/// Returns null, with the reason in `rdp_last_error_message`, if a chunk is
+/// truncated, damaged (its checksum does not match), missing, repeated or
+/// from another frame, or an argument is null.
+///
+/// # Safety
+/// `chunks` and `lens` must be null or point to `count` entries each, and
+/// every `chunks[i]` to `lens[i]` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_reassemble(
+    chunks: *const *const u8,
+    lens: *const usize,
+    count: usize,
+) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        if chunks.is_null() || lens.is_null() {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "chunks and lens must not be null",
+            ));
+        }
+        let (chunks, lens) = unsafe {
+            (
+                std::slice::from_raw_parts(chunks, count),
+                std::slice::from_raw_parts(lens, count),
+            )
+        };
+        let (sequence, data) = chunk::reassemble(
+            chunks
+                .iter()
+                .zip(lens)
+                .map(|(&chunk, &len)| unsafe { std::slice::from_raw_parts(chunk, len) }),
+        )?;
+        Ok(EncodedFrame {
+            content_hash: pixels::frame_hash(&data, &[]),
+            data,
+            width: 0,
+            height: 0,
+            format: FrameFormat::Raw,
+            pixel_format: PixelFormat::Gray,
+            stride: 0,
+            dirty: Rect {
+                x: 0,
+                y: 0,
+                w: 0,
+                h: 0,
+            },
+            cursor: None,
+            hotspot: (0, 0),
+            sequence,
+            timestamp_us: 0,
+            quality: 0,
+            keyframe: true,
+            uncompressed_len: 0,
+            encrypted: false,
+            progressive: false,
+        })
+    }))
+}
+
+/// Decodes a JPEG, PNG or WebP frame into raw pixels in `pixel_format` (as
+/// for `rdp_session_set_pixel_format`): a frame with `format` 3, its
+/// `width`, `height`, `stride` and `pixel_format` set and the rows tightly
+/// packed. JPEG goes through libjpeg-turbo when the library is built with
+/// the `turbojpeg` feature. Frames in the other formats do not describe
+/// themselves or build on the ones before, so they go through a decoder
+/// instead (`rdp_decoder_new`). Release the result with `free_image`.
+///
+/// Returns null on failure, with `RdpStatus::InvalidArgument` for a null
+/// `data` or an unknown pixel format and `RdpStatus::DecodeFailed` for data
+/// that is not a whole JPEG, PNG or WebP image; `rdp_last_error_message`
+/// has the reason.
+///
+/// # Safety
+/// `data` must be null or valid for reads of `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_decode(
+    data: *const u8,
+    len: usize,
+    pixel_format: u32,
+) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        if data.is_null() {
+            return Err(fail(RdpStatus::InvalidArgument, "Frame data is null"));
+        }
+        let pixel_format = pixel_format_from(pixel_format)?;
+        decode::decode_image(
+            unsafe { std::slice::from_raw_parts(data, len) },
+            pixel_format,
+        )
+    }))
+}
+
+/// A decoder following one session's frames, in any image format, and
+/// handing out the image they add up to in `pixel_format` (as for
+/// `rdp_session_set_pixel_format`). Feed it every frame received, in order,
+/// with `rdp_decoder_feed`, read the image with `rdp_decoder_get_frame` and
+/// release it with `rdp_decoder_free`. A decoder is not thread safe; use
+/// one per stream.
+///
+/// Returns null for an unknown pixel format, with the reason in
+/// `rdp_last_error_message`.
+#[unsafe(no_mangle)]
+pub extern "C" fn rdp_decoder_new(pixel_format: u32) -> *mut Decoder {
+    catch(|| {
+        let pixel_format = pixel_format_from(pixel_format)?;
+        Ok(Box::into_raw(Box::new(Decoder::new(pixel_format))))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Sets the matrix `decoder` takes I420 and NV12 frames to be in: the
+/// sending session's `rdp_session_set_yuv_matrix`, 0 = BT.601 (default) or
+/// 1 = BT.709.
+///
+/// Returns `RdpStatus::InvalidArgument` for a null decoder or an unknown
+/// matrix.
+///
+/// # Safety
+/// `decoder` must be null or a live pointer from `rdp_decoder_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_decoder_set_yuv_matrix(decoder: *mut Decoder, matrix: u32) -> i32 {
+    status_of(catch(|| {
+        let decoder = unsafe { decoder.as_mut() }
+            .ok_or_else(|| fail(RdpStatus::InvalidArgument, "Decoder must not be null"))?;
+        let matrix = YuvMatrix::from_u32(matrix).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown YUV matrix {matrix}"),
+            )
+        })?;
+        decoder.set_yuv_matrix(matrix);
+        Ok(())
+    }))
+}
+
+/// Decodes `frame`, the session's next frame, into `decoder`'s image.
+/// Besides `data` and `len` this reads the frame's `format`, `width`,
+/// `height`, `stride`, `pixel_format`, `keyframe` and `encrypted`, so a
+/// receiver that got only the payload fills those in from what it knows of
+/// the session. Tiled deltas are drawn on the image so far and zstd deltas
+/// XORed onto the frame before them; a delta whose earlier frames were
+/// missed or failed fails with `RdpStatus::KeyframeNeeded` until the next
+/// keyframe, which the sender can be asked for
+/// (`rdp_session_request_keyframe`). A failed frame leaves the image as it
+/// was.
+///
+/// Returns `RdpStatus::Ok`; `RdpStatus::InvalidArgument` for a null
+/// argument, an unknown format, text or an encrypted frame (decrypt it
+/// with `rdp_decrypt_frame` first); `RdpStatus::Unsupported` for video
+/// frames; `RdpStatus::UnsupportedFormat` for zstd frames when the library
+/// is built without the `zstd` feature; or `RdpStatus::DecodeFailed` for
+/// damaged data.
+///
+/// # Safety
+/// `decoder` must be null or a live pointer from `rdp_decoder_new`; `frame`
+/// null or pointing to a whole `RawImage` whose `data` is valid for reads
+/// of `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_decoder_feed(decoder: *mut Decoder, frame: *const RawImage) -> i32 {
+    status_of(catch(|| {
+        let (Some(decoder), Some(frame)) = (unsafe { decoder.as_mut() }, unsafe { frame.as_ref() })
+        else {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Decoder and frame must not be null",
+            ));
+        };
+        if frame.data.is_null() || frame.encrypted != 0 {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Frame has no data, or is still encrypted",
+            ));
+        }
+        let format = FrameFormat::from_u32(frame.format).ok_or_else(|| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Unknown frame format {}", frame.format),
+            )
+        })?;
+        let info = FrameInfo {
+            format,
+            width: frame.width,
+            height: frame.height,
+            stride: frame.stride,
+            pixel_format: pixel_format_from(frame.pixel_format)?,
+            keyframe: frame.keyframe != 0,
+        };
+        decoder.feed(
+            unsafe { std::slice::from_raw_parts(frame.data, frame.len) },
+            &info,
+        )
+    }))
+}
+
+/// The image the frames fed to `decoder` add up to, as a raw frame like
+/// `rdp_decode`'s; release it with `free_image`.
+///
+/// Returns null, with the reason in `rdp_last_error_message`, for a null
+/// decoder, or with `RdpStatus::KeyframeNeeded` before a frame was decoded.
+///
+/// # Safety
+/// `decoder` must be null or a live pointer from `rdp_decoder_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_decoder_get_frame(decoder: *const Decoder) -> *mut RawImage {
+    into_raw_or_null(catch(|| {
+        let decoder = unsafe { decoder.as_ref() }
+            .ok_or_else(|| fail(RdpStatus::InvalidArgument, "Decoder must not be null"))?;
+        decoder.frame().ok_or_else(|| {
+            fail(
+                RdpStatus::KeyframeNeeded,
+                "No frame decoded yet; waiting for a keyframe",
+            )
+        })
+    }))
+}
+
+/// Releases `decoder` and its image. Null is ignored.
+///
+/// # Safety
+/// `decoder` must be null or a pointer from `rdp_decoder_new` that has not
+/// already been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_decoder_free(decoder: *mut Decoder) {
+    if decoder.is_null() {
+        return;
+    }
+
+    guard((), || drop(unsafe { Box::from_raw(decoder) }));
+}
+
+/// Releases the packets from `rdp_packetize_rtp_jpeg`, all at once. Null is
+/// ignored.
+///
+/// # Safety
+/// `packets` must be null or a pointer from `rdp_packetize_rtp_jpeg` that
+/// has not already been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rdp_rtp_packets_free(packets: *mut RtpPackets) {
+    if packets.is_null() {
+        return;
+    }
+
+    guard((), || drop(unsafe { Box::from_raw(packets) }));
+}
+
+/// Releases a frame returned by any capture function, together with its
+/// pixel or encoded data. Null is ignored. So is, with an error through the
+/// log callback and `rdp_last_error_message`, a pointer that is not a live
+/// image of this library: one freed already, or never returned by it.
+///
+/// # Safety
+/// `image_ptr` must be null or a pointer returned by this library that has
+/// not already been freed; other pointers are detected and ignored, but
+/// only as a safety net for bugs.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free_image(image_ptr: *mut RawImage) {
+    if image_ptr.is_null() {
+        return;
+    }
+
+    guard((), || {
+        let Some((data, len)) = live_images().remove(&(image_ptr as usize)) else {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!(
+                    "free_image: ignoring {image_ptr:p}, which is not a live image from this \
+                     library (freed twice?)"
+                ),
+            );
+            return;
+        };
+        // From the registry rather than the struct, which the caller may have
+        // changed; an empty slice was never allocated and frees as a no-op
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(data as *mut u8, len)) });
+        drop(unsafe { Box::from_raw(image_ptr) });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{last_error, restore_last_error};
+
+    fn image(data: Vec<u8>) -> *mut RawImage {
+        RawImage::into_raw(EncodedFrame {
+            data,
+            width: 2,
+            height: 1,
+            format: FrameFormat::Raw,
+            pixel_format: PixelFormat::Bgra,
+            stride: 8,
+            dirty: Rect {
+                x: 0,
+                y: 0,
+                w: 2,
+                h: 1,
+            },
+            content_hash: 0,
+            cursor: None,
+            hotspot: (0, 0),
+            sequence: 0,
+            timestamp_us: 0,
+            quality: 0,
+            keyframe: true,
+            uncompressed_len: 0,
+            encrypted: false,
+            progressive: false,
+        })
+    }
+
+    fn is_live(image: *mut RawImage) -> bool {
+        live_images().contains_key(&(image as usize))
+    }
+
+    fn rejected(image: *mut RawImage) -> bool {
+        let _ = restore_last_error(RdpStatus::Ok, String::new());
+        unsafe { free_image(image) };
+        last_error().contains("not a live image")
+    }
+
+    #[test]
+    fn panics_become_a_status_the_caller_can_read() {
+        let status = status_of(catch(|| panic!("forced for the test")));
+        assert_eq!(status, RdpStatus::Panic as i32);
+        let message = unsafe { CStr::from_ptr(rdp_last_error_message()) };
+        assert_eq!(message.to_str(), Ok("Internal panic: forced for the test"));
+        assert!(into_raw_or_null(catch(|| panic!("forced for the test"))).is_null());
+        assert_eq!(status_of(catch(|| Ok(()))), RdpStatus::Ok as i32);
+    }
+
+    #[test]
+    fn unknown_pixel_formats_are_invalid_arguments() {
+        assert_eq!(pixel_format_from(3), Ok(PixelFormat::Rgba));
+        for value in [5, u32::MAX] {
+            assert_eq!(pixel_format_from(value), Err(RdpStatus::InvalidArgument));
+        }
+    }
+
+    #[test]
+    fn free_releases_a_live_image() {
+        let image = image(vec![1; 8]);
+        assert!(is_live(image));
+        assert!(!rejected(image));
+        assert!(!is_live(image));
+    }
+
+    #[test]
+    fn double_free_is_ignored() {
+        let image = image(vec![1; 8]);
+        unsafe { free_image(image) };
+        assert!(rejected(image));
+        assert!(!is_live(image));
+    }
+
+    #[test]
+    fn null_is_ignored() {
+        let _ = restore_last_error(RdpStatus::Ok, String::new());
+        unsafe { free_image(ptr::null_mut()) };
+        assert_eq!(last_error(), "");
+    }
+
+    #[test]
+    fn foreign_and_garbage_pointers_are_ignored() {
+        // All-zero is a valid `RawImage`: null data, no length
+        let mut foreign: RawImage = unsafe { std::mem::zeroed() };
+        assert!(rejected(&mut foreign));
+        // Never dereferenced, only looked up
+        assert!(rejected(0xdead_bee8 as *mut RawImage));
+
+        // Not even an image next to a live one
+        let live = image(vec![1; 8]);
+        assert!(rejected(live.wrapping_byte_add(8)));
+        assert!(is_live(live));
+        unsafe { free_image(live) };
+    }
+
+    #[test]
+    fn freeing_uses_the_registry_not_the_fields() {
+        let image = image(vec![1; 8]);
+        unsafe {
+            (*image).data = ptr::null_mut();
+            (*image).len = 1 << 20;
+        }
+        assert!(!rejected(image));
+        assert!(!is_live(image));
+    }
+
+    #[test]
+    fn empty_images_free() {
+        let image = image(Vec::new());
+        assert!(!rejected(image));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn captured_frames_decrypt_to_their_pixels() {
+        use crate::capture::Backend;
+
+        let key = [7u8; cipher::KEY_LEN];
+        let session = RdpSession::with_backend(Backend::Test, 0).unwrap();
+        let session = Box::into_raw(Box::new(SessionHandle::new(session)));
+        unsafe {
+            assert_eq!(rdp_session_set_format(session, FrameFormat::Raw as u32), 0);
+            assert_eq!(
+                rdp_session_set_encryption_key(session, key.as_ptr(), key.len()),
+                0
+            );
+        }
+        let (width, height) = (160, 90);
+        let sealed = [(); 2].map(|_| unsafe { rdp_session_capture(session, width, height) });
+        unsafe { rdp_session_free(session) };
+
+        let mut nonces = Vec::new();
+        for image in sealed {
+            let sealed = unsafe { &*image };
+            let data = unsafe { std::slice::from_raw_parts(sealed.data, sealed.len) };
+            let plain_len = (width * height * 4) as usize;
+            assert_eq!(sealed.encrypted, 1);
+            assert_eq!(data.len(), cipher::NONCE_LEN + plain_len + cipher::TAG_LEN);
+            nonces.push(data[..cipher::NONCE_LEN].to_vec());
+
+            let opened = unsafe { rdp_decrypt_frame(key.as_ptr(), sealed.data, sealed.len) };
+            assert!(!opened.is_null(), "{}", last_error());
+            let pixels = unsafe { std::slice::from_raw_parts((*opened).data, (*opened).len) };
+            assert_eq!(pixels.len(), plain_len);
+            // The middle of the magenta bar, in BGRA
+            let at = (height as usize / 3 * width as usize + width as usize * 9 / 14) * 4;
+            assert_eq!(&pixels[at..at + 4], &[191, 0, 191, 0xff]);
+
+            let mut tampered = data.to_vec();
+            tampered[cipher::NONCE_LEN] ^= 1;
+            let refused =
+                unsafe { rdp_decrypt_frame(key.as_ptr(), tampered.as_ptr(), tampered.len()) };
+            assert!(refused.is_null(), "tampering is caught");
+            unsafe {
+                free_image(opened);
+                free_image(image);
+            }
+        }
+        assert_ne!(nonces[0], nonces[1], "a fresh nonce every frame");
+    }
+
+    #[test]
+    fn poll_latest_ex_tells_no_frame_from_no_stream() {
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        use crate::capture::Backend;
+
+        let session = RdpSession::with_backend(Backend::Test, 0).unwrap();
+        let session = Box::into_raw(Box::new(SessionHandle::new(session)));
+        let mut image = ptr::dangling_mut();
+        let status = unsafe { rdp_session_poll_latest_ex(session, &mut image) };
+        assert_eq!(status, RdpStatus::InvalidArgument as i32);
+        assert!(image.is_null());
+
+        // One frame a second, so the poll after the first finds nothing new
+        assert_eq!(unsafe { rdp_stream_start_buffered(session, 1, 0) }, 0);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut status = RdpStatus::WouldBlock as i32;
+        while status == RdpStatus::WouldBlock as i32 && Instant::now() < deadline {
+            status = unsafe { rdp_session_poll_latest_ex(session, &mut image) };
+            assert_eq!(image.is_null(), status != 0);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(status, 0, "{}", last_error());
+        unsafe { free_image(image) };
 
-    // Rebuild Vec<u8> so Rust can free the JPEG buffer
-    if !image_box.data.is_null() && image_box.len > 0 {
+        let status = unsafe { rdp_session_poll_latest_ex(session, &mut image) };
+        assert_eq!(status, RdpStatus::WouldBlock as i32);
+        assert!(image.is_null());
         unsafe {
-            let _ = Vec::from_raw_parts(image_box.data, image_box.len, image_box.len);
+            rdp_stream_stop(session);
+            rdp_session_free(session);
         }
     }
-    // image_box drops here, freeing the struct itself
 }
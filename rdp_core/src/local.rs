@@ -0,0 +1,440 @@
+//! The local endpoint of the TCP frame server: a Unix domain socket, or a
+//! named pipe on Windows, for viewers on the same machine. Clients get
+//! exactly what TCP clients do (see the `tcp` module), token check
+//! included, through the same `server::Server`.
+//!
+//! A socket file is created with `DEFAULT_MODE` permissions (or those set
+//! with `set_mode`) under a temporary name and renamed into place, so it is
+//! never reachable with wider ones. A stale file left by a server that died
+//! is replaced; a path some server still answers on, or that is not a
+//! socket, is left alone. Stopping the server removes the file.
+//!
+//! Pipes reject remote clients and, unless the mode grants group or other
+//! access, admit only their owner. Windows removes a pipe with its last
+//! handle, so there is nothing to clean up.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub use platform::{Listener, Stream};
+
+/// Socket permissions unless `set_mode` says otherwise: the user running
+/// the server only.
+pub const DEFAULT_MODE: u32 = 0o600;
+
+static MODE: AtomicU32 = AtomicU32::new(DEFAULT_MODE);
+
+/// Sets the permission bits (`0o777` at most) of sockets created from now
+/// on.
+pub fn set_mode(mode: u32) {
+    MODE.store(mode & 0o777, Ordering::Relaxed);
+}
+
+pub fn mode() -> u32 {
+    MODE.load(Ordering::Relaxed)
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::fs::{self, Permissions};
+    use std::io;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+
+    use crate::error::{RdpStatus, fail, fail_file};
+
+    pub type Stream = UnixStream;
+
+    /// A bound socket; dropping it removes the socket file, if it is still
+    /// the one bound.
+    pub struct Listener {
+        listener: UnixListener,
+        path: PathBuf,
+        /// Device and inode of the socket file.
+        id: (u64, u64),
+    }
+
+    impl Listener {
+        pub fn bind(path: &str, mode: u32) -> Result<Listener, RdpStatus> {
+            let target = Path::new(path);
+            remove_stale(target)?;
+
+            // Bound and given its mode under another name first, since a
+            // socket's mode cannot be set before it exists
+            let mut staging = target.as_os_str().to_owned();
+            staging.push(format!(".{}.tmp", std::process::id()));
+            let staging = PathBuf::from(staging);
+            let _ = fs::remove_file(&staging);
+            let bound = UnixListener::bind(&staging).and_then(|listener| {
+                let placed = fs::set_permissions(&staging, Permissions::from_mode(mode))
+                    .and_then(|()| fs::rename(&staging, target))
+                    .and_then(|()| listener.set_nonblocking(true))
+                    .and_then(|()| fs::symlink_metadata(target));
+                if placed.is_err() {
+                    let _ = fs::remove_file(&staging);
+                }
+                Ok((listener, placed?))
+            });
+            let (listener, metadata) = bound
+                .map_err(|e| fail_file(&e, format!("Failed to listen on socket {path}: {e}")))?;
+            Ok(Listener {
+                listener,
+                path: target.to_path_buf(),
+                id: (metadata.dev(), metadata.ino()),
+            })
+        }
+
+        /// The next client, if one is waiting.
+        pub fn accept(&self) -> io::Result<Stream> {
+            self.listener.accept().map(|(stream, _)| stream)
+        }
+    }
+
+    impl Drop for Listener {
+        fn drop(&mut self) {
+            // Another server may have replaced the file since
+            if let Ok(metadata) = fs::symlink_metadata(&self.path)
+                && (metadata.dev(), metadata.ino()) == self.id
+            {
+                let _ = fs::remove_file(&self.path);
+            }
+        }
+    }
+
+    /// Removes a socket file no server answers on any more.
+    fn remove_stale(path: &Path) -> Result<(), RdpStatus> {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Ok(());
+        };
+        let shown = path.display();
+        if !metadata.file_type().is_socket() {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("{shown} exists and is not a socket"),
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(fail(
+                RdpStatus::Busy,
+                format!("Another server is listening on {shown}"),
+            ));
+        }
+        fs::remove_file(path)
+            .map_err(|e| fail_file(&e, format!("Failed to remove stale socket {shown}: {e}")))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+    use std::io::{self, Read, Write};
+    use std::net::Shutdown;
+    use std::ptr;
+    use std::sync::{Arc, Mutex, PoisonError};
+    use std::time::Duration;
+
+    use crate::error::{RdpStatus, fail};
+
+    type Handle = *mut c_void;
+
+    const INVALID_HANDLE_VALUE: Handle = !0 as Handle;
+    const PIPE_ACCESS_DUPLEX: u32 = 0x3;
+    const FILE_FLAG_FIRST_PIPE_INSTANCE: u32 = 0x0008_0000;
+    const PIPE_WAIT: u32 = 0x0;
+    const PIPE_NOWAIT: u32 = 0x1;
+    const PIPE_REJECT_REMOTE_CLIENTS: u32 = 0x8;
+    const PIPE_UNLIMITED_INSTANCES: u32 = 255;
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const ERROR_BROKEN_PIPE: i32 = 109;
+    const ERROR_NO_DATA: i32 = 232;
+    const ERROR_PIPE_CONNECTED: i32 = 535;
+    const ERROR_PIPE_LISTENING: i32 = 536;
+    const SDDL_REVISION_1: u32 = 1;
+    /// Generic-all access for the owner of the pipe only.
+    const OWNER_ONLY: &str = "D:P(A;;GA;;;OW)";
+    /// Bytes the pipe buffers towards the client.
+    const OUT_BUFFER: u32 = 1 << 20;
+
+    #[repr(C)]
+    struct SecurityAttributes {
+        length: u32,
+        descriptor: *mut c_void,
+        inherit: i32,
+    }
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn CreateNamedPipeW(
+            name: *const u16,
+            open_mode: u32,
+            pipe_mode: u32,
+            max_instances: u32,
+            out_buffer: u32,
+            in_buffer: u32,
+            default_timeout: u32,
+            attributes: *mut SecurityAttributes,
+        ) -> Handle;
+        fn ConnectNamedPipe(pipe: Handle, overlapped: *mut c_void) -> i32;
+        fn DisconnectNamedPipe(pipe: Handle) -> i32;
+        fn SetNamedPipeHandleState(
+            pipe: Handle,
+            mode: *mut u32,
+            max_collection: *mut u32,
+            timeout: *mut u32,
+        ) -> i32;
+        fn ReadFile(
+            file: Handle,
+            buffer: *mut u8,
+            len: u32,
+            read: *mut u32,
+            overlapped: *mut c_void,
+        ) -> i32;
+        fn WriteFile(
+            file: Handle,
+            buffer: *const u8,
+            len: u32,
+            written: *mut u32,
+            overlapped: *mut c_void,
+        ) -> i32;
+        fn FlushFileBuffers(file: Handle) -> i32;
+        fn CancelIoEx(file: Handle, overlapped: *mut c_void) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+        fn LocalFree(memory: *mut c_void) -> *mut c_void;
+    }
+
+    #[link(name = "advapi32")]
+    unsafe extern "system" {
+        fn ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl: *const u16,
+            revision: u32,
+            descriptor: *mut *mut c_void,
+            len: *mut u32,
+        ) -> i32;
+    }
+
+    /// An open pipe instance, closed with its last `Stream`.
+    struct Pipe(Handle);
+
+    // A pipe handle may be used from any thread
+    unsafe impl Send for Pipe {}
+    unsafe impl Sync for Pipe {}
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    /// A connected client.
+    #[derive(Clone)]
+    pub struct Stream(Arc<Pipe>);
+
+    impl Stream {
+        pub fn try_clone(&self) -> io::Result<Stream> {
+            Ok(self.clone())
+        }
+
+        /// Breaks the connection, failing any read or write in progress.
+        pub fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+            unsafe {
+                CancelIoEx(self.0.0, ptr::null_mut());
+                DisconnectNamedPipe(self.0.0);
+            }
+            Ok(())
+        }
+
+        /// Pipe instances are put in blocking mode when they connect.
+        pub fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        /// Synchronous pipes have no timeouts; `server::Server::stop`
+        /// breaks a stuck connection instead.
+        pub fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            let mut read = 0;
+            let ok =
+                unsafe { ReadFile(self.0.0, buf.as_mut_ptr(), len, &mut read, ptr::null_mut()) };
+            if ok == 0 {
+                let e = io::Error::last_os_error();
+                // The client closed its end
+                if e.raw_os_error() == Some(ERROR_BROKEN_PIPE) {
+                    return Ok(0);
+                }
+                return Err(e);
+            }
+            Ok(read as usize)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            let mut written = 0;
+            let ok =
+                unsafe { WriteFile(self.0.0, buf.as_ptr(), len, &mut written, ptr::null_mut()) };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(written as usize)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if unsafe { FlushFileBuffers(self.0.0) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    /// A pipe name with an instance waiting for the next client.
+    pub struct Listener {
+        name: Vec<u16>,
+        /// Owner-only security descriptor, from `LocalAlloc`; null for the
+        /// default.
+        descriptor: *mut c_void,
+        waiting: Mutex<Pipe>,
+    }
+
+    // Only the accept thread uses the listener after it is created
+    unsafe impl Send for Listener {}
+    unsafe impl Sync for Listener {}
+
+    impl Listener {
+        pub fn bind(path: &str, mode: u32) -> Result<Listener, RdpStatus> {
+            if !path.starts_with(r"\\.\pipe\") || path.contains('\0') {
+                return Err(fail(
+                    RdpStatus::InvalidArgument,
+                    format!(r"Pipe name {path} does not start with \\.\pipe\"),
+                ));
+            }
+            let mut descriptor = ptr::null_mut();
+            if mode & 0o077 == 0 {
+                let sddl: Vec<u16> = OWNER_ONLY.encode_utf16().chain([0]).collect();
+                let converted = unsafe {
+                    ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                        sddl.as_ptr(),
+                        SDDL_REVISION_1,
+                        &mut descriptor,
+                        ptr::null_mut(),
+                    )
+                };
+                if converted == 0 {
+                    return Err(fail(
+                        RdpStatus::NetworkError,
+                        format!(
+                            "Failed to secure pipe {path}: {}",
+                            io::Error::last_os_error()
+                        ),
+                    ));
+                }
+            }
+            let name: Vec<u16> = path.encode_utf16().chain([0]).collect();
+            match instance(&name, descriptor, true) {
+                Ok(pipe) => Ok(Listener {
+                    name,
+                    descriptor,
+                    waiting: Mutex::new(pipe),
+                }),
+                Err(e) => {
+                    if !descriptor.is_null() {
+                        unsafe { LocalFree(descriptor) };
+                    }
+                    Err(if e.raw_os_error() == Some(ERROR_ACCESS_DENIED) {
+                        fail(
+                            RdpStatus::Busy,
+                            format!("Another server is listening on {path}"),
+                        )
+                    } else {
+                        fail(
+                            RdpStatus::NetworkError,
+                            format!("Failed to listen on pipe {path}: {e}"),
+                        )
+                    })
+                }
+            }
+        }
+
+        /// The next client, if one is waiting; `WouldBlock` otherwise.
+        pub fn accept(&self) -> io::Result<Stream> {
+            let mut waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+            // Non-blocking, so this returns at once
+            let connected = unsafe { ConnectNamedPipe(waiting.0, ptr::null_mut()) } != 0;
+            match io::Error::last_os_error().raw_os_error() {
+                _ if connected => {}
+                Some(ERROR_PIPE_CONNECTED) => {}
+                Some(ERROR_PIPE_LISTENING) => return Err(io::ErrorKind::WouldBlock.into()),
+                // Came and went before it was accepted
+                Some(ERROR_NO_DATA) => {
+                    unsafe { DisconnectNamedPipe(waiting.0) };
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                _ => return Err(io::Error::last_os_error()),
+            }
+
+            let next = instance(&self.name, self.descriptor, false)?;
+            let pipe = std::mem::replace(&mut *waiting, next);
+            let mut mode = PIPE_WAIT;
+            let blocking = unsafe {
+                SetNamedPipeHandleState(pipe.0, &mut mode, ptr::null_mut(), ptr::null_mut())
+            };
+            if blocking == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Stream(Arc::new(pipe)))
+        }
+    }
+
+    /// A new instance of pipe `name`, polled for a client.
+    fn instance(name: &[u16], descriptor: *mut c_void, first: bool) -> io::Result<Pipe> {
+        let mut attributes = SecurityAttributes {
+            length: std::mem::size_of::<SecurityAttributes>() as u32,
+            descriptor,
+            inherit: 0,
+        };
+        let attributes = if descriptor.is_null() {
+            ptr::null_mut()
+        } else {
+            &mut attributes as *mut SecurityAttributes
+        };
+        let first = if first {
+            FILE_FLAG_FIRST_PIPE_INSTANCE
+        } else {
+            0
+        };
+        let pipe = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                PIPE_ACCESS_DUPLEX | first,
+                PIPE_NOWAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                OUT_BUFFER,
+                4096,
+                0,
+                attributes,
+            )
+        };
+        if pipe == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe(pipe))
+    }
+
+    impl Drop for Listener {
+        fn drop(&mut self) {
+            if !self.descriptor.is_null() {
+                unsafe { LocalFree(self.descriptor) };
+            }
+        }
+    }
+}
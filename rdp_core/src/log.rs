@@ -0,0 +1,54 @@
+use std::ffi::{CString, c_char, c_void};
+use std::sync::Mutex;
+
+/// Severity passed to the log callback.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+/// Host-supplied sink for diagnostics. `msg` is only valid for the duration
+/// of the call.
+pub type LogCallback = extern "C" fn(level: i32, msg: *const c_char, user_data: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Sink {
+    callback: LogCallback,
+    user_data: *mut c_void,
+}
+
+// The host owns `user_data` and promises it can be used from any thread that
+// calls into the library.
+unsafe impl Send for Sink {}
+
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+/// Installs `callback` (or, when `None`, restores the stderr fallback).
+pub fn set_callback(callback: Option<LogCallback>, user_data: *mut c_void) {
+    let sink = callback.map(|callback| Sink {
+        callback,
+        user_data,
+    });
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = sink;
+}
+
+/// Reports `message` through the registered callback, or on stderr for
+/// warnings and errors when none is installed.
+pub fn log(level: LogLevel, message: &str) {
+    // Copy the sink out so the callback runs without the lock held; it may
+    // log, or even swap the callback, itself.
+    let sink = *SINK.lock().unwrap_or_else(|e| e.into_inner());
+
+    match sink {
+        Some(sink) => {
+            let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+            (sink.callback)(level as i32, message.as_ptr(), sink.user_data);
+        }
+        None if level <= LogLevel::Warn => eprintln!("{message}"),
+        None => {}
+    }
+}
@@ -0,0 +1,271 @@
+//! A session's memory budget: a hard cap, set with
+//! `rdp_session_set_memory_limit`, on the bytes its buffers hold. Every
+//! buffer that grows with the frames takes a `Charge` against the budget
+//! before it grows, and the budget refuses what would pass the limit:
+//!
+//! - the capture pipeline's working buffers (the copied frame, resize and
+//!   conversion output, encoder input and the zstd reference), reserved
+//!   for each frame from its size before they are filled;
+//! - the frames waiting in consumer queues (`queue::FrameQueue`), each
+//!   counted once per queue it sits in;
+//! - the replay buffer's stills and a shared memory region's slots.
+//!
+//! What holds frames gives way when the budget is short, in a set order:
+//! a queue pushes out its oldest frames to take a new one, and the replay
+//! buffer its oldest stills, so both keep the newest frames for as long as
+//! there is room for any. A capture short of room for its working buffers
+//! takes it from the replay buffer's oldest stills, then from buffers left
+//! over from larger frames, and fails with `RdpStatus::MemoryLimit` rather
+//! than allocating when that is not enough. Memory the encoders and the
+//! resizer keep internally is not counted, so the limit should leave some
+//! headroom over what `RdpStats::memory_used` shows.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+/// Bytes held against a session's limit. Shared by the session and
+/// everything that holds its frames; `RdpStats` reports it.
+#[derive(Default)]
+pub struct MemoryBudget {
+    /// 0 for no limit.
+    limit: AtomicU64,
+    used: AtomicU64,
+    peak: AtomicU64,
+    /// Times a buffer was refused room.
+    refusals: AtomicU64,
+}
+
+impl MemoryBudget {
+    /// Caps the bytes held at `bytes`; 0 lifts the cap. What is held
+    /// already is not taken back, only refused more room.
+    pub fn set_limit(&self, bytes: u64) {
+        self.limit.store(bytes, Relaxed);
+    }
+
+    /// The cap, 0 for none.
+    pub fn limit(&self) -> u64 {
+        self.limit.load(Relaxed)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Relaxed)
+    }
+
+    pub fn peak(&self) -> u64 {
+        self.peak.load(Relaxed)
+    }
+
+    pub fn refusals(&self) -> u64 {
+        self.refusals.load(Relaxed)
+    }
+
+    /// Bytes that could still be charged; `u64::MAX` without a limit.
+    pub fn available(&self) -> u64 {
+        match self.limit() {
+            0 => u64::MAX,
+            limit => limit.saturating_sub(self.used()),
+        }
+    }
+
+    /// Starts the peak over from what is held now and clears the refusals.
+    pub fn reset(&self) {
+        self.peak.store(self.used(), Relaxed);
+        self.refusals.store(0, Relaxed);
+    }
+
+    fn grow(&self, bytes: u64) -> bool {
+        let grown = self.used.fetch_update(Relaxed, Relaxed, |used| {
+            let total = used.saturating_add(bytes);
+            match self.limit() {
+                0 => Some(total),
+                limit if total <= limit => Some(total),
+                _ => None,
+            }
+        });
+        match grown {
+            Ok(used) => {
+                self.peak.fetch_max(used.saturating_add(bytes), Relaxed);
+                true
+            }
+            Err(_) => {
+                self.refusals.fetch_add(1, Relaxed);
+                false
+            }
+        }
+    }
+
+    fn shrink(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Relaxed);
+    }
+}
+
+/// One holder's share of a `MemoryBudget`, given back when dropped.
+pub struct Charge {
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl Charge {
+    /// An empty charge against `budget`.
+    pub fn new(budget: &Arc<MemoryBudget>) -> Charge {
+        Charge {
+            budget: Arc::clone(budget),
+            bytes: 0,
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The budget charged.
+    pub fn budget(&self) -> &Arc<MemoryBudget> {
+        &self.budget
+    }
+
+    /// Makes the charge `bytes`. Shrinking always works; growing fails,
+    /// leaving the charge as it was, when it would pass the limit.
+    pub fn set(&mut self, bytes: u64) -> bool {
+        if bytes > self.bytes {
+            if !self.budget.grow(bytes - self.bytes) {
+                return false;
+            }
+        } else {
+            self.budget.shrink(self.bytes - bytes);
+        }
+        self.bytes = bytes;
+        true
+    }
+
+    /// Adds `bytes`, as `set` does.
+    pub fn add(&mut self, bytes: u64) -> bool {
+        self.set(self.bytes + bytes)
+    }
+
+    pub fn sub(&mut self, bytes: u64) {
+        self.set(self.bytes.saturating_sub(bytes));
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.shrink(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{ConsumerKind, Footprint, FrameQueue, QueuePolicy};
+    use crate::stats::Stats;
+
+    fn budget(limit: u64) -> Arc<MemoryBudget> {
+        let budget = Arc::new(MemoryBudget::default());
+        budget.set_limit(limit);
+        budget
+    }
+
+    #[test]
+    fn growing_past_the_limit_is_refused() {
+        let budget = budget(100);
+        let mut charge = Charge::new(&budget);
+        assert!(charge.set(60));
+        assert!(!charge.add(41));
+        assert_eq!(
+            (charge.bytes(), budget.used(), budget.refusals()),
+            (60, 60, 1)
+        );
+        assert!(charge.add(40));
+        assert_eq!(budget.available(), 0);
+
+        // Shrinking always works, even under a lowered limit
+        budget.set_limit(10);
+        assert!(charge.set(50));
+        assert!(!charge.set(51));
+        assert_eq!(budget.used(), 50);
+
+        budget.set_limit(0);
+        assert_eq!(budget.available(), u64::MAX);
+        assert!(charge.set(1 << 40));
+    }
+
+    #[test]
+    fn peak_holds_until_reset() {
+        let budget = budget(1000);
+        let mut first = Charge::new(&budget);
+        let mut second = Charge::new(&budget);
+        assert!(first.set(300));
+        assert!(second.set(500));
+        second.sub(400);
+        assert!(!first.add(700));
+        assert_eq!(
+            (budget.used(), budget.peak(), budget.refusals()),
+            (400, 800, 1)
+        );
+
+        budget.reset();
+        assert_eq!((budget.peak(), budget.refusals()), (400, 0));
+    }
+
+    #[test]
+    fn dropping_a_charge_gives_it_back() {
+        let budget = budget(100);
+        let mut charge = Charge::new(&budget);
+        assert!(charge.set(80));
+        assert!(!Charge::new(&budget).set(30));
+        drop(charge);
+        assert_eq!(budget.used(), 0);
+        assert!(Charge::new(&budget).set(100));
+        assert_eq!(budget.used(), 0);
+    }
+
+    struct Frame(u64);
+
+    impl Footprint for Frame {
+        fn footprint(&self) -> u64 {
+            self.0
+        }
+    }
+
+    fn queue(policy: QueuePolicy, limit: u64) -> (FrameQueue<Frame>, Arc<Stats>) {
+        let stats = Arc::new(Stats::default());
+        stats.memory().set_limit(limit);
+        let queue = FrameQueue::new(ConsumerKind::Buffered, policy, 4, &stats);
+        (queue, stats)
+    }
+
+    /// Short of budget, a `LatestWins` queue pushes out its oldest frames,
+    /// and drops a frame that does not fit even once it is empty.
+    #[test]
+    fn a_short_budget_evicts_queued_frames() {
+        let (queue, stats) = queue(QueuePolicy::LatestWins, 250);
+        for _ in 0..3 {
+            queue.push(Frame(100), || false);
+        }
+        assert_eq!((stats.memory().used(), queue.dropped_total()), (200, 1));
+        queue.push(Frame(300), || false);
+        assert_eq!((stats.memory().used(), queue.dropped_total()), (0, 4));
+        assert!(stats.memory().peak() <= 250);
+        assert!(queue.take_latest().is_none());
+    }
+
+    /// A `Block` queue waits for the consumer to make room instead.
+    #[test]
+    fn a_short_budget_holds_up_a_blocking_queue() {
+        let (queue, stats) = queue(QueuePolicy::Block, 250);
+        queue.push(Frame(100), || false);
+        queue.push(Frame(100), || false);
+        // Gives up straight away rather than wait
+        queue.push(Frame(100), || true);
+        assert_eq!((stats.memory().used(), queue.dropped_total()), (200, 0));
+
+        assert!(queue.pop_unless(|| false).is_some());
+        queue.push(Frame(100), || true);
+        assert_eq!(stats.memory().used(), 200);
+
+        // Once empty, the queue drops a frame too large for the budget
+        assert!(queue.take_latest().is_some());
+        queue.push(Frame(300), || true);
+        assert_eq!((stats.memory().used(), queue.dropped_total()), (0, 2));
+    }
+}
@@ -0,0 +1,178 @@
+//! Rotating and mirroring captured frames, e.g. for a monitor that is
+//! physically turned on its side. Runs on the tightly packed BGRA frame
+//! right after the crop, so everything downstream (change detection, dirty
+//! tracking, resizing, the reported size and cursor) sees the frame the
+//! way the user does.
+
+/// How a captured frame is turned before it is resized.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    /// As captured.
+    Normal = 0,
+    /// A quarter turn clockwise.
+    Rotate90 = 1,
+    Rotate180 = 2,
+    /// A quarter turn anticlockwise.
+    Rotate270 = 3,
+    /// Mirrored left to right.
+    FlipH = 4,
+    /// Mirrored top to bottom.
+    FlipV = 5,
+}
+
+impl Orientation {
+    /// Maps an FFI orientation value back onto the enum.
+    pub fn from_u32(value: u32) -> Option<Orientation> {
+        match value {
+            0 => Some(Orientation::Normal),
+            1 => Some(Orientation::Rotate90),
+            2 => Some(Orientation::Rotate180),
+            3 => Some(Orientation::Rotate270),
+            4 => Some(Orientation::FlipH),
+            5 => Some(Orientation::FlipV),
+            _ => None,
+        }
+    }
+
+    /// Size of a `w x h` frame once turned.
+    pub fn size(self, w: u32, h: u32) -> (u32, u32) {
+        match self {
+            Orientation::Rotate90 | Orientation::Rotate270 => (h, w),
+            _ => (w, h),
+        }
+    }
+
+    /// Where the pixel at (`x`, `y`) of a `w x h` frame ends up once turned.
+    pub fn map_point(self, (x, y): (i32, i32), w: u32, h: u32) -> (i32, i32) {
+        let (right, bottom) = (w as i32 - 1, h as i32 - 1);
+        match self {
+            Orientation::Normal => (x, y),
+            Orientation::Rotate90 => (bottom - y, x),
+            Orientation::Rotate180 => (right - x, bottom - y),
+            Orientation::Rotate270 => (y, right - x),
+            Orientation::FlipH => (right - x, y),
+            Orientation::FlipV => (x, bottom - y),
+        }
+    }
+}
+
+/// Edge length of the square tiles a quarter turn copies at a time: small
+/// enough that a tile's source and destination rows all stay in L1.
+const BLOCK: usize = 32;
+
+/// Writes the tightly packed `w x h` BGRA frame `src`, turned by
+/// `orientation`, into `dst` (grown as needed, at `orientation.size()`).
+pub fn apply(src: &[u8], w: u32, h: u32, orientation: Orientation, dst: &mut Vec<u8>) {
+    let (w, h) = (w as usize, h as usize);
+    dst.resize(w * h * 4, 0);
+    let (src, _) = src[..w * h * 4].as_chunks::<4>();
+    let (out, _) = dst.as_chunks_mut::<4>();
+    match orientation {
+        Orientation::Normal => out.copy_from_slice(src),
+        Orientation::Rotate180 => {
+            for (o, s) in out.iter_mut().zip(src.iter().rev()) {
+                *o = *s;
+            }
+        }
+        Orientation::FlipH => {
+            for (out_row, src_row) in out.chunks_exact_mut(w).zip(src.chunks_exact(w)) {
+                for (o, s) in out_row.iter_mut().zip(src_row.iter().rev()) {
+                    *o = *s;
+                }
+            }
+        }
+        Orientation::FlipV => {
+            for (out_row, src_row) in out.chunks_exact_mut(w).zip(src.chunks_exact(w).rev()) {
+                out_row.copy_from_slice(src_row);
+            }
+        }
+        Orientation::Rotate90 => quarter_turn(src, w, h, true, out),
+        Orientation::Rotate270 => quarter_turn(src, w, h, false, out),
+    }
+}
+
+/// Turns `src` (`w x h`) into `out` (`h x w`) tile by tile. Going row by
+/// row instead would walk one side of the copy a whole row apart on every
+/// pixel, missing the cache each time.
+fn quarter_turn(src: &[[u8; 4]], w: usize, h: usize, clockwise: bool, out: &mut [[u8; 4]]) {
+    for tile_y in (0..h).step_by(BLOCK) {
+        for tile_x in (0..w).step_by(BLOCK) {
+            for y in tile_y..(tile_y + BLOCK).min(h) {
+                let row = &src[y * w..(y + 1) * w];
+                let out_x = if clockwise { h - 1 - y } else { y };
+                let end = (tile_x + BLOCK).min(w);
+                for (x, &px) in (tile_x..end).zip(&row[tile_x..end]) {
+                    let out_y = if clockwise { x } else { w - 1 - x };
+                    out[out_y * h + out_x] = px;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Orientation; 6] = [
+        Orientation::Normal,
+        Orientation::Rotate90,
+        Orientation::Rotate180,
+        Orientation::Rotate270,
+        Orientation::FlipH,
+        Orientation::FlipV,
+    ];
+
+    /// A `w x h` frame whose every pixel holds its own coordinates, so any
+    /// pixel out of place shows.
+    fn numbered(w: u32, h: u32) -> Vec<u8> {
+        (0..h)
+            .flat_map(|y| {
+                (0..w).flat_map(move |x| [x as u8, (x >> 8) as u8, y as u8, (y >> 8) as u8])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_pixel_lands_where_map_point_says() {
+        // Neither side a multiple of the block, and one over a block
+        for (w, h) in [(37, 70), (70, 37), (1, 5), (BLOCK as u32 + 1, 3)] {
+            let src = numbered(w, h);
+            for orientation in ALL {
+                let mut out = Vec::new();
+                apply(&src, w, h, orientation, &mut out);
+                let (out_w, _) = orientation.size(w, h);
+                for y in 0..h {
+                    for x in 0..w {
+                        let (ox, oy) = orientation.map_point((x as i32, y as i32), w, h);
+                        let at = (oy as usize * out_w as usize + ox as usize) * 4;
+                        let from = (y as usize * w as usize + x as usize) * 4;
+                        assert_eq!(
+                            out[at..at + 4],
+                            src[from..from + 4],
+                            "{orientation:?} of {w}x{h} at ({x}, {y})"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quarter_turns_swap_the_size_and_undo_each_other() {
+        let (w, h) = (37, 70);
+        assert_eq!(Orientation::Rotate90.size(w, h), (h, w));
+        assert_eq!(Orientation::Rotate270.size(w, h), (h, w));
+        assert_eq!(Orientation::FlipH.size(w, h), (w, h));
+
+        let src = numbered(w, h);
+        let (mut turned, mut back) = (Vec::new(), Vec::new());
+        apply(&src, w, h, Orientation::Rotate90, &mut turned);
+        apply(&turned, h, w, Orientation::Rotate270, &mut back);
+        assert_eq!(back, src);
+        apply(&src, w, h, Orientation::Rotate180, &mut turned);
+        apply(&turned, w, h, Orientation::Rotate180, &mut back);
+        assert_eq!(back, src);
+    }
+}
@@ -0,0 +1,355 @@
+//! Text drawn into output frames, such as the watermark. Text is set in a
+//! built-in 5x7 bitmap font covering printable ASCII (other characters
+//! show as `?`, common dashes and quotes as their ASCII look-alikes), in
+//! white over a dark shadow so it reads on any background. It is drawn
+//! into the frame at its output resolution, with the glyphs scaled to the
+//! output height, so it stays legible however far frames are downscaled.
+//! The debug counters (capture timestamp and sequence number) use large
+//! 7-segment digits on a black panel instead, which draw without
+//! allocating.
+
+/// Where on the frame text is placed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl Anchor {
+    /// Maps the FFI position value (0 = top left, 1 = top right, 2 = bottom
+    /// left, 3 = bottom right, 4 = center) onto the enum.
+    pub fn from_i32(value: i32) -> Option<Anchor> {
+        match value {
+            0 => Some(Anchor::TopLeft),
+            1 => Some(Anchor::TopRight),
+            2 => Some(Anchor::BottomLeft),
+            3 => Some(Anchor::BottomRight),
+            4 => Some(Anchor::Center),
+            _ => None,
+        }
+    }
+}
+
+/// Text to draw on every frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextOverlay {
+    /// May span several lines.
+    pub text: String,
+    pub anchor: Anchor,
+    /// 0 (invisible) to 255 (opaque).
+    pub opacity: u8,
+}
+
+const GLYPH_W: u32 = 5;
+const GLYPH_H: u32 = 7;
+/// Glyph plus spacing, in font pixels.
+const ADVANCE: u32 = GLYPH_W + 1;
+const LINE_HEIGHT: u32 = GLYPH_H + 2;
+/// Distance from the frame edges, in font pixels.
+const MARGIN: u32 = 2;
+/// Output rows per font pixel: 1 up to 539 rows, 2 at 720p, 4 at 1080p.
+const ROWS_PER_SCALE: u32 = 270;
+
+/// Draws `overlay` into the tightly packed `width x height` frame `pixels`
+/// (BGRA, or luma when `bpp` is 1).
+pub fn draw(pixels: &mut [u8], width: u32, height: u32, bpp: usize, overlay: &TextOverlay) {
+    let scale = (height / ROWS_PER_SCALE).max(1);
+    let lines: Vec<Vec<&[u8; 7]>> = overlay
+        .text
+        .lines()
+        .map(|line| line.chars().map(glyph).collect())
+        .collect();
+    if lines.iter().all(Vec::is_empty) || overlay.opacity == 0 {
+        return;
+    }
+    let block_h = (lines.len() as u32 * LINE_HEIGHT - 2) * scale;
+    let margin = MARGIN * scale;
+    let (w, h) = (i64::from(width), i64::from(height));
+    let (bh, m) = (i64::from(block_h), i64::from(margin));
+    let top = match overlay.anchor {
+        Anchor::TopLeft | Anchor::TopRight => m,
+        Anchor::BottomLeft | Anchor::BottomRight => h - m - bh,
+        Anchor::Center => (h - bh) / 2,
+    };
+
+    let mut canvas = Canvas {
+        pixels,
+        width: w,
+        height: h,
+        bpp,
+        scale: i64::from(scale),
+    };
+    // Shadow first, one font pixel down and right, then the text over it
+    for (shade, offset, alpha) in [
+        (0u8, 1, overlay.opacity / 2 + overlay.opacity / 4),
+        (0xff, 0, overlay.opacity),
+    ] {
+        for (row, line) in lines.iter().enumerate() {
+            let line_w = (line.len() as i64 * i64::from(ADVANCE) - 1) * i64::from(scale);
+            let left = match overlay.anchor {
+                Anchor::TopLeft | Anchor::BottomLeft => m,
+                Anchor::TopRight | Anchor::BottomRight => w - m - line_w,
+                Anchor::Center => (w - line_w) / 2,
+            };
+            let y = top + (row as i64 * i64::from(LINE_HEIGHT) + offset) * i64::from(scale);
+            for (col, rows) in line.iter().enumerate() {
+                let x = left + (col as i64 * i64::from(ADVANCE) + offset) * i64::from(scale);
+                canvas.glyph(rows, x, y, shade, alpha);
+            }
+        }
+    }
+}
+
+/// Segments of a 7-segment digit as (x, y, w, h) in segment widths: top,
+/// top right, bottom right, bottom, bottom left, top left, middle.
+const SEGMENTS: [(i64, i64, i64, i64); 7] = [
+    (0, 0, 4, 1),
+    (3, 0, 1, 4),
+    (3, 3, 1, 4),
+    (0, 6, 4, 1),
+    (0, 3, 1, 4),
+    (0, 0, 1, 4),
+    (0, 3, 4, 1),
+];
+/// Lit segments of 0-9, bit 0 being the top one.
+const DIGITS: [u8; 10] = [0x3f, 0x06, 0x5b, 0x4f, 0x66, 0x6d, 0x7d, 0x07, 0x7f, 0x6f];
+/// Digit plus spacing, and row plus spacing, in segment widths.
+const DIGIT_ADVANCE: i64 = 6;
+const DIGIT_LINE: i64 = 9;
+/// Output rows per segment width: 8 at 1080p, never fewer than 2.
+const ROWS_PER_SEGMENT: u32 = 135;
+
+/// Draws `timestamp_ms` over `sequence` in the top left corner of the
+/// tightly packed `width x height` frame `pixels` (BGRA, or luma when
+/// `bpp` is 1), white on black.
+pub fn draw_counters(
+    pixels: &mut [u8],
+    width: u32,
+    height: u32,
+    bpp: usize,
+    timestamp_ms: u64,
+    sequence: u64,
+) {
+    let unit = i64::from((height / ROWS_PER_SEGMENT).max(2));
+    let (mut timestamp_buf, mut sequence_buf) = ([0; 20], [0; 20]);
+    let rows = [
+        decimal(timestamp_ms, &mut timestamp_buf),
+        decimal(sequence, &mut sequence_buf),
+    ];
+    let digits = rows.iter().map(|r| r.len()).max().unwrap_or(0) as i64;
+
+    let mut canvas = Canvas {
+        pixels,
+        width: i64::from(width),
+        height: i64::from(height),
+        bpp,
+        scale: unit,
+    };
+    // One segment width of padding around the digits
+    let panel_w = digits * DIGIT_ADVANCE * unit;
+    let panel_h = (rows.len() as i64 * DIGIT_LINE) * unit;
+    canvas.fill(0, 0, panel_w, panel_h, 0);
+    for (row, digits) in rows.iter().enumerate() {
+        let y = (1 + row as i64 * DIGIT_LINE) * unit;
+        for (col, &digit) in digits.iter().enumerate() {
+            let x = (1 + col as i64 * DIGIT_ADVANCE) * unit;
+            let lit = DIGITS[usize::from(digit)];
+            for (i, &(sx, sy, sw, sh)) in SEGMENTS.iter().enumerate() {
+                if lit >> i & 1 == 1 {
+                    canvas.fill(x + sx * unit, y + sy * unit, sw * unit, sh * unit, 0xff);
+                }
+            }
+        }
+    }
+}
+
+/// The decimal digits (as values 0-9) of `value`, most significant first.
+fn decimal(mut value: u64, buf: &mut [u8; 20]) -> &[u8] {
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            return &buf[start..];
+        }
+    }
+}
+
+struct Canvas<'a> {
+    pixels: &'a mut [u8],
+    width: i64,
+    height: i64,
+    bpp: usize,
+    scale: i64,
+}
+
+impl Canvas<'_> {
+    /// Blends the lit pixels of `rows` at (`x`, `y`), clipped to the frame.
+    fn glyph(&mut self, rows: &[u8; 7], x: i64, y: i64, shade: u8, alpha: u8) {
+        for (gy, bits) in rows.iter().enumerate() {
+            for gx in 0..GLYPH_W as i64 {
+                if bits >> (GLYPH_W as i64 - 1 - gx) & 1 == 1 {
+                    let (px, py) = (x + gx * self.scale, y + gy as i64 * self.scale);
+                    self.block(px, py, shade, alpha);
+                }
+            }
+        }
+    }
+
+    /// Paints the `w x h` rectangle at (`x`, `y`) solid `shade`, clipped to
+    /// the frame.
+    fn fill(&mut self, x: i64, y: i64, w: i64, h: i64, shade: u8) {
+        let (x0, x1) = (x.max(0), (x + w).min(self.width));
+        let (y0, y1) = (y.max(0), (y + h).min(self.height));
+        if x0 >= x1 {
+            return;
+        }
+        for py in y0..y1 {
+            let row = py as usize * self.width as usize;
+            let span =
+                &mut self.pixels[(row + x0 as usize) * self.bpp..(row + x1 as usize) * self.bpp];
+            if self.bpp == 1 {
+                span.fill(shade);
+            } else {
+                for px in span.chunks_exact_mut(4) {
+                    px.copy_from_slice(&[shade, shade, shade, 0xff]);
+                }
+            }
+        }
+    }
+
+    /// One font pixel: a `scale`-sided square.
+    fn block(&mut self, x: i64, y: i64, shade: u8, alpha: u8) {
+        let (x0, x1) = (x.max(0), (x + self.scale).min(self.width));
+        let (y0, y1) = (y.max(0), (y + self.scale).min(self.height));
+        let channels = self.bpp.min(3);
+        let (a, keep) = (u32::from(alpha), 255 - u32::from(alpha));
+        for py in y0..y1 {
+            let row = py as usize * self.width as usize;
+            for px in x0..x1 {
+                let at = (row + px as usize) * self.bpp;
+                for c in &mut self.pixels[at..at + channels] {
+                    *c = ((u32::from(*c) * keep + u32::from(shade) * a + 127) / 255) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// The bitmap of `c`, rows top to bottom, bit 4 leftmost.
+fn glyph(c: char) -> &'static [u8; 7] {
+    let c = match c {
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => '-',
+        '\u{2018}' | '\u{2019}' => '\'',
+        '\u{201c}' | '\u{201d}' => '"',
+        '\t' => ' ',
+        c => c,
+    };
+    match c {
+        ' '..='~' => &FONT[c as usize - 0x20],
+        _ => &FONT[usize::from(b'?' - 0x20)],
+    }
+}
+
+/// Printable ASCII from `' '` to `'~'`.
+const FONT: [[u8; 7]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04], // !
+    [0x0a, 0x0a, 0x0a, 0x00, 0x00, 0x00, 0x00], // "
+    [0x0a, 0x0a, 0x1f, 0x0a, 0x1f, 0x0a, 0x0a], // #
+    [0x04, 0x0f, 0x14, 0x0e, 0x05, 0x1e, 0x04], // $
+    [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03], // %
+    [0x0c, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0d], // &
+    [0x04, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00], // '
+    [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02], // (
+    [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08], // )
+    [0x00, 0x04, 0x15, 0x0e, 0x15, 0x04, 0x00], // *
+    [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00], // +
+    [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08], // ,
+    [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00], // -
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c], // .
+    [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00], // /
+    [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e], // 0
+    [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e], // 1
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f], // 2
+    [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e], // 3
+    [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02], // 4
+    [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e], // 5
+    [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e], // 6
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08], // 7
+    [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e], // 8
+    [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c], // 9
+    [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00], // :
+    [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x04, 0x08], // ;
+    [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02], // <
+    [0x00, 0x00, 0x1f, 0x00, 0x1f, 0x00, 0x00], // =
+    [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08], // >
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // ?
+    [0x0e, 0x11, 0x01, 0x0d, 0x15, 0x15, 0x0e], // @
+    [0x0e, 0x11, 0x11, 0x11, 0x1f, 0x11, 0x11], // A
+    [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e], // B
+    [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e], // C
+    [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c], // D
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f], // E
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10], // F
+    [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f], // G
+    [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11], // H
+    [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // I
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c], // J
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11], // K
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f], // L
+    [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11], // M
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11], // N
+    [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // O
+    [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10], // P
+    [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d], // Q
+    [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11], // R
+    [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e], // S
+    [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // T
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // U
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04], // V
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a], // W
+    [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11], // X
+    [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04], // Y
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f], // Z
+    [0x0e, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0e], // [
+    [0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00], // \\
+    [0x0e, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0e], // ]
+    [0x04, 0x0a, 0x11, 0x00, 0x00, 0x00, 0x00], // ^
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f], // _
+    [0x08, 0x04, 0x02, 0x00, 0x00, 0x00, 0x00], // `
+    [0x00, 0x00, 0x0e, 0x01, 0x0f, 0x11, 0x0f], // a
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x1e], // b
+    [0x00, 0x00, 0x0e, 0x10, 0x10, 0x11, 0x0e], // c
+    [0x01, 0x01, 0x0d, 0x13, 0x11, 0x11, 0x0f], // d
+    [0x00, 0x00, 0x0e, 0x11, 0x1f, 0x10, 0x0e], // e
+    [0x06, 0x09, 0x08, 0x1c, 0x08, 0x08, 0x08], // f
+    [0x00, 0x0f, 0x11, 0x11, 0x0f, 0x01, 0x0e], // g
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x11], // h
+    [0x04, 0x00, 0x0c, 0x04, 0x04, 0x04, 0x0e], // i
+    [0x02, 0x00, 0x06, 0x02, 0x02, 0x12, 0x0c], // j
+    [0x10, 0x10, 0x12, 0x14, 0x18, 0x14, 0x12], // k
+    [0x0c, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // l
+    [0x00, 0x00, 0x1a, 0x15, 0x15, 0x11, 0x11], // m
+    [0x00, 0x00, 0x16, 0x19, 0x11, 0x11, 0x11], // n
+    [0x00, 0x00, 0x0e, 0x11, 0x11, 0x11, 0x0e], // o
+    [0x00, 0x00, 0x1e, 0x11, 0x1e, 0x10, 0x10], // p
+    [0x00, 0x00, 0x0d, 0x13, 0x0f, 0x01, 0x01], // q
+    [0x00, 0x00, 0x16, 0x19, 0x10, 0x10, 0x10], // r
+    [0x00, 0x00, 0x0e, 0x10, 0x0e, 0x01, 0x1e], // s
+    [0x08, 0x08, 0x1c, 0x08, 0x08, 0x09, 0x06], // t
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x13, 0x0d], // u
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x0a, 0x04], // v
+    [0x00, 0x00, 0x11, 0x11, 0x15, 0x15, 0x0a], // w
+    [0x00, 0x00, 0x11, 0x0a, 0x04, 0x0a, 0x11], // x
+    [0x00, 0x00, 0x11, 0x11, 0x0f, 0x01, 0x0e], // y
+    [0x00, 0x00, 0x1f, 0x02, 0x04, 0x08, 0x1f], // z
+    [0x02, 0x04, 0x04, 0x08, 0x04, 0x04, 0x02], // {
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // |
+    [0x08, 0x04, 0x04, 0x02, 0x04, 0x04, 0x08], // }
+    [0x00, 0x00, 0x08, 0x15, 0x02, 0x00, 0x00], // ~
+];
@@ -0,0 +1,241 @@
+//! Frame pacing: holding frames back to a target rate, and measuring the
+//! rate actually achieved.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How far ahead of a deadline sleeping stops and yielding takes over;
+/// OS timers can overshoot by about this much (more on Windows).
+const SPIN_MARGIN: Duration = Duration::from_millis(2);
+
+/// Weight of the newest frame interval in `FpsMeter`'s running average.
+const SMOOTHING: f64 = 0.1;
+
+/// Frame interval at `fps`, or `None` for 0 (unpaced).
+pub fn interval(fps: u32) -> Option<Duration> {
+    (fps > 0).then(|| Duration::from_secs(1) / fps)
+}
+
+/// Schedules frame starts one interval apart, so whatever capture and
+/// encoding leave of a frame's budget is slept away before the next one but
+/// no frame is held back once it is done. A frame that overruns its budget
+/// starts the next one right away and the schedule restarts from there, so
+/// a slow frame never makes the following ones rush to catch up.
+#[derive(Default)]
+pub struct Pacer {
+    last: Option<Instant>,
+}
+
+impl Pacer {
+    /// When the next frame is due; `now` (or earlier) when it already is.
+    pub fn due(&self, interval: Option<Duration>) -> Instant {
+        match (self.last, interval) {
+            (Some(last), Some(interval)) => last + interval,
+            _ => Instant::now(),
+        }
+    }
+
+    /// Records that a frame started at `at`.
+    pub fn mark(&mut self, at: Instant) {
+        self.last = Some(at);
+    }
+
+    /// Sleeps until the next frame is due (or `interrupted` says to stop
+    /// waiting) and marks it started. An on-time start is recorded at its
+    /// due time rather than when the sleep ended, so wake-up latency does
+    /// not add up into drift.
+    pub fn wait(&mut self, interval: Option<Duration>, interrupted: impl Fn() -> bool) {
+        let due = self.due(interval);
+        let now = Instant::now();
+        if due > now {
+            sleep_until(due, interrupted);
+            self.mark(due);
+        } else {
+            self.mark(now);
+        }
+    }
+}
+
+/// Sleeps until `deadline` or until `interrupted` returns true, whichever
+/// comes first. Most of the wait is a `park_timeout` (so `Thread::unpark`
+/// cuts it short); the last `SPIN_MARGIN` is spent yielding, which keeps
+/// the wake-up within a fraction of a millisecond of the deadline.
+pub fn sleep_until(deadline: Instant, interrupted: impl Fn() -> bool) {
+    while !interrupted() {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        let remaining = deadline - now;
+        if remaining > SPIN_MARGIN {
+            thread::park_timeout(remaining - SPIN_MARGIN);
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Bounds of the exponential sleep of `WaitStrategy::Backoff`.
+pub const MIN_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_BACKOFF: Duration = Duration::from_millis(16);
+
+/// How a capture waits between polls that find no new frame, trading
+/// latency against CPU time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Sleeps 1 ms after the first empty poll, doubling up to 16 ms: cheap
+    /// while nothing changes on screen, but a new frame may wait up to a
+    /// nap for its poll.
+    #[default]
+    Backoff,
+    /// Sleeps `ms` between polls.
+    Sleep { ms: u32 },
+    /// Yields the thread between polls: the frame is picked up almost as
+    /// soon as it exists, for a core kept busy while waiting.
+    Yield,
+    /// Polls back to back for `spin_us` microseconds, for frames that are
+    /// about due, then sleeps `sleep_ms` between polls.
+    SpinThenSleep { spin_us: u32, sleep_ms: u32 },
+    /// Waits inside the capture backend, for up to `ms` a poll (0 = the
+    /// rest of the capture timeout), until the OS signals a new frame: DXGI
+    /// desktop duplication's `AcquireNextFrame` timeout on Windows. Other
+    /// backends have no such signal and sleep `ms` between polls instead
+    /// (`Backoff` for 0).
+    Signal { ms: u32 },
+}
+
+impl WaitStrategy {
+    /// Maps the FFI values (0 = backoff, 1 = sleep `sleep_ms`, 2 = yield,
+    /// 3 = spin `spin_us` then sleep `sleep_ms`, 4 = wait for the backend's
+    /// signal up to `sleep_ms`) onto the enum.
+    pub fn from_ffi(kind: u32, spin_us: u32, sleep_ms: u32) -> Option<WaitStrategy> {
+        match kind {
+            0 => Some(WaitStrategy::Backoff),
+            1 => Some(WaitStrategy::Sleep { ms: sleep_ms }),
+            2 => Some(WaitStrategy::Yield),
+            3 => Some(WaitStrategy::SpinThenSleep { spin_us, sleep_ms }),
+            4 => Some(WaitStrategy::Signal { ms: sleep_ms }),
+            _ => None,
+        }
+    }
+
+    /// The `kind` of `from_ffi`.
+    pub fn kind(self) -> u32 {
+        match self {
+            WaitStrategy::Backoff => 0,
+            WaitStrategy::Sleep { .. } => 1,
+            WaitStrategy::Yield => 2,
+            WaitStrategy::SpinThenSleep { .. } => 3,
+            WaitStrategy::Signal { .. } => 4,
+        }
+    }
+
+    /// How long a backend that can wait for its next frame should, with
+    /// `remaining` of the capture timeout left; `None` to poll once.
+    pub fn signal_timeout(self, remaining: Duration) -> Option<Duration> {
+        match self {
+            WaitStrategy::Signal { ms: 0 } => Some(remaining),
+            WaitStrategy::Signal { ms } => {
+                Some(remaining.min(Duration::from_millis(u64::from(ms))))
+            }
+            _ => None,
+        }
+    }
+
+    /// Waits once after an empty poll, `waited` into the capture and for at
+    /// most `remaining`. `backoff` is the next `Backoff` nap, starting at
+    /// `MIN_BACKOFF`.
+    pub fn wait(self, backoff: &mut Duration, waited: Duration, remaining: Duration) {
+        let nap = match self {
+            WaitStrategy::Backoff | WaitStrategy::Signal { ms: 0 } => {
+                let nap = *backoff;
+                *backoff = (*backoff * 2).min(MAX_BACKOFF);
+                nap
+            }
+            WaitStrategy::Sleep { ms } | WaitStrategy::Signal { ms } => {
+                Duration::from_millis(u64::from(ms))
+            }
+            WaitStrategy::Yield => {
+                thread::yield_now();
+                return;
+            }
+            WaitStrategy::SpinThenSleep { spin_us, sleep_ms } => {
+                if waited < Duration::from_micros(u64::from(spin_us)) {
+                    std::hint::spin_loop();
+                    return;
+                }
+                Duration::from_millis(u64::from(sleep_ms))
+            }
+        };
+        thread::sleep(nap.min(remaining));
+    }
+}
+
+/// Running average of the rate frames are produced at.
+#[derive(Default)]
+pub struct FpsMeter {
+    last: Option<Instant>,
+    /// Smoothed seconds between frames; 0 until two frames were seen.
+    average: f64,
+}
+
+impl FpsMeter {
+    pub fn record(&mut self, at: Instant) {
+        if let Some(last) = self.last {
+            let elapsed = at.duration_since(last).as_secs_f64();
+            self.average = if self.average == 0.0 {
+                elapsed
+            } else {
+                self.average + SMOOTHING * (elapsed - self.average)
+            };
+        }
+        self.last = Some(at);
+    }
+
+    /// Frames per second over the recent frames, 0 before the second one.
+    pub fn fps(&self) -> f64 {
+        if self.average > 0.0 {
+            1.0 / self.average
+        } else {
+            0.0
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = FpsMeter::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal_waits_are_capped_by_the_capture_timeout() {
+        let remaining = Duration::from_millis(40);
+        let signal = |ms| WaitStrategy::Signal { ms }.signal_timeout(remaining);
+        assert_eq!(signal(0), Some(remaining));
+        assert_eq!(signal(16), Some(Duration::from_millis(16)));
+        assert_eq!(signal(100), Some(remaining));
+        for other in [
+            WaitStrategy::Backoff,
+            WaitStrategy::Yield,
+            WaitStrategy::Sleep { ms: 5 },
+        ] {
+            assert_eq!(other.signal_timeout(remaining), None, "{other:?} polls");
+        }
+    }
+
+    #[test]
+    fn ffi_values_round_trip() {
+        for kind in 0..5 {
+            let strategy = WaitStrategy::from_ffi(kind, 50, 7).unwrap();
+            assert_eq!(strategy.kind(), kind);
+        }
+        assert_eq!(
+            WaitStrategy::from_ffi(4, 50, 7),
+            Some(WaitStrategy::Signal { ms: 7 })
+        );
+        assert_eq!(WaitStrategy::from_ffi(5, 0, 0), None);
+    }
+}
@@ -0,0 +1,89 @@
+//! The thread pool that splits one frame's encoding across cores (see
+//! `tiles::encode_bands`), shared by every session.
+//!
+//! Encoders report failures through `fail`, which records the message on
+//! the thread it runs on; `map` carries it back to the calling thread, so
+//! `rdp_last_error_message` works as if the encode had run there.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use rayon::ThreadPool;
+use rayon::prelude::*;
+
+use crate::error::{self, RdpStatus, fail};
+
+/// The pool, built on first use with `default_threads` threads.
+static POOL: Mutex<Option<Arc<ThreadPool>>> = Mutex::new(None);
+
+/// One thread per physical core but one, leaving a core to capture the
+/// next frame while this one encodes; at least one. Hyper-threads are not
+/// counted: two encoders sharing a core's SIMD units gain little and would
+/// take the capture thread's sibling.
+pub fn default_threads() -> usize {
+    num_cpus::get_physical().saturating_sub(1).max(1)
+}
+
+/// Replaces the pool with one of `threads` threads, or `default_threads`
+/// for 0. Encodes already running finish on the old pool.
+pub fn set_threads(threads: usize) -> Result<(), RdpStatus> {
+    let pool = build(threads)?;
+    *POOL.lock().unwrap_or_else(PoisonError::into_inner) = Some(pool);
+    Ok(())
+}
+
+/// The size of the current pool.
+pub fn threads() -> usize {
+    pool().map_or(0, |pool| pool.current_num_threads())
+}
+
+fn pool() -> Result<Arc<ThreadPool>, RdpStatus> {
+    let mut pool = POOL.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(pool) = &*pool {
+        return Ok(Arc::clone(pool));
+    }
+    let built = build(0)?;
+    *pool = Some(Arc::clone(&built));
+    Ok(built)
+}
+
+fn build(threads: usize) -> Result<Arc<ThreadPool>, RdpStatus> {
+    let threads = match threads {
+        0 => default_threads(),
+        threads => threads,
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("rdp-encode-{i}"))
+        .build()
+        .map(Arc::new)
+        .map_err(|e| {
+            fail(
+                RdpStatus::Unsupported,
+                format!("Cannot start {threads} encoder threads: {e}"),
+            )
+        })
+}
+
+/// Runs `f` on every item on the pool, returning the results in order, or
+/// the first failure with its message moved to this thread. A single item
+/// runs on this thread, without starting the pool.
+pub fn map<T, R>(
+    items: &[T],
+    f: impl Fn(&T) -> Result<R, RdpStatus> + Sync,
+) -> Result<Vec<R>, RdpStatus>
+where
+    T: Sync,
+    R: Send,
+{
+    if items.len() < 2 {
+        return items.iter().map(f).collect();
+    }
+    let pool = pool()?;
+    pool.install(|| {
+        items
+            .par_iter()
+            .map(|item| f(item).map_err(|status| (status, error::last_error())))
+            .collect::<Result<Vec<R>, _>>()
+    })
+    .map_err(|(status, message)| error::restore_last_error(status, message))
+}
@@ -0,0 +1,209 @@
+//! A synthetic frame source, `Backend::Test`, so the whole pipeline runs
+//! without a display (in CI, say). Frames are generated at the size and
+//! refresh rate last set with `configure` (`rdp_set_test_pattern`), and
+//! each is fully determined by its number:
+//!
+//! - the top two thirds hold 75% SMPTE color bars (`BARS`), which never
+//!   change;
+//! - the bottom third holds a horizontal color gradient that slides left by
+//!   `GRADIENT_STEP` pixels a frame, so change detection and delta encoding
+//!   always have work to do;
+//! - the frame number is burned into the top left corner as `COUNTER_BITS`
+//!   square cells of `cell_size` pixels, most significant bit first, white
+//!   for 1 and black for 0.
+//!
+//! Frame `n` is the `n`-th refresh since the source was opened, so a caller
+//! polling slower than the refresh rate sees gaps in the numbers, as it
+//! would on a real display; a rate of 0 makes every poll a new frame. Rows
+//! are padded past the pixels with `PADDING` filler bytes, as DXGI and
+//! Quartz pad theirs, so the stride repack is exercised too.
+
+use std::io;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+use crate::capture::{Backend, Frame, FrameSource};
+use crate::error::{RdpStatus, fail};
+
+pub const DEFAULT_WIDTH: u32 = 1280;
+pub const DEFAULT_HEIGHT: u32 = 720;
+pub const DEFAULT_FPS: u32 = 60;
+
+/// Smallest width and height: room for the counter at one pixel a cell.
+const MIN_SIZE: u32 = COUNTER_BITS;
+const MAX_SIZE: u32 = 8192;
+const MAX_FPS: u32 = 1000;
+
+/// Cells of the burned-in frame number.
+pub const COUNTER_BITS: u32 = 32;
+
+/// Pixels the gradient moves by from one frame to the next.
+pub const GRADIENT_STEP: u32 = 4;
+
+/// Filler bytes past the end of every row.
+pub const PADDING: usize = 64;
+const PADDING_BYTE: u8 = 0xa5;
+
+/// The bars, left to right, as RGB: white, yellow, cyan, green, magenta,
+/// red and blue at 75%.
+pub const BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [191, 191, 0],
+    [0, 191, 191],
+    [0, 191, 0],
+    [191, 0, 191],
+    [191, 0, 0],
+    [0, 0, 191],
+];
+
+#[derive(Clone, Copy)]
+struct Settings {
+    width: u32,
+    height: u32,
+    fps: u32,
+}
+
+/// What sources opened from now on generate.
+static SETTINGS: Mutex<Settings> = Mutex::new(Settings {
+    width: DEFAULT_WIDTH,
+    height: DEFAULT_HEIGHT,
+    fps: DEFAULT_FPS,
+});
+
+/// Sets the size and refresh rate of test sources opened from now on.
+/// Open test sessions switch to the new size at their next display check,
+/// as after a change of resolution.
+pub fn configure(width: u32, height: u32, fps: u32) -> Result<(), RdpStatus> {
+    let sizes = MIN_SIZE..=MAX_SIZE;
+    if !sizes.contains(&width) || !sizes.contains(&height) {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            format!("Test pattern size {width}x{height} is outside {MIN_SIZE} to {MAX_SIZE}"),
+        ));
+    }
+    if fps > MAX_FPS {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            format!("Test pattern rate {fps} is above {MAX_FPS} fps"),
+        ));
+    }
+    *lock() = Settings { width, height, fps };
+    Ok(())
+}
+
+/// The size sources opened now get.
+pub fn size() -> (usize, usize) {
+    let settings = *lock();
+    (settings.width as usize, settings.height as usize)
+}
+
+/// Side of a counter cell in a `width`-pixel-wide frame: the counter
+/// spans at most half of it, with cells of 1 to 16 pixels.
+pub fn cell_size(width: u32) -> u32 {
+    (width / COUNTER_BITS / 2).clamp(1, 16)
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Settings> {
+    SETTINGS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub struct TestPatternSource {
+    width: usize,
+    height: usize,
+    fps: u32,
+    stride: usize,
+    /// The current frame, bars drawn once when opened.
+    pixels: Vec<u8>,
+    /// One row of the gradient, twice over, so any frame's row is a slice.
+    gradient: Vec<u8>,
+    opened: Instant,
+    /// Number of the next frame to hand out.
+    next: u64,
+}
+
+impl TestPatternSource {
+    /// A source with the settings last given to `configure`.
+    pub fn open() -> TestPatternSource {
+        let Settings { width, height, fps } = *lock();
+        let (width, height) = (width as usize, height as usize);
+        let stride = width * 4 + PADDING;
+        let mut pixels = vec![PADDING_BYTE; stride * height];
+        let bars_h = height * 2 / 3;
+        for row in pixels.chunks_exact_mut(stride).take(bars_h) {
+            for (x, pixel) in row[..width * 4].chunks_exact_mut(4).enumerate() {
+                let [r, g, b] = BARS[x * BARS.len() / width];
+                pixel.copy_from_slice(&[b, g, r, 0xff]);
+            }
+        }
+        let gradient = (0..width * 2)
+            .flat_map(|x| {
+                let t = ((x % width) * 256 / width) as u8;
+                [128, 255 - t, t, 0xff]
+            })
+            .collect();
+        TestPatternSource {
+            width,
+            height,
+            fps,
+            stride,
+            pixels,
+            gradient,
+            opened: Instant::now(),
+            next: 0,
+        }
+    }
+
+    /// Draws what changes from frame to frame for frame `number`.
+    fn draw(&mut self, number: u64) {
+        let row_len = self.width * 4;
+        let shift = (number * u64::from(GRADIENT_STEP) % self.width as u64) as usize;
+        let gradient = &self.gradient[shift * 4..shift * 4 + row_len];
+        let bars_h = self.height * 2 / 3;
+        for row in self.pixels.chunks_exact_mut(self.stride).skip(bars_h) {
+            row[..row_len].copy_from_slice(gradient);
+        }
+
+        let cell = cell_size(self.width as u32) as usize;
+        for bit in 0..COUNTER_BITS as usize {
+            let on = number >> (COUNTER_BITS as usize - 1 - bit) & 1 == 1;
+            let value = if on { 0xff } else { 0 };
+            let x = bit * cell;
+            for row in self.pixels.chunks_exact_mut(self.stride).take(cell) {
+                for pixel in row[x * 4..(x + cell) * 4].chunks_exact_mut(4) {
+                    pixel.copy_from_slice(&[value, value, value, 0xff]);
+                }
+            }
+        }
+    }
+}
+
+impl FrameSource for TestPatternSource {
+    fn backend(&self) -> Backend {
+        Backend::Test
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn frame(&mut self) -> io::Result<Frame<'_>> {
+        let number = match self.fps {
+            0 => self.next,
+            fps => {
+                let due = self.opened.elapsed().as_nanos() * u128::from(fps) / 1_000_000_000;
+                let due = due as u64;
+                if due < self.next {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                due
+            }
+        };
+        self.next = number + 1;
+        self.draw(number);
+        Ok(Frame::Borrowed(&self.pixels))
+    }
+}
@@ -0,0 +1,82 @@
+//! The OS permission screen capture needs. Only macOS gates it (System
+//! Settings → Privacy & Security → Screen Recording); without it capture
+//! still works but frames show just the wallpaper and menu bar, so sessions
+//! check up front instead of streaming a seemingly empty screen.
+
+use crate::error::{RdpStatus, fail};
+
+/// Answer of `rdp_check_capture_permission`.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapturePermission {
+    Granted = 0,
+    Denied = 1,
+    /// Not granted, and the user has not been asked by this process yet.
+    Undetermined = 2,
+}
+
+pub use platform::{check, request};
+
+/// Fails with `RdpStatus::PermissionDenied` unless screen capture is
+/// allowed.
+pub fn ensure_granted() -> Result<(), RdpStatus> {
+    match check() {
+        CapturePermission::Granted => Ok(()),
+        _ => Err(fail(
+            RdpStatus::PermissionDenied,
+            "Screen capture needs the Screen Recording permission",
+        )),
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::CapturePermission;
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    unsafe extern "C" {
+        fn CGPreflightScreenCaptureAccess() -> bool;
+        fn CGRequestScreenCaptureAccess() -> bool;
+    }
+
+    /// The preflight cannot tell "denied" from "never asked", so a refusal
+    /// only counts as denied once this process has asked.
+    static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+    pub fn check() -> CapturePermission {
+        if unsafe { CGPreflightScreenCaptureAccess() } {
+            CapturePermission::Granted
+        } else if REQUESTED.load(Ordering::Relaxed) {
+            CapturePermission::Denied
+        } else {
+            CapturePermission::Undetermined
+        }
+    }
+
+    /// Shows the system prompt the first time; later the OS only points
+    /// the user at System Settings. Granting takes effect after a restart
+    /// of the app.
+    pub fn request() -> CapturePermission {
+        REQUESTED.store(true, Ordering::Relaxed);
+        if unsafe { CGRequestScreenCaptureAccess() } {
+            CapturePermission::Granted
+        } else {
+            CapturePermission::Denied
+        }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    use super::CapturePermission;
+
+    pub fn check() -> CapturePermission {
+        CapturePermission::Granted
+    }
+
+    pub fn request() -> CapturePermission {
+        CapturePermission::Granted
+    }
+}
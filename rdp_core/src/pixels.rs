@@ -0,0 +1,510 @@
+//! Pixel-buffer helpers shared by the capture pipeline.
+
+use crate::frame::PixelFormat;
+use crate::simd::{self, ConvertPath};
+
+/// A rectangle in frame pixel coordinates; `RdpRect` across the FFI.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Rect {
+    /// Intersects the rectangle with a `frame_w x frame_h` frame. Returns
+    /// `None` when nothing of it is left on screen.
+    pub fn clamp_to(self, frame_w: u32, frame_h: u32) -> Option<Rect> {
+        if self.x >= frame_w || self.y >= frame_h {
+            return None;
+        }
+
+        let w = self.w.min(frame_w - self.x);
+        let h = self.h.min(frame_h - self.y);
+        if w == 0 || h == 0 {
+            return None;
+        }
+
+        Some(Rect {
+            x: self.x,
+            y: self.y,
+            w,
+            h,
+        })
+    }
+
+    /// The part of the rectangle inside `frame` (a rectangle in the same
+    /// coordinates), relative to `frame`'s origin; `None` if they do not
+    /// overlap.
+    pub fn within(self, frame: Rect) -> Option<Rect> {
+        let x0 = self.x.max(frame.x);
+        let y0 = self.y.max(frame.y);
+        let x1 = self.x.saturating_add(self.w).min(frame.x + frame.w);
+        let y1 = self.y.saturating_add(self.h).min(frame.y + frame.h);
+        (x0 < x1 && y0 < y1).then(|| Rect {
+            x: x0 - frame.x,
+            y: y0 - frame.y,
+            w: x1 - x0,
+            h: y1 - y0,
+        })
+    }
+
+    /// The rectangle widened by `margin` on every side, stopping at 0.
+    pub fn grow(self, margin: u32) -> Rect {
+        let x = self.x.saturating_sub(margin);
+        let y = self.y.saturating_sub(margin);
+        Rect {
+            x,
+            y,
+            w: (self.x + self.w).saturating_add(margin) - x,
+            h: (self.y + self.h).saturating_add(margin) - y,
+        }
+    }
+}
+
+/// Paints `rect` (which must lie inside the frame) of a tightly packed
+/// `frame_w`-wide BGRA frame with `bgra`.
+pub fn fill_rect(frame: &mut [u8], frame_w: u32, rect: Rect, bgra: [u8; 4]) {
+    let row_len = frame_w as usize * 4;
+    for row in frame
+        .chunks_exact_mut(row_len)
+        .skip(rect.y as usize)
+        .take(rect.h as usize)
+    {
+        let start = rect.x as usize * 4;
+        for px in row[start..start + rect.w as usize * 4].chunks_exact_mut(4) {
+            px.copy_from_slice(&bgra);
+        }
+    }
+}
+
+/// Reorders tightly packed BGRA pixels into `format`, replacing the
+/// contents of `out` (whose capacity is reused). Uses the CPU's vector
+/// instructions where it has them (see `simd`).
+pub fn convert_bgra(bgra: &[u8], format: PixelFormat, out: &mut Vec<u8>) {
+    convert_bgra_with(ConvertPath::detect(), bgra, format, out);
+}
+
+/// `convert_bgra` on a given path, for comparing them; a path the CPU
+/// lacks falls back to scalar code.
+pub fn convert_bgra_with(path: ConvertPath, bgra: &[u8], format: PixelFormat, out: &mut Vec<u8>) {
+    out.clear();
+    append_converted(path, bgra, format, out);
+}
+
+/// `crop` and `convert_bgra` in one pass: converts `rect` of a BGRA frame
+/// whose rows are `stride` bytes apart straight into `format`, row by row,
+/// so the region is never copied as BGRA first. Replaces the contents of
+/// `out`. `rect` must already be clamped to the frame.
+pub fn convert_rect(
+    frame: &[u8],
+    stride: usize,
+    rect: Rect,
+    format: PixelFormat,
+    out: &mut Vec<u8>,
+) {
+    let path = ConvertPath::detect();
+    let row_len = rect.w as usize * 4;
+    out.clear();
+    out.reserve(rect.w as usize * rect.h as usize * format.bytes_per_pixel() as usize);
+
+    // Full-width rows with no padding are one contiguous run
+    if rect.x == 0 && row_len == stride {
+        let start = rect.y as usize * stride;
+        let run = &frame[start..start + row_len * rect.h as usize];
+        append_converted(path, run, format, out);
+        return;
+    }
+
+    for row in rows(frame, stride, 4, rect) {
+        append_converted(path, row, format, out);
+    }
+}
+
+/// Appends `bgra` converted to `format` to `out`, on `path` as far as it
+/// goes and then in scalar code.
+fn append_converted(path: ConvertPath, bgra: &[u8], format: PixelFormat, out: &mut Vec<u8>) {
+    let rest = &bgra[simd::convert(path, bgra, format, out)..];
+    match format {
+        PixelFormat::Gray => {
+            let start = out.len();
+            out.resize(start + rest.len() / 4, 0);
+            luma_into(path, rest, &mut out[start..]);
+        }
+        PixelFormat::Bgra => out.extend_from_slice(bgra),
+        PixelFormat::Rgb => out.extend(rest.chunks_exact(4).flat_map(|px| [px[2], px[1], px[0]])),
+        PixelFormat::Bgr => out.extend(rest.chunks_exact(4).flat_map(|px| [px[0], px[1], px[2]])),
+        PixelFormat::Rgba => out.extend(
+            rest.chunks_exact(4)
+                .flat_map(|px| [px[2], px[1], px[0], px[3]]),
+        ),
+    }
+}
+
+/// Collapses BGRA pixels to one BT.601 luma byte each, using the usual
+/// 8-bit fixed-point weights (77, 150, 29) / 256. Replaces the contents of
+/// `out`.
+pub fn bgra_to_luma(bgra: &[u8], out: &mut Vec<u8>) {
+    convert_bgra(bgra, PixelFormat::Gray, out);
+}
+
+/// Writes the luma of each pixel of `bgra` to `out`, which holds a byte per
+/// pixel.
+fn luma_into(path: ConvertPath, bgra: &[u8], out: &mut [u8]) {
+    let done = simd::weigh(path, bgra, [77, 150, 29], 0, out);
+    for (y, px) in out[done..].iter_mut().zip(bgra[done * 4..].chunks_exact(4)) {
+        let (b, g, r) = (u32::from(px[0]), u32::from(px[1]), u32::from(px[2]));
+        *y = ((77 * r + 150 * g + 29 * b + 128) >> 8) as u8;
+    }
+}
+
+/// Centres a tightly packed `src_w x src_h` image on a `dst_w x dst_h`
+/// canvas filled with the `fill` pixel (whose length is the bytes per
+/// pixel), writing the canvas into `out`. The source must not be larger
+/// than the canvas.
+pub fn letterbox(
+    src: &[u8],
+    src_w: u32,
+    src_h: u32,
+    dst_w: u32,
+    dst_h: u32,
+    fill: &[u8],
+    out: &mut Vec<u8>,
+) {
+    let bpp = fill.len();
+    out.clear();
+    out.extend(
+        fill.iter()
+            .copied()
+            .cycle()
+            .take(dst_w as usize * dst_h as usize * bpp),
+    );
+
+    let left = ((dst_w - src_w) / 2) as usize;
+    let top = ((dst_h - src_h) / 2) as usize;
+    let src_row = src_w as usize * bpp;
+    let dst_row = dst_w as usize * bpp;
+    for (row, pixels) in src.chunks_exact(src_row).enumerate() {
+        let start = (top + row) * dst_row + left * bpp;
+        out[start..start + src_row].copy_from_slice(pixels);
+    }
+}
+
+/// Bounding box of the pixels that differ between two tightly packed BGRA
+/// frames of width `w`, or `None` if they are identical. Rows are compared
+/// whole first (a plain memcmp), and only rows that differ are scanned 8
+/// bytes at a time from each end to find the horizontal extent.
+pub fn dirty_rect(prev: &[u8], cur: &[u8], w: u32) -> Option<Rect> {
+    let row_len = w as usize * 4;
+    let rows = || prev.chunks_exact(row_len).zip(cur.chunks_exact(row_len));
+
+    let top = rows().position(|(p, c)| p != c)?;
+    let bottom = rows().rposition(|(p, c)| p != c).unwrap_or(top);
+
+    let (mut left, mut right) = (usize::MAX, 0);
+    for (p, c) in rows().skip(top).take(bottom - top + 1) {
+        if p == c {
+            continue;
+        }
+        let words = || p.chunks(8).zip(c.chunks(8));
+        if let Some(word) = words().position(|(a, b)| a != b) {
+            let base = word * 8;
+            let first_same = p[base..base + 4] == c[base..base + 4];
+            left = left.min(base / 4 + usize::from(first_same));
+        }
+        if let Some(word) = words().rposition(|(a, b)| a != b) {
+            let base = word * 8;
+            let second_differs =
+                base + 8 <= row_len && p[base + 4..base + 8] != c[base + 4..base + 8];
+            right = right.max(base / 4 + usize::from(second_differs));
+        }
+    }
+
+    Some(Rect {
+        x: left as u32,
+        y: top as u32,
+        w: (right - left + 1) as u32,
+        h: (bottom - top + 1) as u32,
+    })
+}
+
+/// Cheap 64-bit fingerprint of a pixel buffer plus some extra `salt`
+/// values, for spotting unchanged frames. Works a word at a time with an
+/// Fx-style multiply/rotate, which runs much faster than a byte-wise hash
+/// and far faster than encoding. Not collision-resistant against adversarial
+/// input, which screen content is not.
+///
+/// The result is part of the FFI contract (`RawImage::content_hash`) and
+/// must stay identical on every platform: starting from `h = 0`, each input
+/// word `v` is mixed as `h = (h.rotate_left(5) ^ v) * 0x517cc1b727220a95`
+/// (wrapping), first every salt value, then the pixels as little-endian
+/// `u64`s, then the 0 to 7 bytes left over zero-padded to one more word
+/// (a zero word when none are left), then the byte length.
+pub fn frame_hash(pixels: &[u8], salt: &[u32]) -> u64 {
+    let mut hasher = FrameHasher::new(salt);
+    hasher.write(pixels);
+    hasher.finish()
+}
+
+/// `frame_hash` over pixels that arrive in pieces, such as the rows of a
+/// region still in the captured frame: writing the pieces in order hashes
+/// the same as `frame_hash` over them joined.
+pub struct FrameHasher {
+    h: u64,
+    /// Bytes short of a whole word, left over from the last write.
+    pending: [u8; 8],
+    pending_len: usize,
+    len: u64,
+}
+
+impl FrameHasher {
+    const K: u64 = 0x517c_c1b7_2722_0a95;
+
+    pub fn new(salt: &[u32]) -> FrameHasher {
+        FrameHasher {
+            h: salt.iter().fold(0, |h, &v| Self::mix(h, u64::from(v))),
+            pending: [0; 8],
+            pending_len: 0,
+            len: 0,
+        }
+    }
+
+    fn mix(h: u64, word: u64) -> u64 {
+        (h.rotate_left(5) ^ word).wrapping_mul(Self::K)
+    }
+
+    pub fn write(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+        if self.pending_len > 0 {
+            let take = (8 - self.pending_len).min(bytes.len());
+            self.pending[self.pending_len..self.pending_len + take].copy_from_slice(&bytes[..take]);
+            self.pending_len += take;
+            bytes = &bytes[take..];
+            if self.pending_len < 8 {
+                return;
+            }
+            self.h = Self::mix(self.h, u64::from_le_bytes(self.pending));
+            self.pending_len = 0;
+        }
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.h = Self::mix(self.h, u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        let tail = words.remainder();
+        self.pending[..tail.len()].copy_from_slice(tail);
+        self.pending_len = tail.len();
+    }
+
+    pub fn finish(self) -> u64 {
+        let mut last = [0u8; 8];
+        last[..self.pending_len].copy_from_slice(&self.pending[..self.pending_len]);
+        Self::mix(Self::mix(self.h, u64::from_le_bytes(last)), self.len)
+    }
+}
+
+/// Copies `rect` out of a `bpp`-byte-per-pixel frame whose rows are
+/// `stride` bytes apart into `out` as a tightly packed buffer, dropping any
+/// per-row padding. Only the bytes inside the rectangle are touched. `rect`
+/// must already be clamped to the frame.
+pub fn crop(frame: &[u8], stride: usize, bpp: usize, rect: Rect, out: &mut Vec<u8>) {
+    let row_len = rect.w as usize * bpp;
+    out.clear();
+    out.reserve(row_len * rect.h as usize);
+
+    // Full-width rows with no padding are one contiguous run
+    if rect.x == 0 && row_len == stride {
+        let start = rect.y as usize * stride;
+        out.extend_from_slice(&frame[start..start + row_len * rect.h as usize]);
+        return;
+    }
+
+    for row in rows(frame, stride, bpp, rect) {
+        out.extend_from_slice(row);
+    }
+}
+
+/// The rows of `rect` in a `bpp`-byte-per-pixel frame whose rows are
+/// `stride` bytes apart, top to bottom. `rect` must already be clamped to
+/// the frame.
+pub fn rows(frame: &[u8], stride: usize, bpp: usize, rect: Rect) -> impl Iterator<Item = &[u8]> {
+    let row_len = rect.w as usize * bpp;
+    (rect.y as usize..(rect.y + rect.h) as usize).map(move |row| {
+        let start = row * stride + rect.x as usize * bpp;
+        &frame[start..start + row_len]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two BGRA pixels, every byte distinct.
+    const TWO: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    const ALL_PATHS: [ConvertPath; 4] = [
+        ConvertPath::Scalar,
+        ConvertPath::Ssse3,
+        ConvertPath::Avx2,
+        ConvertPath::Neon,
+    ];
+
+    fn convert(format: PixelFormat, bgra: &[u8], path: ConvertPath) -> Vec<u8> {
+        let mut out = vec![0xee; 3];
+        convert_bgra_with(path, bgra, format, &mut out);
+        out
+    }
+
+    #[test]
+    fn two_pixels_come_out_in_each_order() {
+        for (format, golden) in [
+            (PixelFormat::Bgra, &[1, 2, 3, 4, 5, 6, 7, 8][..]),
+            (PixelFormat::Rgb, &[3, 2, 1, 7, 6, 5]),
+            (PixelFormat::Bgr, &[1, 2, 3, 5, 6, 7]),
+            (PixelFormat::Rgba, &[3, 2, 1, 4, 7, 6, 5, 8]),
+        ] {
+            for path in ALL_PATHS {
+                assert_eq!(
+                    convert(format, &TWO, path),
+                    golden,
+                    "{format:?} on {path:?}"
+                );
+            }
+        }
+        // BT.601 weights: (77 * 3 + 150 * 2 + 29 * 1 + 128) >> 8 = 2, and 6
+        assert_eq!(
+            convert(PixelFormat::Gray, &TWO, ConvertPath::Scalar),
+            [2, 6]
+        );
+    }
+
+    #[test]
+    fn vector_paths_match_scalar_past_their_block_size() {
+        // Not a multiple of any vector width, so each path has a tail
+        let bgra: Vec<u8> = (0..4 * 77).map(|i| (i * 37 % 256) as u8).collect();
+        for format in [
+            PixelFormat::Bgra,
+            PixelFormat::Rgb,
+            PixelFormat::Bgr,
+            PixelFormat::Rgba,
+            PixelFormat::Gray,
+        ] {
+            let scalar = convert(format, &bgra, ConvertPath::Scalar);
+            assert_eq!(scalar.len(), 77 * format.bytes_per_pixel() as usize);
+            for path in ALL_PATHS.into_iter().filter(|path| path.is_available()) {
+                assert_eq!(
+                    convert(format, &bgra, path),
+                    scalar,
+                    "{format:?} on {path:?}"
+                );
+            }
+        }
+    }
+
+    /// A `width x height` BGRA frame whose rows are padded to `stride`
+    /// bytes with 0xee; each pixel holds its own x and y.
+    fn padded(width: u32, height: u32, stride: usize) -> Vec<u8> {
+        let mut frame = vec![0xee; stride * height as usize];
+        for y in 0..height {
+            for x in 0..width {
+                let at = y as usize * stride + x as usize * 4;
+                frame[at..at + 4].copy_from_slice(&[x as u8, y as u8, 0xaa, 0xff]);
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn crop_drops_per_row_padding() {
+        // 11 pixels padded to 16, as drivers pad 1366 to 1376
+        let (width, height, stride) = (11, 5, 16 * 4);
+        let frame = padded(width, height, stride);
+        let mut out = Vec::new();
+        for rect in [
+            Rect {
+                x: 0,
+                y: 0,
+                w: width,
+                h: height,
+            },
+            Rect {
+                x: 3,
+                y: 1,
+                w: 8,
+                h: 3,
+            },
+        ] {
+            crop(&frame, stride, 4, rect, &mut out);
+            assert_eq!(out.len(), (rect.w * rect.h * 4) as usize);
+            for (i, px) in out.chunks_exact(4).enumerate() {
+                let (x, y) = (rect.x + i as u32 % rect.w, rect.y + i as u32 / rect.w);
+                assert_eq!(px, [x as u8, y as u8, 0xaa, 0xff], "pixel {i} of {rect:?}");
+            }
+        }
+
+        // Unpadded full-width rows take the contiguous path
+        let tight = padded(width, height, width as usize * 4);
+        let full = Rect {
+            x: 0,
+            y: 2,
+            w: width,
+            h: 2,
+        };
+        crop(&tight, width as usize * 4, 4, full, &mut out);
+        assert_eq!(out, tight[2 * 44..4 * 44]);
+    }
+
+    /// `frame_hash` as its documentation states it, a word at a time.
+    fn documented_hash(pixels: &[u8], salt: &[u32]) -> u64 {
+        let mix = |h: u64, v: u64| (h.rotate_left(5) ^ v).wrapping_mul(0x517c_c1b7_2722_0a95);
+        let mut h = salt.iter().fold(0, |h, &v| mix(h, u64::from(v)));
+        // Always one last, padded word, all zeros when the length is whole
+        let mut padded = pixels.to_vec();
+        padded.resize((pixels.len() / 8 + 1) * 8, 0);
+        for word in padded.chunks_exact(8) {
+            h = mix(h, u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        mix(h, pixels.len() as u64)
+    }
+
+    #[test]
+    fn content_hash_follows_the_pixels() {
+        let frame: Vec<u8> = (0..4 * 301).map(|i| (i * 13 % 256) as u8).collect();
+        let salt = [301, 1, 0];
+        let hash = frame_hash(&frame, &salt);
+        assert_eq!(frame_hash(&frame.clone(), &salt), hash, "same pixels");
+
+        let mut flipped = frame.clone();
+        flipped[4 * 150 + 1] ^= 1;
+        assert_ne!(frame_hash(&flipped, &salt), hash, "one pixel changed");
+        assert_ne!(frame_hash(&frame, &[301, 1, 1]), hash, "salt changed");
+    }
+
+    #[test]
+    fn content_hash_is_the_documented_function() {
+        // Pinned, so a change to the hash (which clients may store) shows
+        assert_eq!(frame_hash(&TWO, &[2, 1]), documented_hash(&TWO, &[2, 1]));
+        for len in [0, 1, 7, 8, 9, 64, 1203] {
+            let pixels: Vec<u8> = (0..len).map(|i| (i * 29 % 256) as u8).collect();
+            assert_eq!(
+                frame_hash(&pixels, &[7]),
+                documented_hash(&pixels, &[7]),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn hashing_in_pieces_matches_hashing_whole() {
+        let pixels: Vec<u8> = (0..1000).map(|i| (i * 31 % 256) as u8).collect();
+        let whole = frame_hash(&pixels, &[4]);
+        for piece in [1, 3, 8, 13, 44] {
+            let mut hasher = FrameHasher::new(&[4]);
+            for chunk in pixels.chunks(piece) {
+                hasher.write(chunk);
+            }
+            assert_eq!(hasher.finish(), whole, "{piece}-byte pieces");
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Scheduling of the library's stream threads: a raised priority, so a
+//! loaded machine does not preempt capture and encoding mid-frame, and
+//! optionally pinning to a set of cores. Both are opt-in per session and
+//! applied by the stream thread to itself when it starts; a stream started
+//! with the defaults makes no scheduling calls at all.
+//!
+//! What each level means per OS:
+//!
+//! | Level         | Windows                         | Linux                      | macOS                      |
+//! |---------------|---------------------------------|----------------------------|----------------------------|
+//! | above normal  | `THREAD_PRIORITY_ABOVE_NORMAL`  | nice -5                    | `QOS_CLASS_USER_INITIATED` |
+//! | time critical | `THREAD_PRIORITY_TIME_CRITICAL` | `SCHED_RR`, else nice -10  | `QOS_CLASS_USER_INTERACTIVE` |
+//!
+//! Lowering the nice value and `SCHED_RR` need `CAP_SYS_NICE` or a fitting
+//! `RLIMIT_NICE` / `RLIMIT_RTPRIO` on Linux. Whatever the OS refuses is
+//! logged as a warning and the thread carries on with what it got, which
+//! `RdpStats::thread_priority` and `thread_affinity` report. macOS has no
+//! way to pin a thread, so an affinity is only ever warned about there.
+
+use crate::log::{self, LogLevel};
+
+/// How far a stream thread is raised above the default scheduling.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThreadPriority {
+    #[default]
+    Normal = 0,
+    AboveNormal = 1,
+    TimeCritical = 2,
+}
+
+impl ThreadPriority {
+    /// Maps an FFI priority value back onto the enum.
+    pub fn from_u32(value: u32) -> Option<ThreadPriority> {
+        match value {
+            0 => Some(ThreadPriority::Normal),
+            1 => Some(ThreadPriority::AboveNormal),
+            2 => Some(ThreadPriority::TimeCritical),
+            _ => None,
+        }
+    }
+}
+
+/// What a thread actually got.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Applied {
+    pub priority: ThreadPriority,
+    /// Bit `n` set for core `n`; 0 when not pinned.
+    pub affinity: u64,
+}
+
+/// Raises the calling thread to `priority` and pins it to the cores set in
+/// `affinity` (0 leaves it where it is), as far as the OS allows, warning
+/// about the rest.
+pub fn apply_to_current(priority: ThreadPriority, affinity: u64) -> Applied {
+    let mut applied = Applied::default();
+    if priority != ThreadPriority::Normal {
+        applied.priority = platform::raise(priority);
+        if applied.priority < priority {
+            log::log(
+                LogLevel::Warn,
+                &format!(
+                    "Stream thread runs at {:?} priority instead of {priority:?}",
+                    applied.priority
+                ),
+            );
+        }
+    }
+    if affinity != 0 {
+        match platform::pin(affinity) {
+            Ok(()) => applied.affinity = affinity,
+            Err(e) => log::log(
+                LogLevel::Warn,
+                &format!("Cannot pin the stream thread to cores {affinity:#x}: {e}"),
+            ),
+        }
+    }
+    applied
+}
+
+/// Logs why a scheduling call failed.
+fn refused(what: &str, e: &std::io::Error) {
+    log::log(LogLevel::Warn, &format!("Cannot {what}: {e}"));
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::ffi::{c_int, c_uint, c_ulong};
+    use std::io;
+    use std::mem;
+
+    use super::{ThreadPriority, refused};
+
+    const PRIO_PROCESS: c_int = 0;
+    const SCHED_RR: c_int = 2;
+    /// Low in `SCHED_RR`'s 1–99, so the thread still yields to the
+    /// kernel's own real-time threads.
+    const RR_PRIORITY: c_int = 10;
+    const ABOVE_NORMAL_NICE: c_int = -5;
+    const TIME_CRITICAL_NICE: c_int = -10;
+
+    #[repr(C)]
+    struct SchedParam {
+        sched_priority: c_int,
+    }
+
+    /// glibc's `cpu_set_t`: 1024 bits.
+    type CpuSet = [u64; 16];
+
+    unsafe extern "C" {
+        fn pthread_self() -> c_ulong;
+        fn pthread_setschedparam(thread: c_ulong, policy: c_int, param: *const SchedParam)
+        -> c_int;
+        fn setpriority(which: c_int, who: c_uint, prio: c_int) -> c_int;
+        fn sched_setaffinity(pid: c_int, size: usize, mask: *const CpuSet) -> c_int;
+    }
+
+    pub fn raise(priority: ThreadPriority) -> ThreadPriority {
+        if priority == ThreadPriority::TimeCritical {
+            let param = SchedParam {
+                sched_priority: RR_PRIORITY,
+            };
+            match unsafe { pthread_setschedparam(pthread_self(), SCHED_RR, &param) } {
+                0 => return ThreadPriority::TimeCritical,
+                errno => refused(
+                    "switch the stream thread to SCHED_RR",
+                    &io::Error::from_raw_os_error(errno),
+                ),
+            }
+            if nice(TIME_CRITICAL_NICE) {
+                return ThreadPriority::AboveNormal;
+            }
+        }
+        if nice(ABOVE_NORMAL_NICE) {
+            ThreadPriority::AboveNormal
+        } else {
+            ThreadPriority::Normal
+        }
+    }
+
+    /// Sets the calling thread's nice value: NPTL keeps it per thread, and
+    /// `who` 0 is the caller.
+    fn nice(value: c_int) -> bool {
+        if unsafe { setpriority(PRIO_PROCESS, 0, value) } == 0 {
+            return true;
+        }
+        refused(
+            &format!("set the stream thread's nice value to {value}"),
+            &io::Error::last_os_error(),
+        );
+        false
+    }
+
+    pub fn pin(affinity: u64) -> io::Result<()> {
+        let mut set: CpuSet = [0; 16];
+        set[0] = affinity;
+        // pid 0 is the calling thread
+        match unsafe { sched_setaffinity(0, mem::size_of::<CpuSet>(), &set) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::ffi::{c_int, c_uint};
+    use std::io;
+
+    use super::{ThreadPriority, refused};
+
+    const QOS_CLASS_USER_INTERACTIVE: c_uint = 0x21;
+    const QOS_CLASS_USER_INITIATED: c_uint = 0x19;
+
+    unsafe extern "C" {
+        fn pthread_set_qos_class_self_np(qos_class: c_uint, relative_priority: c_int) -> c_int;
+    }
+
+    pub fn raise(priority: ThreadPriority) -> ThreadPriority {
+        let class = match priority {
+            ThreadPriority::TimeCritical => QOS_CLASS_USER_INTERACTIVE,
+            _ => QOS_CLASS_USER_INITIATED,
+        };
+        match unsafe { pthread_set_qos_class_self_np(class, 0) } {
+            0 => priority,
+            errno => {
+                refused(
+                    "set the stream thread's QoS class",
+                    &io::Error::from_raw_os_error(errno),
+                );
+                ThreadPriority::Normal
+            }
+        }
+    }
+
+    pub fn pin(_affinity: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "macOS does not pin threads to cores",
+        ))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+    use std::io;
+
+    use super::{ThreadPriority, refused};
+
+    const THREAD_PRIORITY_ABOVE_NORMAL: i32 = 1;
+    const THREAD_PRIORITY_TIME_CRITICAL: i32 = 15;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn SetThreadPriority(thread: *mut c_void, priority: i32) -> i32;
+        fn SetThreadAffinityMask(thread: *mut c_void, mask: usize) -> usize;
+    }
+
+    pub fn raise(priority: ThreadPriority) -> ThreadPriority {
+        if priority == ThreadPriority::TimeCritical && set(THREAD_PRIORITY_TIME_CRITICAL) {
+            return ThreadPriority::TimeCritical;
+        }
+        if set(THREAD_PRIORITY_ABOVE_NORMAL) {
+            ThreadPriority::AboveNormal
+        } else {
+            ThreadPriority::Normal
+        }
+    }
+
+    fn set(priority: i32) -> bool {
+        if unsafe { SetThreadPriority(GetCurrentThread(), priority) } != 0 {
+            return true;
+        }
+        refused(
+            &format!("set the stream thread's priority to {priority}"),
+            &io::Error::last_os_error(),
+        );
+        false
+    }
+
+    pub fn pin(affinity: u64) -> io::Result<()> {
+        let mask = usize::try_from(affinity).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cores past 31 in a 32-bit process",
+            )
+        })?;
+        match unsafe { SetThreadAffinityMask(GetCurrentThread(), mask) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use std::io;
+
+    use super::ThreadPriority;
+
+    pub fn raise(_priority: ThreadPriority) -> ThreadPriority {
+        ThreadPriority::Normal
+    }
+
+    pub fn pin(_affinity: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "not supported on this platform",
+        ))
+    }
+}
@@ -0,0 +1,268 @@
+//! A native Python extension module, `rdp_core_py` (`python` feature), so
+//! Python hosts get objects instead of hand-mirrored ctypes structs:
+//!
+//! ```text
+//! import rdp_core_py
+//! for d in rdp_core_py.displays(): print(d.index, d.width, d.height)
+//! s = rdp_core_py.Session(display=0, quality=70, format="jpeg")
+//! jpeg = s.capture()                   # bytes, or None without a new frame
+//! pixels = s.capture_array()           # memoryview, shape (h, w, channels)
+//! ```
+//!
+//! Built with PyO3 on CPython's stable ABI (3.8 or later). Capturing,
+//! encoding and opening a session run with the GIL released, so other
+//! Python threads keep going; calls on one `Session` from several threads
+//! take turns.
+//!
+//! Python loads the module by file name: copy the built library to
+//! `rdp_core_py.so` (`rdp_core_py.pyd` on Windows) somewhere on `sys.path`.
+//! The `extern "C"` API stays in the same library and usable from ctypes.
+
+use std::sync::{Mutex, PoisonError};
+
+use pyo3::exceptions::{PyOSError, PyPermissionError, PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyList, PyMemoryView};
+
+use crate::api::CaptureSession;
+use crate::display;
+use crate::error::{CaptureError, RdpStatus, guard};
+use crate::frame::{EncodedFrame, FrameFormat, PixelFormat};
+use crate::session::SessionConfig;
+use crate::window;
+
+mod exceptions {
+    pyo3::create_exception!(
+        rdp_core_py,
+        CaptureError,
+        pyo3::exceptions::PyRuntimeError,
+        "A capture failed; `args` are the message and the `RdpStatus` code."
+    );
+}
+
+/// The Python counterpart of `error`.
+fn to_py(error: CaptureError) -> PyErr {
+    let message = error.to_string();
+    match error {
+        CaptureError::InvalidArgument(_) => PyValueError::new_err(message),
+        CaptureError::PermissionDenied(_) => PyPermissionError::new_err(message),
+        CaptureError::Timeout => PyTimeoutError::new_err(message),
+        _ => exceptions::CaptureError::new_err((message, error.status() as i32)),
+    }
+}
+
+/// `body` with a panic turned into `CaptureError::Failed`, as no unwind
+/// may reach the interpreter.
+fn caught<T>(body: impl FnOnce() -> Result<T, CaptureError>) -> Result<T, CaptureError> {
+    guard(None, || Some(body())).unwrap_or_else(|| Err(CaptureError::from(RdpStatus::Panic)))
+}
+
+/// A capture session on display index `display` (-1 for the primary one),
+/// or on the window with id `window`.
+#[pyclass(module = "rdp_core_py", frozen)]
+struct Session {
+    session: Mutex<CaptureSession>,
+}
+
+impl Session {
+    /// Captures with the GIL released. `Ok(None)` when there is no new
+    /// frame (no change, no frame ready, a resolution change).
+    fn capture_with(
+        &self,
+        py: Python<'_>,
+        capture: impl FnOnce(&mut CaptureSession) -> Result<EncodedFrame, CaptureError> + Send,
+    ) -> PyResult<Option<EncodedFrame>> {
+        let result = py.allow_threads(|| {
+            caught(|| capture(&mut self.session.lock().unwrap_or_else(PoisonError::into_inner)))
+        });
+        match result {
+            Ok(frame) => Ok(Some(frame)),
+            Err(
+                CaptureError::NoChange | CaptureError::WouldBlock | CaptureError::ResolutionChanged,
+            ) => Ok(None),
+            Err(error) => Err(to_py(error)),
+        }
+    }
+}
+
+#[pymethods]
+impl Session {
+    #[new]
+    #[pyo3(signature = (
+        display = 0,
+        window = None,
+        quality = 70,
+        format = "jpeg",
+        pixel_format = "bgra",
+        scale = 1.0,
+        max_dim = 0,
+        include_cursor = false,
+        detect_changes = false,
+        fps = 0,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        display: i32,
+        window: Option<u64>,
+        quality: i32,
+        format: &str,
+        pixel_format: &str,
+        scale: f32,
+        max_dim: u32,
+        include_cursor: bool,
+        detect_changes: bool,
+        fps: u32,
+    ) -> PyResult<Session> {
+        let Some(format) = FrameFormat::from_name(format) else {
+            return Err(PyValueError::new_err(format!("Unknown format {format:?}")));
+        };
+        let Some(pixel_format) = PixelFormat::from_name(pixel_format) else {
+            return Err(PyValueError::new_err(format!(
+                "Unknown pixel format {pixel_format:?}"
+            )));
+        };
+        let config = SessionConfig {
+            format,
+            pixel_format,
+            grayscale: pixel_format == PixelFormat::Gray,
+            quality: quality.clamp(1, 100) as u8,
+            scale,
+            max_dim,
+            include_cursor,
+            detect_changes,
+            target_fps: fps,
+            ..SessionConfig::default()
+        };
+        let session = py
+            .allow_threads(|| {
+                caught(|| match window {
+                    Some(id) => CaptureSession::on_window(id, config),
+                    None => CaptureSession::on_display(display, config),
+                })
+            })
+            .map_err(to_py)?;
+        Ok(Session {
+            session: Mutex::new(session),
+        })
+    }
+
+    /// Captures and encodes one frame, resized to width x height if given.
+    /// Returns None when there is no new frame.
+    #[pyo3(signature = (width = None, height = None))]
+    fn capture<'py>(
+        &self,
+        py: Python<'py>,
+        width: Option<u32>,
+        height: Option<u32>,
+    ) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        // 0 is the C API's "no target"
+        let (width, height) = match (width, height) {
+            (Some(0), _) | (_, Some(0)) => {
+                return Err(PyValueError::new_err("width and height must be positive"));
+            }
+            (Some(width), Some(height)) => (width, height),
+            (None, None) => (0, 0),
+            _ => {
+                return Err(PyValueError::new_err(
+                    "Pass both width and height, or neither",
+                ));
+            }
+        };
+        let frame = self.capture_with(py, |session| session.capture_at(width, height))?;
+        Ok(frame.map(|frame| PyBytes::new(py, &frame)))
+    }
+
+    /// Captures one frame of raw pixels in the session's pixel format,
+    /// shaped (height, width, channels), or (height, stride) when rows are
+    /// padded. Returns None when there is no new frame. A session set to
+    /// another format is switched to raw for the capture and back, which
+    /// restarts video streams.
+    fn capture_array<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        let captured = self.capture_with(py, |session| {
+            let format = session.config().format;
+            if format == FrameFormat::Raw {
+                return session.capture();
+            }
+            session.session_mut().set_format(FrameFormat::Raw);
+            let frame = session.capture();
+            session.session_mut().set_format(format);
+            frame
+        })?;
+        let Some(frame) = captured else {
+            return Ok(None);
+        };
+
+        let view = PyMemoryView::from(PyBytes::new(py, &frame).as_any())?;
+        let channels = frame.pixel_format.bytes_per_pixel();
+        let shaped = if frame.stride == frame.width * channels {
+            view.call_method1("cast", ("B", (frame.height, frame.width, channels)))?
+        } else {
+            view.call_method1("cast", ("B", (frame.height, frame.stride)))?
+        };
+        Ok(Some(shaped))
+    }
+}
+
+/// displays() -> list[Display], in display index order.
+#[pyfunction]
+fn displays<'py>(module: &Bound<'py, PyModule>) -> PyResult<Bound<'py, PyList>> {
+    let py = module.py();
+    let all = py
+        .allow_threads(display::enumerate)
+        .map_err(|e| PyOSError::new_err(format!("Failed to enumerate displays: {e}")))?;
+    let class = module.getattr("Display")?;
+    let items = all
+        .iter()
+        .enumerate()
+        .map(|(index, d)| {
+            class.call1((
+                index,
+                d.id,
+                d.width,
+                d.height,
+                d.x,
+                d.y,
+                d.scale,
+                d.is_primary,
+            ))
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    PyList::new(py, items)
+}
+
+/// windows() -> list[Window], frontmost first where the platform says.
+#[pyfunction]
+fn windows<'py>(module: &Bound<'py, PyModule>) -> PyResult<Bound<'py, PyList>> {
+    let py = module.py();
+    let all = py
+        .allow_threads(window::all)
+        .map_err(|e| PyOSError::new_err(format!("Failed to enumerate windows: {e}")))?;
+    let class = module.getattr("Window")?;
+    let items = all
+        .iter()
+        .map(|w| class.call1((w.id, w.pid, w.title(), w.x, w.y, w.width, w.height)))
+        .collect::<PyResult<Vec<_>>>()?;
+    PyList::new(py, items)
+}
+
+/// Screen capture and encoding from rdp_core.
+#[pymodule]
+fn rdp_core_py(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = module.py();
+    module.add("CaptureError", py.get_type::<exceptions::CaptureError>())?;
+    module.add_class::<Session>()?;
+
+    // Read-only records with a readable repr and equality
+    let namedtuple = py.import("collections")?.getattr("namedtuple")?;
+    let record = |name: &str, fields: &str| namedtuple.call1((name, fields));
+    module.add(
+        "Display",
+        record("Display", "index id width height x y scale is_primary")?,
+    )?;
+    module.add("Window", record("Window", "id pid title x y width height")?)?;
+
+    module.add_function(wrap_pyfunction!(displays, module)?)?;
+    module.add_function(wrap_pyfunction!(windows, module)?)?;
+    Ok(())
+}
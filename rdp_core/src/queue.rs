@@ -0,0 +1,422 @@
+//! Per-consumer frame queues. Every consumer of a stream (a buffered
+//! stream's poller, each network client) gets its own bounded queue, so
+//! what happens to a consumer that falls behind is decided and counted per
+//! consumer:
+//!
+//! - `QueuePolicy::LatestWins` (the default): a frame arriving at a full
+//!   queue pushes out the oldest one, which counts as dropped. The stream
+//!   never waits, and a consumer that catches up gets the newest frames.
+//! - `QueuePolicy::Block`: a frame arriving at a full queue waits for room,
+//!   holding up the stream (and with it capture and every other consumer
+//!   of the stream) until the consumer takes one. Nothing is dropped; the
+//!   stream runs at the slowest consumer's pace.
+//!
+//! Under a memory limit (see the `memory` module) a queue also shrinks:
+//! when the budget has no room for a new frame, the oldest queued frames
+//! are dropped to make it, whatever the policy, and a frame that does not
+//! fit even into an empty queue is dropped itself. `QueuePolicy::Block`
+//! first waits for the consumer to take the frames it holds.
+//!
+//! Each queue's counters are registered with the session's `Stats` and
+//! listed by `rdp_session_get_consumer_stats`; drops also add to
+//! `RdpStats::frames_dropped`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::frame::EncodedFrame;
+use crate::memory::Charge;
+use crate::stats::{Consumer, Stats};
+
+/// Frames a consumer's queue holds unless the session says otherwise: just
+/// the next one, for the lowest latency.
+pub const DEFAULT_CAPACITY: usize = 1;
+
+/// Largest accepted queue; more only adds latency and memory.
+pub const MAX_CAPACITY: usize = 16;
+
+/// How often a blocked `push` re-checks its `give_up` condition.
+const RECHECK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What a full queue does with a new frame (see the module docs).
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueuePolicy {
+    #[default]
+    LatestWins = 0,
+    Block = 1,
+}
+
+impl QueuePolicy {
+    /// Maps an FFI queue policy value back onto the enum.
+    pub fn from_u32(value: u32) -> Option<QueuePolicy> {
+        match value {
+            0 => Some(QueuePolicy::LatestWins),
+            1 => Some(QueuePolicy::Block),
+            _ => None,
+        }
+    }
+}
+
+/// Who a queue feeds, as `RdpConsumerStats::kind` reports it.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsumerKind {
+    Buffered = 1,
+    Http = 2,
+    Tcp = 3,
+    WebSocket = 4,
+    Local = 5,
+}
+
+/// What a queued item holds in memory, charged to the session's budget.
+pub trait Footprint {
+    fn footprint(&self) -> u64;
+}
+
+impl Footprint for EncodedFrame {
+    fn footprint(&self) -> u64 {
+        self.data.capacity() as u64
+    }
+}
+
+impl<T: Footprint> Footprint for Arc<T> {
+    fn footprint(&self) -> u64 {
+        T::footprint(self)
+    }
+}
+
+/// One consumer's frames, oldest first.
+pub struct FrameQueue<T> {
+    state: Mutex<State<T>>,
+    /// Signalled whenever a frame is queued or taken, and by `close` and
+    /// `wake`.
+    changed: Condvar,
+    capacity: usize,
+    policy: QueuePolicy,
+    counters: Arc<Consumer>,
+    /// The session's, where drops are counted too.
+    stats: Arc<Stats>,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    /// Set once the consumer or the stream is gone; nothing is queued or
+    /// taken after.
+    closed: bool,
+    /// What `items` hold against the session's memory budget.
+    charge: Charge,
+}
+
+impl<T: Footprint> FrameQueue<T> {
+    /// A queue of `capacity` (clamped to 1–`MAX_CAPACITY`) frames, listed
+    /// among `stats`' consumers for as long as it lives.
+    pub fn new(
+        kind: ConsumerKind,
+        policy: QueuePolicy,
+        capacity: usize,
+        stats: &Arc<Stats>,
+    ) -> FrameQueue<T> {
+        let capacity = capacity.clamp(1, MAX_CAPACITY);
+        FrameQueue {
+            state: Mutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+                charge: Charge::new(stats.memory()),
+            }),
+            changed: Condvar::new(),
+            capacity,
+            policy,
+            counters: stats.add_consumer(kind, policy, capacity),
+            stats: Arc::clone(stats),
+        }
+    }
+
+    /// Queues `item` as the policy says. Under `QueuePolicy::Block` this
+    /// waits for room, giving up (and discarding `item`, which is not
+    /// counted as dropped) once the queue is closed or `give_up` is true.
+    pub fn push(&self, item: T, give_up: impl Fn() -> bool) {
+        let bytes = item.footprint();
+        let memory = Arc::clone(self.stats.memory());
+        let mut state = self.lock();
+        if self.policy == QueuePolicy::Block {
+            while (state.items.len() == self.capacity
+                || !state.items.is_empty() && memory.available() < bytes)
+                && !state.closed
+            {
+                if give_up() {
+                    return;
+                }
+                state = self
+                    .changed
+                    .wait_timeout(state, RECHECK_INTERVAL)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+        }
+        if state.closed {
+            return;
+        }
+        if state.items.len() == self.capacity {
+            Self::pop(&mut state);
+            self.dropped(1);
+        }
+        while !state.charge.add(bytes) {
+            self.dropped(1);
+            if Self::pop(&mut state).is_none() {
+                self.counters.set_queued(0);
+                return;
+            }
+        }
+        state.items.push_back(item);
+        self.counters.set_queued(state.items.len());
+        self.changed.notify_all();
+    }
+
+    /// Takes the oldest frame, waiting for one. `None` once the queue is
+    /// closed or `give_up` is true; it is checked whenever the caller is
+    /// woken, including by `wake`.
+    pub fn pop_unless(&self, give_up: impl Fn() -> bool) -> Option<T> {
+        let mut state = self.lock();
+        loop {
+            if state.closed || give_up() {
+                return None;
+            }
+            if let Some(item) = Self::pop(&mut state) {
+                self.taken(&state);
+                return Some(item);
+            }
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Takes the newest frame without waiting, dropping the older ones.
+    pub fn take_latest(&self) -> Option<T> {
+        let mut state = self.lock();
+        let latest = state.items.pop_back()?;
+        self.dropped(state.items.len());
+        state.items.clear();
+        state.charge.set(0);
+        self.taken(&state);
+        Some(latest)
+    }
+
+    /// Ends the queue, discarding what it holds and releasing a blocked
+    /// `push` and a waiting `pop_unless`.
+    pub fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        state.items.clear();
+        state.charge.set(0);
+        self.counters.set_queued(0);
+        self.changed.notify_all();
+    }
+
+    /// Wakes a waiting `pop_unless` so it re-checks its `give_up`
+    /// condition.
+    #[cfg(feature = "websocket")]
+    pub fn wake(&self) {
+        // Taking the lock orders this after the waiter's last check
+        let _state = self.lock();
+        self.changed.notify_all();
+    }
+
+    /// Frames dropped from this queue so far.
+    pub fn dropped_total(&self) -> u64 {
+        self.counters.dropped()
+    }
+
+    /// Takes the oldest item out, along with its charge.
+    fn pop(state: &mut State<T>) -> Option<T> {
+        let item = state.items.pop_front()?;
+        state.charge.sub(item.footprint());
+        Some(item)
+    }
+
+    fn taken(&self, state: &State<T>) {
+        self.counters.delivered();
+        self.counters.set_queued(state.items.len());
+        // Makes room for a blocked push
+        self.changed.notify_all();
+    }
+
+    fn dropped(&self, count: usize) {
+        if count > 0 {
+            self.counters.add_dropped(count);
+            self.stats.frames_dropped(count);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+    /// A numbered stand-in for a frame.
+    #[derive(Debug, PartialEq)]
+    struct Item(u32);
+
+    impl Footprint for Item {
+        fn footprint(&self) -> u64 {
+            100
+        }
+    }
+
+    fn queue(policy: QueuePolicy, capacity: usize) -> (Arc<FrameQueue<Item>>, Arc<Stats>) {
+        let stats = Arc::new(Stats::default());
+        let queue = FrameQueue::new(ConsumerKind::Buffered, policy, capacity, &stats);
+        (Arc::new(queue), stats)
+    }
+
+    #[test]
+    fn latest_wins_pushes_out_the_oldest() {
+        let (queue, stats) = queue(QueuePolicy::LatestWins, 2);
+        for i in 0..5 {
+            queue.push(Item(i), || false);
+        }
+        assert_eq!(queue.pop_unless(|| false), Some(Item(3)));
+        assert_eq!(queue.pop_unless(|| false), Some(Item(4)));
+        assert_eq!(queue.dropped_total(), 3);
+        assert_eq!(stats.snapshot().frames_dropped, 3);
+        let consumer = stats.consumers()[0];
+        assert_eq!((consumer.queued, consumer.delivered), (0, 2));
+    }
+
+    #[test]
+    fn take_latest_drops_the_older_frames() {
+        let (queue, stats) = queue(QueuePolicy::LatestWins, 3);
+        assert_eq!(queue.take_latest(), None);
+        for i in 0..3 {
+            queue.push(Item(i), || false);
+        }
+        assert_eq!(queue.take_latest(), Some(Item(2)));
+        assert_eq!(queue.take_latest(), None);
+        assert_eq!(queue.dropped_total(), 2);
+        assert_eq!(stats.memory().used(), 0);
+    }
+
+    /// A consumer slower than the stream misses frames under
+    /// `LatestWins`, but the stream never waits for it and every frame is
+    /// either taken or counted.
+    #[test]
+    fn latest_wins_outruns_a_slow_consumer() {
+        let (queue, _stats) = queue(QueuePolicy::LatestWins, 1);
+        let producer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || {
+                let start = Instant::now();
+                for i in 0..50 {
+                    queue.push(Item(i), || false);
+                    thread::sleep(Duration::from_millis(1));
+                }
+                let elapsed = start.elapsed();
+                queue.close();
+                elapsed
+            })
+        };
+        let mut taken = Vec::new();
+        while let Some(Item(i)) = queue.pop_unless(|| false) {
+            taken.push(i);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let elapsed = producer.join().unwrap();
+
+        assert!(
+            elapsed < Duration::from_millis(400),
+            "pushing took {elapsed:?}"
+        );
+        assert!(taken.windows(2).all(|pair| pair[0] < pair[1]), "{taken:?}");
+        let dropped = queue.dropped_total();
+        assert!(dropped > 0);
+        // The frame queued when the stream closed is discarded uncounted
+        assert!(taken.len() as u64 + dropped >= 49, "{taken:?}, {dropped}");
+    }
+
+    /// Under `Block` the same slow consumer gets every frame, in order,
+    /// and the stream runs at its pace.
+    #[test]
+    fn block_holds_up_the_stream_for_a_slow_consumer() {
+        let (queue, _stats) = queue(QueuePolicy::Block, 1);
+        let producer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || {
+                for i in 0..10 {
+                    queue.push(Item(i), || false);
+                }
+            })
+        };
+        let start = Instant::now();
+        let taken: Vec<u32> = (0..10)
+            .map(|_| {
+                thread::sleep(Duration::from_millis(10));
+                queue.pop_unless(|| false).unwrap().0
+            })
+            .collect();
+        producer.join().unwrap();
+
+        assert_eq!(taken, (0..10).collect::<Vec<_>>());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(queue.dropped_total(), 0);
+    }
+
+    #[test]
+    fn block_waits_for_room_and_gives_up_when_told() {
+        let (queue, _stats) = queue(QueuePolicy::Block, 1);
+        queue.push(Item(0), || false);
+
+        let pushed = Arc::new(AtomicBool::new(false));
+        let producer = {
+            let (queue, pushed) = (Arc::clone(&queue), Arc::clone(&pushed));
+            thread::spawn(move || {
+                queue.push(Item(1), || false);
+                pushed.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!pushed.load(Ordering::SeqCst), "a full queue took a frame");
+        assert_eq!(queue.pop_unless(|| false), Some(Item(0)));
+        producer.join().unwrap();
+        assert!(pushed.load(Ordering::SeqCst));
+
+        // Full again: a push that gives up discards its frame uncounted
+        let give_up = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let blocked = scope.spawn(|| queue.push(Item(2), || give_up.load(Ordering::SeqCst)));
+            thread::sleep(Duration::from_millis(50));
+            give_up.store(true, Ordering::SeqCst);
+            blocked.join().unwrap();
+        });
+        assert_eq!(queue.take_latest(), Some(Item(1)));
+        assert_eq!(queue.dropped_total(), 0);
+    }
+
+    #[test]
+    fn close_releases_waiters_and_ends_the_queue() {
+        let (queue, stats) = queue(QueuePolicy::Block, 1);
+        let consumer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.pop_unless(|| false))
+        };
+        thread::sleep(Duration::from_millis(20));
+        queue.close();
+        assert_eq!(consumer.join().unwrap(), None);
+
+        // Nothing is queued after, or counted as dropped
+        queue.push(Item(0), || false);
+        assert_eq!(queue.take_latest(), None);
+        assert_eq!(queue.dropped_total(), 0);
+        assert_eq!(stats.memory().used(), 0);
+    }
+}
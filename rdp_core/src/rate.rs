@@ -0,0 +1,236 @@
+//! Rate control: steering JPEG quality (and, as a last resort, output size)
+//! from frame to frame so encoded frames stay near a byte budget, which is
+//! either fixed or handed out by a bitrate bucket.
+
+use std::time::Instant;
+
+/// Quality points moved per unit of relative budget error, e.g. a frame 50%
+/// under budget raises the quality by 15.
+const GAIN: f64 = 30.0;
+
+/// Most the quality moves in one frame, so a single outlier (a scene cut,
+/// a blank screen) cannot swing it across the whole range.
+const MAX_STEP: f64 = 20.0;
+
+/// Smallest output scale the controller shrinks to.
+const MIN_SCALE: f32 = 0.25;
+
+/// A frame at maximum quality using less than this share of the budget
+/// lets a reduced resolution grow back by a step.
+const GROW_BELOW: f64 = 0.5;
+
+/// Per-session controller state.
+#[derive(Debug)]
+pub struct QualityController {
+    /// Quality for the next frame; tracked as a float so small corrections
+    /// accumulate instead of rounding away.
+    quality: f64,
+    /// Output size multiplier for the next frame, 1.0 unless the budget
+    /// could not be met at minimum quality.
+    scale: f32,
+}
+
+/// The knobs the controller works within.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    /// Target encoded size of one frame.
+    pub bytes: u32,
+    pub min_quality: u8,
+    pub max_quality: u8,
+    /// Fraction each side shrinks by when even `min_quality` overshoots;
+    /// 0 keeps the resolution.
+    pub downscale_step: f32,
+}
+
+impl QualityController {
+    pub fn new(quality: u8) -> QualityController {
+        QualityController {
+            quality: f64::from(quality),
+            scale: 1.0,
+        }
+    }
+
+    /// Quality to encode the next frame at.
+    pub fn quality(&self, budget: &Budget) -> u8 {
+        (self.quality.round() as u8).clamp(budget.min_quality, budget.max_quality)
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Feeds back the size of a frame encoded at `quality` (as returned by
+    /// `quality`).
+    pub fn update(&mut self, bytes: usize, quality: u8, budget: &Budget) {
+        let (min, max) = (f64::from(budget.min_quality), f64::from(budget.max_quality));
+        let target = f64::from(budget.bytes);
+        let error = (target - bytes as f64) / target;
+        let step = (error * GAIN).clamp(-MAX_STEP, MAX_STEP);
+        self.quality = (self.quality.clamp(min, max) + step).clamp(min, max);
+
+        if budget.downscale_step <= 0.0 {
+            self.scale = 1.0;
+        } else if bytes as f64 > target && quality <= budget.min_quality {
+            self.scale = (self.scale * (1.0 - budget.downscale_step)).max(MIN_SCALE);
+        } else if (bytes as f64) < target * GROW_BELOW
+            && quality >= budget.max_quality
+            && self.scale < 1.0
+        {
+            self.scale = (self.scale / (1.0 - budget.downscale_step)).min(1.0);
+        }
+    }
+}
+
+/// How much unused bitrate a quiet stretch can bank for later frames.
+const WINDOW_SECS: f64 = 1.0;
+
+/// Bounds of one frame's allowance, in shares of the average frame size.
+/// The floor keeps frames coming while debt is repaid; the ceiling stops a
+/// single frame from spending the whole window.
+const MIN_SHARE: f64 = 0.5;
+const MAX_BORROW: f64 = 4.0;
+
+/// Leaky bucket turning a bitrate into per-frame byte budgets. Credit
+/// accrues with wall time (so skipped, unchanged frames still bank it) up
+/// to `WINDOW_SECS` worth; each frame may spend what is there, and a frame
+/// that overspends leaves debt the following ones pay back.
+#[derive(Debug, Default)]
+pub struct BitrateBucket {
+    /// Available bytes; negative while in debt.
+    level: f64,
+    last: Option<Instant>,
+    /// Cancel the debt of the next frame: it is a requested keyframe,
+    /// expected to be large.
+    forgive_next: bool,
+}
+
+impl BitrateBucket {
+    /// Starts over with one average frame of credit, e.g. after the bitrate
+    /// changed.
+    pub fn reset(&mut self) {
+        *self = BitrateBucket::default();
+    }
+
+    /// Byte budget for the next frame at `kbps`, with frames expected
+    /// `fps` times a second.
+    pub fn allowance(&mut self, kbps: u32, fps: f64) -> u32 {
+        self.allowance_at(kbps, fps, Instant::now())
+    }
+
+    fn allowance_at(&mut self, kbps: u32, fps: f64, now: Instant) -> u32 {
+        let rate = f64::from(kbps) * 125.0;
+        let per_frame = rate / fps;
+        self.level = match self.last {
+            Some(last) => self.level + rate * now.duration_since(last).as_secs_f64(),
+            None => per_frame,
+        }
+        .min(rate * WINDOW_SECS);
+        self.last = Some(now);
+
+        (self
+            .level
+            .clamp(per_frame * MIN_SHARE, per_frame * MAX_BORROW) as u32)
+            .max(1)
+    }
+
+    /// Takes the `bytes` a frame used out of the bucket.
+    pub fn spend(&mut self, bytes: usize) {
+        self.level -= bytes as f64;
+        if std::mem::take(&mut self.forgive_next) {
+            self.level = self.level.max(0.0);
+        }
+    }
+
+    /// Lets the next frame, a keyframe, overspend without leaving debt
+    /// behind that would starve the frames after it.
+    pub fn forgive_next(&mut self) {
+        self.forgive_next = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const FPS: f64 = 30.0;
+
+    /// Bytes an encoder makes of a frame of `complexity` at `quality`.
+    fn encoded_size(complexity: f64, quality: u8) -> usize {
+        (complexity * f64::from(quality) * 150.0) as usize
+    }
+
+    /// Runs `seconds` of frames through the bucket and controller at
+    /// `kbps`, returning the bitrate they came out at. Most frames are
+    /// ordinary, with the odd busy frame (a scroll) and quiet stretch
+    /// (typing) in between.
+    fn stream(kbps: u32, seconds: u32, clock: &mut Instant) -> f64 {
+        let mut bucket = BitrateBucket::default();
+        let mut controller = QualityController::new(75);
+        let mut total = 0;
+        let frames = seconds * FPS as u32;
+        for n in 0..frames {
+            let complexity = match n % 45 {
+                0 => 6.0,
+                30..=37 => 0.3,
+                _ => 1.0 + f64::from(n % 7) / 10.0,
+            };
+            let budget = Budget {
+                bytes: bucket.allowance_at(kbps, FPS, *clock),
+                min_quality: 10,
+                max_quality: 95,
+                downscale_step: 0.0,
+            };
+            let quality = controller.quality(&budget);
+            let bytes = encoded_size(complexity, quality);
+            controller.update(bytes, quality, &budget);
+            bucket.spend(bytes);
+            total += bytes;
+            *clock += Duration::from_secs_f64(1.0 / FPS);
+        }
+        total as f64 * 8.0 / 1000.0 / f64::from(seconds)
+    }
+
+    #[test]
+    fn long_run_bitrate_stays_near_the_target() {
+        let mut clock = Instant::now();
+        for kbps in [1000, 2000, 4000] {
+            let achieved = stream(kbps, 120, &mut clock);
+            let error = (achieved - f64::from(kbps)).abs() / f64::from(kbps);
+            assert!(error < 0.1, "{achieved:.0} kbps against {kbps}");
+        }
+    }
+
+    #[test]
+    fn busy_frames_borrow_from_quiet_ones() {
+        let mut bucket = BitrateBucket::default();
+        let mut clock = Instant::now();
+        let per_frame = 1000.0 * 125.0 / FPS;
+        bucket.allowance_at(1000, FPS, clock);
+        bucket.spend(0);
+        // Half a second of nothing banks fifteen frames' worth, capped at
+        // what one frame may borrow
+        clock += Duration::from_millis(500);
+        let allowance = f64::from(bucket.allowance_at(1000, FPS, clock));
+        assert_eq!(allowance, (per_frame * MAX_BORROW).floor());
+
+        // Spending it all and more leaves debt, paid back at the floor
+        bucket.spend(allowance as usize * 8);
+        clock += Duration::from_secs_f64(1.0 / FPS);
+        let allowance = f64::from(bucket.allowance_at(1000, FPS, clock));
+        assert_eq!(allowance, (per_frame * MIN_SHARE).floor());
+    }
+
+    #[test]
+    fn forgiven_keyframes_leave_no_debt() {
+        let mut bucket = BitrateBucket::default();
+        let clock = Instant::now();
+        let per_frame = 1000.0 * 125.0 / FPS;
+        bucket.allowance_at(1000, FPS, clock);
+        bucket.forgive_next();
+        bucket.spend(per_frame as usize * 20);
+        let next = bucket.allowance_at(1000, FPS, clock + Duration::from_secs_f64(1.0 / FPS));
+        assert_eq!(f64::from(next), per_frame.floor());
+    }
+}
@@ -0,0 +1,386 @@
+//! Recording of a session to AVI files with an MJPG stream, which VLC,
+//! ffmpeg and most other players open directly. The recorder does no
+//! capturing or encoding of its own: the session hands it a copy of every
+//! JPEG frame it produces (for the host or for a stream alike), and a
+//! writer thread files them away, so a slow disk never holds up capture.
+//!
+//! An AVI plays at a fixed rate, the `fps` the recording was started with.
+//! Frames are placed by their capture timestamp: where the session produced
+//! none for a while, empty chunks (the AVI way of saying "repeat the last
+//! frame") fill the gap, and frames arriving faster than `fps` are left
+//! out, so the recording keeps wall-clock time either way.
+//!
+//! A file holds a single resolution. When the frame size changes, or the
+//! file reaches `MAX_FILE_LEN`, it is finalized and the recording rolls on
+//! to the next part: `screen.avi`, then `screen-2.avi`, `screen-3.avi`...
+//!
+//! Each file is the classic RIFF layout: an `hdrl` list (`avih` main
+//! header, one `strl` with `strh` and a `BITMAPINFOHEADER` `strf`), a
+//! `movi` list with one `00dc` chunk per frame and an `idx1` index. The
+//! header is written with placeholder counts when the file is opened and
+//! rewritten once the index is in place.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use crate::error::{RdpStatus, fail, fail_file};
+use crate::log::{self, LogLevel};
+
+/// Frames waiting for the writer; when the disk falls further behind,
+/// frames are dropped rather than held in memory.
+const QUEUE_LEN: usize = 32;
+
+/// Size a file may reach before the recording rolls on to the next one.
+/// AVI 1.0 counts bytes in 32 bits, and many players stop at 2 GiB.
+const MAX_FILE_LEN: u64 = 1 << 30;
+
+/// Bytes before the first `movi` chunk.
+const HEADER_LEN: usize = 224;
+
+/// `avih` flag: the file has an `idx1` index.
+const AVIF_HASINDEX: u32 = 0x10;
+
+/// `idx1` flag: the chunk is a keyframe, which every MJPG frame is.
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// A running recording.
+pub struct Recorder {
+    queue: Option<SyncSender<Frame>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// A frame on its way to the writer.
+struct Frame {
+    jpeg: Vec<u8>,
+    width: u32,
+    height: u32,
+    timestamp_us: u64,
+}
+
+impl Recorder {
+    /// Creates the file at `path` and starts the writer thread, which plays
+    /// the frames back at `fps`.
+    pub fn start(path: &str, fps: u32) -> Result<Recorder, RdpStatus> {
+        if fps == 0 {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Recording frame rate must be > 0",
+            ));
+        }
+        let file = File::create(path)
+            .map_err(|e| fail_file(&e, format!("Failed to create recording {path}: {e}")))?;
+
+        let (queue, frames) = mpsc::sync_channel(QUEUE_LEN);
+        let writer = Writer {
+            base: PathBuf::from(path),
+            fps,
+            first: Some(file),
+            parts: 0,
+            avi: None,
+            origin_us: 0,
+        };
+        let thread = thread::Builder::new()
+            .name("rdp-record".to_string())
+            .spawn(move || writer.run(&frames))
+            .map_err(|e| {
+                fail(
+                    RdpStatus::FileError,
+                    format!("Failed to start the recording thread: {e}"),
+                )
+            })?;
+        log::log(LogLevel::Info, &format!("Recording to {path} at {fps} fps"));
+        Ok(Recorder {
+            queue: Some(queue),
+            thread: Some(thread),
+        })
+    }
+
+    /// Queues a copy of a JPEG frame. Returns `false` once the writer has
+    /// given up (after a write error), so the caller can drop the recorder.
+    pub fn record(&self, jpeg: &[u8], width: u32, height: u32, timestamp_us: u64) -> bool {
+        let Some(queue) = &self.queue else {
+            return false;
+        };
+        let frame = Frame {
+            jpeg: jpeg.to_vec(),
+            width,
+            height,
+            timestamp_us,
+        };
+        match queue.try_send(frame) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::log(
+                    LogLevel::Debug,
+                    "Recording is behind; frame left out of the recording",
+                );
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Writes out every queued frame, finalizes the file and waits for the
+    /// writer thread to finish.
+    pub fn stop(mut self) {
+        self.finish();
+    }
+
+    fn finish(&mut self) {
+        // Closing the queue ends the writer once it has drained it
+        self.queue = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// The writer thread's state.
+struct Writer {
+    base: PathBuf,
+    fps: u32,
+    /// The first part's file, created by `start` so a bad path is reported
+    /// to the caller.
+    first: Option<File>,
+    /// Parts opened so far.
+    parts: u32,
+    avi: Option<Avi>,
+    /// Capture timestamp of the current part's first frame.
+    origin_us: u64,
+}
+
+impl Writer {
+    fn run(mut self, frames: &Receiver<Frame>) {
+        let written = frames.iter().try_for_each(|frame| self.write(&frame));
+        let finished = self.avi.take().map_or(Ok(()), Avi::finish);
+        let base = self.base.display();
+        match written.and(finished) {
+            Ok(()) => log::log(
+                LogLevel::Info,
+                &format!("Recording to {base} finished in {} file(s)", self.parts),
+            ),
+            Err(e) => log::log(
+                LogLevel::Error,
+                &format!("Recording to {base} failed and stopped: {e}"),
+            ),
+        }
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        let outgrown = self.avi.as_ref().is_some_and(|avi| {
+            (avi.width, avi.height) != (frame.width, frame.height) || !avi.fits(frame.jpeg.len())
+        });
+        if outgrown && let Some(avi) = self.avi.take() {
+            avi.finish()?;
+        }
+        let avi = match &mut self.avi {
+            Some(avi) => avi,
+            None => {
+                let file = self.next_file()?;
+                self.origin_us = frame.timestamp_us;
+                self.avi
+                    .insert(Avi::create(file, frame.width, frame.height, self.fps)?)
+            }
+        };
+
+        // The slot the frame falls in at the file's frame rate, rounded so
+        // timestamp jitter does not drop frames captured right on time
+        let elapsed = frame.timestamp_us.saturating_sub(self.origin_us);
+        let slot = (elapsed * u64::from(self.fps) + 500_000) / 1_000_000;
+        if slot < avi.frames() {
+            return Ok(());
+        }
+        while avi.frames() < slot {
+            avi.push(&[])?;
+        }
+        avi.push(&frame.jpeg)
+    }
+
+    fn next_file(&mut self) -> io::Result<File> {
+        self.parts += 1;
+        if let Some(file) = self.first.take() {
+            return Ok(file);
+        }
+        let path = part_path(&self.base, self.parts);
+        log::log(
+            LogLevel::Info,
+            &format!("Recording continues in {}", path.display()),
+        );
+        File::create(path)
+    }
+}
+
+/// `screen.avi` for part 1, `screen-2.avi` for part 2 and so on.
+fn part_path(base: &Path, part: u32) -> PathBuf {
+    let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match base.extension() {
+        Some(ext) => format!("{stem}-{part}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{part}"),
+    };
+    base.with_file_name(name)
+}
+
+/// An AVI file being written.
+struct Avi {
+    file: BufWriter<File>,
+    width: u32,
+    height: u32,
+    fps: u32,
+    /// Offset (from the `movi` fourcc) and size of every chunk.
+    index: Vec<(u32, u32)>,
+    /// Bytes of the chunks in `movi`, headers and padding included.
+    movi_len: u64,
+    largest: u32,
+}
+
+impl Avi {
+    fn create(file: File, width: u32, height: u32, fps: u32) -> io::Result<Avi> {
+        let mut avi = Avi {
+            file: BufWriter::new(file),
+            width,
+            height,
+            fps,
+            index: Vec::new(),
+            movi_len: 0,
+            largest: 0,
+        };
+        let header = avi.header();
+        avi.file.write_all(&header)?;
+        Ok(avi)
+    }
+
+    fn frames(&self) -> u64 {
+        self.index.len() as u64
+    }
+
+    /// Whether a `len`-byte frame still fits, index included.
+    fn fits(&self, len: usize) -> bool {
+        let chunks = self.movi_len + 8 + padded(len as u64);
+        let index = 8 + 16 * (self.frames() + 1);
+        HEADER_LEN as u64 + chunks + index <= MAX_FILE_LEN
+    }
+
+    /// Appends a frame; an empty one repeats the frame before it.
+    fn push(&mut self, jpeg: &[u8]) -> io::Result<()> {
+        let len = jpeg.len() as u32;
+        self.file.write_all(b"00dc")?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(jpeg)?;
+        if len % 2 == 1 {
+            // Chunks start on even offsets
+            self.file.write_all(&[0])?;
+        }
+        self.index.push((4 + self.movi_len as u32, len));
+        self.movi_len += 8 + padded(u64::from(len));
+        self.largest = self.largest.max(len);
+        Ok(())
+    }
+
+    /// Writes the index, rewrites the header with the final counts and
+    /// flushes everything to disk.
+    fn finish(mut self) -> io::Result<()> {
+        let mut index = Vec::with_capacity(8 + 16 * self.index.len());
+        index.extend_from_slice(b"idx1");
+        index.extend_from_slice(&(16 * self.index.len() as u32).to_le_bytes());
+        for &(offset, len) in &self.index {
+            let flags = if len > 0 { AVIIF_KEYFRAME } else { 0 };
+            index.extend_from_slice(b"00dc");
+            for value in [flags, offset, len] {
+                index.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        self.file.write_all(&index)?;
+
+        let header = self.header();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+
+    /// Everything up to the first `movi` chunk, for the frames written so
+    /// far.
+    fn header(&self) -> Vec<u8> {
+        let frames = self.index.len() as u32;
+        let index_len = if frames > 0 { 8 + 16 * frames } else { 0 };
+        let riff_len = (HEADER_LEN - 8) as u32 + self.movi_len as u32 + index_len;
+        let buffer = self.largest + 8;
+        let (w, h) = (self.width, self.height);
+
+        let mut out = Vec::with_capacity(HEADER_LEN);
+        let mut put = |fields: &[u32]| {
+            for field in fields {
+                out.extend_from_slice(&field.to_le_bytes());
+            }
+        };
+        put(&[fourcc(b"RIFF"), riff_len, fourcc(b"AVI ")]);
+        put(&[fourcc(b"LIST"), 192, fourcc(b"hdrl")]);
+        put(&[fourcc(b"avih"), 56]);
+        put(&[
+            1_000_000 / self.fps,
+            self.largest.saturating_mul(self.fps),
+            0,
+            AVIF_HASINDEX,
+            frames,
+            0,
+            1,
+            buffer,
+            w,
+            h,
+            0,
+            0,
+            0,
+            0,
+        ]);
+        put(&[fourcc(b"LIST"), 116, fourcc(b"strl")]);
+        put(&[fourcc(b"strh"), 56]);
+        put(&[
+            fourcc(b"vids"),
+            fourcc(b"MJPG"),
+            0,
+            0, // priority and language, u16 each
+            0,
+            1,
+            self.fps,
+            0,
+            frames,
+            buffer,
+            u32::MAX, // default quality
+            0,
+            0, // frame rectangle as i16 left, top, right, bottom
+            (w.min(i16::MAX as u32)) | (h.min(i16::MAX as u32)) << 16,
+        ]);
+        put(&[fourcc(b"strf"), 40]);
+        put(&[
+            40,
+            w,
+            h,
+            1 | 24 << 16,
+            fourcc(b"MJPG"),
+            w * h * 3,
+            0,
+            0,
+            0,
+            0,
+        ]);
+        put(&[fourcc(b"LIST"), 4 + self.movi_len as u32, fourcc(b"movi")]);
+        out
+    }
+}
+
+fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+fn padded(len: u64) -> u64 {
+    len + len % 2
+}
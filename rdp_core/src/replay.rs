@@ -0,0 +1,258 @@
+//! The replay buffer: small copies of a session's most recent frames, kept
+//! so the last few seconds can be exported as an animated GIF (e.g. for a
+//! bug report) after the fact. Like recording, it costs no capture or
+//! encode pass of its own; it samples the frames the session produces.
+//!
+//! Frames are kept at most `SAMPLE_FPS` times a second, downscaled to at
+//! most `MAX_WIDTH` pixels wide and stored as RGB, which bounds a 10 s
+//! buffer to under 40 MB for a 16:9 screen. On top of the duration, the
+//! buffer has a hard byte cap; whichever limit is reached first drops the
+//! oldest frames. The stills are also charged to the session's memory
+//! budget, and give way to it the same way: the oldest go first when a new
+//! still or a capture needs the room.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use fast_image_resize as fr;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, RgbaImage};
+
+use crate::error::{RdpStatus, fail, fail_file};
+use crate::frame::PixelFormat;
+use crate::log::{self, LogLevel};
+use crate::memory::{Charge, MemoryBudget};
+use crate::pixels;
+
+/// Widest frame the buffer keeps; wider ones are scaled down to this.
+pub const MAX_WIDTH: u32 = 480;
+
+/// Most frames kept per second; GIF viewers rarely play anything faster.
+const SAMPLE_FPS: u64 = 10;
+
+/// Byte cap used when the host does not give one.
+pub const DEFAULT_MAX_BYTES: u64 = 64 << 20;
+
+/// GIF encoder speed (1–30): well below 1's exhaustive quantization, which
+/// takes seconds per frame, with little visible difference.
+const GIF_SPEED: i32 = 10;
+
+/// One kept frame.
+pub struct Still {
+    rgb: Vec<u8>,
+    width: u32,
+    height: u32,
+    timestamp_us: u64,
+}
+
+pub struct ReplayBuffer {
+    duration_us: u64,
+    max_bytes: u64,
+    /// Oldest first; shared with exports running outside the session lock.
+    stills: VecDeque<Arc<Still>>,
+    /// What the stills hold, against the session's memory budget.
+    charge: Charge,
+    resizer: fr::Resizer,
+}
+
+impl ReplayBuffer {
+    pub fn new(seconds: u32, max_bytes: u64, memory: &Arc<MemoryBudget>) -> ReplayBuffer {
+        let mut buffer = ReplayBuffer {
+            duration_us: 0,
+            max_bytes: 0,
+            stills: VecDeque::new(),
+            charge: Charge::new(memory),
+            resizer: fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::Bilinear)),
+        };
+        buffer.set_limits(seconds, max_bytes);
+        buffer
+    }
+
+    /// Changes how much is kept, dropping what no longer fits.
+    pub fn set_limits(&mut self, seconds: u32, max_bytes: u64) {
+        self.duration_us = u64::from(seconds) * 1_000_000;
+        self.max_bytes = max_bytes;
+        self.trim();
+    }
+
+    /// Offers the output frame `pixels` (`width` x `height`, BGRA or, for
+    /// grayscale sessions, luma) captured at `timestamp_us`; it is kept if
+    /// the last one kept is old enough.
+    pub fn offer(&mut self, pixels: &[u8], width: u32, height: u32, gray: bool, timestamp_us: u64) {
+        if let Some(last) = self.stills.back()
+            && timestamp_us.saturating_sub(last.timestamp_us) < 1_000_000 / SAMPLE_FPS
+        {
+            return;
+        }
+        match self.shrink(pixels, width, height, gray) {
+            Some((rgb, width, height)) => {
+                while !self.charge.add(rgb.len() as u64) {
+                    if self.pop().is_none() {
+                        log::log(
+                            LogLevel::Debug,
+                            "Replay buffer skipped a frame for the memory limit",
+                        );
+                        return;
+                    }
+                }
+                self.stills.push_back(Arc::new(Still {
+                    rgb,
+                    width,
+                    height,
+                    timestamp_us,
+                }));
+                self.trim();
+            }
+            None => log::log(LogLevel::Debug, "Replay buffer skipped a frame"),
+        }
+    }
+
+    /// The frames kept, oldest first.
+    pub fn stills(&self) -> Vec<Arc<Still>> {
+        self.stills.iter().cloned().collect()
+    }
+
+    /// Drops the oldest stills until `bytes` are freed or none is left;
+    /// whether anything was freed.
+    pub fn shed(&mut self, bytes: u64) -> bool {
+        let mut freed = 0;
+        while freed < bytes
+            && let Some(still) = self.pop()
+        {
+            freed += still.rgb.len() as u64;
+        }
+        freed > 0
+    }
+
+    fn trim(&mut self) {
+        let newest = self.stills.back().map_or(0, |s| s.timestamp_us);
+        while let Some(oldest) = self.stills.front()
+            && (self.charge.bytes() > self.max_bytes
+                || newest - oldest.timestamp_us > self.duration_us)
+        {
+            self.pop();
+        }
+    }
+
+    /// Takes the oldest still out, along with its charge.
+    fn pop(&mut self) -> Option<Arc<Still>> {
+        let still = self.stills.pop_front()?;
+        self.charge.sub(still.rgb.len() as u64);
+        Some(still)
+    }
+
+    /// `pixels` as RGB, at most `MAX_WIDTH` wide.
+    fn shrink(
+        &mut self,
+        pixels: &[u8],
+        width: u32,
+        height: u32,
+        gray: bool,
+    ) -> Option<(Vec<u8>, u32, u32)> {
+        let mut rgb = Vec::new();
+        if gray {
+            rgb.extend(pixels.iter().flat_map(|&y| [y, y, y]));
+        } else {
+            pixels::convert_bgra(pixels, PixelFormat::Rgb, &mut rgb);
+        }
+        resize_rgb(&mut self.resizer, rgb, width, height, MAX_WIDTH)
+    }
+}
+
+/// `rgb` scaled down to at most `max_width` wide, keeping its aspect ratio.
+fn resize_rgb(
+    resizer: &mut fr::Resizer,
+    rgb: Vec<u8>,
+    width: u32,
+    height: u32,
+    max_width: u32,
+) -> Option<(Vec<u8>, u32, u32)> {
+    if width <= max_width {
+        return Some((rgb, width, height));
+    }
+    let out_h = ((u64::from(height) * u64::from(max_width)) / u64::from(width)).max(1) as u32;
+    let src = fr::Image::from_vec_u8(
+        NonZeroU32::new(width)?,
+        NonZeroU32::new(height)?,
+        rgb,
+        fr::PixelType::U8x3,
+    )
+    .ok()?;
+    let mut dst = fr::Image::new(
+        NonZeroU32::new(max_width)?,
+        NonZeroU32::new(out_h)?,
+        fr::PixelType::U8x3,
+    );
+    resizer.resize(&src.view(), &mut dst.view_mut()).ok()?;
+    Some((dst.into_vec(), max_width, out_h))
+}
+
+/// Writes `stills` to `path` as a looping animated GIF, each frame shown
+/// until the next one was captured, scaled down to at most `max_width`
+/// (0 = as kept). A GIF has a single size, so only the frames since the
+/// last change of resolution are exported.
+pub fn export_gif(stills: &[Arc<Still>], path: &str, max_width: u32) -> Result<(), RdpStatus> {
+    let Some(newest) = stills.last() else {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            "The replay buffer holds no frames yet",
+        ));
+    };
+    let size = (newest.width, newest.height);
+    let first = stills
+        .iter()
+        .rposition(|s| (s.width, s.height) != size)
+        .map_or(0, |i| i + 1);
+    let stills = &stills[first..];
+
+    // Encoded in memory, so a failed write is reported rather than lost in
+    // the encoder's drop
+    let mut gif = Vec::new();
+    let mut encoder = GifEncoder::new_with_speed(&mut gif, GIF_SPEED);
+    let encode_failed =
+        |e: image::ImageError| fail(RdpStatus::EncodeFailed, format!("GIF encoding failed: {e}"));
+    encoder
+        .set_repeat(Repeat::Infinite)
+        .map_err(encode_failed)?;
+
+    let mut resizer = fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::Bilinear));
+    let max_width = if max_width == 0 { u32::MAX } else { max_width };
+    for (i, still) in stills.iter().enumerate() {
+        let shown_us = stills.get(i + 1).map_or(1_000_000 / SAMPLE_FPS, |next| {
+            next.timestamp_us - still.timestamp_us
+        });
+        let (rgb, width, height) = resize_rgb(
+            &mut resizer,
+            still.rgb.clone(),
+            still.width,
+            still.height,
+            max_width,
+        )
+        .ok_or_else(|| fail(RdpStatus::ResizeFailed, "Failed to scale a GIF frame"))?;
+        let rgba = rgb
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 0xff])
+            .collect();
+        let image = RgbaImage::from_raw(width, height, rgba).ok_or_else(|| {
+            fail(
+                RdpStatus::BufferFailed,
+                "GIF frame buffer has the wrong size",
+            )
+        })?;
+        let delay =
+            Delay::from_numer_denom_ms((shown_us / 1000).min(u64::from(u32::MAX)) as u32, 1);
+        encoder
+            .encode_frame(Frame::from_parts(image, 0, 0, delay))
+            .map_err(encode_failed)?;
+    }
+    // Writes the trailer
+    drop(encoder);
+    fs::write(path, &gif).map_err(|e| fail_file(&e, format!("Failed to write {path}: {e}")))?;
+    log::log(
+        LogLevel::Info,
+        &format!("Exported {} frame(s) to {path}", stills.len()),
+    );
+    Ok(())
+}
@@ -0,0 +1,184 @@
+//! JPEG restart markers, after which a decoder starts afresh, so a lost or
+//! corrupted stretch of a frame only damages the band it falls in rather
+//! than everything below it.
+//!
+//! Neither turbojpeg's API nor the `image` encoder can set a restart
+//! interval. Instead the frame is encoded as a stack of strips, each one
+//! interval high, and `join` splices their scans into a single JPEG: the
+//! first strip's header, with the full height and a DRI segment, then each
+//! strip's entropy-coded data followed by the next RSTn marker. A scan
+//! begins just like the data after a restart marker (on a byte boundary,
+//! with the DC predictions reset), and both encoders use the standard
+//! Huffman tables, so the result is what an encoder with the interval set
+//! would have written.
+
+use crate::error::{RdpStatus, fail};
+
+pub const SOF0: u8 = 0xC0;
+const SOI: u8 = 0xD8;
+pub const EOI: u8 = 0xD9;
+pub const SOS: u8 = 0xDA;
+pub const DRI: u8 = 0xDD;
+/// The first of the eight restart markers, RST0 to RST7, used in turn.
+const RST0: u8 = 0xD0;
+
+/// Where a baseline JPEG's parts are.
+struct Layout {
+    /// Offset of the SOF0 segment's height field.
+    height_at: usize,
+    /// Offset of the SOS marker, and of the scan data after its segment.
+    scan_at: usize,
+    data_at: usize,
+}
+
+/// A marker segment of a JPEG's header.
+pub struct Segment<'a> {
+    pub marker: u8,
+    /// Offset of the marker.
+    pub at: usize,
+    /// What follows the length field.
+    pub body: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// Offset of whatever follows the segment.
+    pub fn end(&self) -> usize {
+        self.at + 4 + self.body.len()
+    }
+}
+
+/// The marker segments of a JPEG's header, from after SOI up to and
+/// including SOS, after which the scan data starts. Stops early at
+/// anything that is not a well-formed segment.
+pub fn segments(jpeg: &[u8]) -> impl Iterator<Item = Segment<'_>> {
+    let mut at = 2;
+    std::iter::from_fn(move || {
+        let [0xFF, marker, hi, lo, ..] = *jpeg.get(at..)? else {
+            return None;
+        };
+        let end = at + 2 + usize::from(u16::from_be_bytes([hi, lo]));
+        if end < at + 4 || end > jpeg.len() {
+            return None;
+        }
+        let segment = Segment {
+            marker,
+            at,
+            body: &jpeg[at + 4..end],
+        };
+        // Nothing after the scan's header is a segment
+        at = if marker == SOS { jpeg.len() } else { end };
+        Some(segment)
+    })
+}
+
+/// Joins `strips`, the JPEGs of consecutive bands of one `height`-pixel
+/// frame, into one with a restart marker every `interval` MCUs. Every strip
+/// but the last must hold exactly `interval` MCUs, and all of them must have
+/// been encoded with the same settings.
+pub fn join(strips: &[Vec<u8>], height: u32, interval: u16) -> Result<Vec<u8>, RdpStatus> {
+    let height = u16::try_from(height).map_err(|_| {
+        fail(
+            RdpStatus::EncodeFailed,
+            format!("JPEG height {height} is too large"),
+        )
+    })?;
+    let layouts = strips
+        .iter()
+        .map(|strip| layout(strip))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (Some(first), Some(head)) = (strips.first(), layouts.first()) else {
+        return Err(fail(RdpStatus::EncodeFailed, "No JPEG strips to join"));
+    };
+
+    // The strips may only differ in height, or their data would not decode
+    // with the first one's tables
+    let header = |strip: &[u8], at: &Layout| {
+        let mut header = strip[..at.data_at].to_vec();
+        header[at.height_at..at.height_at + 2].fill(0);
+        header
+    };
+    let expected = header(first, head);
+    if strips
+        .iter()
+        .zip(&layouts)
+        .any(|(strip, at)| header(strip, at) != expected)
+    {
+        return Err(fail(
+            RdpStatus::EncodeFailed,
+            "JPEG strips were encoded with different tables",
+        ));
+    }
+
+    let data_len: usize = strips
+        .iter()
+        .zip(&layouts)
+        .map(|(strip, at)| strip.len() - at.data_at)
+        .sum();
+    let mut out = Vec::with_capacity(head.data_at + 6 + data_len + 2 * strips.len());
+    out.extend_from_slice(&first[..head.scan_at]);
+    out[head.height_at..head.height_at + 2].copy_from_slice(&height.to_be_bytes());
+    out.extend_from_slice(&[0xFF, DRI, 0, 4]);
+    out.extend_from_slice(&interval.to_be_bytes());
+    out.extend_from_slice(&first[head.scan_at..head.data_at]);
+    for (i, (strip, at)) in strips.iter().zip(&layouts).enumerate() {
+        if i > 0 {
+            out.extend_from_slice(&[0xFF, RST0 + (i as u8 - 1) % 8]);
+        }
+        // Without the strip's EOI, which `layout` checked for
+        out.extend_from_slice(&strip[at.data_at..strip.len() - 2]);
+    }
+    out.extend_from_slice(&[0xFF, EOI]);
+    Ok(out)
+}
+
+/// Offsets of the restart markers in `jpeg`'s scan data, each the start of
+/// a stretch a decoder can pick up at; empty without restart markers.
+/// Stuffing keeps 0xFF followed by a marker code out of the data itself.
+pub fn markers(jpeg: &[u8]) -> Vec<usize> {
+    let Some(start) = segments(jpeg)
+        .find(|segment| segment.marker == SOS)
+        .map(|sos| sos.end())
+    else {
+        return Vec::new();
+    };
+    jpeg[start..]
+        .windows(2)
+        .enumerate()
+        .filter(|(_, pair)| pair[0] == 0xFF && (RST0..RST0 + 8).contains(&pair[1]))
+        .map(|(i, _)| start + i)
+        .collect()
+}
+
+/// Finds the parts of a single-scan baseline JPEG ending in EOI, as both
+/// encoders write them.
+fn layout(jpeg: &[u8]) -> Result<Layout, RdpStatus> {
+    let malformed = |what: &str| {
+        fail(
+            RdpStatus::EncodeFailed,
+            format!("Cannot add restart markers to the JPEG: {what}"),
+        )
+    };
+    if !jpeg.starts_with(&[0xFF, SOI]) || !jpeg.ends_with(&[0xFF, EOI]) {
+        return Err(malformed("not a complete JPEG"));
+    }
+
+    let mut height_at = None;
+    for segment in segments(jpeg) {
+        match segment.marker {
+            SOF0 => height_at = Some(segment.at + 5),
+            SOS => {
+                return match height_at {
+                    Some(height_at) => Ok(Layout {
+                        height_at,
+                        scan_at: segment.at,
+                        data_at: segment.end(),
+                    }),
+                    None => Err(malformed("no baseline frame header")),
+                };
+            }
+            DRI => return Err(malformed("it already has a restart interval")),
+            _ => {}
+        }
+    }
+    Err(malformed("no scan"))
+}
@@ -0,0 +1,420 @@
+//! RTP packets carrying JPEG frames as RFC 2435 payloads, for receivers that
+//! speak RTP/JPEG (static payload type 26).
+//!
+//! The format describes baseline colour frames at 4:2:2 (type 0) or 4:2:0
+//! (type 1), coded with the standard Huffman tables, which every baseline
+//! frame this library encodes uses, and at most 2040 pixels a side in
+//! multiples of 8. Quantization tables travel in band (Q = 255) in the
+//! first packet of every frame, so any quality works. Frames with restart
+//! markers (see `restart`) are sent as types 64 and 65, whose restart header
+//! lets a receiver decode past a lost packet.
+
+use crate::error::{RdpStatus, fail};
+use crate::restart::{self, DRI, EOI, SOF0};
+
+/// RTP payload type of JPEG.
+pub const PAYLOAD_TYPE: u8 = 26;
+
+const DQT: u8 = 0xDB;
+/// The RTP header, then the JPEG header every packet starts with.
+const HEADER_LEN: usize = 12 + 8;
+/// The restart marker header of types 64 to 127.
+const RESTART_HEADER_LEN: usize = 4;
+/// The quantization table header of a frame's first packet, with the luma
+/// and chroma tables.
+const TABLES_LEN: usize = 4 + 2 * 64;
+/// Q values from 128 say the tables are in band; 255 that they may change
+/// from one frame to the next.
+const Q_IN_BAND: u8 = 255;
+/// Largest side RFC 2435 can describe, in 8-pixel units of one byte.
+const MAX_SIDE: u32 = 255 * 8;
+
+/// A packet, as handed to FFI callers.
+#[repr(C)]
+pub struct RtpPacket {
+    pub data: *const u8,
+    pub len: usize,
+}
+
+/// The packets of one frame, from `rdp_packetize_rtp_jpeg`; release them
+/// with `rdp_rtp_packets_free`.
+#[repr(C)]
+pub struct RtpPackets {
+    /// `count` packets, to send in order.
+    pub packets: *const RtpPacket,
+    pub count: usize,
+    /// The sequence number after the last packet's, to start the next
+    /// frame at.
+    pub next_seq: u16,
+    descriptors: Vec<RtpPacket>,
+    /// What `descriptors` point into.
+    bytes: Vec<Vec<u8>>,
+}
+
+impl RtpPackets {
+    /// Wraps the packets `packetize_jpeg` made starting at `seq_start`.
+    pub fn new(bytes: Vec<Vec<u8>>, seq_start: u16) -> RtpPackets {
+        let descriptors: Vec<RtpPacket> = bytes
+            .iter()
+            .map(|packet| RtpPacket {
+                data: packet.as_ptr(),
+                len: packet.len(),
+            })
+            .collect();
+        RtpPackets {
+            packets: descriptors.as_ptr(),
+            count: descriptors.len(),
+            next_seq: seq_start.wrapping_add(descriptors.len() as u16),
+            descriptors,
+            bytes,
+        }
+    }
+}
+
+/// What a frame's packets are made of.
+struct Frame<'a> {
+    /// RFC 2435 type, before restart markers add 64.
+    kind: u8,
+    width: u32,
+    height: u32,
+    /// Luma and chroma quantization tables, in zigzag order.
+    tables: [&'a [u8]; 2],
+    restart_interval: u16,
+    /// The entropy-coded data, without EOI.
+    scan: &'a [u8],
+}
+
+/// Splits `jpeg` into RTP packets of at most `mtu` bytes, headers included,
+/// numbered from `seq_start`, all stamped with `timestamp` (90 kHz) and
+/// `ssrc`, with the marker bit on the last. Fails with
+/// `RdpStatus::Unsupported` for frames RFC 2435 cannot describe and
+/// `RdpStatus::InvalidArgument` for anything that is not a JPEG or an `mtu`
+/// too small to make progress.
+pub fn packetize_jpeg(
+    jpeg: &[u8],
+    mtu: u16,
+    ssrc: u32,
+    seq_start: u16,
+    timestamp: u32,
+) -> Result<Vec<Vec<u8>>, RdpStatus> {
+    let frame = parse(jpeg)?;
+    let restart_len = if frame.restart_interval > 0 {
+        RESTART_HEADER_LEN
+    } else {
+        0
+    };
+    let mtu = usize::from(mtu);
+    if mtu <= HEADER_LEN + restart_len + TABLES_LEN {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            format!(
+                "An MTU of {mtu} bytes leaves no room for JPEG data after the RTP/JPEG headers"
+            ),
+        ));
+    }
+    // Fragment offsets have 24 bits
+    if frame.scan.len() >= 1 << 24 {
+        return Err(fail(
+            RdpStatus::Unsupported,
+            format!(
+                "The {}-byte JPEG is too large for RTP/JPEG fragment offsets",
+                jpeg.len()
+            ),
+        ));
+    }
+
+    let mut packets = Vec::new();
+    let mut offset = 0;
+    let mut seq = seq_start;
+    while offset < frame.scan.len() {
+        let first = offset == 0;
+        let headers = HEADER_LEN + restart_len + if first { TABLES_LEN } else { 0 };
+        let end = frame.scan.len().min(offset + mtu - headers);
+        let last = end == frame.scan.len();
+
+        let mut packet = Vec::with_capacity(headers + end - offset);
+        // Version 2, without padding, extension or contributing sources
+        packet.push(0x80);
+        packet.push(u8::from(last) << 7 | PAYLOAD_TYPE);
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+
+        // Type-specific 0 (progressive scan), then the fragment offset
+        packet.extend_from_slice(&(offset as u32).to_be_bytes());
+        packet.extend_from_slice(&[
+            frame.kind + if restart_len > 0 { 64 } else { 0 },
+            Q_IN_BAND,
+            (frame.width / 8) as u8,
+            (frame.height / 8) as u8,
+        ]);
+        if restart_len > 0 {
+            // F and L set with a count of 0x3FFF: packets are not cut at
+            // the restart intervals
+            packet.extend_from_slice(&frame.restart_interval.to_be_bytes());
+            packet.extend_from_slice(&[0xFF, 0xFF]);
+        }
+        if first {
+            // MBZ, 8-bit precision for both tables, their length
+            packet.extend_from_slice(&[0, 0]);
+            packet.extend_from_slice(&(2 * 64u16).to_be_bytes());
+            packet.extend_from_slice(frame.tables[0]);
+            packet.extend_from_slice(frame.tables[1]);
+        }
+        packet.extend_from_slice(&frame.scan[offset..end]);
+
+        packets.push(packet);
+        offset = end;
+        seq = seq.wrapping_add(1);
+    }
+    Ok(packets)
+}
+
+/// Reads what RFC 2435 needs from `jpeg`'s header, checking the frame fits
+/// one of its types.
+fn parse(jpeg: &[u8]) -> Result<Frame<'_>, RdpStatus> {
+    let unsupported = |what: String| fail(RdpStatus::Unsupported, what);
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err(fail(RdpStatus::InvalidArgument, "The frame is not a JPEG"));
+    }
+
+    let mut size = None;
+    let mut kind = None;
+    let mut tables: [Option<&[u8]>; 4] = [None; 4];
+    let mut restart_interval = 0;
+    let mut scan_at = None;
+    for segment in restart::segments(jpeg) {
+        let body = segment.body;
+        match segment.marker {
+            SOF0 if body.len() >= 6 => {
+                let height = u32::from(u16::from_be_bytes([body[1], body[2]]));
+                let width = u32::from(u16::from_be_bytes([body[3], body[4]]));
+                size = Some((width, height));
+                // Components as (sampling, table): luma sampled 2x1 or 2x2
+                // on table 0, both chroma 1x1 on table 1
+                let components: Vec<(u8, u8)> =
+                    body[6..].chunks_exact(3).map(|c| (c[1], c[2])).collect();
+                kind = match components[..] {
+                    [(0x21, 0), (0x11, 1), (0x11, 1)] => Some(0),
+                    [(0x22, 0), (0x11, 1), (0x11, 1)] => Some(1),
+                    _ => None,
+                };
+            }
+            // Every other start of frame but DHT, JPG and DAC
+            0xC1..=0xCF if !matches!(segment.marker, 0xC4 | 0xC8 | 0xCC) => {
+                return Err(unsupported(
+                    "RTP/JPEG only carries baseline JPEG, not progressive".into(),
+                ));
+            }
+            DQT => {
+                let mut rest = body;
+                while let [pq_tq, table @ ..] = rest {
+                    if pq_tq >> 4 != 0 || table.len() < 64 {
+                        return Err(unsupported(
+                            "RTP/JPEG only carries 8-bit quantization tables".into(),
+                        ));
+                    }
+                    tables[usize::from(pq_tq & 3)] = Some(&table[..64]);
+                    rest = &table[64..];
+                }
+            }
+            DRI if body.len() >= 2 => restart_interval = u16::from_be_bytes([body[0], body[1]]),
+            restart::SOS => scan_at = Some(segment.end()),
+            _ => {}
+        }
+    }
+
+    let (Some((width, height)), Some(scan_at)) = (size, scan_at) else {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            "The JPEG has no baseline frame header or no scan",
+        ));
+    };
+    let Some(kind) = kind else {
+        return Err(unsupported(
+            "RTP/JPEG only carries 4:2:2 and 4:2:0 colour JPEG; set one of those subsamplings"
+                .into(),
+        ));
+    };
+    if width % 8 != 0 || height % 8 != 0 || width > MAX_SIDE || height > MAX_SIDE {
+        return Err(unsupported(format!(
+            "RTP/JPEG only carries frames up to {MAX_SIDE} pixels a side in multiples of 8, \
+             not {width}x{height}"
+        )));
+    }
+    let (Some(luma), Some(chroma)) = (tables[0], tables[1]) else {
+        return Err(fail(
+            RdpStatus::InvalidArgument,
+            "The JPEG lacks its quantization tables",
+        ));
+    };
+
+    let scan = &jpeg[scan_at..];
+    Ok(Frame {
+        kind,
+        width,
+        height,
+        tables: [luma, chroma],
+        restart_interval,
+        scan: scan.strip_suffix(&[0xFF, EOI]).unwrap_or(scan),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u16 = 128;
+    const HEIGHT: u16 = 64;
+    const SCAN_LEN: usize = 3000;
+
+    fn luma() -> Vec<u8> {
+        (1..=64).collect()
+    }
+
+    fn chroma() -> Vec<u8> {
+        (101..=164).collect()
+    }
+
+    fn scan() -> Vec<u8> {
+        // Never 0xFF, so no byte needs stuffing
+        (0..SCAN_LEN).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn segment(jpeg: &mut Vec<u8>, marker: u8, body: &[u8]) {
+        jpeg.extend_from_slice(&[0xFF, marker]);
+        jpeg.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+        jpeg.extend_from_slice(body);
+    }
+
+    /// A baseline JPEG with the tables above, luma sampled `sampling`
+    /// (0x21 for 4:2:2, 0x22 for 4:2:0) and chroma 1x1, and a restart
+    /// interval unless it is 0. Only the headers are real.
+    fn jpeg(sof: u8, sampling: u8, restart_interval: u16) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        segment(
+            &mut jpeg,
+            DQT,
+            &[&[0][..], &luma(), &[1], &chroma()].concat(),
+        );
+        let mut frame = vec![8];
+        frame.extend_from_slice(&HEIGHT.to_be_bytes());
+        frame.extend_from_slice(&WIDTH.to_be_bytes());
+        frame.extend_from_slice(&[3, 1, sampling, 0, 2, 0x11, 1, 3, 0x11, 1]);
+        segment(&mut jpeg, sof, &frame);
+        if restart_interval > 0 {
+            segment(&mut jpeg, DRI, &restart_interval.to_be_bytes());
+        }
+        segment(
+            &mut jpeg,
+            restart::SOS,
+            &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0],
+        );
+        jpeg.extend_from_slice(&scan());
+        jpeg.extend_from_slice(&[0xFF, EOI]);
+        jpeg
+    }
+
+    /// The JPEG data each packet carries after its headers.
+    fn payloads(packets: &[Vec<u8>], restart: bool) -> Vec<&[u8]> {
+        let restart_len = if restart { RESTART_HEADER_LEN } else { 0 };
+        packets
+            .iter()
+            .enumerate()
+            .map(|(i, packet)| {
+                let tables = if i == 0 { TABLES_LEN } else { 0 };
+                &packet[HEADER_LEN + restart_len + tables..]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packets_carry_the_rtp_jpeg_headers() {
+        let (ssrc, seq_start, timestamp) = (0xDEAD_BEEF, 65534, 90_000);
+        let packets =
+            packetize_jpeg(&jpeg(SOF0, 0x21, 0), 500, ssrc, seq_start, timestamp).unwrap();
+        assert_eq!(packets.len(), 7);
+
+        let mut offset = 0;
+        for (i, (packet, payload)) in packets.iter().zip(payloads(&packets, false)).enumerate() {
+            assert!(packet.len() <= 500);
+            assert_eq!(packet[0], 0x80, "version 2");
+            let last = i == packets.len() - 1;
+            assert_eq!(packet[1], u8::from(last) << 7 | PAYLOAD_TYPE, "packet {i}");
+            let seq = u16::from_be_bytes([packet[2], packet[3]]);
+            assert_eq!(seq, seq_start.wrapping_add(i as u16));
+            assert_eq!(packet[4..8], timestamp.to_be_bytes());
+            assert_eq!(packet[8..12], ssrc.to_be_bytes());
+
+            // Type-specific, fragment offset, type 0, Q, width and height
+            assert_eq!(packet[12], 0);
+            let fragment_offset = u32::from_be_bytes([0, packet[13], packet[14], packet[15]]);
+            assert_eq!(fragment_offset as usize, offset, "packet {i}");
+            assert_eq!(packet[16..20], [0, Q_IN_BAND, 16, 8]);
+            offset += payload.len();
+        }
+        assert_eq!(offset, SCAN_LEN);
+        let rejoined: Vec<u8> = payloads(&packets, false).concat();
+        assert_eq!(rejoined, scan());
+    }
+
+    /// The first packet, and only it, carries both tables in band.
+    #[test]
+    fn quantization_tables_travel_in_the_first_packet() {
+        let packets = packetize_jpeg(&jpeg(SOF0, 0x21, 0), 500, 1, 0, 0).unwrap();
+        let tables = &packets[0][HEADER_LEN..HEADER_LEN + TABLES_LEN];
+        assert_eq!(tables[..4], [0, 0, 0, 128], "MBZ, precision and length");
+        assert_eq!(tables[4..68], luma());
+        assert_eq!(tables[68..], chroma());
+        for packet in &packets[1..] {
+            assert_eq!(packet[17], Q_IN_BAND);
+        }
+    }
+
+    /// Restart markers add 64 to the type and a restart header to every
+    /// packet, ahead of the tables.
+    #[test]
+    fn restart_intervals_make_types_from_64() {
+        let packets = packetize_jpeg(&jpeg(SOF0, 0x22, 4), 500, 1, 0, 0).unwrap();
+        for packet in &packets {
+            assert_eq!(packet[16], 1 + 64, "4:2:0 with restarts");
+            assert_eq!(packet[20..24], [0, 4, 0xFF, 0xFF]);
+        }
+        let tables = &packets[0][HEADER_LEN + RESTART_HEADER_LEN..][..TABLES_LEN];
+        assert_eq!(tables[4..68], luma());
+        assert_eq!(payloads(&packets, true).concat(), scan());
+    }
+
+    #[test]
+    fn frames_rfc_2435_cannot_describe_are_refused() {
+        let packetize = |jpeg: &[u8], mtu| packetize_jpeg(jpeg, mtu, 1, 0, 0);
+        assert_eq!(
+            packetize(&jpeg(SOF0, 0x11, 0), 500),
+            Err(RdpStatus::Unsupported),
+            "4:4:4"
+        );
+        assert_eq!(
+            packetize(&jpeg(0xC2, 0x21, 0), 500),
+            Err(RdpStatus::Unsupported),
+            "progressive"
+        );
+        let mut odd = jpeg(SOF0, 0x21, 0);
+        let at = restart::segments(&odd)
+            .find(|s| s.marker == SOF0)
+            .unwrap()
+            .at;
+        odd[at + 8] = 100;
+        assert_eq!(
+            packetize(&odd, 500),
+            Err(RdpStatus::Unsupported),
+            "width 100"
+        );
+
+        assert_eq!(packetize(b"GIF89a", 500), Err(RdpStatus::InvalidArgument));
+        let tiny = (HEADER_LEN + TABLES_LEN) as u16;
+        assert_eq!(
+            packetize(&jpeg(SOF0, 0x21, 0), tiny),
+            Err(RdpStatus::InvalidArgument)
+        );
+    }
+}
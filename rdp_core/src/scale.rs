@@ -0,0 +1,204 @@
+//! Output-size arithmetic and filter selection for the resize stage.
+
+use fast_image_resize as fr;
+
+use crate::pixels::Rect;
+
+/// How a frame is mapped onto an explicit `target_w x target_h`.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FitMode {
+    /// Resize straight to the target, distorting the aspect ratio.
+    Stretch = 0,
+    /// Scale to the largest size that fits and pad the rest with the fill
+    /// colour (letterbox / pillarbox).
+    Fit = 1,
+    /// Scale to cover the target and crop the overflow, keeping the centre.
+    Fill = 2,
+}
+
+impl FitMode {
+    /// Maps an FFI fit-mode value back onto the enum.
+    pub fn from_u32(value: u32) -> Option<FitMode> {
+        match value {
+            0 => Some(FitMode::Stretch),
+            1 => Some(FitMode::Fit),
+            2 => Some(FitMode::Fill),
+            _ => None,
+        }
+    }
+}
+
+/// Maps the FFI resize algorithm (0 = nearest, 1 = bilinear,
+/// 2 = Catmull-Rom bicubic, 3 = Lanczos3) onto `fast_image_resize`.
+///
+/// Nearest is by far the cheapest but drops pixels when downscaling, which
+/// makes thin text shimmer; the convolution filters cost progressively more
+/// per frame in exchange for smoother output.
+pub fn resize_alg_from_u32(value: u32) -> Option<fr::ResizeAlg> {
+    match value {
+        0 => Some(fr::ResizeAlg::Nearest),
+        1 => Some(fr::ResizeAlg::Convolution(fr::FilterType::Bilinear)),
+        2 => Some(fr::ResizeAlg::Convolution(fr::FilterType::CatmullRom)),
+        3 => Some(fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3)),
+        _ => None,
+    }
+}
+
+/// Largest size with the aspect ratio of `src_w x src_h` that fits inside
+/// `box_w x box_h`. The limiting axis is filled exactly, the other is
+/// rounded to the nearest pixel and never drops to zero.
+pub fn fit_within(src_w: u32, src_h: u32, box_w: u32, box_h: u32) -> (u32, u32) {
+    let (sw, sh, bw, bh) = (
+        u64::from(src_w),
+        u64::from(src_h),
+        u64::from(box_w),
+        u64::from(box_h),
+    );
+
+    // Compare aspect ratios by cross-multiplying so no float rounding can
+    // pick the wrong axis
+    if sw * bh >= sh * bw {
+        let h = div_round(sh * bw, sw).clamp(1, bh);
+        (box_w, h as u32)
+    } else {
+        let w = div_round(sw * bh, sh).clamp(1, bw);
+        (w as u32, box_h)
+    }
+}
+
+/// The centred part of `area` with the aspect ratio of `target`, which
+/// `FitMode::Fill` scales to the target. The arithmetic is that of
+/// `fr::ImageView::set_crop_box_to_fit_dst_size` (in `f32`, like Pillow's
+/// `ImageOps.fit`), but within `area` rather than the whole image, so a
+/// region crops the same whether it was copied out of the frame first or
+/// not.
+pub fn fill_crop(area: Rect, target: (u32, u32)) -> Rect {
+    let (width, height) = (area.w as f32, area.h as f32);
+    let image_ratio = width / height;
+    let required_ratio = target.0 as f32 / target.1 as f32;
+
+    let (crop_w, crop_h) = if (image_ratio - required_ratio).abs() < f32::EPSILON {
+        (width, height)
+    } else if image_ratio >= required_ratio {
+        (required_ratio * height, height)
+    } else {
+        (width, width / required_ratio)
+    };
+    Rect {
+        x: area.x + ((width - crop_w) * 0.5).round() as u32,
+        y: area.y + ((height - crop_h) * 0.5).round() as u32,
+        w: (crop_w.round() as u32).max(1),
+        h: (crop_h.round() as u32).max(1),
+    }
+}
+
+/// How far past the edges of `src` (in source pixels) resizing it to
+/// `target` may read when the pixels there exist, as they do around a crop
+/// box: the widest filter's support (Lanczos3, 3 pixels) stretched by the
+/// downscale factor, plus a pixel for rounding.
+pub fn filter_reach(src: (u32, u32), target: (u32, u32)) -> u32 {
+    let factor = (src.0 as f32 / target.0 as f32)
+        .max(src.1 as f32 / target.1 as f32)
+        .max(1.0);
+    (3.0 * factor).ceil() as u32 + 1
+}
+
+/// Shrinks `target_w x target_h` (keeping its aspect ratio) until it no
+/// longer exceeds `src_w x src_h` on either axis; targets that already fit
+/// are returned unchanged. When only one axis is too large, that axis ends
+/// up exactly at the source size.
+pub fn clamp_to_source(target_w: u32, target_h: u32, src_w: u32, src_h: u32) -> (u32, u32) {
+    if target_w <= src_w && target_h <= src_h {
+        return (target_w, target_h);
+    }
+    fit_within(target_w, target_h, src_w, src_h)
+}
+
+/// `src_w x src_h` multiplied by `factor`, each side rounded to an even
+/// number (4:2:0 JPEG prefers even dimensions) and at least 2. A factor of
+/// exactly 1.0 returns the source size untouched.
+pub fn scaled_size(src_w: u32, src_h: u32, factor: f32) -> (u32, u32) {
+    if factor == 1.0 {
+        return (src_w, src_h);
+    }
+    let even = |side: u32| {
+        let halves = (f64::from(side) * f64::from(factor) / 2.0).round();
+        (halves as u32).saturating_mul(2).max(2)
+    };
+    (even(src_w), even(src_h))
+}
+
+/// Proportionally shrinks `w x h` so its long edge is at most `max_dim`;
+/// sizes that are already small enough (or `max_dim == 0`) pass through.
+pub fn cap_long_edge(w: u32, h: u32, max_dim: u32) -> (u32, u32) {
+    if max_dim == 0 || w.max(h) <= max_dim {
+        return (w, h);
+    }
+    fit_within(w, h, max_dim, max_dim)
+}
+
+/// Maps `rect` (in source pixels) onto the output of resizing `src` to
+/// `target` with `fit`, rounding outwards and padding by a pixel so filter
+/// bleed stays inside. `FitMode::Fill` crops by a fractional amount, so it
+/// conservatively reports the whole output.
+pub fn map_rect(rect: Rect, src: (u32, u32), target: (u32, u32), fit: FitMode) -> Rect {
+    let (inner, offset) = match fit {
+        FitMode::Stretch => (target, (0, 0)),
+        FitMode::Fit => {
+            let inner = fit_within(src.0, src.1, target.0, target.1);
+            (inner, ((target.0 - inner.0) / 2, (target.1 - inner.1) / 2))
+        }
+        FitMode::Fill => {
+            return Rect {
+                x: 0,
+                y: 0,
+                w: target.0,
+                h: target.1,
+            };
+        }
+    };
+
+    let axis = |start: u32, len: u32, src_len: u32, out_len: u32, off: u32| {
+        let (s, n, o) = (u64::from(src_len), u64::from(out_len), u64::from(off));
+        let lo = (u64::from(start) * n / s).saturating_sub(1);
+        let hi = (u64::from(start + len) * n).div_ceil(s) + 1;
+        let hi = hi.min(n);
+        ((lo + o) as u32, (hi - lo) as u32)
+    };
+    let (x, w) = axis(rect.x, rect.w, src.0, inner.0, offset.0);
+    let (y, h) = axis(rect.y, rect.h, src.1, inner.1, offset.1);
+    Rect { x, y, w, h }
+}
+
+/// Maps the source pixel (`x`, `y`) onto the output of resizing `src` to
+/// `target` with `fit`; `None` if it ends up outside the output (cropped
+/// away by `FitMode::Fill`).
+pub fn map_point(
+    (x, y): (i32, i32),
+    src: (u32, u32),
+    target: (u32, u32),
+    fit: FitMode,
+) -> Option<(i32, i32)> {
+    let sx = f64::from(target.0) / f64::from(src.0);
+    let sy = f64::from(target.1) / f64::from(src.1);
+    let (sx, sy) = match fit {
+        FitMode::Stretch => (sx, sy),
+        FitMode::Fit => (sx.min(sy), sx.min(sy)),
+        FitMode::Fill => (sx.max(sy), sx.max(sy)),
+    };
+    // Fit centres the image in padding, Fill centres the crop: either way
+    // the offset is half the size difference (negative when cropping)
+    let off_x = (f64::from(target.0) - f64::from(src.0) * sx) / 2.0;
+    let off_y = (f64::from(target.1) - f64::from(src.1) * sy) / 2.0;
+
+    let out_x = (f64::from(x) * sx + off_x).floor();
+    let out_y = (f64::from(y) * sy + off_y).floor();
+    let inside =
+        (0.0..f64::from(target.0)).contains(&out_x) && (0.0..f64::from(target.1)).contains(&out_y);
+    inside.then_some((out_x as i32, out_y as i32))
+}
+
+fn div_round(num: u64, den: u64) -> u64 {
+    (num + den / 2) / den
+}
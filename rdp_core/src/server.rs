@@ -0,0 +1,453 @@
+//! The listener shared by the network servers (`http`, `tcp`, `ws`): binds
+//! the address, accepts connections on its own thread and runs each client
+//! on another, keeping track of them so stopping the server closes every
+//! connection and waits for every thread. With a TLS certificate
+//! configured (see the `tls` module) every connection is wrapped in TLS
+//! before its handler sees it. The same goes for the local socket or pipe
+//! of the `local` module, whose connections never use TLS.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::broadcast::Broadcast;
+use crate::error::{RdpStatus, fail};
+use crate::local;
+use crate::log::{self, LogLevel};
+use crate::stats::Stats;
+#[cfg(feature = "tls")]
+use crate::tls;
+
+/// How often the accept loop checks whether it should stop.
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+/// How long stopping lets clients finish what they are sending (e.g. a
+/// WebSocket close frame) before their sockets are shut down.
+const CLOSE_GRACE: Duration = Duration::from_millis(500);
+
+/// Serves one accepted connection until the client leaves or the broadcast
+/// closes. A client turned away for its access token ends with a
+/// `PermissionDenied` error, which is counted in the session's stats.
+pub type Handler = dyn Fn(Conn, &Broadcast) -> io::Result<()> + Send + Sync;
+
+/// A client connection, plain or TLS over TCP, or local.
+pub enum Conn {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(tls::Stream),
+    Local(local::Stream),
+}
+
+/// The socket under a connection.
+enum Socket<'a> {
+    Tcp(&'a TcpStream),
+    Local(&'a local::Stream),
+}
+
+impl Conn {
+    fn socket(&self) -> Socket<'_> {
+        match self {
+            Conn::Plain(socket) => Socket::Tcp(socket),
+            #[cfg(feature = "tls")]
+            Conn::Tls(stream) => Socket::Tcp(stream.socket()),
+            Conn::Local(stream) => Socket::Local(stream),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.socket() {
+            Socket::Tcp(socket) => socket.set_read_timeout(timeout),
+            Socket::Local(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.socket() {
+            Socket::Tcp(socket) => socket.set_write_timeout(timeout),
+            Socket::Local(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// Does nothing for local connections, which have no Nagle delay.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self.socket() {
+            Socket::Tcp(socket) => socket.set_nodelay(nodelay),
+            Socket::Local(_) => Ok(()),
+        }
+    }
+
+    /// Wakes a thread blocked reading the connection, as for `TcpStream`.
+    #[cfg(feature = "websocket")]
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self.socket() {
+            Socket::Tcp(socket) => socket.shutdown(how),
+            Socket::Local(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// Another handle on the same connection; one may read while the other
+    /// writes.
+    #[cfg(feature = "websocket")]
+    pub fn try_clone(&self) -> io::Result<Conn> {
+        match self {
+            Conn::Plain(socket) => socket.try_clone().map(Conn::Plain),
+            #[cfg(feature = "tls")]
+            Conn::Tls(stream) => stream.try_clone().map(Conn::Tls),
+            Conn::Local(stream) => stream.try_clone().map(Conn::Local),
+        }
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Conn::Plain(socket) => socket.read(buf),
+            #[cfg(feature = "tls")]
+            Conn::Tls(stream) => stream.read(buf),
+            Conn::Local(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Conn::Plain(socket) => socket.write(buf),
+            #[cfg(feature = "tls")]
+            Conn::Tls(stream) => stream.write(buf),
+            Conn::Local(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Conn::Plain(socket) => socket.flush(),
+            #[cfg(feature = "tls")]
+            Conn::Tls(stream) => stream.flush(),
+            Conn::Local(stream) => stream.flush(),
+        }
+    }
+}
+
+/// What a server listens on.
+enum Listener {
+    Tcp(TcpListener),
+    Local(local::Listener),
+}
+
+/// An accepted connection, before any TLS.
+enum Accepted {
+    Tcp(TcpStream),
+    Local(local::Stream),
+}
+
+impl Listener {
+    /// The next client; `WouldBlock` when none is waiting.
+    fn accept(&self, accepted: &mut u64) -> io::Result<(Accepted, String)> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .map(|(socket, peer)| (Accepted::Tcp(socket), peer.to_string())),
+            // Local clients have no address of their own
+            Listener::Local(listener) => listener.accept().map(|stream| {
+                *accepted += 1;
+                (Accepted::Local(stream), format!("#{accepted}"))
+            }),
+        }
+    }
+}
+
+impl Accepted {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Accepted::Tcp(socket) => socket.set_nonblocking(nonblocking),
+            Accepted::Local(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Accepted> {
+        match self {
+            Accepted::Tcp(socket) => socket.try_clone().map(Accepted::Tcp),
+            Accepted::Local(stream) => stream.try_clone().map(Accepted::Local),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Accepted::Tcp(socket) => socket.shutdown(how),
+            Accepted::Local(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+/// Sets up TLS for servers started afterwards, from the PEM files at
+/// `paths` (certificate, then private key); `None` goes back to plaintext.
+#[cfg(feature = "tls")]
+pub fn configure_tls(paths: Option<(&str, &str)>) -> Result<(), RdpStatus> {
+    tls::configure(paths)
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn configure_tls(_paths: Option<(&str, &str)>) -> Result<(), RdpStatus> {
+    Err(fail(RdpStatus::Unsupported, "TLS needs the `tls` feature"))
+}
+
+/// A running server.
+pub struct Server {
+    name: &'static str,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+    broadcast: Arc<Broadcast>,
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+struct Client {
+    /// A handle on the client's socket, to shut it down from `stop`.
+    socket: Accepted,
+    thread: JoinHandle<()>,
+}
+
+impl Server {
+    /// Binds `bind_addr:port` and hands every connection to `handler`, on a
+    /// thread of its own. `name` labels the threads and log lines. Serves
+    /// TLS if a certificate is configured at this point.
+    pub fn start(
+        name: &'static str,
+        bind_addr: &str,
+        port: u16,
+        broadcast: Arc<Broadcast>,
+        stats: Arc<Stats>,
+        handler: Arc<Handler>,
+    ) -> Result<Server, RdpStatus> {
+        let listener = TcpListener::bind((bind_addr, port))
+            .and_then(|listener| {
+                // Polled, so stopping never waits on a connection that may not come
+                listener.set_nonblocking(true)?;
+                Ok(listener)
+            })
+            .map_err(|e| {
+                fail(
+                    RdpStatus::NetworkError,
+                    format!("Failed to listen on {bind_addr}:{port}: {e}"),
+                )
+            })?;
+        let local = listener
+            .local_addr()
+            .map_or_else(|_| format!("{bind_addr}:{port}"), |a| a.to_string());
+        Server::run(
+            name,
+            Listener::Tcp(listener),
+            &local,
+            broadcast,
+            stats,
+            handler,
+        )
+    }
+
+    /// `start` on the Unix domain socket or named pipe at `path` (see the
+    /// `local` module), created with the permission bits `local::mode`
+    /// gives at this point.
+    pub fn start_local(
+        name: &'static str,
+        path: &str,
+        broadcast: Arc<Broadcast>,
+        stats: Arc<Stats>,
+        handler: Arc<Handler>,
+    ) -> Result<Server, RdpStatus> {
+        let listener = local::Listener::bind(path, local::mode())?;
+        Server::run(
+            name,
+            Listener::Local(listener),
+            path,
+            broadcast,
+            stats,
+            handler,
+        )
+    }
+
+    /// Accepts connections from `listener`, which `start` and
+    /// `start_local` have bound as `local`.
+    fn run(
+        name: &'static str,
+        listener: Listener,
+        local: &str,
+        broadcast: Arc<Broadcast>,
+        stats: Arc<Stats>,
+        handler: Arc<Handler>,
+    ) -> Result<Server, RdpStatus> {
+        let serve = Arc::new(Serve {
+            name,
+            broadcast: Arc::clone(&broadcast),
+            stats,
+            handler,
+            #[cfg(feature = "tls")]
+            tls: tls::current(),
+        });
+        #[cfg(feature = "tls")]
+        let secure = match listener {
+            Listener::Tcp(_) if serve.tls.is_some() => " over TLS",
+            _ => "",
+        };
+        #[cfg(not(feature = "tls"))]
+        let secure = "";
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let thread = {
+            let (stop, clients) = (Arc::clone(&stop), Arc::clone(&clients));
+            thread::Builder::new()
+                .name(format!("rdp-{name}"))
+                .spawn(move || accept_loop(&serve, &listener, &clients, &stop))
+                .map_err(|e| {
+                    fail(
+                        RdpStatus::NetworkError,
+                        format!("Failed to start the {name} server thread: {e}"),
+                    )
+                })?
+        };
+
+        log::log(
+            LogLevel::Info,
+            &format!("{name} server listening on {local}{secure}"),
+        );
+        Ok(Server {
+            name,
+            stop,
+            thread,
+            broadcast,
+            clients,
+        })
+    }
+
+    /// Closes the listener and every client connection, and waits for all
+    /// of the server's threads to finish.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        let _ = self.thread.join();
+
+        // Wakes the clients waiting for a frame, so they can say goodbye;
+        // shutting the sockets down then ends any that did not
+        self.broadcast.close();
+        let clients =
+            std::mem::take(&mut *self.clients.lock().unwrap_or_else(PoisonError::into_inner));
+        for client in &clients {
+            let _ = client.socket.shutdown(Shutdown::Read);
+        }
+        let deadline = Instant::now() + CLOSE_GRACE;
+        while Instant::now() < deadline && clients.iter().any(|c| !c.thread.is_finished()) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        for client in clients {
+            let _ = client.socket.shutdown(Shutdown::Both);
+            let _ = client.thread.join();
+        }
+        log::log(LogLevel::Info, &format!("{} server stopped", self.name));
+    }
+}
+
+/// What every client of a server is served with.
+struct Serve {
+    name: &'static str,
+    broadcast: Arc<Broadcast>,
+    stats: Arc<Stats>,
+    handler: Arc<Handler>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tls::Context>>,
+}
+
+impl Serve {
+    /// Runs on the client's thread, so a slow TLS handshake holds up no
+    /// one else.
+    fn client(&self, socket: Accepted) -> io::Result<()> {
+        let conn = match socket {
+            #[cfg(feature = "tls")]
+            Accepted::Tcp(socket) => match &self.tls {
+                Some(tls) => Conn::Tls(tls.accept(socket)?),
+                None => Conn::Plain(socket),
+            },
+            #[cfg(not(feature = "tls"))]
+            Accepted::Tcp(socket) => Conn::Plain(socket),
+            Accepted::Local(stream) => Conn::Local(stream),
+        };
+        (self.handler)(conn, &self.broadcast)
+    }
+}
+
+fn accept_loop(
+    serve: &Arc<Serve>,
+    listener: &Listener,
+    clients: &Mutex<Vec<Client>>,
+    stop: &AtomicBool,
+) {
+    let name = serve.name;
+    let mut accepted = 0;
+    while !stop.load(Ordering::Acquire) {
+        let (conn, peer) = match listener.accept(&mut accepted) {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                log::log(LogLevel::Warn, &format!("{name} accept failed: {e}"));
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+        };
+
+        // Accepted sockets inherit non-blocking mode from the listener on
+        // some platforms
+        let clones = conn
+            .set_nonblocking(false)
+            .and_then(|()| Ok((conn.try_clone()?, conn.try_clone()?)));
+        let (socket, closer) = match clones {
+            Ok(clones) => clones,
+            Err(e) => {
+                log::log(
+                    LogLevel::Warn,
+                    &format!("Dropping {name} client {peer}: {e}"),
+                );
+                continue;
+            }
+        };
+        let serve = Arc::clone(serve);
+        let client = peer.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("rdp-{name}-client"))
+            .spawn(move || {
+                let served = serve.client(conn);
+                // The server's own handle keeps the socket open until
+                // `stop`; this ends the connection for the client now
+                let _ = closer.shutdown(Shutdown::Both);
+                match served {
+                    Ok(()) => log::log(LogLevel::Debug, &format!("{name} client {client} left")),
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        serve.stats.auth_failed();
+                        log::log(
+                            LogLevel::Warn,
+                            &format!("{name} client {client} rejected: {e}"),
+                        );
+                    }
+                    Err(e) => log::log(
+                        LogLevel::Debug,
+                        &format!("{name} client {client} dropped: {e}"),
+                    ),
+                }
+            });
+        match spawned {
+            Ok(thread) => {
+                log::log(LogLevel::Debug, &format!("{name} client {peer} connected"));
+                let mut clients = clients.lock().unwrap_or_else(PoisonError::into_inner);
+                clients.retain(|c| !c.thread.is_finished());
+                clients.push(Client { socket, thread });
+            }
+            Err(e) => log::log(
+                LogLevel::Warn,
+                &format!("Failed to start a thread for {name} client {peer}: {e}"),
+            ),
+        }
+    }
+}
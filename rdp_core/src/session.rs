@@ -0,0 +1,3156 @@
+use scrap::Display;
+use std::io::ErrorKind::WouldBlock;
+use std::time::{Duration, Instant};
+
+use fast_image_resize as fr;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use image::codecs::png::CompressionType;
+
+use crate::capture::{self, Backend, FrameSource};
+use crate::cipher::FrameCipher;
+use crate::cursor::{self, CursorImage, CursorProbe};
+use crate::display::{self, SPAN_ALL};
+use crate::encode::{self, Subsampling};
+use crate::error::{RdpStatus, fail, fail_at};
+use crate::focus::{Focus, FocusState};
+use crate::frame::{EncodedFrame, FrameFormat, PixelFormat};
+use crate::input;
+use crate::log::{self, LogLevel};
+use crate::memory::{Charge, MemoryBudget};
+use crate::orient::{self, Orientation};
+use crate::overlay::{self, Anchor, TextOverlay};
+use crate::pace::{self, FpsMeter, Pacer, WaitStrategy};
+use crate::pattern::{self, TestPatternSource};
+use crate::permission;
+use crate::pixels::{self, Rect};
+use crate::priority::ThreadPriority;
+use crate::queue::{self, QueuePolicy};
+use crate::rate::{BitrateBucket, Budget, QualityController};
+use crate::record::Recorder;
+use crate::replay::{ReplayBuffer, Still};
+use crate::scale::{self, FitMode};
+use crate::shm::{self, ShmWriter};
+use crate::span::{Layout, Span};
+use crate::stats::{Stage, Stats};
+use crate::tiles::{self, TileState};
+use crate::video;
+use crate::window::{self, Bounds, Placement};
+use crate::yuv::{self, YuvMatrix};
+use crate::zstd::{self, DeltaState};
+
+/// Default JPEG quality, tuned for speed over fidelity.
+pub const DEFAULT_QUALITY: u8 = 70;
+
+/// Default bounds of the quality picked to meet a frame byte budget.
+pub const DEFAULT_MIN_QUALITY: u8 = 10;
+pub const DEFAULT_MAX_QUALITY: u8 = 90;
+
+/// Largest accepted `budget_downscale_step`.
+pub const MAX_DOWNSCALE_STEP: f32 = 0.5;
+
+/// Frame rate bitrate budgets assume before any has been measured.
+const ASSUMED_FPS: f64 = 30.0;
+
+/// Captured frames are BGRA.
+const BYTES_PER_PIXEL: usize = 4;
+
+/// `timeout_ms` value meaning "block until a frame arrives".
+pub const WAIT_FOREVER: u32 = u32::MAX;
+
+/// Tiled keyframe cadence: every 4 s at 30 fps.
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 120;
+
+/// Smallest accepted tile edge; below this the per-tile headers and JPEG
+/// overhead outweigh the savings.
+pub const MIN_TILE_SIZE: u32 = 16;
+
+/// Most bands a frame is split into for parallel encoding.
+pub const MAX_ENCODE_BANDS: u32 = 64;
+
+/// How often the display's size is compared with the capturer's. Some
+/// platforms keep delivering frames of the old size after a resolution
+/// change, so it cannot be left to the frames alone.
+const DISPLAY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bounds of the wait between attempts to reopen a lost display.
+const MIN_RECOVERY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECOVERY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Default `recovery_timeout_ms`: long enough for a coffee break at a
+/// locked workstation.
+pub const DEFAULT_RECOVERY_TIMEOUT_MS: u32 = 10 * 60 * 1000;
+
+/// Per-session encoding settings.
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    /// Output encoding.
+    pub format: FrameFormat,
+    /// Encode single-channel luma instead of colour; overrides
+    /// `pixel_format` and `subsampling`.
+    pub grayscale: bool,
+    /// Channel layout of `FrameFormat::Raw` output and of the pixels handed
+    /// to the JPEG encoder.
+    pub pixel_format: PixelFormat,
+    /// JPEG and WebP quality, 1–100.
+    pub quality: u8,
+    /// JPEG chroma subsampling.
+    pub subsampling: Subsampling,
+    /// Encode JPEG progressively, so slow links show a coarse image early.
+    pub progressive: bool,
+    /// MCU rows between JPEG restart markers, so a decoder can resume
+    /// after a lost chunk; 0 for none. Ignored by progressive JPEG.
+    pub restart_rows: u32,
+    /// PNG zlib effort; `Fast` by default since anything more is slow on
+    /// 4K frames.
+    pub png_compression: CompressionType,
+    /// Target size of captures that pass none to `capture`; `(0, 0)` leaves
+    /// the size to `scale` and `max_dim`.
+    pub output_size: (u32, u32),
+    /// Output size as a multiple of the captured size, used when no explicit
+    /// target is passed to `capture`; 1.0 keeps the native size.
+    pub scale: f32,
+    /// Upper bound on the long edge of captures without an explicit target,
+    /// applied after `scale`; 0 disables it.
+    pub max_dim: u32,
+    /// Never upscale: targets larger than the source are shrunk to fit it.
+    pub downscale_only: bool,
+    /// Filter used whenever a frame is resized.
+    pub resize_alg: fr::ResizeAlg,
+    /// How frames are mapped onto an explicit target size.
+    pub fit: FitMode,
+    /// Padding colour for `FitMode::Fit`, as `0xRRGGBB`.
+    pub fill_color: u32,
+    /// Sub-rectangle of the display to capture; `None` captures it all.
+    pub region: Option<Rect>,
+    /// How frames are turned after the crop.
+    pub orientation: Orientation,
+    /// Shrink frames from the display's physical pixels to logical points
+    /// (e.g. halve them on a Retina panel) unless an explicit size is set.
+    pub capture_logical_size: bool,
+    /// Display areas painted over with `blackout_color` as soon as a frame
+    /// is captured.
+    pub blackout: Vec<Rect>,
+    /// As `0xRRGGBB`.
+    pub blackout_color: u32,
+    /// Text drawn on every output frame.
+    pub watermark: Option<TextOverlay>,
+    /// Burn the capture timestamp (ms) and sequence number into frames,
+    /// for measuring latency on the viewer.
+    pub overlay_timestamp: bool,
+    /// How long a capture waits for a frame: `0` tries exactly once,
+    /// `WAIT_FOREVER` never gives up.
+    pub timeout_ms: u32,
+    /// How long a lost display is retried before the session fails for
+    /// good; `WAIT_FOREVER` never gives up.
+    pub recovery_timeout_ms: u32,
+    /// Skip frames identical to the previous capture (`RdpStatus::NoChange`).
+    pub detect_changes: bool,
+    /// Diff against the previous frame to report `EncodedFrame::dirty`.
+    pub track_dirty: bool,
+    /// Edge length of delta tiles; 0 sends whole frames. Ignored for the
+    /// video and YUV formats.
+    pub tile_size: u32,
+    /// Frames between tiled or video keyframes; 0 sends them only on
+    /// demand.
+    pub keyframe_interval: u32,
+    /// Horizontal bands JPEG, PNG and WebP frames (or tiled keyframes) are
+    /// split into and encoded in parallel, sent as a tile container (see
+    /// `tiles`); 0 or 1 encodes them whole.
+    pub encode_bands: u32,
+    /// Blend the mouse cursor into captured frames.
+    pub include_cursor: bool,
+    /// Rectangle around the cursor JPEG frames re-encode at a higher
+    /// quality (see `focus`); `None` encodes them evenly.
+    pub focus: Option<Focus>,
+    /// Rate `capture` (and a stream) paces frames to; 0 leaves them
+    /// unpaced.
+    pub target_fps: u32,
+    /// Encoded size JPEG frames are steered towards by adapting the quality
+    /// (which then replaces `quality`); 0 turns adaptation off.
+    pub target_frame_bytes: u32,
+    /// Range the adapted quality stays in.
+    pub min_quality: u8,
+    pub max_quality: u8,
+    /// Fraction each side of the output shrinks by when a frame overshoots
+    /// the budget even at `min_quality`; 0 never shrinks it.
+    pub budget_downscale_step: f32,
+    /// Bitrate the per-frame budgets are derived from; takes precedence
+    /// over `target_frame_bytes`. 0 turns it off.
+    pub bitrate_kbps: u32,
+    /// zstd level of `FrameFormat::RawZstd` frames.
+    pub zstd_level: i32,
+    /// XOR `RawZstd` frames between keyframes with the previous frame.
+    pub zstd_delta: bool,
+    /// RGB to YUV matrix of `FrameFormat::I420` and `Nv12` frames.
+    pub yuv_matrix: YuvMatrix,
+    /// How `capture` waits between polls that find no new frame. Streams
+    /// poll on their own schedule.
+    pub wait_strategy: WaitStrategy,
+    /// What the frame queue of a consumer started afterwards (a network
+    /// client, a buffered stream) does when the consumer falls behind.
+    pub queue_policy: QueuePolicy,
+    /// Frames a network client's queue holds, 1 to `queue::MAX_CAPACITY`;
+    /// a buffered stream's ring size is its own.
+    pub queue_capacity: u32,
+    /// Tell TCP and WebSocket clients how many frames they missed (see the
+    /// `tcp` and `ws` modules).
+    pub report_drops: bool,
+    /// Scheduling priority of stream threads started afterwards.
+    pub thread_priority: ThreadPriority,
+    /// Cores (bit `n` for core `n`) stream threads started afterwards are
+    /// pinned to; 0 leaves them unpinned.
+    pub thread_affinity: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            format: FrameFormat::Jpeg,
+            grayscale: false,
+            pixel_format: PixelFormat::Bgra,
+            quality: DEFAULT_QUALITY,
+            subsampling: Subsampling::Yuv420,
+            progressive: false,
+            restart_rows: 0,
+            png_compression: CompressionType::Fast,
+            output_size: (0, 0),
+            scale: 1.0,
+            max_dim: 0,
+            downscale_only: false,
+            resize_alg: fr::ResizeAlg::Nearest,
+            fit: FitMode::Stretch,
+            fill_color: 0x000000,
+            region: None,
+            orientation: Orientation::Normal,
+            capture_logical_size: false,
+            blackout: Vec::new(),
+            blackout_color: 0x000000,
+            watermark: None,
+            overlay_timestamp: false,
+            timeout_ms: WAIT_FOREVER,
+            recovery_timeout_ms: DEFAULT_RECOVERY_TIMEOUT_MS,
+            detect_changes: false,
+            track_dirty: false,
+            tile_size: 0,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            encode_bands: 0,
+            include_cursor: false,
+            focus: None,
+            target_fps: 0,
+            target_frame_bytes: 0,
+            min_quality: DEFAULT_MIN_QUALITY,
+            max_quality: DEFAULT_MAX_QUALITY,
+            budget_downscale_step: 0.0,
+            bitrate_kbps: 0,
+            zstd_level: zstd::DEFAULT_LEVEL,
+            zstd_delta: false,
+            yuv_matrix: YuvMatrix::Bt601,
+            wait_strategy: WaitStrategy::Backoff,
+            queue_policy: QueuePolicy::LatestWins,
+            queue_capacity: queue::DEFAULT_CAPACITY as u32,
+            report_drops: false,
+            thread_priority: ThreadPriority::Normal,
+            thread_affinity: 0,
+        }
+    }
+}
+
+impl SessionConfig {
+    /// The byte budget the next frame's quality is adapted to, if a bitrate
+    /// or frame size target is set and the output is JPEG (tiled or not);
+    /// the other formats have no quality to turn. A bitrate budget comes out
+    /// of `bucket`, assuming frames arrive `fps` times a second.
+    pub fn budget(&self, bucket: &mut BitrateBucket, fps: f64) -> Option<Budget> {
+        if self.format != FrameFormat::Jpeg {
+            return None;
+        }
+        let bytes = if self.bitrate_kbps > 0 {
+            bucket.allowance(self.bitrate_kbps, fps)
+        } else {
+            self.target_frame_bytes
+        };
+        (bytes > 0).then_some(Budget {
+            bytes,
+            min_quality: self.min_quality,
+            max_quality: self.max_quality,
+            downscale_step: self.budget_downscale_step,
+        })
+    }
+
+    fn adapts_quality(&self) -> bool {
+        self.bitrate_kbps > 0 || self.target_frame_bytes > 0
+    }
+}
+
+/// A persistent capture session.
+///
+/// Owns the `Capturer` (so desktop duplication is only initialized once),
+/// the resizer and the scratch buffers, so steady-state capture allocates
+/// nothing large except the frame handed back to the caller.
+///
+/// Sessions share no mutable state, so several can capture different
+/// displays at once, each on its own thread or stream. Every backend opens
+/// its own OS resources per session, none tied to the thread that opened
+/// them: an X connection and shared memory segment, a D3D11 device and
+/// output duplication, a CGDisplayStream on its own dispatch queue, or a
+/// portal session and PipeWire stream. What is process-wide (the backend
+/// choice, the display list, the cursor shape) is only read here.
+pub struct RdpSession {
+    /// `None` between dropping a failed capturer and opening its
+    /// replacement.
+    capturer: Option<Box<dyn FrameSource>>,
+    /// Size of the display as last opened.
+    display_size: (usize, usize),
+    resizer: fr::Resizer,
+    scratch: Scratch,
+    config: SessionConfig,
+    /// Hash of the last frame that was turned into output; `None` until the
+    /// first capture and after any settings change.
+    last_hash: Option<u64>,
+    /// Whether `scratch.packed` holds the last frame that was turned into
+    /// output, so it can serve as the dirty-tracking reference.
+    packed_is_last: bool,
+    /// A frame the caller could not take (see `hold`), with the target size
+    /// it was captured for.
+    pending: Option<(EncodedFrame, (u32, u32))>,
+    /// What the client was last sent in tiled mode.
+    tiles: TileState,
+    /// The last zstd frame, for delta coding.
+    zstd: DeltaState,
+    /// Where the focus rectangle was last sent.
+    focus: FocusState,
+    /// Which capture backend the session opened with.
+    backend: Backend,
+    /// Index of the session's display in `Display::all()`, for resolving
+    /// the cursor's display; -1 for whichever is primary, `SPAN_ALL` for
+    /// all of them.
+    display_index: i32,
+    /// `DisplayInfo::id` of the display, tracked across index changes;
+    /// `None` for sessions following the primary display.
+    display_id: Option<u64>,
+    /// The display's `DisplayInfo::scale` as of the last check.
+    display_scale: f32,
+    /// Where the displays sit on a spanning session's canvas, to tell when
+    /// the arrangement changes; `None` for sessions on one display.
+    span: Option<Layout>,
+    /// The Wayland portal's token for the monitor the user picked, so the
+    /// session reopens its stream without asking again.
+    restore_token: Option<String>,
+    /// When the display's size was last compared with the capturer's.
+    display_checked: Instant,
+    /// Whether the capturer can reach the display.
+    availability: Availability,
+    /// Cursor position source; `None` where it cannot be queried.
+    cursor_probe: Option<CursorProbe>,
+    /// The window a window session crops to; `None` for display sessions.
+    window: Option<WindowTarget>,
+    /// Drawn at the cursor position while `include_cursor` is on: the
+    /// system cursor where it can be read, a plain arrow otherwise.
+    cursor_image: CursorImage,
+    /// `cursor::generation()` that `cursor_image` was read at.
+    cursor_generation: u64,
+    /// Spaces out the frames `capture` returns to `target_fps`.
+    pacer: Pacer,
+    /// Rate frames are actually produced at.
+    fps_meter: FpsMeter,
+    /// Origin of `EncodedFrame::timestamp_us`.
+    opened: Instant,
+    /// Sequence number the next captured frame gets.
+    next_sequence: u64,
+    /// Timings and counters, shared with the handle so they can be read
+    /// while a capture is running.
+    stats: Arc<Stats>,
+    /// Picks the quality (and size) of each frame under a byte budget.
+    rate: QualityController,
+    /// Hands out byte budgets under `bitrate_kbps`.
+    bucket: BitrateBucket,
+    /// Encoder of the video formats, opened by the first capture in one.
+    video: Option<Box<dyn video::Encoder>>,
+    /// Encrypts payloads once a key is set.
+    cipher: FrameCipher,
+    /// Gets a copy of every JPEG frame while recording.
+    recorder: Option<Recorder>,
+    /// Recent frames, kept for `replay::export_gif`.
+    replay: Option<ReplayBuffer>,
+    /// Shared memory every output frame is also published to.
+    shm: Option<ShmWriter>,
+    /// What `shm` maps, against the memory budget.
+    shm_memory: Charge,
+}
+
+// The capturer, cursor probe and window tracker are `!Send` only because of
+// the raw handles and reference counts they hold; every one of them is owned
+// by this session alone, so moving it to a stream thread as a whole is fine.
+unsafe impl Send for RdpSession {}
+
+/// Whether a session's capturer can reach its display.
+#[derive(Clone, Copy)]
+enum Availability {
+    Available,
+    /// Lost, e.g. to the lock screen, the secure desktop, sleep or being
+    /// unplugged. The capturer is reopened at `retry_at`, backing off
+    /// between attempts; until then captures fail with `status`.
+    Lost {
+        since: Instant,
+        retry_at: Instant,
+        backoff: Duration,
+        status: RdpStatus,
+    },
+    /// Recovery timed out; every capture fails.
+    Failed,
+}
+
+/// The window a window session shares.
+struct WindowTarget {
+    tracker: window::Tracker,
+    /// The window in display pixels as of the last capture; `None` before
+    /// the first.
+    region: Option<Rect>,
+    /// Set once the window is gone; the session cannot capture any more.
+    closed: bool,
+}
+
+/// Intermediate pixel buffers kept between frames. They only reallocate
+/// when the frame grows; none of them is ever handed to the caller.
+struct Scratch {
+    /// Tightly packed (and cropped) BGRA copy of the captured frame.
+    packed: Vec<u8>,
+    /// The previous output frame's `packed`, for dirty tracking.
+    previous: Vec<u8>,
+    /// The frame as captured, before it was rotated or mirrored into
+    /// `packed`.
+    oriented: Vec<u8>,
+    /// Luma plane for grayscale sessions.
+    luma: Vec<u8>,
+    /// Resize output.
+    resized: Vec<u8>,
+    /// Letterboxed canvas for `FitMode::Fit`.
+    padded: Vec<u8>,
+    /// Channel-reordered pixels for the encoder or raw output.
+    converted: Vec<u8>,
+    /// One tile's pixels in tiled mode.
+    tile: Vec<u8>,
+    /// XOR of a zstd delta frame with the one before it.
+    xored: Vec<u8>,
+    /// I420 input of the video encoder.
+    yuv: Vec<u8>,
+    /// What the buffers and the zstd reference hold, or are reserved to
+    /// grow to, against the memory budget.
+    memory: Charge,
+}
+
+impl Scratch {
+    fn new(memory: &Arc<MemoryBudget>) -> Scratch {
+        Scratch {
+            packed: Vec::new(),
+            previous: Vec::new(),
+            oriented: Vec::new(),
+            luma: Vec::new(),
+            resized: Vec::new(),
+            padded: Vec::new(),
+            converted: Vec::new(),
+            tile: Vec::new(),
+            xored: Vec::new(),
+            yuv: Vec::new(),
+            memory: Charge::new(memory),
+        }
+    }
+
+    /// Copies `rect` of the captured frame into `packed`, first keeping the
+    /// last output frame's copy as `previous` (the diff reference) when
+    /// `packed` still holds it.
+    fn copy_region(&mut self, frame: &[u8], stride: usize, rect: Rect, keep_previous: bool) {
+        if keep_previous {
+            std::mem::swap(&mut self.packed, &mut self.previous);
+        }
+        pixels::crop(frame, stride, 4, rect, &mut self.packed);
+    }
+
+    /// Bytes the buffers hold.
+    fn retained(&self) -> u64 {
+        [
+            &self.packed,
+            &self.previous,
+            &self.oriented,
+            &self.luma,
+            &self.resized,
+            &self.padded,
+            &self.converted,
+            &self.tile,
+            &self.xored,
+            &self.yuv,
+        ]
+        .iter()
+        .map(|buffer| buffer.capacity() as u64)
+        .sum()
+    }
+
+    /// Frees the buffers, but for `packed` and `previous` when
+    /// `keep_source`, and gives their charge back.
+    fn release(&mut self, keep_source: bool) {
+        if !keep_source {
+            self.packed = Vec::new();
+            self.previous = Vec::new();
+        }
+        for buffer in [
+            &mut self.oriented,
+            &mut self.luma,
+            &mut self.resized,
+            &mut self.padded,
+            &mut self.converted,
+            &mut self.tile,
+            &mut self.xored,
+            &mut self.yuv,
+        ] {
+            *buffer = Vec::new();
+        }
+        self.memory.set(self.retained());
+    }
+
+    fn held(&self, held: Held) -> &[u8] {
+        match held {
+            Held::Packed => &self.packed,
+            Held::Luma => &self.luma,
+            Held::Resized => &self.resized,
+            Held::Padded => &self.padded,
+            Held::Converted => &self.converted,
+        }
+    }
+
+    fn held_mut(&mut self, held: Held) -> &mut [u8] {
+        match held {
+            Held::Packed => &mut self.packed,
+            Held::Luma => &mut self.luma,
+            Held::Resized => &mut self.resized,
+            Held::Padded => &mut self.padded,
+            Held::Converted => &mut self.converted,
+        }
+    }
+
+    /// Converts the BGRA pixels `held` names to `pixel_format` into
+    /// `converted`, unless they are already in it (or are luma), and says
+    /// where the result is.
+    fn convert(&mut self, held: Held, pixel_format: PixelFormat, grayscale: bool) -> Held {
+        if grayscale || pixel_format == PixelFormat::Bgra || held == Held::Converted {
+            return held;
+        }
+        let mut converted = std::mem::take(&mut self.converted);
+        pixels::convert_bgra(self.held(held), pixel_format, &mut converted);
+        self.converted = converted;
+        Held::Converted
+    }
+
+    /// The pixels `held` names, next to the buffers the encoders work in.
+    fn encoder_input(&mut self, held: Held) -> (&[u8], EncodeBuffers<'_>) {
+        let pixels: &[u8] = match held {
+            Held::Packed => &self.packed,
+            Held::Luma => &self.luma,
+            Held::Resized => &self.resized,
+            Held::Padded => &self.padded,
+            Held::Converted => &self.converted,
+        };
+        let buffers = EncodeBuffers {
+            tile: &mut self.tile,
+            xored: &mut self.xored,
+            yuv: &mut self.yuv,
+        };
+        (pixels, buffers)
+    }
+}
+
+/// Which `Scratch` buffer holds a frame's pixels at some stage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Held {
+    Packed,
+    Luma,
+    Resized,
+    Padded,
+    Converted,
+}
+
+/// The `Scratch` buffers encoders work in, borrowed alongside the input.
+struct EncodeBuffers<'a> {
+    tile: &'a mut Vec<u8>,
+    xored: &'a mut Vec<u8>,
+    yuv: &'a mut Vec<u8>,
+}
+
+/// How long a capture has waited for a frame, and how it goes on waiting.
+struct Wait {
+    timeout_ms: u32,
+    started: Instant,
+    backoff: Duration,
+    /// Set once the capturer has been reopened; it is not reopened twice.
+    reopened: bool,
+    /// Set while the last poll already waited in the backend for its
+    /// signal, so there is nothing left to wait for between polls.
+    signalled: bool,
+}
+
+impl Wait {
+    fn new(timeout_ms: u32) -> Wait {
+        Wait {
+            timeout_ms,
+            started: Instant::now(),
+            backoff: pace::MIN_BACKOFF,
+            reopened: false,
+            signalled: false,
+        }
+    }
+
+    /// What is left of the timeout.
+    fn remaining(&self) -> Duration {
+        match self.timeout_ms {
+            WAIT_FOREVER => Duration::MAX,
+            timeout_ms => {
+                Duration::from_millis(u64::from(timeout_ms)).saturating_sub(self.started.elapsed())
+            }
+        }
+    }
+
+    /// How long the next poll of `source` may wait for a frame in the
+    /// backend; zero for a plain poll.
+    fn poll_timeout(&mut self, strategy: WaitStrategy, source: &dyn FrameSource) -> Duration {
+        let timeout = strategy
+            .signal_timeout(self.remaining())
+            .filter(|_| source.signals());
+        self.signalled = timeout.is_some();
+        timeout.unwrap_or(Duration::ZERO)
+    }
+
+    /// Waits before the next poll, or fails when the time is up.
+    fn next(&mut self, strategy: WaitStrategy) -> Result<(), RdpStatus> {
+        let timeout_ms = self.timeout_ms;
+        if timeout_ms == 0 {
+            return Err(fail_at(
+                LogLevel::Debug,
+                RdpStatus::WouldBlock,
+                "No frame ready yet",
+            ));
+        }
+
+        let remaining = self.remaining();
+        if remaining.is_zero() {
+            return Err(fail(
+                RdpStatus::Timeout,
+                format!("No frame within {timeout_ms} ms"),
+            ));
+        }
+
+        if !self.signalled {
+            strategy.wait(&mut self.backoff, self.started.elapsed(), remaining);
+        }
+        Ok(())
+    }
+}
+
+/// When a frame was captured.
+#[derive(Clone, Copy)]
+struct Stamp {
+    timestamp_us: u64,
+    sequence: u64,
+}
+
+/// Where the pixels to capture lie in a frame.
+struct Source {
+    /// Bytes per row of the frame, padding included.
+    stride: usize,
+    /// The part of the display captured.
+    rect: Rect,
+    /// The cursor, relative to `rect`.
+    cursor: Option<(i32, i32)>,
+}
+
+/// The captured region after cropping, blackout, the cursor and
+/// orientation.
+struct Prepared {
+    width: u32,
+    height: u32,
+    /// The cursor, when inside the region, in its pixels.
+    cursor: Option<(i32, i32)>,
+    /// Whether the resize or the conversion may read the frame instead of
+    /// `packed`.
+    may_fuse: bool,
+    /// Whether `previous` holds the last output frame's pixels.
+    have_previous: bool,
+}
+
+impl Prepared {
+    fn full(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            w: self.width,
+            h: self.height,
+        }
+    }
+}
+
+/// The size a frame is output at.
+struct Output {
+    width: u32,
+    height: u32,
+    /// Whether that differs from the prepared size.
+    resize: bool,
+    budget: Option<Budget>,
+}
+
+/// A frame at output size, overlays drawn.
+struct Rendered {
+    held: Held,
+    /// Whether the resize or the conversion read the frame directly,
+    /// leaving `packed` stale.
+    fused: bool,
+    convert_time: Duration,
+}
+
+/// A frame's payload before encryption.
+struct Encoded {
+    data: Vec<u8>,
+    format: FrameFormat,
+    keyframe: bool,
+    quality: u8,
+    progressive: bool,
+    uncompressed_len: u64,
+}
+
+/// Everything `RdpSession::publish` turns into an `EncodedFrame`.
+struct Publish {
+    encoded: Encoded,
+    pixel_format: PixelFormat,
+    content_hash: u64,
+    hash: Option<u64>,
+    dirty: Rect,
+    prepared: Prepared,
+    output: Output,
+    fused: bool,
+    stamp: Stamp,
+}
+
+impl RdpSession {
+    /// Opens a session on `display_index` from `Display::all()`, on the
+    /// primary display when the index is -1, or on every display side by
+    /// side (see the `span` module) when it is `SPAN_ALL`.
+    ///
+    /// Displays are re-enumerated on every call, so an index that no longer
+    /// exists (monitor unplugged) fails instead of falling back to another
+    /// screen. Fails with `RdpStatus::PermissionDenied` when the OS does not
+    /// let this process capture the screen.
+    pub fn new(display_index: i32) -> Result<RdpSession, RdpStatus> {
+        RdpSession::with_backend(Backend::Auto, display_index)
+    }
+
+    /// `new` with the capture backend forced to `backend`, unless it is
+    /// `Backend::Auto`. Fails with `RdpStatus::BackendUnavailable` when
+    /// that backend is not compiled in or cannot work here.
+    pub fn with_backend(backend: Backend, display_index: i32) -> Result<RdpSession, RdpStatus> {
+        if display_index < SPAN_ALL {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Invalid display index {display_index}"),
+            ));
+        }
+
+        let backend = capture::resolve(backend)?;
+        // Generated frames need neither a screen nor leave to capture it
+        if backend != Backend::Test {
+            permission::ensure_granted()?;
+        }
+        if display_index == SPAN_ALL && backend != Backend::Native {
+            let why = match backend {
+                Backend::Wayland => "the portal shares one monitor",
+                _ => "the test pattern is a single screen",
+            };
+            return Err(fail(
+                RdpStatus::Unsupported,
+                format!("Spanning every display needs native capture; {why}"),
+            ));
+        }
+        // The portal picks the monitor on Wayland, where X11 sees none
+        let all = match backend {
+            Backend::Wayland | Backend::Test => Vec::new(),
+            _ => display::enumerate().map_err(enumerate_failed)?,
+        };
+        let info = match display_index {
+            -1 => all.iter().find(|d| d.is_primary != 0),
+            SPAN_ALL => None,
+            index => all.get(index as usize),
+        };
+        let display_id = info.filter(|_| display_index != -1).map(|d| d.id);
+        let display_scale = info.map_or(1.0, |d| d.scale);
+        let mut restore_token = None;
+        let (capturer, span) = if display_index == SPAN_ALL {
+            let layout = Layout::current()?;
+            let span: Box<dyn FrameSource> = Box::new(Span::open(&layout)?);
+            (span, Some(layout))
+        } else {
+            (
+                capture::open(
+                    backend,
+                    display_index,
+                    || find_display(display_index),
+                    &mut restore_token,
+                )?,
+                None,
+            )
+        };
+        let display_size = (capturer.width(), capturer.height());
+
+        log::log(
+            LogLevel::Info,
+            &format!(
+                "Opened {backend:?} capture session on display {display_index} ({}x{})",
+                capturer.width(),
+                capturer.height()
+            ),
+        );
+
+        let config = SessionConfig::default();
+        let stats = Arc::new(Stats::default());
+        stats.set_backend(capturer.backend());
+        Ok(RdpSession {
+            capturer: Some(capturer),
+            display_size,
+            resizer: fr::Resizer::new(config.resize_alg),
+            scratch: Scratch::new(stats.memory()),
+            config,
+            last_hash: None,
+            packed_is_last: false,
+            pending: None,
+            tiles: TileState::default(),
+            zstd: DeltaState::default(),
+            focus: FocusState::default(),
+            backend,
+            display_index,
+            display_id,
+            display_scale,
+            span,
+            restore_token,
+            display_checked: Instant::now(),
+            availability: Availability::Available,
+            cursor_probe: probe_cursor(backend, display_index),
+            window: None,
+            cursor_image: cursor::fallback_arrow(),
+            cursor_generation: 0,
+            pacer: Pacer::default(),
+            fps_meter: FpsMeter::default(),
+            opened: Instant::now(),
+            next_sequence: 0,
+            shm_memory: Charge::new(stats.memory()),
+            stats,
+            rate: QualityController::new(DEFAULT_QUALITY),
+            bucket: BitrateBucket::default(),
+            video: None,
+            cipher: FrameCipher::default(),
+            recorder: None,
+            replay: None,
+            shm: None,
+        })
+    }
+
+    /// Opens a session on the display with `DisplayInfo::id` `id`, which
+    /// the session keeps following if other displays come and go.
+    pub fn with_display_id(id: u64) -> Result<RdpSession, RdpStatus> {
+        let index = display::index_of(id)
+            .map_err(enumerate_failed)?
+            .ok_or_else(|| {
+                fail(
+                    RdpStatus::NoDisplay,
+                    format!("No display with ID {id:#018x}"),
+                )
+            })?;
+        RdpSession::new(index as i32)
+    }
+
+    /// Opens a session sharing just the window with `WindowInfo::id` `id`:
+    /// the display holding the middle of the window is captured and
+    /// cropped to it, following the window as it moves (onto other
+    /// displays too) and resizes. Anything overlapping the window is
+    /// captured with it; see the `window` module. Any capture region is
+    /// ignored.
+    ///
+    /// Captures fail with `RdpStatus::DisplayUnavailable` while the window
+    /// is minimized and with `RdpStatus::WindowClosed` once it is closed.
+    /// Uses native capture, as Wayland does not let clients find other
+    /// windows.
+    pub fn with_window(id: u64) -> Result<RdpSession, RdpStatus> {
+        let tracker = window::Tracker::new(id).map_err(|e| {
+            fail(
+                RdpStatus::NoDisplay,
+                format!("Cannot look up window {id:#x}: {e}"),
+            )
+        })?;
+        let index = match tracker.placement() {
+            Placement::Closed => {
+                return Err(fail(
+                    RdpStatus::WindowClosed,
+                    format!("No window with ID {id:#x}"),
+                ));
+            }
+            Placement::Shown(bounds) => {
+                let (x, y) = bounds.center();
+                display_at(x, y)?.map(|(index, _)| index)
+            }
+            Placement::Hidden => None,
+        };
+
+        // The primary display until the window turns up somewhere
+        let mut session = RdpSession::with_backend(Backend::Native, index.unwrap_or(-1))?;
+        session.window = Some(WindowTarget {
+            tracker,
+            region: None,
+            closed: false,
+        });
+        Ok(session)
+    }
+
+    /// Settings access for the setters. Any change forgets the previous
+    /// frame's hash, so the next capture always reflects the new settings.
+    fn config_mut(&mut self) -> &mut SessionConfig {
+        self.forget_previous();
+        &mut self.config
+    }
+
+    /// Forgets the previous frame, so the next capture produces output
+    /// (starting with a keyframe) however much it looks like it.
+    fn forget_previous(&mut self) {
+        self.last_hash = None;
+        self.packed_is_last = false;
+        self.pending = None;
+        self.tiles.request_keyframe();
+        self.zstd.request_keyframe();
+    }
+
+    /// Makes sure there is a capturer matching the display: reopens it when
+    /// it is missing, due for a size check or due for another attempt after
+    /// the display was lost. Fails with `RdpStatus::DisplayUnavailable`
+    /// without blocking while the display stays lost.
+    fn ensure_capturer(&mut self) -> Result<(), RdpStatus> {
+        match self.availability {
+            Availability::Failed => {
+                return Err(fail(
+                    RdpStatus::CaptureFailed,
+                    format!(
+                        "Gave up on display {}; open a new session",
+                        self.display_index
+                    ),
+                ));
+            }
+            Availability::Lost {
+                retry_at, status, ..
+            } if Instant::now() < retry_at => {
+                return Err(fail_at(
+                    LogLevel::Debug,
+                    status,
+                    format!("Display {} is still unavailable", self.display_index),
+                ));
+            }
+            Availability::Available
+                if self.capturer.is_some()
+                    && self.display_checked.elapsed() < DISPLAY_CHECK_INTERVAL =>
+            {
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        match self.check_display() {
+            Err(status) if status != RdpStatus::ResolutionChanged => Err(self.display_lost(status)),
+            result => {
+                if let Availability::Lost { since, .. } = self.availability {
+                    log::log(
+                        LogLevel::Info,
+                        &format!(
+                            "Display {} available again after {} ms",
+                            self.display_index,
+                            since.elapsed().as_millis()
+                        ),
+                    );
+                }
+                self.availability = Availability::Available;
+                result
+            }
+        }
+    }
+
+    /// Drops the capturer and schedules the next attempt to reopen it, or
+    /// gives up once the display has been gone for `recovery_timeout_ms`.
+    /// `cause` is what reaching the display last failed with; it is reported
+    /// as `RdpStatus::DisplayUnavailable` unless the display was removed.
+    /// A declined Wayland portal dialog gives up straight away.
+    fn display_lost(&mut self, cause: RdpStatus) -> RdpStatus {
+        let status = match cause {
+            RdpStatus::DisplayRemoved => RdpStatus::DisplayRemoved,
+            _ => RdpStatus::DisplayUnavailable,
+        };
+        self.capturer = None;
+        let now = Instant::now();
+        let (since, backoff) = match self.availability {
+            Availability::Lost { since, backoff, .. } => {
+                (since, (backoff * 2).min(MAX_RECOVERY_BACKOFF))
+            }
+            _ => (now, MIN_RECOVERY_BACKOFF),
+        };
+
+        // Asking again would only pop the portal's dialog up again
+        if cause == RdpStatus::PermissionDenied {
+            self.availability = Availability::Failed;
+            return fail(
+                RdpStatus::PermissionDenied,
+                "Screen sharing was declined; open a new session to ask again",
+            );
+        }
+        let timeout_ms = self.config.recovery_timeout_ms;
+        if timeout_ms != WAIT_FOREVER && now - since >= Duration::from_millis(u64::from(timeout_ms))
+        {
+            self.availability = Availability::Failed;
+            return fail(
+                RdpStatus::CaptureFailed,
+                format!(
+                    "Display {} still unavailable after {timeout_ms} ms, giving up",
+                    self.display_index
+                ),
+            );
+        }
+        self.availability = Availability::Lost {
+            since,
+            retry_at: now + backoff,
+            backoff,
+            status,
+        };
+        fail_at(
+            LogLevel::Warn,
+            status,
+            format!(
+                "Display {} unavailable, retrying in {} ms",
+                self.display_index,
+                backoff.as_millis()
+            ),
+        )
+    }
+
+    /// The session's display as of now. A display opened by index or ID is
+    /// followed to wherever it moved in the list, and once it is gone this
+    /// fails with `RdpStatus::DisplayRemoved` rather than picking up
+    /// whatever display took its index.
+    fn locate(&mut self) -> Result<Display, RdpStatus> {
+        let all = display::enumerate().map_err(enumerate_failed)?;
+        let info = match self.display_id {
+            Some(id) => {
+                let index = all.iter().position(|d| d.id == id).ok_or_else(|| {
+                    fail(
+                        RdpStatus::DisplayRemoved,
+                        format!("Display {} (ID {id:#018x}) was removed", self.display_index),
+                    )
+                })?;
+                self.display_index = index as i32;
+                all.get(index)
+            }
+            None if self.display_index == -1 => all.iter().find(|d| d.is_primary != 0),
+            None => all.get(self.display_index as usize),
+        };
+        // Scaling can change without the pixel size doing so
+        self.display_scale = info.map_or(1.0, |d| d.scale);
+        find_display(self.display_index)
+    }
+
+    /// Reopens the capturer when there is none (a failed capture dropped
+    /// it) or the display's size no longer matches it.
+    fn check_display(&mut self) -> Result<(), RdpStatus> {
+        self.display_checked = Instant::now();
+        let reopened = match self.backend {
+            // The stream renegotiates its size by itself
+            Backend::Wayland if self.capturer.is_some() => false,
+            Backend::Wayland => {
+                self.capturer = Some(capture::wayland(&mut self.restore_token)?);
+                true
+            }
+            // Follows `rdp_set_test_pattern` as a display follows its mode
+            Backend::Test => {
+                if self.capturer.is_some() && pattern::size() == self.display_size {
+                    return Ok(());
+                }
+                self.capturer = None;
+                self.capturer = Some(Box::new(TestPatternSource::open()));
+                true
+            }
+            // Sizes change with the arrangement, so that is what is compared
+            _ if self.span.is_some() => {
+                let layout = Layout::current()?;
+                if self.capturer.is_some() && self.span.as_ref() == Some(&layout) {
+                    return Ok(());
+                }
+                self.capturer = None;
+                self.capturer = Some(Box::new(Span::open(&layout)?));
+                self.span = Some(layout);
+                true
+            }
+            _ => {
+                let display = self.locate()?;
+                let size = (display.width(), display.height());
+                if self.capturer.is_some() && size == self.display_size {
+                    return Ok(());
+                }
+                // Dropped first, as some platforms allow only one capturer
+                // per display
+                self.capturer = None;
+                self.capturer = Some(capture::native(display, self.display_index)?);
+                true
+            }
+        };
+        let capturer = self.capturer.as_ref().expect("capturer was just checked");
+        let size = (capturer.width(), capturer.height());
+        if !reopened && size == self.display_size {
+            return Ok(());
+        }
+
+        // Everything sized by or diffed against earlier frames starts over
+        self.cursor_probe = probe_cursor(self.backend, self.display_index);
+        self.scratch.release(false);
+        self.video = None;
+        self.forget_previous();
+
+        let (old_w, old_h) = std::mem::replace(&mut self.display_size, size);
+        if size == (old_w, old_h) {
+            log::log(
+                LogLevel::Info,
+                &format!("Reopened capture on display {}", self.display_index),
+            );
+            return Ok(());
+        }
+        Err(fail_at(
+            LogLevel::Info,
+            RdpStatus::ResolutionChanged,
+            format!(
+                "Display {} changed from {old_w}x{old_h} to {}x{}",
+                self.display_index, size.0, size.1
+            ),
+        ))
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    /// The backend the session opened with; never `Backend::Auto`.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// The display's current index in `Display::all()`, -1 or `SPAN_ALL`.
+    pub fn display_index(&self) -> i32 {
+        self.display_index
+    }
+
+    /// Replaces every setting at once, checking each as its setter would.
+    /// Nothing changes when one is rejected. The encryption key, recording
+    /// and replay buffer are not part of the config and stay as they are.
+    pub fn set_config(&mut self, config: SessionConfig) -> Result<(), RdpStatus> {
+        if matches!(
+            config.format,
+            FrameFormat::TiledKeyframe | FrameFormat::TiledDelta | FrameFormat::Text
+        ) {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("{:?} is not an output format", config.format),
+            ));
+        }
+        encode::ensure_supported(config.format)?;
+
+        let previous = self.config.clone();
+        let checked = self
+            .set_zstd(config.zstd_level, config.zstd_delta)
+            .and_then(|()| self.set_scale(config.scale, true))
+            .and_then(|()| self.set_output_size(config.output_size.0, config.output_size.1))
+            .and_then(|()| self.set_quality_range(config.min_quality, config.max_quality))
+            .and_then(|()| self.set_budget_downscale(config.budget_downscale_step))
+            .and_then(|()| self.set_region(config.region))
+            .and_then(|()| self.set_blackout(config.blackout.clone()))
+            .and_then(|()| self.set_tiling(config.tile_size, config.keyframe_interval))
+            .and_then(|()| self.set_encode_bands(config.encode_bands))
+            .and_then(|()| self.set_focus(config.focus))
+            .and_then(|()| self.set_progressive(config.progressive));
+        if let Err(status) = checked {
+            self.config = previous;
+            return Err(status);
+        }
+
+        // The setters that reset state along with the value
+        self.set_format(config.format);
+        self.set_target_fps(config.target_fps);
+        self.set_target_frame_bytes(config.target_frame_bytes);
+        self.set_bitrate(config.bitrate_kbps);
+        self.set_include_cursor(config.include_cursor);
+        self.set_wait_strategy(config.wait_strategy);
+        self.set_resize_alg(config.resize_alg);
+        *self.config_mut() = SessionConfig {
+            quality: config.quality.clamp(1, 100),
+            grayscale: config.grayscale || config.pixel_format == PixelFormat::Gray,
+            fill_color: config.fill_color & 0x00ff_ffff,
+            blackout_color: config.blackout_color & 0x00ff_ffff,
+            queue_capacity: config.queue_capacity.clamp(1, queue::MAX_CAPACITY as u32),
+            ..config
+        };
+        Ok(())
+    }
+
+    /// Sets the JPEG quality, clamping it to 1–100 so turbojpeg never sees
+    /// an out-of-range value.
+    pub fn set_quality(&mut self, quality: u8) {
+        self.config_mut().quality = quality.clamp(1, 100);
+    }
+
+    /// Selects the output encoding.
+    pub fn set_format(&mut self, format: FrameFormat) {
+        if !video::is_video(format) {
+            self.video = None;
+        }
+        self.config_mut().format = format;
+    }
+
+    /// Switches between colour and grayscale (BT.601 luma) output.
+    pub fn set_grayscale(&mut self, grayscale: bool) {
+        self.config_mut().grayscale = grayscale;
+    }
+
+    /// Sets the channel layout of raw output and JPEG encoder input.
+    /// `PixelFormat::Gray` is shorthand for turning grayscale on.
+    pub fn set_pixel_format(&mut self, pixel_format: PixelFormat) {
+        self.config_mut().pixel_format = pixel_format;
+        if pixel_format == PixelFormat::Gray {
+            self.config_mut().grayscale = true;
+        }
+    }
+
+    /// Sets the PNG compression effort.
+    pub fn set_png_compression(&mut self, compression: CompressionType) {
+        self.config_mut().png_compression = compression;
+    }
+
+    /// Sets the zstd level (1–22) and delta coding of `RawZstd` frames.
+    pub fn set_zstd(&mut self, level: i32, delta: bool) -> Result<(), RdpStatus> {
+        if !(zstd::MIN_LEVEL..=zstd::MAX_LEVEL).contains(&level) {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("zstd level {level} is outside 1–22"),
+            ));
+        }
+        let config = self.config_mut();
+        config.zstd_level = level;
+        config.zstd_delta = delta;
+        Ok(())
+    }
+
+    /// Sets the JPEG chroma subsampling.
+    pub fn set_subsampling(&mut self, subsampling: Subsampling) {
+        self.config_mut().subsampling = subsampling;
+    }
+
+    /// Switches JPEG between baseline (the default) and progressive
+    /// encoding, which lets a viewer draw a coarse image before the whole
+    /// frame has arrived, for some 10% less data but several times the
+    /// encode time (see `benches/jpeg.rs`). Needs the `turbojpeg` feature;
+    /// the fallback encoder fails with `RdpStatus::Unsupported`.
+    pub fn set_progressive(&mut self, progressive: bool) -> Result<(), RdpStatus> {
+        if progressive && !cfg!(feature = "turbojpeg") {
+            return Err(fail(
+                RdpStatus::Unsupported,
+                "Progressive JPEG requires building rdp_core with the `turbojpeg` feature",
+            ));
+        }
+        self.config_mut().progressive = progressive;
+        Ok(())
+    }
+
+    /// Puts a JPEG restart marker after every `rows` rows of MCUs (8 or,
+    /// for 4:2:0, 16 pixels high), or none for 0, the default. A decoder
+    /// resynchronizes at each marker, so when part of a frame sent in
+    /// chunks is lost only its band is damaged, not the rest of the image.
+    /// Each marker costs a few bytes and a little compression, as the DC
+    /// prediction restarts. Progressive JPEG ignores it.
+    pub fn set_restart_rows(&mut self, rows: u32) {
+        self.config_mut().restart_rows = rows;
+    }
+
+    /// Makes captures without an explicit target come out at `scale` times
+    /// the captured size. The scale must be positive and, unless
+    /// `allow_upscale` is set, at most 1.
+    pub fn set_scale(&mut self, scale: f32, allow_upscale: bool) -> Result<(), RdpStatus> {
+        // Written so NaN fails as well
+        if !(scale > 0.0 && scale.is_finite()) || (scale > 1.0 && !allow_upscale) {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Scale {scale} out of range (allow_upscale={allow_upscale})"),
+            ));
+        }
+        self.config_mut().scale = scale;
+        Ok(())
+    }
+
+    /// Makes captures without an explicit target come out at `width x
+    /// height`, as if it were passed to `capture`; `(0, 0)` goes back to
+    /// `scale` and `max_dim`. Both or neither must be 0.
+    pub fn set_output_size(&mut self, width: u32, height: u32) -> Result<(), RdpStatus> {
+        if (width == 0) != (height == 0) {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Output size {width}x{height} must be set or cleared on both axes"),
+            ));
+        }
+        self.config_mut().output_size = (width, height);
+        Ok(())
+    }
+
+    /// Caps the long edge of captures without an explicit target at
+    /// `max_dim` pixels (0 = no cap), keeping the aspect ratio.
+    pub fn set_max_dim(&mut self, max_dim: u32) {
+        self.config_mut().max_dim = max_dim;
+    }
+
+    /// Stops captures from upscaling: a target larger than the (cropped)
+    /// frame is reduced, keeping its aspect ratio, so the limiting axis
+    /// matches the frame.
+    pub fn set_downscale_only(&mut self, downscale_only: bool) {
+        self.config_mut().downscale_only = downscale_only;
+    }
+
+    /// Sets the resize filter used for every following frame.
+    pub fn set_resize_alg(&mut self, resize_alg: fr::ResizeAlg) {
+        self.config_mut().resize_alg = resize_alg;
+        self.resizer.algorithm = resize_alg;
+    }
+
+    /// Sets how frames are mapped onto an explicit target size.
+    pub fn set_fit_mode(&mut self, fit: FitMode) {
+        self.config_mut().fit = fit;
+    }
+
+    /// Sets the `FitMode::Fit` padding colour (`0xRRGGBB`).
+    pub fn set_fill_color(&mut self, rgb: u32) {
+        self.config_mut().fill_color = rgb & 0x00ff_ffff;
+    }
+
+    /// Sets how long `capture` waits for a frame before failing with
+    /// `RdpStatus::Timeout` (or `RdpStatus::WouldBlock` when 0).
+    pub fn set_timeout(&mut self, timeout_ms: u32) {
+        self.config_mut().timeout_ms = timeout_ms;
+    }
+
+    /// Selects the RGB to YUV matrix of I420 and NV12 frames.
+    pub fn set_yuv_matrix(&mut self, matrix: YuvMatrix) {
+        self.config_mut().yuv_matrix = matrix;
+    }
+
+    /// Sets how `capture` waits between polls that find no new frame. Not
+    /// a change to the output.
+    pub fn set_wait_strategy(&mut self, strategy: WaitStrategy) {
+        self.config.wait_strategy = strategy;
+        self.stats.set_wait_strategy(strategy);
+    }
+
+    /// Sets the queue policy and capacity (0 for the default) of consumers
+    /// started from now on; running ones keep theirs. Not a change to the
+    /// output.
+    pub fn set_queue(&mut self, policy: QueuePolicy, capacity: u32) {
+        self.config.queue_policy = policy;
+        self.config.queue_capacity = match capacity {
+            0 => queue::DEFAULT_CAPACITY as u32,
+            n => n.min(queue::MAX_CAPACITY as u32),
+        };
+    }
+
+    /// Sets whether network clients started from now on are told about the
+    /// frames they missed. Not a change to the output.
+    pub fn set_report_drops(&mut self, report: bool) {
+        self.config.report_drops = report;
+    }
+
+    /// Sets the priority and core mask of stream threads started from now
+    /// on (see the `priority` module). Not a change to the output.
+    pub fn set_thread_scheduling(&mut self, priority: ThreadPriority, affinity: u64) {
+        self.config.thread_priority = priority;
+        self.config.thread_affinity = affinity;
+    }
+
+    /// Sets how long a lost display is retried before the session fails for
+    /// good. Not a change to the output.
+    pub fn set_recovery_timeout(&mut self, timeout_ms: u32) {
+        self.config.recovery_timeout_ms = timeout_ms;
+    }
+
+    /// Paces `capture` to `fps` frames a second (0 = as fast as possible)
+    /// and restarts the measured rate. Not a change to the output, so the
+    /// next frame may still be skipped as unchanged.
+    pub fn set_target_fps(&mut self, fps: u32) {
+        self.config.target_fps = fps;
+        self.fps_meter.reset();
+        self.stats.set_fps(0.0);
+    }
+
+    pub fn target_fps(&self) -> u32 {
+        self.config.target_fps
+    }
+
+    /// The session's timings and counters.
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Adapts the JPEG quality of every frame so its encoded size stays
+    /// near `bytes` (0 goes back to the fixed quality). The controller
+    /// starts from the current quality; changing the budget keeps its state,
+    /// so it can be adjusted every frame without a jump.
+    pub fn set_target_frame_bytes(&mut self, bytes: u32) {
+        if !self.config.adapts_quality() {
+            self.rate = QualityController::new(self.config.quality);
+        }
+        self.config.target_frame_bytes = bytes;
+    }
+
+    /// Budgets frames so the stream averages `kbps` kilobits a second (0
+    /// turns it off), overriding any per-frame target. Frames are assumed
+    /// to come at `target_fps`, or at the measured rate without one. A new
+    /// bitrate starts the bucket over, but the adapted quality carries on
+    /// from where it was.
+    pub fn set_bitrate(&mut self, kbps: u32) {
+        if !self.config.adapts_quality() {
+            self.rate = QualityController::new(self.config.quality);
+        }
+        if kbps != self.config.bitrate_kbps {
+            self.bucket.reset();
+        }
+        self.config.bitrate_kbps = kbps;
+    }
+
+    /// Bounds the quality adapted to the byte budget; both must be 1–100
+    /// and `min <= max`.
+    pub fn set_quality_range(&mut self, min: u8, max: u8) -> Result<(), RdpStatus> {
+        if min == 0 || max > 100 || min > max {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Invalid quality range {min}..={max}"),
+            ));
+        }
+        self.config.min_quality = min;
+        self.config.max_quality = max;
+        Ok(())
+    }
+
+    /// Lets frames that overshoot the byte budget at minimum quality shrink
+    /// by `step` of each side (repeatedly, down to a quarter of the size;
+    /// it grows back once there is room). 0 keeps the resolution; up to 0.5
+    /// is accepted.
+    pub fn set_budget_downscale(&mut self, step: f32) -> Result<(), RdpStatus> {
+        if !(0.0..=MAX_DOWNSCALE_STEP).contains(&step) {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Downscale step {step} is outside 0..={MAX_DOWNSCALE_STEP}"),
+            ));
+        }
+        self.config.budget_downscale_step = step;
+        Ok(())
+    }
+
+    /// Restricts capture to `region` (clamped to the display bounds at
+    /// capture time), or captures the whole display when `None`. Zero-area
+    /// regions are rejected.
+    ///
+    /// A region that is resized goes straight from the captured frame into
+    /// the resize, without being copied out first, unless the cursor is
+    /// drawn, the output is rotated, mirrored or grayscale, dirty tracking
+    /// is on or blackout lies in or next to it. That keeps a small region
+    /// scaled up to a large target (a digital zoom) as cheap as the target
+    /// allows. Its edges are then filtered with the pixels around it
+    /// rather than repeated edge pixels.
+    pub fn set_region(&mut self, region: Option<Rect>) -> Result<(), RdpStatus> {
+        if let Some(r) = region
+            && (r.w == 0 || r.h == 0)
+        {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Rejecting zero-area capture region {r:?}"),
+            ));
+        }
+        self.config_mut().region = region;
+        Ok(())
+    }
+
+    /// Turns scaling frames down to the display's logical points on or off
+    /// (the default).
+    pub fn set_capture_logical_size(&mut self, capture_logical_size: bool) {
+        self.config_mut().capture_logical_size = capture_logical_size;
+    }
+
+    /// Rotates or mirrors every frame from now on. A quarter turn swaps the
+    /// output width and height; explicit targets apply to the turned frame.
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.config_mut().orientation = orientation;
+    }
+
+    /// Paints `rects` (in display coordinates) over every frame from now
+    /// on; an empty list stops that.
+    pub fn set_blackout(&mut self, rects: Vec<Rect>) -> Result<(), RdpStatus> {
+        if let Some(r) = rects.iter().find(|r| r.w == 0 || r.h == 0) {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Rejecting zero-area blackout rectangle {r:?}"),
+            ));
+        }
+        self.config_mut().blackout = rects;
+        Ok(())
+    }
+
+    pub fn set_blackout_color(&mut self, rgb: u32) {
+        self.config_mut().blackout_color = rgb & 0x00ff_ffff;
+    }
+
+    /// Draws `text` on every output frame from now on; an empty string
+    /// stops that.
+    pub fn set_watermark(&mut self, text: &str, anchor: Anchor, opacity: u8) {
+        self.config_mut().watermark = (!text.is_empty()).then(|| TextOverlay {
+            text: text.to_owned(),
+            anchor,
+            opacity,
+        });
+    }
+
+    /// Turns drawing the capture timestamp and sequence number into frames
+    /// on or off (the default).
+    pub fn set_overlay_timestamp(&mut self, overlay_timestamp: bool) {
+        self.config_mut().overlay_timestamp = overlay_timestamp;
+    }
+
+    /// Enables tiled keyframe/delta output with `tile_size`-pixel tiles and a
+    /// keyframe every `keyframe_interval` frames, or disables it when
+    /// `tile_size` is 0.
+    pub fn set_tiling(&mut self, tile_size: u32, keyframe_interval: u32) -> Result<(), RdpStatus> {
+        if tile_size != 0 && tile_size < MIN_TILE_SIZE {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Tile size {tile_size} is below the minimum of {MIN_TILE_SIZE}"),
+            ));
+        }
+        let config = self.config_mut();
+        config.tile_size = tile_size;
+        config.keyframe_interval = keyframe_interval;
+        Ok(())
+    }
+
+    /// Splits JPEG, PNG and WebP frames into `bands` horizontal bands that
+    /// are encoded in parallel, or encodes them whole for 0 or 1. Tiled
+    /// sessions split their keyframes and encode changed tiles in parallel.
+    pub fn set_encode_bands(&mut self, bands: u32) -> Result<(), RdpStatus> {
+        if bands > MAX_ENCODE_BANDS {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("{bands} bands is above the maximum of {MAX_ENCODE_BANDS}"),
+            ));
+        }
+        self.config_mut().encode_bands = bands;
+        Ok(())
+    }
+
+    /// Encrypts the payload of every following frame with AES-256-GCM under
+    /// `key` (32 bytes), or stops encrypting with `None`. The next frame is
+    /// a keyframe, so a receiver that just got the key can start from it.
+    pub fn set_encryption_key(&mut self, key: Option<&[u8]>) -> Result<(), RdpStatus> {
+        self.cipher.set_key(key)?;
+        self.request_keyframe();
+        Ok(())
+    }
+
+    pub fn is_encrypting(&self) -> bool {
+        self.cipher.is_on()
+    }
+
+    /// Starts recording every JPEG frame the session produces from now on to
+    /// an AVI file at `path`, played back at `fps` (see the `record`
+    /// module). The session must be producing whole, untiled JPEG; frames
+    /// in other formats after a settings change are left out.
+    pub fn start_recording(&mut self, path: &str, fps: u32) -> Result<(), RdpStatus> {
+        if self.config.format != FrameFormat::Jpeg
+            || self.config.tile_size > 0
+            || self.config.encode_bands > 1
+            || self.config.focus.is_some()
+        {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Recording needs untiled JPEG output without bands or focus",
+            ));
+        }
+        if self.recorder.is_some() {
+            return Err(fail(RdpStatus::Busy, "Session is already recording"));
+        }
+        self.recorder = Some(Recorder::start(path, fps)?);
+        Ok(())
+    }
+
+    /// Ends the recording, if any. The caller stops it, outside the session
+    /// lock, since that waits for the queued frames to be written.
+    pub fn take_recorder(&mut self) -> Option<Recorder> {
+        self.recorder.take()
+    }
+
+    /// Keeps the last `seconds` of output frames, in at most `max_bytes`, for
+    /// `replay_stills`; `seconds` 0 turns the buffer off and frees it.
+    pub fn set_replay_buffer(&mut self, seconds: u32, max_bytes: u64) {
+        match (&mut self.replay, seconds) {
+            (_, 0) => self.replay = None,
+            (Some(replay), _) => replay.set_limits(seconds, max_bytes),
+            (None, _) => {
+                let replay = ReplayBuffer::new(seconds, max_bytes, self.stats.memory());
+                self.replay = Some(replay);
+            }
+        }
+    }
+
+    /// Publishes every frame the session produces from now on to shared
+    /// memory `name`, a ring of `slot_count` slots sized for the display
+    /// (see the `shm` module), replacing any region the session had.
+    /// `None` stops publishing; either way the old region is closed and
+    /// unlinked first, so its name can be reused.
+    pub fn set_shm(&mut self, name: Option<&str>, slot_count: u32) -> Result<(), RdpStatus> {
+        self.shm = None;
+        self.shm_memory.set(0);
+        if let Some(name) = name {
+            let (width, height) = self.display_size;
+            let slot_size = shm::slot_size_for(width, height);
+            let size = shm::region_size(slot_count, slot_size) as u64;
+            if !self.shm_memory.set(size) {
+                return Err(fail(
+                    RdpStatus::MemoryLimit,
+                    format!(
+                        "A {size}-byte shared memory region does not fit the session's \
+                         memory limit ({} bytes free)",
+                        self.stats.memory().available()
+                    ),
+                ));
+            }
+            match ShmWriter::create(name, slot_count, slot_size) {
+                Ok(writer) => self.shm = Some(writer),
+                Err(status) => {
+                    self.shm_memory.set(0);
+                    return Err(status);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Caps what the session's buffers and queued frames hold at `bytes`
+    /// (0 for no cap; see the `memory` module). Replay stills beyond the
+    /// new cap are dropped at once; everything else gives way as it next
+    /// needs room.
+    pub fn set_memory_limit(&mut self, bytes: u64) {
+        let memory = Arc::clone(self.stats.memory());
+        memory.set_limit(bytes);
+        if bytes > 0
+            && let Some(replay) = &mut self.replay
+        {
+            replay.shed(memory.used().saturating_sub(bytes));
+        }
+    }
+
+    /// The frames the replay buffer holds, oldest first.
+    pub fn replay_stills(&self) -> Result<Vec<Arc<Still>>, RdpStatus> {
+        match &self.replay {
+            Some(replay) => Ok(replay.stills()),
+            None => Err(fail(
+                RdpStatus::InvalidArgument,
+                "Session has no replay buffer (see rdp_session_set_replay_buffer)",
+            )),
+        }
+    }
+
+    /// Makes the next tiled, video or zstd delta frame a keyframe, which
+    /// the bitrate bucket lets overspend without running into debt.
+    pub fn request_keyframe(&mut self) {
+        self.tiles.request_keyframe();
+        self.zstd.request_keyframe();
+        if let Some(video) = &mut self.video
+            && let Err(status) = video.force_keyframe()
+        {
+            // A reopened encoder starts on a keyframe
+            log::log(
+                LogLevel::Warn,
+                &format!("Reopening the video encoder for a keyframe ({status:?})"),
+            );
+            self.video = None;
+        }
+        self.bucket.forgive_next();
+    }
+
+    /// Turns dirty-rectangle tracking on or off (the default). While on,
+    /// each frame is diffed against the previous one and
+    /// `EncodedFrame::dirty` bounds the pixels that changed.
+    pub fn set_track_dirty(&mut self, track_dirty: bool) {
+        self.config_mut().track_dirty = track_dirty;
+    }
+
+    /// Turns "no change" detection on or off (the default). While on, a
+    /// capture whose pixels (and cursor position) are identical to the
+    /// previous one fails with `RdpStatus::NoChange` instead of being resized and encoded again.
+    pub fn set_detect_changes(&mut self, detect_changes: bool) {
+        self.config_mut().detect_changes = detect_changes;
+    }
+
+    /// Turns drawing the mouse cursor into frames on or off (the default).
+    /// If the cursor position cannot be queried on this display, frames are
+    /// still captured, just without it.
+    pub fn set_include_cursor(&mut self, include_cursor: bool) {
+        if include_cursor && self.cursor_probe.is_none() {
+            log::log(
+                LogLevel::Warn,
+                &format!("Cannot query the cursor on display {}", self.display_index),
+            );
+        }
+        self.config_mut().include_cursor = include_cursor;
+    }
+
+    /// Re-encodes the rectangle around the cursor described by `focus` at its
+    /// quality and subsampling on top of every JPEG frame, or stops with
+    /// `None` (the default); see the `focus` module. Frames then come as
+    /// tile containers, also in untiled sessions. If the cursor position
+    /// cannot be queried on this display, the rectangle stays in the middle
+    /// of the frame.
+    pub fn set_focus(&mut self, focus: Option<Focus>) -> Result<(), RdpStatus> {
+        if let Some(focus) = &focus {
+            if focus.width == 0 || focus.height == 0 {
+                return Err(fail(
+                    RdpStatus::InvalidArgument,
+                    format!("Focus region {}x{} has no area", focus.width, focus.height),
+                ));
+            }
+            if !(1..=100).contains(&focus.quality) {
+                return Err(fail(
+                    RdpStatus::InvalidArgument,
+                    format!("Focus quality {} is outside 1..=100", focus.quality),
+                ));
+            }
+            if self.cursor_probe.is_none() {
+                log::log(
+                    LogLevel::Warn,
+                    &format!("Cannot query the cursor on display {}", self.display_index),
+                );
+            }
+        }
+        self.config_mut().focus = focus;
+        Ok(())
+    }
+
+    /// Moves the pointer to (`x`, `y`) in captured-image pixels before any
+    /// resize: relative to the capture region if one is set, otherwise to the
+    /// display, so coordinates line up with what the session captures. With
+    /// `capture_logical_size` on they are logical points instead, like the
+    /// frames.
+    pub fn inject_mouse_move(&self, x: i32, y: i32) -> Result<(), RdpStatus> {
+        let Some(probe) = &self.cursor_probe else {
+            return Err(fail(
+                RdpStatus::NoDisplay,
+                format!(
+                    "Cannot locate display {} on the desktop",
+                    self.display_index
+                ),
+            ));
+        };
+        let (left, top) = self
+            .crop_region()
+            .map_or((0, 0), |r| (r.x as i32, r.y as i32));
+        let scale = f64::from(self.logical_scale());
+        let (x, y) = (
+            (f64::from(x) * scale).round() as i32,
+            (f64::from(y) * scale).round() as i32,
+        );
+        let (screen_x, screen_y) = probe.to_screen(x + left, y + top);
+        input::move_to(screen_x, screen_y)
+    }
+
+    /// What captures are cropped to: the window of a window session,
+    /// otherwise the configured region.
+    fn crop_region(&self) -> Option<Rect> {
+        match &self.window {
+            Some(target) => target.region,
+            None => self.config.region,
+        }
+    }
+
+    /// Points a window session's crop at where its window is now, moving
+    /// to the display holding the middle of the window if that changed.
+    /// Fails with `RdpStatus::DisplayUnavailable` while the window is
+    /// minimized or off screen and with `RdpStatus::WindowClosed` once it
+    /// is gone.
+    fn track_window(&mut self) -> Result<(), RdpStatus> {
+        let Some(target) = &mut self.window else {
+            return Ok(());
+        };
+        if target.closed {
+            return Err(fail(
+                RdpStatus::WindowClosed,
+                "The shared window was closed; open a new session",
+            ));
+        }
+        let bounds = match target.tracker.placement() {
+            Placement::Shown(bounds) => bounds,
+            Placement::Hidden => {
+                return Err(fail_at(
+                    LogLevel::Debug,
+                    RdpStatus::DisplayUnavailable,
+                    "The shared window is minimized or hidden",
+                ));
+            }
+            Placement::Closed => {
+                target.closed = true;
+                self.capturer = None;
+                return Err(fail_at(
+                    LogLevel::Info,
+                    RdpStatus::WindowClosed,
+                    "The shared window was closed",
+                ));
+            }
+        };
+
+        let (center_x, center_y) = bounds.center();
+        let (w, h) = self.display_size;
+        let on_display = self.cursor_probe.as_ref().is_some_and(|probe| {
+            let (x, y) = probe.to_display(f64::from(center_x), f64::from(center_y));
+            x >= 0 && y >= 0 && (x as usize) < w && (y as usize) < h
+        });
+        if !on_display
+            && let Some((index, id)) = display_at(center_x, center_y)?
+            && self.display_id != Some(id)
+        {
+            log::log(
+                LogLevel::Info,
+                &format!("Shared window moved to display {index}"),
+            );
+            self.display_index = index;
+            self.display_id = Some(id);
+            // Reopened on the new display by `ensure_capturer`
+            self.capturer = None;
+            self.cursor_probe = probe_cursor(self.backend, index);
+        }
+
+        let region = self
+            .cursor_probe
+            .as_ref()
+            .and_then(|probe| window_rect(probe, bounds))
+            .ok_or_else(|| {
+                fail_at(
+                    LogLevel::Debug,
+                    RdpStatus::DisplayUnavailable,
+                    "The shared window is off screen",
+                )
+            })?;
+        let target = self.window.as_mut().expect("window was just checked");
+        let previous = target.region.replace(region);
+        if previous.is_none_or(|r| (r.w, r.h) != (region.w, region.h)) {
+            self.forget_previous();
+        }
+        Ok(())
+    }
+
+    /// Physical pixels per unit of frame and input coordinates before any
+    /// other resize: the display's scale with `capture_logical_size` on,
+    /// otherwise 1.
+    fn logical_scale(&self) -> f32 {
+        if self.config.capture_logical_size && self.display_scale > 1.0 {
+            self.display_scale
+        } else {
+            1.0
+        }
+    }
+
+    /// Captures one frame, optionally resizes it to `target_w x target_h`
+    /// (both must be > 0; otherwise the configured output size, or scale and
+    /// `max_dim`, apply) and returns it in the configured format.
+    ///
+    /// With a `target_fps`, first sleeps until one frame interval after the
+    /// previous call started, i.e. whatever capture and encoding left of
+    /// that frame's budget.
+    pub fn capture(&mut self, target_w: u32, target_h: u32) -> Result<EncodedFrame, RdpStatus> {
+        if let Some(frame) = self.take_pending((target_w, target_h)) {
+            return Ok(frame);
+        }
+        self.pacer
+            .wait(pace::interval(self.config.target_fps), || false);
+        self.capture_within(target_w, target_h, self.config.timeout_ms)
+    }
+
+    /// Polls the capturer exactly once, without sleeping. Fails with
+    /// `RdpStatus::WouldBlock` (and does no resize/encode work) when no new
+    /// frame is ready yet.
+    pub fn try_capture(&mut self, target_w: u32, target_h: u32) -> Result<EncodedFrame, RdpStatus> {
+        self.capture_within(target_w, target_h, 0)
+    }
+
+    /// Hands `frame`, captured for `target` but not taken by the caller
+    /// (its buffer was too small), back to the session. The next capture
+    /// for the same target returns it before capturing anything new, so
+    /// no delta or sequence number goes missing; one for another target
+    /// drops it and starts over from a keyframe.
+    pub fn hold(&mut self, frame: EncodedFrame, target: (u32, u32)) {
+        self.pending = Some((frame, target));
+    }
+
+    fn take_pending(&mut self, target: (u32, u32)) -> Option<EncodedFrame> {
+        let (frame, held_for) = self.pending.take()?;
+        if held_for == target {
+            return Some(frame);
+        }
+        // Later frames may be deltas against it
+        self.forget_previous();
+        None
+    }
+
+    fn capture_within(
+        &mut self,
+        target_w: u32,
+        target_h: u32,
+        timeout_ms: u32,
+    ) -> Result<EncodedFrame, RdpStatus> {
+        if let Some(frame) = self.take_pending((target_w, target_h)) {
+            return Ok(frame);
+        }
+        self.track_window()?;
+        self.ensure_capturer()?;
+        let (w, h) = self.display_size;
+
+        // 1. Get a frame (blocking until ready, or until the timeout
+        //    expires). The capturer is out of the session while its frame
+        //    is borrowed, so the stages after can have the rest of it
+        let mut capturer = self.capturer.take();
+        let mut wait = Wait::new(timeout_ms);
+        let result = {
+            let frame = loop {
+                let source = capturer.as_mut().expect("capturer was just checked");
+                let timeout = wait.poll_timeout(self.config.wait_strategy, source.as_ref());
+                let failure = match source.frame_within(timeout) {
+                    // A short frame means the display changed under the capturer
+                    Ok(frame) if frame.len() >= w * h * 4 || wait.reopened => break frame,
+                    Ok(_) => None,
+                    Err(e) => Some(e),
+                };
+                self.retry(failure, &mut wait, &mut capturer)?;
+            };
+            self.process(&frame, (target_w, target_h), wait.started)
+        };
+        self.capturer = capturer;
+        result
+    }
+
+    /// Deals with a poll of the capturer that gave no usable frame, because
+    /// of `failure` or, when that is `None`, because the frame was too short
+    /// for the display: waits before the next poll, reopens the capturer
+    /// (once), or fails. On failure `capturer` is back in the session unless
+    /// the display was given up on.
+    fn retry(
+        &mut self,
+        failure: Option<std::io::Error>,
+        wait: &mut Wait,
+        capturer: &mut Option<Box<dyn FrameSource>>,
+    ) -> Result<(), RdpStatus> {
+        match failure {
+            Some(e) if e.kind() == WouldBlock => {
+                self.stats.would_block();
+                let waited = wait.next(self.config.wait_strategy);
+                if waited.is_err() {
+                    self.capturer = capturer.take();
+                }
+                waited
+            }
+            Some(e) if wait.reopened && is_display_lost(&e) => {
+                self.stats.capture_error();
+                log::log(LogLevel::Warn, &format!("Capture error: {e}"));
+                *capturer = None;
+                Err(self.display_lost(RdpStatus::DisplayUnavailable))
+            }
+            Some(e) if wait.reopened => {
+                self.stats.capture_error();
+                self.capturer = capturer.take();
+                Err(fail(
+                    RdpStatus::CaptureFailed,
+                    format!("Capture error: {e}"),
+                ))
+            }
+            // Typically the display changing or the capturer losing access
+            // to it; a fresh capturer gets one more try
+            failure => {
+                if let Some(e) = failure {
+                    self.stats.capture_error();
+                    log::log(
+                        LogLevel::Warn,
+                        &format!("Capture error, reopening the capturer: {e}"),
+                    );
+                }
+                *capturer = None;
+                self.ensure_capturer()?;
+                *capturer = self.capturer.take();
+                wait.reopened = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Turns a captured frame into output, stage by stage.
+    fn process(
+        &mut self,
+        frame: &[u8],
+        target: (u32, u32),
+        started: Instant,
+    ) -> Result<EncodedFrame, RdpStatus> {
+        // Stamped as soon as the OS hands the frame over, so encode time
+        // never shows up in the timestamps
+        let stamp = Stamp {
+            timestamp_us: self.opened.elapsed().as_micros() as u64,
+            sequence: self.next_sequence,
+        };
+        self.next_sequence += 1;
+        self.stats.record(Stage::CaptureWait, started.elapsed());
+
+        let copy_started = Instant::now();
+        let source = self.locate_frame(frame)?;
+        let prepared = self.transform(frame, &source)?;
+        self.stats.record(Stage::Copy, copy_started.elapsed());
+
+        let hash = self.detect_change(frame, &source, &prepared, target)?;
+        let dirty = self.dirty_rect(&prepared);
+        let output = self.output_size(&prepared, target);
+        let rendered = self.render(frame, &source, &prepared, &output, stamp)?;
+
+        // 4. Convert BGRA (as Scrap gives it) to what the output wants
+        let pixel_format = encode::input_format(&self.config);
+        let convert_started = Instant::now();
+        let held = self
+            .scratch
+            .convert(rendered.held, pixel_format, self.config.grayscale);
+        self.stats.record(
+            Stage::Convert,
+            rendered.convert_time + convert_started.elapsed(),
+        );
+        let content_hash = pixels::frame_hash(
+            self.scratch.held(held),
+            &[output.width, output.height, pixel_format as u32],
+        );
+
+        let encode_started = Instant::now();
+        let encoded = self.encode(held, pixel_format, &prepared, &output, stamp)?;
+        self.stats.record(Stage::Encode, encode_started.elapsed());
+
+        self.publish(Publish {
+            encoded,
+            pixel_format,
+            content_hash,
+            hash,
+            dirty,
+            prepared,
+            output,
+            fused: rendered.fused,
+            stamp,
+        })
+    }
+
+    /// Where the frame's pixels are: checks it holds the whole display,
+    /// works out its row stride and the part of it to capture, and samples
+    /// the cursor with it.
+    fn locate_frame(&self, frame: &[u8]) -> Result<Source, RdpStatus> {
+        let (w, h) = self.display_size;
+        let total_len = frame.len();
+        if h == 0 || w == 0 || total_len == 0 {
+            return Err(fail(
+                RdpStatus::EmptyFrame,
+                format!("Capture got empty frame (w={w}, h={h}, len={total_len})"),
+            ));
+        }
+
+        // We EXPECT at least w * h * 4 bytes (BGRA)
+        let needed = w
+            .checked_mul(h)
+            .and_then(|px| px.checked_mul(BYTES_PER_PIXEL))
+            .unwrap_or(0);
+
+        if needed == 0 || total_len < needed {
+            return Err(fail(
+                RdpStatus::FrameTooSmall,
+                format!("Frame too small: w={w}, h={h}, needed={needed}, got={total_len}"),
+            ));
+        }
+
+        // Padding is per row (DXGI and Quartz pad each row to an aligned
+        // pitch), so slicing off the first w*h*4 bytes would shear the image
+        let stride = total_len / h;
+        if stride > w * BYTES_PER_PIXEL {
+            log::log(
+                LogLevel::Debug,
+                &format!(
+                    "Frame rows are padded: stride={stride}, row={} (w={w}, h={h})",
+                    w * BYTES_PER_PIXEL
+                ),
+            );
+        }
+
+        let rect = match self.crop_region() {
+            None => Rect {
+                x: 0,
+                y: 0,
+                w: w as u32,
+                h: h as u32,
+            },
+
+            // Crop straight out of the frame so no discarded pixels are copied
+            Some(region) => match region.clamp_to(w as u32, h as u32) {
+                Some(rect) => rect,
+                None => {
+                    return Err(fail(
+                        RdpStatus::RegionOutOfBounds,
+                        format!("Capture region {region:?} lies outside the {w}x{h} display"),
+                    ));
+                }
+            },
+        };
+
+        // Sampled together with the frame, in region coordinates
+        let cursor = self
+            .cursor_probe
+            .as_ref()
+            .and_then(CursorProbe::position)
+            .map(|(x, y)| (x - rect.x as i32, y - rect.y as i32));
+
+        Ok(Source {
+            stride,
+            rect,
+            cursor,
+        })
+    }
+
+    /// Copies the captured region into `scratch.packed` and applies what
+    /// rewrites its pixels before anything else looks at them: blackout,
+    /// the cursor and orientation. The copy is left to `render` when the
+    /// resize or the conversion may read the frame directly.
+    fn transform(&mut self, frame: &[u8], source: &Source) -> Result<Prepared, RdpStatus> {
+        let &Source { stride, rect, .. } = source;
+
+        // Resizing straight out of the frame skips the copy into `packed`,
+        // unless something has to change the region's pixels or compare
+        // them first: blackout must hit the source before the resize filter
+        // spreads it, the cursor and orientation rewrite pixels, and dirty
+        // tracking works on the copy. Settled once the
+        // target size is known (see `render`); until then the pixels are
+        // only hashed where they lie
+        let draws_cursor = self.config.include_cursor && source.cursor.is_some();
+        let may_fuse = !draws_cursor
+            && self.config.orientation == Orientation::Normal
+            && !self.config.track_dirty
+            && stride % BYTES_PER_PIXEL == 0
+            && frame.as_ptr().align_offset(BYTES_PER_PIXEL) == 0
+            && !self
+                .config
+                .blackout
+                .iter()
+                .any(|a| a.within(rect).is_some());
+        // Before any buffer grows for the frame
+        reserve(
+            &mut self.scratch,
+            &mut self.zstd,
+            self.replay.as_mut(),
+            &mut self.packed_is_last,
+            source_bytes(&self.config, rect.w, rect.h),
+            false,
+        )?;
+        let have_previous = std::mem::replace(&mut self.packed_is_last, false);
+        let scratch = &mut self.scratch;
+        if !may_fuse {
+            scratch.copy_region(frame, stride, rect, have_previous);
+        }
+        let (src_w, src_h) = (rect.w, rect.h);
+
+        // Straight after the copy, so every later stage and output only
+        // ever sees the painted pixels
+        let [_, r, g, b] = self.config.blackout_color.to_be_bytes();
+        for area in self.config.blackout.iter().filter_map(|a| a.within(rect)) {
+            pixels::fill_rect(&mut scratch.packed, src_w, area, [b, g, r, 0xff]);
+        }
+
+        // Drawn before hashing so a moving cursor counts as a change, and
+        // before resizing so it scales with the frame
+        if self.config.include_cursor
+            && let Some((x, y)) = source.cursor
+        {
+            let generation = cursor::generation();
+            if generation != self.cursor_generation {
+                self.cursor_generation = generation;
+                if let Ok(shape) = cursor::current_shape() {
+                    self.cursor_image = shape;
+                }
+            }
+            cursor::overlay(&mut scratch.packed, src_w, src_h, x, y, &self.cursor_image);
+        }
+        let cursor = source
+            .cursor
+            .filter(|&(x, y)| x >= 0 && y >= 0 && (x as u32) < src_w && (y as u32) < src_h);
+
+        // After the cursor, which is positioned in display orientation, and
+        // before anything that depends on the frame's shape
+        let orientation = self.config.orientation;
+        let (width, height, cursor) = if orientation == Orientation::Normal {
+            (src_w, src_h, cursor)
+        } else {
+            orient::apply(
+                &scratch.packed,
+                src_w,
+                src_h,
+                orientation,
+                &mut scratch.oriented,
+            );
+            std::mem::swap(&mut scratch.packed, &mut scratch.oriented);
+            let (w, h) = orientation.size(src_w, src_h);
+            (
+                w,
+                h,
+                cursor.map(|at| orientation.map_point(at, src_w, src_h)),
+            )
+        };
+
+        Ok(Prepared {
+            width,
+            height,
+            cursor,
+            may_fuse,
+            have_previous,
+        })
+    }
+
+    /// Bails out before the expensive stages if nothing moved, with
+    /// `RdpStatus::NoChange`. Returns the frame's hash when change detection
+    /// is on. The target size is mixed in so asking for a different size
+    /// still gets a frame, and the cursor so its reported position never
+    /// goes stale.
+    fn detect_change(
+        &mut self,
+        frame: &[u8],
+        source: &Source,
+        prepared: &Prepared,
+        (target_w, target_h): (u32, u32),
+    ) -> Result<Option<u64>, RdpStatus> {
+        if !self.config.detect_changes {
+            return Ok(None);
+        }
+        let (cursor_x, cursor_y) = prepared
+            .cursor
+            .map_or((u32::MAX, u32::MAX), |(x, y)| (x as u32, y as u32));
+        let mut hasher = pixels::FrameHasher::new(&[
+            prepared.width,
+            prepared.height,
+            target_w,
+            target_h,
+            cursor_x,
+            cursor_y,
+        ]);
+        if prepared.may_fuse {
+            for row in pixels::rows(frame, source.stride, BYTES_PER_PIXEL, source.rect) {
+                hasher.write(row);
+            }
+        } else {
+            hasher.write(&self.scratch.packed);
+        }
+        let hash = hasher.finish();
+        if self.last_hash == Some(hash) {
+            // Identical to the last output, so still a valid reference if it
+            // was copied, or left alone
+            self.packed_is_last = !prepared.may_fuse || prepared.have_previous;
+            self.stats.frame_skipped();
+            return Err(fail_at(
+                LogLevel::Debug,
+                RdpStatus::NoChange,
+                "Frame unchanged since the last capture",
+            ));
+        }
+        Ok(Some(hash))
+    }
+
+    /// What changed since the last output frame, in the prepared frame's
+    /// pixels: all of it unless dirty tracking is on and there is a last
+    /// frame to compare with.
+    fn dirty_rect(&self, prepared: &Prepared) -> Rect {
+        let scratch = &self.scratch;
+        if self.config.track_dirty
+            && prepared.have_previous
+            && scratch.previous.len() == scratch.packed.len()
+        {
+            pixels::dirty_rect(&scratch.previous, &scratch.packed, prepared.width).unwrap_or(Rect {
+                x: 0,
+                y: 0,
+                w: 0,
+                h: 0,
+            })
+        } else {
+            prepared.full()
+        }
+    }
+
+    /// The output size. An explicit target, or else the configured
+    /// output size, takes precedence over the scale factor and `max_dim`;
+    /// then whatever the byte budget could not absorb through quality
+    /// shrinks it further.
+    fn output_size(&mut self, prepared: &Prepared, (target_w, target_h): (u32, u32)) -> Output {
+        let (src_w, src_h) = (prepared.width, prepared.height);
+        let (target_w, target_h) = if target_w > 0 && target_h > 0 {
+            (target_w, target_h)
+        } else if self.config.output_size.0 > 0 {
+            self.config.output_size
+        } else {
+            // Going down to logical points is folded into the same resize
+            let factor = self.config.scale / self.logical_scale();
+            let (w, h) = scale::scaled_size(src_w, src_h, factor);
+            scale::cap_long_edge(w, h, self.config.max_dim)
+        };
+        let (target_w, target_h) = if self.config.downscale_only {
+            scale::clamp_to_source(target_w, target_h, src_w, src_h)
+        } else {
+            (target_w, target_h)
+        };
+        let fps = match (self.config.target_fps, self.fps_meter.fps()) {
+            (0, measured) if measured > 0.0 => measured,
+            (0, _) => ASSUMED_FPS,
+            (target, _) => f64::from(target),
+        };
+        let budget = self.config.budget(&mut self.bucket, fps);
+        let (target_w, target_h) = match budget {
+            Some(_) if self.rate.scale() < 1.0 => {
+                scale::scaled_size(target_w, target_h, self.rate.scale())
+            }
+            _ => (target_w, target_h),
+        };
+        let resize = target_w > 0 && target_h > 0 && (target_w, target_h) != (src_w, src_h);
+        let (width, height) = if resize {
+            (target_w, target_h)
+        } else {
+            (src_w, src_h)
+        };
+        Output {
+            width,
+            height,
+            resize,
+            budget,
+        }
+    }
+
+    /// Brings the prepared pixels to the output size, reading the frame
+    /// directly when nothing had to copy it, and draws the overlays on the
+    /// result. Grayscale drops to one channel first. Output at the region's
+    /// size with nothing drawn on it is converted straight out of the frame.
+    fn render(
+        &mut self,
+        frame: &[u8],
+        source: &Source,
+        prepared: &Prepared,
+        output: &Output,
+        stamp: Stamp,
+    ) -> Result<Rendered, RdpStatus> {
+        let &Source { stride, rect, .. } = source;
+        let (src_w, src_h) = (prepared.width, prepared.height);
+        let (out_w, out_h) = (output.width, output.height);
+        let grayscale = self.config.grayscale;
+        let pixel_format = encode::input_format(&self.config);
+        let overlays = self.config.watermark.is_some() || self.config.overlay_timestamp;
+        // The filter reads real pixels a little way past the region's
+        // edges, where a copy would repeat the edge instead, so blackout
+        // just outside the region must not be within its reach either
+        let fused = prepared.may_fuse && !grayscale && output.resize && {
+            let reach = scale::filter_reach((src_w, src_h), (out_w, out_h));
+            let read = rect.grow(reach);
+            self.config
+                .blackout
+                .iter()
+                .all(|a| a.within(read).is_none())
+        };
+        reserve(
+            &mut self.scratch,
+            &mut self.zstd,
+            self.replay.as_mut(),
+            &mut self.packed_is_last,
+            source_bytes(&self.config, src_w, src_h)
+                + output_bytes(&self.config, out_w, out_h, self.cipher.is_on()),
+            true,
+        )?;
+        // Luma is always converted before anything else touches it; other
+        // layouts only when the conversion is all there is left to do, as
+        // overlays draw on BGRA and the replay buffer keeps it
+        let converts_frame = prepared.may_fuse
+            && !fused
+            && (grayscale
+                || (!output.resize
+                    && pixel_format != PixelFormat::Bgra
+                    && !overlays
+                    && self.replay.is_none()));
+        let scratch = &mut self.scratch;
+        if prepared.may_fuse && !fused && !converts_frame {
+            scratch.copy_region(frame, stride, rect, prepared.have_previous);
+        }
+
+        // Grayscale drops to one channel before resizing, so the resize
+        // touches a quarter of the bytes
+        let convert_started = Instant::now();
+        let (src, pixel_type) = if grayscale {
+            if converts_frame {
+                pixels::convert_rect(frame, stride, rect, PixelFormat::Gray, &mut scratch.luma);
+            } else {
+                pixels::bgra_to_luma(&scratch.packed, &mut scratch.luma);
+            }
+            (Held::Luma, fr::PixelType::U8)
+        } else if converts_frame {
+            pixels::convert_rect(frame, stride, rect, pixel_format, &mut scratch.converted);
+            (Held::Converted, fr::PixelType::U8x4)
+        } else {
+            (Held::Packed, fr::PixelType::U8x4)
+        };
+        let convert_time = convert_started.elapsed();
+
+        let resize_started = Instant::now();
+        let held = if fused {
+            // The whole frame, padding included, cropped to the region
+            let h = self.display_size.1;
+            let mut src_view = match fr::ImageView::<fr::pixels::U8x4>::from_buffer(
+                non_zero((stride / BYTES_PER_PIXEL) as u32, "frame width")?,
+                non_zero(h as u32, "frame height")?,
+                &frame[..stride * h],
+            ) {
+                Ok(view) => view,
+                Err(e) => {
+                    return Err(fail(
+                        RdpStatus::BufferFailed,
+                        format!("Failed to view the frame for resize: {e}"),
+                    ));
+                }
+            };
+            if let Err(e) = src_view.set_crop_box(fr::CropBox {
+                left: rect.x,
+                top: rect.y,
+                width: non_zero(rect.w, "source width")?,
+                height: non_zero(rect.h, "source height")?,
+            }) {
+                return Err(fail(
+                    RdpStatus::BufferFailed,
+                    format!("Failed to crop the frame for resize: {e}"),
+                ));
+            }
+            resize(
+                &mut self.resizer,
+                &self.config,
+                src_view.into(),
+                (out_w, out_h),
+                &mut scratch.resized,
+                &mut scratch.padded,
+            )?
+        } else if output.resize {
+            let src_pixels = match src {
+                Held::Luma => &mut scratch.luma,
+                _ => &mut scratch.packed,
+            };
+            // Wrap in fast_image_resize Image
+            let src_image = match fr::Image::from_slice_u8(
+                non_zero(src_w, "source width")?,
+                non_zero(src_h, "source height")?,
+                src_pixels,
+                pixel_type,
+            ) {
+                Ok(img) => img,
+                Err(e) => {
+                    return Err(fail(
+                        RdpStatus::BufferFailed,
+                        format!("Failed to create src_image for resize: {e}"),
+                    ));
+                }
+            };
+            resize(
+                &mut self.resizer,
+                &self.config,
+                src_image.view(),
+                (out_w, out_h),
+                &mut scratch.resized,
+                &mut scratch.padded,
+            )?
+        } else if overlays && !grayscale {
+            // `packed` becomes the next frame's dirty-tracking reference,
+            // so it has to stay free of overlays
+            scratch.resized.clear();
+            scratch.resized.extend_from_slice(&scratch.packed);
+            Held::Resized
+        } else {
+            src
+        };
+        self.stats.record(Stage::Resize, resize_started.elapsed());
+
+        // At output resolution, so it is never shrunk into illegibility
+        let bpp = if grayscale { 1 } else { 4 };
+        let pixels = self.scratch.held_mut(held);
+        if let Some(watermark) = &self.config.watermark {
+            overlay::draw(pixels, out_w, out_h, bpp, watermark);
+        }
+        if self.config.overlay_timestamp {
+            overlay::draw_counters(
+                pixels,
+                out_w,
+                out_h,
+                bpp,
+                stamp.timestamp_us / 1000,
+                stamp.sequence,
+            );
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.offer(
+                self.scratch.held(held),
+                out_w,
+                out_h,
+                grayscale,
+                stamp.timestamp_us,
+            );
+        }
+        Ok(Rendered {
+            held,
+            fused: fused || converts_frame,
+            convert_time,
+        })
+    }
+
+    /// Compresses the pixels `held` names, in `pixel_format`, into the
+    /// session's format (raw frames get a fresh copy, so the caller never
+    /// owns a scratch buffer), and feeds the size to the rate controller.
+    fn encode(
+        &mut self,
+        held: Held,
+        pixel_format: PixelFormat,
+        prepared: &Prepared,
+        output: &Output,
+        stamp: Stamp,
+    ) -> Result<Encoded, RdpStatus> {
+        let (final_w, final_h) = (output.width, output.height);
+        let (pixels, buffers) = self.scratch.encoder_input(held);
+        let format = self.config.format;
+        let adapted;
+        let config = match &output.budget {
+            Some(budget) => {
+                adapted = SessionConfig {
+                    quality: self.rate.quality(budget),
+                    ..self.config.clone()
+                };
+                &adapted
+            }
+            None => &self.config,
+        };
+        // Only JPEG has a quality to raise around the cursor
+        let focus = config.focus.filter(|_| format == FrameFormat::Jpeg);
+        let focus_config;
+        let focus_at = match focus {
+            Some(focus) => {
+                focus_config = SessionConfig {
+                    quality: focus.quality,
+                    subsampling: focus.subsampling,
+                    ..config.clone()
+                };
+                let at = if output.resize {
+                    prepared.cursor.and_then(|at| {
+                        scale::map_point(
+                            at,
+                            (prepared.width, prepared.height),
+                            (final_w, final_h),
+                            config.fit,
+                        )
+                    })
+                } else {
+                    prepared.cursor
+                };
+                Some((
+                    self.focus.place(&focus, at, (final_w, final_h)),
+                    &focus_config,
+                ))
+            }
+            None => None,
+        };
+        let bpp = pixel_format.bytes_per_pixel();
+        let (data, format, keyframe) = if video::is_video(format) {
+            yuv::to_i420(
+                pixels,
+                final_w,
+                final_h,
+                pixel_format,
+                YuvMatrix::Bt601,
+                buffers.yuv,
+            );
+            let (width, height) = yuv::even_size(final_w, final_h);
+            let params = video::Params {
+                format,
+                width,
+                height,
+                // The configured rate, not the measured one, which would
+                // reopen the encoder every frame
+                fps: match config.target_fps {
+                    0 => ASSUMED_FPS as f32,
+                    fps => fps as f32,
+                },
+                bitrate_kbps: match config.bitrate_kbps {
+                    0 => video::DEFAULT_BITRATE_KBPS,
+                    kbps => kbps,
+                },
+                keyframe_interval: config.keyframe_interval,
+            };
+            let encoder = video::encoder_for(&mut self.video, params)?;
+            let Some(packet) = encoder.encode(buffers.yuv, stamp.timestamp_us / 1000)? else {
+                return Err(fail_at(
+                    LogLevel::Debug,
+                    RdpStatus::NoChange,
+                    "Video encoder dropped the frame to hold the bitrate",
+                ));
+            };
+            (packet.data, format, packet.keyframe)
+        } else if config.tile_size > 0 && !yuv::is_yuv(format) {
+            let (mut data, format) = tiles::encode(
+                &mut self.tiles,
+                pixels,
+                (final_w, final_h),
+                bpp,
+                config,
+                buffers.tile,
+            )?;
+            let keyframe = format != FrameFormat::TiledDelta;
+            // Otherwise the client still shows the last one
+            if let Some(((rect, moved), focus_config)) = focus_at
+                && (keyframe || moved || tiles::overlaps(&data, rect))
+            {
+                tiles::push_over(
+                    &mut data,
+                    pixels,
+                    (final_w, bpp),
+                    rect,
+                    focus_config,
+                    buffers.tile,
+                )?;
+            }
+            (data, format, keyframe)
+        } else if format == FrameFormat::RawZstd {
+            let (data, keyframe) = zstd::encode(
+                &mut self.zstd,
+                pixels,
+                (final_w, final_h, pixel_format),
+                config,
+                buffers.xored,
+            )?;
+            (data, format, keyframe)
+        } else if tiles::uses_bands(config) || focus_at.is_some() {
+            let mut data = tiles::encode_bands(pixels, (final_w, final_h), bpp, config)?;
+            if let Some(((rect, _), focus_config)) = focus_at {
+                tiles::push_over(
+                    &mut data,
+                    pixels,
+                    (final_w, bpp),
+                    rect,
+                    focus_config,
+                    buffers.tile,
+                )?;
+            }
+            (data, FrameFormat::TiledKeyframe, true)
+        } else {
+            (
+                encode::encode(pixels, final_w, final_h, config)?,
+                format,
+                true,
+            )
+        };
+        let quality = if matches!(config.format, FrameFormat::Jpeg | FrameFormat::WebP) {
+            config.quality
+        } else {
+            0
+        };
+        // Tiles and the focus rectangle included
+        let progressive = config.progressive && config.format == FrameFormat::Jpeg;
+        let uncompressed_len = if format == FrameFormat::RawZstd {
+            pixels.len() as u64
+        } else {
+            0
+        };
+        if let Some(budget) = &output.budget {
+            self.rate.update(data.len(), quality, budget);
+            self.bucket.spend(data.len());
+        }
+        Ok(Encoded {
+            data,
+            format,
+            keyframe,
+            quality,
+            progressive,
+            uncompressed_len,
+        })
+    }
+
+    /// Fills in the frame's metadata, remembers it as the last output,
+    /// records it, encrypts it and hands it to shared memory.
+    fn publish(&mut self, frame: Publish) -> Result<EncodedFrame, RdpStatus> {
+        let Publish {
+            encoded,
+            pixel_format,
+            content_hash,
+            hash,
+            dirty,
+            prepared,
+            output,
+            fused,
+            stamp,
+        } = frame;
+        let format = encoded.format;
+        let (final_w, final_h) = if video::is_video(format) || yuv::is_yuv(format) {
+            yuv::even_size(output.width, output.height)
+        } else {
+            (output.width, output.height)
+        };
+        let stride = match format {
+            FrameFormat::Raw | FrameFormat::RawZstd => final_w * pixel_format.bytes_per_pixel(),
+            FrameFormat::I420 | FrameFormat::Nv12 => final_w,
+            _ => 0,
+        };
+        let src = (prepared.width, prepared.height);
+        let dirty = if !output.resize || dirty.w == 0 {
+            dirty
+        } else if dirty == prepared.full() {
+            Rect {
+                x: 0,
+                y: 0,
+                w: final_w,
+                h: final_h,
+            }
+        } else {
+            scale::map_rect(dirty, src, (final_w, final_h), self.config.fit)
+        };
+        let cursor = if output.resize {
+            prepared
+                .cursor
+                .and_then(|at| scale::map_point(at, src, (final_w, final_h), self.config.fit))
+        } else {
+            prepared.cursor
+        };
+
+        // Down from the estimate to what is kept until the next frame
+        let retained = self.scratch.retained() + self.zstd.retained() as u64;
+        self.scratch.memory.set(retained);
+        // Only remembered once output exists, so a failed encode is retried
+        self.last_hash = hash;
+        self.packed_is_last = !fused;
+        self.fps_meter.record(Instant::now());
+        self.stats.set_fps(self.fps_meter.fps());
+        if format == FrameFormat::Jpeg
+            && let Some(recorder) = &self.recorder
+            && !recorder.record(&encoded.data, final_w, final_h, stamp.timestamp_us)
+        {
+            // The writer failed and has said why
+            self.recorder = None;
+        }
+        // Last, so everything before works on the plain payload
+        let encrypted = self.cipher.is_on();
+        let data = if encrypted {
+            self.cipher.seal(&encoded.data)?
+        } else {
+            encoded.data
+        };
+        self.stats.frame_emitted(data.len());
+        let frame = EncodedFrame {
+            data,
+            width: final_w,
+            height: final_h,
+            format,
+            pixel_format,
+            stride,
+            dirty,
+            content_hash,
+            cursor,
+            hotspot: (0, 0),
+            sequence: stamp.sequence,
+            timestamp_us: stamp.timestamp_us,
+            quality: encoded.quality,
+            keyframe: encoded.keyframe,
+            uncompressed_len: encoded.uncompressed_len,
+            encrypted,
+            progressive: encoded.progressive,
+        };
+        if let Some(shm) = &mut self.shm {
+            shm.publish(&frame);
+        }
+        Ok(frame)
+    }
+}
+
+/// Most the working buffers can need for a `w` x `h` region before its
+/// output size is known: the copy, its diff reference, the copy before
+/// orientation and the luma plane.
+fn source_bytes(config: &SessionConfig, w: u32, h: u32) -> u64 {
+    let pixels = u64::from(w) * u64::from(h);
+    let copies =
+        1 + u64::from(config.track_dirty) + u64::from(config.orientation != Orientation::Normal);
+    let luma = if config.grayscale { pixels } else { 0 };
+    pixels * 4 * copies + luma
+}
+
+/// Most they can need on top for a `w` x `h` output: the resize output and
+/// letterbox canvas, the converted pixels, the video encoder's input, the
+/// zstd reference and XOR, and the frame handed to the caller (a second
+/// time when `encrypted`), at most a raw copy.
+fn output_bytes(config: &SessionConfig, w: u32, h: u32, encrypted: bool) -> u64 {
+    let pixels = u64::from(w) * u64::from(h);
+    let mut copies = 3;
+    if config.fit == FitMode::Fit {
+        copies += 1;
+    }
+    if config.format == FrameFormat::RawZstd {
+        copies += 2;
+    }
+    if encrypted {
+        copies += 1;
+    }
+    let yuv = if video::is_video(config.format) {
+        pixels * 3 / 2
+    } else {
+        0
+    };
+    pixels * 4 * copies + yuv
+}
+
+/// Grows the charge of `scratch` and `zstd`'s reference to `bytes`, or to
+/// what they hold if that is more. Short of room, `replay`'s oldest stills
+/// make it, then the buffers left over from larger frames or other settings
+/// (all but the frame copied so far when `keep_source`, otherwise clearing
+/// `packed_is_last`; the next zstd frame becomes a keyframe). Fails with
+/// `RdpStatus::MemoryLimit` when that is still not enough.
+fn reserve(
+    scratch: &mut Scratch,
+    zstd: &mut DeltaState,
+    replay: Option<&mut ReplayBuffer>,
+    packed_is_last: &mut bool,
+    bytes: u64,
+    keep_source: bool,
+) -> Result<(), RdpStatus> {
+    let retained =
+        |scratch: &Scratch, zstd: &DeltaState| scratch.retained() + zstd.retained() as u64;
+    let wanted = bytes.max(retained(scratch, zstd));
+    if scratch.memory.set(wanted) {
+        return Ok(());
+    }
+    let budget = Arc::clone(scratch.memory.budget());
+    if let Some(replay) = replay {
+        let short = (wanted - scratch.memory.bytes()).saturating_sub(budget.available());
+        if replay.shed(short) && scratch.memory.set(wanted) {
+            return Ok(());
+        }
+    }
+    scratch.release(keep_source);
+    *zstd = DeltaState::default();
+    if !keep_source {
+        *packed_is_last = false;
+    }
+    if scratch.memory.set(bytes.max(retained(scratch, zstd))) {
+        return Ok(());
+    }
+    Err(fail_at(
+        LogLevel::Warn,
+        RdpStatus::MemoryLimit,
+        format!(
+            "A frame needs up to {bytes} bytes of buffers; the session's memory limit of {} \
+             bytes leaves {} for them",
+            budget.limit(),
+            scratch.memory.bytes().saturating_add(budget.available()),
+        ),
+    ))
+}
+
+/// Resizes the crop box of `src` onto `target` according to the fit mode,
+/// using `resized` and (when letterboxing) `padded` as output storage.
+/// Returns the packed `target`-sized pixels.
+fn resize(
+    resizer: &mut fr::Resizer,
+    config: &SessionConfig,
+    mut src: fr::DynamicImageView<'_>,
+    (target_w, target_h): (u32, u32),
+    resized: &mut Vec<u8>,
+    padded: &mut Vec<u8>,
+) -> Result<Held, RdpStatus> {
+    let fit = config.fit;
+    let crop = src.crop_box();
+    let area = Rect {
+        x: crop.left,
+        y: crop.top,
+        w: crop.width.get(),
+        h: crop.height.get(),
+    };
+    let (out_w, out_h) = match fit {
+        FitMode::Stretch | FitMode::Fill => (target_w, target_h),
+        FitMode::Fit => scale::fit_within(area.w, area.h, target_w, target_h),
+    };
+
+    // Only grows the buffer when the output size goes up; the contents are
+    // overwritten by the resize
+    let pixel_type = src.pixel_type();
+    let bpp = if pixel_type == fr::PixelType::U8 {
+        1
+    } else {
+        4
+    };
+    resized.resize(out_w as usize * out_h as usize * bpp, 0);
+    let mut dst_image = match fr::Image::from_slice_u8(
+        non_zero(out_w, "target width")?,
+        non_zero(out_h, "target height")?,
+        resized,
+        pixel_type,
+    ) {
+        Ok(img) => img,
+        Err(e) => {
+            return Err(fail(
+                RdpStatus::BufferFailed,
+                format!("Failed to create dst_image for resize: {e}"),
+            ));
+        }
+    };
+
+    if fit == FitMode::Fill {
+        let fill = scale::fill_crop(area, (target_w, target_h));
+        if let Err(e) = src.set_crop_box(fr::CropBox {
+            left: fill.x,
+            top: fill.y,
+            width: non_zero(fill.w, "fill width")?,
+            height: non_zero(fill.h, "fill height")?,
+        }) {
+            return Err(fail(
+                RdpStatus::ResizeFailed,
+                format!("Fill crop error: {e}"),
+            ));
+        }
+    }
+
+    if let Err(e) = resizer.resize(&src, &mut dst_image.view_mut()) {
+        return Err(fail(RdpStatus::ResizeFailed, format!("Resize error: {e}")));
+    }
+
+    if (out_w, out_h) == (target_w, target_h) {
+        return Ok(Held::Resized);
+    }
+
+    let [_, r, g, b] = config.fill_color.to_be_bytes();
+    let mut fill = vec![b, g, r, 0xff];
+    if pixel_type == fr::PixelType::U8 {
+        let bgra = std::mem::take(&mut fill);
+        pixels::bgra_to_luma(&bgra, &mut fill);
+    }
+    pixels::letterbox(resized, out_w, out_h, target_w, target_h, &fill, padded);
+    Ok(Held::Padded)
+}
+
+/// Looks up `display_index` in `Display::all()`, or the primary display
+/// when the index is -1.
+fn find_display(display_index: i32) -> Result<Display, RdpStatus> {
+    let display = if display_index == -1 {
+        match Display::primary() {
+            Ok(d) => d,
+            Err(e) => {
+                return Err(fail(
+                    RdpStatus::NoDisplay,
+                    format!("Failed to get primary display: {e}"),
+                ));
+            }
+        }
+    } else {
+        let displays = match Display::all() {
+            Ok(all) => all,
+            Err(e) => {
+                return Err(fail(
+                    RdpStatus::NoDisplay,
+                    format!("Failed to enumerate displays: {e}"),
+                ));
+            }
+        };
+        match displays.into_iter().nth(display_index as usize) {
+            Some(d) => d,
+            None => {
+                return Err(fail(
+                    RdpStatus::NoDisplay,
+                    format!("No display at index {display_index} (display list changed?)"),
+                ));
+            }
+        }
+    };
+
+    Ok(display)
+}
+
+/// Whether a capture error means the display is out of reach for now
+/// (Windows reports the lock screen, the secure desktop and a disconnected
+/// session this way) rather than that capturing is broken.
+fn is_display_lost(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        ConnectionReset | ConnectionAborted | PermissionDenied | Interrupted
+    )
+}
+
+/// Index and `DisplayInfo::id` of the display showing the desktop point
+/// (`x`, `y`), in `WindowInfo` coordinates.
+fn display_at(x: i32, y: i32) -> Result<Option<(i32, u64)>, RdpStatus> {
+    let all = display::enumerate().map_err(enumerate_failed)?;
+    Ok(all.iter().enumerate().find_map(|(i, d)| {
+        let (px, py) = CursorProbe::new(i as i32)?.to_display(f64::from(x), f64::from(y));
+        (px >= 0 && py >= 0 && (px as u32) < d.width && (py as u32) < d.height)
+            .then_some((i as i32, d.id))
+    }))
+}
+
+/// `bounds` in the pixels of `probe`'s display, cut off at its top and
+/// left edges (`Rect::clamp_to` cuts off the rest at capture time); `None`
+/// if none of it is right of and below the display's origin.
+fn window_rect(probe: &CursorProbe, bounds: Bounds) -> Option<Rect> {
+    let (left, top) = probe.to_display(f64::from(bounds.x), f64::from(bounds.y));
+    let (right, bottom) = probe.to_display(
+        f64::from(bounds.x) + f64::from(bounds.width),
+        f64::from(bounds.y) + f64::from(bounds.height),
+    );
+    let (x, y) = (left.max(0), top.max(0));
+    (right > x && bottom > y).then(|| Rect {
+        x: x as u32,
+        y: y as u32,
+        w: (right - x) as u32,
+        h: (bottom - y) as u32,
+    })
+}
+
+fn enumerate_failed(e: std::io::Error) -> RdpStatus {
+    fail(
+        RdpStatus::NoDisplay,
+        format!("Failed to enumerate displays: {e}"),
+    )
+}
+
+/// The cursor probe for `display_index`, except on Wayland, where the
+/// portal draws the cursor into the stream and X11 cannot see where it is,
+/// and for the test pattern, which has no cursor.
+fn probe_cursor(backend: Backend, display_index: i32) -> Option<CursorProbe> {
+    match backend {
+        Backend::Wayland | Backend::Test => None,
+        _ => CursorProbe::new(display_index),
+    }
+}
+
+/// `fast_image_resize` needs non-zero sizes; fail cleanly instead of
+/// unwrapping.
+fn non_zero(value: u32, what: &str) -> Result<NonZeroU32, RdpStatus> {
+    NonZeroU32::new(value)
+        .ok_or_else(|| fail(RdpStatus::BufferFailed, format!("Zero {what} in pipeline")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session on the test pattern, which needs no display.
+    fn pattern_session() -> RdpSession {
+        RdpSession::with_backend(Backend::Test, 0).expect("test pattern session")
+    }
+
+    #[test]
+    fn display_indices_below_span_all_are_rejected() {
+        for index in [SPAN_ALL - 1, i32::MIN] {
+            let result = RdpSession::with_backend(Backend::Test, index);
+            assert!(
+                matches!(result, Err(RdpStatus::InvalidArgument)),
+                "index {index}"
+            );
+        }
+    }
+
+    #[test]
+    fn zero_sizes_fail_instead_of_panicking() {
+        assert!(matches!(non_zero(0, "width"), Err(RdpStatus::BufferFailed)));
+        assert_eq!(non_zero(3, "width").map(NonZeroU32::get), Ok(3));
+    }
+
+    #[test]
+    fn quality_is_clamped_to_what_the_encoder_takes() {
+        let mut session = pattern_session();
+        for (asked, kept) in [(0, 1), (1, 1), (70, 70), (100, 100), (101, 100), (255, 100)] {
+            session.set_quality(asked);
+            assert_eq!(session.config().quality, kept, "quality {asked}");
+            let frame = session.capture(160, 90).expect("capture");
+            assert_eq!(frame.quality, kept);
+            assert_eq!(frame.data[..2], [0xff, 0xd8], "quality {asked} is a JPEG");
+        }
+    }
+
+    #[test]
+    fn quarter_turns_apply_the_target_to_the_turned_frame() {
+        let mut session = pattern_session();
+        session.set_format(FrameFormat::Raw);
+        session.set_orientation(Orientation::Rotate90);
+        let frame = session.capture(0, 0).expect("capture");
+        assert_eq!((frame.width, frame.height), (720, 1280));
+
+        let (width, height) = (90, 160);
+        let frame = session.capture(width, height).expect("capture");
+        assert_eq!((frame.width, frame.height), (width, height));
+        // The bars, left to right on the display, run top to bottom on the
+        // right two thirds of the turned frame
+        let at = |x: u32, y: u32| (y * width + x) as usize * 4;
+        let magenta = at(width * 3 / 4, height * 9 / 14);
+        assert_eq!(frame.data[magenta..magenta + 4], [191, 0, 191, 0xff]);
+        let white = at(width * 3 / 4, height / 14);
+        assert_eq!(frame.data[white..white + 4], [191, 191, 191, 0xff]);
+    }
+
+    #[test]
+    fn set_config_switches_the_resizer() {
+        let mut session = pattern_session();
+        let lanczos = fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3);
+        let config = SessionConfig {
+            resize_alg: lanczos,
+            ..session.config().clone()
+        };
+        session.set_config(config).unwrap();
+        assert!(matches!(
+            session.resizer.algorithm,
+            fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3)
+        ));
+    }
+
+    #[test]
+    fn conversion_straight_from_the_frame_matches_converting_a_copy() {
+        let mut session = pattern_session();
+        session.set_format(FrameFormat::Raw);
+        // Not full width, so the rows are converted one by one, and in the
+        // bars, which stay put from frame to frame
+        let region = Rect {
+            x: 3,
+            y: 100,
+            w: 1001,
+            h: 50,
+        };
+        session.set_region(Some(region)).unwrap();
+        let bgra = session.capture(0, 0).expect("capture").data;
+
+        for format in [PixelFormat::Rgb, PixelFormat::Bgr, PixelFormat::Rgba] {
+            session.set_pixel_format(format);
+            let frame = session.capture(0, 0).expect("capture");
+            let mut copied = Vec::new();
+            pixels::convert_bgra(&bgra, format, &mut copied);
+            assert!(frame.data == copied, "{format:?}");
+        }
+        session.set_pixel_format(PixelFormat::Bgra);
+        session.set_grayscale(true);
+        let frame = session.capture(0, 0).expect("capture");
+        let mut luma = Vec::new();
+        pixels::bgra_to_luma(&bgra, &mut luma);
+        assert!(frame.data == luma, "grayscale");
+    }
+
+    #[test]
+    fn signal_waits_fall_back_to_sleeping_without_a_signal() {
+        let mut session = pattern_session();
+        session.set_wait_strategy(WaitStrategy::Signal { ms: 2 });
+        assert_eq!(session.stats().snapshot().wait_strategy, 4);
+        let frame = session.capture(160, 90).expect("capture");
+        assert_eq!((frame.width, frame.height), (160, 90));
+    }
+}
@@ -0,0 +1,740 @@
+//! Frames delivered through named shared memory, so a viewer in another
+//! process on the same machine reads them where they were written instead
+//! of having them copied through a socket.
+//!
+//! The session creates the region (`shm_open` on Unix, a pagefile-backed
+//! `CreateFileMapping` on Windows) and lays it out as a ring of slots. All
+//! integers are in the machine's own byte order, and every offset below is
+//! a multiple of 8 so the counters can be read atomically:
+//!
+//! ```text
+//! Header, 64 bytes
+//!    0 u32 magic         "RDPS" (0x53504452)
+//!    4 u32 version       1
+//!    8 u32 slot_count
+//!   12 u32 closed        1 once the session has stopped writing
+//!   16 u64 slot_size     bytes of frame data a slot holds
+//!   24 u64 published     frames published so far
+//!   32 u64 dropped       frames left out for not fitting a slot
+//!   40                   reserved
+//! Slot headers, 128 bytes each, slot_count of them
+//!    0 u64 state         0 until first written; odd while being written,
+//!                        even once the slot holds a whole frame
+//!    8 u64 index         which published frame the slot holds, from 0
+//!   16 u64 sequence      RawImage::sequence
+//!   24 u64 timestamp_us
+//!   32 u64 len           bytes of frame data
+//!   40 u64 content_hash
+//!   48 u64 uncompressed_len
+//!   56 u32 width, u32 height, u32 format, u32 pixel_format, u32 stride
+//!   76 u32 flags         bit 0 keyframe, 1 encrypted, 2 progressive,
+//!                        3 cursor visible
+//!   80 u32 dirty_x, dirty_y, dirty_w, dirty_h
+//!   96 i32 cursor_x, i32 cursor_y
+//!  104 u32 quality
+//!  108                   reserved
+//! Slot data, slot_size bytes each, in slot order
+//! ```
+//!
+//! Frame `n` (counting from 0) goes to slot `n % slot_count`. The session
+//! bumps the slot's `state` to odd, writes the header fields and data,
+//! bumps `state` to even with a release store and then stores `n + 1` to
+//! `published`, also with release. A reader loads `published` (acquire),
+//! picks the slot of the frame it wants, loads `state` (acquire), copies
+//! the fields and data out, and loads `state` again after an acquire fence:
+//! the copy is good if both loads gave the same even value and `index` is
+//! the frame wanted; otherwise the slot was being overwritten and the
+//! reader moves on to a newer frame. `ShmReader` does exactly that.
+//!
+//! A slot is sized for the display's pixels at 4 bytes each, plus some
+//! room; a frame that does not fit (output upscaled past the display's
+//! size, say) is left out and counted in `dropped`. The region keeps its
+//! size when the display is resized; enable it again to resize it.
+
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering, fence};
+
+use crate::error::{RdpStatus, fail};
+use crate::frame::{EncodedFrame, FrameFormat, PixelFormat};
+use crate::log::{self, LogLevel};
+use crate::pixels::Rect;
+
+/// "RDPS", the first four bytes of every region.
+pub const MAGIC: u32 = u32::from_le_bytes(*b"RDPS");
+pub const VERSION: u32 = 1;
+/// Most slots a region can have.
+pub const MAX_SLOTS: u32 = 64;
+const HEADER_LEN: usize = 64;
+const SLOT_HEADER_LEN: usize = 128;
+/// Room above the display's raw size for formats that can come out larger,
+/// such as a JPEG of noise or an encrypted frame.
+const SLOT_SLACK: usize = 64 * 1024;
+
+const FLAG_KEYFRAME: u32 = 1 << 0;
+const FLAG_ENCRYPTED: u32 = 1 << 1;
+const FLAG_PROGRESSIVE: u32 = 1 << 2;
+const FLAG_CURSOR: u32 = 1 << 3;
+
+/// Slot data size for frames of a `width` x `height` display.
+pub fn slot_size_for(width: usize, height: usize) -> usize {
+    (width * height * 4 + SLOT_SLACK).next_multiple_of(64)
+}
+
+/// Bytes a region of `slot_count` slots of `slot_size` bytes maps, as
+/// charged to the session's memory budget.
+pub fn region_size(slot_count: u32, slot_size: usize) -> usize {
+    Ring::len(slot_count, slot_size.next_multiple_of(64))
+}
+
+/// Offsets within a region's header and slot headers.
+mod at {
+    pub const MAGIC: usize = 0;
+    pub const VERSION: usize = 4;
+    pub const SLOT_COUNT: usize = 8;
+    pub const CLOSED: usize = 12;
+    pub const SLOT_SIZE: usize = 16;
+    pub const PUBLISHED: usize = 24;
+    pub const DROPPED: usize = 32;
+
+    pub const STATE: usize = 0;
+    pub const INDEX: usize = 8;
+    pub const SEQUENCE: usize = 16;
+    pub const TIMESTAMP_US: usize = 24;
+    pub const LEN: usize = 32;
+    pub const CONTENT_HASH: usize = 40;
+    pub const UNCOMPRESSED_LEN: usize = 48;
+    pub const WIDTH: usize = 56;
+    pub const HEIGHT: usize = 60;
+    pub const FORMAT: usize = 64;
+    pub const PIXEL_FORMAT: usize = 68;
+    pub const STRIDE: usize = 72;
+    pub const FLAGS: usize = 76;
+    pub const DIRTY: usize = 80;
+    pub const CURSOR: usize = 96;
+    pub const QUALITY: usize = 104;
+}
+
+/// A mapped region and where its slots are.
+struct Ring {
+    region: platform::Region,
+    slot_count: u32,
+    slot_size: usize,
+}
+
+impl Ring {
+    fn len(slot_count: u32, slot_size: usize) -> usize {
+        HEADER_LEN + slot_count as usize * (SLOT_HEADER_LEN + slot_size)
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.region.ptr
+    }
+
+    /// The counter at `offset`, which must be 8-aligned within the region.
+    fn counter(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.base().add(offset) as *const AtomicU64) }
+    }
+
+    fn closed(&self) -> &AtomicU32 {
+        unsafe { &*(self.base().add(at::CLOSED) as *const AtomicU32) }
+    }
+
+    fn slot_header(&self, slot: usize) -> *mut u8 {
+        unsafe { self.base().add(HEADER_LEN + slot * SLOT_HEADER_LEN) }
+    }
+
+    fn slot_data(&self, slot: usize) -> *mut u8 {
+        let headers = HEADER_LEN + self.slot_count as usize * SLOT_HEADER_LEN;
+        unsafe { self.base().add(headers + slot * self.slot_size) }
+    }
+
+    /// Reads a plain field. Volatile, since another process may be writing
+    /// it; a torn read is caught by the slot's `state`.
+    fn get<T: Copy>(&self, field: *mut u8) -> T {
+        unsafe { ptr::read_volatile(field as *const T) }
+    }
+
+    fn put<T: Copy>(&self, field: *mut u8, value: T) {
+        unsafe { ptr::write_volatile(field as *mut T, value) }
+    }
+}
+
+/// The writing end, owned by a session; dropping it marks the region
+/// closed and removes its name.
+pub struct ShmWriter {
+    ring: Ring,
+    name: String,
+    /// Frames published so far.
+    published: u64,
+    /// Whether a frame too large for a slot has been reported.
+    warned: bool,
+}
+
+impl ShmWriter {
+    /// Creates region `name` with `slot_count` slots of `slot_size` bytes,
+    /// replacing any region of that name a previous session left behind.
+    pub fn create(name: &str, slot_count: u32, slot_size: usize) -> Result<ShmWriter, RdpStatus> {
+        if slot_count == 0 || slot_count > MAX_SLOTS {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Shared memory needs 1 to {MAX_SLOTS} slots, not {slot_count}"),
+            ));
+        }
+        let slot_size = slot_size.next_multiple_of(64);
+        let region = platform::Region::create(name, Ring::len(slot_count, slot_size))?;
+        let ring = Ring {
+            region,
+            slot_count,
+            slot_size,
+        };
+        let base = ring.base();
+        unsafe {
+            ring.put(base.add(at::MAGIC), MAGIC);
+            ring.put(base.add(at::VERSION), VERSION);
+            ring.put(base.add(at::SLOT_COUNT), slot_count);
+            ring.put(base.add(at::SLOT_SIZE), slot_size as u64);
+        }
+        // The region starts zeroed, so every slot is unwritten and nothing
+        // is published
+        fence(Ordering::Release);
+        Ok(ShmWriter {
+            ring,
+            name: name.to_string(),
+            published: 0,
+            warned: false,
+        })
+    }
+
+    /// Copies `frame` into the next slot and publishes it. A frame larger
+    /// than a slot is counted as dropped instead, with a warning the first
+    /// time.
+    pub fn publish(&mut self, frame: &EncodedFrame) {
+        let ring = &self.ring;
+        if frame.data.len() > ring.slot_size {
+            ring.counter(at::DROPPED).fetch_add(1, Ordering::Relaxed);
+            if !self.warned {
+                self.warned = true;
+                log::log(
+                    LogLevel::Warn,
+                    &format!(
+                        "A {}-byte frame does not fit the {}-byte slots of shared memory \
+                         '{}'; such frames are left out",
+                        frame.data.len(),
+                        ring.slot_size,
+                        self.name
+                    ),
+                );
+            }
+            return;
+        }
+
+        let index = self.published;
+        let slot = (index % u64::from(ring.slot_count)) as usize;
+        let header = ring.slot_header(slot);
+        let state = ring.counter(HEADER_LEN + slot * SLOT_HEADER_LEN + at::STATE);
+        let version = state.load(Ordering::Relaxed);
+        state.store(version + 1, Ordering::Relaxed);
+        // Readers that see the new contents must also see the odd state
+        fence(Ordering::Release);
+
+        let flags = [
+            (frame.keyframe, FLAG_KEYFRAME),
+            (frame.encrypted, FLAG_ENCRYPTED),
+            (frame.progressive, FLAG_PROGRESSIVE),
+            (frame.cursor.is_some(), FLAG_CURSOR),
+        ]
+        .into_iter()
+        .filter(|&(set, _)| set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        let (cursor_x, cursor_y) = frame.cursor.unwrap_or((0, 0));
+        let dirty = frame.dirty;
+        unsafe {
+            ring.put(header.add(at::INDEX), index);
+            ring.put(header.add(at::SEQUENCE), frame.sequence);
+            ring.put(header.add(at::TIMESTAMP_US), frame.timestamp_us);
+            ring.put(header.add(at::LEN), frame.data.len() as u64);
+            ring.put(header.add(at::CONTENT_HASH), frame.content_hash);
+            ring.put(header.add(at::UNCOMPRESSED_LEN), frame.uncompressed_len);
+            ring.put(header.add(at::WIDTH), frame.width);
+            ring.put(header.add(at::HEIGHT), frame.height);
+            ring.put(header.add(at::FORMAT), frame.format as u32);
+            ring.put(header.add(at::PIXEL_FORMAT), frame.pixel_format as u32);
+            ring.put(header.add(at::STRIDE), frame.stride);
+            ring.put(header.add(at::FLAGS), flags);
+            ring.put(header.add(at::DIRTY), [dirty.x, dirty.y, dirty.w, dirty.h]);
+            ring.put(header.add(at::CURSOR), [cursor_x, cursor_y]);
+            ring.put(header.add(at::QUALITY), u32::from(frame.quality));
+            ptr::copy_nonoverlapping(frame.data.as_ptr(), ring.slot_data(slot), frame.data.len());
+        }
+
+        state.store(version + 2, Ordering::Release);
+        self.published = index + 1;
+        ring.counter(at::PUBLISHED)
+            .store(self.published, Ordering::Release);
+    }
+}
+
+impl Drop for ShmWriter {
+    fn drop(&mut self) {
+        self.ring.closed().store(1, Ordering::Release);
+        // The mapping itself goes with the region, which also unlinks the
+        // name where the system keeps it after the last handle closes
+    }
+}
+
+/// Reads the frames a session publishes to a region, from any process; for
+/// consumers written in Rust and for checking the layout in-process.
+pub struct ShmReader {
+    ring: Ring,
+}
+
+impl ShmReader {
+    /// Opens region `name`, read-only. Fails with `RdpStatus::FileNotFound`
+    /// when there is no such region and `RdpStatus::InvalidArgument` when
+    /// it is not laid out as this module describes.
+    pub fn open(name: &str) -> Result<ShmReader, RdpStatus> {
+        let region = platform::Region::open(name)?;
+        let invalid = |what: &str| {
+            fail(
+                RdpStatus::InvalidArgument,
+                format!("Shared memory '{name}' {what}"),
+            )
+        };
+        if region.len < HEADER_LEN {
+            return Err(invalid("is too small for its header"));
+        }
+        let base = region.ptr;
+        let (magic, version, slot_count, slot_size): (u32, u32, u32, u64) = unsafe {
+            (
+                ptr::read_volatile(base.add(at::MAGIC) as *const u32),
+                ptr::read_volatile(base.add(at::VERSION) as *const u32),
+                ptr::read_volatile(base.add(at::SLOT_COUNT) as *const u32),
+                ptr::read_volatile(base.add(at::SLOT_SIZE) as *const u64),
+            )
+        };
+        if magic != MAGIC || version != VERSION {
+            return Err(invalid("is not a version 1 frame ring"));
+        }
+        let slot_size = usize::try_from(slot_size).unwrap_or(usize::MAX);
+        if slot_count == 0
+            || slot_count > MAX_SLOTS
+            || slot_size > region.len
+            || Ring::len(slot_count, slot_size) > region.len
+        {
+            return Err(invalid("has a header that does not match its size"));
+        }
+        Ok(ShmReader {
+            ring: Ring {
+                region,
+                slot_count,
+                slot_size,
+            },
+        })
+    }
+
+    /// Frames published so far.
+    pub fn published(&self) -> u64 {
+        self.ring.counter(at::PUBLISHED).load(Ordering::Acquire)
+    }
+
+    /// Frames left out for not fitting a slot.
+    pub fn dropped(&self) -> u64 {
+        self.ring.counter(at::DROPPED).load(Ordering::Relaxed)
+    }
+
+    /// Whether the session has stopped writing to the region; no frame
+    /// will be published after those already are.
+    pub fn is_closed(&self) -> bool {
+        self.ring.closed().load(Ordering::Acquire) != 0
+    }
+
+    /// The newest frame; `None` before the first.
+    pub fn latest(&self) -> Option<EncodedFrame> {
+        loop {
+            let index = self.published().checked_sub(1)?;
+            if let Some(frame) = self.read(index) {
+                return Some(frame);
+            }
+        }
+    }
+
+    /// Published frame `index` (from 0); `None` if it has not been
+    /// published yet or its slot has since been reused.
+    pub fn read(&self, index: u64) -> Option<EncodedFrame> {
+        let ring = &self.ring;
+        if index >= self.published() {
+            return None;
+        }
+        let slot = (index % u64::from(ring.slot_count)) as usize;
+        let header = ring.slot_header(slot);
+        let state = ring.counter(HEADER_LEN + slot * SLOT_HEADER_LEN + at::STATE);
+        let before = state.load(Ordering::Acquire);
+        if before % 2 == 1 {
+            return None;
+        }
+
+        let frame = unsafe {
+            let len = ring.get::<u64>(header.add(at::LEN)) as usize;
+            let flags: u32 = ring.get(header.add(at::FLAGS));
+            let [x, y, w, h]: [u32; 4] = ring.get(header.add(at::DIRTY));
+            let cursor: [i32; 2] = ring.get(header.add(at::CURSOR));
+            let mut data = vec![0; len.min(ring.slot_size)];
+            ptr::copy_nonoverlapping(ring.slot_data(slot), data.as_mut_ptr(), data.len());
+            (
+                ring.get::<u64>(header.add(at::INDEX)),
+                ring.get::<u32>(header.add(at::FORMAT)),
+                ring.get::<u32>(header.add(at::PIXEL_FORMAT)),
+                EncodedFrame {
+                    data,
+                    width: ring.get(header.add(at::WIDTH)),
+                    height: ring.get(header.add(at::HEIGHT)),
+                    format: FrameFormat::Raw,
+                    pixel_format: PixelFormat::Bgra,
+                    stride: ring.get(header.add(at::STRIDE)),
+                    dirty: Rect { x, y, w, h },
+                    content_hash: ring.get(header.add(at::CONTENT_HASH)),
+                    cursor: (flags & FLAG_CURSOR != 0).then_some((cursor[0], cursor[1])),
+                    hotspot: (0, 0),
+                    sequence: ring.get(header.add(at::SEQUENCE)),
+                    timestamp_us: ring.get(header.add(at::TIMESTAMP_US)),
+                    quality: ring.get::<u32>(header.add(at::QUALITY)) as u8,
+                    keyframe: flags & FLAG_KEYFRAME != 0,
+                    uncompressed_len: ring.get(header.add(at::UNCOMPRESSED_LEN)),
+                    encrypted: flags & FLAG_ENCRYPTED != 0,
+                    progressive: flags & FLAG_PROGRESSIVE != 0,
+                },
+            )
+        };
+        fence(Ordering::Acquire);
+        if state.load(Ordering::Relaxed) != before {
+            return None;
+        }
+
+        // Only trusted now the copy is known to be whole
+        let (slot_index, format, pixel_format, mut frame) = frame;
+        if slot_index != index {
+            return None;
+        }
+        frame.format = FrameFormat::from_u32(format)?;
+        frame.pixel_format = PixelFormat::from_u32(pixel_format)?;
+        Some(frame)
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::ffi::{CString, c_char, c_int, c_uint, c_void};
+    use std::io;
+
+    use crate::error::{RdpStatus, fail, fail_file};
+
+    #[cfg(target_os = "macos")]
+    const O_CREAT: c_int = 0x200;
+    #[cfg(target_os = "macos")]
+    const O_EXCL: c_int = 0x800;
+    #[cfg(not(target_os = "macos"))]
+    const O_CREAT: c_int = 0o100;
+    #[cfg(not(target_os = "macos"))]
+    const O_EXCL: c_int = 0o200;
+    const O_RDONLY: c_int = 0;
+    const O_RDWR: c_int = 2;
+    const PROT_READ: c_int = 1;
+    const PROT_WRITE: c_int = 2;
+    const MAP_SHARED: c_int = 1;
+    const SEEK_END: c_int = 2;
+    const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    // Only in librt before glibc 2.34, which still ships an empty one
+    #[cfg_attr(target_os = "linux", link(name = "rt"))]
+    unsafe extern "C" {
+        fn shm_open(name: *const c_char, flags: c_int, ...) -> c_int;
+        fn shm_unlink(name: *const c_char) -> c_int;
+        fn ftruncate(fd: c_int, len: i64) -> c_int;
+        fn lseek(fd: c_int, offset: i64, whence: c_int) -> i64;
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+        fn close(fd: c_int) -> c_int;
+    }
+
+    /// A mapped POSIX shared memory object; the creating end unlinks it on
+    /// drop, after which it lives on only while mapped.
+    pub struct Region {
+        pub ptr: *mut u8,
+        pub len: usize,
+        /// The name to unlink, for the end that created it.
+        owned: Option<CString>,
+    }
+
+    // Only the counters in the mapping are shared, and they are atomics
+    unsafe impl Send for Region {}
+
+    /// `name` as `shm_open` wants it: one leading slash, no others.
+    fn object_name(name: &str) -> Result<CString, RdpStatus> {
+        let name = format!("/{}", name.strip_prefix('/').unwrap_or(name));
+        if name.len() < 2 || name[1..].contains('/') {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                format!("Shared memory name '{name}' must be a single non-empty component"),
+            ));
+        }
+        CString::new(name).map_err(|_| {
+            fail(
+                RdpStatus::InvalidArgument,
+                "Shared memory name contains a NUL",
+            )
+        })
+    }
+
+    impl Region {
+        pub fn create(name: &str, len: usize) -> Result<Region, RdpStatus> {
+            let object = object_name(name)?;
+            // A stale region of a session that died is replaced, not reused,
+            // so no reader keeps a mapping of the old one for the new
+            unsafe { shm_unlink(object.as_ptr()) };
+            let fd =
+                unsafe { shm_open(object.as_ptr(), O_RDWR | O_CREAT | O_EXCL, 0o600 as c_uint) };
+            if fd < 0 {
+                let e = io::Error::last_os_error();
+                return Err(fail_file(
+                    &e,
+                    format!("Cannot create shared memory '{name}': {e}"),
+                ));
+            }
+            let region = if unsafe { ftruncate(fd, len as i64) } != 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                map(fd, len, PROT_READ | PROT_WRITE)
+            };
+            unsafe { close(fd) };
+            match region {
+                Ok(ptr) => Ok(Region {
+                    ptr,
+                    len,
+                    owned: Some(object),
+                }),
+                Err(e) => {
+                    unsafe { shm_unlink(object.as_ptr()) };
+                    Err(fail_file(
+                        &e,
+                        format!("Cannot size {len} bytes of shared memory '{name}': {e}"),
+                    ))
+                }
+            }
+        }
+
+        pub fn open(name: &str) -> Result<Region, RdpStatus> {
+            let object = object_name(name)?;
+            let fd = unsafe { shm_open(object.as_ptr(), O_RDONLY, 0 as c_uint) };
+            if fd < 0 {
+                let e = io::Error::last_os_error();
+                return Err(fail_file(
+                    &e,
+                    format!("Cannot open shared memory '{name}': {e}"),
+                ));
+            }
+            let len = unsafe { lseek(fd, 0, SEEK_END) };
+            let region = match usize::try_from(len) {
+                Ok(0) | Err(_) => Err(io::Error::other("it is empty")),
+                Ok(len) => map(fd, len, PROT_READ).map(|ptr| (ptr, len)),
+            };
+            unsafe { close(fd) };
+            let (ptr, len) = region
+                .map_err(|e| fail_file(&e, format!("Cannot map shared memory '{name}': {e}")))?;
+            Ok(Region {
+                ptr,
+                len,
+                owned: None,
+            })
+        }
+    }
+
+    fn map(fd: c_int, len: usize, prot: c_int) -> io::Result<*mut u8> {
+        let ptr = unsafe { mmap(std::ptr::null_mut(), len, prot, MAP_SHARED, fd, 0) };
+        if ptr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ptr as *mut u8)
+    }
+
+    impl Drop for Region {
+        fn drop(&mut self) {
+            unsafe { munmap(self.ptr as *mut c_void, self.len) };
+            if let Some(object) = &self.owned {
+                unsafe { shm_unlink(object.as_ptr()) };
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+    use std::io;
+
+    use crate::error::{RdpStatus, fail, fail_file};
+
+    const PAGE_READWRITE: u32 = 0x04;
+    const FILE_MAP_READ: u32 = 0x04;
+    const FILE_MAP_ALL_ACCESS: u32 = 0xF001F;
+    const ERROR_ALREADY_EXISTS: i32 = 183;
+    const INVALID_HANDLE_VALUE: *mut c_void = !0 as *mut c_void;
+
+    #[repr(C)]
+    struct MemoryBasicInformation {
+        base_address: *mut c_void,
+        allocation_base: *mut c_void,
+        allocation_protect: u32,
+        #[cfg(target_pointer_width = "64")]
+        partition_id: u16,
+        region_size: usize,
+        state: u32,
+        protect: u32,
+        kind: u32,
+    }
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn CreateFileMappingW(
+            file: *mut c_void,
+            attributes: *mut c_void,
+            protect: u32,
+            size_high: u32,
+            size_low: u32,
+            name: *const u16,
+        ) -> *mut c_void;
+        fn OpenFileMappingW(access: u32, inherit: i32, name: *const u16) -> *mut c_void;
+        fn MapViewOfFile(
+            mapping: *mut c_void,
+            access: u32,
+            offset_high: u32,
+            offset_low: u32,
+            len: usize,
+        ) -> *mut c_void;
+        fn UnmapViewOfFile(base: *const c_void) -> i32;
+        fn VirtualQuery(
+            address: *const c_void,
+            info: *mut MemoryBasicInformation,
+            len: usize,
+        ) -> usize;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+
+    /// A mapped, pagefile-backed section; Windows removes it when the last
+    /// handle and view are gone, so there is nothing to unlink.
+    pub struct Region {
+        pub ptr: *mut u8,
+        pub len: usize,
+        mapping: *mut c_void,
+    }
+
+    // Only the counters in the mapping are shared, and they are atomics
+    unsafe impl Send for Region {}
+
+    fn wide(name: &str) -> Result<Vec<u16>, RdpStatus> {
+        if name.is_empty() || name.contains('\0') {
+            return Err(fail(
+                RdpStatus::InvalidArgument,
+                "Shared memory name is empty or contains a NUL",
+            ));
+        }
+        Ok(name.encode_utf16().chain([0]).collect())
+    }
+
+    impl Region {
+        pub fn create(name: &str, len: usize) -> Result<Region, RdpStatus> {
+            let wide = wide(name)?;
+            let len64 = len as u64;
+            let mapping = unsafe {
+                CreateFileMappingW(
+                    INVALID_HANDLE_VALUE,
+                    std::ptr::null_mut(),
+                    PAGE_READWRITE,
+                    (len64 >> 32) as u32,
+                    len64 as u32,
+                    wide.as_ptr(),
+                )
+            };
+            let e = io::Error::last_os_error();
+            if mapping.is_null() {
+                return Err(fail_file(
+                    &e,
+                    format!("Cannot create shared memory '{name}': {e}"),
+                ));
+            }
+            // A section of that name is still open somewhere, at its old
+            // size, and cannot be replaced
+            if e.raw_os_error() == Some(ERROR_ALREADY_EXISTS) {
+                unsafe { CloseHandle(mapping) };
+                return Err(fail(
+                    RdpStatus::Busy,
+                    format!("Shared memory '{name}' is still open in another process"),
+                ));
+            }
+            Self::view(name, mapping, FILE_MAP_ALL_ACCESS, len)
+        }
+
+        pub fn open(name: &str) -> Result<Region, RdpStatus> {
+            let wide = wide(name)?;
+            let mapping = unsafe { OpenFileMappingW(FILE_MAP_READ, 0, wide.as_ptr()) };
+            if mapping.is_null() {
+                let e = io::Error::last_os_error();
+                return Err(fail_file(
+                    &e,
+                    format!("Cannot open shared memory '{name}': {e}"),
+                ));
+            }
+            Self::view(name, mapping, FILE_MAP_READ, 0)
+        }
+
+        /// Maps `len` bytes of `mapping`, or all of it for 0.
+        fn view(
+            name: &str,
+            mapping: *mut c_void,
+            access: u32,
+            len: usize,
+        ) -> Result<Region, RdpStatus> {
+            let ptr = unsafe { MapViewOfFile(mapping, access, 0, 0, len) };
+            if ptr.is_null() {
+                let e = io::Error::last_os_error();
+                unsafe { CloseHandle(mapping) };
+                return Err(fail_file(
+                    &e,
+                    format!("Cannot map shared memory '{name}': {e}"),
+                ));
+            }
+            let len = if len > 0 {
+                len
+            } else {
+                // Rounded up to whole pages, which the header's sizes check
+                let mut info = std::mem::MaybeUninit::<MemoryBasicInformation>::zeroed();
+                let size = std::mem::size_of::<MemoryBasicInformation>();
+                if unsafe { VirtualQuery(ptr, info.as_mut_ptr(), size) } == 0 {
+                    0
+                } else {
+                    unsafe { info.assume_init() }.region_size
+                }
+            };
+            Ok(Region {
+                ptr: ptr as *mut u8,
+                len,
+                mapping,
+            })
+        }
+    }
+
+    impl Drop for Region {
+        fn drop(&mut self) {
+            unsafe {
+                UnmapViewOfFile(self.ptr as *const c_void);
+                CloseHandle(self.mapping);
+            }
+        }
+    }
+}
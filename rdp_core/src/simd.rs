@@ -0,0 +1,398 @@
+//! Vectorized BGRA conversion, picked at run time: byte shuffles with AVX2
+//! or SSSE3 on x86_64 and interleaving loads and stores with NEON on
+//! aarch64 for `pixels::convert_bgra`, and widening multiplies for the
+//! weighted sums behind luma and YUV. Each path handles the whole vectors'
+//! worth at the start of a row and leaves the last few pixels to the scalar
+//! loop, which is also the reference every path must match byte for byte
+//! (the unit tests in `pixels` and `yuv` check them against it).
+//!
+//! The YUV chroma averages have an x86_64 path only; on aarch64 they stay
+//! scalar.
+
+use crate::frame::PixelFormat;
+
+/// Which code converts pixels. `detect` picks the fastest one the CPU
+/// supports; the others exist for comparing them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConvertPath {
+    Scalar,
+    Ssse3,
+    Avx2,
+    Neon,
+}
+
+impl ConvertPath {
+    /// The fastest path this CPU supports.
+    pub fn detect() -> ConvertPath {
+        [ConvertPath::Avx2, ConvertPath::Ssse3, ConvertPath::Neon]
+            .into_iter()
+            .find(|path| path.is_available())
+            .unwrap_or(ConvertPath::Scalar)
+    }
+
+    /// Whether this build and CPU can run the path.
+    pub fn is_available(self) -> bool {
+        match self {
+            ConvertPath::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            ConvertPath::Ssse3 => std::arch::is_x86_feature_detected!("ssse3"),
+            #[cfg(target_arch = "x86_64")]
+            ConvertPath::Avx2 => std::arch::is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            ConvertPath::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            _ => false,
+        }
+    }
+}
+
+/// Appends the leading pixels of `bgra` that `path` converts in whole
+/// vectors to `out`, laid out as `format`, and returns how many bytes of
+/// `bgra` it consumed; the caller converts the rest. Returns 0 for the
+/// scalar path, a path this CPU lacks and layouts that are not a reordering
+/// (`Bgra` is a copy, and `Gray` goes through `weigh`).
+pub fn convert(path: ConvertPath, bgra: &[u8], format: PixelFormat, out: &mut Vec<u8>) -> usize {
+    let bpp = match format {
+        PixelFormat::Rgb | PixelFormat::Bgr | PixelFormat::Rgba => {
+            format.bytes_per_pixel() as usize
+        }
+        PixelFormat::Bgra | PixelFormat::Gray => return 0,
+    };
+    if path == ConvertPath::Scalar || !path.is_available() {
+        return 0;
+    }
+
+    // The x86 stores write a whole register, up to 8 bytes past the last
+    // pixel they produce
+    out.reserve(bgra.len() / 4 * bpp + 32);
+    let start = out.len();
+    // SAFETY: `start` is within the allocation, which the paths stay in
+    // thanks to the slack reserved above; `is_available` vouched for the
+    // CPU features
+    let consumed = unsafe {
+        let dst = out.as_mut_ptr().add(start);
+        match path {
+            #[cfg(target_arch = "x86_64")]
+            ConvertPath::Ssse3 => x86::shuffle_ssse3(bgra, &x86::mask(format), bpp * 4, dst),
+            #[cfg(target_arch = "x86_64")]
+            ConvertPath::Avx2 => x86::shuffle_avx2(bgra, &x86::mask(format), bpp * 8, dst),
+            #[cfg(target_arch = "aarch64")]
+            ConvertPath::Neon => arm::interleave_neon(bgra, format, dst),
+            _ => 0,
+        }
+    };
+    // SAFETY: the path initialized an output pixel per input pixel consumed
+    unsafe { out.set_len(start + consumed / 4 * bpp) };
+    consumed
+}
+
+/// Writes `((kr * r + kg * g + kb * b + 128) >> 8) + offset` to `out` for
+/// each leading pixel of `bgra` that `path` handles in whole vectors, the
+/// sum behind luma and the Y plane, and returns how many pixels it did; the
+/// caller weighs the rest. `out` holds a byte per pixel. The weights must
+/// not be negative, and the result must fit a byte.
+pub fn weigh(
+    path: ConvertPath,
+    bgra: &[u8],
+    weights: [i32; 3],
+    offset: u8,
+    out: &mut [u8],
+) -> usize {
+    if !path.is_available() {
+        return 0;
+    }
+    // Never more pixels than `out` has room for
+    let bgra = &bgra[..bgra.len().min(out.len() * 4)];
+    // SAFETY: `is_available` vouched for the CPU features, and every path
+    // stores only the pixels it reads
+    unsafe {
+        let dst = out.as_mut_ptr();
+        match path {
+            #[cfg(target_arch = "x86_64")]
+            ConvertPath::Ssse3 => x86::weigh_ssse3(bgra, &x86::pair_weights(weights), offset, dst),
+            #[cfg(target_arch = "x86_64")]
+            ConvertPath::Avx2 => x86::weigh_avx2(bgra, &x86::pair_weights(weights), offset, dst),
+            #[cfg(target_arch = "aarch64")]
+            ConvertPath::Neon => arm::weigh_neon(bgra, weights.map(|k| k as u8), offset, dst),
+            _ => 0,
+        }
+    }
+}
+
+/// U and V of the leading 2x2 blocks of the BGRA rows `top` and `bottom`
+/// that `path` handles in whole vectors, each block's channels averaged
+/// with rounding first; otherwise like `weigh` with 128 added. Calls
+/// `put(block, u, v)` for each, and returns how many blocks it did. Only
+/// x86_64 has a vector path.
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+pub fn chroma(
+    path: ConvertPath,
+    top: &[u8],
+    bottom: &[u8],
+    (ku, kv): ([i32; 3], [i32; 3]),
+    put: impl FnMut(usize, u8, u8),
+) -> usize {
+    match path {
+        #[cfg(target_arch = "x86_64")]
+        ConvertPath::Ssse3 | ConvertPath::Avx2 if path.is_available() => {
+            // SAFETY: AVX2 implies SSSE3, which `is_available` vouched for
+            unsafe {
+                x86::chroma_ssse3(
+                    top,
+                    bottom,
+                    (&x86::pair_weights(ku), &x86::pair_weights(kv)),
+                    put,
+                )
+            }
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use crate::frame::PixelFormat;
+
+    /// Clears the byte in a `pshufb` mask.
+    const ZERO: u8 = 0x80;
+
+    /// The `pshufb` mask taking four BGRA pixels to `format`, packed at the
+    /// start of the register.
+    pub fn mask(format: PixelFormat) -> [u8; 16] {
+        match format {
+            PixelFormat::Rgb => [
+                2, 1, 0, 6, 5, 4, 10, 9, 8, 14, 13, 12, ZERO, ZERO, ZERO, ZERO,
+            ],
+            PixelFormat::Bgr => [
+                0, 1, 2, 4, 5, 6, 8, 9, 10, 12, 13, 14, ZERO, ZERO, ZERO, ZERO,
+            ],
+            _ => [2, 1, 0, 3, 6, 5, 4, 7, 10, 9, 8, 11, 14, 13, 12, 15],
+        }
+    }
+
+    /// Shuffles 4 pixels at a time with `mask`, storing each result `step`
+    /// bytes after the last. Returns the bytes of `src` consumed.
+    ///
+    /// # Safety
+    /// The CPU supports SSSE3, and `dst` has room for the output plus 16
+    /// bytes.
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn shuffle_ssse3(src: &[u8], mask: &[u8; 16], step: usize, dst: *mut u8) -> usize {
+        let blocks = src.len() / 16;
+        unsafe {
+            let mask = _mm_loadu_si128(mask.as_ptr().cast());
+            for i in 0..blocks {
+                let px = _mm_loadu_si128(src.as_ptr().add(i * 16).cast());
+                _mm_storeu_si128(dst.add(i * step).cast(), _mm_shuffle_epi8(px, mask));
+            }
+        }
+        blocks * 16
+    }
+
+    /// `shuffle_ssse3` 8 pixels at a time. `pshufb` works within each
+    /// 128-bit lane, so 3-byte layouts are then gathered from both lanes
+    /// into the low 24 bytes.
+    ///
+    /// # Safety
+    /// The CPU supports AVX2, and `dst` has room for the output plus 32
+    /// bytes.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn shuffle_avx2(src: &[u8], mask: &[u8; 16], step: usize, dst: *mut u8) -> usize {
+        let blocks = src.len() / 32;
+        unsafe {
+            let mask = _mm256_broadcastsi128_si256(_mm_loadu_si128(mask.as_ptr().cast()));
+            let gather = _mm256_setr_epi32(0, 1, 2, 4, 5, 6, 3, 7);
+            for i in 0..blocks {
+                let px = _mm256_loadu_si256(src.as_ptr().add(i * 32).cast());
+                let mut shuffled = _mm256_shuffle_epi8(px, mask);
+                if step == 24 {
+                    shuffled = _mm256_permutevar8x32_epi32(shuffled, gather);
+                }
+                _mm256_storeu_si256(dst.add(i * step).cast(), shuffled);
+            }
+        }
+        blocks * 32
+    }
+
+    /// `pmaddwd` weights for two BGRA pixels widened to 16 bits, from the
+    /// weights of R, G and B.
+    pub fn pair_weights([kr, kg, kb]: [i32; 3]) -> [i16; 8] {
+        let [kr, kg, kb] = [kr, kg, kb].map(|k| k as i16);
+        [kb, kg, kr, 0, kb, kg, kr, 0]
+    }
+
+    /// The weighted sums of 4 BGRA pixels, rounded and shifted down.
+    #[inline]
+    #[target_feature(enable = "ssse3")]
+    fn sums4(px: __m128i, weights: __m128i) -> __m128i {
+        let zero = _mm_setzero_si128();
+        let lo = _mm_madd_epi16(_mm_unpacklo_epi8(px, zero), weights);
+        let hi = _mm_madd_epi16(_mm_unpackhi_epi8(px, zero), weights);
+        _mm_srai_epi32(
+            _mm_add_epi32(_mm_hadd_epi32(lo, hi), _mm_set1_epi32(128)),
+            8,
+        )
+    }
+
+    /// `sums4` on each 128-bit lane, 8 pixels in order.
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    fn sums8(px: __m256i, weights: __m256i) -> __m256i {
+        let zero = _mm256_setzero_si256();
+        let lo = _mm256_madd_epi16(_mm256_unpacklo_epi8(px, zero), weights);
+        let hi = _mm256_madd_epi16(_mm256_unpackhi_epi8(px, zero), weights);
+        let sums = _mm256_add_epi32(_mm256_hadd_epi32(lo, hi), _mm256_set1_epi32(128));
+        _mm256_srai_epi32(sums, 8)
+    }
+
+    /// Weighs 8 pixels at a time. Returns the pixels done.
+    ///
+    /// # Safety
+    /// The CPU supports SSSE3, and `dst` has room for a byte per pixel of
+    /// `src`.
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn weigh_ssse3(src: &[u8], weights: &[i16; 8], offset: u8, dst: *mut u8) -> usize {
+        let blocks = src.len() / 32;
+        unsafe {
+            let weights = _mm_loadu_si128(weights.as_ptr().cast());
+            let offset = _mm_set1_epi8(offset as i8);
+            for i in 0..blocks {
+                let at = src.as_ptr().add(i * 32);
+                let first = sums4(_mm_loadu_si128(at.cast()), weights);
+                let second = sums4(_mm_loadu_si128(at.add(16).cast()), weights);
+                let words = _mm_packs_epi32(first, second);
+                let bytes = _mm_add_epi8(_mm_packus_epi16(words, words), offset);
+                _mm_storel_epi64(dst.add(i * 8).cast(), bytes);
+            }
+        }
+        blocks * 8
+    }
+
+    /// `weigh_ssse3` 16 pixels at a time. Packing works within each lane,
+    /// so the four runs of 4 results are put back in order before storing.
+    ///
+    /// # Safety
+    /// The CPU supports AVX2, and `dst` has room for a byte per pixel of
+    /// `src`.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn weigh_avx2(src: &[u8], weights: &[i16; 8], offset: u8, dst: *mut u8) -> usize {
+        let blocks = src.len() / 64;
+        unsafe {
+            let weights = _mm256_broadcastsi128_si256(_mm_loadu_si128(weights.as_ptr().cast()));
+            let offset = _mm_set1_epi8(offset as i8);
+            let order = _mm256_setr_epi32(0, 4, 1, 5, 2, 6, 3, 7);
+            for i in 0..blocks {
+                let at = src.as_ptr().add(i * 64);
+                let first = sums8(_mm256_loadu_si256(at.cast()), weights);
+                let second = sums8(_mm256_loadu_si256(at.add(32).cast()), weights);
+                let words = _mm256_packs_epi32(first, second);
+                let bytes = _mm256_permutevar8x32_epi32(_mm256_packus_epi16(words, words), order);
+                let bytes = _mm_add_epi8(_mm256_castsi256_si128(bytes), offset);
+                _mm_storeu_si128(dst.add(i * 16).cast(), bytes);
+            }
+        }
+        blocks * 16
+    }
+
+    /// U and V of 2 blocks at a time: 4 pixels of each row widened, summed
+    /// down and then across, averaged and weighed. Returns the blocks done.
+    ///
+    /// # Safety
+    /// The CPU supports SSSE3.
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn chroma_ssse3(
+        top: &[u8],
+        bottom: &[u8],
+        (ku, kv): (&[i16; 8], &[i16; 8]),
+        mut put: impl FnMut(usize, u8, u8),
+    ) -> usize {
+        let pairs = top.len().min(bottom.len()) / 16;
+        unsafe {
+            let ku = _mm_loadu_si128(ku.as_ptr().cast());
+            let kv = _mm_loadu_si128(kv.as_ptr().cast());
+            let zero = _mm_setzero_si128();
+            for i in 0..pairs {
+                let upper = _mm_loadu_si128(top.as_ptr().add(i * 16).cast());
+                let lower = _mm_loadu_si128(bottom.as_ptr().add(i * 16).cast());
+                // Pixels 0 and 1, then 2 and 3, each summed with the one below
+                let lo = _mm_add_epi16(
+                    _mm_unpacklo_epi8(upper, zero),
+                    _mm_unpacklo_epi8(lower, zero),
+                );
+                let hi = _mm_add_epi16(
+                    _mm_unpackhi_epi8(upper, zero),
+                    _mm_unpackhi_epi8(lower, zero),
+                );
+                let sums = _mm_add_epi16(_mm_unpacklo_epi64(lo, hi), _mm_unpackhi_epi64(lo, hi));
+                let average = _mm_srli_epi16(_mm_add_epi16(sums, _mm_set1_epi16(2)), 2);
+                // U of both blocks, then V of both
+                let uv = _mm_hadd_epi32(_mm_madd_epi16(average, ku), _mm_madd_epi16(average, kv));
+                let uv = _mm_srai_epi32(_mm_add_epi32(uv, _mm_set1_epi32(128)), 8);
+                let uv = _mm_add_epi32(uv, _mm_set1_epi32(128));
+                let words = _mm_packs_epi32(uv, uv);
+                let [u0, u1, v0, v1] =
+                    _mm_cvtsi128_si32(_mm_packus_epi16(words, words)).to_le_bytes();
+                put(i * 2, u0, v0);
+                put(i * 2 + 1, u1, v1);
+            }
+        }
+        pairs * 2
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use std::arch::aarch64::*;
+
+    use crate::frame::PixelFormat;
+
+    /// Splits 16 pixels at a time into channel registers and stores them
+    /// interleaved in `format`'s order. Returns the bytes of `src`
+    /// consumed.
+    ///
+    /// # Safety
+    /// The CPU supports NEON, and `dst` has room for the output.
+    #[target_feature(enable = "neon")]
+    pub unsafe fn interleave_neon(src: &[u8], format: PixelFormat, dst: *mut u8) -> usize {
+        let blocks = src.len() / 64;
+        unsafe {
+            for i in 0..blocks {
+                let uint8x16x4_t(b, g, r, a) = vld4q_u8(src.as_ptr().add(i * 64));
+                match format {
+                    PixelFormat::Rgb => vst3q_u8(dst.add(i * 48), uint8x16x3_t(r, g, b)),
+                    PixelFormat::Bgr => vst3q_u8(dst.add(i * 48), uint8x16x3_t(b, g, r)),
+                    _ => vst4q_u8(dst.add(i * 64), uint8x16x4_t(r, g, b, a)),
+                }
+            }
+        }
+        blocks * 64
+    }
+
+    /// Weighs 16 pixels at a time in 16-bit sums, which the weights keep
+    /// from overflowing. Returns the pixels done.
+    ///
+    /// # Safety
+    /// The CPU supports NEON, and `dst` has room for a byte per pixel of
+    /// `src`.
+    #[target_feature(enable = "neon")]
+    pub unsafe fn weigh_neon(src: &[u8], [kr, kg, kb]: [u8; 3], offset: u8, dst: *mut u8) -> usize {
+        let blocks = src.len() / 64;
+        unsafe {
+            let offset = vdupq_n_u8(offset);
+            let (kr8, kg8, kb8) = (vdup_n_u8(kr), vdup_n_u8(kg), vdup_n_u8(kb));
+            let (kr16, kg16, kb16) = (vdupq_n_u8(kr), vdupq_n_u8(kg), vdupq_n_u8(kb));
+            for i in 0..blocks {
+                let uint8x16x4_t(b, g, r, _) = vld4q_u8(src.as_ptr().add(i * 64));
+                let lo = vmull_u8(vget_low_u8(r), kr8);
+                let lo = vmlal_u8(vmlal_u8(lo, vget_low_u8(g), kg8), vget_low_u8(b), kb8);
+                let hi = vmull_high_u8(r, kr16);
+                let hi = vmlal_high_u8(vmlal_high_u8(hi, g, kg16), b, kb16);
+                // Rounding narrows add the 128 before shifting
+                let sums = vcombine_u8(vrshrn_n_u16::<8>(lo), vrshrn_n_u16::<8>(hi));
+                vst1q_u8(dst.add(i * 16), vaddq_u8(sums, offset));
+            }
+        }
+        blocks * 16
+    }
+}
@@ -0,0 +1,172 @@
+//! Capturing every display as one frame, like RDP's "use all my monitors"
+//! (display index `SPAN_ALL`): each display is captured on its own and
+//! copied onto a canvas covering the bounding box of the layout, at its
+//! place on the virtual desktop. Whatever part of the box no display
+//! covers (an L-shaped arrangement, monitors of different heights) stays
+//! black.
+//!
+//! Displays deliver frames at their own pace. One with nothing new keeps
+//! its previous pixels on the canvas, so an idle or slow monitor never
+//! holds the others back; the canvas is a new frame as soon as any display
+//! has one.
+
+use std::io;
+
+use crate::capture::{Backend, Frame, FrameSource};
+use crate::cursor::CursorProbe;
+use crate::display::{self, SPAN_ALL};
+use crate::error::{RdpStatus, fail};
+use crate::pixels::Rect;
+
+/// Where each display lands on the canvas, in `Display::all()` order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    parts: Vec<Rect>,
+}
+
+impl Layout {
+    /// The arrangement as of now, in the canvas pixels
+    /// `CursorProbe::new(SPAN_ALL)` maps the desktop onto, so cursor and
+    /// input coordinates line up with the canvas. On macOS that is points
+    /// at the highest scale of any display; a display with a lower scale
+    /// fills less of its place on the canvas than it does on the desktop.
+    pub fn current() -> Result<Layout, RdpStatus> {
+        let all = display::enumerate().map_err(|e| {
+            fail(
+                RdpStatus::NoDisplay,
+                format!("Failed to enumerate displays: {e}"),
+            )
+        })?;
+        let probe = CursorProbe::new(SPAN_ALL).ok_or_else(|| {
+            fail(
+                RdpStatus::NoDisplay,
+                "Cannot locate the displays on the desktop",
+            )
+        })?;
+        Ok(Layout {
+            parts: all
+                .iter()
+                .map(|d| {
+                    let (x, y) = probe.to_display(f64::from(d.x), f64::from(d.y));
+                    Rect {
+                        x: x.max(0) as u32,
+                        y: y.max(0) as u32,
+                        w: d.width,
+                        h: d.height,
+                    }
+                })
+                .collect(),
+        })
+    }
+}
+
+/// One display's capturer and its place on the canvas.
+struct Part {
+    capturer: scrap::Capturer,
+    at: Rect,
+}
+
+/// The composite of every display, as one `FrameSource`.
+pub struct Span {
+    parts: Vec<Part>,
+    /// Tightly packed BGRA, `width x height`.
+    canvas: Vec<u8>,
+    width: usize,
+    height: usize,
+}
+
+impl Span {
+    /// Opens a capturer on every display of `layout`. Fails with
+    /// `RdpStatus::NoDisplay` if the displays changed since it was taken.
+    pub fn open(layout: &Layout) -> Result<Span, RdpStatus> {
+        let displays = scrap::Display::all().map_err(|e| {
+            fail(
+                RdpStatus::NoDisplay,
+                format!("Failed to enumerate displays: {e}"),
+            )
+        })?;
+        if displays.is_empty() || displays.len() != layout.parts.len() {
+            return Err(fail(
+                RdpStatus::NoDisplay,
+                format!(
+                    "Found {} displays to span, expected {}",
+                    displays.len(),
+                    layout.parts.len()
+                ),
+            ));
+        }
+
+        let parts = displays
+            .into_iter()
+            .zip(&layout.parts)
+            .map(|(display, place)| {
+                let capturer = scrap::Capturer::new(display).map_err(|e| {
+                    fail(
+                        RdpStatus::CapturerInitFailed,
+                        format!("Failed to create capturer: {e}"),
+                    )
+                })?;
+                let at = Rect {
+                    x: place.x,
+                    y: place.y,
+                    w: capturer.width() as u32,
+                    h: capturer.height() as u32,
+                };
+                Ok(Part { capturer, at })
+            })
+            .collect::<Result<Vec<_>, RdpStatus>>()?;
+
+        let width = parts.iter().map(|p| (p.at.x + p.at.w) as usize).max();
+        let height = parts.iter().map(|p| (p.at.y + p.at.h) as usize).max();
+        let (width, height) = (width.unwrap_or(0), height.unwrap_or(0));
+        Ok(Span {
+            parts,
+            canvas: [0, 0, 0, 0xff].repeat(width * height),
+            width,
+            height,
+        })
+    }
+}
+
+impl FrameSource for Span {
+    fn backend(&self) -> Backend {
+        Backend::Native
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Copies in every display that has a new frame; `WouldBlock` only if
+    /// none has.
+    fn frame(&mut self) -> io::Result<Frame<'_>> {
+        let mut fresh = false;
+        for part in &mut self.parts {
+            let frame = match part.capturer.frame() {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            };
+            let (x, y) = (part.at.x as usize, part.at.y as usize);
+            let (w, h) = (part.at.w as usize, part.at.h as usize);
+            // The display changed size under its capturer
+            if h == 0 || frame.len() < w * h * 4 {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            let stride = frame.len() / h;
+            for (row, src) in frame.chunks(stride).take(h).enumerate() {
+                let start = ((y + row) * self.width + x) * 4;
+                self.canvas[start..start + w * 4].copy_from_slice(&src[..w * 4]);
+            }
+            fresh = true;
+        }
+        if !fresh {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(Frame::Borrowed(&self.canvas))
+    }
+}
@@ -0,0 +1,393 @@
+//! Pipeline instrumentation: per-stage timings and frame counters. They live
+//! in atomics shared with the session handle, so reading them never waits
+//! for a capture in progress.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::Duration;
+
+use crate::capture::Backend;
+use crate::memory::MemoryBudget;
+use crate::pace::WaitStrategy;
+use crate::priority::Applied;
+use crate::queue::{ConsumerKind, QueuePolicy};
+
+/// Upper bounds (exclusive, in microseconds) of the `capture_wait_hist`
+/// buckets but the last, which takes the rest: doubling from 1 ms, with
+/// 16 and 33 ms framing a 60 and a 30 fps display.
+pub const CAPTURE_WAIT_BUCKETS_US: [u64; 7] = [1_000, 2_000, 4_000, 8_000, 16_000, 33_000, 66_000];
+
+/// Weight of the newest sample in the rolling averages, as a shift: 1/8,
+/// i.e. averaged over roughly the last eight frames.
+const SMOOTHING_SHIFT: u32 = 3;
+
+/// Snapshot filled in by `rdp_session_get_stats`.
+///
+/// Times are microseconds. `*_avg` is a rolling average over the recent
+/// frames that reached the stage, `*_max` the slowest since the session was
+/// opened or the stats were last reset. Fields are only ever appended.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RdpStats {
+    /// Waiting for the OS to hand over a frame.
+    pub capture_wait_us_avg: u64,
+    pub capture_wait_us_max: u64,
+    /// Copying (and cropping) it out of the OS buffer, cursor included.
+    pub copy_us_avg: u64,
+    pub copy_us_max: u64,
+    /// Resizing; 0 for frames kept at their captured size.
+    pub resize_us_avg: u64,
+    pub resize_us_max: u64,
+    /// Channel conversion (grayscale or reordering for the encoder).
+    pub convert_us_avg: u64,
+    pub convert_us_max: u64,
+    /// Compression, or the copy out for raw frames.
+    pub encode_us_avg: u64,
+    pub encode_us_max: u64,
+    /// Frames handed to the caller.
+    pub frames_captured: u64,
+    /// Captures dropped by change detection.
+    pub frames_skipped: u64,
+    /// Total size of the frames handed to the caller.
+    pub bytes_emitted: u64,
+    /// Measured output rate, as from `rdp_session_get_actual_fps`; not
+    /// cleared by a reset.
+    pub actual_fps: f64,
+    /// Network clients turned away for a missing or wrong access token.
+    pub auth_failures: u64,
+    /// The capture backend the session opened with (1 = native, 2 =
+    /// Wayland, 3 = test pattern; see `rdp_set_capture_backend`); not
+    /// cleared by a reset.
+    pub backend: u32,
+    /// Polls that found no new frame from the OS yet. Many of them with a
+    /// short `encode_us_avg` point at the OS not producing frames rather
+    /// than the pipeline being slow.
+    pub wouldblock_retries: u64,
+    /// Errors from the OS capturer, including those it recovered from by
+    /// reopening.
+    pub capture_errors: u64,
+    /// Frames a stream captured but dropped because a consumer (a buffered
+    /// or async stream's, or a network client) took the newer ones first;
+    /// `rdp_session_get_consumer_stats` tells which.
+    pub frames_dropped: u64,
+    /// Shortest `capture_wait`; 0 until a frame was captured.
+    pub capture_wait_us_min: u64,
+    /// Captures by how long they waited for the OS: under 1, 2, 4, 8, 16,
+    /// 33 and 66 ms, and the rest.
+    pub capture_wait_hist: [u64; 8],
+    /// The wait strategy `capture` polls with, as passed to
+    /// `rdp_session_set_wait_strategy` (0 = backoff); the `capture_wait`
+    /// fields show what it achieves. Not cleared by a reset.
+    pub wait_strategy: u32,
+    /// Priority the running stream thread actually got (0 = normal, 1 =
+    /// above normal, 2 = time critical), which is less than
+    /// `rdp_session_set_thread_priority` asked for when the OS refused;
+    /// 0 without a stream. Not cleared by a reset.
+    pub thread_priority: u32,
+    /// Cores the running stream thread is pinned to (bit `n` for core
+    /// `n`); 0 when it is not, or without a stream. Not cleared by a reset.
+    pub thread_affinity: u64,
+    /// The session's memory limit in bytes (see
+    /// `rdp_session_set_memory_limit`), 0 for none. Not cleared by a reset.
+    pub memory_limit: u64,
+    /// Bytes its buffers and queued frames hold against the limit now,
+    /// counted with or without one. Not cleared by a reset.
+    pub memory_used: u64,
+    /// Most `memory_used` has been.
+    pub memory_peak: u64,
+    /// Times the limit refused a buffer room, so a queued frame or replay
+    /// still made way or a capture failed with `MEMORY_LIMIT`.
+    pub memory_refusals: u64,
+}
+
+/// One frame consumer's queue, as listed by
+/// `rdp_session_get_consumer_stats`. Fields are only ever appended.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RdpConsumerStats {
+    /// Unique among the session's consumers, never reused.
+    pub id: u64,
+    /// 1 = buffered stream, 2 = HTTP client, 3 = TCP client, 4 = WebSocket
+    /// client, 5 = local socket client.
+    pub kind: u32,
+    /// 0 = latest wins, 1 = block, as for `rdp_session_set_queue_policy`.
+    pub policy: u32,
+    /// Frames the queue holds at most.
+    pub capacity: u32,
+    /// Frames waiting in it now.
+    pub queued: u32,
+    /// Frames the consumer took.
+    pub delivered: u64,
+    /// Frames pushed out of the full queue before the consumer took them.
+    pub dropped: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum Stage {
+    CaptureWait,
+    Copy,
+    Resize,
+    Convert,
+    Encode,
+}
+
+#[derive(Default)]
+struct StageTimes {
+    avg: AtomicU64,
+    max: AtomicU64,
+}
+
+/// Live counters behind `RdpStats`. Written only by the thread capturing,
+/// which holds the session lock, apart from `auth_failures`, which the
+/// network servers count, and `frames_dropped` and the consumers', which
+/// the frame queues count; readers just load.
+#[derive(Default)]
+pub struct Stats {
+    stages: [StageTimes; 5],
+    frames_captured: AtomicU64,
+    frames_skipped: AtomicU64,
+    bytes_emitted: AtomicU64,
+    auth_failures: AtomicU64,
+    /// `f64::to_bits` of the measured rate.
+    fps: AtomicU64,
+    backend: AtomicU32,
+    wouldblock_retries: AtomicU64,
+    capture_errors: AtomicU64,
+    frames_dropped: AtomicU64,
+    /// The shortest capture wait plus one, so the default 0 means none yet.
+    capture_wait_min: AtomicU64,
+    capture_wait_hist: [AtomicU64; 8],
+    wait_strategy: AtomicU32,
+    /// The live frame queues' counters; a queue's entry goes with it.
+    consumers: Mutex<Vec<Weak<Consumer>>>,
+    consumer_ids: AtomicU64,
+    thread_priority: AtomicU32,
+    thread_affinity: AtomicU64,
+    memory: Arc<MemoryBudget>,
+}
+
+/// Live counters of one frame queue (see `queue::FrameQueue`).
+pub struct Consumer {
+    id: u64,
+    kind: ConsumerKind,
+    policy: QueuePolicy,
+    capacity: u32,
+    queued: AtomicU32,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Consumer {
+    pub fn set_queued(&self, count: usize) {
+        self.queued.store(count as u32, Relaxed);
+    }
+
+    pub fn delivered(&self) {
+        self.delivered.fetch_add(1, Relaxed);
+    }
+
+    pub fn add_dropped(&self, count: usize) {
+        self.dropped.fetch_add(count as u64, Relaxed);
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Relaxed)
+    }
+
+    fn snapshot(&self) -> RdpConsumerStats {
+        RdpConsumerStats {
+            id: self.id,
+            kind: self.kind as u32,
+            policy: self.policy as u32,
+            capacity: self.capacity,
+            queued: self.queued.load(Relaxed),
+            delivered: self.delivered.load(Relaxed),
+            dropped: self.dropped.load(Relaxed),
+        }
+    }
+}
+
+impl Stats {
+    pub fn record(&self, stage: Stage, elapsed: Duration) {
+        let sample = elapsed.as_micros() as u64;
+        let times = &self.stages[stage as usize];
+        let avg = times.avg.load(Relaxed);
+        let avg = if avg == 0 {
+            sample
+        } else {
+            // avg + (sample - avg) / 8, without going negative
+            avg - (avg >> SMOOTHING_SHIFT) + (sample >> SMOOTHING_SHIFT)
+        };
+        times.avg.store(avg, Relaxed);
+        times.max.fetch_max(sample, Relaxed);
+
+        if let Stage::CaptureWait = stage {
+            let _ = self.capture_wait_min.fetch_update(Relaxed, Relaxed, |min| {
+                (min == 0 || sample + 1 < min).then_some(sample + 1)
+            });
+            let bucket = CAPTURE_WAIT_BUCKETS_US
+                .iter()
+                .position(|&bound| sample < bound)
+                .unwrap_or(CAPTURE_WAIT_BUCKETS_US.len());
+            self.capture_wait_hist[bucket].fetch_add(1, Relaxed);
+        }
+    }
+
+    pub fn frame_emitted(&self, bytes: usize) {
+        self.frames_captured.fetch_add(1, Relaxed);
+        self.bytes_emitted.fetch_add(bytes as u64, Relaxed);
+    }
+
+    pub fn frame_skipped(&self) {
+        self.frames_skipped.fetch_add(1, Relaxed);
+    }
+
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Relaxed);
+    }
+
+    pub fn would_block(&self) {
+        self.wouldblock_retries.fetch_add(1, Relaxed);
+    }
+
+    pub fn capture_error(&self) {
+        self.capture_errors.fetch_add(1, Relaxed);
+    }
+
+    pub fn frames_dropped(&self, count: usize) {
+        self.frames_dropped.fetch_add(count as u64, Relaxed);
+    }
+
+    pub fn set_fps(&self, fps: f64) {
+        self.fps.store(fps.to_bits(), Relaxed);
+    }
+
+    pub fn set_backend(&self, backend: Backend) {
+        self.backend.store(backend as u32, Relaxed);
+    }
+
+    pub fn set_wait_strategy(&self, strategy: WaitStrategy) {
+        self.wait_strategy.store(strategy.kind(), Relaxed);
+    }
+
+    pub fn set_thread(&self, applied: Applied) {
+        self.thread_priority.store(applied.priority as u32, Relaxed);
+        self.thread_affinity.store(applied.affinity, Relaxed);
+    }
+
+    /// The session's memory budget, which its buffers and frame queues
+    /// charge.
+    pub fn memory(&self) -> &Arc<MemoryBudget> {
+        &self.memory
+    }
+
+    pub fn fps(&self) -> f64 {
+        f64::from_bits(self.fps.load(Relaxed))
+    }
+
+    /// Counters for a new frame queue, listed by `consumers` until the
+    /// returned `Arc` is gone.
+    pub fn add_consumer(
+        &self,
+        kind: ConsumerKind,
+        policy: QueuePolicy,
+        capacity: usize,
+    ) -> Arc<Consumer> {
+        let consumer = Arc::new(Consumer {
+            id: self.consumer_ids.fetch_add(1, Relaxed) + 1,
+            kind,
+            policy,
+            capacity: capacity as u32,
+            queued: AtomicU32::new(0),
+            delivered: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        let mut consumers = self.lock_consumers();
+        consumers.retain(|c| c.strong_count() > 0);
+        consumers.push(Arc::downgrade(&consumer));
+        consumer
+    }
+
+    /// The live consumers' counters, oldest consumer first.
+    pub fn consumers(&self) -> Vec<RdpConsumerStats> {
+        self.lock_consumers()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|c| c.snapshot())
+            .collect()
+    }
+
+    fn lock_consumers(&self) -> MutexGuard<'_, Vec<Weak<Consumer>>> {
+        self.consumers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A copy of the current values. Each field is read atomically, though
+    /// a capture finishing meanwhile may show up in some fields only.
+    pub fn snapshot(&self) -> RdpStats {
+        let stage = |stage: Stage| {
+            let times = &self.stages[stage as usize];
+            (times.avg.load(Relaxed), times.max.load(Relaxed))
+        };
+        let (capture_wait_us_avg, capture_wait_us_max) = stage(Stage::CaptureWait);
+        let (copy_us_avg, copy_us_max) = stage(Stage::Copy);
+        let (resize_us_avg, resize_us_max) = stage(Stage::Resize);
+        let (convert_us_avg, convert_us_max) = stage(Stage::Convert);
+        let (encode_us_avg, encode_us_max) = stage(Stage::Encode);
+        RdpStats {
+            capture_wait_us_avg,
+            capture_wait_us_max,
+            copy_us_avg,
+            copy_us_max,
+            resize_us_avg,
+            resize_us_max,
+            convert_us_avg,
+            convert_us_max,
+            encode_us_avg,
+            encode_us_max,
+            frames_captured: self.frames_captured.load(Relaxed),
+            frames_skipped: self.frames_skipped.load(Relaxed),
+            bytes_emitted: self.bytes_emitted.load(Relaxed),
+            actual_fps: self.fps(),
+            auth_failures: self.auth_failures.load(Relaxed),
+            backend: self.backend.load(Relaxed),
+            wouldblock_retries: self.wouldblock_retries.load(Relaxed),
+            capture_errors: self.capture_errors.load(Relaxed),
+            frames_dropped: self.frames_dropped.load(Relaxed),
+            capture_wait_us_min: self.capture_wait_min.load(Relaxed).saturating_sub(1),
+            capture_wait_hist: self.capture_wait_hist.each_ref().map(|n| n.load(Relaxed)),
+            wait_strategy: self.wait_strategy.load(Relaxed),
+            thread_priority: self.thread_priority.load(Relaxed),
+            thread_affinity: self.thread_affinity.load(Relaxed),
+            memory_limit: self.memory.limit(),
+            memory_used: self.memory.used(),
+            memory_peak: self.memory.peak(),
+            memory_refusals: self.memory.refusals(),
+        }
+    }
+
+    /// Zeroes everything but the measured rate.
+    pub fn reset(&self) {
+        for times in &self.stages {
+            times.avg.store(0, Relaxed);
+            times.max.store(0, Relaxed);
+        }
+        self.frames_captured.store(0, Relaxed);
+        self.frames_skipped.store(0, Relaxed);
+        self.bytes_emitted.store(0, Relaxed);
+        self.auth_failures.store(0, Relaxed);
+        self.wouldblock_retries.store(0, Relaxed);
+        self.capture_errors.store(0, Relaxed);
+        self.frames_dropped.store(0, Relaxed);
+        self.capture_wait_min.store(0, Relaxed);
+        for bucket in &self.capture_wait_hist {
+            bucket.store(0, Relaxed);
+        }
+        self.memory.reset();
+        for consumer in self.lock_consumers().iter().filter_map(Weak::upgrade) {
+            consumer.delivered.store(0, Relaxed);
+            consumer.dropped.store(0, Relaxed);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Background capture: a thread that captures from a session at its target
+//! rate and either hands every frame to a host callback, keeps the newest
+//! few in a ring for the host to poll or publishes them to the library's
+//! own servers, so the host does not have to run (and pay per-call
+//! overhead for) its own capture loop.
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use crate::async_capture::Feed;
+use crate::broadcast::Broadcast;
+#[cfg(feature = "async")]
+use crate::error::CaptureError;
+use crate::error::{RdpStatus, fail, guard};
+use crate::frame::EncodedFrame;
+use crate::log::{self, LogLevel};
+use crate::pace::{self, Pacer};
+use crate::priority::{self, Applied};
+use crate::queue::{ConsumerKind, FrameQueue, QueuePolicy};
+use crate::session::RdpSession;
+use crate::stats::Stats;
+use crate::{RawImage, free_image};
+
+/// Receives each streamed frame on the stream thread. `image` is only valid
+/// until the callback returns; a null `image` reports that the stream ended
+/// because capturing failed.
+pub type FrameCallback = extern "C" fn(image: *const RawImage, user_data: *mut c_void);
+
+/// Nap between polls while waiting for the capturer to produce a frame.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Frames a buffered stream keeps when no size is given.
+pub const DEFAULT_RING_SIZE: usize = 3;
+
+/// Largest accepted ring; more only adds latency and memory for frames
+/// nobody will see.
+pub const MAX_RING_SIZE: usize = 16;
+
+/// Where a stream's frames go.
+pub enum Sink {
+    Callback {
+        callback: FrameCallback,
+        user_data: *mut c_void,
+    },
+    Ring(Arc<FrameRing>),
+    /// Fan-out to network clients; closed when the stream ends.
+    Broadcast(Arc<Broadcast>),
+    /// An `async_capture::FrameStream`; ended along with the stream.
+    #[cfg(feature = "async")]
+    Feed(Arc<Feed>),
+}
+
+// The host owns `user_data` and promises it can be used from the stream
+// thread.
+unsafe impl Send for Sink {}
+
+/// The newest frames of a buffered stream, queued by the session's policy
+/// (see the `queue` module): by default older ones are dropped as new ones
+/// arrive, so a stalled consumer costs at most `capacity` frames; under
+/// `QueuePolicy::Block` the stream waits for the consumer to poll instead.
+pub struct FrameRing {
+    frames: FrameQueue<EncodedFrame>,
+}
+
+impl FrameRing {
+    pub fn new(capacity: usize, policy: QueuePolicy, stats: &Arc<Stats>) -> FrameRing {
+        FrameRing {
+            frames: FrameQueue::new(ConsumerKind::Buffered, policy, capacity, stats),
+        }
+    }
+
+    fn push(&self, frame: EncodedFrame, give_up: impl Fn() -> bool) {
+        self.frames.push(frame, give_up);
+    }
+
+    /// Takes the newest frame, discarding the older ones.
+    pub fn take_latest(&self) -> Option<EncodedFrame> {
+        self.frames.take_latest()
+    }
+}
+
+/// A running stream thread.
+pub struct Stream {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+    ring: Option<Arc<FrameRing>>,
+}
+
+impl Stream {
+    /// Starts capturing from `session` at its `target_fps`.
+    pub fn start(session: Arc<Mutex<RdpSession>>, sink: Sink) -> Result<Stream, RdpStatus> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let ring = match &sink {
+            Sink::Ring(ring) => Some(Arc::clone(ring)),
+            _ => None,
+        };
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::Builder::new()
+            .name("rdp-stream".into())
+            .spawn(move || {
+                let (stats, priority, affinity) = {
+                    let session = lock(&session);
+                    let config = session.config();
+                    (
+                        session.stats(),
+                        config.thread_priority,
+                        config.thread_affinity,
+                    )
+                };
+                stats.set_thread(priority::apply_to_current(priority, affinity));
+                run(&session, &sink, &thread_stop);
+                stats.set_thread(Applied::default());
+                match &sink {
+                    Sink::Broadcast(broadcast) => broadcast.close(),
+                    #[cfg(feature = "async")]
+                    Sink::Feed(feed) => feed.end(),
+                    _ => {}
+                }
+            })
+            .map_err(|e| {
+                fail(
+                    RdpStatus::CapturerInitFailed,
+                    format!("Failed to start stream thread: {e}"),
+                )
+            })?;
+
+        log::log(LogLevel::Info, "Stream started");
+        Ok(Stream { stop, thread, ring })
+    }
+
+    /// The ring of a buffered stream; `None` for a callback stream.
+    pub fn ring(&self) -> Option<&FrameRing> {
+        self.ring.as_deref()
+    }
+
+    /// Ends the stream; no callback starts once this returns. Joins the
+    /// thread, unless called on it (i.e. from the callback), in which case
+    /// the thread exits as soon as the callback returns.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        if self.thread.thread().id() == thread::current().id() {
+            return;
+        }
+        self.thread.thread().unpark();
+        let _ = self.thread.join();
+    }
+}
+
+fn run(session: &Mutex<RdpSession>, sink: &Sink, stop: &AtomicBool) {
+    let stopped = || stop.load(Ordering::Acquire);
+    let mut pacer = Pacer::default();
+
+    while !stopped() {
+        // Read every frame, so `rdp_session_set_target_fps` retimes the stream
+        let interval = pace::interval(lock(session).target_fps());
+        pacer.wait(interval, stopped);
+        if stopped() {
+            return;
+        }
+
+        match (next_frame(session, pacer.due(interval), stop), sink) {
+            (Ok(frame), Sink::Ring(ring)) => ring.push(frame, stopped),
+            (Ok(frame), Sink::Broadcast(broadcast)) => broadcast.publish(frame, stopped),
+            #[cfg(feature = "async")]
+            (Ok(frame), Sink::Feed(feed)) => feed.push(Ok(frame)),
+            (
+                Ok(frame),
+                &Sink::Callback {
+                    callback,
+                    user_data,
+                },
+            ) => {
+                let image = RawImage::into_raw(frame);
+                if !stopped() {
+                    callback(image, user_data);
+                }
+                unsafe { free_image(image) };
+            }
+            // The frames after a resolution change carry the new size, and
+            // consumers taking their queued frames make room under a memory
+            // limit
+            (
+                Err(
+                    RdpStatus::NoChange
+                    | RdpStatus::WouldBlock
+                    | RdpStatus::ResolutionChanged
+                    | RdpStatus::DisplayUnavailable
+                    | RdpStatus::DisplayRemoved
+                    | RdpStatus::MemoryLimit,
+                ),
+                _,
+            ) => {}
+            (Err(status), sink) => {
+                if !stopped() {
+                    log::log(
+                        LogLevel::Error,
+                        &format!("Stream stopped after a failed capture ({status:?})"),
+                    );
+                    match sink {
+                        &Sink::Callback {
+                            callback,
+                            user_data,
+                        } => callback(ptr::null(), user_data),
+                        // Converted here, where the status's message was recorded
+                        #[cfg(feature = "async")]
+                        Sink::Feed(feed) => feed.push(Err(CaptureError::from(status))),
+                        _ => {}
+                    }
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn lock(session: &Mutex<RdpSession>) -> MutexGuard<'_, RdpSession> {
+    session.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Polls the capturer until it has a frame or `until` passes (napping at
+/// least once, so an unpaced stream does not spin). The lock is only held
+/// for each attempt, so setters called meanwhile (including from the
+/// callback) get through.
+fn next_frame(
+    session: &Mutex<RdpSession>,
+    until: Instant,
+    stop: &AtomicBool,
+) -> Result<EncodedFrame, RdpStatus> {
+    loop {
+        match guard(Err(RdpStatus::Panic), || lock(session).try_capture(0, 0)) {
+            Err(
+                status @ (RdpStatus::WouldBlock
+                | RdpStatus::DisplayUnavailable
+                | RdpStatus::DisplayRemoved),
+            ) if !stop.load(Ordering::Acquire) => {
+                thread::sleep(POLL_INTERVAL);
+                if Instant::now() >= until {
+                    return Err(status);
+                }
+            }
+            other => return other,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! A plain TCP frame server for native viewers. After connecting, a client
+//! receives one record per frame until either side closes the connection.
+//! It never has to send anything, unless an access token is set
+//! (`rdp_server_set_auth_token`): then its first message must be the token,
+//! as a `u32` little-endian byte count followed by the UTF-8 bytes, within
+//! 5 s. A client with a missing or wrong token is disconnected without a
+//! reply. Each record is:
+//!
+//! ```text
+//! u32 length         payload byte count
+//! u32 sequence       RawImage::sequence, truncated to 32 bits
+//! u64 timestamp_us   RawImage::timestamp_us
+//! [u8; length]       the frame, in the session's output format
+//! ```
+//!
+//! All integers are little-endian and the header is 16 bytes, unpadded
+//! (Python: `struct.unpack("<IIQ", header)`). Records follow each other
+//! directly; there is no handshake or trailer.
+//!
+//! Every client has its own thread and its own frame queue (see the `queue`
+//! module). Under the default latest-wins policy a slow client never stalls
+//! capture or the other clients: newer frames push out the ones it has not
+//! taken yet, and it always catches up to the newest frame. A skipped frame
+//! shows as a gap in the sequence numbers; with a delta format (tiles,
+//! video) such a client has to wait for the next keyframe. Under the block
+//! policy the stream waits for the slowest client instead.
+//!
+//! With drop reports on (`rdp_session_set_drop_reports`), a client that
+//! missed frames gets a control record before its next frame, with a
+//! header no frame can have:
+//!
+//! ```text
+//! u32 0xFFFFFFFF     marks a control record; no payload follows
+//! u32 kind           1 = frames dropped
+//! u64 value          frames this client has missed since it connected
+//! ```
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::auth;
+use crate::broadcast::Broadcast;
+use crate::server::Conn;
+
+/// A client that accepts nothing for this long is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client has to send its token.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest token read; anything longer cannot match.
+const MAX_TOKEN: u32 = 4096;
+
+/// The length field of a control record.
+const CONTROL: u32 = u32::MAX;
+
+/// Control record kinds.
+const DROPPED: u32 = 1;
+
+/// `server::Handler` for TCP clients.
+pub fn serve(mut conn: Conn, broadcast: &Broadcast) -> io::Result<()> {
+    conn.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    conn.set_nodelay(true)?;
+    if let Some(token) = auth::token() {
+        conn.set_read_timeout(Some(AUTH_TIMEOUT))?;
+        let presented = read_token(&mut conn).ok();
+        if !auth::matches(&token, presented.as_deref()) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "missing or wrong access token",
+            ));
+        }
+    }
+
+    let frames = broadcast.subscribe();
+    let mut reported = 0;
+    while let Some(frame) = frames.next() {
+        let dropped = frames.dropped();
+        if broadcast.reports_drops() && dropped > reported {
+            reported = dropped;
+            conn.write_all(&record(CONTROL, DROPPED, dropped))?;
+        }
+        conn.write_all(&record(
+            frame.data.len() as u32,
+            frame.sequence as u32,
+            frame.timestamp_us,
+        ))?;
+        conn.write_all(&frame.data)?;
+    }
+    Ok(())
+}
+
+/// A record header (see the module docs).
+fn record(length: u32, sequence: u32, value: u64) -> [u8; 16] {
+    let mut header = [0u8; 16];
+    header[0..4].copy_from_slice(&length.to_le_bytes());
+    header[4..8].copy_from_slice(&sequence.to_le_bytes());
+    header[8..16].copy_from_slice(&value.to_le_bytes());
+    header
+}
+
+fn read_token(conn: &mut Conn) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    conn.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_TOKEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "token too long"));
+    }
+    let mut token = vec![0u8; len as usize];
+    conn.read_exact(&mut token)?;
+    Ok(token)
+}
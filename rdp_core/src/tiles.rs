@@ -0,0 +1,493 @@
+//! Tile-based keyframe/delta payloads.
+//!
+//! The frame is split into a grid of `tile_size` squares (edge tiles are
+//! smaller). A keyframe carries the whole image as a single tile; a delta
+//! carries only the tiles whose content hash changed since the previous
+//! frame, neighbours joined into larger rectangles. A delta that would be
+//! no smaller than the last keyframe is sent as a keyframe instead. Both
+//! use the same little-endian container, so one parser handles either:
+//!
+//! ```text
+//! u32 tile_count
+//! u32 payload_format        FrameFormat of every tile payload (0 = JPEG, ...)
+//! tile_count times:
+//!     u32 x, u32 y          top-left corner in image pixels
+//!     u32 w, u32 h          tile size in pixels
+//!     u32 len               payload byte count
+//!     [u8; len]             the tile, encoded as payload_format
+//! ```
+//!
+//! `RawImage::format` tells the two apart (`TiledKeyframe` / `TiledDelta`);
+//! a delta is only meaningful on top of the frames before it, so clients
+//! joining late must wait for (or request) a keyframe. `parse` reads the
+//! container back for the decoder (`rdp_decoder_feed`).
+//!
+//! With `encode_bands` above 1 a keyframe carries the image as that many
+//! full-width bands instead, encoded in parallel (see `parallel`), and
+//! changed tiles are encoded in parallel too. Untiled sessions can use the
+//! bands alone: every frame is then a `TiledKeyframe` of bands.
+//!
+//! Tiles may overlap, and are drawn in order: with a focus region (see
+//! `focus`) the last one is the rectangle around the cursor at a higher
+//! quality, covering what the tiles before it drew there.
+
+use crate::encode;
+use crate::error::{RdpStatus, fail};
+use crate::frame::FrameFormat;
+use crate::parallel;
+use crate::pixels::{self, Rect};
+use crate::session::SessionConfig;
+
+/// Per-session record of what the client was last sent.
+#[derive(Default)]
+pub struct TileState {
+    /// Content hash of every tile in the last frame, row-major; empty when
+    /// the next frame must be a keyframe.
+    hashes: Vec<u64>,
+    /// Grid the hashes belong to, as (width, height, tile size).
+    grid: (u32, u32, u32),
+    /// Frames emitted since the last keyframe.
+    since_keyframe: u32,
+    /// Bytes of the last keyframe; a delta that comes out as large is
+    /// replaced by a keyframe.
+    keyframe_len: usize,
+}
+
+impl TileState {
+    /// Makes the next frame a keyframe.
+    pub fn request_keyframe(&mut self) {
+        self.hashes.clear();
+    }
+}
+
+/// Builds the tiled payload for a tightly packed `width x height` image of
+/// `bpp`-byte pixels. Returns the container and whether it is a keyframe or
+/// a delta; `tile` is scratch space for one tile's pixels.
+///
+/// Changed tiles next to each other go out as one rectangle (see `merge`),
+/// since every payload pays for its own headers: a JPEG's are some 600
+/// bytes, more than a flat 64-pixel tile.
+pub fn encode(
+    state: &mut TileState,
+    image: &[u8],
+    (width, height): (u32, u32),
+    bpp: u32,
+    config: &SessionConfig,
+    tile: &mut Vec<u8>,
+) -> Result<(Vec<u8>, FrameFormat), RdpStatus> {
+    let size = config.tile_size;
+    let grid = (width, height, size);
+    let mut keyframe = state.hashes.is_empty()
+        || state.grid != grid
+        || (config.keyframe_interval > 0 && state.since_keyframe >= config.keyframe_interval);
+
+    let stride = (width * bpp) as usize;
+    let mut hashes = Vec::with_capacity(state.hashes.len());
+    let mut changed = Vec::with_capacity(state.hashes.len());
+    for y in (0..height).step_by(size as usize) {
+        for x in (0..width).step_by(size as usize) {
+            let rect = Rect {
+                x,
+                y,
+                w: size.min(width - x),
+                h: size.min(height - y),
+            };
+            pixels::crop(image, stride, bpp as usize, rect, tile);
+            let hash = pixels::frame_hash(tile, &[rect.w, rect.h]);
+            changed.push(state.hashes.get(hashes.len()) != Some(&hash));
+            hashes.push(hash);
+        }
+    }
+
+    // Every tile changed: a delta would only be a keyframe the client
+    // cannot start from
+    keyframe |= changed.iter().all(|&changed| changed);
+    let mut out = header(0, config.format);
+    let mut count = 0;
+    if !keyframe {
+        let rects = merge(&changed, width.div_ceil(size) as usize, grid);
+        if config.encode_bands > 1 {
+            let payloads = parallel::map(&rects, |&rect| {
+                let mut tile = Vec::new();
+                pixels::crop(image, stride, bpp as usize, rect, &mut tile);
+                encode::encode(&tile, rect.w, rect.h, config)
+            })?;
+            for (&rect, payload) in rects.iter().zip(&payloads) {
+                push_tile(&mut out, rect, payload);
+            }
+        } else {
+            for &rect in &rects {
+                pixels::crop(image, stride, bpp as usize, rect, tile);
+                let payload = encode::encode(tile, rect.w, rect.h, config)?;
+                push_tile(&mut out, rect, &payload);
+            }
+        }
+        count = rects.len() as u32;
+        // Busier than the last keyframe; a keyframe may not be smaller, but
+        // is worth more
+        if out.len() >= state.keyframe_len {
+            keyframe = true;
+            out.truncate(8);
+        }
+    }
+
+    if keyframe {
+        count = push_bands(&mut out, image, (width, height), stride, config)?;
+        state.since_keyframe = 0;
+        state.keyframe_len = out.len();
+    } else {
+        state.since_keyframe += 1;
+    }
+    out[..4].copy_from_slice(&count.to_le_bytes());
+
+    state.hashes = hashes;
+    state.grid = grid;
+    let format = if keyframe {
+        FrameFormat::TiledKeyframe
+    } else {
+        FrameFormat::TiledDelta
+    };
+    Ok((out, format))
+}
+
+/// Joins the `changed` cells of a `(width, height, tile size)` grid,
+/// `columns` wide and row-major, into rectangles: runs of cells along a
+/// row, extended down while the next row has a run over the same columns.
+fn merge(changed: &[bool], columns: usize, (width, height, size): (u32, u32, u32)) -> Vec<Rect> {
+    let mut rects: Vec<Rect> = Vec::new();
+    // Rectangles reaching the row above, by index
+    let mut open = Vec::new();
+    for (row, cells) in changed.chunks(columns).enumerate() {
+        let y = row as u32 * size;
+        let h = size.min(height - y);
+        let mut reaching = Vec::new();
+        let mut column = 0;
+        while column < cells.len() {
+            if !cells[column] {
+                column += 1;
+                continue;
+            }
+            let start = column;
+            while column < cells.len() && cells[column] {
+                column += 1;
+            }
+            let x = start as u32 * size;
+            let w = (column as u32 * size).min(width) - x;
+            match open
+                .iter()
+                .copied()
+                .find(|&i: &usize| rects[i].x == x && rects[i].w == w)
+            {
+                Some(i) => {
+                    rects[i].h += h;
+                    reaching.push(i);
+                }
+                None => {
+                    reaching.push(rects.len());
+                    rects.push(Rect { x, y, w, h });
+                }
+            }
+        }
+        open = reaching;
+    }
+    rects
+}
+
+/// Whether untiled frames of `config` are split into bands: JPEG, PNG and
+/// WebP with `encode_bands` above 1.
+pub fn uses_bands(config: &SessionConfig) -> bool {
+    config.encode_bands > 1
+        && matches!(
+            config.format,
+            FrameFormat::Jpeg | FrameFormat::Png | FrameFormat::WebP
+        )
+}
+
+/// The whole of a tightly packed `width x height` image of `bpp`-byte
+/// pixels as a keyframe container of `config.encode_bands` bands, encoded
+/// in parallel.
+pub fn encode_bands(
+    image: &[u8],
+    (width, height): (u32, u32),
+    bpp: u32,
+    config: &SessionConfig,
+) -> Result<Vec<u8>, RdpStatus> {
+    let mut out = header(0, config.format);
+    let count = push_bands(
+        &mut out,
+        image,
+        (width, height),
+        (width * bpp) as usize,
+        config,
+    )?;
+    out[..4].copy_from_slice(&count.to_le_bytes());
+    Ok(out)
+}
+
+/// Appends the image as `config.encode_bands` bands, encoded in parallel,
+/// and returns how many there are.
+fn push_bands(
+    out: &mut Vec<u8>,
+    image: &[u8],
+    (width, height): (u32, u32),
+    stride: usize,
+    config: &SessionConfig,
+) -> Result<u32, RdpStatus> {
+    let regions = bands(width, height, config.encode_bands);
+    let payloads = parallel::map(&regions, |&band| {
+        encode::encode(band_pixels(image, stride, band), band.w, band.h, config)
+    })?;
+    for (&band, payload) in regions.iter().zip(&payloads) {
+        push_tile(out, band, payload);
+    }
+    Ok(regions.len() as u32)
+}
+
+/// Splits `height` rows into at most `count` full-width bands (one for 0
+/// or 1). Heights are rounded up to a multiple of 16, the largest JPEG
+/// block, so band seams fall on block edges and look like a single encode.
+fn bands(width: u32, height: u32, count: u32) -> Vec<Rect> {
+    let rows = height.div_ceil(count.max(1)).next_multiple_of(16).max(1);
+    (0..height)
+        .step_by(rows as usize)
+        .map(|y| Rect {
+            x: 0,
+            y,
+            w: width,
+            h: rows.min(height - y),
+        })
+        .collect()
+}
+
+/// The rows of `band` in a tightly packed image, which are contiguous.
+fn band_pixels(image: &[u8], stride: usize, band: Rect) -> &[u8] {
+    &image[band.y as usize * stride..(band.y + band.h) as usize * stride]
+}
+
+/// Appends `rect` of a tightly packed `width`-wide image of `bpp`-byte
+/// pixels, encoded with `config`, as the last tile of a container from
+/// `encode` or `encode_bands`. `tile` is scratch space for its pixels.
+pub fn push_over(
+    out: &mut Vec<u8>,
+    image: &[u8],
+    (width, bpp): (u32, u32),
+    rect: Rect,
+    config: &SessionConfig,
+    tile: &mut Vec<u8>,
+) -> Result<(), RdpStatus> {
+    pixels::crop(image, (width * bpp) as usize, bpp as usize, rect, tile);
+    let payload = encode::encode(tile, rect.w, rect.h, config)?;
+    push_tile(out, rect, &payload);
+    let count = u32::from_le_bytes(out[..4].try_into().unwrap()) + 1;
+    out[..4].copy_from_slice(&count.to_le_bytes());
+    Ok(())
+}
+
+/// Whether any tile of a container from `encode` overlaps `rect`.
+pub fn overlaps(container: &[u8], rect: Rect) -> bool {
+    let field = |at: usize| u32::from_le_bytes(container[at..at + 4].try_into().unwrap());
+    let mut at = 8;
+    for _ in 0..field(0) {
+        let tile = Rect {
+            x: field(at),
+            y: field(at + 4),
+            w: field(at + 8),
+            h: field(at + 12),
+        };
+        if tile.within(rect).is_some() {
+            return true;
+        }
+        at += 20 + field(at + 16) as usize;
+    }
+    false
+}
+
+/// One tile of a container, as `parse` reads it.
+pub struct Tile<'a> {
+    pub rect: Rect,
+    pub payload: &'a [u8],
+}
+
+/// Reads a container from `encode` or `encode_bands`: the format of its
+/// tile payloads and its tiles, in drawing order. Fails with
+/// `RdpStatus::DecodeFailed` when it is truncated, names no known format
+/// or has bytes past its last tile.
+pub fn parse(container: &[u8]) -> Result<(FrameFormat, Vec<Tile<'_>>), RdpStatus> {
+    let mut rest = container;
+    let count = field(&mut rest)?;
+    let format = field(&mut rest)?;
+    let format = FrameFormat::from_u32(format).ok_or_else(|| {
+        fail(
+            RdpStatus::DecodeFailed,
+            format!("Tile container holds unknown format {format}"),
+        )
+    })?;
+
+    let mut tiles = Vec::new();
+    for _ in 0..count {
+        let rect = Rect {
+            x: field(&mut rest)?,
+            y: field(&mut rest)?,
+            w: field(&mut rest)?,
+            h: field(&mut rest)?,
+        };
+        let len = field(&mut rest)? as usize;
+        tiles.push(Tile {
+            rect,
+            payload: take(&mut rest, len)?,
+        });
+    }
+    if !rest.is_empty() {
+        return Err(fail(
+            RdpStatus::DecodeFailed,
+            format!("{} bytes follow the last tile", rest.len()),
+        ));
+    }
+    Ok((format, tiles))
+}
+
+/// Cuts the next `len` bytes off the front of `rest`.
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8], RdpStatus> {
+    if rest.len() < len {
+        return Err(fail(RdpStatus::DecodeFailed, "Tile container is truncated"));
+    }
+    let (taken, tail) = rest.split_at(len);
+    *rest = tail;
+    Ok(taken)
+}
+
+/// Reads the next little-endian `u32` off the front of `rest`.
+fn field(rest: &mut &[u8]) -> Result<u32, RdpStatus> {
+    let bytes = take(rest, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn header(count: u32, payload_format: FrameFormat) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8);
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(&(payload_format as u32).to_le_bytes());
+    out
+}
+
+fn push_tile(out: &mut Vec<u8>, rect: Rect, payload: &[u8]) {
+    for field in [rect.x, rect.y, rect.w, rect.h, payload.len() as u32] {
+        out.extend_from_slice(&field.to_le_bytes());
+    }
+    out.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u32, y: u32, w: u32, h: u32) -> Rect {
+        Rect { x, y, w, h }
+    }
+
+    fn config() -> SessionConfig {
+        SessionConfig {
+            format: FrameFormat::Jpeg,
+            tile_size: 64,
+            ..SessionConfig::default()
+        }
+    }
+
+    /// Dark text-like detail on a light panel, as screens tend to show, in
+    /// gray so it reads the same in the encoder's input format whatever
+    /// that is.
+    fn screen(width: u32, height: u32, config: &SessionConfig) -> (Vec<u8>, u32) {
+        let bpp = encode::input_format(config).bytes_per_pixel();
+        let mut image = Vec::with_capacity((width * height * bpp) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let level = if (x / 5 + y / 9) % 3 == 0 && (x * 31 + y * 17) % 5 != 0 {
+                    20
+                } else {
+                    250
+                };
+                image.extend(std::iter::repeat_n(level, bpp as usize));
+            }
+        }
+        (image, bpp)
+    }
+
+    #[test]
+    fn partial_change_makes_a_smaller_delta() {
+        let (width, height) = (512, 384);
+        let config = config();
+        let mut state = TileState::default();
+        let mut tile = Vec::new();
+        let (mut image, bpp) = screen(width, height, &config);
+        let size = (width, height);
+        let (key, format) =
+            encode(&mut state, &image, size, bpp, &config, &mut tile).expect("keyframe");
+        assert_eq!(format, FrameFormat::TiledKeyframe);
+
+        // A caret's worth of change on one line of "text", over two tiles
+        for y in 100..116 {
+            let at = ((y * width + 60) * bpp) as usize;
+            image[at..at + (8 * bpp) as usize].fill(128);
+        }
+        let (delta, format) =
+            encode(&mut state, &image, size, bpp, &config, &mut tile).expect("delta");
+        assert_eq!(format, FrameFormat::TiledDelta);
+        assert!(
+            delta.len() < key.len(),
+            "{}-byte delta against a {}-byte keyframe",
+            delta.len(),
+            key.len()
+        );
+        let (_, tiles) = parse(&delta).expect("parse");
+        let rects: Vec<Rect> = tiles.iter().map(|tile| tile.rect).collect();
+        assert_eq!(rects, [rect(0, 64, 128, 64)]);
+    }
+
+    #[test]
+    fn larger_delta_falls_back_to_a_keyframe() {
+        let (width, height) = (256, 192);
+        let config = config();
+        let mut state = TileState::default();
+        let mut tile = Vec::new();
+        let (busy, bpp) = screen(width, height, &config);
+        let size = (width, height);
+        let flat = vec![250; busy.len()];
+        let (_, format) = encode(&mut state, &flat, size, bpp, &config, &mut tile).expect("flat");
+        assert_eq!(format, FrameFormat::TiledKeyframe);
+
+        // Detail everywhere but the last tile
+        let mut image = busy.clone();
+        let last_tile = ((height - 64) * width * bpp) as usize;
+        let row = (width * bpp) as usize;
+        for at in (last_tile..image.len()).step_by(row) {
+            image[at + row - (64 * bpp) as usize..at + row].fill(250);
+        }
+        let (_, format) = encode(&mut state, &image, size, bpp, &config, &mut tile).expect("busy");
+        assert_eq!(format, FrameFormat::TiledKeyframe);
+
+        // Every tile changed
+        let inverted: Vec<u8> = busy.iter().map(|&b| !b).collect();
+        let (_, format) =
+            encode(&mut state, &inverted, size, bpp, &config, &mut tile).expect("inverted");
+        assert_eq!(format, FrameFormat::TiledKeyframe);
+    }
+
+    #[test]
+    fn merge_joins_runs_across_rows() {
+        // 3 x 3 cells of 10 pixels over a 25 x 25 image, so the last row
+        // and column are 5 pixels
+        #[rustfmt::skip]
+        let changed = [
+            true,  true,  false,
+            true,  true,  true,
+            false, false, true,
+        ];
+        assert_eq!(
+            merge(&changed, 3, (25, 25, 10)),
+            [rect(0, 0, 20, 10), rect(0, 10, 25, 10), rect(20, 20, 5, 5)]
+        );
+        assert_eq!(
+            merge(&[false, true, false, true], 2, (20, 20, 10)),
+            [rect(10, 0, 10, 20)]
+        );
+    }
+}
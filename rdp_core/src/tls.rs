@@ -0,0 +1,385 @@
+//! TLS for the network servers, built with the `tls` feature, through
+//! rustls. `rdp_server_set_tls` installs a certificate process-wide; every
+//! server started afterwards accepts only TLS connections (HTTPS, WSS, or
+//! TLS-wrapped TCP records), TLS 1.2 or newer. The certificate is not
+//! checked against anything, so self-signed ones work.
+//!
+//! A client speaking plaintext to a TLS server is turned away as soon as
+//! its first byte shows it is not a TLS handshake (an HTTP request gets a
+//! 400 saying so); one that sends nothing is dropped after the handshake
+//! timeout.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ServerConfig, ServerConnection};
+use rustls::sign::{CertifiedKey, SingleCertAndKey};
+use rustls::{Error as TlsError, InconsistentKeys};
+
+use crate::error::{RdpStatus, fail};
+use crate::http;
+
+/// Longest a client may take to start and finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First byte of every TLS handshake record (ClientHello included).
+const HANDSHAKE_RECORD: u8 = 0x16;
+
+/// The certificate servers started from now on use; `None` serves
+/// plaintext.
+static CURRENT: Mutex<Option<Arc<Context>>> = Mutex::new(None);
+
+/// Loads the PEM certificate (chain) and private key at the given paths
+/// and makes servers started afterwards use them; `None` goes back to
+/// plaintext. Servers already running keep what they started with.
+pub fn configure(paths: Option<(&str, &str)>) -> Result<(), RdpStatus> {
+    let context = paths
+        .map(|(cert, key)| Context::load(Path::new(cert), Path::new(key)))
+        .transpose()?;
+    *CURRENT.lock().unwrap_or_else(PoisonError::into_inner) = context.map(Arc::new);
+    Ok(())
+}
+
+pub fn current() -> Option<Arc<Context>> {
+    CURRENT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// A loaded certificate and key, ready to accept connections.
+pub struct Context(Arc<ServerConfig>);
+
+impl Context {
+    fn load(cert: &Path, key: &Path) -> Result<Context, RdpStatus> {
+        // Opened first so a missing or unreadable file is reported as such
+        // rather than as a parse error
+        std::fs::File::open(cert).map_err(|e| {
+            fail(
+                RdpStatus::TlsCertificate,
+                format!("Failed to read TLS certificate {}: {e}", cert.display()),
+            )
+        })?;
+        std::fs::File::open(key).map_err(|e| {
+            fail(
+                RdpStatus::TlsKey,
+                format!("Failed to read TLS private key {}: {e}", key.display()),
+            )
+        })?;
+        let bad_key = |e: &dyn std::fmt::Display| {
+            fail(
+                RdpStatus::TlsKey,
+                format!("{} is not a valid PEM private key: {e}", key.display()),
+            )
+        };
+        let bad_cert = |e: &dyn std::fmt::Display| {
+            fail(
+                RdpStatus::TlsCertificate,
+                format!("{} is not a valid PEM certificate: {e}", cert.display()),
+            )
+        };
+
+        let provider = Arc::new(ring::default_provider());
+        let signing_key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| bad_key(&e))
+            .and_then(|der| {
+                provider
+                    .key_provider
+                    .load_private_key(der)
+                    .map_err(|e| bad_key(&e))
+            })?;
+        let chain = CertificateDer::pem_file_iter(cert)
+            .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+            .map_err(|e| bad_cert(&e))?;
+        if chain.is_empty() {
+            return Err(bad_cert(&"no CERTIFICATE block"));
+        }
+        let certified = CertifiedKey::new(chain, signing_key);
+        match certified.keys_match() {
+            // Key types rustls cannot compare are left to the handshake
+            Ok(()) | Err(TlsError::InconsistentKeys(InconsistentKeys::Unknown)) => {}
+            Err(TlsError::InconsistentKeys(InconsistentKeys::KeyMismatch)) => {
+                return Err(fail(
+                    RdpStatus::TlsKeyMismatch,
+                    format!(
+                        "Private key {} does not match certificate {}",
+                        key.display(),
+                        cert.display()
+                    ),
+                ));
+            }
+            Err(e) => return Err(bad_cert(&e)),
+        }
+
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(rustls::ALL_VERSIONS)
+            .map_err(|e| {
+                fail(
+                    RdpStatus::NetworkError,
+                    format!("Failed to create a TLS context: {e}"),
+                )
+            })?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SingleCertAndKey::from(certified)));
+        Ok(Context(Arc::new(config)))
+    }
+
+    /// Runs the server side of the handshake on a freshly accepted
+    /// connection. Plaintext clients are rejected (see the module docs).
+    pub fn accept(&self, socket: TcpStream) -> io::Result<Stream> {
+        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut first = [0u8];
+        if socket.peek(&mut first)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if first[0] != HANDSHAKE_RECORD {
+            if first[0].is_ascii_uppercase() {
+                // Most likely an http:// or ws:// URL pointed at the port
+                let _ = http::respond(
+                    &mut &socket,
+                    "400 Bad Request",
+                    "text/plain",
+                    b"This server only accepts TLS (https:// or wss://)\n",
+                );
+                // Closing with the request unread would reset the
+                // connection, which can throw the response away too
+                let _ = socket.set_nonblocking(true);
+                let _ = io::copy(&mut &socket, &mut io::sink());
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "plaintext connection to a TLS server",
+            ));
+        }
+
+        let mut tls = ServerConnection::new(Arc::clone(&self.0)).map_err(io::Error::other)?;
+        while tls.is_handshaking() {
+            tls.complete_io(&mut &socket)?;
+        }
+        // The handler sets its own timeouts
+        socket.set_read_timeout(None)?;
+        socket.set_write_timeout(None)?;
+        Ok(Stream {
+            socket: socket.try_clone()?,
+            connection: Arc::new(Mutex::new(Connection { tls, socket })),
+        })
+    }
+}
+
+/// One TLS connection. Clones share the connection, so one thread can read
+/// while another writes: reading waits for data without holding the lock
+/// the writer needs.
+pub struct Stream {
+    socket: TcpStream,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Stream {
+    /// The underlying socket, for timeouts and shutting it down.
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        Ok(Stream {
+            socket: self.socket.try_clone()?,
+            connection: Arc::clone(&self.connection),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.lock().tls.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                done => return done,
+            }
+            // Waits (under the socket's read timeout) for bytes to arrive;
+            // EOF counts as arriving, and rustls reports it on the next turn
+            self.socket.peek(&mut [0u8])?;
+            let mut connection = self.lock();
+            let Connection { tls, socket } = &mut *connection;
+            tls.read_tls(&mut &*socket)?;
+            tls.process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            // Alerts and key updates the records asked for
+            while tls.wants_write() {
+                tls.write_tls(&mut &*socket)?;
+            }
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut connection = self.lock();
+        let Connection { tls, socket } = &mut *connection;
+        let written = tls.writer().write(buf)?;
+        while tls.wants_write() {
+            tls.write_tls(&mut &*socket)?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // `write` hands every record to the socket before returning
+        Ok(())
+    }
+}
+
+/// The TLS state and the socket it runs over; only used behind `Stream`'s
+/// lock.
+struct Connection {
+    tls: ServerConnection,
+    socket: TcpStream,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Sends close_notify where the socket is still writable; a peer
+        // that already left makes this fail harmlessly
+        self.tls.send_close_notify();
+        while self.tls.wants_write() {
+            if self.tls.write_tls(&mut &self.socket).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    /// A fresh self-signed certificate for `localhost`, its PEM files
+    /// written under `name`, and its DER for clients to trust.
+    fn self_signed(name: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("generate a certificate");
+        let dir = std::env::temp_dir().join(format!("rdp_tls_{}_{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+        (cert, key, generated.cert.der().clone())
+    }
+
+    /// A client trusting only `root`, on the given TLS versions.
+    fn client(
+        root: CertificateDer<'static>,
+        version: &'static rustls::SupportedProtocolVersion,
+    ) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(root).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Arc::new(config)
+    }
+
+    #[test]
+    fn loopback_handshake_carries_data_both_ways() {
+        let (cert, key, root) = self_signed("loopback");
+        let context = Arc::new(Context::load(&cert, &key).expect("load"));
+        for version in [&rustls::version::TLS12, &rustls::version::TLS13] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = {
+                let context = Arc::clone(&context);
+                thread::spawn(move || {
+                    let (socket, _) = listener.accept().unwrap();
+                    let mut stream = context.accept(socket).expect("handshake");
+                    let mut hello = [0u8; 5];
+                    stream.read_exact(&mut hello).unwrap();
+                    assert_eq!(&hello, b"hello");
+                    stream.write_all(b"world").unwrap();
+                    // The client's close_notify reads as a clean end
+                    let mut rest = Vec::new();
+                    stream.read_to_end(&mut rest).unwrap();
+                    assert!(rest.is_empty());
+                })
+            };
+
+            let name = ServerName::try_from("localhost").unwrap();
+            let connection = ClientConnection::new(client(root.clone(), version), name).unwrap();
+            let socket = TcpStream::connect(addr).unwrap();
+            let mut stream = StreamOwned::new(connection, socket);
+            stream.write_all(b"hello").unwrap();
+            let mut world = [0u8; 5];
+            stream.read_exact(&mut world).unwrap();
+            assert_eq!(&world, b"world");
+            assert_eq!(stream.conn.protocol_version(), Some(version.version));
+            stream.conn.send_close_notify();
+            stream.flush().unwrap();
+            server.join().expect("server side");
+        }
+    }
+
+    #[test]
+    fn plaintext_clients_get_a_400() {
+        let (cert, key, _) = self_signed("plaintext");
+        let context = Context::load(&cert, &key).expect("load");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        let (socket, _) = listener.accept().unwrap();
+        let refused = context.accept(socket).err().expect("plaintext refused");
+        assert_eq!(refused.kind(), io::ErrorKind::InvalidData);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    }
+
+    #[test]
+    fn unusable_files_map_to_their_status() {
+        let (cert, key, _) = self_signed("files");
+        let (_, other_key, _) = self_signed("files_other");
+        let missing = cert.with_file_name("missing.pem");
+        for (cert, key, status) in [
+            (&missing, &key, RdpStatus::TlsCertificate),
+            (&cert, &missing, RdpStatus::TlsKey),
+            (&key, &key, RdpStatus::TlsCertificate),
+            (&cert, &cert, RdpStatus::TlsKey),
+            (&cert, &other_key, RdpStatus::TlsKeyMismatch),
+        ] {
+            assert_eq!(
+                Context::load(cert, key).err(),
+                Some(status),
+                "{} with {}",
+                cert.display(),
+                key.display()
+            );
+        }
+    }
+}
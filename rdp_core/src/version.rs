@@ -0,0 +1,114 @@
+//! What this build of the library is: its version, the revision of its C
+//! ABI and the optional parts compiled into it, for bindings that load
+//! whichever build they find.
+//!
+//! The capability bits come from `cfg!`, so they follow the cargo features
+//! the library was built with; nothing here needs updating when a build
+//! changes its features.
+
+use crate::error::{RdpStatus, fail};
+
+/// The crate version, NUL-terminated for `rdp_version`.
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+
+/// Revision of the C ABI: the `#[repr(C)]` structs and the `extern "C"`
+/// signatures. Raised whenever one changes incompatibly; new functions and
+/// `RdpConfig` growing at the end (see the `config` module) do not count.
+pub const ABI_VERSION: u32 = 1;
+
+pub const CAP_JPEG: u64 = 1 << 0;
+pub const CAP_PNG: u64 = 1 << 1;
+pub const CAP_RAW: u64 = 1 << 2;
+pub const CAP_WEBP: u64 = 1 << 3;
+pub const CAP_H264: u64 = 1 << 4;
+/// VP8 and VP9.
+pub const CAP_VPX: u64 = 1 << 5;
+pub const CAP_ZSTD: u64 = 1 << 6;
+/// `rdp_http_stream_start`.
+pub const CAP_SERVER_HTTP: u64 = 1 << 7;
+/// `rdp_tcp_serve`.
+pub const CAP_SERVER_TCP: u64 = 1 << 8;
+/// `rdp_ws_serve`.
+pub const CAP_SERVER_WS: u64 = 1 << 9;
+/// TLS for the network servers.
+pub const CAP_TLS: u64 = 1 << 10;
+/// `rdp_session_set_encryption_key`.
+pub const CAP_ENCRYPTION: u64 = 1 << 11;
+pub const CAP_INPUT_INJECTION: u64 = 1 << 12;
+pub const CAP_CLIPBOARD: u64 = 1 << 13;
+/// The Wayland capture backend (Linux only).
+pub const CAP_WAYLAND: u64 = 1 << 14;
+/// JPEG is encoded by libjpeg-turbo rather than the pure-Rust fallback.
+pub const CAP_JPEG_TURBO: u64 = 1 << 15;
+
+/// The `CAP_*` bits of this build.
+pub const CAPABILITIES: u64 = CAP_JPEG
+    | CAP_PNG
+    | CAP_RAW
+    | bit(cfg!(feature = "webp"), CAP_WEBP)
+    | bit(cfg!(feature = "h264"), CAP_H264)
+    | bit(cfg!(feature = "vpx"), CAP_VPX)
+    | bit(cfg!(feature = "zstd"), CAP_ZSTD)
+    | CAP_SERVER_HTTP
+    | CAP_SERVER_TCP
+    | bit(cfg!(feature = "websocket"), CAP_SERVER_WS)
+    | bit(cfg!(feature = "tls"), CAP_TLS)
+    | bit(cfg!(feature = "encryption"), CAP_ENCRYPTION)
+    | CAP_INPUT_INJECTION
+    | bit(cfg!(feature = "clipboard"), CAP_CLIPBOARD)
+    | bit(
+        cfg!(all(feature = "wayland", target_os = "linux")),
+        CAP_WAYLAND,
+    )
+    | bit(cfg!(feature = "turbojpeg"), CAP_JPEG_TURBO);
+
+const fn bit(compiled: bool, cap: u64) -> u64 {
+    if compiled { cap } else { 0 }
+}
+
+/// Whether bindings written for ABI revision `expected` can use this
+/// library; if not, the error message says which revisions differ.
+pub fn abi_compatible(expected: u32) -> bool {
+    if expected == ABI_VERSION {
+        return true;
+    }
+    fail(
+        RdpStatus::Unsupported,
+        format!(
+            "Bindings expect ABI version {expected}, but rdp_core {} implements version {ABI_VERSION}",
+            env!("CARGO_PKG_VERSION")
+        ),
+    );
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every optional bit is set exactly when its feature is compiled in.
+    #[test]
+    fn feature_bits_follow_the_build() {
+        let optional = [
+            (CAP_WEBP, cfg!(feature = "webp")),
+            (CAP_H264, cfg!(feature = "h264")),
+            (CAP_VPX, cfg!(feature = "vpx")),
+            (CAP_ZSTD, cfg!(feature = "zstd")),
+            (CAP_SERVER_WS, cfg!(feature = "websocket")),
+            (CAP_TLS, cfg!(feature = "tls")),
+            (CAP_ENCRYPTION, cfg!(feature = "encryption")),
+            (CAP_CLIPBOARD, cfg!(feature = "clipboard")),
+            (
+                CAP_WAYLAND,
+                cfg!(all(feature = "wayland", target_os = "linux")),
+            ),
+            (CAP_JPEG_TURBO, cfg!(feature = "turbojpeg")),
+        ];
+        for (cap, compiled) in optional {
+            assert_eq!(CAPABILITIES & cap != 0, compiled, "bit {cap:#x}");
+        }
+        let always =
+            CAP_JPEG | CAP_PNG | CAP_RAW | CAP_SERVER_HTTP | CAP_SERVER_TCP | CAP_INPUT_INJECTION;
+        assert_eq!(CAPABILITIES & always, always);
+    }
+}
@@ -0,0 +1,90 @@
+//! The video codecs: H.264 (`h264` feature) and VP8/VP9 (`vpx` feature).
+//! Unlike the still-image formats they code each frame against the ones
+//! before it, so a session keeps one encoder open and feeds it every frame,
+//! converted to I420 (see `yuv`). Each backend documents its payload.
+
+use crate::encode;
+use crate::error::RdpStatus;
+use crate::frame::FrameFormat;
+
+/// Bitrate used when the session does not set one.
+pub const DEFAULT_BITRATE_KBPS: u32 = 2000;
+
+/// What an encoder is opened with; anything but the bitrate changing means
+/// a new encoder (and so a keyframe).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Params {
+    pub format: FrameFormat,
+    /// Even, as `yuv::even_size` makes them.
+    pub width: u32,
+    pub height: u32,
+    pub fps: f32,
+    pub bitrate_kbps: u32,
+    /// Frames between keyframes; 0 only sends them on request.
+    pub keyframe_interval: u32,
+}
+
+/// One encoded frame.
+pub struct Packet {
+    pub data: Vec<u8>,
+    pub keyframe: bool,
+}
+
+pub trait Encoder {
+    fn params(&self) -> Params;
+
+    /// Retunes the rate control without reopening the encoder.
+    fn set_bitrate(&mut self, kbps: u32) -> Result<(), RdpStatus>;
+
+    /// Makes the next frame a keyframe.
+    fn force_keyframe(&mut self) -> Result<(), RdpStatus>;
+
+    /// Encodes one I420 frame of the size the encoder was opened with;
+    /// `None` when rate control dropped it.
+    fn encode(&mut self, i420: &[u8], timestamp_ms: u64) -> Result<Option<Packet>, RdpStatus>;
+}
+
+/// Whether `format` is produced by a video encoder rather than `encode`.
+pub fn is_video(format: FrameFormat) -> bool {
+    matches!(
+        format,
+        FrameFormat::H264 | FrameFormat::Vp8 | FrameFormat::Vp9
+    )
+}
+
+fn open(params: Params) -> Result<Box<dyn Encoder>, RdpStatus> {
+    match params.format {
+        #[cfg(feature = "h264")]
+        FrameFormat::H264 => Ok(Box::new(crate::h264::H264Encoder::new(params)?)),
+        #[cfg(feature = "vpx")]
+        FrameFormat::Vp8 | FrameFormat::Vp9 => Ok(Box::new(crate::vpx::VpxEncoder::new(params)?)),
+        format => {
+            encode::ensure_supported(format)?;
+            unreachable!("{format:?} has no video encoder in this build")
+        }
+    }
+}
+
+/// Keeps `slot` holding an encoder for `params`, opening a new one when
+/// anything but the bitrate changed and only retuning the bitrate
+/// otherwise.
+pub fn encoder_for(
+    slot: &mut Option<Box<dyn Encoder>>,
+    params: Params,
+) -> Result<&mut dyn Encoder, RdpStatus> {
+    let reusable = slot.as_ref().is_some_and(|e| {
+        Params {
+            bitrate_kbps: params.bitrate_kbps,
+            ..e.params()
+        } == params
+    });
+    if !reusable {
+        *slot = None;
+        *slot = Some(open(params)?);
+    }
+    let encoder = slot.as_deref_mut().expect("encoder was just opened");
+    if encoder.params().bitrate_kbps != params.bitrate_kbps {
+        encoder.set_bitrate(params.bitrate_kbps)?;
+    }
+    Ok(encoder)
+}
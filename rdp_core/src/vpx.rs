@@ -0,0 +1,402 @@
+//! VP8 and VP9 output through libvpx 1.8, built with the `vpx` feature
+//! (which links `libvpx`). The structs below are declared from libvpx 1.8's
+//! headers, so other releases, whose encoder ABI differs, are refused with
+//! `RdpStatus::Unsupported` rather than handed structs of the wrong layout.
+//!
+//! Frames are encoded from I420 (see `yuv`) in realtime mode, constant
+//! bitrate and without lookahead, so every capture comes straight back out.
+//! Each frame's payload is one compressed VPx frame exactly as libvpx emits
+//! it, with no container around it: the data of one WebM SimpleBlock, or of
+//! an IVF frame without its 12-byte header. A browser can hand it straight
+//! to WebCodecs as `new EncodedVideoChunk({ type: keyframe ? "key" :
+//! "delta", timestamp: timestamp_us, data })` on a `VideoDecoder`
+//! configured with `codec: "vp8"` or `"vp09.00.10.08"` (profile 0, 8-bit).
+//! Decoding has to start at a keyframe (`RawImage::keyframe`).
+
+use std::ffi::{c_int, c_long, c_uint, c_ulong, c_void};
+use std::ptr;
+
+use crate::error::{RdpStatus, fail};
+use crate::frame::FrameFormat;
+use crate::video::{Encoder, Packet, Params};
+
+// vpx_encoder.h / vpx_image.h / vp8cx.h
+const VPX_CODEC_OK: c_int = 0;
+const VPX_CODEC_ABI_MISMATCH: c_int = 3;
+const VPX_SS_MAX_LAYERS: usize = 5;
+const VPX_TS_MAX_LAYERS: usize = 5;
+const VPX_TS_MAX_PERIODICITY: usize = 16;
+const VPX_MAX_LAYERS: usize = 12;
+const VPX_IMG_FMT_I420: c_int = 0x102;
+const VPX_CBR: c_int = 1;
+const VPX_KF_DISABLED: c_int = 0;
+const VPX_EFLAG_FORCE_KF: c_long = 1;
+const VPX_DL_REALTIME: c_ulong = 1;
+const VPX_CODEC_CX_FRAME_PKT: c_int = 0;
+const VPX_FRAME_IS_KEY: u32 = 1;
+const VP8E_SET_CPUUSED: c_int = 13;
+
+/// Speed/quality trade-off for realtime encoding; the fast end of the range
+/// both codecs accept.
+const CPU_USED: c_int = 8;
+
+/// libvpx 1.8's `VPX_ENCODER_ABI_VERSION`, which `vpx_codec_enc_init_ver`
+/// checks for an exact match: the version the structs below come from.
+const ENCODER_ABI_VERSION: c_int = 23;
+/// libvpx 1.8 as `vpx_codec_version` reports it, without the patch level.
+const LIBVPX_1_8: c_int = (1 << 16) | (8 << 8);
+
+#[repr(C)]
+struct VpxRational {
+    num: c_int,
+    den: c_int,
+}
+
+#[repr(C)]
+struct VpxFixedBuf {
+    buf: *mut c_void,
+    sz: usize,
+}
+
+/// `vpx_codec_enc_cfg_t`.
+#[repr(C)]
+struct VpxCodecEncCfg {
+    g_usage: c_uint,
+    g_threads: c_uint,
+    g_profile: c_uint,
+    g_w: c_uint,
+    g_h: c_uint,
+    g_bit_depth: c_int,
+    g_input_bit_depth: c_uint,
+    g_timebase: VpxRational,
+    g_error_resilient: u32,
+    g_pass: c_int,
+    g_lag_in_frames: c_uint,
+    rc_dropframe_thresh: c_uint,
+    rc_resize_allowed: c_uint,
+    rc_scaled_width: c_uint,
+    rc_scaled_height: c_uint,
+    rc_resize_up_thresh: c_uint,
+    rc_resize_down_thresh: c_uint,
+    rc_end_usage: c_int,
+    rc_twopass_stats_in: VpxFixedBuf,
+    rc_firstpass_mb_stats_in: VpxFixedBuf,
+    rc_target_bitrate: c_uint,
+    rc_min_quantizer: c_uint,
+    rc_max_quantizer: c_uint,
+    rc_undershoot_pct: c_uint,
+    rc_overshoot_pct: c_uint,
+    rc_buf_sz: c_uint,
+    rc_buf_initial_sz: c_uint,
+    rc_buf_optimal_sz: c_uint,
+    rc_2pass_vbr_bias_pct: c_uint,
+    rc_2pass_vbr_minsection_pct: c_uint,
+    rc_2pass_vbr_maxsection_pct: c_uint,
+    rc_2pass_vbr_corpus_complexity: c_uint,
+    kf_mode: c_int,
+    kf_min_dist: c_uint,
+    kf_max_dist: c_uint,
+    ss_number_layers: c_uint,
+    ss_enable_auto_alt_ref: [c_int; VPX_SS_MAX_LAYERS],
+    ss_target_bitrate: [c_uint; VPX_SS_MAX_LAYERS],
+    ts_number_layers: c_uint,
+    ts_target_bitrate: [c_uint; VPX_TS_MAX_LAYERS],
+    ts_rate_decimator: [c_uint; VPX_TS_MAX_LAYERS],
+    ts_periodicity: c_uint,
+    ts_layer_id: [c_uint; VPX_TS_MAX_PERIODICITY],
+    layer_target_bitrate: [c_uint; VPX_MAX_LAYERS],
+    temporal_layering_mode: c_int,
+}
+
+#[repr(C)]
+struct VpxCodecCtx {
+    name: *const c_void,
+    iface: *mut c_void,
+    err: c_int,
+    err_detail: *const c_void,
+    init_flags: c_long,
+    config: *const c_void,
+    priv_: *mut c_void,
+}
+
+#[repr(C)]
+struct VpxImage {
+    fmt: c_int,
+    cs: c_int,
+    range: c_int,
+    w: c_uint,
+    h: c_uint,
+    bit_depth: c_uint,
+    d_w: c_uint,
+    d_h: c_uint,
+    r_w: c_uint,
+    r_h: c_uint,
+    x_chroma_shift: c_uint,
+    y_chroma_shift: c_uint,
+    planes: [*mut u8; 4],
+    stride: [c_int; 4],
+    bps: c_int,
+    user_priv: *mut c_void,
+    img_data: *mut u8,
+    img_data_owner: c_int,
+    self_allocd: c_int,
+    fb_priv: *mut c_void,
+}
+
+/// The frame arm of `vpx_codec_cx_pkt_t`'s union, up to its flags.
+#[repr(C)]
+struct VpxCodecCxPkt {
+    kind: c_int,
+    buf: *const u8,
+    sz: usize,
+    pts: i64,
+    duration: c_ulong,
+    flags: u32,
+}
+
+#[link(name = "vpx")]
+unsafe extern "C" {
+    fn vpx_codec_version() -> c_int;
+    fn vpx_codec_vp8_cx() -> *mut c_void;
+    fn vpx_codec_vp9_cx() -> *mut c_void;
+    fn vpx_codec_enc_config_default(
+        iface: *mut c_void,
+        cfg: *mut VpxCodecEncCfg,
+        usage: c_uint,
+    ) -> c_int;
+    fn vpx_codec_enc_init_ver(
+        ctx: *mut VpxCodecCtx,
+        iface: *mut c_void,
+        cfg: *const VpxCodecEncCfg,
+        flags: c_long,
+        ver: c_int,
+    ) -> c_int;
+    fn vpx_codec_enc_config_set(ctx: *mut VpxCodecCtx, cfg: *const VpxCodecEncCfg) -> c_int;
+    fn vpx_codec_control_(ctx: *mut VpxCodecCtx, ctrl_id: c_int, ...) -> c_int;
+    fn vpx_codec_encode(
+        ctx: *mut VpxCodecCtx,
+        img: *const VpxImage,
+        pts: i64,
+        duration: c_ulong,
+        flags: c_long,
+        deadline: c_ulong,
+    ) -> c_int;
+    fn vpx_codec_get_cx_data(
+        ctx: *mut VpxCodecCtx,
+        iter: *mut *const c_void,
+    ) -> *const VpxCodecCxPkt;
+    fn vpx_img_wrap(
+        img: *mut VpxImage,
+        fmt: c_int,
+        d_w: c_uint,
+        d_h: c_uint,
+        stride_align: c_uint,
+        img_data: *mut u8,
+    ) -> *mut VpxImage;
+    fn vpx_codec_destroy(ctx: *mut VpxCodecCtx) -> c_int;
+}
+
+pub struct VpxEncoder {
+    // Boxed: libvpx may keep pointers to both
+    ctx: Box<VpxCodecCtx>,
+    cfg: Box<VpxCodecEncCfg>,
+    params: Params,
+    /// Frames since the last keyframe.
+    since_keyframe: u32,
+    force_keyframe: bool,
+}
+
+impl VpxEncoder {
+    pub fn new(params: Params) -> Result<VpxEncoder, RdpStatus> {
+        let name = codec_name(params.format);
+        // Checked before anything writes a config: a newer libvpx fills in a
+        // longer struct than the one declared here
+        let version = unsafe { vpx_codec_version() };
+        if version & !0xff != LIBVPX_1_8 {
+            return Err(abi_unsupported(name, version));
+        }
+        let iface = unsafe {
+            if params.format == FrameFormat::Vp9 {
+                vpx_codec_vp9_cx()
+            } else {
+                vpx_codec_vp8_cx()
+            }
+        };
+        let mut cfg: Box<VpxCodecEncCfg> = Box::new(unsafe { std::mem::zeroed() });
+        if unsafe { vpx_codec_enc_config_default(iface, &mut *cfg, 0) } != VPX_CODEC_OK {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!("Failed to get the default {name} encoder config"),
+            ));
+        }
+        cfg.g_w = params.width;
+        cfg.g_h = params.height;
+        // Timestamps in milliseconds
+        cfg.g_timebase = VpxRational { num: 1, den: 1000 };
+        cfg.g_lag_in_frames = 0;
+        cfg.rc_end_usage = VPX_CBR;
+        cfg.rc_target_bitrate = params.bitrate_kbps;
+        // Keyframes are placed here, on the session's cadence
+        cfg.kf_mode = VPX_KF_DISABLED;
+
+        let mut ctx: Box<VpxCodecCtx> = Box::new(unsafe { std::mem::zeroed() });
+        let status =
+            unsafe { vpx_codec_enc_init_ver(&mut *ctx, iface, &*cfg, 0, ENCODER_ABI_VERSION) };
+        if status == VPX_CODEC_ABI_MISMATCH {
+            return Err(abi_unsupported(name, version));
+        }
+        if status != VPX_CODEC_OK {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!(
+                    "Failed to initialize the {name} encoder for {}x{} ({status})",
+                    params.width, params.height
+                ),
+            ));
+        }
+        // Owned from here on, so failures below are cleaned up by Drop
+        let mut this = VpxEncoder {
+            ctx,
+            cfg,
+            params,
+            since_keyframe: 0,
+            force_keyframe: false,
+        };
+        if unsafe { vpx_codec_control_(&mut *this.ctx, VP8E_SET_CPUUSED, CPU_USED) } != VPX_CODEC_OK
+        {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!("Failed to set the {name} encoder speed"),
+            ));
+        }
+        Ok(this)
+    }
+}
+
+impl Encoder for VpxEncoder {
+    fn params(&self) -> Params {
+        self.params
+    }
+
+    fn set_bitrate(&mut self, kbps: u32) -> Result<(), RdpStatus> {
+        self.cfg.rc_target_bitrate = kbps;
+        if unsafe { vpx_codec_enc_config_set(&mut *self.ctx, &*self.cfg) } != VPX_CODEC_OK {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!(
+                    "Failed to set the {} bitrate",
+                    codec_name(self.params.format)
+                ),
+            ));
+        }
+        self.params.bitrate_kbps = kbps;
+        Ok(())
+    }
+
+    fn force_keyframe(&mut self) -> Result<(), RdpStatus> {
+        self.force_keyframe = true;
+        Ok(())
+    }
+
+    fn encode(&mut self, i420: &[u8], timestamp_ms: u64) -> Result<Option<Packet>, RdpStatus> {
+        let name = codec_name(self.params.format);
+        let mut image: VpxImage = unsafe { std::mem::zeroed() };
+        // libvpx only reads the planes, despite the mutable type
+        let wrapped = unsafe {
+            vpx_img_wrap(
+                &mut image,
+                VPX_IMG_FMT_I420,
+                self.params.width,
+                self.params.height,
+                1,
+                i420.as_ptr() as *mut u8,
+            )
+        };
+        if wrapped.is_null() {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!("Failed to wrap the frame for the {name} encoder"),
+            ));
+        }
+
+        let interval = self.params.keyframe_interval;
+        let flags = if std::mem::take(&mut self.force_keyframe)
+            || (interval > 0 && self.since_keyframe >= interval)
+        {
+            VPX_EFLAG_FORCE_KF
+        } else {
+            0
+        };
+        let duration = (1000.0 / self.params.fps.max(1.0)) as c_ulong;
+        let status = unsafe {
+            vpx_codec_encode(
+                &mut *self.ctx,
+                &image,
+                timestamp_ms as i64,
+                duration,
+                flags,
+                VPX_DL_REALTIME,
+            )
+        };
+        if status != VPX_CODEC_OK {
+            return Err(fail(
+                RdpStatus::EncodeFailed,
+                format!("{name} encoding failed ({status})"),
+            ));
+        }
+
+        // Without lookahead a frame comes out whole or not at all
+        let mut packet: Option<Packet> = None;
+        let mut iter = ptr::null();
+        loop {
+            let pkt = unsafe { vpx_codec_get_cx_data(&mut *self.ctx, &mut iter) };
+            let Some(pkt) = (unsafe { pkt.as_ref() }) else {
+                break;
+            };
+            if pkt.kind != VPX_CODEC_CX_FRAME_PKT {
+                continue;
+            }
+            let out = packet.get_or_insert_with(|| Packet {
+                data: Vec::new(),
+                keyframe: false,
+            });
+            out.data
+                .extend_from_slice(unsafe { std::slice::from_raw_parts(pkt.buf, pkt.sz) });
+            out.keyframe |= pkt.flags & VPX_FRAME_IS_KEY != 0;
+        }
+
+        self.since_keyframe = match &packet {
+            Some(p) if p.keyframe => 0,
+            _ => self.since_keyframe + 1,
+        };
+        Ok(packet)
+    }
+}
+
+impl Drop for VpxEncoder {
+    fn drop(&mut self) {
+        unsafe { vpx_codec_destroy(&mut *self.ctx) };
+    }
+}
+
+/// The error for a libvpx other than the 1.8 the declarations here match.
+fn abi_unsupported(name: &str, version: c_int) -> RdpStatus {
+    fail(
+        RdpStatus::Unsupported,
+        format!(
+            "The {name} encoder needs libvpx 1.8 (encoder ABI {ENCODER_ABI_VERSION}), \
+             not the linked {}.{}.{}",
+            version >> 16,
+            (version >> 8) & 0xff,
+            version & 0xff
+        ),
+    )
+}
+
+fn codec_name(format: FrameFormat) -> &'static str {
+    if format == FrameFormat::Vp9 {
+        "VP9"
+    } else {
+        "VP8"
+    }
+}
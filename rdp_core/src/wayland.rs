@@ -0,0 +1,800 @@
+//! Screen capture on Wayland, built with the `wayland` feature (which links
+//! `libpipewire-0.3`). Experimental: CI builds and links it, but it has not
+//! been run against a compositor yet.
+//!
+//! Wayland compositors only share the screen through xdg-desktop-portal's
+//! ScreenCast interface. The session is negotiated over D-Bus through
+//! `ashpd`, blocking on async-io's reactor: CreateSession, SelectSources,
+//! then Start, which shows the compositor's dialog and waits for the user.
+//! OpenPipeWireRemote then hands over a socket on which the chosen monitor
+//! arrives as a PipeWire video stream. The stream's callbacks run on
+//! PipeWire's own thread and keep the newest frame in a slot that
+//! `Capturer::frame` swaps out, so polling never blocks.
+//!
+//! Only shared-memory BGRx/BGRA buffers are negotiated (no DMA-BUF
+//! modifiers are offered), which every compositor supports and which map
+//! straight onto the BGRA frames of the other backends. Where the portal
+//! supports it (version 4) the grant is remembered through a restore token,
+//! so reopening a dropped stream does not ask the user again.
+
+use std::ffi::{CStr, c_char, c_int, c_void};
+use std::io;
+use std::os::fd::{IntoRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Once, PoisonError};
+use std::time::Duration;
+
+use ashpd::desktop::screencast::{CursorMode, Screencast, SourceType};
+use ashpd::desktop::{PersistMode, ResponseError, Session};
+use async_io::Timer;
+use futures_lite::future;
+
+use crate::error::{RdpStatus, fail};
+use crate::log::{self, LogLevel};
+
+/// First portal version with restore tokens.
+const RESTORE_VERSION: u32 = 4;
+
+/// How long a portal request may take to answer, which for Start includes
+/// the user making up their mind in the dialog.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long the stream may take to agree on a format once connected.
+const NEGOTIATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// spa/utils/type.h, spa/param/format.h, spa/param/video/raw.h,
+// spa/param/buffers.h, spa/buffer/buffer.h, pipewire/stream.h
+const SPA_TYPE_ID: u32 = 3;
+const SPA_TYPE_INT: u32 = 4;
+const SPA_TYPE_RECTANGLE: u32 = 10;
+const SPA_TYPE_FRACTION: u32 = 11;
+const SPA_TYPE_OBJECT: u32 = 15;
+const SPA_TYPE_CHOICE: u32 = 19;
+const SPA_TYPE_OBJECT_FORMAT: u32 = 0x40003;
+const SPA_TYPE_OBJECT_PARAM_BUFFERS: u32 = 0x40004;
+const SPA_PARAM_ENUM_FORMAT: u32 = 3;
+const SPA_PARAM_FORMAT: u32 = 4;
+const SPA_PARAM_BUFFERS: u32 = 5;
+const SPA_FORMAT_MEDIA_TYPE: u32 = 1;
+const SPA_FORMAT_MEDIA_SUBTYPE: u32 = 2;
+const SPA_FORMAT_VIDEO_FORMAT: u32 = 0x20001;
+const SPA_FORMAT_VIDEO_SIZE: u32 = 0x20003;
+const SPA_FORMAT_VIDEO_FRAMERATE: u32 = 0x20004;
+const SPA_PARAM_BUFFERS_DATA_TYPE: u32 = 6;
+const SPA_MEDIA_TYPE_VIDEO: u32 = 2;
+const SPA_MEDIA_SUBTYPE_RAW: u32 = 1;
+const SPA_VIDEO_FORMAT_BGRX: u32 = 8;
+const SPA_VIDEO_FORMAT_BGRA: u32 = 12;
+const SPA_CHOICE_RANGE: u32 = 1;
+const SPA_CHOICE_ENUM: u32 = 3;
+const SPA_CHOICE_FLAGS: u32 = 4;
+const SPA_DATA_MEM_PTR: u32 = 1;
+const SPA_DATA_MEM_FD: u32 = 2;
+const SPA_CHUNK_FLAG_CORRUPTED: i32 = 1;
+const PW_DIRECTION_INPUT: c_int = 0;
+const PW_STREAM_FLAG_AUTOCONNECT: c_int = 1;
+const PW_STREAM_FLAG_MAP_BUFFERS: c_int = 1 << 2;
+const PW_STREAM_STATE_ERROR: c_int = -1;
+const PW_STREAM_STATE_UNCONNECTED: c_int = 0;
+
+#[repr(C)]
+struct PwBuffer {
+    buffer: *mut SpaBuffer,
+    user_data: *mut c_void,
+    size: u64,
+    requested: u64,
+}
+
+#[repr(C)]
+struct SpaBuffer {
+    n_metas: u32,
+    n_datas: u32,
+    metas: *mut c_void,
+    datas: *mut SpaData,
+}
+
+#[repr(C)]
+struct SpaData {
+    kind: u32,
+    flags: u32,
+    fd: i64,
+    mapoffset: u32,
+    maxsize: u32,
+    data: *mut c_void,
+    chunk: *mut SpaChunk,
+}
+
+#[repr(C)]
+struct SpaChunk {
+    offset: u32,
+    size: u32,
+    stride: i32,
+    flags: i32,
+}
+
+/// `spa_hook`: list links, callbacks, removal hook and private data.
+#[repr(C)]
+struct SpaHook {
+    link: [*mut c_void; 2],
+    funcs: *const c_void,
+    data: *mut c_void,
+    removed: *mut c_void,
+    private: *mut c_void,
+}
+
+/// `pw_stream_events` at version 0.
+#[repr(C)]
+struct PwStreamEvents {
+    version: u32,
+    destroy: Option<unsafe extern "C" fn(*mut c_void)>,
+    state_changed: Option<unsafe extern "C" fn(*mut c_void, c_int, c_int, *const c_char)>,
+    control_info: Option<unsafe extern "C" fn(*mut c_void, u32, *const c_void)>,
+    io_changed: Option<unsafe extern "C" fn(*mut c_void, u32, *mut c_void, u32)>,
+    param_changed: Option<unsafe extern "C" fn(*mut c_void, u32, *const c_void)>,
+    add_buffer: Option<unsafe extern "C" fn(*mut c_void, *mut PwBuffer)>,
+    remove_buffer: Option<unsafe extern "C" fn(*mut c_void, *mut PwBuffer)>,
+    process: Option<unsafe extern "C" fn(*mut c_void)>,
+    drained: Option<unsafe extern "C" fn(*mut c_void)>,
+}
+
+#[link(name = "pipewire-0.3")]
+unsafe extern "C" {
+    fn pw_init(argc: *mut c_int, argv: *mut *mut *mut c_char);
+    fn pw_thread_loop_new(name: *const c_char, props: *const c_void) -> *mut c_void;
+    fn pw_thread_loop_get_loop(thread_loop: *mut c_void) -> *mut c_void;
+    fn pw_thread_loop_start(thread_loop: *mut c_void) -> c_int;
+    fn pw_thread_loop_stop(thread_loop: *mut c_void);
+    fn pw_thread_loop_destroy(thread_loop: *mut c_void);
+    fn pw_thread_loop_lock(thread_loop: *mut c_void);
+    fn pw_thread_loop_unlock(thread_loop: *mut c_void);
+    fn pw_context_new(
+        main_loop: *mut c_void,
+        props: *mut c_void,
+        user_data_size: usize,
+    ) -> *mut c_void;
+    fn pw_context_destroy(context: *mut c_void);
+    fn pw_context_connect_fd(
+        context: *mut c_void,
+        fd: c_int,
+        props: *mut c_void,
+        user_data_size: usize,
+    ) -> *mut c_void;
+    fn pw_core_disconnect(core: *mut c_void) -> c_int;
+    fn pw_properties_new(key: *const c_char, ...) -> *mut c_void;
+    fn pw_stream_new(core: *mut c_void, name: *const c_char, props: *mut c_void) -> *mut c_void;
+    fn pw_stream_add_listener(
+        stream: *mut c_void,
+        listener: *mut SpaHook,
+        events: *const PwStreamEvents,
+        data: *mut c_void,
+    );
+    fn pw_stream_connect(
+        stream: *mut c_void,
+        direction: c_int,
+        target_id: u32,
+        flags: c_int,
+        params: *mut *const c_void,
+        n_params: u32,
+    ) -> c_int;
+    fn pw_stream_update_params(
+        stream: *mut c_void,
+        params: *mut *const c_void,
+        n_params: u32,
+    ) -> c_int;
+    fn pw_stream_disconnect(stream: *mut c_void) -> c_int;
+    fn pw_stream_destroy(stream: *mut c_void);
+    fn pw_stream_dequeue_buffer(stream: *mut c_void) -> *mut PwBuffer;
+    fn pw_stream_queue_buffer(stream: *mut c_void, buffer: *mut PwBuffer) -> c_int;
+}
+
+/// A monitor shared through the portal, streamed over PipeWire.
+pub struct Capturer {
+    // Declared first so the stream stops before the portal session closes
+    stream: Stream,
+    portal: Portal,
+    /// The frame last handed out, swapped with the slot's on every frame.
+    pixels: Vec<u8>,
+}
+
+impl Capturer {
+    /// Negotiates a screencast of one monitor and connects to its stream.
+    /// Blocks while the portal's dialog is up, for up to two minutes. Fails
+    /// with `RdpStatus::PermissionDenied` when the user declines.
+    ///
+    /// With a `restore_token` from an earlier screencast, the portal shares
+    /// the same monitor again without asking.
+    pub fn open(restore_token: Option<&str>) -> Result<Capturer, RdpStatus> {
+        let portal = Portal::start(restore_token)?;
+        let fd = portal.open_remote()?;
+        let stream = Stream::connect(fd, portal.node)?;
+        let (width, height) = stream.size();
+        log::log(
+            LogLevel::Info,
+            &format!(
+                "Screencast started on PipeWire node {} ({width}x{height})",
+                portal.node
+            ),
+        );
+        Ok(Capturer {
+            stream,
+            portal,
+            pixels: Vec::new(),
+        })
+    }
+
+    /// What to pass to `open` to share the same monitor again, on portals
+    /// that support it.
+    pub fn restore_token(&self) -> Option<&str> {
+        self.portal.restore_token.as_deref()
+    }
+
+    pub fn width(&self) -> usize {
+        self.stream.size().0 as usize
+    }
+
+    pub fn height(&self) -> usize {
+        self.stream.size().1 as usize
+    }
+
+    /// The newest frame not handed out yet, or `WouldBlock` if there is
+    /// none. Fails with `ConnectionReset` once the stream has ended (the
+    /// user stopped sharing, or the compositor went away).
+    pub fn frame(&mut self) -> io::Result<&[u8]> {
+        let mut slot = self.stream.shared.lock();
+        if let Some(reason) = &slot.ended {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                reason.clone(),
+            ));
+        }
+        if !slot.fresh {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        slot.fresh = false;
+        std::mem::swap(&mut slot.pixels, &mut self.pixels);
+        drop(slot);
+        Ok(&self.pixels)
+    }
+}
+
+/// A ScreenCast session on the portal, closed on drop.
+struct Portal {
+    proxy: Screencast<'static>,
+    session: Session<'static, Screencast<'static>>,
+    /// PipeWire node of the shared monitor.
+    node: u32,
+    /// Restores this screencast's monitor choice (portal version 4 on).
+    restore_token: Option<String>,
+}
+
+impl Portal {
+    fn start(restore_token: Option<&str>) -> Result<Portal, RdpStatus> {
+        async_io::block_on(Portal::negotiate(restore_token))
+    }
+
+    async fn negotiate(restore_token: Option<&str>) -> Result<Portal, RdpStatus> {
+        let proxy = Screencast::new().await.map_err(|e| {
+            fail(
+                RdpStatus::CapturerInitFailed,
+                format!("Failed to reach the ScreenCast portal: {e}"),
+            )
+        })?;
+        let session = within("CreateSession", proxy.create_session()).await?;
+        // Owned from here on, so a failure below closes it again
+        let mut portal = Portal {
+            proxy,
+            session,
+            node: 0,
+            restore_token: None,
+        };
+
+        let version = portal.proxy.get_property::<u32>("version").await;
+        let cursor_modes = portal.proxy.available_cursor_modes().await;
+        // The portal draws the cursor, as the X11 probe cannot see it here
+        let cursor_mode = match cursor_modes {
+            Ok(modes) if modes.contains(CursorMode::Embedded) => CursorMode::Embedded,
+            _ => CursorMode::Hidden,
+        };
+        let (restore_token, persist_mode) = match version {
+            Ok(version) if version >= RESTORE_VERSION => {
+                (restore_token, PersistMode::ExplicitlyRevoked)
+            }
+            _ => (None, PersistMode::DoNot),
+        };
+        let selected = async {
+            let request = portal
+                .proxy
+                .select_sources(
+                    &portal.session,
+                    cursor_mode,
+                    SourceType::Monitor.into(),
+                    false,
+                    restore_token,
+                    persist_mode,
+                )
+                .await?;
+            request.response()
+        };
+        within("SelectSources", selected).await?;
+
+        let started = async {
+            let request = portal.proxy.start(&portal.session, None).await?;
+            request.response()
+        };
+        let streams = within("Start", started).await?;
+        portal.restore_token = streams.restore_token().map(str::to_owned);
+        portal.node = match streams.streams().first() {
+            Some(stream) => stream.pipe_wire_node_id(),
+            None => {
+                return Err(fail(
+                    RdpStatus::CapturerInitFailed,
+                    "The portal started screen sharing without a stream",
+                ));
+            }
+        };
+        Ok(portal)
+    }
+
+    /// A socket on the PipeWire instance carrying the stream.
+    fn open_remote(&self) -> Result<OwnedFd, RdpStatus> {
+        let remote = self.proxy.open_pipe_wire_remote(&self.session);
+        async_io::block_on(within("OpenPipeWireRemote", remote))
+    }
+}
+
+impl Drop for Portal {
+    fn drop(&mut self) {
+        let _ = async_io::block_on(self.session.close());
+    }
+}
+
+/// Runs the portal's `method` call, giving up after `RESPONSE_TIMEOUT`.
+/// Fails with `RdpStatus::PermissionDenied` when the user cancelled.
+async fn within<T>(
+    method: &str,
+    call: impl Future<Output = Result<T, ashpd::Error>>,
+) -> Result<T, RdpStatus> {
+    let answered = async {
+        call.await.map_err(|error| match error {
+            ashpd::Error::Response(ResponseError::Cancelled) => fail(
+                RdpStatus::PermissionDenied,
+                "Screen sharing was declined in the portal dialog",
+            ),
+            error => fail(
+                RdpStatus::CapturerInitFailed,
+                format!("The portal's {method} request failed: {error}"),
+            ),
+        })
+    };
+    let timed_out = async {
+        Timer::after(RESPONSE_TIMEOUT).await;
+        Err(fail(
+            RdpStatus::Timeout,
+            format!(
+                "No answer to the portal's {method} request within {} s",
+                RESPONSE_TIMEOUT.as_secs()
+            ),
+        ))
+    };
+    future::or(answered, timed_out).await
+}
+
+/// State shared with the stream callbacks on PipeWire's thread.
+#[derive(Default)]
+struct Shared {
+    stream: AtomicPtr<c_void>,
+    slot: Mutex<Slot>,
+    /// Signalled once the format is known or the stream has ended.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Slot {
+    /// The negotiated frame size; 0 until the format is agreed.
+    width: u32,
+    height: u32,
+    /// The newest frame, rows possibly padded, and whether it is unread.
+    pixels: Vec<u8>,
+    fresh: bool,
+    /// Why the stream stopped, once it has.
+    ended: Option<String>,
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slot> {
+        self.slot.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn end(&self, reason: String) {
+        log::log(LogLevel::Warn, &reason);
+        self.lock().ended = Some(reason);
+        self.changed.notify_all();
+    }
+
+    /// Copies the frame in `buffer` into the slot. Buffers without one (the
+    /// compositor sends empty ones when only the cursor moved) are skipped.
+    fn store(&self, buffer: &SpaBuffer) {
+        if buffer.n_datas == 0 || buffer.datas.is_null() {
+            return;
+        }
+        let data = unsafe { &*buffer.datas };
+        if data.data.is_null() || data.chunk.is_null() {
+            return;
+        }
+        let chunk = unsafe { &*data.chunk };
+        if chunk.flags & SPA_CHUNK_FLAG_CORRUPTED != 0 || chunk.size == 0 {
+            return;
+        }
+
+        let mut slot = self.lock();
+        let row = slot.width as usize * 4;
+        let stride = match chunk.stride {
+            stride if stride > 0 => stride as usize,
+            _ => row,
+        };
+        let len = stride * slot.height as usize;
+        let offset = chunk.offset as usize;
+        if len == 0 || stride < row || offset + len > data.maxsize as usize {
+            return;
+        }
+        let pixels =
+            unsafe { std::slice::from_raw_parts((data.data as *const u8).add(offset), len) };
+        slot.pixels.clear();
+        slot.pixels.extend_from_slice(pixels);
+        slot.fresh = true;
+    }
+}
+
+static EVENTS: PwStreamEvents = PwStreamEvents {
+    version: 0,
+    destroy: None,
+    state_changed: Some(on_state_changed),
+    control_info: None,
+    io_changed: None,
+    param_changed: Some(on_param_changed),
+    add_buffer: None,
+    remove_buffer: None,
+    process: Some(on_process),
+    drained: None,
+};
+
+unsafe extern "C" fn on_state_changed(
+    data: *mut c_void,
+    _old: c_int,
+    state: c_int,
+    error: *const c_char,
+) {
+    let shared = unsafe { &*(data as *const Shared) };
+    match state {
+        PW_STREAM_STATE_ERROR => {
+            let error = if error.is_null() {
+                "unknown error".into()
+            } else {
+                unsafe { CStr::from_ptr(error) }.to_string_lossy()
+            };
+            shared.end(format!("The screencast stream failed: {error}"));
+        }
+        PW_STREAM_STATE_UNCONNECTED => shared.end("The screencast stream ended".to_string()),
+        _ => {}
+    }
+}
+
+unsafe extern "C" fn on_param_changed(data: *mut c_void, id: u32, param: *const c_void) {
+    if id != SPA_PARAM_FORMAT || param.is_null() {
+        return;
+    }
+    let shared = unsafe { &*(data as *const Shared) };
+    let Some((width, height)) = (unsafe { video_size(param as *const u32) }) else {
+        return;
+    };
+    {
+        let mut slot = shared.lock();
+        slot.width = width;
+        slot.height = height;
+        // A frame of the old size would be read with the new one
+        slot.fresh = false;
+    }
+    shared.changed.notify_all();
+
+    let buffers = buffers_param();
+    let mut params = [buffers.as_ptr() as *const c_void];
+    let stream = shared.stream.load(Ordering::Acquire);
+    unsafe { pw_stream_update_params(stream, params.as_mut_ptr(), 1) };
+}
+
+unsafe extern "C" fn on_process(data: *mut c_void) {
+    let shared = unsafe { &*(data as *const Shared) };
+    let stream = shared.stream.load(Ordering::Acquire);
+    // Only the newest buffer matters; older ones go straight back
+    let mut newest: *mut PwBuffer = ptr::null_mut();
+    loop {
+        let buffer = unsafe { pw_stream_dequeue_buffer(stream) };
+        if buffer.is_null() {
+            break;
+        }
+        if !newest.is_null() {
+            unsafe { pw_stream_queue_buffer(stream, newest) };
+        }
+        newest = buffer;
+    }
+    if newest.is_null() {
+        return;
+    }
+    unsafe {
+        if !(*newest).buffer.is_null() {
+            shared.store(&*(*newest).buffer);
+        }
+        pw_stream_queue_buffer(stream, newest);
+    }
+}
+
+/// A PipeWire connection and the video stream on it, all driven by a
+/// thread loop of their own.
+struct Stream {
+    thread_loop: *mut c_void,
+    context: *mut c_void,
+    core: *mut c_void,
+    stream: *mut c_void,
+    hook: Box<SpaHook>,
+    shared: Arc<Shared>,
+}
+
+impl Stream {
+    /// Connects to `node` over `fd` and waits until the format is agreed.
+    fn connect(fd: OwnedFd, node: u32) -> Result<Stream, RdpStatus> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| unsafe { pw_init(ptr::null_mut(), ptr::null_mut()) });
+
+        let thread_loop = unsafe { pw_thread_loop_new(c"rdp-pipewire".as_ptr(), ptr::null()) };
+        if thread_loop.is_null() {
+            return Err(pipewire_failed("create a thread loop"));
+        }
+        let mut stream = Stream {
+            thread_loop,
+            context: ptr::null_mut(),
+            core: ptr::null_mut(),
+            stream: ptr::null_mut(),
+            hook: Box::new(SpaHook {
+                link: [ptr::null_mut(); 2],
+                funcs: ptr::null(),
+                data: ptr::null_mut(),
+                removed: ptr::null_mut(),
+                private: ptr::null_mut(),
+            }),
+            shared: Arc::default(),
+        };
+        unsafe {
+            stream.context =
+                pw_context_new(pw_thread_loop_get_loop(thread_loop), ptr::null_mut(), 0);
+            if stream.context.is_null() {
+                return Err(pipewire_failed("create a context"));
+            }
+            if pw_thread_loop_start(thread_loop) < 0 {
+                return Err(pipewire_failed("start its thread"));
+            }
+            pw_thread_loop_lock(thread_loop);
+            let started = stream.start(fd, node);
+            pw_thread_loop_unlock(thread_loop);
+            started?;
+        }
+
+        let slot = stream.shared.lock();
+        let (slot, _) = stream
+            .shared
+            .changed
+            .wait_timeout_while(slot, NEGOTIATE_TIMEOUT, |slot| {
+                slot.width == 0 && slot.ended.is_none()
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(reason) = &slot.ended {
+            return Err(fail(RdpStatus::CapturerInitFailed, reason.clone()));
+        }
+        if slot.width == 0 {
+            return Err(fail(
+                RdpStatus::Timeout,
+                format!(
+                    "The screencast stream agreed on no format within {} s",
+                    NEGOTIATE_TIMEOUT.as_secs()
+                ),
+            ));
+        }
+        drop(slot);
+        Ok(stream)
+    }
+
+    /// Creates and connects the stream. Called with the loop locked.
+    unsafe fn start(&mut self, fd: OwnedFd, node: u32) -> Result<(), RdpStatus> {
+        unsafe {
+            // The core owns the socket from here on, even if this fails
+            self.core = pw_context_connect_fd(self.context, fd.into_raw_fd(), ptr::null_mut(), 0);
+            if self.core.is_null() {
+                return Err(pipewire_failed("connect"));
+            }
+            let props = pw_properties_new(
+                c"media.type".as_ptr(),
+                c"Video".as_ptr(),
+                c"media.category".as_ptr(),
+                c"Capture".as_ptr(),
+                c"media.role".as_ptr(),
+                c"Screen".as_ptr(),
+                ptr::null::<c_char>(),
+            );
+            self.stream = pw_stream_new(self.core, c"rdp_core".as_ptr(), props);
+            if self.stream.is_null() {
+                return Err(pipewire_failed("create a stream"));
+            }
+            self.shared.stream.store(self.stream, Ordering::Release);
+            pw_stream_add_listener(
+                self.stream,
+                &mut *self.hook,
+                &EVENTS,
+                Arc::as_ptr(&self.shared) as *mut c_void,
+            );
+
+            let format = format_param();
+            let mut params = [format.as_ptr() as *const c_void];
+            let flags = PW_STREAM_FLAG_AUTOCONNECT | PW_STREAM_FLAG_MAP_BUFFERS;
+            if pw_stream_connect(
+                self.stream,
+                PW_DIRECTION_INPUT,
+                node,
+                flags,
+                params.as_mut_ptr(),
+                1,
+            ) < 0
+            {
+                return Err(pipewire_failed("connect the stream"));
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> (u32, u32) {
+        let slot = self.shared.lock();
+        (slot.width, slot.height)
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        unsafe {
+            pw_thread_loop_lock(self.thread_loop);
+            if !self.stream.is_null() {
+                pw_stream_disconnect(self.stream);
+                pw_stream_destroy(self.stream);
+            }
+            if !self.core.is_null() {
+                pw_core_disconnect(self.core);
+            }
+            pw_thread_loop_unlock(self.thread_loop);
+            pw_thread_loop_stop(self.thread_loop);
+            if !self.context.is_null() {
+                pw_context_destroy(self.context);
+            }
+            pw_thread_loop_destroy(self.thread_loop);
+        }
+    }
+}
+
+fn pipewire_failed(what: &str) -> RdpStatus {
+    fail(
+        RdpStatus::CapturerInitFailed,
+        format!("PipeWire failed to {what}"),
+    )
+}
+
+/// The formats offered: raw BGRx or BGRA video of any size and rate.
+fn format_param() -> Vec<u64> {
+    let props = [
+        (SPA_FORMAT_MEDIA_TYPE, id_pod(SPA_MEDIA_TYPE_VIDEO)),
+        (SPA_FORMAT_MEDIA_SUBTYPE, id_pod(SPA_MEDIA_SUBTYPE_RAW)),
+        (
+            SPA_FORMAT_VIDEO_FORMAT,
+            choice_pod(
+                SPA_CHOICE_ENUM,
+                SPA_TYPE_ID,
+                1,
+                &[
+                    SPA_VIDEO_FORMAT_BGRX,
+                    SPA_VIDEO_FORMAT_BGRX,
+                    SPA_VIDEO_FORMAT_BGRA,
+                ],
+            ),
+        ),
+        (
+            SPA_FORMAT_VIDEO_SIZE,
+            choice_pod(
+                SPA_CHOICE_RANGE,
+                SPA_TYPE_RECTANGLE,
+                2,
+                &[1920, 1080, 1, 1, 16384, 16384],
+            ),
+        ),
+        (
+            SPA_FORMAT_VIDEO_FRAMERATE,
+            choice_pod(
+                SPA_CHOICE_RANGE,
+                SPA_TYPE_FRACTION,
+                2,
+                &[60, 1, 0, 1, 1000, 1],
+            ),
+        ),
+    ];
+    object_pod(SPA_TYPE_OBJECT_FORMAT, SPA_PARAM_ENUM_FORMAT, &props)
+}
+
+/// Buffers the stream accepts: mappable memory only, no DMA-BUF.
+fn buffers_param() -> Vec<u64> {
+    let data_types = (1 << SPA_DATA_MEM_PTR) | (1 << SPA_DATA_MEM_FD);
+    let props = [(
+        SPA_PARAM_BUFFERS_DATA_TYPE,
+        choice_pod(SPA_CHOICE_FLAGS, SPA_TYPE_INT, 1, &[data_types]),
+    )];
+    object_pod(SPA_TYPE_OBJECT_PARAM_BUFFERS, SPA_PARAM_BUFFERS, &props)
+}
+
+// SPA PODs are built by hand, as libspa's builder lives entirely in inline
+// header functions. Each POD is a (size, type) header and a body padded to
+// 8 bytes; the size leaves the padding out.
+
+fn id_pod(id: u32) -> Vec<u32> {
+    vec![4, SPA_TYPE_ID, id, 0]
+}
+
+/// A choice of `kind` between `values`, each `words` 32-bit words of
+/// `child` type; the first value is the default.
+fn choice_pod(kind: u32, child: u32, words: u32, values: &[u32]) -> Vec<u32> {
+    let size = 16 + values.len() as u32 * 4;
+    let mut pod = vec![size, SPA_TYPE_CHOICE, kind, 0, words * 4, child];
+    pod.extend_from_slice(values);
+    if pod.len() % 2 == 1 {
+        pod.push(0);
+    }
+    pod
+}
+
+/// An object of `kind` for param `id` with `props`, in 8-byte-aligned
+/// storage as PipeWire expects.
+fn object_pod(kind: u32, id: u32, props: &[(u32, Vec<u32>)]) -> Vec<u64> {
+    let mut body = vec![kind, id];
+    for (key, value) in props {
+        body.extend_from_slice(&[*key, 0]);
+        body.extend_from_slice(value);
+    }
+    let mut pod = vec![body.len() as u32 * 4, SPA_TYPE_OBJECT];
+    pod.extend_from_slice(&body);
+    pod.chunks_exact(2)
+        .map(|pair| {
+            let mut bytes = [0; 8];
+            bytes[..4].copy_from_slice(&pair[0].to_ne_bytes());
+            bytes[4..].copy_from_slice(&pair[1].to_ne_bytes());
+            u64::from_ne_bytes(bytes)
+        })
+        .collect()
+}
+
+/// Reads the frame size out of a negotiated `SPA_PARAM_Format` object.
+unsafe fn video_size(pod: *const u32) -> Option<(u32, u32)> {
+    let (size, kind) = unsafe { (*pod, *pod.add(1)) };
+    if kind != SPA_TYPE_OBJECT {
+        return None;
+    }
+    let body = unsafe { std::slice::from_raw_parts(pod.add(2), size as usize / 4) };
+    // After the object's type and ID, each property is its key and flags,
+    // then its value's size, type and body
+    let mut at = 2;
+    while at + 4 <= body.len() {
+        let (key, value_size, value_kind) = (body[at], body[at + 2], body[at + 3]);
+        let end = (at + 4 + value_size as usize / 4).min(body.len());
+        if key == SPA_FORMAT_VIDEO_SIZE {
+            return match (value_kind, &body[at + 4..end]) {
+                (SPA_TYPE_RECTANGLE, [w, h, ..]) => Some((*w, *h)),
+                // A fixed choice still lists its value first
+                (SPA_TYPE_CHOICE, [_, _, _, SPA_TYPE_RECTANGLE, w, h, ..]) => Some((*w, *h)),
+                _ => None,
+            };
+        }
+        at += 4 + (value_size as usize).div_ceil(8) * 2;
+    }
+    None
+}
@@ -0,0 +1,784 @@
+//! Top-level application windows, for sessions that share one window
+//! instead of a whole display (`RdpSession::with_window`).
+//!
+//! Such a session captures the display the window is on and crops it to
+//! the window's bounds, looked up again for every frame, so it follows the
+//! window as it moves and resizes. That works the same on every platform,
+//! and it means whatever overlaps the window (another window, a menu, a
+//! notification) is captured along with it, exactly as it appears on
+//! screen. Capturing the window's own pixels would take `PrintWindow` on
+//! Windows, which is slow and comes out blank for many GPU-drawn windows,
+//! and `CGWindowListCreateImage` on macOS, which macOS 15 has obsoleted in
+//! favour of ScreenCaptureKit.
+
+use std::io;
+use std::sync::{Mutex, PoisonError};
+
+pub use platform::Tracker;
+
+/// Bytes in `WindowInfo::title`, including the terminating NUL.
+pub const TITLE_LEN: usize = 256;
+
+/// Description of one window, as reported by `rdp_window_info`.
+///
+/// Bounds are in desktop coordinates: root window pixels on X11, virtual
+/// desktop pixels on Windows (the visible frame, as DWM draws it) and
+/// global points on macOS.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowInfo {
+    /// What `rdp_session_new_for_window` takes: the X11 window, the HWND
+    /// on Windows or the CGWindowID on macOS.
+    pub id: u64,
+    /// Process owning the window; 0 when unknown (X11 clients that do not
+    /// set `_NET_WM_PID`).
+    pub pid: u32,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    /// UTF-8, NUL-terminated and cut short at a character boundary when
+    /// it does not fit. On macOS it is the owning application's name
+    /// unless the Screen Recording permission has been granted.
+    pub title: [u8; TITLE_LEN],
+}
+
+impl WindowInfo {
+    fn new(id: u64, pid: u32, bounds: Bounds, title: &str) -> WindowInfo {
+        let mut end = title.len().min(TITLE_LEN - 1);
+        while !title.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut buf = [0; TITLE_LEN];
+        buf[..end].copy_from_slice(&title.as_bytes()[..end]);
+        WindowInfo {
+            id,
+            pid,
+            x: bounds.x,
+            y: bounds.y,
+            width: bounds.width,
+            height: bounds.height,
+            title: buf,
+        }
+    }
+
+    /// `title` up to its NUL.
+    pub fn title(&self) -> &str {
+        let end = self.title.iter().position(|&b| b == 0).unwrap_or(TITLE_LEN);
+        std::str::from_utf8(&self.title[..end]).unwrap_or_default()
+    }
+}
+
+/// A window's rectangle, in the desktop coordinates of `WindowInfo`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bounds {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Bounds {
+    pub fn center(self) -> (i32, i32) {
+        (
+            self.x.saturating_add((self.width / 2) as i32),
+            self.y.saturating_add((self.height / 2) as i32),
+        )
+    }
+}
+
+/// Where a tracked window is now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placement {
+    Shown(Bounds),
+    /// Minimized, or otherwise not on screen for the moment.
+    Hidden,
+    /// The window no longer exists.
+    Closed,
+}
+
+/// The list as of the last `refresh`, so `rdp_window_info` indices keep
+/// pointing at the same windows while the host walks them.
+static LISTED: Mutex<Vec<WindowInfo>> = Mutex::new(Vec::new());
+
+/// Re-enumerates the top-level windows worth sharing, frontmost first
+/// where the platform reports the stacking order. Returns how many there
+/// are.
+pub fn refresh() -> io::Result<usize> {
+    let all = all()?;
+    let count = all.len();
+    *LISTED.lock().unwrap_or_else(PoisonError::into_inner) = all;
+    Ok(count)
+}
+
+/// The top-level windows worth sharing, as `refresh` lists them, without
+/// touching the list `rdp_window_info` walks.
+pub fn all() -> io::Result<Vec<WindowInfo>> {
+    platform::list()
+}
+
+/// Window `index` of the last `refresh`.
+pub fn listed(index: usize) -> Option<WindowInfo> {
+    LISTED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(index)
+        .copied()
+}
+
+/// Lists the clients the window manager advertises, through the same X
+/// server connection scrap would use.
+#[cfg(all(unix, not(target_os = "macos")))]
+mod platform {
+    use std::ffi::{c_char, c_void};
+    use std::io;
+    use std::ops::Deref;
+    use std::rc::Rc;
+
+    use scrap::x11::Server;
+
+    use super::{Bounds, Placement, WindowInfo};
+
+    const ATOM_NONE: u32 = 0;
+    const ATOM_WM_NAME: u32 = 39;
+    const ANY_PROPERTY_TYPE: u32 = 0;
+    const MAP_STATE_VIEWABLE: u8 = 2;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Cookie {
+        sequence: u32,
+    }
+
+    #[repr(C)]
+    struct InternAtomReply {
+        response_type: u8,
+        pad0: u8,
+        sequence: u16,
+        length: u32,
+        atom: u32,
+    }
+
+    #[repr(C)]
+    struct GetPropertyReply {
+        response_type: u8,
+        format: u8,
+        sequence: u16,
+        length: u32,
+        kind: u32,
+        bytes_after: u32,
+        value_len: u32,
+        pad0: [u8; 12],
+    }
+
+    #[repr(C)]
+    struct GetGeometryReply {
+        response_type: u8,
+        depth: u8,
+        sequence: u16,
+        length: u32,
+        root: u32,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+        border_width: u16,
+        pad0: [u8; 2],
+    }
+
+    #[repr(C)]
+    struct TranslateCoordinatesReply {
+        response_type: u8,
+        same_screen: u8,
+        sequence: u16,
+        length: u32,
+        child: u32,
+        dst_x: i16,
+        dst_y: i16,
+    }
+
+    #[repr(C)]
+    struct GetWindowAttributesReply {
+        response_type: u8,
+        backing_store: u8,
+        sequence: u16,
+        length: u32,
+        visual: u32,
+        class: u16,
+        bit_gravity: u8,
+        win_gravity: u8,
+        backing_planes: u32,
+        backing_pixel: u32,
+        save_under: u8,
+        map_is_installed: u8,
+        map_state: u8,
+        override_redirect: u8,
+        colormap: u32,
+        all_event_masks: u32,
+        your_event_mask: u32,
+        do_not_propagate_mask: u16,
+        pad0: [u8; 2],
+    }
+
+    type ReplyFn<T> = unsafe extern "C" fn(*mut c_void, Cookie, *mut *mut c_void) -> *mut T;
+
+    unsafe extern "C" {
+        fn xcb_intern_atom(
+            c: *mut c_void,
+            only_if_exists: u8,
+            name_len: u16,
+            name: *const c_char,
+        ) -> Cookie;
+        fn xcb_intern_atom_reply(
+            c: *mut c_void,
+            cookie: Cookie,
+            e: *mut *mut c_void,
+        ) -> *mut InternAtomReply;
+        fn xcb_get_property(
+            c: *mut c_void,
+            delete: u8,
+            window: u32,
+            property: u32,
+            kind: u32,
+            long_offset: u32,
+            long_length: u32,
+        ) -> Cookie;
+        fn xcb_get_property_reply(
+            c: *mut c_void,
+            cookie: Cookie,
+            e: *mut *mut c_void,
+        ) -> *mut GetPropertyReply;
+        fn xcb_get_property_value(reply: *const GetPropertyReply) -> *const u8;
+        fn xcb_get_property_value_length(reply: *const GetPropertyReply) -> i32;
+        fn xcb_get_geometry(c: *mut c_void, drawable: u32) -> Cookie;
+        fn xcb_get_geometry_reply(
+            c: *mut c_void,
+            cookie: Cookie,
+            e: *mut *mut c_void,
+        ) -> *mut GetGeometryReply;
+        fn xcb_translate_coordinates(
+            c: *mut c_void,
+            src_window: u32,
+            dst_window: u32,
+            src_x: i16,
+            src_y: i16,
+        ) -> Cookie;
+        fn xcb_translate_coordinates_reply(
+            c: *mut c_void,
+            cookie: Cookie,
+            e: *mut *mut c_void,
+        ) -> *mut TranslateCoordinatesReply;
+        fn xcb_get_window_attributes(c: *mut c_void, window: u32) -> Cookie;
+        fn xcb_get_window_attributes_reply(
+            c: *mut c_void,
+            cookie: Cookie,
+            e: *mut *mut c_void,
+        ) -> *mut GetWindowAttributesReply;
+        fn free(ptr: *mut c_void);
+    }
+
+    /// A reply xcb allocated, freed on drop.
+    struct Reply<T>(*mut T);
+
+    impl<T> Deref for Reply<T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.0 }
+        }
+    }
+
+    impl<T> Drop for Reply<T> {
+        fn drop(&mut self) {
+            unsafe { free(self.0.cast()) };
+        }
+    }
+
+    struct Connection {
+        server: Rc<Server>,
+        root: u32,
+    }
+
+    impl Connection {
+        fn open() -> io::Result<Connection> {
+            let server = Rc::new(
+                Server::default().map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?,
+            );
+            let mut displays = Server::displays(server.clone());
+            let root = displays
+                .find(|d| d.is_default())
+                .map(|d| d.root())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            Ok(Connection { server, root })
+        }
+
+        /// Waits for the reply to `cookie`; `None` if the request failed,
+        /// e.g. because the window is gone.
+        fn reply<T>(&self, cookie: Cookie, read: ReplyFn<T>) -> Option<Reply<T>> {
+            let reply = unsafe { read(self.server.raw().cast(), cookie, std::ptr::null_mut()) };
+            (!reply.is_null()).then_some(Reply(reply))
+        }
+
+        fn atom(&self, name: &str) -> u32 {
+            let cookie = unsafe {
+                xcb_intern_atom(
+                    self.server.raw().cast(),
+                    0,
+                    name.len() as u16,
+                    name.as_ptr().cast(),
+                )
+            };
+            self.reply(cookie, xcb_intern_atom_reply)
+                .map_or(ATOM_NONE, |reply| reply.atom)
+        }
+
+        /// The raw value of `property` on `window`, whatever its type.
+        fn property(&self, window: u32, property: u32) -> Option<Vec<u8>> {
+            let cookie = unsafe {
+                xcb_get_property(
+                    self.server.raw().cast(),
+                    0,
+                    window,
+                    property,
+                    ANY_PROPERTY_TYPE,
+                    0,
+                    u32::MAX / 4,
+                )
+            };
+            let reply = self.reply(cookie, xcb_get_property_reply)?;
+            if reply.kind == ATOM_NONE {
+                return None;
+            }
+            unsafe {
+                let len = xcb_get_property_value_length(&*reply).max(0) as usize;
+                Some(std::slice::from_raw_parts(xcb_get_property_value(&*reply), len).to_vec())
+            }
+        }
+
+        /// A property of 32-bit values (windows, cardinals).
+        fn words(&self, window: u32, property: u32) -> Vec<u32> {
+            self.property(window, property)
+                .unwrap_or_default()
+                .chunks_exact(4)
+                .map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+                .collect()
+        }
+
+        /// Client area of `window` on the root window, and whether it is
+        /// mapped; `None` once the server no longer knows the window.
+        fn locate(&self, window: u32) -> Option<(Bounds, bool)> {
+            let conn = self.server.raw().cast();
+            let attributes = unsafe { xcb_get_window_attributes(conn, window) };
+            let geometry = unsafe { xcb_get_geometry(conn, window) };
+            let origin = unsafe { xcb_translate_coordinates(conn, window, self.root, 0, 0) };
+            let attributes = self.reply(attributes, xcb_get_window_attributes_reply);
+            let geometry = self.reply(geometry, xcb_get_geometry_reply);
+            let origin = self.reply(origin, xcb_translate_coordinates_reply);
+            let (attributes, geometry, origin) = (attributes?, geometry?, origin?);
+            let bounds = Bounds {
+                x: i32::from(origin.dst_x),
+                y: i32::from(origin.dst_y),
+                width: u32::from(geometry.width),
+                height: u32::from(geometry.height),
+            };
+            Some((bounds, attributes.map_state == MAP_STATE_VIEWABLE))
+        }
+
+        fn placement(&self, window: u32) -> Placement {
+            match self.locate(window) {
+                Some((bounds, true)) => Placement::Shown(bounds),
+                // Minimized windows are unmapped by the window manager
+                Some((_, false)) => Placement::Hidden,
+                None => Placement::Closed,
+            }
+        }
+    }
+
+    pub fn list() -> io::Result<Vec<WindowInfo>> {
+        let conn = Connection::open()?;
+        // Bottom to top; the unordered list is the fallback
+        let mut clients = conn.words(conn.root, conn.atom("_NET_CLIENT_LIST_STACKING"));
+        if clients.is_empty() {
+            clients = conn.words(conn.root, conn.atom("_NET_CLIENT_LIST"));
+        }
+        if clients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the window manager does not list its windows",
+            ));
+        }
+
+        let net_wm_name = conn.atom("_NET_WM_NAME");
+        let net_wm_pid = conn.atom("_NET_WM_PID");
+        Ok(clients
+            .into_iter()
+            .rev()
+            .filter_map(|window| {
+                let (bounds, _) = conn.locate(window)?;
+                let title = conn
+                    .property(window, net_wm_name)
+                    .or_else(|| conn.property(window, ATOM_WM_NAME))
+                    .unwrap_or_default();
+                let pid = conn.words(window, net_wm_pid).first().copied().unwrap_or(0);
+                Some(WindowInfo::new(
+                    u64::from(window),
+                    pid,
+                    bounds,
+                    &String::from_utf8_lossy(&title),
+                ))
+            })
+            .collect())
+    }
+
+    /// Looks a window up on a connection of its own, kept for the life of
+    /// the session.
+    pub struct Tracker {
+        conn: Connection,
+        window: u32,
+    }
+
+    impl Tracker {
+        pub fn new(id: u64) -> io::Result<Tracker> {
+            let window = u32::try_from(id)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not an X11 window"))?;
+            Ok(Tracker {
+                conn: Connection::open()?,
+                window,
+            })
+        }
+
+        pub fn placement(&self) -> Placement {
+            self.conn.placement(self.window)
+        }
+    }
+}
+
+/// Lists the windows `EnumWindows` reports that would show up in the task
+/// switcher: visible, not cloaked by DWM (e.g. on another virtual
+/// desktop), unowned and not tool windows.
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+    use std::io;
+
+    use super::{Bounds, Placement, WindowInfo};
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Rect {
+        left: i32,
+        top: i32,
+        right: i32,
+        bottom: i32,
+    }
+
+    type EnumWindowsProc = unsafe extern "system" fn(*mut c_void, isize) -> i32;
+
+    #[link(name = "user32")]
+    unsafe extern "system" {
+        fn EnumWindows(callback: EnumWindowsProc, data: isize) -> i32;
+        fn IsWindow(window: *mut c_void) -> i32;
+        fn IsWindowVisible(window: *mut c_void) -> i32;
+        fn IsIconic(window: *mut c_void) -> i32;
+        fn GetWindow(window: *mut c_void, cmd: u32) -> *mut c_void;
+        fn GetWindowLongW(window: *mut c_void, index: i32) -> i32;
+        fn GetWindowTextW(window: *mut c_void, text: *mut u16, max: i32) -> i32;
+        fn GetWindowThreadProcessId(window: *mut c_void, pid: *mut u32) -> u32;
+        fn GetWindowRect(window: *mut c_void, rect: *mut Rect) -> i32;
+    }
+
+    #[link(name = "dwmapi")]
+    unsafe extern "system" {
+        fn DwmGetWindowAttribute(
+            window: *mut c_void,
+            attribute: u32,
+            value: *mut c_void,
+            size: u32,
+        ) -> i32;
+    }
+
+    const GW_OWNER: u32 = 4;
+    const GWL_EXSTYLE: i32 = -20;
+    const WS_EX_TOOLWINDOW: i32 = 0x80;
+    const DWMWA_EXTENDED_FRAME_BOUNDS: u32 = 9;
+    const DWMWA_CLOAKED: u32 = 14;
+
+    unsafe extern "system" fn collect(window: *mut c_void, data: isize) -> i32 {
+        let found = unsafe { &mut *(data as *mut Vec<*mut c_void>) };
+        found.push(window);
+        1
+    }
+
+    fn cloaked(window: *mut c_void) -> bool {
+        let mut cloaked = 0u32;
+        let hr = unsafe {
+            DwmGetWindowAttribute(
+                window,
+                DWMWA_CLOAKED,
+                (&mut cloaked as *mut u32).cast(),
+                std::mem::size_of::<u32>() as u32,
+            )
+        };
+        hr == 0 && cloaked != 0
+    }
+
+    /// The frame DWM draws, without the invisible resize borders
+    /// `GetWindowRect` includes since Windows 10.
+    fn bounds(window: *mut c_void) -> Option<Bounds> {
+        let mut rect = Rect::default();
+        let hr = unsafe {
+            DwmGetWindowAttribute(
+                window,
+                DWMWA_EXTENDED_FRAME_BOUNDS,
+                (&mut rect as *mut Rect).cast(),
+                std::mem::size_of::<Rect>() as u32,
+            )
+        };
+        if hr != 0 && unsafe { GetWindowRect(window, &mut rect) } == 0 {
+            return None;
+        }
+        Some(Bounds {
+            x: rect.left,
+            y: rect.top,
+            width: rect.right.saturating_sub(rect.left).max(0) as u32,
+            height: rect.bottom.saturating_sub(rect.top).max(0) as u32,
+        })
+    }
+
+    fn title(window: *mut c_void) -> String {
+        let mut text = [0u16; 512];
+        let len = unsafe { GetWindowTextW(window, text.as_mut_ptr(), text.len() as i32) };
+        String::from_utf16_lossy(&text[..len.max(0) as usize])
+    }
+
+    pub fn list() -> io::Result<Vec<WindowInfo>> {
+        let mut windows: Vec<*mut c_void> = Vec::new();
+        if unsafe { EnumWindows(collect, &mut windows as *mut _ as isize) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(windows
+            .into_iter()
+            .filter(|&window| unsafe {
+                IsWindowVisible(window) != 0
+                    && GetWindow(window, GW_OWNER).is_null()
+                    && GetWindowLongW(window, GWL_EXSTYLE) & WS_EX_TOOLWINDOW == 0
+            })
+            .filter(|&window| !cloaked(window))
+            .filter_map(|window| {
+                let title = title(window);
+                if title.is_empty() {
+                    return None;
+                }
+                let mut pid = 0;
+                unsafe { GetWindowThreadProcessId(window, &mut pid) };
+                Some(WindowInfo::new(window as u64, pid, bounds(window)?, &title))
+            })
+            .collect())
+    }
+
+    pub struct Tracker {
+        window: *mut c_void,
+    }
+
+    impl Tracker {
+        pub fn new(id: u64) -> io::Result<Tracker> {
+            Ok(Tracker {
+                window: id as usize as *mut c_void,
+            })
+        }
+
+        pub fn placement(&self) -> Placement {
+            let window = self.window;
+            if unsafe { IsWindow(window) } == 0 {
+                return Placement::Closed;
+            }
+            if unsafe { IsWindowVisible(window) == 0 || IsIconic(window) != 0 } || cloaked(window) {
+                return Placement::Hidden;
+            }
+            bounds(window).map_or(Placement::Hidden, Placement::Shown)
+        }
+    }
+}
+
+/// Lists the normal (layer 0) windows `CGWindowListCopyWindowInfo`
+/// reports on screen, which leaves out the menu bar, Dock and desktop.
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::ffi::{c_char, c_void};
+    use std::io;
+
+    use super::{Bounds, Placement, WindowInfo};
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct CGRect {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    }
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    unsafe extern "C" {
+        fn CGWindowListCopyWindowInfo(option: u32, relative_to: u32) -> *const c_void;
+        fn CGRectMakeWithDictionaryRepresentation(dict: *const c_void, rect: *mut CGRect) -> bool;
+        static kCGWindowNumber: *const c_void;
+        static kCGWindowOwnerPID: *const c_void;
+        static kCGWindowOwnerName: *const c_void;
+        static kCGWindowName: *const c_void;
+        static kCGWindowBounds: *const c_void;
+        static kCGWindowLayer: *const c_void;
+        static kCGWindowIsOnscreen: *const c_void;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    unsafe extern "C" {
+        fn CFArrayGetCount(array: *const c_void) -> isize;
+        fn CFArrayGetValueAtIndex(array: *const c_void, index: isize) -> *const c_void;
+        fn CFDictionaryGetValue(dict: *const c_void, key: *const c_void) -> *const c_void;
+        fn CFNumberGetValue(number: *const c_void, kind: isize, value: *mut c_void) -> bool;
+        fn CFBooleanGetValue(boolean: *const c_void) -> bool;
+        fn CFStringGetCString(
+            string: *const c_void,
+            buffer: *mut c_char,
+            size: isize,
+            encoding: u32,
+        ) -> bool;
+        fn CFRelease(cf: *const c_void);
+    }
+
+    const ON_SCREEN_ONLY: u32 = 1;
+    const INCLUDING_WINDOW: u32 = 8;
+    const EXCLUDE_DESKTOP_ELEMENTS: u32 = 16;
+    const NUMBER_SINT64: isize = 4;
+    const STRING_ENCODING_UTF8: u32 = 0x0800_0100;
+
+    /// One window's description dictionary, borrowed from the list.
+    #[derive(Clone, Copy)]
+    struct Description(*const c_void);
+
+    impl Description {
+        fn value(self, key: *const c_void) -> Option<*const c_void> {
+            let value = unsafe { CFDictionaryGetValue(self.0, key) };
+            (!value.is_null()).then_some(value)
+        }
+
+        fn number(self, key: *const c_void) -> Option<i64> {
+            let mut number = 0i64;
+            let value = self.value(key)?;
+            unsafe { CFNumberGetValue(value, NUMBER_SINT64, (&mut number as *mut i64).cast()) }
+                .then_some(number)
+        }
+
+        fn string(self, key: *const c_void) -> Option<String> {
+            let mut buf = [0 as c_char; 1024];
+            let value = self.value(key)?;
+            if !unsafe {
+                CFStringGetCString(
+                    value,
+                    buf.as_mut_ptr(),
+                    buf.len() as isize,
+                    STRING_ENCODING_UTF8,
+                )
+            } {
+                return None;
+            }
+            let bytes: Vec<u8> = buf
+                .iter()
+                .take_while(|&&c| c != 0)
+                .map(|&c| c as u8)
+                .collect();
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        }
+
+        fn on_screen(self) -> bool {
+            self.value(unsafe { kCGWindowIsOnscreen })
+                .is_some_and(|value| unsafe { CFBooleanGetValue(value) })
+        }
+
+        fn bounds(self) -> Option<Bounds> {
+            let mut rect = CGRect::default();
+            let dict = self.value(unsafe { kCGWindowBounds })?;
+            if !unsafe { CGRectMakeWithDictionaryRepresentation(dict, &mut rect) } {
+                return None;
+            }
+            Some(Bounds {
+                x: rect.x.round() as i32,
+                y: rect.y.round() as i32,
+                width: rect.width.round().max(0.0) as u32,
+                height: rect.height.round().max(0.0) as u32,
+            })
+        }
+    }
+
+    /// Runs `read` on every entry of a `CGWindowListCopyWindowInfo` list.
+    fn windows<T>(
+        option: u32,
+        relative_to: u32,
+        read: impl FnMut(Description) -> Option<T>,
+    ) -> io::Result<Vec<T>> {
+        let list = unsafe { CGWindowListCopyWindowInfo(option, relative_to) };
+        if list.is_null() {
+            return Err(io::Error::other(
+                "the window server returned no window list",
+            ));
+        }
+        let count = unsafe { CFArrayGetCount(list) };
+        let found = (0..count)
+            .map(|i| Description(unsafe { CFArrayGetValueAtIndex(list, i) }))
+            .filter_map(read)
+            .collect();
+        unsafe { CFRelease(list) };
+        Ok(found)
+    }
+
+    pub fn list() -> io::Result<Vec<WindowInfo>> {
+        windows(ON_SCREEN_ONLY | EXCLUDE_DESKTOP_ELEMENTS, 0, |window| {
+            if window.number(unsafe { kCGWindowLayer }) != Some(0) {
+                return None;
+            }
+            let id = window.number(unsafe { kCGWindowNumber })?;
+            let pid = window.number(unsafe { kCGWindowOwnerPID }).unwrap_or(0);
+            // Titles need the Screen Recording permission
+            let title = window
+                .string(unsafe { kCGWindowName })
+                .filter(|title| !title.is_empty())
+                .or_else(|| window.string(unsafe { kCGWindowOwnerName }))
+                .unwrap_or_default();
+            Some(WindowInfo::new(
+                id as u64,
+                pid as u32,
+                window.bounds()?,
+                &title,
+            ))
+        })
+    }
+
+    pub struct Tracker {
+        id: u32,
+    }
+
+    impl Tracker {
+        pub fn new(id: u64) -> io::Result<Tracker> {
+            let id = u32::try_from(id)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not a CGWindowID"))?;
+            Ok(Tracker { id })
+        }
+
+        pub fn placement(&self) -> Placement {
+            let found = windows(INCLUDING_WINDOW, self.id, |window| {
+                (window.number(unsafe { kCGWindowNumber }) == Some(i64::from(self.id))).then(|| {
+                    match window.bounds() {
+                        Some(bounds) if window.on_screen() => Placement::Shown(bounds),
+                        _ => Placement::Hidden,
+                    }
+                })
+            });
+            match found {
+                Ok(found) => found.into_iter().next().unwrap_or(Placement::Closed),
+                // The window server is busy or gone; try again next frame
+                Err(_) => Placement::Hidden,
+            }
+        }
+    }
+}
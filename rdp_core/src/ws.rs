@@ -0,0 +1,506 @@
+//! A WebSocket (RFC 6455) endpoint for browser viewers, built with the
+//! `websocket` feature. Clients connect on any path; after the handshake
+//! they receive one binary message per frame, starting with a 32-byte
+//! little-endian header:
+//!
+//! ```text
+//! u32 format         RawImage::format
+//! u32 flags          bit 0: RawImage::keyframe, bit 1: RawImage::encrypted,
+//!                    bit 2: RawImage::progressive
+//! u64 sequence       RawImage::sequence
+//! u64 timestamp_us   RawImage::timestamp_us
+//! u32 width          RawImage::width
+//! u32 height         RawImage::height
+//! [u8]               the frame, in the session's output format
+//! ```
+//!
+//! (JS: `new DataView(message)`, `getUint32(0, true)` and so on.) Frames
+//! are shared with the other clients the way the `tcp` module describes:
+//! by default a slow client skips to the newest frame. With drop reports on
+//! (`rdp_session_set_drop_reports`), a client that missed frames gets a
+//! text message `{"dropped": N}` before its next frame, `N` counting every
+//! frame it has missed since it connected.
+//!
+//! Clients can steer the session with JSON text messages, applied to the
+//! session live (and so to every client). All members are optional:
+//!
+//! - `"width"`, `"height"`: output size of the frames, as with
+//!   `rdp_session_set_output_size`; both 0 goes back to the native size.
+//! - `"quality"`: JPEG quality, 1–100.
+//! - `"keyframe": true`: make the next frame a keyframe, e.g. after a
+//!   client joins a delta stream.
+//!
+//! With an access token set (`rdp_server_set_auth_token`), the upgrade
+//! request must carry it, as a `?token=` query parameter (browsers cannot
+//! set headers on a WebSocket) or an `Authorization: Bearer` header;
+//! otherwise it gets a 401.
+//!
+//! Invalid messages are logged and ignored. Pings are answered, and when
+//! the server stops every client gets a close frame (1001, going away).
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::base64;
+use crate::broadcast::{Broadcast, Subscription};
+use crate::error::{RdpStatus, fail_at};
+use crate::frame::EncodedFrame;
+use crate::http::{self, Request};
+use crate::json;
+use crate::log::{self, LogLevel};
+use crate::server::Conn;
+use crate::session::RdpSession;
+
+/// Appended to the client's key to derive `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest a client may take over the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client that accepts nothing for this long is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client has to answer our close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Largest message accepted from a client; control messages are tiny.
+const MAX_MESSAGE: usize = 64 * 1024;
+
+/// Size of the header in front of every frame.
+const HEADER_LEN: usize = 32;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Close codes.
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const TOO_BIG: u16 = 1009;
+
+/// Serves one client: streams `broadcast` to it and applies its control
+/// messages to `session`. Used as the `server::Handler`, with the session
+/// bound in.
+pub fn serve(mut conn: Conn, broadcast: &Broadcast, session: &Mutex<RdpSession>) -> io::Result<()> {
+    conn.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    conn.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    conn.set_nodelay(true)?;
+    if !handshake(&mut conn)? {
+        return Ok(());
+    }
+    conn.set_read_timeout(None)?;
+
+    let sender = Mutex::new(Sender {
+        conn: conn.try_clone()?,
+        closed: false,
+    });
+    let frames = broadcast.subscribe();
+    // Set once the reader is done, so the writer stops waiting for frames
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let reader = scope.spawn(|| {
+            let received = receive(&mut conn, &sender, session);
+            done.store(true, Ordering::Release);
+            frames.wake();
+            received
+        });
+
+        let sent = send_frames(&sender, &frames, broadcast.reports_drops(), &done);
+        if sent.is_ok() {
+            // Says goodbye (unless the client already did) and gives the
+            // client a moment to answer before the socket goes
+            let _ = lock(&sender).close(Some(GOING_AWAY));
+            let deadline = Instant::now() + CLOSE_TIMEOUT;
+            while !reader.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        // Unblocks the reader if it is still waiting
+        let _ = lock(&sender).conn.shutdown(Shutdown::Both);
+        let received = reader.join().unwrap_or(Ok(()));
+        sent.and(received)
+    })
+}
+
+/// Answers the upgrade request; `false` if it was not one (the client got
+/// a 400 instead). Fails with `PermissionDenied` for a bad token.
+fn handshake(conn: &mut Conn) -> io::Result<bool> {
+    let request = Request::read(conn)?;
+    let upgrade = request
+        .header("upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let key = request.header("sec-websocket-key");
+    let (true, Some(key)) = (request.method == "GET" && upgrade, key) else {
+        http::respond(
+            conn,
+            "400 Bad Request",
+            "text/plain",
+            b"WebSocket upgrade expected\n",
+        )?;
+        return Ok(false);
+    };
+    if !request.authorized() {
+        return http::unauthorized(conn).map(|()| false);
+    }
+    if request.header("sec-websocket-version") != Some("13") {
+        http::respond(
+            conn,
+            "400 Bad Request",
+            "text/plain",
+            b"WebSocket version 13 only\n",
+        )?;
+        return Ok(false);
+    }
+
+    let accept = base64::encode(&sha1(format!("{key}{ACCEPT_GUID}").as_bytes()));
+    write!(
+        conn,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {accept}\r\n\r\n"
+    )?;
+    conn.flush()?;
+    Ok(true)
+}
+
+/// Sends the client's frames, preceded by drop reports if `report_drops`,
+/// until the broadcast closes, `done` is set or a close frame has gone out.
+fn send_frames(
+    sender: &Mutex<Sender>,
+    frames: &Subscription,
+    report_drops: bool,
+    done: &AtomicBool,
+) -> io::Result<()> {
+    let mut reported = 0;
+    while let Some(frame) = frames.next_unless(|| done.load(Ordering::Acquire)) {
+        let mut sender = lock(sender);
+        if sender.closed {
+            break;
+        }
+        let dropped = frames.dropped();
+        if report_drops && dropped > reported {
+            reported = dropped;
+            sender.send(
+                OP_TEXT,
+                &[],
+                format!("{{\"dropped\":{dropped}}}").as_bytes(),
+            )?;
+        }
+        sender.send(OP_BINARY, &header(&frame), &frame.data)?;
+    }
+    Ok(())
+}
+
+/// The header in front of `frame`'s payload (see the module docs).
+fn header(frame: &EncodedFrame) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[0..4].copy_from_slice(&(frame.format as u32).to_le_bytes());
+    let flags = u32::from(frame.keyframe)
+        | u32::from(frame.encrypted) << 1
+        | u32::from(frame.progressive) << 2;
+    header[4..8].copy_from_slice(&flags.to_le_bytes());
+    header[8..16].copy_from_slice(&frame.sequence.to_le_bytes());
+    header[16..24].copy_from_slice(&frame.timestamp_us.to_le_bytes());
+    header[24..28].copy_from_slice(&frame.width.to_le_bytes());
+    header[28..32].copy_from_slice(&frame.height.to_le_bytes());
+    header
+}
+
+/// The write half of a connection; shared, since the reader answers pings
+/// and close frames while the writer sends frames.
+struct Sender {
+    conn: Conn,
+    /// A close frame went out; nothing may follow it.
+    closed: bool,
+}
+
+impl Sender {
+    /// Sends `prefix` and `body` as one unfragmented message.
+    fn send(&mut self, opcode: u8, prefix: &[u8], body: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        // Server frames are never masked
+        let len = prefix.len() + body.len();
+        let mut head = Vec::with_capacity(10 + prefix.len());
+        head.push(0x80 | opcode);
+        match len {
+            0..=125 => head.push(len as u8),
+            126..=0xffff => {
+                head.push(126);
+                head.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                head.push(127);
+                head.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        head.extend_from_slice(prefix);
+        self.conn.write_all(&head)?;
+        self.conn.write_all(body)?;
+        self.conn.flush()
+    }
+
+    /// Sends a close frame carrying `code`, unless one was sent already.
+    fn close(&mut self, code: Option<u16>) -> io::Result<()> {
+        let payload = code.map(u16::to_be_bytes);
+        let sent = self.send(OP_CLOSE, payload.as_ref().map_or(&[], |p| p), &[]);
+        self.closed = true;
+        sent
+    }
+}
+
+/// What ended reading from a client.
+enum Fault {
+    Io(io::Error),
+    /// The client broke the protocol; it is sent a close frame with this
+    /// code.
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for Fault {
+    fn from(e: io::Error) -> Fault {
+        Fault::Io(e)
+    }
+}
+
+/// Reads the client's messages until it closes the connection, answering
+/// pings and applying control messages to `session`.
+fn receive(conn: &mut Conn, sender: &Mutex<Sender>, session: &Mutex<RdpSession>) -> io::Result<()> {
+    match read_messages(conn, sender, session) {
+        Ok(()) => Ok(()),
+        Err(Fault::Io(e)) => Err(e),
+        Err(Fault::Protocol(code, why)) => {
+            let _ = lock(sender).close(Some(code));
+            Err(io::Error::new(io::ErrorKind::InvalidData, why))
+        }
+    }
+}
+
+fn read_messages(
+    conn: &mut Conn,
+    sender: &Mutex<Sender>,
+    session: &Mutex<RdpSession>,
+) -> Result<(), Fault> {
+    let mut message = Vec::new();
+    // Opcode of the message `message` is collecting, while fragmented
+    let mut kind = None;
+    loop {
+        let frame = read_frame(conn)?;
+        match frame.opcode {
+            OP_PING => lock(sender).send(OP_PONG, &[], &frame.payload)?,
+            OP_PONG => {}
+            OP_CLOSE => {
+                // Echoes the client's code, completing the close handshake
+                let code = frame
+                    .payload
+                    .get(..2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]));
+                lock(sender).close(code)?;
+                return Ok(());
+            }
+            OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                kind = match (kind, frame.opcode) {
+                    (None, OP_TEXT | OP_BINARY) => Some(frame.opcode),
+                    (Some(kind), OP_CONTINUATION) => Some(kind),
+                    _ => return Err(Fault::Protocol(PROTOCOL_ERROR, "bad fragmentation")),
+                };
+                if message.len() + frame.payload.len() > MAX_MESSAGE {
+                    return Err(Fault::Protocol(TOO_BIG, "message too big"));
+                }
+                message.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    if kind == Some(OP_TEXT) {
+                        // Holds the session only while applying the message
+                        if let Ok(control) = Control::parse(&message) {
+                            control.apply(&mut lock(session));
+                        }
+                    } else {
+                        log::log(LogLevel::Debug, "Ignoring binary WebSocket message");
+                    }
+                    message.clear();
+                    kind = None;
+                }
+            }
+            _ => return Err(Fault::Protocol(PROTOCOL_ERROR, "unknown opcode")),
+        }
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    /// Unmasked.
+    payload: Vec<u8>,
+}
+
+fn read_frame(conn: &mut impl Read) -> Result<Frame, Fault> {
+    let mut head = [0u8; 2];
+    conn.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    if head[0] & 0x70 != 0 {
+        return Err(Fault::Protocol(PROTOCOL_ERROR, "reserved bits set"));
+    }
+    if head[1] & 0x80 == 0 {
+        return Err(Fault::Protocol(PROTOCOL_ERROR, "unmasked client frame"));
+    }
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            conn.read_exact(&mut len)?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            conn.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    if opcode >= OP_CLOSE && (!fin || len > 125) {
+        return Err(Fault::Protocol(PROTOCOL_ERROR, "invalid control frame"));
+    }
+    if len > MAX_MESSAGE as u64 {
+        return Err(Fault::Protocol(TOO_BIG, "message too big"));
+    }
+
+    let mut mask = [0u8; 4];
+    conn.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    conn.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+/// A client's control message, checked in full before any of it is
+/// applied.
+#[derive(Default)]
+struct Control {
+    output_size: Option<(u32, u32)>,
+    quality: Option<u8>,
+    keyframe: bool,
+}
+
+impl Control {
+    /// Parses a text message; failures are logged.
+    fn parse(text: &[u8]) -> Result<Control, RdpStatus> {
+        let invalid = |why: &str| {
+            fail_at(
+                LogLevel::Warn,
+                RdpStatus::InvalidArgument,
+                format!("Ignoring WebSocket control message: {why}"),
+            )
+        };
+        let text = std::str::from_utf8(text).map_err(|_| invalid("not UTF-8"))?;
+        let message = json::parse(text).map_err(|e| invalid(&e))?;
+        if !matches!(message, json::Value::Object(_)) {
+            return Err(invalid("not an object"));
+        }
+
+        let mut control = Control::default();
+        match (message.get("width"), message.get("height")) {
+            (None, None) => {}
+            (Some(w), Some(h)) => {
+                let (Some(w), Some(h)) = (w.as_u32(), h.as_u32()) else {
+                    return Err(invalid("width and height must be whole numbers"));
+                };
+                if (w == 0) != (h == 0) {
+                    return Err(invalid("width and height must both be 0 or neither"));
+                }
+                control.output_size = Some((w, h));
+            }
+            _ => return Err(invalid("width and height go together")),
+        }
+        if let Some(quality) = message.get("quality") {
+            let quality = quality
+                .as_u32()
+                .filter(|q| (1..=100).contains(q))
+                .ok_or_else(|| invalid("quality must be 1-100"))?;
+            control.quality = Some(quality as u8);
+        }
+        if let Some(keyframe) = message.get("keyframe") {
+            control.keyframe = keyframe
+                .as_bool()
+                .ok_or_else(|| invalid("keyframe must be true or false"))?;
+        }
+        Ok(control)
+    }
+
+    fn apply(self, session: &mut RdpSession) {
+        if let Some((w, h)) = self.output_size {
+            // Checked in `parse`
+            let _ = session.set_output_size(w, h);
+        }
+        if let Some(quality) = self.quality {
+            session.set_quality(quality);
+        }
+        if self.keyframe {
+            session.request_keyframe();
+        }
+    }
+}
+
+/// SHA-1, which the handshake needs for `Sec-WebSocket-Accept` (and only
+/// for that; it is not used for anything secret).
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+        }
+        for (state, add) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,400 @@
+//! YUV 4:2:0 conversion feeding the video encoders and the `I420` and
+//! `NV12` output formats, limited range. I420 is a Y plane followed by U
+//! and V planes of half the width and height; NV12 has the same Y plane
+//! followed by one half-height plane of interleaved U and V. The video
+//! encoders always use BT.601, which is what H.264 and VPx decoders assume
+//! when a stream does not say otherwise; the outputs can use BT.709.
+
+use crate::frame::{FrameFormat, PixelFormat};
+use crate::simd::{self, ConvertPath};
+
+/// The RGB to YUV matrix of I420 and NV12 output.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum YuvMatrix {
+    /// Standard definition, and what decoders assume by default.
+    #[default]
+    Bt601 = 0,
+    /// High definition.
+    Bt709 = 1,
+}
+
+impl YuvMatrix {
+    /// Maps an FFI matrix value back onto the enum.
+    pub fn from_u32(value: u32) -> Option<YuvMatrix> {
+        match value {
+            0 => Some(YuvMatrix::Bt601),
+            1 => Some(YuvMatrix::Bt709),
+            _ => None,
+        }
+    }
+
+    /// 8-bit fixed-point (x256) weights of R, G and B in Y, U and V, scaled
+    /// to the limited range (Y 16–235, U and V 16–240).
+    fn weights(self) -> [[i32; 3]; 3] {
+        match self {
+            YuvMatrix::Bt601 => [[66, 129, 25], [-38, -74, 112], [112, -94, -18]],
+            YuvMatrix::Bt709 => [[47, 157, 16], [-26, -86, 112], [112, -102, -10]],
+        }
+    }
+
+    /// The way back, x256: the weight of Y (once 16 is taken off), of V in
+    /// R, of U and V in G, and of U in B.
+    fn inverse(self) -> [i32; 5] {
+        match self {
+            YuvMatrix::Bt601 => [298, 409, -100, -208, 516],
+            YuvMatrix::Bt709 => [298, 459, -55, -136, 541],
+        }
+    }
+}
+
+/// Whether `format` is one of the YUV outputs, `I420` or `NV12`.
+pub fn is_yuv(format: FrameFormat) -> bool {
+    matches!(format, FrameFormat::I420 | FrameFormat::Nv12)
+}
+
+/// Output size for a `width x height` source. 4:2:0 needs even dimensions,
+/// so an odd last column or row is dropped.
+pub fn even_size(width: u32, height: u32) -> (u32, u32) {
+    (width & !1, height & !1)
+}
+
+/// Where the planes of an `I420` or `NV12` frame of `even_size` `width x
+/// height` start in its buffer, and the bytes per row of each: Y, U and V
+/// for I420, Y and interleaved UV (with a zero third entry) for NV12.
+/// `None` for other formats.
+pub fn planes(format: FrameFormat, width: u32, height: u32) -> Option<([u64; 3], [u32; 3])> {
+    let luma_len = u64::from(width) * u64::from(height);
+    match format {
+        FrameFormat::I420 => Some((
+            [0, luma_len, luma_len + luma_len / 4],
+            [width, width / 2, width / 2],
+        )),
+        FrameFormat::Nv12 => Some(([0, luma_len, 0], [width, width, 0])),
+        _ => None,
+    }
+}
+
+/// Converts tightly packed `width x height` pixels in `format` (captured
+/// BGRA, or the luma plane of a grayscale session) into I420 in `out`, at
+/// `even_size(width, height)`. Each chroma sample averages a 2x2 block.
+/// BGRA uses the CPU's vector instructions where it has them (see `simd`).
+pub fn to_i420(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    matrix: YuvMatrix,
+    out: &mut Vec<u8>,
+) {
+    to_yuv420(
+        ConvertPath::detect(),
+        pixels,
+        (width, height),
+        format,
+        matrix,
+        false,
+        out,
+    );
+}
+
+/// `to_i420` with the chroma interleaved as NV12.
+pub fn to_nv12(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    matrix: YuvMatrix,
+    out: &mut Vec<u8>,
+) {
+    to_yuv420(
+        ConvertPath::detect(),
+        pixels,
+        (width, height),
+        format,
+        matrix,
+        true,
+        out,
+    );
+}
+
+/// The shared conversion; only where U and V samples go differs. `path`
+/// converts what it can of BGRA rows, the scalar loops the rest.
+fn to_yuv420(
+    path: ConvertPath,
+    pixels: &[u8],
+    (width, height): (u32, u32),
+    format: PixelFormat,
+    matrix: YuvMatrix,
+    interleave: bool,
+    out: &mut Vec<u8>,
+) {
+    let (w, h) = even_size(width, height);
+    let (w, h, src_w) = (w as usize, h as usize, width as usize);
+    let bpp = format.bytes_per_pixel() as usize;
+    let luma_len = w * h;
+    let chroma_len = luma_len / 4;
+
+    out.clear();
+    out.resize(luma_len + 2 * chroma_len, 128);
+    let (y_plane, chroma) = out.split_at_mut(luma_len);
+    // Indices of the U and V of chroma sample `i`
+    let uv = |i: usize| {
+        if interleave {
+            (2 * i, 2 * i + 1)
+        } else {
+            (i, chroma_len + i)
+        }
+    };
+
+    if format == PixelFormat::Gray {
+        // Full-range luma squeezed into 16–235; chroma stays neutral
+        for (dst, src) in y_plane.chunks_exact_mut(w).zip(pixels.chunks(src_w)) {
+            for (y, &l) in dst.iter_mut().zip(src) {
+                *y = (16 + (u32::from(l) * 219 + 127) / 255) as u8;
+            }
+        }
+        return;
+    }
+
+    let [ky, ku, kv] = matrix.weights();
+    let mul = |k: [i32; 3], (r, g, b): (i32, i32, i32)| (k[0] * r + k[1] * g + k[2] * b + 128) >> 8;
+    let rgb = |x: usize, y: usize| {
+        let px = &pixels[(y * src_w + x) * bpp..];
+        (i32::from(px[2]), i32::from(px[1]), i32::from(px[0]))
+    };
+    // The vector paths read BGRA only
+    let vector = bpp == 4;
+    let line = |y: usize| &pixels[y * src_w * bpp..][..w * bpp];
+    for (row, y_row) in y_plane.chunks_exact_mut(w).enumerate() {
+        let done = if vector {
+            simd::weigh(path, line(row), ky, 16, y_row)
+        } else {
+            0
+        };
+        for (col, y) in y_row.iter_mut().enumerate().skip(done) {
+            *y = (mul(ky, rgb(col, row)) + 16) as u8;
+        }
+    }
+    for row in 0..h / 2 {
+        let first = row * (w / 2);
+        let done = if vector {
+            simd::chroma(
+                path,
+                line(row * 2),
+                line(row * 2 + 1),
+                (ku, kv),
+                |col, u, v| {
+                    let (at_u, at_v) = uv(first + col);
+                    chroma[at_u] = u;
+                    chroma[at_v] = v;
+                },
+            )
+        } else {
+            0
+        };
+        for col in done..w / 2 {
+            let (mut r, mut g, mut b) = (0, 0, 0);
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let (pr, pg, pb) = rgb(col * 2 + dx, row * 2 + dy);
+                (r, g, b) = (r + pr, g + pg, b + pb);
+            }
+            let average = ((r + 2) / 4, (g + 2) / 4, (b + 2) / 4);
+            let (u, v) = uv(first + col);
+            chroma[u] = (mul(ku, average) + 128) as u8;
+            chroma[v] = (mul(kv, average) + 128) as u8;
+        }
+    }
+}
+
+/// Converts an `I420` or `NV12` frame of `even_size` `width x height` back
+/// into tightly packed BGRA, appended to `out`. Each chroma sample covers
+/// its 2x2 block. `data` must hold the whole frame.
+pub fn to_bgra(
+    data: &[u8],
+    format: FrameFormat,
+    (width, height): (u32, u32),
+    matrix: YuvMatrix,
+    out: &mut Vec<u8>,
+) {
+    let ([_, u_at, v_at], [_, chroma_stride, _]) =
+        planes(format, width, height).expect("to_bgra takes I420 or NV12");
+    let (w, h) = (width as usize, height as usize);
+    let (u_at, chroma_stride) = (u_at as usize, chroma_stride as usize);
+    // Where the U and V of the block at (col, row) of 2x2 blocks are
+    let uv = |col: usize, row: usize| match format {
+        FrameFormat::Nv12 => {
+            let at = u_at + row * chroma_stride + col * 2;
+            (at, at + 1)
+        }
+        _ => (
+            u_at + row * chroma_stride + col,
+            v_at as usize + row * chroma_stride + col,
+        ),
+    };
+
+    let [ky, kvr, kug, kvg, kub] = matrix.inverse();
+    let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+    out.reserve(w * h * 4);
+    for row in 0..h {
+        for col in 0..w {
+            let (u, v) = uv(col / 2, row / 2);
+            let y = ky * (i32::from(data[row * w + col]) - 16);
+            let (u, v) = (i32::from(data[u]) - 128, i32::from(data[v]) - 128);
+            out.extend_from_slice(&[
+                clamp(y + kub * u),
+                clamp(y + kug * u + kvg * v),
+                clamp(y + kvr * v),
+                0xff,
+            ]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rounding of limited-range 8-bit YUV, per channel.
+    const TOLERANCE: i32 = 3;
+
+    /// `width x height` BGRA of 2x2 blocks of random colours, which 4:2:0
+    /// keeps exactly.
+    fn blocks(width: usize, height: usize) -> Vec<u8> {
+        let mut bgra = vec![0u8; width * height * 4];
+        let mut seed = 12345u32;
+        for block in 0..width * height / 4 {
+            let (bx, by) = (block % (width / 2) * 2, block / (width / 2) * 2);
+            let mut px = [0, 0, 0, 255];
+            for channel in &mut px[..3] {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                *channel = (seed >> 24) as u8;
+            }
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let i = ((by + dy) * width + bx + dx) * 4;
+                bgra[i..i + 4].copy_from_slice(&px);
+            }
+        }
+        bgra
+    }
+
+    #[test]
+    fn round_trip_through_the_exact_inverse() {
+        let (w, h) = (64usize, 48usize);
+        let bgra = blocks(w, h);
+        for (matrix, kr, kb) in [
+            (YuvMatrix::Bt601, 0.299, 0.114),
+            (YuvMatrix::Bt709, 0.2126, 0.0722),
+        ] {
+            let mut i420 = Vec::new();
+            to_i420(
+                &bgra,
+                w as u32,
+                h as u32,
+                PixelFormat::Bgra,
+                matrix,
+                &mut i420,
+            );
+            let (u_plane, v_plane) = (&i420[w * h..], &i420[w * h * 5 / 4..]);
+            for y in 0..h {
+                for x in 0..w {
+                    let chroma = y / 2 * (w / 2) + x / 2;
+                    let luma = (f64::from(i420[y * w + x]) - 16.0) * 255.0 / 219.0;
+                    let pb = (f64::from(u_plane[chroma]) - 128.0) * 255.0 / 224.0;
+                    let pr = (f64::from(v_plane[chroma]) - 128.0) * 255.0 / 224.0;
+                    let r = luma + 2.0 * (1.0 - kr) * pr;
+                    let b = luma + 2.0 * (1.0 - kb) * pb;
+                    let g = (luma - kr * r - kb * b) / (1.0 - kr - kb);
+                    let i = (y * w + x) * 4;
+                    for (got, want) in [(r, bgra[i + 2]), (g, bgra[i + 1]), (b, bgra[i])] {
+                        let got = got.round().clamp(0.0, 255.0) as i32;
+                        let error = (got - i32::from(want)).abs();
+                        assert!(error <= TOLERANCE, "{matrix:?} is off by {error}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn to_bgra_inverts_both_layouts() {
+        let (w, h) = (32u32, 16u32);
+        let bgra = blocks(w as usize, h as usize);
+        for matrix in [YuvMatrix::Bt601, YuvMatrix::Bt709] {
+            for format in [FrameFormat::I420, FrameFormat::Nv12] {
+                let mut yuv = Vec::new();
+                let interleave = format == FrameFormat::Nv12;
+                to_yuv420(
+                    ConvertPath::detect(),
+                    &bgra,
+                    (w, h),
+                    PixelFormat::Bgra,
+                    matrix,
+                    interleave,
+                    &mut yuv,
+                );
+                let mut back = Vec::new();
+                to_bgra(&yuv, format, (w, h), matrix, &mut back);
+                assert_eq!(back.len(), bgra.len());
+                for (got, want) in back.iter().zip(&bgra) {
+                    let error = (i32::from(*got) - i32::from(*want)).abs();
+                    assert!(
+                        error <= TOLERANCE,
+                        "{matrix:?} {format:?} is off by {error}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn vector_paths_match_scalar() {
+        // Odd, and not a multiple of any vector width, so each path has a tail
+        let (w, h) = (77u32, 9u32);
+        let bgra: Vec<u8> = (0..w * h * 4).map(|i| (i * 37 % 256) as u8).collect();
+        let paths = [ConvertPath::Ssse3, ConvertPath::Avx2, ConvertPath::Neon];
+        for matrix in [YuvMatrix::Bt601, YuvMatrix::Bt709] {
+            for interleave in [false, true] {
+                let convert = |path| {
+                    let mut out = Vec::new();
+                    let format = PixelFormat::Bgra;
+                    to_yuv420(path, &bgra, (w, h), format, matrix, interleave, &mut out);
+                    out
+                };
+                let scalar = convert(ConvertPath::Scalar);
+                for path in paths.into_iter().filter(|path| path.is_available()) {
+                    assert!(
+                        convert(path) == scalar,
+                        "{path:?} {matrix:?}, interleaved {interleave}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nv12_interleaves_the_i420_chroma() {
+        // Odd, so both lose their last column and row
+        let (w, h) = (65u32, 49u32);
+        let bgra: Vec<u8> = (0..w * h * 4).map(|i| (i * 7 % 251) as u8).collect();
+        for matrix in [YuvMatrix::Bt601, YuvMatrix::Bt709] {
+            let (mut i420, mut nv12) = (Vec::new(), Vec::new());
+            to_i420(&bgra, w, h, PixelFormat::Bgra, matrix, &mut i420);
+            to_nv12(&bgra, w, h, PixelFormat::Bgra, matrix, &mut nv12);
+
+            let luma_len = (w as usize - 1) * (h as usize - 1);
+            let chroma_len = luma_len / 4;
+            assert_eq!(
+                i420.len(),
+                luma_len + 2 * chroma_len,
+                "odd sizes round down"
+            );
+            assert_eq!(nv12.len(), i420.len());
+            assert_eq!(i420[..luma_len], nv12[..luma_len], "Y planes differ");
+            let (u_plane, v_plane) = i420[luma_len..].split_at(chroma_len);
+            for (i, uv) in nv12[luma_len..].chunks_exact(2).enumerate() {
+                assert_eq!(uv, [u_plane[i], v_plane[i]], "chroma sample {i}");
+            }
+        }
+    }
+}
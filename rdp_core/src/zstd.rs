@@ -0,0 +1,129 @@
+//! zstd-compressed raw frames (`FrameFormat::RawZstd`), built with the
+//! `zstd` feature (through the `zstd` crate).
+//!
+//! The payload is a single zstd frame that decompresses to
+//! `RawImage::uncompressed_len` bytes of pixels, laid out as the frame's
+//! `pixel_format` and `stride` describe, exactly like a `Raw` frame. With
+//! delta coding on, a frame not flagged `RawImage::keyframe` decompresses to
+//! its pixels XORed with the previous frame's instead, so the client XORs it
+//! onto the image it is showing (`rdp_decoder_feed` does this). Unchanged areas come out as zeros, which
+//! cost zstd next to nothing.
+
+use crate::error::RdpStatus;
+use crate::frame::PixelFormat;
+use crate::session::SessionConfig;
+
+/// Default compression level: the fastest that still beats JPEG on size
+/// for typical desktop content.
+pub const DEFAULT_LEVEL: i32 = 1;
+
+/// Accepted compression levels.
+pub const MIN_LEVEL: i32 = 1;
+pub const MAX_LEVEL: i32 = 22;
+
+/// Per-session record of the frame the client was last sent.
+#[derive(Default)]
+pub struct DeltaState {
+    /// Pixels of the last frame.
+    reference: Vec<u8>,
+    /// Their width, height and layout; `None` when the next frame must be a
+    /// keyframe.
+    shape: Option<(u32, u32, PixelFormat)>,
+    /// Frames emitted since the last keyframe.
+    since_keyframe: u32,
+}
+
+impl DeltaState {
+    /// Makes the next frame a keyframe.
+    pub fn request_keyframe(&mut self) {
+        self.shape = None;
+    }
+
+    /// Bytes the reference frame holds.
+    pub fn retained(&self) -> usize {
+        self.reference.capacity()
+    }
+}
+
+/// Compresses tightly packed `width x height` pixels in `pixel_format`,
+/// XORed with the previous frame's when `config.zstd_delta` is on and that
+/// frame had the same shape. Returns the payload and whether it is a
+/// keyframe; `xored` is scratch space for the delta.
+pub fn encode(
+    state: &mut DeltaState,
+    pixels: &[u8],
+    shape: (u32, u32, PixelFormat),
+    config: &SessionConfig,
+    xored: &mut Vec<u8>,
+) -> Result<(Vec<u8>, bool), RdpStatus> {
+    if !config.zstd_delta {
+        state.request_keyframe();
+        return Ok((compress(pixels, config.zstd_level)?, true));
+    }
+
+    let keyframe = state.shape != Some(shape)
+        || (config.keyframe_interval > 0 && state.since_keyframe >= config.keyframe_interval);
+    let data = if keyframe {
+        compress(pixels, config.zstd_level)?
+    } else {
+        xored.clear();
+        xored.extend(pixels.iter().zip(&state.reference).map(|(a, b)| a ^ b));
+        compress(xored, config.zstd_level)?
+    };
+
+    state.reference.clear();
+    state.reference.extend_from_slice(pixels);
+    state.shape = Some(shape);
+    state.since_keyframe = if keyframe {
+        0
+    } else {
+        state.since_keyframe + 1
+    };
+    Ok((data, keyframe))
+}
+
+/// One zstd frame holding `src`.
+#[cfg(feature = "zstd")]
+pub fn compress(src: &[u8], level: i32) -> Result<Vec<u8>, RdpStatus> {
+    ::zstd::bulk::compress(src, level).map_err(|e| {
+        crate::error::fail(
+            RdpStatus::EncodeFailed,
+            format!("Failed to compress with zstd: {e}"),
+        )
+    })
+}
+
+/// The `len` bytes the zstd frame `src` holds. Fails with
+/// `RdpStatus::DecodeFailed` when `src` is not a zstd frame or does not
+/// hold exactly `len` bytes.
+#[cfg(feature = "zstd")]
+pub fn decompress(src: &[u8], len: usize) -> Result<Vec<u8>, RdpStatus> {
+    use crate::error::fail;
+
+    // Never allocates more than `len`, whatever size the frame claims
+    let out = ::zstd::bulk::decompress(src, len).map_err(|e| {
+        fail(
+            RdpStatus::DecodeFailed,
+            format!("Failed to decompress with zstd: {e}"),
+        )
+    })?;
+    if out.len() != len {
+        return Err(fail(
+            RdpStatus::DecodeFailed,
+            format!("zstd frame holds {} bytes, not {len}", out.len()),
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn compress(_src: &[u8], _level: i32) -> Result<Vec<u8>, RdpStatus> {
+    crate::encode::ensure_supported(crate::frame::FrameFormat::RawZstd)?;
+    unreachable!("zstd is only supported with the `zstd` feature")
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn decompress(_src: &[u8], _len: usize) -> Result<Vec<u8>, RdpStatus> {
+    crate::encode::ensure_supported(crate::frame::FrameFormat::RawZstd)?;
+    unreachable!("zstd is only supported with the `zstd` feature")
+}
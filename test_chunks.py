@@ -0,0 +1,203 @@
+"""Round-trips captured frames through rdp_frame_chunks and rdp_reassemble.
+
+Captures a JPEG frame with restart markers, cuts it into 1200-byte chunks
+(evenly, and at the markers), checks every chunk's header as documented in
+rdp_core/src/chunk.rs, and reassembles the chunks in reverse order. A
+missing and a damaged chunk must both be refused. Run it from the
+repository root after 'cargo build' in 'rdp_core'; it captures the test
+pattern, so it needs no display.
+"""
+
+import ctypes
+import platform
+import struct
+import sys
+import zlib
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+else:  # Linux
+    lib_name = "librdp_core.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+BACKEND_TEST = 3
+MTU = 1200
+HEADER = struct.Struct("<QHHI")
+RESTART_MARKERS = range(0xD0, 0xD8)
+
+
+class RawImage(ctypes.Structure):
+    # Leading fields only; the library only ever appends
+    _fields_ = [
+        ("data", ctypes.POINTER(ctypes.c_uint8)),
+        ("len", ctypes.c_size_t),
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+        ("format", ctypes.c_uint32),
+        ("stride", ctypes.c_uint32),
+        ("pixel_format", ctypes.c_uint32),
+        ("dirty", ctypes.c_uint32 * 4),
+        ("content_hash", ctypes.c_uint64),
+        ("cursor_x", ctypes.c_int32),
+        ("cursor_y", ctypes.c_int32),
+        ("cursor_visible", ctypes.c_uint8),
+        ("hotspot_x", ctypes.c_uint32),
+        ("hotspot_y", ctypes.c_uint32),
+        ("sequence", ctypes.c_uint64),
+    ]
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_set_capture_backend.argtypes = [ctypes.c_uint32]
+    lib.rdp_set_capture_backend.restype = ctypes.c_int32
+    lib.rdp_session_new.argtypes = [ctypes.c_int32]
+    lib.rdp_session_new.restype = ctypes.c_void_p
+    lib.rdp_session_set_restart_interval.argtypes = [ctypes.c_void_p, ctypes.c_uint32]
+    lib.rdp_session_capture.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.c_uint32]
+    lib.rdp_session_capture.restype = ctypes.POINTER(RawImage)
+    lib.rdp_session_free.argtypes = [ctypes.c_void_p]
+    lib.rdp_frame_chunks_ex.argtypes = [ctypes.POINTER(RawImage), ctypes.c_uint32, ctypes.c_bool]
+    lib.rdp_frame_chunks_ex.restype = ctypes.c_void_p
+    lib.rdp_chunkset_count.argtypes = [ctypes.c_void_p]
+    lib.rdp_chunkset_count.restype = ctypes.c_size_t
+    lib.rdp_chunkset_get.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_size_t,
+        ctypes.POINTER(ctypes.POINTER(ctypes.c_uint8)),
+        ctypes.POINTER(ctypes.c_size_t),
+    ]
+    lib.rdp_chunkset_free.argtypes = [ctypes.c_void_p]
+    lib.rdp_reassemble.argtypes = [
+        ctypes.POINTER(ctypes.POINTER(ctypes.c_uint8)),
+        ctypes.POINTER(ctypes.c_size_t),
+        ctypes.c_size_t,
+    ]
+    lib.rdp_reassemble.restype = ctypes.POINTER(RawImage)
+    lib.free_image.argtypes = [ctypes.POINTER(RawImage)]
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def chunks_of(lib, image, align):
+    """The chunks of `image` as byte strings."""
+    chunk_set = lib.rdp_frame_chunks_ex(image, MTU, align)
+    if not chunk_set:
+        raise RuntimeError(f"rdp_frame_chunks_ex failed: {last_error(lib)}")
+    try:
+        chunks = []
+        for i in range(lib.rdp_chunkset_count(chunk_set)):
+            data = ctypes.POINTER(ctypes.c_uint8)()
+            length = ctypes.c_size_t()
+            if lib.rdp_chunkset_get(chunk_set, i, ctypes.byref(data), ctypes.byref(length)):
+                raise RuntimeError(f"rdp_chunkset_get({i}) failed: {last_error(lib)}")
+            chunks.append(ctypes.string_at(data, length.value))
+        return chunks
+    finally:
+        lib.rdp_chunkset_free(chunk_set)
+
+
+def reassemble(lib, chunks):
+    """The (sequence, data) rdp_reassemble makes of `chunks`, or None."""
+    buffers = [ctypes.create_string_buffer(chunk, len(chunk)) for chunk in chunks]
+    pointers = (ctypes.POINTER(ctypes.c_uint8) * len(chunks))(
+        *[ctypes.cast(b, ctypes.POINTER(ctypes.c_uint8)) for b in buffers]
+    )
+    lengths = (ctypes.c_size_t * len(chunks))(*[len(chunk) for chunk in chunks])
+    image = lib.rdp_reassemble(pointers, lengths, len(chunks))
+    if not image:
+        return None
+    try:
+        return image.contents.sequence, ctypes.string_at(image.contents.data, image.contents.len)
+    finally:
+        lib.free_image(image)
+
+
+def check(chunks, sequence, frame, align):
+    """Describes what is wrong with `chunks` as cut from `frame`, or None."""
+    payloads = []
+    for i, chunk in enumerate(chunks):
+        if len(chunk) > MTU:
+            return f"chunk {i} is {len(chunk)} bytes, over the {MTU}-byte MTU"
+        seq, index, count, checksum = HEADER.unpack_from(chunk)
+        payload = chunk[HEADER.size:]
+        if (seq, index, count) != (sequence, i, len(chunks)):
+            return f"chunk {i} has header {(seq, index, count)}"
+        if zlib.crc32(payload) != checksum:
+            return f"chunk {i} has a wrong checksum"
+        payloads.append(payload)
+    if b"".join(payloads) != frame:
+        return "the payloads do not add up to the frame"
+    if align:
+        at_marker = sum(p[0] == 0xFF and p[1] in RESTART_MARKERS for p in payloads[1:])
+        if at_marker == 0:
+            return "no chunk starts at a restart marker"
+    return None
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    if lib.rdp_set_capture_backend(BACKEND_TEST):
+        print(f"Cannot switch to the test pattern: {last_error(lib)}")
+        return 1
+
+    session = lib.rdp_session_new(-1)
+    if not session:
+        print(f"Cannot open a session: {last_error(lib)}")
+        return 1
+    lib.rdp_session_set_restart_interval(session, 4)
+    image = lib.rdp_session_capture(session, 0, 0)
+    if not image:
+        print(f"Cannot capture: {last_error(lib)}")
+        lib.rdp_session_free(session)
+        return 1
+
+    errors = []
+    try:
+        frame = ctypes.string_at(image.contents.data, image.contents.len)
+        sequence = image.contents.sequence
+        for align in (False, True):
+            name = "aligned" if align else "even"
+            chunks = chunks_of(lib, image, align)
+            problem = check(chunks, sequence, frame, align)
+            if problem:
+                errors.append(f"{name}: {problem}")
+                continue
+            if reassemble(lib, chunks[::-1]) != (sequence, frame):
+                errors.append(f"{name}: reassembly did not give the frame back")
+            if len(chunks) > 1 and reassemble(lib, chunks[1:]) is not None:
+                errors.append(f"{name}: a missing chunk went unnoticed")
+            damaged = bytearray(chunks[-1])
+            damaged[-1] ^= 0xFF
+            if reassemble(lib, chunks[:-1] + [bytes(damaged)]) is not None:
+                errors.append(f"{name}: a damaged chunk went unnoticed")
+    except RuntimeError as e:
+        errors.append(str(e))
+    finally:
+        lib.free_image(image)
+        lib.rdp_session_free(session)
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print(f"OK: a {len(frame)}-byte frame survived chunking, even and aligned")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,186 @@
+"""Runs two capture sessions at the same time and checks both deliver.
+
+Opens one session per display (the same display twice when there is only
+one), captures raw frames from both on separate threads and checks that
+frames from the two sessions arrive interleaved, each at its own display's
+size and intact. Run it from the repository root after 'cargo build' in
+'rdp_core', on a machine with a desktop session.
+"""
+
+import ctypes
+import platform
+import sys
+import threading
+import time
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+else:  # Linux
+    lib_name = "librdp_core.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+FRAMES_PER_SESSION = 30
+FORMAT_RAW = 3
+STATUS_OK = 0
+# Statuses that only mean "no new frame this time"
+STATUS_RETRY = {-13, -16, -29, -30}
+
+
+class RawImage(ctypes.Structure):
+    # Leading fields only; the library only ever appends
+    _fields_ = [
+        ("data", ctypes.POINTER(ctypes.c_uint8)),
+        ("len", ctypes.c_size_t),
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+        ("format", ctypes.c_uint32),
+        ("stride", ctypes.c_uint32),
+        ("pixel_format", ctypes.c_uint32),
+    ]
+
+
+class DisplayInfo(ctypes.Structure):
+    _fields_ = [
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+        ("is_primary", ctypes.c_uint8),
+        ("id", ctypes.c_uint64),
+        ("scale", ctypes.c_float),
+        ("x", ctypes.c_int32),
+        ("y", ctypes.c_int32),
+    ]
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_display_count.restype = ctypes.c_int32
+    lib.rdp_display_info.argtypes = [ctypes.c_int32, ctypes.POINTER(DisplayInfo)]
+    lib.rdp_display_info.restype = ctypes.c_int32
+    lib.rdp_session_new_ex.argtypes = [ctypes.c_int32, ctypes.POINTER(ctypes.c_void_p)]
+    lib.rdp_session_new_ex.restype = ctypes.c_int32
+    lib.rdp_session_set_format.argtypes = [ctypes.c_void_p, ctypes.c_uint32]
+    lib.rdp_session_set_format.restype = ctypes.c_int32
+    lib.rdp_session_capture_ex.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_uint32,
+        ctypes.c_uint32,
+        ctypes.POINTER(ctypes.POINTER(RawImage)),
+    ]
+    lib.rdp_session_capture_ex.restype = ctypes.c_int32
+    lib.rdp_session_free.argtypes = [ctypes.c_void_p]
+    lib.free_image.argtypes = [ctypes.POINTER(RawImage)]
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def capture_frames(lib, session, expected, log, errors):
+    """Captures FRAMES_PER_SESSION raw frames, checking each one."""
+    width, height = expected
+    captured = 0
+    while captured < FRAMES_PER_SESSION:
+        image = ctypes.POINTER(RawImage)()
+        status = lib.rdp_session_capture_ex(session, 0, 0, ctypes.byref(image))
+        if status in STATUS_RETRY:
+            time.sleep(0.005)
+            continue
+        if status != STATUS_OK:
+            errors.append(f"capture failed with status {status}: {last_error(lib)}")
+            return
+        frame = image.contents
+        try:
+            if frame.format != FORMAT_RAW:
+                errors.append(f"expected a raw frame, got format {frame.format}")
+            elif (frame.width, frame.height) != (width, height):
+                errors.append(
+                    f"frame is {frame.width}x{frame.height}, display is {width}x{height}"
+                )
+            elif frame.stride < frame.width * 4 or frame.len != frame.stride * frame.height:
+                errors.append(
+                    f"frame of {frame.len} bytes does not hold {frame.height} rows "
+                    f"of {frame.stride} bytes"
+                )
+            else:
+                # Touch the first and last byte so a bad pointer shows up here
+                _ = frame.data[0], frame.data[frame.len - 1]
+        finally:
+            lib.free_image(image)
+        log.append((session, time.monotonic()))
+        captured += 1
+
+
+def interleaved(log):
+    """Whether captures of the two sessions took turns, rather than one
+    session finishing before the other started."""
+    switches = sum(1 for a, b in zip(log, log[1:]) if a[0] != b[0])
+    return switches >= 2
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    count = lib.rdp_display_count()
+    if count <= 0:
+        print(f"No displays to capture ({count}): {last_error(lib)}")
+        return 1
+    indices = [0, 1] if count >= 2 else [0, 0]
+
+    sessions = []
+    try:
+        for index in indices:
+            info = DisplayInfo()
+            if lib.rdp_display_info(index, ctypes.byref(info)) != STATUS_OK:
+                print(f"Cannot describe display {index}: {last_error(lib)}")
+                return 1
+            session = ctypes.c_void_p()
+            status = lib.rdp_session_new_ex(index, ctypes.byref(session))
+            if status != STATUS_OK:
+                print(f"Cannot open display {index} ({status}): {last_error(lib)}")
+                return 1
+            sessions.append((session, (info.width, info.height)))
+            lib.rdp_session_set_format(session, FORMAT_RAW)
+
+        log, errors = [], []
+        threads = [
+            threading.Thread(
+                target=capture_frames,
+                args=(lib, session.value, expected, log, errors),
+            )
+            for session, expected in sessions
+        ]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+    finally:
+        for session, _ in sessions:
+            lib.rdp_session_free(session)
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    if not interleaved(log):
+        print("FAIL: one session only captured after the other had finished")
+        return 1
+    print(
+        f"OK: {len(log)} frames from displays {indices[0]} and {indices[1]}, "
+        "captured concurrently"
+    )
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
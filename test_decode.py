@@ -0,0 +1,403 @@
+"""Round-trips frames through the encoders and back through the decoders.
+
+A test pattern session (no display needed) encodes 320x240 frames in each
+format; every frame is decoded again, with rdp_decode for JPEG and PNG and
+with a decoder (rdp_decoder_new) for the raw, zstd, tiled and I420 ones,
+and compared with the pattern it was made from. Lossless formats must
+come back exactly, lossy ones within a small mean difference. Deltas fed
+to a decoder that missed their keyframe must fail with KEYFRAME_NEEDED,
+and data that is not an image with DECODE_FAILED. Run it from the
+repository root after 'cargo build' in 'rdp_core'.
+"""
+
+import ctypes
+import platform
+import sys
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+else:  # Linux
+    lib_name = "librdp_core.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+BACKEND_TEST = 3
+WIDTH, HEIGHT = 320, 240
+FRAMES = 4
+KEYFRAME_NEEDED = -47
+DECODE_FAILED = -48
+
+FORMAT_JPEG, FORMAT_PNG, FORMAT_RAW = 0, 1, 3
+FORMAT_TILED_KEY, FORMAT_TILED_DELTA, FORMAT_ZSTD, FORMAT_I420 = 4, 5, 10, 11
+PIXEL_BGRA, PIXEL_RGB = 0, 1
+
+# Mean difference per channel lossy formats may come back with
+JPEG_TOLERANCE = 4.0
+I420_TOLERANCE = 3.0
+
+# The pattern's bars as RGB, left to right, and its burned-in counter (see
+# the `pattern` module)
+BARS = [
+    (191, 191, 191),
+    (191, 191, 0),
+    (0, 191, 191),
+    (0, 191, 0),
+    (191, 0, 191),
+    (191, 0, 0),
+    (0, 0, 191),
+]
+COUNTER_BITS = 32
+GRADIENT_STEP = 4
+
+
+class RawImage(ctypes.Structure):
+    # The whole struct: the decoder reads fields past `keyframe`
+    _fields_ = [
+        ("data", ctypes.POINTER(ctypes.c_uint8)),
+        ("len", ctypes.c_size_t),
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+        ("format", ctypes.c_uint32),
+        ("stride", ctypes.c_uint32),
+        ("pixel_format", ctypes.c_uint32),
+        ("dirty_x", ctypes.c_uint32),
+        ("dirty_y", ctypes.c_uint32),
+        ("dirty_w", ctypes.c_uint32),
+        ("dirty_h", ctypes.c_uint32),
+        ("content_hash", ctypes.c_uint64),
+        ("cursor_x", ctypes.c_int32),
+        ("cursor_y", ctypes.c_int32),
+        ("cursor_visible", ctypes.c_uint8),
+        ("hotspot_x", ctypes.c_uint32),
+        ("hotspot_y", ctypes.c_uint32),
+        ("sequence", ctypes.c_uint64),
+        ("timestamp_us", ctypes.c_uint64),
+        ("quality", ctypes.c_uint8),
+        ("keyframe", ctypes.c_uint8),
+        ("uncompressed_len", ctypes.c_uint64),
+        ("encrypted", ctypes.c_uint8),
+        ("checksum", ctypes.c_uint32),
+        ("plane_offsets", ctypes.c_uint64 * 3),
+        ("plane_strides", ctypes.c_uint32 * 3),
+        ("progressive", ctypes.c_uint8),
+    ]
+
+
+class Image:
+    """A decoded image, copied out of its RawImage."""
+
+    def __init__(self, image):
+        self.data = ctypes.string_at(image.data, image.len)
+        self.width = image.width
+        self.height = image.height
+        self.format = image.format
+        self.stride = image.stride
+        self.pixel_format = image.pixel_format
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_set_test_pattern.argtypes = [ctypes.c_uint32] * 3
+    lib.rdp_set_test_pattern.restype = ctypes.c_int32
+    lib.rdp_session_new_with_backend.argtypes = [
+        ctypes.c_int32,
+        ctypes.c_int32,
+        ctypes.POINTER(ctypes.c_void_p),
+    ]
+    lib.rdp_session_new_with_backend.restype = ctypes.c_int32
+    lib.rdp_session_set_format.argtypes = [ctypes.c_void_p, ctypes.c_uint32]
+    lib.rdp_session_set_format.restype = ctypes.c_int32
+    lib.rdp_session_set_detect_changes.argtypes = [ctypes.c_void_p, ctypes.c_bool]
+    lib.rdp_session_set_detect_changes.restype = None
+    lib.rdp_session_set_tiling.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.c_uint32]
+    lib.rdp_session_set_tiling.restype = ctypes.c_int32
+    lib.rdp_session_set_zstd.argtypes = [ctypes.c_void_p, ctypes.c_int32, ctypes.c_uint8]
+    lib.rdp_session_set_zstd.restype = ctypes.c_int32
+    lib.rdp_session_capture_ex.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_uint32,
+        ctypes.c_uint32,
+        ctypes.POINTER(ctypes.POINTER(RawImage)),
+    ]
+    lib.rdp_session_capture_ex.restype = ctypes.c_int32
+    lib.rdp_session_free.argtypes = [ctypes.c_void_p]
+    lib.rdp_decode.argtypes = [ctypes.c_void_p, ctypes.c_size_t, ctypes.c_uint32]
+    lib.rdp_decode.restype = ctypes.POINTER(RawImage)
+    lib.rdp_decoder_new.argtypes = [ctypes.c_uint32]
+    lib.rdp_decoder_new.restype = ctypes.c_void_p
+    lib.rdp_decoder_feed.argtypes = [ctypes.c_void_p, ctypes.POINTER(RawImage)]
+    lib.rdp_decoder_feed.restype = ctypes.c_int32
+    lib.rdp_decoder_get_frame.argtypes = [ctypes.c_void_p]
+    lib.rdp_decoder_get_frame.restype = ctypes.POINTER(RawImage)
+    lib.rdp_decoder_free.argtypes = [ctypes.c_void_p]
+    lib.rdp_decoder_free.restype = None
+    lib.free_image.argtypes = [ctypes.POINTER(RawImage)]
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def take(lib, image):
+    """Copies out and frees an image from the library; raises on null."""
+    if not image:
+        raise RuntimeError(f"no image: {last_error(lib)}")
+    copy = Image(image.contents)
+    lib.free_image(image)
+    return copy
+
+
+def capture(lib, session):
+    """One captured frame, still owned by the library: free it with
+    free_image."""
+    image = ctypes.POINTER(RawImage)()
+    status = lib.rdp_session_capture_ex(session, 0, 0, ctypes.byref(image))
+    if status:
+        raise RuntimeError(f"capture failed with {status}: {last_error(lib)}")
+    return image
+
+
+def counter(image):
+    """The frame number burned into a decoded BGRA image."""
+    cell = max(1, min(image.width // COUNTER_BITS // 2, 16))
+    number = 0
+    for bit in range(COUNTER_BITS):
+        offset = (cell // 2) * image.stride + (bit * cell + cell // 2) * 4
+        number = number << 1 | (image.data[offset] > 127)
+    return number
+
+
+def pattern(number, width=WIDTH, height=HEIGHT):
+    """Frame `number` of the test pattern as tightly packed BGRA."""
+    bar_row = bytearray()
+    for x in range(width):
+        r, g, b = BARS[x * len(BARS) // width]
+        bar_row += bytes((b, g, r, 255))
+    shift = number * GRADIENT_STEP % width
+    gradient_row = bytearray()
+    for x in range(width):
+        t = (x + shift) % width * 256 // width
+        gradient_row += bytes((128, 255 - t, t, 255))
+    bars_h = height * 2 // 3
+    rows = [bytearray(bar_row) for _ in range(bars_h)]
+    rows += [bytearray(gradient_row) for _ in range(height - bars_h)]
+
+    cell = max(1, min(width // COUNTER_BITS // 2, 16))
+    for bit in range(COUNTER_BITS):
+        value = 255 if number >> (COUNTER_BITS - 1 - bit) & 1 else 0
+        for row in rows[:cell]:
+            row[bit * cell * 4 : (bit + 1) * cell * 4] = bytes((value, value, value, 255)) * cell
+    return b"".join(rows)
+
+
+def compare(image, tolerance):
+    """Describes how a decoded BGRA image differs from the pattern frame it
+    shows, or None. A tolerance of 0 asks for an exact match."""
+    if (image.width, image.height, image.format) != (WIDTH, HEIGHT, FORMAT_RAW):
+        return f"decoded to a {image.width}x{image.height} frame of format {image.format}"
+    if image.stride != WIDTH * 4 or len(image.data) != WIDTH * HEIGHT * 4:
+        return f"decoded with a stride of {image.stride} and {len(image.data)} bytes"
+    number = counter(image)
+    expected = pattern(number)
+    if tolerance == 0:
+        if image.data != expected:
+            return f"frame {number} does not decode exactly"
+        return None
+    mean = sum(abs(a - b) for a, b in zip(image.data, expected)) / len(expected)
+    if mean > tolerance:
+        return f"frame {number} decodes with a mean difference of {mean:.2f}"
+    return None
+
+
+def check_rdp_decode(lib, session):
+    """JPEG and PNG frames through rdp_decode, in BGRA and in RGB."""
+    for frame_format, tolerance in ((FORMAT_JPEG, JPEG_TOLERANCE), (FORMAT_PNG, 0)):
+        lib.rdp_session_set_format(session, frame_format)
+        frame = capture(lib, session)
+        try:
+            data, length = frame.contents.data, frame.contents.len
+            bgra = take(lib, lib.rdp_decode(data, length, PIXEL_BGRA))
+            rgb = take(lib, lib.rdp_decode(data, length, PIXEL_RGB))
+        finally:
+            lib.free_image(frame)
+        problem = compare(bgra, tolerance)
+        if problem:
+            return f"format {frame_format}: {problem}"
+        if rgb.pixel_format != PIXEL_RGB or rgb.stride != WIDTH * 3:
+            return f"format {frame_format} decoded to RGB has a stride of {rgb.stride}"
+        swapped = bytearray()
+        for offset in range(0, len(bgra.data), 4):
+            swapped += bytes((bgra.data[offset + 2], bgra.data[offset + 1], bgra.data[offset]))
+        if rgb.data != swapped:
+            return f"format {frame_format} decodes to RGB unlike to BGRA"
+    return None
+
+
+def decode_stream(lib, session, tolerance, formats):
+    """Feeds FRAMES captures to a new decoder, comparing the image after
+    each; `formats` are the frame formats the captures must come in."""
+    decoder = lib.rdp_decoder_new(PIXEL_BGRA)
+    if not decoder:
+        return f"rdp_decoder_new failed: {last_error(lib)}"
+    try:
+        for index in range(FRAMES):
+            frame = capture(lib, session)
+            try:
+                frame_format = frame.contents.format
+                status = lib.rdp_decoder_feed(decoder, frame)
+            finally:
+                lib.free_image(frame)
+            if frame_format not in formats:
+                return f"frame {index} came as format {frame_format}, not one of {formats}"
+            if status:
+                return f"feeding frame {index} (format {frame_format}) failed with {status}: {last_error(lib)}"
+            problem = compare(take(lib, lib.rdp_decoder_get_frame(decoder)), tolerance)
+            if problem:
+                return f"after frame {index} (format {frame_format}): {problem}"
+    finally:
+        lib.rdp_decoder_free(decoder)
+    return None
+
+
+def check_raw(lib, session):
+    lib.rdp_session_set_format(session, FORMAT_RAW)
+    return decode_stream(lib, session, 0, [FORMAT_RAW])
+
+
+def check_i420(lib, session):
+    lib.rdp_session_set_format(session, FORMAT_I420)
+    return decode_stream(lib, session, I420_TOLERANCE, [FORMAT_I420])
+
+
+def check_tiled(lib, session):
+    """A keyframe then deltas, with lossless and lossy tiles."""
+    try:
+        for tile_format, tolerance in ((FORMAT_PNG, 0), (FORMAT_JPEG, JPEG_TOLERANCE)):
+            # Any change of settings starts the session on a keyframe
+            lib.rdp_session_set_format(session, tile_format)
+            lib.rdp_session_set_tiling(session, 64, 0)
+            problem = decode_stream(lib, session, tolerance, [FORMAT_TILED_KEY, FORMAT_TILED_DELTA])
+            if problem:
+                return f"{tile_format} tiles: {problem}"
+    finally:
+        lib.rdp_session_set_tiling(session, 0, 0)
+    return None
+
+
+def check_zstd(lib, session):
+    """Keyframes and XOR deltas; skipped without the zstd feature."""
+    if lib.rdp_session_set_format(session, FORMAT_ZSTD):
+        print(f"skip: zstd ({last_error(lib)})")
+        return None
+    try:
+        lib.rdp_session_set_zstd(session, 1, 1)
+        return decode_stream(lib, session, 0, [FORMAT_ZSTD])
+    finally:
+        lib.rdp_session_set_zstd(session, 1, 0)
+        lib.rdp_session_set_format(session, FORMAT_JPEG)
+
+
+def check_keyframe_needed(lib, session):
+    """A delta without its keyframe is refused, and the keyframe after it
+    is taken."""
+    lib.rdp_session_set_format(session, FORMAT_PNG)
+    lib.rdp_session_set_tiling(session, 64, 2)
+    decoder = lib.rdp_decoder_new(PIXEL_BGRA)
+    try:
+        # The keyframe, which the decoder never sees
+        lib.free_image(capture(lib, session))
+        statuses = []
+        for _ in range(3):
+            frame = capture(lib, session)
+            statuses.append((frame.contents.format, lib.rdp_decoder_feed(decoder, frame)))
+            lib.free_image(frame)
+    finally:
+        lib.rdp_decoder_free(decoder)
+        lib.rdp_session_set_tiling(session, 0, 0)
+    # Two deltas, then the keyframe the interval of 2 brings
+    expected = [
+        (FORMAT_TILED_DELTA, KEYFRAME_NEEDED),
+        (FORMAT_TILED_DELTA, KEYFRAME_NEEDED),
+        (FORMAT_TILED_KEY, 0),
+    ]
+    if statuses != expected:
+        return f"(format, status) of frames after a missed keyframe are {statuses}, not {expected}"
+    return None
+
+
+def check_damaged(lib, session):
+    garbage = b"\xff\xd8 not a JPEG at all"
+    if lib.rdp_decode(garbage, len(garbage), PIXEL_BGRA):
+        return "rdp_decode decoded garbage"
+    if not last_error(lib):
+        return "rdp_decode failed on garbage without saying why"
+
+    decoder = lib.rdp_decoder_new(PIXEL_BGRA)
+    try:
+        buffer = (ctypes.c_uint8 * len(garbage)).from_buffer_copy(garbage)
+        frame = RawImage(data=buffer, len=len(garbage), width=WIDTH, height=HEIGHT, format=FORMAT_PNG)
+        status = lib.rdp_decoder_feed(decoder, ctypes.byref(frame))
+        empty = lib.rdp_decoder_get_frame(decoder)
+    finally:
+        lib.rdp_decoder_free(decoder)
+    if status != DECODE_FAILED:
+        return f"feeding garbage returned {status}, not DECODE_FAILED"
+    if empty:
+        return "a decoder that only saw garbage has an image"
+    return None
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    if lib.rdp_set_test_pattern(WIDTH, HEIGHT, 0):
+        print(f"rdp_set_test_pattern failed: {last_error(lib)}")
+        return 1
+    session = ctypes.c_void_p()
+    status = lib.rdp_session_new_with_backend(BACKEND_TEST, 0, ctypes.byref(session))
+    if status:
+        print(f"Cannot open a test pattern session ({status}): {last_error(lib)}")
+        return 1
+    lib.rdp_session_set_detect_changes(session, False)
+
+    errors = []
+    checks = (
+        check_rdp_decode,
+        check_raw,
+        check_i420,
+        check_tiled,
+        check_zstd,
+        check_keyframe_needed,
+        check_damaged,
+    )
+    try:
+        for check in checks:
+            try:
+                problem = check(lib, session)
+            except RuntimeError as e:
+                problem = str(e)
+            if problem:
+                errors.append(f"{check.__name__}: {problem}")
+    finally:
+        lib.rdp_session_free(session)
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print(f"OK: {len(checks)} round trips decoded back to the pattern they were encoded from")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
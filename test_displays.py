@@ -0,0 +1,114 @@
+"""Captures every display by index, then asks for ones that do not exist.
+
+Each index from 0 to rdp_display_count() - 1 must give a JPEG frame, as must
+-1 (the primary display). An index one past the last, as after a monitor is
+unplugged, must fail with NO_DISPLAY, and one below -2 with
+INVALID_ARGUMENT, both without a frame and without taking the process down.
+Run it from the repository root after 'cargo build' in 'rdp_core', on a
+machine with a desktop session.
+"""
+
+import ctypes
+import platform
+import sys
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+else:  # Linux
+    lib_name = "librdp_core.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+INVALID_ARGUMENT = -1
+NO_DISPLAY = -2
+# Small, so a wall of monitors captures quickly
+TARGET = (320, 180)
+
+
+class RawImage(ctypes.Structure):
+    _fields_ = [
+        ("data", ctypes.POINTER(ctypes.c_uint8)),
+        ("len", ctypes.c_size_t),
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+    ]
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_display_count.restype = ctypes.c_int32
+    lib.capture_and_encode_ex.argtypes = [
+        ctypes.c_int32,
+        ctypes.c_uint32,
+        ctypes.c_uint32,
+        ctypes.POINTER(ctypes.POINTER(RawImage)),
+    ]
+    lib.capture_and_encode_ex.restype = ctypes.c_int32
+    lib.free_image.argtypes = [ctypes.POINTER(RawImage)]
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def capture(lib, index):
+    """The status of capturing display `index`, and a problem with the
+    frame it gave, if any."""
+    image = ctypes.POINTER(RawImage)()
+    status = lib.capture_and_encode_ex(index, *TARGET, ctypes.byref(image))
+    if status:
+        return status, None if not image else "a frame came with a failure"
+    try:
+        frame = image.contents
+        if ctypes.string_at(frame.data, 2) != b"\xff\xd8":
+            return status, "the frame is not a JPEG"
+        if (frame.width, frame.height) != TARGET:
+            return status, f"the frame is {frame.width}x{frame.height}"
+    finally:
+        lib.free_image(image)
+    return status, None
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    count = lib.rdp_display_count()
+    if count < 0:
+        print(f"Cannot enumerate displays ({count}): {last_error(lib)}")
+        return 1
+
+    errors = []
+    for index in [-1, *range(count)]:
+        status, problem = capture(lib, index)
+        if status:
+            errors.append(f"display {index}: capture failed with {status}: {last_error(lib)}")
+        elif problem:
+            errors.append(f"display {index}: {problem}")
+
+    for index, expected in [(count, NO_DISPLAY), (-3, INVALID_ARGUMENT)]:
+        status, problem = capture(lib, index)
+        if status != expected:
+            errors.append(f"display {index}: status {status}, not {expected}")
+        elif problem:
+            errors.append(f"display {index}: {problem}")
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print(f"OK: captured all {count} displays and the primary; bad indices fail cleanly")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,128 @@
+"""Checks that free_image shrugs off pointers it must not free.
+
+Frees null, a pointer the library never returned, a frame twice and a
+frame whose 'data'/'len' the caller overwrote, and checks that each bad
+call is reported through rdp_last_error_message instead of crashing. Run it
+from the repository root after 'cargo build' in 'rdp_core'; the real frames
+come from a capture of the test pattern, so it needs no display.
+"""
+
+import ctypes
+import platform
+import sys
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+else:  # Linux
+    lib_name = "librdp_core.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+BACKEND_TEST = 3
+
+
+class RawImage(ctypes.Structure):
+    # Leading fields only; the library only ever appends
+    _fields_ = [
+        ("data", ctypes.POINTER(ctypes.c_uint8)),
+        ("len", ctypes.c_size_t),
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+    ]
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_set_capture_backend.argtypes = [ctypes.c_uint32]
+    lib.rdp_set_capture_backend.restype = ctypes.c_int32
+    lib.capture_and_encode.argtypes = [ctypes.c_uint32, ctypes.c_uint32]
+    lib.capture_and_encode.restype = ctypes.POINTER(RawImage)
+    lib.free_image.argtypes = [ctypes.POINTER(RawImage)]
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def rejected(lib, name, image):
+    """Frees `image`, which the library should refuse; returns an error
+    description, or None if it was refused as expected."""
+    address = ctypes.cast(image, ctypes.c_void_p).value
+    # Leave a message about another address first, so a stale one about
+    # this address (whose memory may have been reused) cannot pass
+    lib.free_image(ctypes.cast(0x8, ctypes.POINTER(RawImage)))
+    lib.free_image(image)
+    message = last_error(lib)
+    if "free_image" not in message or f"{address:#x}" not in message:
+        return f"{name}: free_image did not report {address:#x} ({message!r})"
+    return None
+
+
+def capture(lib):
+    image = lib.capture_and_encode(0, 0)
+    if not image:
+        raise RuntimeError(f"capture failed: {last_error(lib)}")
+    return image
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    if lib.rdp_set_capture_backend(BACKEND_TEST):
+        print(f"Cannot switch to the test pattern: {last_error(lib)}")
+        return 1
+
+    errors = []
+
+    # Null is simply ignored
+    lib.free_image(None)
+
+    # Never returned by the library: a struct of our own, and an address
+    # that is not even mapped (the library must not dereference it)
+    foreign = RawImage()
+    foreign.data = (ctypes.c_uint8 * 16)()
+    foreign.len = 16
+    errors.append(rejected(lib, "foreign struct", ctypes.pointer(foreign)))
+    errors.append(
+        rejected(lib, "garbage address", ctypes.cast(0x10, ctypes.POINTER(RawImage)))
+    )
+
+    try:
+        # Twice on the same frame: the first free is fine, the second is
+        # refused
+        image = capture(lib)
+        lib.free_image(image)
+        errors.append(rejected(lib, "double free", image))
+
+        # Fields overwritten by the caller: the library frees what it
+        # handed out, not what the struct now says
+        image = capture(lib)
+        image.contents.data = ctypes.cast(0x10, ctypes.POINTER(ctypes.c_uint8))
+        image.contents.len = 0
+        lib.free_image(image)
+        errors.append(rejected(lib, "double free after overwrite", image))
+    except RuntimeError as e:
+        print(f"Cannot capture: {e}")
+        return 1
+
+    errors = [e for e in errors if e]
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print("OK: null, foreign, garbage and double frees were all ignored safely")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,394 @@
+"""Runs the capture pipeline on the test pattern backend, which needs no
+display, so it works in CI.
+
+Every capture of a 320x240 pattern at rate 0 is a new frame: color bars,
+a gradient that moves between frames and the frame number burned into the
+top left corner. The checks read those back through each pipeline stage:
+the stride repack (raw BGRA, exact bars and frame numbers), resizing,
+channel conversion, JPEG encoding, change detection, tiled and zstd
+deltas, recording to AVI, a capture into a caller's buffer that was too
+small for it, and a change of pattern size seen as a change of resolution. Run it from the repository root after 'cargo build' in
+'rdp_core'.
+"""
+
+import ctypes
+import os
+import platform
+import sys
+import tempfile
+import time
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+else:  # Linux
+    lib_name = "librdp_core.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+BACKEND_TEST = 3
+WIDTH, HEIGHT = 320, 240
+RESIZED = (160, 120)
+BUFFER_TOO_SMALL = -15
+RESOLUTION_CHANGED = -29
+# Longer than the session's one-second display check
+DISPLAY_CHECK_WAIT = 1.2
+
+FORMAT_JPEG, FORMAT_RAW, FORMAT_TILED_KEY, FORMAT_TILED_DELTA, FORMAT_ZSTD = 0, 3, 4, 5, 10
+PIXEL_BGRA, PIXEL_RGB = 0, 1
+
+# The pattern's bars as RGB, left to right, and its burned-in counter
+BARS = [
+    (191, 191, 191),
+    (191, 191, 0),
+    (0, 191, 191),
+    (0, 191, 0),
+    (191, 0, 191),
+    (191, 0, 0),
+    (0, 0, 191),
+]
+COUNTER_BITS = 32
+
+
+class RawImage(ctypes.Structure):
+    _fields_ = [
+        ("data", ctypes.POINTER(ctypes.c_uint8)),
+        ("len", ctypes.c_size_t),
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+        ("format", ctypes.c_uint32),
+        ("stride", ctypes.c_uint32),
+        ("pixel_format", ctypes.c_uint32),
+        ("dirty_x", ctypes.c_uint32),
+        ("dirty_y", ctypes.c_uint32),
+        ("dirty_w", ctypes.c_uint32),
+        ("dirty_h", ctypes.c_uint32),
+        ("content_hash", ctypes.c_uint64),
+        ("cursor_x", ctypes.c_int32),
+        ("cursor_y", ctypes.c_int32),
+        ("cursor_visible", ctypes.c_uint8),
+        ("hotspot_x", ctypes.c_uint32),
+        ("hotspot_y", ctypes.c_uint32),
+        ("sequence", ctypes.c_uint64),
+        ("timestamp_us", ctypes.c_uint64),
+        ("quality", ctypes.c_uint8),
+        ("keyframe", ctypes.c_uint8),
+    ]
+
+
+class Frame:
+    """What the checks need of a captured frame, copied out of it."""
+
+    def __init__(self, image):
+        self.data = ctypes.string_at(image.data, image.len)
+        self.width = image.width
+        self.height = image.height
+        self.format = image.format
+        self.stride = image.stride
+        self.content_hash = image.content_hash
+        self.keyframe = image.keyframe
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_set_test_pattern.argtypes = [ctypes.c_uint32] * 3
+    lib.rdp_set_test_pattern.restype = ctypes.c_int32
+    lib.rdp_session_new_with_backend.argtypes = [
+        ctypes.c_int32,
+        ctypes.c_int32,
+        ctypes.POINTER(ctypes.c_void_p),
+    ]
+    lib.rdp_session_new_with_backend.restype = ctypes.c_int32
+    lib.rdp_session_set_format.argtypes = [ctypes.c_void_p, ctypes.c_uint32]
+    lib.rdp_session_set_format.restype = ctypes.c_int32
+    lib.rdp_session_set_pixel_format.argtypes = [ctypes.c_void_p, ctypes.c_uint32]
+    lib.rdp_session_set_pixel_format.restype = ctypes.c_int32
+    lib.rdp_session_set_detect_changes.argtypes = [ctypes.c_void_p, ctypes.c_bool]
+    lib.rdp_session_set_detect_changes.restype = None
+    lib.rdp_session_set_tiling.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.c_uint32]
+    lib.rdp_session_set_tiling.restype = ctypes.c_int32
+    lib.rdp_session_set_zstd.argtypes = [ctypes.c_void_p, ctypes.c_int32, ctypes.c_uint8]
+    lib.rdp_session_set_zstd.restype = ctypes.c_int32
+    lib.rdp_session_capture_ex.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_uint32,
+        ctypes.c_uint32,
+        ctypes.POINTER(ctypes.POINTER(RawImage)),
+    ]
+    lib.rdp_session_capture_ex.restype = ctypes.c_int32
+    lib.rdp_session_capture_into.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_uint32,
+        ctypes.c_uint32,
+        ctypes.c_void_p,
+        ctypes.c_size_t,
+        ctypes.POINTER(ctypes.c_size_t),
+    ]
+    lib.rdp_session_capture_into.restype = ctypes.c_int32
+    lib.rdp_record_start.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_uint32]
+    lib.rdp_record_start.restype = ctypes.c_int32
+    lib.rdp_record_stop.argtypes = [ctypes.c_void_p]
+    lib.rdp_record_stop.restype = None
+    lib.rdp_session_free.argtypes = [ctypes.c_void_p]
+    lib.free_image.argtypes = [ctypes.POINTER(RawImage)]
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def capture(lib, session, size=(0, 0)):
+    """One frame, copied out and freed; raises on a failed capture."""
+    image = ctypes.POINTER(RawImage)()
+    status = lib.rdp_session_capture_ex(session, size[0], size[1], ctypes.byref(image))
+    if status:
+        raise RuntimeError(f"capture failed with {status}: {last_error(lib)}")
+    frame = Frame(image.contents)
+    lib.free_image(image)
+    return frame
+
+
+def pixel(frame, x, y, channels):
+    offset = y * frame.stride + x * channels
+    return tuple(frame.data[offset : offset + channels])
+
+
+def bar_x(width, index):
+    """A column in the middle of bar `index`."""
+    return (2 * index + 1) * width // (2 * len(BARS))
+
+
+def counter(frame):
+    """The frame number burned into a raw BGRA frame."""
+    cell = max(1, min(frame.width // COUNTER_BITS // 2, 16))
+    number = 0
+    for bit in range(COUNTER_BITS):
+        blue = pixel(frame, bit * cell + cell // 2, cell // 2, 4)[0]
+        number = number << 1 | (blue > 127)
+    return number
+
+
+def check_raw(lib, session):
+    """The stride repack: exact bars and consecutive frame numbers."""
+    lib.rdp_session_set_format(session, FORMAT_RAW)
+    lib.rdp_session_set_pixel_format(session, PIXEL_BGRA)
+    first = capture(lib, session)
+    if (first.width, first.height) != (WIDTH, HEIGHT):
+        return f"raw frames are {first.width}x{first.height}, not {WIDTH}x{HEIGHT}"
+    if first.stride != WIDTH * 4 or len(first.data) != WIDTH * HEIGHT * 4:
+        return f"raw frames keep a stride of {first.stride}, not {WIDTH * 4}"
+    y = HEIGHT // 3
+    for index, (r, g, b) in enumerate(BARS):
+        got = pixel(first, bar_x(WIDTH, index), y, 4)
+        if got != (b, g, r, 255):
+            return f"bar {index} reads BGRA {got}, not {(b, g, r, 255)}"
+    second = capture(lib, session)
+    if counter(second) != counter(first) + 1:
+        return f"frame numbers go from {counter(first)} to {counter(second)}"
+    gradient = HEIGHT - 1
+    if pixel(first, 0, gradient, 4) == pixel(second, 0, gradient, 4):
+        return "the gradient did not move from one frame to the next"
+    return None
+
+
+def check_resize(lib, session):
+    frame = capture(lib, session, RESIZED)
+    if (frame.width, frame.height) != RESIZED:
+        return f"a resize to {RESIZED} gave {frame.width}x{frame.height}"
+    for index, (r, g, b) in enumerate(BARS):
+        got = pixel(frame, bar_x(RESIZED[0], index), RESIZED[1] // 3, 4)
+        if max(abs(a - e) for a, e in zip(got, (b, g, r, 255))) > 2:
+            return f"resized bar {index} reads BGRA {got}, not about {(b, g, r, 255)}"
+    return None
+
+
+def check_convert(lib, session):
+    lib.rdp_session_set_pixel_format(session, PIXEL_RGB)
+    frame = capture(lib, session)
+    lib.rdp_session_set_pixel_format(session, PIXEL_BGRA)
+    if frame.stride != WIDTH * 3:
+        return f"RGB frames have a stride of {frame.stride}, not {WIDTH * 3}"
+    for index, rgb in enumerate(BARS):
+        got = pixel(frame, bar_x(WIDTH, index), HEIGHT // 3, 3)
+        if got != rgb:
+            return f"bar {index} reads RGB {got}, not {rgb}"
+    return None
+
+
+def check_jpeg(lib, session):
+    lib.rdp_session_set_format(session, FORMAT_JPEG)
+    frame = capture(lib, session)
+    if frame.format != FORMAT_JPEG or frame.data[:2] != b"\xff\xd8" or frame.data[-2:] != b"\xff\xd9":
+        return f"a JPEG frame (format {frame.format}) lacks its SOI or EOI marker"
+    return None
+
+
+def check_change_detection(lib, session):
+    """Each frame differs, so none may be skipped as unchanged."""
+    lib.rdp_session_set_detect_changes(session, True)
+    try:
+        hashes = {capture(lib, session).content_hash for _ in range(5)}
+    finally:
+        lib.rdp_session_set_detect_changes(session, False)
+    if len(hashes) != 5:
+        return f"5 moving frames gave {len(hashes)} content hashes"
+    return None
+
+
+def check_tiled(lib, session):
+    """Only the gradient and counter tiles change, so deltas are smaller."""
+    if lib.rdp_session_set_tiling(session, 64, 0):
+        return f"rdp_session_set_tiling failed: {last_error(lib)}"
+    try:
+        key = capture(lib, session)
+        deltas = [capture(lib, session) for _ in range(3)]
+    finally:
+        lib.rdp_session_set_tiling(session, 0, 0)
+    if key.format != FORMAT_TILED_KEY or not key.keyframe:
+        return f"the first tiled frame has format {key.format}, not a keyframe"
+    for delta in deltas:
+        if delta.format != FORMAT_TILED_DELTA or delta.keyframe:
+            return f"a later tiled frame has format {delta.format}, not a delta"
+        if not 0 < len(delta.data) < len(key.data):
+            return f"a {len(delta.data)}-byte delta against a {len(key.data)}-byte keyframe"
+    return None
+
+
+def check_zstd_delta(lib, session):
+    """Skipped when the zstd feature is not built in."""
+    if lib.rdp_session_set_format(session, FORMAT_ZSTD):
+        print(f"skip: zstd ({last_error(lib)})")
+        return None
+    try:
+        lib.rdp_session_set_zstd(session, 1, 1)
+        frames = [capture(lib, session) for _ in range(3)]
+    finally:
+        lib.rdp_session_set_zstd(session, 1, 0)
+        lib.rdp_session_set_format(session, FORMAT_JPEG)
+    if [bool(f.keyframe) for f in frames] != [True, False, False]:
+        return f"zstd delta keyframe flags are {[f.keyframe for f in frames]}"
+    if not len(frames[2].data) < len(frames[0].data):
+        return f"a {len(frames[2].data)}-byte zstd delta against a {len(frames[0].data)}-byte keyframe"
+    return None
+
+
+def check_recording(lib, session):
+    lib.rdp_session_set_format(session, FORMAT_JPEG)
+    with tempfile.TemporaryDirectory() as directory:
+        path = os.path.join(directory, "pattern.avi")
+        if lib.rdp_record_start(session, path.encode(), 10):
+            return f"rdp_record_start failed: {last_error(lib)}"
+        for _ in range(5):
+            capture(lib, session)
+            time.sleep(0.1)
+        lib.rdp_record_stop(session)
+        with open(path, "rb") as f:
+            header = f.read(12)
+        size = os.path.getsize(path)
+    if header[:4] != b"RIFF" or header[8:12] != b"AVI ":
+        return f"the recording starts with {header!r}, not a RIFF AVI header"
+    print(f"recording: {size} bytes for 5 frames")
+    return None
+
+
+def check_capture_into(lib, session):
+    """A frame too big for the buffer is the one the retry gets, so no tiled
+    delta goes missing and change detection does not swallow it."""
+    lib.rdp_session_set_detect_changes(session, True)
+    lib.rdp_session_set_tiling(session, 64, 0)
+    try:
+        needed = ctypes.c_size_t()
+        status = lib.rdp_session_capture_into(session, 0, 0, None, 0, ctypes.byref(needed))
+        if status != BUFFER_TOO_SMALL:
+            return f"probing for the size gave status {status}, not BUFFER_TOO_SMALL"
+        buf = ctypes.create_string_buffer(needed.value)
+        written = ctypes.c_size_t()
+        status = lib.rdp_session_capture_into(
+            session, 0, 0, buf, needed.value, ctypes.byref(written)
+        )
+        if status:
+            return f"the retry gave status {status}: {last_error(lib)}"
+        if written.value != needed.value:
+            return f"the retry wrote {written.value} bytes, not the {needed.value} probed"
+    finally:
+        lib.rdp_session_set_tiling(session, 0, 0)
+        lib.rdp_session_set_detect_changes(session, False)
+    return None
+
+
+def check_resolution_change(lib, session):
+    """A new pattern size reaches an open session as a new resolution."""
+    lib.rdp_session_set_format(session, FORMAT_RAW)
+    lib.rdp_set_test_pattern(WIDTH // 2, HEIGHT // 2, 0)
+    try:
+        time.sleep(DISPLAY_CHECK_WAIT)
+        image = ctypes.POINTER(RawImage)()
+        status = lib.rdp_session_capture_ex(session, 0, 0, ctypes.byref(image))
+        if status == 0:
+            lib.free_image(image)
+        if status != RESOLUTION_CHANGED:
+            return f"a new pattern size gave status {status}, not RESOLUTION_CHANGED"
+        frame = capture(lib, session)
+    finally:
+        lib.rdp_set_test_pattern(WIDTH, HEIGHT, 0)
+    if (frame.width, frame.height) != (WIDTH // 2, HEIGHT // 2):
+        return f"after the change frames are {frame.width}x{frame.height}"
+    return None
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    if lib.rdp_set_test_pattern(WIDTH, HEIGHT, 0):
+        print(f"rdp_set_test_pattern failed: {last_error(lib)}")
+        return 1
+    session = ctypes.c_void_p()
+    status = lib.rdp_session_new_with_backend(BACKEND_TEST, 0, ctypes.byref(session))
+    if status:
+        print(f"Cannot open a test pattern session ({status}): {last_error(lib)}")
+        return 1
+    lib.rdp_session_set_detect_changes(session, False)
+
+    errors = []
+    checks = (
+        check_raw,
+        check_resize,
+        check_convert,
+        check_jpeg,
+        check_change_detection,
+        check_tiled,
+        check_zstd_delta,
+        check_recording,
+        check_capture_into,
+        check_resolution_change,
+    )
+    try:
+        for check in checks:
+            try:
+                problem = check(lib, session)
+            except RuntimeError as e:
+                problem = str(e)
+            if problem:
+                errors.append(f"{check.__name__}: {problem}")
+    finally:
+        lib.rdp_session_free(session)
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print(f"OK: {len(checks)} pipeline checks ran on the test pattern without a display")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,137 @@
+"""Receives frames from rdp_local_serve over its Unix socket or named pipe.
+
+Starts the local server with the default settings, connects two clients and
+reads a few records from each in the TCP server's format (rdp_core/src/tcp.rs),
+checking that every payload is a whole JPEG. On Linux and macOS the socket
+must carry mode 0600 and be gone after rdp_local_stop. Run it from the
+repository root after 'cargo build' in 'rdp_core'; it captures the test
+pattern, so it needs no display.
+"""
+
+import ctypes
+import os
+import platform
+import socket
+import stat
+import struct
+import sys
+import tempfile
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+else:  # Linux
+    lib_name = "librdp_core.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+BACKEND_TEST = 3
+RECORDS = 3
+HEADER = struct.Struct("<IIQ")
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_set_capture_backend.argtypes = [ctypes.c_uint32]
+    lib.rdp_set_capture_backend.restype = ctypes.c_int32
+    lib.rdp_local_serve.argtypes = [ctypes.c_char_p, ctypes.c_void_p]
+    lib.rdp_local_serve.restype = ctypes.c_int32
+    lib.rdp_local_stop.restype = None
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def connect(path):
+    """A binary file reading from the server at `path`."""
+    if platform.system() == "Windows":
+        return open(path, "rb", buffering=0)
+    client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    client.settimeout(10)
+    client.connect(path)
+    return client.makefile("rb")
+
+
+def read_exact(stream, count):
+    data = b""
+    while len(data) < count:
+        chunk = stream.read(count - len(data))
+        if not chunk:
+            raise RuntimeError("the server closed the connection")
+        data += chunk
+    return data
+
+
+def read_records(stream):
+    """Describes what is wrong with the next records of `stream`, or None."""
+    last = None
+    for _ in range(RECORDS):
+        length, sequence, _ = HEADER.unpack(read_exact(stream, HEADER.size))
+        payload = read_exact(stream, length)
+        if not (payload.startswith(b"\xff\xd8") and payload.endswith(b"\xff\xd9")):
+            return f"frame {sequence} is not a whole JPEG"
+        if last is not None and sequence <= last:
+            return f"frame {sequence} came after frame {last}"
+        last = sequence
+    return None
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    if lib.rdp_set_capture_backend(BACKEND_TEST):
+        print(f"Cannot switch to the test pattern: {last_error(lib)}")
+        return 1
+
+    if platform.system() == "Windows":
+        path = rf"\\.\pipe\rdp-core-test-{os.getpid()}"
+    else:
+        path = os.path.join(tempfile.mkdtemp(), "rdp.sock")
+    if lib.rdp_local_serve(path.encode(), None):
+        print(f"Cannot start the local server: {last_error(lib)}")
+        return 1
+
+    errors = []
+    try:
+        if platform.system() != "Windows":
+            mode = stat.S_IMODE(os.stat(path).st_mode)
+            if mode != 0o600:
+                errors.append(f"the socket has mode {mode:o}, not 600")
+        clients = [connect(path), connect(path)]
+        for i, client in enumerate(clients):
+            problem = read_records(client)
+            if problem:
+                errors.append(f"client {i}: {problem}")
+        for client in clients:
+            client.close()
+    except (OSError, RuntimeError) as e:
+        errors.append(str(e))
+    finally:
+        lib.rdp_local_stop()
+
+    if platform.system() != "Windows":
+        if os.path.exists(path):
+            errors.append("the socket file outlived rdp_local_stop")
+        else:
+            os.rmdir(os.path.dirname(path))
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print(f"OK: two clients each received {RECORDS} frames over {path}")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
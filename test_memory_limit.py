@@ -0,0 +1,245 @@
+"""Holds a session to artificially small memory limits.
+
+First a limit far below one frame's buffers: the capture must fail with
+MEMORY_LIMIT rather than allocate, with the attempt reported in RdpStats.
+Then, with a replay buffer on, a limit with room for one frame's buffers
+and only a couple of replay stills: every capture must still succeed, the
+replay buffer must give up its oldest stills to stay under the limit and
+memory_used must never pass it. Run it from the repository root after
+'cargo build' in 'rdp_core'; it captures the test pattern, so it needs no
+display.
+"""
+
+import ctypes
+import platform
+import sys
+import time
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+else:  # Linux
+    lib_name = "librdp_core.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+BACKEND_TEST = 3
+MEMORY_LIMIT = -46
+TINY_LIMIT = 64 << 10
+FRAMES = 15
+# The replay buffer keeps at most 10 frames a second
+FRAME_INTERVAL = 0.12
+# Stills the tight limit leaves room for on top of a frame's buffers
+ROOM_IN_STILLS = 2.5
+
+
+class RawImage(ctypes.Structure):
+    # Leading fields only; the library only ever appends
+    _fields_ = [
+        ("data", ctypes.POINTER(ctypes.c_uint8)),
+        ("len", ctypes.c_size_t),
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+    ]
+
+
+class RdpStats(ctypes.Structure):
+    _fields_ = [
+        ("capture_wait_us_avg", ctypes.c_uint64),
+        ("capture_wait_us_max", ctypes.c_uint64),
+        ("copy_us_avg", ctypes.c_uint64),
+        ("copy_us_max", ctypes.c_uint64),
+        ("resize_us_avg", ctypes.c_uint64),
+        ("resize_us_max", ctypes.c_uint64),
+        ("convert_us_avg", ctypes.c_uint64),
+        ("convert_us_max", ctypes.c_uint64),
+        ("encode_us_avg", ctypes.c_uint64),
+        ("encode_us_max", ctypes.c_uint64),
+        ("frames_captured", ctypes.c_uint64),
+        ("frames_skipped", ctypes.c_uint64),
+        ("bytes_emitted", ctypes.c_uint64),
+        ("actual_fps", ctypes.c_double),
+        ("auth_failures", ctypes.c_uint64),
+        ("backend", ctypes.c_uint32),
+        ("wouldblock_retries", ctypes.c_uint64),
+        ("capture_errors", ctypes.c_uint64),
+        ("frames_dropped", ctypes.c_uint64),
+        ("capture_wait_us_min", ctypes.c_uint64),
+        ("capture_wait_hist", ctypes.c_uint64 * 8),
+        ("wait_strategy", ctypes.c_uint32),
+        ("thread_priority", ctypes.c_uint32),
+        ("thread_affinity", ctypes.c_uint64),
+        ("memory_limit", ctypes.c_uint64),
+        ("memory_used", ctypes.c_uint64),
+        ("memory_peak", ctypes.c_uint64),
+        ("memory_refusals", ctypes.c_uint64),
+    ]
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_set_capture_backend.argtypes = [ctypes.c_uint32]
+    lib.rdp_set_capture_backend.restype = ctypes.c_int32
+    lib.rdp_session_new.argtypes = [ctypes.c_int32]
+    lib.rdp_session_new.restype = ctypes.c_void_p
+    lib.rdp_session_set_detect_changes.argtypes = [ctypes.c_void_p, ctypes.c_bool]
+    lib.rdp_session_set_detect_changes.restype = None
+    lib.rdp_session_set_memory_limit.argtypes = [ctypes.c_void_p, ctypes.c_uint64]
+    lib.rdp_session_set_memory_limit.restype = ctypes.c_int32
+    lib.rdp_session_set_replay_buffer.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_uint32,
+        ctypes.c_uint64,
+    ]
+    lib.rdp_session_set_replay_buffer.restype = None
+    lib.rdp_session_capture_ex.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_uint32,
+        ctypes.c_uint32,
+        ctypes.POINTER(ctypes.POINTER(RawImage)),
+    ]
+    lib.rdp_session_capture_ex.restype = ctypes.c_int32
+    lib.rdp_session_get_stats.argtypes = [ctypes.c_void_p, ctypes.POINTER(RdpStats)]
+    lib.rdp_session_get_stats.restype = ctypes.c_int32
+    lib.rdp_session_reset_stats.argtypes = [ctypes.c_void_p]
+    lib.rdp_session_reset_stats.restype = None
+    lib.rdp_session_free.argtypes = [ctypes.c_void_p]
+    lib.free_image.argtypes = [ctypes.POINTER(RawImage)]
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def stats(lib, session):
+    out = RdpStats()
+    if lib.rdp_session_get_stats(session, ctypes.byref(out)):
+        raise RuntimeError(f"rdp_session_get_stats failed: {last_error(lib)}")
+    return out
+
+
+def capture(lib, session):
+    """The status of one capture, freeing the frame it produced."""
+    image = ctypes.POINTER(RawImage)()
+    status = lib.rdp_session_capture_ex(session, 0, 0, ctypes.byref(image))
+    if status == 0:
+        lib.free_image(image)
+    return status
+
+
+def capture_paced(lib, session, count):
+    """Captures `count` frames far enough apart for the replay buffer to
+    keep each; the statuses that were not OK, and the highest
+    memory_used seen after a capture."""
+    failures, highest = [], 0
+    for _ in range(count):
+        status = capture(lib, session)
+        if status:
+            failures.append((status, last_error(lib)))
+        highest = max(highest, stats(lib, session).memory_used)
+        time.sleep(FRAME_INTERVAL)
+    return failures, highest
+
+
+def check_tiny_limit(lib, session):
+    """Describes what is wrong under TINY_LIMIT, or None."""
+    lib.rdp_session_set_memory_limit(session, TINY_LIMIT)
+    status = capture(lib, session)
+    if status != MEMORY_LIMIT:
+        return f"a capture under a {TINY_LIMIT}-byte limit returned {status}, not MEMORY_LIMIT"
+    if "memory limit" not in last_error(lib):
+        return f"the MEMORY_LIMIT failure said {last_error(lib)!r}"
+    after = stats(lib, session)
+    if after.memory_limit != TINY_LIMIT:
+        return f"memory_limit reads {after.memory_limit}, not {TINY_LIMIT}"
+    if after.memory_used > TINY_LIMIT:
+        return f"{after.memory_used} bytes are held under a {TINY_LIMIT}-byte limit"
+    if not after.memory_refusals:
+        return "the refused capture is not counted in memory_refusals"
+    return None
+
+
+def check_replay_limit(lib, session):
+    """Describes what is wrong when the limit squeezes the replay buffer,
+    or None."""
+    lib.rdp_session_set_memory_limit(session, 0)
+    status = capture(lib, session)
+    if status:
+        return f"a capture without a limit failed with {status}: {last_error(lib)}"
+    # What one frame's buffers take at most, before any still is kept
+    frame_peak = stats(lib, session).memory_peak
+    frame_used = stats(lib, session).memory_used
+
+    lib.rdp_session_set_replay_buffer(session, 10, 0)
+    failures, _ = capture_paced(lib, session, FRAMES)
+    if failures:
+        return f"captures without a limit failed: {failures}"
+    stills = stats(lib, session).memory_used - frame_used
+    still = stills / FRAMES
+    if still <= 0:
+        return "the replay buffer's stills are not counted in memory_used"
+
+    limit = int(frame_peak + ROOM_IN_STILLS * still)
+    lib.rdp_session_set_memory_limit(session, limit)
+    lib.rdp_session_reset_stats(session)
+    if stats(lib, session).memory_used > limit:
+        return "lowering the limit did not drop replay stills at once"
+    failures, highest = capture_paced(lib, session, FRAMES)
+    after = stats(lib, session)
+    if failures:
+        return f"captures under a {limit}-byte limit failed: {failures}"
+    if highest > limit or after.memory_peak > limit:
+        return f"memory use reached {max(highest, after.memory_peak)} under a {limit}-byte limit"
+    if not after.memory_refusals:
+        return "the replay buffer never had to give way"
+    print(
+        f"replay: {limit}-byte limit, {after.memory_peak} bytes at most, "
+        f"{after.memory_refusals} refusals, stills of {still:.0f} bytes"
+    )
+    return None
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    if lib.rdp_set_capture_backend(BACKEND_TEST):
+        print(f"Cannot switch to the test pattern: {last_error(lib)}")
+        return 1
+
+    session = lib.rdp_session_new(-1)
+    if not session:
+        print(f"Cannot open a session: {last_error(lib)}")
+        return 1
+    # Every capture is a frame, however still the screen
+    lib.rdp_session_set_detect_changes(session, False)
+
+    errors = []
+    try:
+        for check in (check_tiny_limit, check_replay_limit):
+            problem = check(lib, session)
+            if problem:
+                errors.append(problem)
+    except RuntimeError as e:
+        errors.append(str(e))
+    finally:
+        lib.rdp_session_free(session)
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print("OK: captures fail with MEMORY_LIMIT or shed replay stills instead of passing the limit")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
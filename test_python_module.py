@@ -0,0 +1,187 @@
+"""Imports the `rdp_core_py` extension module and captures through it on the
+test pattern backend, which needs no display, so it works in CI.
+
+Python loads an extension module by file name, so the library is copied
+to a temporary directory as rdp_core_py. The copy is also opened through
+ctypes (the same file, so the same loaded library) to switch capture to
+the test pattern before any session opens. The checks cover encoded and
+raw captures, resizing, argument errors as ValueError, and that a capture
+releases the GIL. Run it from the repository root after
+'cargo build --features python' in 'rdp_core'.
+"""
+
+import ctypes
+import os
+import platform
+import shutil
+import sys
+import tempfile
+import threading
+
+if platform.system() == "Windows":
+    lib_name, module_name = "rdp_core.dll", "rdp_core_py.pyd"
+elif platform.system() == "Darwin":  # macOS
+    lib_name, module_name = "librdp_core.dylib", "rdp_core_py.so"
+else:  # Linux
+    lib_name, module_name = "librdp_core.so", "rdp_core_py.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+BACKEND_TEST = 3
+WIDTH, HEIGHT = 320, 240
+RESIZED = (160, 120)
+
+
+def load(directory):
+    """The module, imported from a copy of the library in `directory`, and
+    that copy opened through ctypes."""
+    module_path = os.path.join(directory, module_name)
+    shutil.copyfile(lib_path, module_path)
+    lib = ctypes.CDLL(module_path)
+    for name in ("rdp_set_capture_backend", "rdp_set_test_pattern"):
+        getattr(lib, name).restype = ctypes.c_int32
+    if lib.rdp_set_capture_backend(BACKEND_TEST):
+        raise RuntimeError("cannot switch to the test pattern backend")
+    if lib.rdp_set_test_pattern(WIDTH, HEIGHT, 0):
+        raise RuntimeError("cannot size the test pattern")
+    sys.path.insert(0, directory)
+    import rdp_core_py
+
+    return rdp_core_py
+
+
+def check_jpeg(module):
+    """capture() returns a JPEG of the whole pattern."""
+    session = module.Session(format="jpeg", quality=80)
+    jpeg = session.capture()
+    if not isinstance(jpeg, bytes):
+        return f"capture() returned {type(jpeg).__name__}, not bytes"
+    if jpeg[:2] != b"\xff\xd8" or jpeg[-2:] != b"\xff\xd9":
+        return "capture() did not return a whole JPEG"
+    return None
+
+
+def check_array(module):
+    """capture_array() returns pixels shaped (height, width, channels)."""
+    session = module.Session(format="raw", pixel_format="rgb")
+    view = session.capture_array()
+    if view is None:
+        return "capture_array() returned None"
+    if view.shape != (HEIGHT, WIDTH, 3):
+        return f"capture_array() has shape {view.shape}"
+    # The leftmost bar, below the counter, is light gray
+    pixel = tuple(view[HEIGHT // 2, 2, c] for c in range(3))
+    if pixel != (191, 191, 191):
+        return f"the first bar reads {pixel}"
+    return None
+
+
+def check_array_from_jpeg_session(module):
+    """capture_array() on a JPEG session still returns raw pixels, and the
+    session goes back to JPEG afterwards."""
+    session = module.Session(format="jpeg")
+    view = session.capture_array()
+    if view is None or view.shape != (HEIGHT, WIDTH, 4):
+        return f"capture_array() gave {view and view.shape}"
+    if session.capture()[:2] != b"\xff\xd8":
+        return "the session did not go back to JPEG"
+    return None
+
+
+def check_resize(module):
+    """capture(width, height) resizes; a raw capture shows the size."""
+    session = module.Session(format="raw", pixel_format="bgra")
+    pixels = session.capture(*RESIZED)
+    expected = RESIZED[0] * RESIZED[1] * 4
+    if pixels is None or len(pixels) != expected:
+        return f"a resized raw capture has {pixels and len(pixels)} bytes, not {expected}"
+    return None
+
+
+def check_errors(module):
+    """Bad arguments raise ValueError before capturing."""
+    session = module.Session()
+    calls = (
+        ("a lone width", lambda: session.capture(160)),
+        ("a zero height", lambda: session.capture(160, 0)),
+        ("an unknown format", lambda: module.Session(format="gif")),
+        ("an unknown pixel format", lambda: module.Session(pixel_format="cmyk")),
+    )
+    for what, call in calls:
+        try:
+            call()
+        except ValueError:
+            continue
+        return f"{what} did not raise ValueError"
+    if not issubclass(module.CaptureError, RuntimeError):
+        return "CaptureError is not a RuntimeError"
+    return None
+
+
+def check_gil_released(module):
+    """Another Python thread runs while captures are in progress."""
+    session = module.Session(format="jpeg", quality=95)
+    ticks = 0
+    done = threading.Event()
+
+    def count():
+        nonlocal ticks
+        while not done.is_set():
+            ticks += 1
+
+    counter = threading.Thread(target=count)
+    # Hand the GIL over rarely, so between the two reads of `ticks` the
+    # counter only runs if the captures give the GIL up
+    interval = sys.getswitchinterval()
+    sys.setswitchinterval(1.0)
+    counter.start()
+    try:
+        before = ticks
+        for _ in range(20):
+            session.capture()
+        during = ticks - before
+    finally:
+        done.set()
+        counter.join()
+        sys.setswitchinterval(interval)
+    if during == 0:
+        return "the other thread never ran during 20 captures"
+    return None
+
+
+def main():
+    with tempfile.TemporaryDirectory() as directory:
+        try:
+            module = load(directory)
+        except (OSError, ImportError) as e:
+            print(f"Error loading the module: {e}")
+            print("\nHave you run 'cargo build --features python' in the 'rdp_core' directory?")
+            return 1
+
+        errors = []
+        checks = (
+            check_jpeg,
+            check_array,
+            check_array_from_jpeg_session,
+            check_resize,
+            check_errors,
+            check_gil_released,
+        )
+        for check in checks:
+            try:
+                problem = check(module)
+            except Exception as e:
+                problem = f"{type(e).__name__}: {e}"
+            if problem:
+                errors.append(f"{check.__name__}: {problem}")
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print(f"OK: {len(checks)} checks ran through the imported rdp_core_py module")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,220 @@
+"""Checks both frame queue policies against a deliberately slow client.
+
+Serves a session through rdp_local_serve with drop reports on and reads its
+records (rdp_core/src/tcp.rs) far slower than the 30 fps it captures at.
+Under the latest-wins policy the client must miss frames, see them as gaps
+in the sequence numbers and be told about them by control records that
+account for every gap. Under the block policy it must get every frame, in
+order, and no control record. Run it from the repository root after
+'cargo build' in 'rdp_core'; it captures the test pattern, so it needs no
+display.
+"""
+
+import ctypes
+import os
+import platform
+import socket
+import struct
+import sys
+import tempfile
+import time
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+else:  # Linux
+    lib_name = "librdp_core.so"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+BACKEND_TEST = 3
+FORMAT_RAW = 3
+LATEST_WINS = 0
+BLOCK = 1
+FPS = 30
+FRAMES = 8
+# Far slower than FPS, so the socket buffer fills and the queue decides
+READ_DELAY = 0.3
+HEADER = struct.Struct("<IIQ")
+CONTROL = 0xFFFFFFFF
+DROPPED = 1
+
+
+class RdpConfig(ctypes.Structure):
+    _fields_ = [
+        ("struct_size", ctypes.c_uint32),
+        ("display_index", ctypes.c_int32),
+        ("target_w", ctypes.c_uint32),
+        ("target_h", ctypes.c_uint32),
+        ("format", ctypes.c_uint32),
+        ("pixel_format", ctypes.c_uint32),
+        ("quality", ctypes.c_uint32),
+        ("subsampling", ctypes.c_int32),
+        ("fit_mode", ctypes.c_uint32),
+        ("fill_color", ctypes.c_uint32),
+        ("resize_alg", ctypes.c_uint32),
+        ("orientation", ctypes.c_uint32),
+        ("scale", ctypes.c_float),
+        ("max_dim", ctypes.c_uint32),
+        ("timeout_ms", ctypes.c_uint32),
+        ("recovery_timeout_ms", ctypes.c_uint32),
+        ("fps", ctypes.c_uint32),
+        ("backend", ctypes.c_int32),
+        ("include_cursor", ctypes.c_uint8),
+        ("detect_changes", ctypes.c_uint8),
+        ("track_dirty", ctypes.c_uint8),
+        ("capture_logical_size", ctypes.c_uint8),
+        ("progressive", ctypes.c_uint8),
+        ("restart_rows", ctypes.c_uint32),
+        ("queue_policy", ctypes.c_uint32),
+        ("queue_capacity", ctypes.c_uint32),
+        ("report_drops", ctypes.c_uint8),
+    ]
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_set_capture_backend.argtypes = [ctypes.c_uint32]
+    lib.rdp_set_capture_backend.restype = ctypes.c_int32
+    lib.rdp_config_default.argtypes = [ctypes.POINTER(RdpConfig)]
+    lib.rdp_config_default.restype = ctypes.c_int32
+    lib.rdp_local_serve.argtypes = [ctypes.c_char_p, ctypes.POINTER(RdpConfig)]
+    lib.rdp_local_serve.restype = ctypes.c_int32
+    lib.rdp_local_stop.restype = None
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def connect(path):
+    """A binary file reading from the server at `path`."""
+    if platform.system() == "Windows":
+        return open(path, "rb", buffering=0)
+    client = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    client.settimeout(10)
+    client.connect(path)
+    return client.makefile("rb")
+
+
+def read_exact(stream, count):
+    data = b""
+    while len(data) < count:
+        chunk = stream.read(count - len(data))
+        if not chunk:
+            raise RuntimeError("the server closed the connection")
+        data += chunk
+    return data
+
+
+def read_slowly(stream):
+    """(sequences, reports): the frames' sequence numbers and, for each
+    frame, the drop count last reported before it."""
+    sequences, reports = [], []
+    reported = 0
+    while len(sequences) < FRAMES:
+        length, sequence, value = HEADER.unpack(read_exact(stream, HEADER.size))
+        if length == CONTROL:
+            if sequence != DROPPED or value <= reported:
+                raise RuntimeError(f"unexpected control record ({sequence}, {value})")
+            reported = value
+            continue
+        read_exact(stream, length)
+        sequences.append(sequence)
+        reports.append(reported)
+        time.sleep(READ_DELAY)
+    return sequences, reports
+
+
+def check(policy, sequences, reports):
+    """Describes what is wrong with what the client received, or None."""
+    gaps = [b - a - 1 for a, b in zip(sequences, sequences[1:])]
+    if any(gap < 0 for gap in gaps):
+        return f"frames came out of order: {sequences}"
+    if policy == BLOCK:
+        if any(gaps):
+            return f"frames were skipped: {sequences}"
+        if reports[-1]:
+            return f"{reports[-1]} drops were reported"
+        return None
+    if not any(gaps):
+        return f"no frame was skipped: {sequences}"
+    missed = 0
+    for gap, reported in zip(gaps, reports[1:]):
+        missed += gap
+        if reported < missed:
+            return f"{missed} frames were missed but only {reported} reported"
+    return None
+
+
+def run(lib, policy):
+    config = RdpConfig()
+    config.struct_size = ctypes.sizeof(RdpConfig)
+    if lib.rdp_config_default(ctypes.byref(config)):
+        raise RuntimeError(f"rdp_config_default failed: {last_error(lib)}")
+    config.fps = FPS
+    # Raw frames cost next to nothing to encode, and any one of them fills
+    # the socket buffer
+    config.format = FORMAT_RAW
+    config.detect_changes = 0  # Every capture is a frame and takes a number
+    config.queue_policy = policy
+    config.queue_capacity = 1
+    config.report_drops = 1
+
+    if platform.system() == "Windows":
+        path = rf"\\.\pipe\rdp-core-queue-test-{os.getpid()}-{policy}"
+    else:
+        path = os.path.join(tempfile.mkdtemp(), "rdp.sock")
+    if lib.rdp_local_serve(path.encode(), ctypes.byref(config)):
+        raise RuntimeError(f"Cannot start the local server: {last_error(lib)}")
+    try:
+        client = connect(path)
+        try:
+            return read_slowly(client)
+        finally:
+            client.close()
+    finally:
+        lib.rdp_local_stop()
+        if platform.system() != "Windows":
+            os.rmdir(os.path.dirname(path))
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    if lib.rdp_set_capture_backend(BACKEND_TEST):
+        print(f"Cannot switch to the test pattern: {last_error(lib)}")
+        return 1
+
+    errors = []
+    for policy, name in ((LATEST_WINS, "latest wins"), (BLOCK, "block")):
+        try:
+            sequences, reports = run(lib, policy)
+        except (OSError, RuntimeError) as e:
+            errors.append(f"{name}: {e}")
+            continue
+        problem = check(policy, sequences, reports)
+        if problem:
+            errors.append(f"{name}: {problem}")
+        else:
+            print(f"{name}: frames {sequences}, {reports[-1]} reported dropped")
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print("OK: a slow client skips frames under latest wins and holds up capture under block")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
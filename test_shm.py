@@ -0,0 +1,187 @@
+"""Reads frames a session publishes to shared memory, as another process would.
+
+Enables shared-memory delivery on a session, captures a few JPEG frames and
+reads the newest one out of the region by the protocol documented in
+rdp_core/src/shm.rs, checking it against the frame rdp_session_capture
+returned. Freeing the session must then close the region and, on Linux and
+macOS, remove its name. Run it from the repository root after 'cargo build'
+in 'rdp_core'; it captures the test pattern, so it needs no display.
+"""
+
+import ctypes
+import mmap
+import platform
+import struct
+import sys
+
+if platform.system() == "Windows":
+    lib_name = "rdp_core.dll"
+    SHM_NAME = "Local\\rdp-core-test"
+elif platform.system() == "Darwin":  # macOS
+    lib_name = "librdp_core.dylib"
+    SHM_NAME = "/rdp-core-test"
+else:  # Linux
+    lib_name = "librdp_core.so"
+    SHM_NAME = "/rdp-core-test"
+
+lib_path = f"./rdp_core/target/debug/{lib_name}"
+
+BACKEND_TEST = 3
+SLOTS = 4
+FRAMES = 6
+HEADER = struct.Struct("=IIIIQQQ")
+SLOT_HEADER = struct.Struct("=QQQQQQQIIIIII")
+HEADER_LEN = 64
+SLOT_HEADER_LEN = 128
+MAGIC = int.from_bytes(b"RDPS", "little")
+
+
+class RawImage(ctypes.Structure):
+    # Leading fields only; the library only ever appends
+    _fields_ = [
+        ("data", ctypes.POINTER(ctypes.c_uint8)),
+        ("len", ctypes.c_size_t),
+        ("width", ctypes.c_uint32),
+        ("height", ctypes.c_uint32),
+        ("format", ctypes.c_uint32),
+        ("stride", ctypes.c_uint32),
+        ("pixel_format", ctypes.c_uint32),
+        ("dirty", ctypes.c_uint32 * 4),
+        ("content_hash", ctypes.c_uint64),
+        ("cursor_x", ctypes.c_int32),
+        ("cursor_y", ctypes.c_int32),
+        ("cursor_visible", ctypes.c_uint8),
+        ("hotspot_x", ctypes.c_uint32),
+        ("hotspot_y", ctypes.c_uint32),
+        ("sequence", ctypes.c_uint64),
+    ]
+
+
+def load():
+    lib = ctypes.CDLL(lib_path)
+    lib.rdp_set_capture_backend.argtypes = [ctypes.c_uint32]
+    lib.rdp_set_capture_backend.restype = ctypes.c_int32
+    lib.rdp_session_new.argtypes = [ctypes.c_int32]
+    lib.rdp_session_new.restype = ctypes.c_void_p
+    lib.rdp_session_enable_shm.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_uint32]
+    lib.rdp_session_enable_shm.restype = ctypes.c_int32
+    lib.rdp_session_capture.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.c_uint32]
+    lib.rdp_session_capture.restype = ctypes.POINTER(RawImage)
+    lib.rdp_session_free.argtypes = [ctypes.c_void_p]
+    lib.free_image.argtypes = [ctypes.POINTER(RawImage)]
+    lib.rdp_last_error_message.restype = ctypes.c_char_p
+    return lib
+
+
+def last_error(lib):
+    message = lib.rdp_last_error_message()
+    return message.decode() if message else ""
+
+
+def open_region():
+    """The region mapped read-only, or None if it does not exist."""
+    if platform.system() == "Windows":
+        # Maps the header first for the size; an all-zero header means the
+        # section did not exist and mmap made an empty one
+        header = mmap.mmap(-1, HEADER_LEN, tagname=SHM_NAME, access=mmap.ACCESS_READ)
+        magic, _, slot_count, _, slot_size = HEADER.unpack_from(header)[:5]
+        header.close()
+        if magic != MAGIC:
+            return None
+        size = HEADER_LEN + slot_count * (SLOT_HEADER_LEN + slot_size)
+        return mmap.mmap(-1, size, tagname=SHM_NAME, access=mmap.ACCESS_READ)
+    import _posixshmem
+    import os
+
+    try:
+        fd = _posixshmem.shm_open(SHM_NAME, os.O_RDONLY, 0)
+    except FileNotFoundError:
+        return None
+    try:
+        return mmap.mmap(fd, 0, access=mmap.ACCESS_READ)
+    finally:
+        os.close(fd)
+
+
+def read_latest(region):
+    """(sequence, width, height, data) of the newest frame, as shm.rs says to."""
+    magic, version, slot_count, closed, slot_size, published, dropped = HEADER.unpack_from(region)
+    if magic != MAGIC or version != 1:
+        raise RuntimeError("the region is not a version 1 frame ring")
+    while published > 0:
+        index = published - 1
+        slot = index % slot_count
+        at = HEADER_LEN + slot * SLOT_HEADER_LEN
+        fields = SLOT_HEADER.unpack_from(region, at)
+        state, slot_index, sequence, _, length = fields[:5]
+        width, height = fields[7:9]
+        data_at = HEADER_LEN + slot_count * SLOT_HEADER_LEN + slot * slot_size
+        data = region[data_at : data_at + length]
+        # A changed or odd state means the slot was overwritten meanwhile
+        if state % 2 == 0 and struct.unpack_from("=Q", region, at)[0] == state and slot_index == index:
+            return sequence, width, height, data
+        published = HEADER.unpack_from(region)[5]
+    return None
+
+
+def main():
+    try:
+        lib = load()
+    except OSError as e:
+        print(f"Error loading library: {e}")
+        print("\nHave you run 'cargo build' in the 'rdp_core' directory?")
+        return 1
+
+    if lib.rdp_set_capture_backend(BACKEND_TEST):
+        print(f"Cannot switch to the test pattern: {last_error(lib)}")
+        return 1
+
+    session = lib.rdp_session_new(-1)
+    if not session:
+        print(f"Cannot open a session: {last_error(lib)}")
+        return 1
+    if lib.rdp_session_enable_shm(session, SHM_NAME.encode(), SLOTS):
+        print(f"Cannot enable shared memory: {last_error(lib)}")
+        lib.rdp_session_free(session)
+        return 1
+
+    errors = []
+    expected = None
+    for _ in range(FRAMES):
+        image = lib.rdp_session_capture(session, 0, 0)
+        if not image:
+            continue
+        frame = image.contents
+        expected = (frame.sequence, frame.width, frame.height, ctypes.string_at(frame.data, frame.len))
+        lib.free_image(image)
+
+    if expected is None:
+        errors.append(f"no frame was captured: {last_error(lib)}")
+    region = open_region()
+    if region is None:
+        errors.append("the region cannot be opened")
+    elif expected is not None:
+        latest = read_latest(region)
+        if latest is None:
+            errors.append("nothing was published")
+        elif latest != expected:
+            errors.append(f"the region holds frame {latest[0]}, not frame {expected[0]} as captured")
+
+    lib.rdp_session_free(session)
+    if region is not None:
+        if HEADER.unpack_from(region)[3] != 1:
+            errors.append("the region is not marked closed after rdp_session_free")
+        region.close()
+    if platform.system() != "Windows" and open_region() is not None:
+        errors.append("the region's name outlived the session")
+
+    for error in errors:
+        print(f"FAIL: {error}")
+    if errors:
+        return 1
+    print(f"OK: frame {expected[0]} ({len(expected[3])} bytes) read back from shared memory")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())